@@ -161,7 +161,7 @@ impl<T: Float + Bounded> BBox3<T> {
     /// Returns the surface area of the bounding box.
     pub fn surface_area(self) -> T {
         let d = self.diagonal();
-        T::two() * (d.x * d.y + d.x * d.y + d.y * d.z)
+        T::two() * (d.x * d.y + d.x * d.z + d.y * d.z)
     }
 
     /// Returns the centroid of the bounding box.
@@ -194,42 +194,34 @@ impl<T: Float + Bounded> BBox3<T> {
         Some((t0, t1))
     }
 
+    /// Tests whether the ray hits the bounding box between `t_near` and `t_far`. `inv_dir` has to be
+    /// `1 / ray.dir` (a zero component becomes an infinity with the sign of the zero) and `is_dir_neg`
+    /// whether the sign of each component of the direction is negative.
+    ///
+    /// The test is conservative: a ray parallel to a slab that lies exactly in one of its planes gives
+    /// 0 * inf = NaN, which is ignored (the ray counts as inside of the slab), and the exit distance is
+    /// padded for the rounding error, so boxes with zero extent and rays starting on a face are hit.
     pub fn intersect_test(&self, ray: Ray<T>, inv_dir: Vec3<T>, is_dir_neg: Vec3<bool>) -> bool {
-        // Use as indices:
-        let is_dir_neg = [
-            usize::from(is_dir_neg.x),
-            usize::from(is_dir_neg.y),
-            usize::from(is_dir_neg.z),
-        ];
-
-        let t_min = (self[is_dir_neg[0]].x - ray.org.x) * inv_dir.x;
-        let t_max = (self[1 - is_dir_neg[0]].x - ray.org.x) * inv_dir.x;
-        let ty_min = (self[is_dir_neg[1]].y - ray.org.y) * inv_dir.y;
-        let ty_max = (self[1 - is_dir_neg[1]].y - ray.org.y) * inv_dir.y;
-
-        // // Use this to take into account error connection
-        // let t_max = t_max * (1. + 2. * gamma_f64(3));
-        // let ty_max = ty_max * (1. + 2. * gamma_f64(3));
-
-        if t_min > ty_max || ty_min > t_max {
-            return false;
-        }
-
-        let t_min = if ty_min > t_min { ty_min } else { t_min };
-        let t_max = if ty_max < t_max { ty_max } else { t_max };
-
-        let tz_min = (self[is_dir_neg[2]].z - ray.org.z) * inv_dir.z;
-        let tz_max = (self[1 - is_dir_neg[2]].z - ray.org.z) * inv_dir.z;
-
-        // let tz_max = tz_max * (1. + 2. * gamma_f64(3));
-        if t_min > tz_max || tz_min > t_max {
-            return false;
+        // gamma(3) (see `gamma_f64`) for the type of the box:
+        let n_eps = T::from_f64(1.5) * T::epsilon();
+        let error_scale = T::one() + T::two() * (n_eps / (T::one() - n_eps));
+
+        let mut t_min = ray.t_near;
+        let mut t_max = ray.t_far;
+        for axis in 0..3 {
+            // Use as indices:
+            let near = usize::from(is_dir_neg[axis]);
+            let far = 1 - near;
+            let t0 = (self[near][axis] - ray.org[axis]) * inv_dir[axis];
+            let t1 = (self[far][axis] - ray.org[axis]) * inv_dir[axis] * error_scale;
+            // Written so that a NaN never ends up in t_min or t_max (comparisons with NaN are false):
+            t_min = if t0 > t_min { t0 } else { t_min };
+            t_max = if t1 < t_max { t1 } else { t_max };
+            if t_min > t_max {
+                return false;
+            }
         }
-
-        let t_min = if tz_min > t_min { tz_min } else { t_min };
-        let t_max = if tz_max < t_max { tz_max } else { t_max };
-
-        t_min < ray.t_far && t_max > T::zero() && t_max > ray.t_near
+        t_max >= T::zero()
     }
 }
 
@@ -256,3 +248,88 @@ impl<T: PartialOrd + Bounded + Copy> Index<usize> for BBox3<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box() -> BBox3<f64> {
+        BBox3 {
+            pmin: Vec3::zero(),
+            pmax: Vec3 {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+        }
+    }
+
+    fn vec3(x: f64, y: f64, z: f64) -> Vec3<f64> {
+        Vec3 { x, y, z }
+    }
+
+    fn hits(org: Vec3<f64>, dir: Vec3<f64>) -> bool {
+        let ray = Ray::new(org, dir, 0.0);
+        unit_box().intersect_test(ray, dir.inv_scale(1.0), dir.comp_wise_is_neg())
+    }
+
+    #[test]
+    fn rays_parallel_to_a_slab() {
+        for axis in 0..3 {
+            // Travel along the next axis, so the direction is zero along `axis`:
+            let travel = (axis + 1) % 3;
+            for &dir_sign in [1.0, -1.0].iter() {
+                let mut dir = Vec3::zero();
+                dir[travel] = dir_sign;
+                for &(offset, inside) in [(0.5, true), (1.5, false), (-0.5, false)].iter() {
+                    let mut org = vec3(0.5, 0.5, 0.5);
+                    org[travel] = 0.5 - 2.0 * dir_sign;
+                    org[axis] = offset;
+                    assert_eq!(hits(org, dir), inside, "{:?} {:?}", org, dir);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rays_starting_inside() {
+        let org = vec3(0.25, 0.5, 0.75);
+        for &dir in [
+            vec3(1.0, 0.0, 0.0),
+            vec3(-1.0, 0.0, 0.0),
+            vec3(0.0, -1.0, 0.0),
+            vec3(0.0, 0.0, 1.0),
+            vec3(-0.3, 0.4, -0.5),
+            vec3(0.6, -0.2, 0.1),
+        ]
+        .iter()
+        {
+            assert!(hits(org, dir), "{:?}", dir);
+        }
+
+        // Even if the ray ends before it leaves the box:
+        let dir = vec3(1.0, 1.0, 1.0);
+        let ray = Ray::new_extent(org, dir, 0.0, 1e-3);
+        assert!(unit_box().intersect_test(ray, dir.inv_scale(1.0), dir.comp_wise_is_neg()));
+    }
+
+    #[test]
+    fn rays_grazing_a_face() {
+        // A ray that lies in the plane of the top face (and is parallel to two slabs):
+        assert!(hits(vec3(-1.0, 1.0, 0.5), vec3(1.0, 0.0, 0.0)));
+        assert!(hits(vec3(0.5, 0.0, 2.0), vec3(0.0, 0.0, -1.0)));
+        // Along an edge of the box:
+        assert!(hits(vec3(0.0, 0.0, -1.0), vec3(0.0, 0.0, 1.0)));
+        // Just above the face misses:
+        assert!(!hits(vec3(-1.0, 1.0 + 1e-6, 0.5), vec3(1.0, 0.0, 0.0)));
+        // A diagonal ray that only touches an edge:
+        assert!(hits(vec3(2.0, -1.0, 0.5), vec3(-1.0, 1.0, 0.0)));
+    }
+
+    #[test]
+    fn rays_pointing_away_miss() {
+        assert!(!hits(vec3(2.0, 0.5, 0.5), vec3(1.0, 0.0, 0.0)));
+        assert!(!hits(vec3(0.5, -1.0, 0.5), vec3(0.0, -1.0, 0.0)));
+        assert!(!hits(vec3(-1.0, -1.0, -1.0), vec3(-1.0, 1.0, 1.0)));
+    }
+}
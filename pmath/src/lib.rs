@@ -11,6 +11,12 @@ use vector::{Vec2, Vec3};
 
 use std::cmp::PartialOrd;
 
+/// Conservative bound on the relative error introduced by n floating point operations.
+pub fn gamma_f64(n: u32) -> f64 {
+    let n_eps = (n as f64) * f64::EPSILON * 0.5;
+    n_eps / (1.0 - n_eps)
+}
+
 /// Morton encodes form two u32's to a single u64.
 pub fn morton_from_2d(xy: Vec2<u32>) -> u64 {
     fn pdep(n: u64) -> u64 {
@@ -44,6 +50,22 @@ pub fn morton_to_2d(m: u64) -> Vec2<u32> {
     }
 }
 
+/// Morton encodes three u32's (only the lower 21 bits of each are used) to a single u64.
+/// The x coordinate occupies the lowest bit, followed by y and z.
+pub fn morton_from_3d(xyz: Vec3<u32>) -> u64 {
+    fn pdep(n: u64) -> u64 {
+        let n = n & 0x1fffff;
+        let n = (n | (n << 32)) & 0x001f00000000ffff;
+        let n = (n | (n << 16)) & 0x001f0000ff0000ff;
+        let n = (n | (n << 8)) & 0x100f00f00f00f00f;
+        let n = (n | (n << 4)) & 0x10c30c30c30c30c3;
+        let n = (n | (n << 2)) & 0x1249249249249249;
+        n
+    }
+
+    pdep(xyz.x as u64) | (pdep(xyz.y as u64) << 1) | (pdep(xyz.z as u64) << 2)
+}
+
 /// Reverses the bits in a u32 number.
 pub fn reverse_u32(n: u32) -> u32 {
     let n = (n << 16) | (n >> 16);
@@ -107,7 +129,7 @@ pub fn coord_system<T: Float>(v1: Vec3<T>) -> (Vec3<T>, Vec3<T>) {
     // v2 can easily be calculated by just negating one of the components:
     let v2 = if v1.x.abs() > v1.y.abs() {
         Vec3 {
-            x: -v1.x,
+            x: -v1.z,
             y: T::zero(),
             z: v1.x,
         }
@@ -190,3 +212,21 @@ pub fn refract<T: Float>(wi: Vec3<T>, n: Vec3<T>, eta: T) -> Option<Vec3<T>> {
     let cos_theta_t = (T::one() - sin2_theta_t).sqrt();
     Some((-wi).scale(eta) + n.scale(cos_theta_i * eta - cos_theta_t))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn morton_3d_interleaves_the_bits() {
+        let morton = |x, y, z| morton_from_3d(Vec3 { x, y, z });
+        assert_eq!(morton(1, 0, 0), 0b001);
+        assert_eq!(morton(0, 1, 0), 0b010);
+        assert_eq!(morton(0, 0, 1), 0b100);
+        assert_eq!(morton(0b11, 0b01, 0b10), 0b101_011);
+        // Only the lower 21 bits are used:
+        let max = (1 << 21) - 1;
+        assert_eq!(morton(max, max, max), (1 << 63) - 1);
+        assert_eq!(morton(max + 1, 0, 0), 0);
+    }
+}
@@ -30,6 +30,10 @@ pub trait Float: num_traits::Float + num_traits::Bounded + num_traits::sign::Sig
 
     fn from_f32(v: f32) -> Self;
     fn from_f64(v: f64) -> Self;
+
+    // The next representable value above (below) this one:
+    fn next_float_up(self) -> Self;
+    fn next_float_down(self) -> Self;
 }
 
 impl Float for f32 {
@@ -66,6 +70,20 @@ impl Float for f32 {
     fn from_f64(v: f64) -> f32 {
         v as f32
     }
+
+    fn next_float_up(self) -> f32 {
+        if self.is_nan() || (self == f32::INFINITY) {
+            return self;
+        }
+        // Skips -0, which is the same as 0:
+        let v = if self == 0.0 { 0.0 } else { self };
+        let bits = v.to_bits();
+        f32::from_bits(if v >= 0.0 { bits + 1 } else { bits - 1 })
+    }
+
+    fn next_float_down(self) -> f32 {
+        -(-self).next_float_up()
+    }
 }
 
 impl Float for f64 {
@@ -102,4 +120,35 @@ impl Float for f64 {
     fn from_f64(v: f64) -> f64 {
         v
     }
+
+    fn next_float_up(self) -> f64 {
+        if self.is_nan() || (self == f64::INFINITY) {
+            return self;
+        }
+        // Skips -0, which is the same as 0:
+        let v = if self == 0.0 { 0.0 } else { self };
+        let bits = v.to_bits();
+        f64::from_bits(if v >= 0.0 { bits + 1 } else { bits - 1 })
+    }
+
+    fn next_float_down(self) -> f64 {
+        -(-self).next_float_up()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_float_steps_by_one_ulp() {
+        assert_eq!(1.0f64.next_float_up(), 1.0 + f64::EPSILON);
+        assert_eq!(1.0f32.next_float_down(), 1.0 - f32::EPSILON / 2.0);
+        assert_eq!((-1.0f64).next_float_up(), -1.0 + f64::EPSILON / 2.0);
+        // Both zeros step to the smallest subnormal:
+        assert_eq!((-0.0f64).next_float_up(), f64::from_bits(1));
+        assert_eq!(0.0f32.next_float_down(), -f32::from_bits(1));
+        assert_eq!(f64::INFINITY.next_float_up(), f64::INFINITY);
+        assert_eq!(f64::NEG_INFINITY.next_float_down(), f64::NEG_INFINITY);
+    }
 }
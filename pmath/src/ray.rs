@@ -17,8 +17,129 @@ pub struct Ray<T: Float> {
 }
 
 impl<T: Float> Ray<T> {
+    /// Creates a ray that extends from the origin to infinity.
+    pub fn new(org: Vec3<T>, dir: Vec3<T>, time: T) -> Self {
+        Self::new_extent(org, dir, time, T::infinity())
+    }
+
+    /// Creates a ray that only extends to `t_far` (for instance, a shadow ray that stops at a point on a
+    /// light, in which case `dir` is the unnormalized vector to the point and `t_far` is 1).
+    pub fn new_extent(org: Vec3<T>, dir: Vec3<T>, time: T, t_far: T) -> Self {
+        Ray {
+            org,
+            dir,
+            time,
+            t_far,
+            t_near: T::zero(),
+        }
+    }
+
     /// Calculates a point along the ray given a parametric parameter.
     pub fn point_at(self, t: T) -> Vec3<T> {
         self.org + self.dir.scale(t)
     }
+
+    /// Converts the ray to another floating point type (which can be the same one).
+    pub fn cast<U: Float>(self) -> Ray<U> {
+        Ray {
+            org: self.org.cast(),
+            dir: self.dir.cast(),
+            time: U::from_f64(self.time.to_f64()),
+            t_far: U::from_f64(self.t_far.to_f64()),
+            t_near: U::from_f64(self.t_near.to_f64()),
+        }
+    }
+}
+
+/// The rays of the neighbouring pixels in the x and y direction, used for anti-aliasing textures.
+#[derive(Clone, Copy, Debug)]
+pub struct RayDiff<T: Float> {
+    pub rx_org: Vec3<T>,
+    pub rx_dir: Vec3<T>,
+    pub ry_org: Vec3<T>,
+    pub ry_dir: Vec3<T>,
+}
+
+/// A ray generated by the camera, together with its ray differentials.
+#[derive(Clone, Copy, Debug)]
+pub struct PrimaryRay<T: Float> {
+    pub ray: Ray<T>,
+    pub ray_diff: RayDiff<T>,
+}
+
+impl<T: Float> PrimaryRay<T> {
+    /// Converts the ray and its differentials to another floating point type (which can be the same one).
+    pub fn cast<U: Float>(self) -> PrimaryRay<U> {
+        PrimaryRay {
+            ray: self.ray.cast(),
+            ray_diff: RayDiff {
+                rx_org: self.ray_diff.rx_org.cast(),
+                rx_dir: self.ray_diff.rx_dir.cast(),
+                ry_org: self.ray_diff.ry_org.cast(),
+                ry_dir: self.ray_diff.ry_dir.cast(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_extends_to_infinity() {
+        let ray = Ray::new(
+            Vec3::zero(),
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            0.5,
+        );
+        assert_eq!(ray.t_near, 0.0);
+        assert_eq!(ray.t_far, f64::INFINITY);
+        assert_eq!(ray.time, 0.5);
+    }
+
+    #[test]
+    fn new_extent_ends_at_the_target() {
+        let org = Vec3 {
+            x: 1.0,
+            y: 2.0,
+            z: 3.0,
+        };
+        let target = Vec3 {
+            x: -4.0,
+            y: 0.0,
+            z: 7.0,
+        };
+        let ray = Ray::new_extent(org, target - org, 0.0, 1.0);
+        assert_eq!(ray.t_near, 0.0);
+        assert_eq!(ray.t_far, 1.0);
+        let end = ray.point_at(ray.t_far);
+        assert!((end - target).length() < 1e-12);
+    }
+
+    #[test]
+    fn cast_keeps_the_extent() {
+        let ray = Ray::new(
+            Vec3 {
+                x: 1.0,
+                y: 2.0,
+                z: 3.0,
+            },
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            0.25,
+        );
+        let ray32: Ray<f32> = ray.cast();
+        assert_eq!(ray32.t_far, f32::INFINITY);
+        assert_eq!((ray32.org.z, ray32.time), (3.0, 0.25));
+        let back: Ray<f64> = ray32.cast();
+        assert_eq!((back.org.x, back.dir.z, back.t_near), (1.0, 1.0, 0.0));
+    }
 }
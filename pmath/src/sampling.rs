@@ -47,6 +47,15 @@ pub fn uniform_sphere_pdf<T: Float>() -> T {
     T::INV_4PI
 }
 
+/// Returns the barycentric coordinates (of the first two vertices) of a point sampled uniformly on a triangle.
+pub fn uniform_sample_triangle<T: Float>(u: Vec2<T>) -> Vec2<T> {
+    let su0 = u.x.sqrt();
+    Vec2 {
+        x: T::one() - su0,
+        y: u.y * su0,
+    }
+}
+
 pub fn concentric_sample_disk<T: Float>(u: Vec2<T>) -> Vec2<T> {
     // Map to [-1, 1]:
     let u_offset = u.scale(T::two()) - Vec2::one();
@@ -174,6 +174,14 @@ impl<T: Float> Vec2<T> {
             y: self.y.to_f64(),
         }
     }
+
+    /// Converts the vector to another floating point type (which can be the same one).
+    pub fn cast<U: Float>(self) -> Vec2<U> {
+        Vec2 {
+            x: U::from_f64(self.x.to_f64()),
+            y: U::from_f64(self.y.to_f64()),
+        }
+    }
 }
 
 impl<T: Add<Output = T> + Copy> Add for Vec2<T> {
@@ -415,6 +423,15 @@ impl<T: Float> Vec3<T> {
             z: self.z.to_f32(),
         }
     }
+
+    /// Converts the vector to another floating point type (which can be the same one).
+    pub fn cast<U: Float>(self) -> Vec3<U> {
+        Vec3 {
+            x: U::from_f64(self.x.to_f64()),
+            y: U::from_f64(self.y.to_f64()),
+            z: U::from_f64(self.z.to_f64()),
+        }
+    }
 }
 
 impl<T: PartialOrd + Copy> Vec3<T> {
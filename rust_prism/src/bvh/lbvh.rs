@@ -0,0 +1,392 @@
+// This is based on the paper:
+// Maximizing Parallelism in the Construction of BVHs, Octrees, and k-d Trees by
+// Tero Karras.
+
+use crate::bvh::{BVHObject, Node, NodeType, BVH};
+use core_affinity;
+use crossbeam::thread;
+use pmath;
+use pmath::bbox::BBox3;
+use pmath::vector::Vec3;
+
+/// Number of bits used per axis for the morton codes.
+const MORTON_BITS: u32 = 21;
+
+#[derive(Clone, Copy, Debug)]
+enum Child {
+    Internal(usize),
+    Leaf(usize),
+}
+
+/// A node of the binary radix tree, before it gets turned into a regular BVH node.
+#[derive(Clone, Copy, Debug)]
+struct RadixNode {
+    left: Child,
+    right: Child,
+    // The range of (sorted) objects covered by the node:
+    first: usize,
+    last: usize,
+    axis: usize,
+}
+
+/// Used so that threads can write to disjoint parts of the same buffer.
+struct SharedPtr<T>(*mut T);
+unsafe impl<T> Sync for SharedPtr<T> {}
+
+impl<Object: BVHObject + Send + Sync> BVH<Object>
+where
+    Object::UserData: Sync,
+{
+    /// Constructs a BVH by sorting the objects along a morton curve and building a binary
+    /// radix tree over them. This is a lot faster than the SAH build (and every step runs in
+    /// parallel), but the resulting BVH is usually of lower quality.
+    pub fn new_lbvh(objects: &[Object], max_per_leaf: usize, user_data: &Object::UserData) -> Self {
+        // Not worth it for a single leaf:
+        if objects.len() < max_per_leaf.max(2) {
+            return Self::new(objects, max_per_leaf, user_data);
+        }
+
+        let num_threads = core_affinity::get_core_ids().map_or(1, |ids| ids.len().max(1));
+        let n = objects.len();
+
+        let mut bboxes = vec![BBox3::new_initial(); n];
+        parallel_chunks(&mut bboxes, num_threads, |offset, chunk| {
+            for (i, bbox) in chunk.iter_mut().enumerate() {
+                *bbox = objects[offset + i].get_bbox(user_data);
+            }
+        });
+
+        let centroid_bbox = bboxes.iter().fold(BBox3::new_initial(), |accum, bbox| {
+            accum.combine_pnt(bbox.centroid())
+        });
+
+        // Calculate the morton code of every centroid:
+        let scale = ((1u32 << MORTON_BITS) - 1) as f64;
+        let mut keys = vec![(0u64, 0u32); n];
+        parallel_chunks(&mut keys, num_threads, |offset, chunk| {
+            for (i, key) in chunk.iter_mut().enumerate() {
+                let index = offset + i;
+                let p = centroid_bbox.offset(bboxes[index].centroid());
+                // If all of the centroids are the same along an axis, the offset is NaN:
+                let quantize = |v: f64| if v.is_nan() { 0 } else { (v * scale) as u32 };
+                let code = pmath::morton_from_3d(Vec3 {
+                    x: quantize(p.x),
+                    y: quantize(p.y),
+                    z: quantize(p.z),
+                });
+                *key = (code, index as u32);
+            }
+        });
+        radix_sort(&mut keys, num_threads);
+
+        // Every internal node of the radix tree can be constructed independently:
+        let mut radix_nodes = vec![
+            RadixNode {
+                left: Child::Leaf(0),
+                right: Child::Leaf(0),
+                first: 0,
+                last: 0,
+                axis: 0,
+            };
+            n - 1
+        ];
+        parallel_chunks(&mut radix_nodes, num_threads, |offset, chunk| {
+            for (i, node) in chunk.iter_mut().enumerate() {
+                *node = construct_radix_node(&keys, offset + i);
+            }
+        });
+
+        // Finally, turn it into the regular layout (children before their parents):
+        let order: Vec<_> = keys.iter().map(|&(_, index)| index).collect();
+        let ordered_bboxes: Vec<_> = keys
+            .iter()
+            .map(|&(_, index)| bboxes[index as usize])
+            .collect();
+
+        let mut nodes = Vec::with_capacity(2 * n);
+        emit_node(
+            Child::Internal(0),
+            &radix_nodes,
+            &ordered_bboxes,
+            max_per_leaf,
+            &mut nodes,
+        );
+
+        let bbox = nodes.last().unwrap().bbox;
+        Self::from_parts(objects, order, nodes, bbox)
+    }
+}
+
+/// Splits the data into one chunk per thread and calls `f` on each of them in parallel.
+/// `f` also gets passed the offset of the chunk.
+fn parallel_chunks<T: Send, F: Fn(usize, &mut [T]) + Sync>(
+    data: &mut [T],
+    num_threads: usize,
+    f: F,
+) {
+    let chunk_size = ((data.len() + num_threads - 1) / num_threads).max(1);
+    let f = &f;
+    thread::scope(|s| {
+        for (chunk_index, chunk) in data.chunks_mut(chunk_size).enumerate() {
+            s.spawn(move |_| f(chunk_index * chunk_size, chunk));
+        }
+    })
+    .unwrap();
+}
+
+/// Parallel least significant digit radix sort of the keys (sorted by the morton code).
+fn radix_sort(keys: &mut Vec<(u64, u32)>, num_threads: usize) {
+    let chunk_size = ((keys.len() + num_threads - 1) / num_threads).max(1);
+    let mut temp = keys.clone();
+
+    // 8 bits at a time, the morton codes only use the lower 63 bits:
+    for pass in 0..8 {
+        let shift = pass * 8;
+        let digit = move |key: &(u64, u32)| ((key.0 >> shift) & 0xff) as usize;
+
+        let histograms: Vec<[usize; 256]> = thread::scope(|s| {
+            let handles: Vec<_> = keys
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    s.spawn(move |_| {
+                        let mut histogram = [0; 256];
+                        for key in chunk {
+                            histogram[digit(key)] += 1;
+                        }
+                        histogram
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        })
+        .unwrap();
+
+        // Every chunk writes its keys to a disjoint part of the output:
+        let mut offsets = vec![[0; 256]; histograms.len()];
+        let mut total = 0;
+        for d in 0..256 {
+            for (chunk_offsets, histogram) in offsets.iter_mut().zip(histograms.iter()) {
+                chunk_offsets[d] = total;
+                total += histogram[d];
+            }
+        }
+
+        let dst = SharedPtr(temp.as_mut_ptr());
+        let dst = &dst;
+        thread::scope(|s| {
+            for (chunk, mut chunk_offsets) in keys.chunks(chunk_size).zip(offsets.into_iter()) {
+                s.spawn(move |_| {
+                    for key in chunk {
+                        let d = digit(key);
+                        unsafe {
+                            *dst.0.add(chunk_offsets[d]) = *key;
+                        }
+                        chunk_offsets[d] += 1;
+                    }
+                });
+            }
+        })
+        .unwrap();
+
+        std::mem::swap(keys, &mut temp);
+    }
+}
+
+/// Determines the range and split of internal node `i` (see the paper for the details).
+fn construct_radix_node(keys: &[(u64, u32)], i: usize) -> RadixNode {
+    let n = keys.len() as isize;
+    // The length of the common prefix between two keys. Duplicate codes are handled by
+    // falling back to the index:
+    let delta = |a: isize, b: isize| -> i32 {
+        if b < 0 || b >= n {
+            return -1;
+        }
+        let (ka, kb) = (keys[a as usize].0, keys[b as usize].0);
+        if ka == kb {
+            64 + ((a as u64) ^ (b as u64)).leading_zeros() as i32
+        } else {
+            (ka ^ kb).leading_zeros() as i32
+        }
+    };
+
+    let i = i as isize;
+
+    // Determine the direction of the range:
+    let d = if delta(i, i + 1) - delta(i, i - 1) >= 0 {
+        1
+    } else {
+        -1
+    };
+
+    // Compute an upper bound for the length of the range:
+    let delta_min = delta(i, i - d);
+    let mut l_max = 2;
+    while delta(i, i + l_max * d) > delta_min {
+        l_max *= 2;
+    }
+
+    // Find the other end using binary search:
+    let mut l = 0;
+    let mut t = l_max / 2;
+    while t >= 1 {
+        if delta(i, i + (l + t) * d) > delta_min {
+            l += t;
+        }
+        t /= 2;
+    }
+    let j = i + l * d;
+
+    // Find the split position using binary search:
+    let delta_node = delta(i, j);
+    let mut s = 0;
+    let mut t = l;
+    loop {
+        t = (t + 1) / 2;
+        if delta(i, i + (s + t) * d) > delta_node {
+            s += t;
+        }
+        if t == 1 {
+            break;
+        }
+    }
+    let gamma = (i + s * d + d.min(0)) as usize;
+
+    let first = i.min(j) as usize;
+    let last = i.max(j) as usize;
+
+    // The highest differing bit tells us which axis the node splits:
+    let axis = if delta_node < 64 {
+        ((63 - delta_node) % 3) as usize
+    } else {
+        0
+    };
+
+    RadixNode {
+        left: if first == gamma {
+            Child::Leaf(gamma)
+        } else {
+            Child::Internal(gamma)
+        },
+        right: if last == gamma + 1 {
+            Child::Leaf(gamma + 1)
+        } else {
+            Child::Internal(gamma + 1)
+        },
+        first,
+        last,
+        axis,
+    }
+}
+
+/// Recursively adds the nodes in the regular layout, collapsing small subtrees into leaves.
+/// Returns the index of the node that was added.
+fn emit_node(
+    child: Child,
+    radix_nodes: &[RadixNode],
+    bboxes: &[BBox3<f64>],
+    max_per_leaf: usize,
+    nodes: &mut Vec<Node>,
+) -> usize {
+    let leaf = |first: usize, last: usize| Node {
+        bbox: bboxes[first..=last]
+            .iter()
+            .fold(BBox3::new_initial(), |accum, &bbox| accum.combine_bnd(bbox)),
+        node_type: NodeType::Leaf {
+            index: first,
+            count: last - first + 1,
+        },
+    };
+
+    let node = match child {
+        Child::Leaf(index) => leaf(index, index),
+        Child::Internal(index) => {
+            let radix_node = radix_nodes[index];
+            if radix_node.last - radix_node.first + 1 <= max_per_leaf {
+                leaf(radix_node.first, radix_node.last)
+            } else {
+                let first = emit_node(radix_node.left, radix_nodes, bboxes, max_per_leaf, nodes);
+                let second = emit_node(radix_node.right, radix_nodes, bboxes, max_per_leaf, nodes);
+                Node {
+                    bbox: nodes[first].bbox.combine_bnd(nodes[second].bbox),
+                    node_type: NodeType::Internal {
+                        axis: radix_node.axis,
+                        first,
+                        second,
+                    },
+                }
+            }
+        }
+    };
+
+    nodes.push(node);
+    nodes.len() - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bvh::BuildAlgorithm;
+    use crate::geometry::mesh::{Mesh, Triangle};
+    use crate::geometry::Geometry;
+    use pmath::ray::Ray;
+    use pmath::vector::Vec3;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn lbvh_hits_the_same_triangles_as_sah() {
+        // A soup of random triangles:
+        let mut rng = rand_pcg::Pcg32::seed_from_u64(5);
+        let mut pos = Vec::new();
+        let mut triangles = Vec::new();
+        for i in 0..2000 {
+            let center = Vec3 {
+                x: rng.gen_range(-10.0f32, 10.0),
+                y: rng.gen_range(-10.0f32, 10.0),
+                z: rng.gen_range(-10.0f32, 10.0),
+            };
+            for _ in 0..3 {
+                pos.push(Vec3 {
+                    x: center.x + rng.gen_range(-0.5f32, 0.5),
+                    y: center.y + rng.gen_range(-0.5f32, 0.5),
+                    z: center.z + rng.gen_range(-0.5f32, 0.5),
+                });
+            }
+            triangles.push(Triangle {
+                indices: [3 * i, 3 * i + 1, 3 * i + 2],
+                attribute_id: 0,
+            });
+        }
+        let mesh = |algorithm| {
+            Mesh::new(
+                triangles.clone(),
+                pos.clone(),
+                Vec::new(),
+                Vec::new(),
+                Vec::new(),
+                Vec::new(),
+                4,
+                algorithm,
+            )
+        };
+        let sah = mesh(BuildAlgorithm::Sah);
+        let lbvh = mesh(BuildAlgorithm::Lbvh);
+
+        let mut num_hits = 0;
+        for _ in 0..2000 {
+            let org = Vec3 {
+                x: rng.gen_range(-15.0, 15.0),
+                y: rng.gen_range(-15.0, 15.0),
+                z: -20.0,
+            };
+            let target = Vec3 {
+                x: rng.gen_range(-10.0, 10.0),
+                y: rng.gen_range(-10.0, 10.0),
+                z: rng.gen_range(-10.0, 10.0),
+            };
+            let ray = Ray::new(org, (target - org).normalize(), 0.0);
+            let hit = |mesh: &Mesh| mesh.intersect(ray).map(|int| (int.prim, int.t));
+            assert_eq!(hit(&sah), hit(&lbvh));
+            num_hits += hit(&sah).is_some() as u32;
+        }
+        assert!(num_hits > 100);
+    }
+}
@@ -0,0 +1,1236 @@
+// This code is based on the paper from:
+// Importance Sampling of Many Lights with Adaptive Tree Splitting by
+// Estevez and Kulla.
+
+mod lbvh;
+#[cfg(feature = "qbvh")]
+mod qbvh;
+mod sbvh;
+mod serialize;
+mod stack;
+mod stats;
+
+pub use sbvh::SBVH_DEFAULT_ALPHA;
+pub use stats::BVHStats;
+
+use crate::bvh::stack::TraversalStack;
+use crate::interaction::Interaction;
+use partition;
+use pmath::bbox::BBox3;
+use pmath::numbers::Float;
+use pmath::ray::Ray;
+use pmath::vector::Vec3;
+
+/// A trait for a BVH object. For certain use cases (like when constructing
+/// a BVH for a triangular mesh), it may be more efficient to store the primitive
+/// directly with this trait. For other cases (with a number of different BVH objects),
+/// a BVHObject can simply be a reference.
+pub trait BVHObject: Clone {
+    type UserData;
+
+    fn get_bbox(&self, user_data: &Self::UserData) -> BBox3<f64>;
+
+    fn intersect_test(&self, ray: Ray<f64>, user_data: &Self::UserData) -> bool;
+    fn intersect(&self, ray: Ray<f64>, user_data: &Self::UserData) -> Option<Interaction>;
+
+    /// Returns the bounding box of the part of the object that lies inside of `bbox`. This is used
+    /// when performing spatial splits. By default, this is just the overlap of the two bounding boxes.
+    fn clip_bbox(&self, bbox: BBox3<f64>, user_data: &Self::UserData) -> BBox3<f64> {
+        bbox_overlap(self.get_bbox(user_data), bbox).unwrap_or_else(BBox3::new_initial)
+    }
+
+    /// Calls `f` for every intersection with the object (in any order), stopping early if `f`
+    /// asks for it. By default, only the closest intersection is reported, which is correct for
+    /// objects that can't be hit more than once (like triangles).
+    fn intersect_all(
+        &self,
+        ray: Ray<f64>,
+        user_data: &Self::UserData,
+        f: &mut dyn FnMut(Interaction) -> TraversalControl,
+    ) -> TraversalControl {
+        match self.intersect(ray, user_data) {
+            Some(interaction) => f(interaction),
+            None => TraversalControl::Continue,
+        }
+    }
+}
+
+/// Returned by the callbacks when visiting every intersection along a ray to decide whether or
+/// not to keep going.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraversalControl {
+    Continue,
+    Stop,
+}
+
+/// The different ways a BVH can be constructed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildAlgorithm {
+    /// Binned SAH, slow to build but results in high quality BVHs.
+    Sah,
+    /// Linear BVH (built from morton codes), fast to build but of lower quality.
+    Lbvh,
+    /// Binned SAH with spatial splits, the slowest to build (and uses more memory), but
+    /// handles long and thin objects a lot better.
+    Sbvh,
+}
+
+pub struct BVH<Object: BVHObject> {
+    objects: Vec<Object>,
+    // The index of the original object of every object in `objects`:
+    order: Vec<u32>,
+    nodes: Vec<Node>,
+    bbox: BBox3<f64>,
+    // Whether or not objects may be referenced by multiple leaves (see `new_sbvh`):
+    spatial_splits: bool,
+    // The same nodes collapsed into 4-wide nodes, used for traversal:
+    #[cfg(feature = "qbvh")]
+    wide_nodes: Vec<qbvh::WideNode>,
+}
+
+impl<Object: BVHObject> BVH<Object> {
+    const SAH_BIN_COUNT: usize = 12;
+    /// Deeper nodes are turned into leaves. Traversal handles any depth, but degenerate inputs could
+    /// otherwise recurse once per object while the BVH is constructed.
+    const MAX_DEPTH: usize = 128;
+
+    /// Given a collection of BVH objects, constructs a BVH.
+    pub fn new(objects: &[Object], max_per_leaf: usize, user_data: &Object::UserData) -> Self {
+        let (mut object_infos, global_bbox) = Self::object_infos(objects, user_data);
+
+        // Then construct the bvh recursively:
+        let mut nodes = Vec::new();
+        let mut order = Vec::with_capacity(objects.len());
+        Self::rec_construct_bvh(
+            &mut object_infos,
+            &mut order,
+            &mut nodes,
+            max_per_leaf,
+            global_bbox,
+            0,
+        );
+
+        nodes.shrink_to_fit();
+
+        // Now go ahead and return them:
+        Self::from_parts(objects, order, nodes, global_bbox)
+    }
+
+    /// Creates the information needed to construct the BVH for every object, and returns it along
+    /// with the bounding box of all of the objects.
+    fn object_infos(
+        objects: &[Object],
+        user_data: &Object::UserData,
+    ) -> (Vec<ObjectInfo>, BBox3<f64>) {
+        // First we go ahead and create a bunch of light info structures:
+        let object_infos: Vec<_> = objects
+            .iter()
+            .enumerate()
+            .map(|(index, object)| {
+                let bbox = object.get_bbox(user_data);
+                ObjectInfo {
+                    index,
+                    bbox,
+                    centroid: bbox.centroid(),
+                }
+            })
+            .collect();
+
+        let global_bbox = object_infos
+            .iter()
+            .fold(BBox3::new_initial(), |accum, object_info| {
+                accum.combine_bnd(object_info.bbox)
+            });
+
+        (object_infos, global_bbox)
+    }
+
+    /// Constructs the BVH once the nodes have been constructed. `order` specifies, for every object
+    /// referenced by the leaves, the index of the original object.
+    fn from_parts(objects: &[Object], order: Vec<u32>, nodes: Vec<Node>, bbox: BBox3<f64>) -> Self {
+        BVH {
+            #[cfg(feature = "qbvh")]
+            wide_nodes: qbvh::build_wide_nodes(&nodes),
+            objects: order
+                .iter()
+                .map(|&index| objects[index as usize].clone())
+                .collect(),
+            order,
+            nodes,
+            bbox,
+            spatial_splits: false,
+        }
+    }
+
+    pub fn get_bbox(&self) -> BBox3<f64> {
+        self.bbox
+    }
+
+    pub fn get_objects(&self) -> &[Object] {
+        &self.objects[..]
+    }
+
+    /// Nodes are stored such that children always come before their parents, so the root is last.
+    fn root(&self) -> usize {
+        self.nodes.len() - 1
+    }
+
+    /// The bounding box of every node along with its depth (the root is at depth 0), in no particular
+    /// order.
+    pub fn node_bounds(&self) -> impl Iterator<Item = (u32, BBox3<f64>)> + '_ {
+        // Parents come after their children, so going backwards sets the depth of every parent first:
+        let mut depths = vec![0; self.nodes.len()];
+        for node_index in (0..self.nodes.len()).rev() {
+            if let NodeType::Internal { first, second, .. } = self.nodes[node_index].node_type {
+                depths[first] = depths[node_index] + 1;
+                depths[second] = depths[node_index] + 1;
+            }
+        }
+        self.nodes
+            .iter()
+            .zip(depths)
+            .map(|(node, depth)| (depth, node.bbox))
+    }
+
+    /// Recomputes the bounding boxes of every node after the objects (or the user data they
+    /// refer to) have changed, without changing the structure of the BVH. This is a lot faster
+    /// than building a new BVH, but the quality degrades as the objects move further away from
+    /// where they were during construction (see `sah_cost`).
+    pub fn refit(&mut self, user_data: &Object::UserData) {
+        // Because children come before their parents, a single forward pass is enough:
+        for node_index in 0..self.nodes.len() {
+            let bbox = match self.nodes[node_index].node_type {
+                NodeType::Leaf { index, count } => self.objects[index..(index + count)]
+                    .iter()
+                    .fold(BBox3::new_initial(), |accum, object| {
+                        accum.combine_bnd(object.get_bbox(user_data))
+                    }),
+                NodeType::Internal { first, second, .. } => {
+                    self.nodes[first].bbox.combine_bnd(self.nodes[second].bbox)
+                }
+            };
+            self.nodes[node_index].bbox = bbox;
+        }
+
+        self.bbox = self.nodes[self.root()].bbox;
+
+        #[cfg(feature = "qbvh")]
+        {
+            self.wide_nodes = qbvh::build_wide_nodes(&self.nodes);
+        }
+    }
+
+    /// Returns the SAH cost of the BVH, using the same costs as during construction (1 for
+    /// traversing a node and 1 for intersecting an object). Comparing this before and after
+    /// refitting gives an idea of when it's worth rebuilding the BVH.
+    pub fn sah_cost(&self) -> f64 {
+        let root_area = self.bbox.surface_area();
+        if root_area <= 0.0 {
+            return self.objects.len() as f64;
+        }
+
+        self.nodes.iter().fold(0.0, |cost, node| {
+            let area = node.bbox.surface_area() / root_area;
+            match node.node_type {
+                NodeType::Leaf { count, .. } => cost + area * (count as f64),
+                NodeType::Internal { .. } => cost + area,
+            }
+        })
+    }
+
+    /// Returns the number of bytes used by the objects and nodes of the BVH.
+    pub fn memory_usage(&self) -> usize {
+        let usage = self.objects.len() * std::mem::size_of::<Object>()
+            + self.order.len() * std::mem::size_of::<u32>()
+            + self.nodes.len() * std::mem::size_of::<Node>();
+        #[cfg(feature = "qbvh")]
+        let usage = usage + self.wide_nodes.len() * std::mem::size_of::<qbvh::WideNode>();
+        usage
+    }
+
+    /// Given a `Ray`, performs an intersection test, simply returning true if the ray intersects any object in
+    /// the BVH and false otherwise.
+    pub fn intersect_test(&self, ray: Ray<f64>, user_data: &Object::UserData) -> bool {
+        self.intersect_test_excluding(ray, user_data, None)
+    }
+
+    /// Given a `Ray`, performs an intersection, returning a `GeomSurface` of the point of intersection.
+    pub fn intersect(&self, ray: Ray<f64>, user_data: &Object::UserData) -> Option<Interaction> {
+        self.intersect_excluding(ray, user_data, None)
+            .map(|(interaction, _)| interaction)
+    }
+
+    /// Same as `intersect_test`, but skips the object `exclude` (the index of the object in the slice the
+    /// BVH was built from).
+    #[cfg(not(feature = "qbvh"))]
+    pub fn intersect_test_excluding(
+        &self,
+        ray: Ray<f64>,
+        user_data: &Object::UserData,
+        exclude: Option<u32>,
+    ) -> bool {
+        // We do this because t_far may get updated:
+        let inv_dir = ray.dir.inv_scale(1.0);
+        let is_dir_neg = ray.dir.comp_wise_is_neg();
+
+        let mut stack = TraversalStack::<[_; 64]>::new();
+        stack.push(self.root()); // first index to visit
+
+        loop {
+            // Get the next node to visit. If no nodes are left, we are done:
+            let node_index = match stack.pop() {
+                Some(node_index) => node_index,
+                None => return false,
+            };
+
+            // Check if we can intersect the point:
+            let node = self.nodes[node_index];
+            if node.bbox.intersect_test(ray, inv_dir, is_dir_neg) {
+                match node.node_type {
+                    NodeType::Leaf { index, count } => {
+                        // Because we update the t_far variable, new hit is a closer hit:
+                        let objects = self.objects[index..(index + count)].iter();
+                        for (object, &i) in objects.zip(&self.order[index..(index + count)]) {
+                            if (exclude != Some(i)) && object.intersect_test(ray, user_data) {
+                                return true;
+                            }
+                        }
+                    }
+                    NodeType::Internal {
+                        axis,
+                        first,
+                        second,
+                    } => {
+                        // We want to first intersect the child that is closest to the ray (to "prune" t_far)
+                        // as much as possible first.
+                        if is_dir_neg[axis] {
+                            stack.push(first);
+                            stack.push(second); // we are under the axis and "second" should be first
+                        } else {
+                            stack.push(second);
+                            stack.push(first); // otherwise, "first" should be first
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Same as `intersect`, but skips the object `exclude`. Also returns the index of the object that was
+    /// hit (in the slice the BVH was built from).
+    #[cfg(not(feature = "qbvh"))]
+    pub fn intersect_excluding(
+        &self,
+        ray: Ray<f64>,
+        user_data: &Object::UserData,
+        exclude: Option<u32>,
+    ) -> Option<(Interaction, u32)> {
+        // We do this because t_far may get updated:
+        let inv_dir = ray.dir.inv_scale(1.0);
+        let is_dir_neg = ray.dir.comp_wise_is_neg();
+        let mut ray = ray;
+
+        let mut stack = TraversalStack::<[_; 64]>::new();
+        stack.push(self.root()); // first index to visit
+
+        let mut hit = None;
+
+        loop {
+            // Get the next node to visit:
+            let node_index = match stack.pop() {
+                Some(node_index) => node_index,
+                None => return hit,
+            };
+
+            // Check if we can intersect the point:
+            let node = self.nodes[node_index];
+            if node.bbox.intersect_test(ray, inv_dir, is_dir_neg) {
+                match node.node_type {
+                    NodeType::Leaf { index, count } => {
+                        // Because we update the extent, every new hit is a closer hit:
+                        let objects = self.objects[index..(index + count)].iter();
+                        for (object, &i) in objects.zip(&self.order[index..(index + count)]) {
+                            if exclude == Some(i) {
+                                continue;
+                            }
+                            if let Some(geom_surface) = object.intersect(ray, user_data) {
+                                ray.t_far = geom_surface.t.to_f64();
+                                hit = Some((geom_surface, i));
+                            }
+                        }
+                    }
+                    NodeType::Internal {
+                        axis,
+                        first,
+                        second,
+                    } => {
+                        // We want to first intersect the child that is closest to the ray (to "prune" t_far)
+                        // as much as possible first.
+                        if is_dir_neg[axis] {
+                            stack.push(first);
+                            stack.push(second); // we are under the axis and "second" should be first
+                        } else {
+                            stack.push(second);
+                            stack.push(first); // otherwise, "first" should be first
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Given a `Ray`, calls `f` for every intersection with the objects in the BVH. The intersections are
+    /// visited in arbitrary order, and traversal ends as soon as `f` returns `TraversalControl::Stop`
+    /// (which is then also returned). This is useful for shadow rays through transparent surfaces.
+    pub fn intersect_all(
+        &self,
+        ray: Ray<f64>,
+        user_data: &Object::UserData,
+        f: &mut impl FnMut(Interaction) -> TraversalControl,
+    ) -> TraversalControl {
+        self.visit_all(ray, |object| object.intersect_all(ray, user_data, &mut *f))
+    }
+
+    /// Given a `Ray`, calls `f` for every object whose bounding box is hit by the ray, in arbitrary order.
+    /// Traversal ends as soon as `f` returns `TraversalControl::Stop`. Every object is visited at most once,
+    /// even if the BVH was constructed with spatial splits (where objects may be in multiple leaves).
+    pub fn visit_all(
+        &self,
+        ray: Ray<f64>,
+        mut f: impl FnMut(&Object) -> TraversalControl,
+    ) -> TraversalControl {
+        let mut visited = VisitedObjects::default();
+        self.visit_leaves(ray, |objects, indices| {
+            for (object, &index) in objects.iter().zip(indices) {
+                if self.spatial_splits && !visited.insert(index) {
+                    continue;
+                }
+                if f(object) == TraversalControl::Stop {
+                    return TraversalControl::Stop;
+                }
+            }
+            TraversalControl::Continue
+        })
+    }
+
+    /// Calls `f` with the objects of every leaf hit by the ray (and the indices of the original objects), in
+    /// arbitrary order.
+    fn visit_leaves(
+        &self,
+        ray: Ray<f64>,
+        mut f: impl FnMut(&[Object], &[u32]) -> TraversalControl,
+    ) -> TraversalControl {
+        let inv_dir = ray.dir.inv_scale(1.0);
+        let is_dir_neg = ray.dir.comp_wise_is_neg();
+
+        let mut stack = TraversalStack::<[_; 64]>::new();
+        stack.push(self.root()); // first index to visit
+
+        while let Some(node_index) = stack.pop() {
+            let node = self.nodes[node_index];
+            if node.bbox.intersect_test(ray, inv_dir, is_dir_neg) {
+                match node.node_type {
+                    NodeType::Leaf { index, count } => {
+                        let range = index..(index + count);
+                        if f(&self.objects[range.clone()], &self.order[range])
+                            == TraversalControl::Stop
+                        {
+                            return TraversalControl::Stop;
+                        }
+                    }
+                    // The order doesn't matter as every object has to be visited anyways:
+                    NodeType::Internal { first, second, .. } => {
+                        stack.push(first);
+                        stack.push(second);
+                    }
+                }
+            }
+        }
+
+        TraversalControl::Continue
+    }
+
+    /// Recursively constructs the scene. Returns the index of the node that is constructed by the function call.
+    ///
+    /// # Arguments
+    /// * `object_infos` - A collection of information about the objects we are trying to split. This is mutable as it
+    ///                    gets partitioned as we continue the process.
+    /// * `order` - The final order of the objects (as indices into the original objects) so that the nodes can index them.
+    fn rec_construct_bvh(
+        object_infos: &mut [ObjectInfo],
+        order: &mut Vec<u32>,
+        nodes: &mut Vec<Node>,
+        max_per_leaf: usize,
+        global_bbox: BBox3<f64>,
+        depth: usize,
+    ) -> usize {
+        // Check the number of lights and see if we should make a leaf or not:
+        if object_infos.len() < max_per_leaf {
+            Self::create_leaf(object_infos, order, nodes, global_bbox);
+            return nodes.len() - 1;
+        }
+
+        if depth >= Self::MAX_DEPTH {
+            eprintln!(
+                "BVH reached its maximum depth, creating a leaf with {} objects",
+                object_infos.len()
+            );
+            Self::create_leaf(object_infos, order, nodes, global_bbox);
+            return nodes.len() - 1;
+        }
+
+        // Otherwise, we try performing a split:
+        match Self::split_clusters(object_infos, global_bbox) {
+            Some((first_object_infos, second_object_infos, axis)) => {
+                // We push this here so that we can easily calculate the overal global bound and store it at the
+                // "top level".
+
+                let first_global_bbox = first_object_infos
+                    .iter()
+                    .fold(BBox3::new_initial(), |accum, object_info| {
+                        accum.combine_bnd(object_info.bbox)
+                    });
+
+                let second_global_bbox = second_object_infos
+                    .iter()
+                    .fold(BBox3::new_initial(), |accum, object_info| {
+                        accum.combine_bnd(object_info.bbox)
+                    });
+
+                // We recursively build the left and right one:
+                let first = Self::rec_construct_bvh(
+                    first_object_infos,
+                    order,
+                    nodes,
+                    max_per_leaf,
+                    first_global_bbox,
+                    depth + 1,
+                );
+                let second = Self::rec_construct_bvh(
+                    second_object_infos,
+                    order,
+                    nodes,
+                    max_per_leaf,
+                    second_global_bbox,
+                    depth + 1,
+                );
+
+                // Construct an internal node and add it to the node vector:
+                nodes.push(Node {
+                    bbox: global_bbox,
+                    node_type: NodeType::Internal {
+                        axis,
+                        first,
+                        second,
+                    },
+                })
+            }
+            None => Self::create_leaf(object_infos, order, nodes, global_bbox),
+        }
+
+        nodes.len() - 1
+    }
+
+    /// Creates a leaf node with all of the objects.
+    fn create_leaf(
+        object_infos: &[ObjectInfo],
+        order: &mut Vec<u32>,
+        nodes: &mut Vec<Node>,
+        global_bbox: BBox3<f64>,
+    ) {
+        let index = order.len();
+        order.extend(
+            object_infos
+                .iter()
+                .map(|object_info| object_info.index as u32),
+        );
+        nodes.push(Node {
+            bbox: global_bbox,
+            node_type: NodeType::Leaf {
+                index,
+                count: object_infos.len(),
+            },
+        })
+    }
+
+    /// Attempts to split the cluster along a given axis. Returns a pair of slices and the axis where the split occured
+    /// if a split was performed. If no split was performed (because it wasn't worth it), then `None` is returned.
+    ///
+    /// # Arguments
+    /// * `object_infos` - A collection of information about the objects we are trying to split.
+    /// * `global_bound` - The overall bound of all of the objects that we are trying to split.
+    fn split_clusters(
+        object_infos: &mut [ObjectInfo],
+        global_bbox: BBox3<f64>,
+    ) -> Option<(&mut [ObjectInfo], &mut [ObjectInfo], usize)> {
+        // These values are used for the regularization factor:
+        let bbox_diagonal = global_bbox.diagonal();
+        let bbox_max_length = bbox_diagonal[bbox_diagonal.max_dim()];
+
+        let mut global_min_cost = f64::INFINITY;
+        let mut global_min_bin = 0;
+        let mut global_min_axis = 0;
+
+        // The objects are binned by their centroids. If all of them coincide, there is no way to split them:
+        let centroid_bbox = object_infos
+            .iter()
+            .fold(BBox3::new_initial(), |accum, object_info| {
+                accum.combine_pnt(object_info.centroid)
+            });
+        let centroid_diagonal = centroid_bbox.diagonal();
+        if centroid_diagonal[centroid_diagonal.max_dim()] <= 0.0 {
+            return None;
+        }
+
+        // Stores all of the potential splits across the different axises (there are 3 of them):
+        let mut global_bins = [[SAHBin::new(); Self::SAH_BIN_COUNT]; 3];
+
+        // Look for the best split across all of the different axises:
+        for (axis, bins) in global_bins.iter_mut().enumerate() {
+            // All of the objects would end up in the same bin:
+            if centroid_diagonal[axis] <= 0.0 {
+                continue;
+            }
+
+            // Go through all the objects and place them into different sets of buckets:
+            for object_info in object_infos.iter() {
+                // Get the bucket index for the current primitive:
+                let b = Self::sah_bin(centroid_bbox, object_info.centroid, axis);
+                bins[b] = bins[b].add_object(object_info.bbox);
+            }
+
+            let mut min_cost = f64::INFINITY;
+            let mut min_bin = 0;
+
+            // Find the bin that would lead to the best heuristic for this axis:
+            for b in 0..(Self::SAH_BIN_COUNT - 1) {
+                // Combine everything up to bin b (inclusive):
+                let left_bins = bins[0..=b]
+                    .iter()
+                    .fold(SAHBin::new(), |accum, &bin| accum.combine(bin));
+
+                // Combine everything after bin b:
+                let right_bins = bins[(b + 1)..Self::SAH_BIN_COUNT]
+                    .iter()
+                    .fold(SAHBin::new(), |accum, &bin| accum.combine(bin));
+
+                let cost = 1.0
+                    + ((left_bins.count as f64) * left_bins.bbox.surface_area()
+                        + (right_bins.count as f64) * right_bins.bbox.surface_area())
+                        / global_bbox.surface_area();
+
+                if cost < min_cost {
+                    min_cost = cost;
+                    min_bin = b;
+                }
+            }
+
+            // Check how this cost compares to the other axis.
+            if min_cost < global_min_cost {
+                global_min_cost = min_cost;
+                global_min_bin = min_bin;
+                global_min_axis = axis;
+            }
+        }
+
+        // Now check if we should perform a split or not. If we assume that every leaf has a cost of 1, then we have
+        // that it's not worth it if the cost is greater or equal to the number of objects:
+        if global_min_cost >= (object_infos.len() as f64) {
+            return None;
+        }
+
+        let is_first = |object_info: &ObjectInfo| {
+            Self::sah_bin(centroid_bbox, object_info.centroid, global_min_axis) <= global_min_bin
+        };
+
+        // If the split would leave one of the sides empty, split at the median along the largest axis instead:
+        let first_count = object_infos.iter().filter(|&o| is_first(o)).count();
+        if first_count == 0 || first_count == object_infos.len() {
+            let axis = centroid_diagonal.max_dim();
+            let mid = object_infos.len() / 2;
+            order_stat::kth_by(object_infos, mid, |a, b| {
+                a.centroid[axis]
+                    .partial_cmp(&b.centroid[axis])
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+            let (first_part, second_part) = object_infos.split_at_mut(mid);
+            return Some((first_part, second_part, axis));
+        }
+
+        // Now we go ahead and perform the partition:
+        let (first_part, second_part) = partition::partition(object_infos, is_first);
+
+        Some((first_part, second_part, global_min_axis))
+    }
+
+    /// Returns the SAH bin of the centroid along the axis.
+    fn sah_bin(centroid_bbox: BBox3<f64>, centroid: Vec3<f64>, axis: usize) -> usize {
+        let b = (Self::SAH_BIN_COUNT as f64) * centroid_bbox.offset(centroid)[axis];
+        if b >= (Self::SAH_BIN_COUNT as f64) {
+            Self::SAH_BIN_COUNT - 1
+        } else {
+            b as usize
+        }
+    }
+}
+
+impl<Object: BVHObject + Send + Sync> BVH<Object>
+where
+    Object::UserData: Sync,
+{
+    /// Constructs a BVH with the specified algorithm.
+    pub fn build(
+        objects: &[Object],
+        max_per_leaf: usize,
+        user_data: &Object::UserData,
+        algorithm: BuildAlgorithm,
+    ) -> Self {
+        match algorithm {
+            BuildAlgorithm::Sah => Self::new_parallel(objects, max_per_leaf, user_data),
+            BuildAlgorithm::Lbvh => Self::new_lbvh(objects, max_per_leaf, user_data),
+            BuildAlgorithm::Sbvh => {
+                Self::new_sbvh(objects, max_per_leaf, user_data, SBVH_DEFAULT_ALPHA)
+            }
+        }
+    }
+
+    /// Subtrees with fewer objects than this are constructed on a single thread.
+    const PARALLEL_BUILD_THRESHOLD: usize = 1 << 16;
+
+    /// Same as `new`, but the two subtrees of large nodes are constructed in parallel (with rayon).
+    /// The resulting BVH is exactly the same as the one constructed by `new`.
+    pub fn new_parallel(
+        objects: &[Object],
+        max_per_leaf: usize,
+        user_data: &Object::UserData,
+    ) -> Self {
+        let (mut object_infos, global_bbox) = Self::object_infos(objects, user_data);
+
+        let (order, mut nodes) =
+            Self::rec_construct_bvh_parallel(&mut object_infos, max_per_leaf, global_bbox, 0);
+
+        nodes.shrink_to_fit();
+
+        Self::from_parts(objects, order, nodes, global_bbox)
+    }
+
+    /// Same as `rec_construct_bvh`, except that every subtree gets its own object order and nodes
+    /// (with indices relative to the subtree), which are then merged. Because the first subtree is always
+    /// merged before the second one, the result is the same as the one from `rec_construct_bvh`.
+    fn rec_construct_bvh_parallel(
+        object_infos: &mut [ObjectInfo],
+        max_per_leaf: usize,
+        global_bbox: BBox3<f64>,
+        depth: usize,
+    ) -> (Vec<u32>, Vec<Node>) {
+        let mut order = Vec::new();
+        let mut nodes = Vec::new();
+
+        // Nodes at the maximum depth become leaves in `rec_construct_bvh`:
+        if object_infos.len() < Self::PARALLEL_BUILD_THRESHOLD.max(max_per_leaf)
+            || depth >= Self::MAX_DEPTH
+        {
+            Self::rec_construct_bvh(
+                object_infos,
+                &mut order,
+                &mut nodes,
+                max_per_leaf,
+                global_bbox,
+                depth,
+            );
+            return (order, nodes);
+        }
+
+        let (first_object_infos, second_object_infos, axis) =
+            match Self::split_clusters(object_infos, global_bbox) {
+                Some(split) => split,
+                None => {
+                    Self::rec_construct_bvh(
+                        object_infos,
+                        &mut order,
+                        &mut nodes,
+                        max_per_leaf,
+                        global_bbox,
+                        depth,
+                    );
+                    return (order, nodes);
+                }
+            };
+
+        let bbox_of = |object_infos: &[ObjectInfo]| {
+            object_infos
+                .iter()
+                .fold(BBox3::new_initial(), |accum, object_info| {
+                    accum.combine_bnd(object_info.bbox)
+                })
+        };
+        let first_global_bbox = bbox_of(first_object_infos);
+        let second_global_bbox = bbox_of(second_object_infos);
+
+        // The subtrees are built as tasks of rayon's thread pool (so that the number of threads doesn't
+        // grow with the size of the tree):
+        let ((first_order, first_nodes), (second_order, second_nodes)) = rayon::join(
+            || {
+                Self::rec_construct_bvh_parallel(
+                    first_object_infos,
+                    max_per_leaf,
+                    first_global_bbox,
+                    depth + 1,
+                )
+            },
+            || {
+                Self::rec_construct_bvh_parallel(
+                    second_object_infos,
+                    max_per_leaf,
+                    second_global_bbox,
+                    depth + 1,
+                )
+            },
+        );
+
+        // Merge the two subtrees, offsetting the indices of the second one:
+        let object_offset = first_order.len();
+        let node_offset = first_nodes.len();
+        order.reserve(first_order.len() + second_order.len());
+        order.extend(first_order);
+        order.extend(second_order);
+
+        nodes.reserve(first_nodes.len() + second_nodes.len() + 1);
+        nodes.extend(first_nodes);
+        nodes.extend(second_nodes.into_iter().map(|node| Node {
+            bbox: node.bbox,
+            node_type: match node.node_type {
+                NodeType::Leaf { index, count } => NodeType::Leaf {
+                    index: index + object_offset,
+                    count,
+                },
+                NodeType::Internal {
+                    axis,
+                    first,
+                    second,
+                } => NodeType::Internal {
+                    axis,
+                    first: first + node_offset,
+                    second: second + node_offset,
+                },
+            },
+        }));
+
+        // The roots of the subtrees are the last nodes of each:
+        let first = node_offset - 1;
+        let second = nodes.len() - 1;
+        nodes.push(Node {
+            bbox: global_bbox,
+            node_type: NodeType::Internal {
+                axis,
+                first,
+                second,
+            },
+        });
+
+        (order, nodes)
+    }
+}
+
+/// The objects that were already visited while traversing a BVH with spatial splits (where the same object
+/// may be referenced by multiple leaves), as a bitset of the indices of the original objects.
+#[derive(Default)]
+struct VisitedObjects {
+    bits: Vec<u64>,
+}
+
+impl VisitedObjects {
+    /// Marks the object as visited. Returns false if it already was visited.
+    fn insert(&mut self, index: u32) -> bool {
+        let word = (index / 64) as usize;
+        let bit = 1 << (index % 64);
+        if word >= self.bits.len() {
+            self.bits.resize(word + 1, 0);
+        }
+        let first_visit = (self.bits[word] & bit) == 0;
+        self.bits[word] |= bit;
+        first_visit
+    }
+}
+
+/// Returns the bounding box of the overlap between the two bounding boxes (if they overlap).
+fn bbox_overlap(a: BBox3<f64>, b: BBox3<f64>) -> Option<BBox3<f64>> {
+    let pmin = a.pmin.max(b.pmin);
+    let pmax = a.pmax.min(b.pmax);
+    if pmin.x > pmax.x || pmin.y > pmax.y || pmin.z > pmax.z {
+        None
+    } else {
+        Some(BBox3 { pmin, pmax })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Node {
+    bbox: BBox3<f64>,
+    node_type: NodeType,
+}
+
+/// A node for BVH stuff. Note that this probably isn't very
+#[derive(Clone, Copy, Debug)]
+enum NodeType {
+    Internal {
+        axis: usize, // The axis where we split
+        first: usize,
+        second: usize,
+    },
+    Leaf {
+        index: usize, // This specifies the range over the objects that belong to it
+        count: usize,
+    },
+}
+
+struct ObjectInfo {
+    index: usize,     // The index of the light
+    bbox: BBox3<f64>, // The bound over the lights (TODO: make bounds generic?)
+    centroid: Vec3<f64>,
+}
+
+/// The bins that we use to traverse the BVH. Note that
+/// it's aligned to a cache line (64 bytes).
+#[derive(Clone, Copy, Debug)]
+struct SAHBin {
+    bbox: BBox3<f64>,
+    count: u32,
+}
+
+impl SAHBin {
+    fn new() -> Self {
+        SAHBin {
+            bbox: BBox3::new_initial(),
+            count: 0,
+        }
+    }
+
+    /// Combines two different bins.
+    fn combine(self, o: SAHBin) -> Self {
+        SAHBin {
+            bbox: self.bbox.combine_bnd(o.bbox),
+            count: self.count + o.count,
+        }
+    }
+
+    /// Updates the bin with an object's bbox.
+    fn add_object(self, bbox: BBox3<f64>) -> Self {
+        SAHBin {
+            bbox: self.bbox.combine_bnd(bbox),
+            count: self.count + 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::mesh::{MeshData, Triangle, UvProjection};
+
+    // Long and thin triangles that all cross each other around the origin (so that their bounding
+    // boxes are nested), which can only be separated well by splitting them spatially.
+    fn crossing_triangles(count: usize) -> (Vec<Triangle>, MeshData) {
+        let mut pos = Vec::new();
+        let mut triangles = Vec::new();
+        for i in 0..count {
+            let y = 0.1 + (i as f32);
+            let index = pos.len() as u32;
+            pos.push(Vec3 {
+                x: -10.0,
+                y,
+                z: -1.0,
+            });
+            pos.push(Vec3 {
+                x: 10.0,
+                y: -y,
+                z: 1.0,
+            });
+            pos.push(Vec3 {
+                x: 0.0,
+                y: 0.1,
+                z: 0.0,
+            });
+            triangles.push(Triangle {
+                indices: [index, index + 1, index + 2],
+                attribute_id: i as u32,
+            });
+        }
+        let mesh = mesh_data(&triangles, pos);
+        (triangles, mesh)
+    }
+
+    fn mesh_data(triangles: &[Triangle], pos: Vec<Vec3<f32>>) -> MeshData {
+        MeshData {
+            triangles: triangles.to_vec(),
+            pos,
+            nrm: Vec::new(),
+            tan: Vec::new(),
+            tan_sgn: Vec::new(),
+            uvs: Vec::new(),
+            col: Vec::new(),
+            creases: Vec::new(),
+            compact: None,
+            uv_projection: UvProjection::Fixed,
+        }
+    }
+
+    #[test]
+    fn objects_straddling_a_split_are_visited_once() {
+        let (triangles, mesh) = crossing_triangles(8);
+        let bvh = BVH::new_sbvh(&triangles, 2, &mesh, 0.0);
+
+        // Make sure the test actually has objects in multiple leaves:
+        let mut references = vec![0; triangles.len()];
+        for &index in &bvh.order {
+            references[index as usize] += 1;
+        }
+        assert!(
+            references.iter().any(|&count| count > 1),
+            "{:?}",
+            references
+        );
+
+        // A ray along the x axis that goes through every leaf:
+        let ray = Ray::new(
+            Vec3 {
+                x: -20.0,
+                y: 0.05,
+                z: 0.0,
+            },
+            Vec3 {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            0.0,
+        );
+        let mut visits = vec![0; triangles.len()];
+        bvh.visit_all(ray, |triangle| {
+            visits[triangle.attribute_id as usize] += 1;
+            TraversalControl::Continue
+        });
+        assert!(visits.iter().all(|&count| count <= 1), "{:?}", visits);
+
+        // Every triangle that is hit is reported once, and they are the same ones the bvh without
+        // spatial splits reports:
+        let count_hits = |bvh: &BVH<Triangle>| {
+            let mut hits = 0;
+            bvh.intersect_all(ray, &mesh, &mut |_| {
+                hits += 1;
+                TraversalControl::Continue
+            });
+            hits
+        };
+        let hits = count_hits(&bvh);
+        assert!(hits > 0);
+        assert_eq!(hits, count_hits(&BVH::new(&triangles, 2, &mesh)));
+    }
+
+    #[test]
+    fn spatial_splits_dont_miss_any_hits() {
+        use rand::{Rng, SeedableRng};
+
+        // Long diagonal beams crossing a box, each one made of two thin triangles:
+        let mut rng = rand_pcg::Pcg32::seed_from_u64(3);
+        let random_point = |rng: &mut rand_pcg::Pcg32| Vec3 {
+            x: rng.gen_range(-10.0f32, 10.0),
+            y: rng.gen_range(-10.0f32, 10.0),
+            z: rng.gen_range(-10.0f32, 10.0),
+        };
+        let mut pos = Vec::new();
+        let mut triangles = Vec::new();
+        for i in 0..200 {
+            let (start, end) = (random_point(&mut rng), random_point(&mut rng));
+            let offset = Vec3 {
+                x: 0.05,
+                y: 0.05,
+                z: 0.0,
+            };
+            let index = pos.len() as u32;
+            pos.extend_from_slice(&[start, end, start + offset, end + offset]);
+            for &indices in &[
+                [index, index + 1, index + 2],
+                [index + 2, index + 1, index + 3],
+            ] {
+                triangles.push(Triangle {
+                    indices,
+                    attribute_id: i,
+                });
+            }
+        }
+        let mesh = mesh_data(&triangles, pos);
+
+        for &alpha in &[0.0, SBVH_DEFAULT_ALPHA] {
+            let bvh = BVH::new_sbvh(&triangles, 2, &mesh, alpha);
+            assert!(bvh.order.len() > triangles.len());
+
+            for _ in 0..2000 {
+                let org = random_point(&mut rng);
+                let target = random_point(&mut rng);
+                let ray = Ray::new(
+                    Vec3 {
+                        x: org.x as f64,
+                        y: org.y as f64,
+                        z: org.z as f64,
+                    },
+                    Vec3 {
+                        x: (target.x - org.x) as f64,
+                        y: (target.y - org.y) as f64,
+                        z: (target.z - org.z) as f64,
+                    }
+                    .normalize(),
+                    0.0,
+                );
+
+                let closest = triangles
+                    .iter()
+                    .filter_map(|triangle| triangle.intersect(ray, &mesh))
+                    .map(|interaction| interaction.t.to_f64())
+                    .fold(f64::INFINITY, f64::min);
+                assert_eq!(bvh.intersect_test(ray, &mesh), closest.is_finite());
+                let t = bvh
+                    .intersect(ray, &mesh)
+                    .map_or(f64::INFINITY, |hit| hit.t.to_f64());
+                assert_eq!(t, closest);
+            }
+        }
+    }
+
+    // An object that is just a bounding box.
+    #[derive(Clone)]
+    pub(super) struct BoxObject(pub(super) BBox3<f64>);
+
+    impl BVHObject for BoxObject {
+        type UserData = ();
+
+        fn get_bbox(&self, _: &()) -> BBox3<f64> {
+            self.0
+        }
+
+        fn intersect_test(&self, ray: Ray<f64>, _: &()) -> bool {
+            self.0
+                .intersect_test(ray, ray.dir.inv_scale(1.0), ray.dir.comp_wise_is_neg())
+        }
+
+        fn intersect(&self, _: Ray<f64>, _: &()) -> Option<Interaction> {
+            None
+        }
+    }
+
+    #[test]
+    fn deep_bvhs_can_be_traversed() {
+        // Boxes that get further apart exponentially, so that every split only separates the last one:
+        let boxes: Vec<_> = (0..150)
+            .map(|i| {
+                let x = 16f64.powi(i);
+                BoxObject(BBox3 {
+                    pmin: Vec3 { x, y: 0.0, z: 0.0 },
+                    pmax: Vec3 {
+                        x: 2.0 * x,
+                        y: 1.0,
+                        z: 1.0,
+                    },
+                })
+            })
+            .collect();
+        let bvh = BVH::new(&boxes, 1, &());
+        // Deeper than the stacks of the traversal, but the last boxes end up in a leaf at the maximum
+        // depth:
+        let max_depth = bvh.node_bounds().map(|(depth, _)| depth).max().unwrap();
+        assert!(max_depth > 64, "{}", max_depth);
+        assert_eq!(max_depth as usize, BVH::<BoxObject>::MAX_DEPTH);
+
+        for object in &boxes {
+            let ray = Ray::new(
+                Vec3 {
+                    x: 1.5 * object.0.pmin.x,
+                    y: 0.5,
+                    z: 2.0,
+                },
+                Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: -1.0,
+                },
+                0.0,
+            );
+            assert!(bvh.intersect_test(ray, &()));
+            let mut visited = 0;
+            bvh.visit_all(ray, |visited_object| {
+                if visited_object.0.pmin.x == object.0.pmin.x {
+                    visited += 1;
+                }
+                TraversalControl::Continue
+            });
+            assert_eq!(visited, 1);
+        }
+    }
+
+    #[test]
+    fn objects_with_the_same_centroid_end_up_in_a_leaf() {
+        // Boxes that are nested in each other:
+        let boxes: Vec<_> = (1..20)
+            .map(|i| {
+                let r = i as f64;
+                BoxObject(BBox3 {
+                    pmin: Vec3 {
+                        x: -r,
+                        y: -r,
+                        z: -r,
+                    },
+                    pmax: Vec3 { x: r, y: r, z: r },
+                })
+            })
+            .collect();
+        let bvh = BVH::new(&boxes, 1, &());
+        assert_eq!(bvh.nodes.len(), 1);
+
+        let ray = Ray::new(
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -50.0,
+            },
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            0.0,
+        );
+        let mut visited = 0;
+        bvh.visit_all(ray, |_| {
+            visited += 1;
+            TraversalControl::Continue
+        });
+        assert_eq!(visited, boxes.len());
+    }
+
+    #[test]
+    fn parallel_construction_matches_serial_construction() {
+        // Enough boxes that the top of the tree is split in parallel:
+        let num_boxes = 2 * BVH::<BoxObject>::PARALLEL_BUILD_THRESHOLD;
+        let boxes: Vec<_> = (0..num_boxes)
+            .map(|i| {
+                let (x, y, z) = ((i % 64) as f64, ((i / 64) % 64) as f64, (i / 4096) as f64);
+                let size = 0.5 + ((i * 7919) % 13) as f64 * 0.1;
+                BoxObject(BBox3 {
+                    pmin: Vec3 { x, y, z },
+                    pmax: Vec3 {
+                        x: x + size,
+                        y: y + size,
+                        z: z + size,
+                    },
+                })
+            })
+            .collect();
+        let serial = BVH::new(&boxes, 4, &());
+        let parallel = BVH::new_parallel(&boxes, 4, &());
+        assert_eq!(serial.order, parallel.order);
+        assert_eq!(
+            format!("{:?}", serial.nodes),
+            format!("{:?}", parallel.nodes)
+        );
+    }
+}
@@ -0,0 +1,422 @@
+// Collapses the binary BVH into 4-wide nodes so that the bounding boxes of all 4 children can be
+// tested at once (with SSE on x86_64). This roughly halves the number of traversal steps (and
+// dependent memory loads) per ray, so traversal should be noticeably faster than the binary
+// version. The objects, leaves and the order in which they are visited stay the same.
+
+use crate::bvh::stack::TraversalStack;
+use crate::bvh::{BVHObject, Node, NodeType, BVH};
+use crate::interaction::Interaction;
+use pmath::bbox::BBox3;
+use pmath::ray::Ray;
+
+#[derive(Clone, Copy, Debug)]
+enum WideChild {
+    Empty,
+    Node(usize),
+    Leaf { index: usize, count: usize },
+}
+
+/// A node with (up to) 4 children. The bounding boxes are stored in SoA layout so that they can be
+/// loaded directly into SIMD registers.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, align(16))]
+pub(super) struct WideNode {
+    // The min x, y, z followed by the max x, y, z of each of the children:
+    bounds: [[f32; 4]; 6],
+    children: [WideChild; 4],
+    // The axes of the collapsed binary nodes (the top one, followed by its first and second child).
+    // These are used to visit the children from near to far:
+    axes: [usize; 3],
+}
+
+impl WideNode {
+    fn new_empty() -> Self {
+        WideNode {
+            bounds: [
+                [f32::INFINITY; 4],
+                [f32::INFINITY; 4],
+                [f32::INFINITY; 4],
+                [f32::NEG_INFINITY; 4],
+                [f32::NEG_INFINITY; 4],
+                [f32::NEG_INFINITY; 4],
+            ],
+            children: [WideChild::Empty; 4],
+            axes: [0; 3],
+        }
+    }
+
+    fn set_child(&mut self, slot: usize, bbox: BBox3<f64>, child: WideChild) {
+        // Round outwards so that the test stays conservative:
+        self.bounds[0][slot] = round_down(bbox.pmin.x);
+        self.bounds[1][slot] = round_down(bbox.pmin.y);
+        self.bounds[2][slot] = round_down(bbox.pmin.z);
+        self.bounds[3][slot] = round_up(bbox.pmax.x);
+        self.bounds[4][slot] = round_up(bbox.pmax.y);
+        self.bounds[5][slot] = round_up(bbox.pmax.z);
+        self.children[slot] = child;
+    }
+
+    /// Returns a bit mask of which children's bounding boxes the ray intersects.
+    #[cfg(target_arch = "x86_64")]
+    fn intersect_children(&self, ray: &WideRay) -> u32 {
+        use std::arch::x86_64::*;
+
+        // SSE is always available on x86_64:
+        unsafe {
+            let mut t_min = _mm_set1_ps(ray.t_near);
+            let mut t_max = _mm_set1_ps(ray.t_far);
+            for axis in 0..3 {
+                let org = _mm_set1_ps(ray.org[axis]);
+                let inv_dir = _mm_set1_ps(ray.inv_dir[axis]);
+                let near = _mm_load_ps(self.bounds[ray.near[axis]].as_ptr());
+                let far = _mm_load_ps(self.bounds[ray.far[axis]].as_ptr());
+                let t0 = _mm_mul_ps(_mm_sub_ps(near, org), inv_dir);
+                let t1 = _mm_mul_ps(_mm_sub_ps(far, org), inv_dir);
+                // If one of the arguments is NaN, the second one is returned. This way a NaN
+                // (from 0 * inf) never ends up in the result:
+                t_min = _mm_max_ps(t0, t_min);
+                t_max = _mm_min_ps(t1, t_max);
+            }
+            let t_max = _mm_mul_ps(t_max, _mm_set1_ps(ERROR_SCALE));
+            _mm_movemask_ps(_mm_cmple_ps(t_min, t_max)) as u32
+        }
+    }
+
+    /// Returns a bit mask of which children's bounding boxes the ray intersects.
+    #[cfg(not(target_arch = "x86_64"))]
+    fn intersect_children(&self, ray: &WideRay) -> u32 {
+        let mut mask = 0;
+        for slot in 0..4 {
+            let mut t_min = ray.t_near;
+            let mut t_max = ray.t_far;
+            for axis in 0..3 {
+                let t0 = (self.bounds[ray.near[axis]][slot] - ray.org[axis]) * ray.inv_dir[axis];
+                let t1 = (self.bounds[ray.far[axis]][slot] - ray.org[axis]) * ray.inv_dir[axis];
+                t_min = if t0 > t_min { t0 } else { t_min };
+                t_max = if t1 < t_max { t1 } else { t_max };
+            }
+            if t_min <= t_max * ERROR_SCALE {
+                mask |= 1 << slot;
+            }
+        }
+        mask
+    }
+
+    /// Pushes the children that were hit onto the stack such that the nearest one is popped first
+    /// (the same order the binary traversal uses).
+    fn push_children(
+        &self,
+        mask: u32,
+        ray: &WideRay,
+        stack: &mut TraversalStack<[WideChild; 128]>,
+    ) {
+        // The order in which the slots should be visited:
+        let pair_order = |axis: usize, pair: usize| {
+            if ray.is_dir_neg[axis] {
+                [pair + 1, pair]
+            } else {
+                [pair, pair + 1]
+            }
+        };
+        let first = pair_order(self.axes[1], 0);
+        let second = pair_order(self.axes[2], 2);
+        let order = if ray.is_dir_neg[self.axes[0]] {
+            [second[0], second[1], first[0], first[1]]
+        } else {
+            [first[0], first[1], second[0], second[1]]
+        };
+
+        for &slot in order.iter().rev() {
+            if (mask & (1 << slot)) != 0 {
+                stack.push(self.children[slot]);
+            }
+        }
+    }
+}
+
+/// The ray in the layout used when traversing the wide nodes.
+struct WideRay {
+    org: [f32; 3],
+    inv_dir: [f32; 3],
+    is_dir_neg: [bool; 3],
+    // Which rows of the bounds are the near and far planes along each axis:
+    near: [usize; 3],
+    far: [usize; 3],
+    t_near: f32,
+    t_far: f32,
+}
+
+impl WideRay {
+    fn new(ray: Ray<f64>) -> Self {
+        let inv_dir = ray.dir.inv_scale(1.0);
+        let is_dir_neg = ray.dir.comp_wise_is_neg();
+        let is_dir_neg = [is_dir_neg.x, is_dir_neg.y, is_dir_neg.z];
+        let near = |axis: usize| if is_dir_neg[axis] { axis + 3 } else { axis };
+        let far = |axis: usize| if is_dir_neg[axis] { axis } else { axis + 3 };
+
+        WideRay {
+            org: [ray.org.x as f32, ray.org.y as f32, ray.org.z as f32],
+            inv_dir: [inv_dir.x as f32, inv_dir.y as f32, inv_dir.z as f32],
+            is_dir_neg,
+            near: [near(0), near(1), near(2)],
+            far: [far(0), far(1), far(2)],
+            t_near: round_down(ray.t_near.max(0.0)),
+            t_far: round_up(ray.t_far),
+        }
+    }
+}
+
+/// Accounts for the error of computing the intersection in single precision (including the error
+/// of converting the ray origin and direction).
+const ERROR_SCALE: f32 = 1.0 + 8.0 * f32::EPSILON;
+
+/// Converts to f32, rounding towards negative infinity.
+fn round_down(v: f64) -> f32 {
+    let r = v as f32;
+    if (r as f64) <= v {
+        r
+    } else if r == 0.0 {
+        -f32::from_bits(1)
+    } else if r > 0.0 {
+        f32::from_bits(r.to_bits() - 1)
+    } else {
+        f32::from_bits(r.to_bits() + 1)
+    }
+}
+
+/// Converts to f32, rounding towards positive infinity.
+fn round_up(v: f64) -> f32 {
+    -round_down(-v)
+}
+
+/// Recursively collapses the binary node at `node_index` (and everything below it) into wide nodes.
+/// Returns the index of the wide node that was added.
+fn collapse(nodes: &[Node], node_index: usize, wide_nodes: &mut Vec<WideNode>) -> usize {
+    let wide_index = wide_nodes.len();
+    wide_nodes.push(WideNode::new_empty());
+
+    let mut wide_node = WideNode::new_empty();
+    let node = nodes[node_index];
+    match node.node_type {
+        // This only happens when the root is a leaf:
+        NodeType::Leaf { index, count } => {
+            wide_node.set_child(0, node.bbox, WideChild::Leaf { index, count })
+        }
+        NodeType::Internal {
+            axis,
+            first,
+            second,
+        } => {
+            wide_node.axes[0] = axis;
+            for (pair, &child_index) in [first, second].iter().enumerate() {
+                let child = nodes[child_index];
+                match child.node_type {
+                    NodeType::Leaf { index, count } => {
+                        wide_node.set_child(2 * pair, child.bbox, WideChild::Leaf { index, count })
+                    }
+                    NodeType::Internal {
+                        axis,
+                        first,
+                        second,
+                    } => {
+                        wide_node.axes[1 + pair] = axis;
+                        for (i, &grandchild_index) in [first, second].iter().enumerate() {
+                            let grandchild = nodes[grandchild_index];
+                            let wide_child = match grandchild.node_type {
+                                NodeType::Leaf { index, count } => WideChild::Leaf { index, count },
+                                NodeType::Internal { .. } => {
+                                    WideChild::Node(collapse(nodes, grandchild_index, wide_nodes))
+                                }
+                            };
+                            wide_node.set_child(2 * pair + i, grandchild.bbox, wide_child);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    wide_nodes[wide_index] = wide_node;
+    wide_index
+}
+
+/// Builds the wide nodes from the binary nodes. The root ends up at index 0.
+pub(super) fn build_wide_nodes(nodes: &[Node]) -> Vec<WideNode> {
+    let mut wide_nodes = Vec::with_capacity(nodes.len() / 2 + 1);
+    if !nodes.is_empty() {
+        collapse(nodes, nodes.len() - 1, &mut wide_nodes);
+    }
+    wide_nodes.shrink_to_fit();
+    wide_nodes
+}
+
+impl<Object: BVHObject> BVH<Object> {
+    /// Same as `intersect_test`, but skips the object `exclude` (the index of the object in the slice the
+    /// BVH was built from).
+    pub fn intersect_test_excluding(
+        &self,
+        ray: Ray<f64>,
+        user_data: &Object::UserData,
+        exclude: Option<u32>,
+    ) -> bool {
+        let wide_ray = WideRay::new(ray);
+
+        let mut stack = TraversalStack::<[_; 128]>::new();
+        stack.push(WideChild::Node(0));
+
+        while let Some(child) = stack.pop() {
+            match child {
+                WideChild::Node(index) => {
+                    let node = &self.wide_nodes[index];
+                    node.push_children(node.intersect_children(&wide_ray), &wide_ray, &mut stack);
+                }
+                WideChild::Leaf { index, count } => {
+                    let objects = self.objects[index..(index + count)].iter();
+                    for (object, &i) in objects.zip(&self.order[index..(index + count)]) {
+                        if (exclude != Some(i)) && object.intersect_test(ray, user_data) {
+                            return true;
+                        }
+                    }
+                }
+                WideChild::Empty => (),
+            }
+        }
+
+        false
+    }
+
+    /// Same as `intersect`, but skips the object `exclude`. Also returns the index of the object that was
+    /// hit (in the slice the BVH was built from).
+    pub fn intersect_excluding(
+        &self,
+        ray: Ray<f64>,
+        user_data: &Object::UserData,
+        exclude: Option<u32>,
+    ) -> Option<(Interaction, u32)> {
+        let mut wide_ray = WideRay::new(ray);
+        let mut ray = ray;
+
+        let mut stack = TraversalStack::<[_; 128]>::new();
+        stack.push(WideChild::Node(0));
+
+        let mut hit = None;
+        while let Some(child) = stack.pop() {
+            match child {
+                WideChild::Node(index) => {
+                    let node = &self.wide_nodes[index];
+                    node.push_children(node.intersect_children(&wide_ray), &wide_ray, &mut stack);
+                }
+                WideChild::Leaf { index, count } => {
+                    // Because we update the extent, every new hit is a closer hit:
+                    let objects = self.objects[index..(index + count)].iter();
+                    for (object, &i) in objects.zip(&self.order[index..(index + count)]) {
+                        if exclude == Some(i) {
+                            continue;
+                        }
+                        if let Some(interaction) = object.intersect(ray, user_data) {
+                            ray.t_far = interaction.t;
+                            wide_ray.t_far = round_up(interaction.t);
+                            hit = Some((interaction, i));
+                        }
+                    }
+                }
+                WideChild::Empty => (),
+            }
+        }
+
+        hit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::tests::BoxObject;
+    use pmath::vector::Vec3;
+    use rand::{Rng, SeedableRng};
+
+    // The leaves (the index of their first object) the binary traversal visits, in order:
+    fn binary_leaf_visits(bvh: &BVH<BoxObject>, ray: Ray<f64>) -> Vec<usize> {
+        let inv_dir = ray.dir.inv_scale(1.0);
+        let is_dir_neg = ray.dir.comp_wise_is_neg();
+        let mut visits = Vec::new();
+        let mut stack = vec![bvh.root()];
+        while let Some(node_index) = stack.pop() {
+            let node = bvh.nodes[node_index];
+            if node.bbox.intersect_test(ray, inv_dir, is_dir_neg) {
+                match node.node_type {
+                    NodeType::Leaf { index, .. } => visits.push(index),
+                    NodeType::Internal {
+                        axis,
+                        first,
+                        second,
+                    } => {
+                        if is_dir_neg[axis] {
+                            stack.push(first);
+                            stack.push(second);
+                        } else {
+                            stack.push(second);
+                            stack.push(first);
+                        }
+                    }
+                }
+            }
+        }
+        visits
+    }
+
+    fn wide_leaf_visits(bvh: &BVH<BoxObject>, ray: Ray<f64>) -> Vec<usize> {
+        let wide_ray = WideRay::new(ray);
+        let mut visits = Vec::new();
+        let mut stack = TraversalStack::<[_; 128]>::new();
+        stack.push(WideChild::Node(0));
+        while let Some(child) = stack.pop() {
+            match child {
+                WideChild::Node(index) => {
+                    let node = &bvh.wide_nodes[index];
+                    node.push_children(node.intersect_children(&wide_ray), &wide_ray, &mut stack);
+                }
+                WideChild::Leaf { index, .. } => visits.push(index),
+                WideChild::Empty => (),
+            }
+        }
+        visits
+    }
+
+    #[test]
+    fn wide_traversal_visits_the_same_leaves_as_the_binary_one() {
+        let mut rng = rand_pcg::Pcg32::seed_from_u64(9);
+        let random_vec = |rng: &mut rand_pcg::Pcg32, min: f64, max: f64| Vec3 {
+            x: rng.gen_range(min, max),
+            y: rng.gen_range(min, max),
+            z: rng.gen_range(min, max),
+        };
+
+        let boxes: Vec<_> = (0..500)
+            .map(|_| {
+                let pmin = random_vec(&mut rng, -10.0, 10.0);
+                BoxObject(BBox3 {
+                    pmin,
+                    pmax: pmin + random_vec(&mut rng, 0.05, 1.0),
+                })
+            })
+            .collect();
+        for &max_per_leaf in &[1, 4] {
+            let bvh = BVH::new(&boxes, max_per_leaf, &());
+            for _ in 0..2000 {
+                let mut ray = Ray::new(
+                    random_vec(&mut rng, -15.0, 15.0),
+                    random_vec(&mut rng, -1.0, 1.0),
+                    0.0,
+                );
+                ray.t_far = rng.gen_range(1.0, 40.0);
+
+                let visits = binary_leaf_visits(&bvh, ray);
+                assert_eq!(visits, wide_leaf_visits(&bvh, ray));
+
+                let hit = boxes.iter().any(|object| object.intersect_test(ray, &()));
+                assert_eq!(bvh.intersect_test(ray, &()), hit);
+            }
+        }
+    }
+}
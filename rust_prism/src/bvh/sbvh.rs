@@ -0,0 +1,438 @@
+// This is based on the paper:
+// Spatial Splits in Bounding Volume Hierarchies by
+// Martin Stich, Heiko Friedrich and Andreas Dietrich.
+
+use crate::bvh::{bbox_overlap, BVHObject, Node, NodeType, SAHBin, BVH};
+use pmath::bbox::BBox3;
+
+/// The default value of alpha used by `BuildAlgorithm::Sbvh` (the value suggested in the paper).
+pub const SBVH_DEFAULT_ALPHA: f64 = 1e-5;
+
+/// The number of bins used when looking for object splits (the same as the regular SAH build).
+const OBJECT_BIN_COUNT: usize = 12;
+
+/// The number of bins used when looking for spatial splits.
+const SPATIAL_BIN_COUNT: usize = 32;
+
+/// No more spatial splits are performed once there are this many references per object.
+const MAX_REFERENCES_PER_OBJECT: f64 = 2.0;
+
+/// A reference to an object. The bounding box may be smaller than the object's if it was split.
+#[derive(Clone, Copy, Debug)]
+struct Reference {
+    index: usize,
+    bbox: BBox3<f64>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Split {
+    /// Splits the references by their centroids (the same as the regular SAH split).
+    Object {
+        axis: usize,
+        bin: usize,
+        centroid_bbox: BBox3<f64>,
+    },
+    /// Splits the references by a plane, references that straddle the plane go in both children.
+    Spatial { axis: usize, position: f64 },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct SpatialBin {
+    bbox: BBox3<f64>,
+    // The number of references that start and end in this bin:
+    entries: usize,
+    exits: usize,
+}
+
+struct Builder<'a, Object: BVHObject> {
+    objects: &'a [Object],
+    user_data: &'a Object::UserData,
+    max_per_leaf: usize,
+    // Spatial splits are only considered if the children of the best object split overlap by more
+    // than this surface area:
+    min_overlap: f64,
+    max_references: usize,
+    num_references: usize,
+
+    order: Vec<u32>,
+    nodes: Vec<Node>,
+}
+
+impl<Object: BVHObject> BVH<Object> {
+    /// Constructs a BVH using the SAH, where, besides splitting the objects, nodes can also be split
+    /// spatially. Objects that straddle a spatial split are referenced by both children, which helps a lot
+    /// with long and thin objects (whose bounding boxes would otherwise overlap many others).
+    ///
+    /// # Arguments
+    /// * `alpha` - Spatial splits are only considered when the children of the best object split overlap
+    ///             by more than `alpha` times the surface area of the root. 0 always considers them, while
+    ///             1 (practically) never does.
+    pub fn new_sbvh(
+        objects: &[Object],
+        max_per_leaf: usize,
+        user_data: &Object::UserData,
+        alpha: f64,
+    ) -> Self {
+        let references: Vec<_> = objects
+            .iter()
+            .enumerate()
+            .map(|(index, object)| Reference {
+                index,
+                bbox: object.get_bbox(user_data),
+            })
+            .collect();
+
+        let global_bbox = references
+            .iter()
+            .fold(BBox3::new_initial(), |accum, reference| {
+                accum.combine_bnd(reference.bbox)
+            });
+
+        let mut builder = Builder {
+            objects,
+            user_data,
+            max_per_leaf,
+            min_overlap: alpha * global_bbox.surface_area(),
+            max_references: ((objects.len() as f64) * MAX_REFERENCES_PER_OBJECT) as usize,
+            num_references: objects.len(),
+            order: Vec::with_capacity(objects.len()),
+            nodes: Vec::new(),
+        };
+        builder.build(references, global_bbox);
+
+        let Builder {
+            mut order,
+            mut nodes,
+            ..
+        } = builder;
+        nodes.shrink_to_fit();
+        order.shrink_to_fit();
+
+        let mut bvh = Self::from_parts(objects, order, nodes, global_bbox);
+        bvh.spatial_splits = true;
+        bvh
+    }
+}
+
+impl<'a, Object: BVHObject> Builder<'a, Object> {
+    /// Recursively constructs the BVH. Returns the index of the node that was constructed.
+    fn build(&mut self, references: Vec<Reference>, bbox: BBox3<f64>) -> usize {
+        if references.len() < self.max_per_leaf {
+            return self.create_leaf(&references, bbox);
+        }
+
+        // Like with the regular SAH, every leaf has a cost of 1 per object:
+        let mut best_cost = references.len() as f64;
+        let mut best_split = None;
+
+        let object_overlap = match self.find_object_split(&references, bbox) {
+            Some((cost, split, overlap)) => {
+                if cost < best_cost {
+                    best_cost = cost;
+                    best_split = Some(split);
+                }
+                overlap
+            }
+            None => f64::INFINITY,
+        };
+
+        if object_overlap > self.min_overlap && self.num_references < self.max_references {
+            if let Some((cost, split)) = self.find_spatial_split(&references, bbox) {
+                if cost < best_cost {
+                    best_split = Some(split);
+                }
+            }
+        }
+
+        let split = match best_split {
+            Some(split) => split,
+            None => return self.create_leaf(&references, bbox),
+        };
+
+        let (first_references, second_references) = self.partition(references, split);
+        if first_references.is_empty() || second_references.is_empty() {
+            let references: Vec<_> = first_references
+                .into_iter()
+                .chain(second_references.into_iter())
+                .collect();
+            return self.create_leaf(&references, bbox);
+        }
+
+        let axis = match split {
+            Split::Object { axis, .. } => axis,
+            Split::Spatial { axis, .. } => axis,
+        };
+
+        let first_bbox = references_bbox(&first_references);
+        let second_bbox = references_bbox(&second_references);
+        let first = self.build(first_references, first_bbox);
+        let second = self.build(second_references, second_bbox);
+
+        self.nodes.push(Node {
+            bbox,
+            node_type: NodeType::Internal {
+                axis,
+                first,
+                second,
+            },
+        });
+        self.nodes.len() - 1
+    }
+
+    fn create_leaf(&mut self, references: &[Reference], bbox: BBox3<f64>) -> usize {
+        let index = self.order.len();
+        self.order
+            .extend(references.iter().map(|reference| reference.index as u32));
+        self.nodes.push(Node {
+            bbox,
+            node_type: NodeType::Leaf {
+                index,
+                count: references.len(),
+            },
+        });
+        self.nodes.len() - 1
+    }
+
+    /// Finds the best object split. Returns the cost, the split, and the surface area of the overlap of the
+    /// two children.
+    fn find_object_split(
+        &self,
+        references: &[Reference],
+        bbox: BBox3<f64>,
+    ) -> Option<(f64, Split, f64)> {
+        let area = bbox.surface_area();
+        if area <= 0.0 {
+            return None;
+        }
+
+        let centroid_bbox = references
+            .iter()
+            .fold(BBox3::new_initial(), |accum, reference| {
+                accum.combine_pnt(reference.bbox.centroid())
+            });
+
+        let mut best = None;
+        let mut best_cost = f64::INFINITY;
+        for axis in 0..3 {
+            if centroid_bbox.pmax[axis] <= centroid_bbox.pmin[axis] {
+                continue;
+            }
+
+            let mut bins = [SAHBin::new(); OBJECT_BIN_COUNT];
+            for reference in references {
+                let b = object_bin(centroid_bbox, axis, reference.bbox);
+                bins[b] = bins[b].add_object(reference.bbox);
+            }
+
+            let (first_bins, second_bins) = sweep(&bins, |accum, &bin| accum.combine(bin));
+            for b in 0..(bins.len() - 1) {
+                let first = first_bins[b];
+                let second = second_bins[b + 1];
+                if first.count == 0 || second.count == 0 {
+                    continue;
+                }
+
+                let cost = 1.0
+                    + ((first.count as f64) * first.bbox.surface_area()
+                        + (second.count as f64) * second.bbox.surface_area())
+                        / area;
+                if cost < best_cost {
+                    let overlap = bbox_overlap(first.bbox, second.bbox)
+                        .map_or(0.0, |overlap| overlap.surface_area());
+                    best_cost = cost;
+                    best = Some((
+                        cost,
+                        Split::Object {
+                            axis,
+                            bin: b,
+                            centroid_bbox,
+                        },
+                        overlap,
+                    ));
+                }
+            }
+        }
+
+        best
+    }
+
+    /// Finds the best spatial split. Returns the cost and the split.
+    fn find_spatial_split(
+        &self,
+        references: &[Reference],
+        bbox: BBox3<f64>,
+    ) -> Option<(f64, Split)> {
+        let area = bbox.surface_area();
+        if area <= 0.0 {
+            return None;
+        }
+
+        let mut best = None;
+        let mut best_cost = f64::INFINITY;
+        for axis in 0..3 {
+            let start = bbox.pmin[axis];
+            let extent = bbox.pmax[axis] - start;
+            if extent <= 0.0 {
+                continue;
+            }
+            let bin_width = extent / (SPATIAL_BIN_COUNT as f64);
+            let bin_of = |v: f64| {
+                let b = ((v - start) / bin_width) as isize;
+                b.max(0).min(SPATIAL_BIN_COUNT as isize - 1) as usize
+            };
+
+            let mut bins = [SpatialBin {
+                bbox: BBox3::new_initial(),
+                entries: 0,
+                exits: 0,
+            }; SPATIAL_BIN_COUNT];
+
+            // Chop every reference into the bins it overlaps:
+            for reference in references {
+                let first = bin_of(reference.bbox.pmin[axis]);
+                let last = bin_of(reference.bbox.pmax[axis]);
+                for (b, bin) in bins.iter_mut().enumerate().take(last + 1).skip(first) {
+                    let mut slab = bbox;
+                    slab.pmin[axis] = start + (b as f64) * bin_width;
+                    if b != SPATIAL_BIN_COUNT - 1 {
+                        slab.pmax[axis] = start + ((b + 1) as f64) * bin_width;
+                    }
+                    if let Some(clipped) = self.clip(*reference, slab) {
+                        bin.bbox = bin.bbox.combine_bnd(clipped);
+                    }
+                }
+                bins[first].entries += 1;
+                bins[last].exits += 1;
+            }
+
+            let (first_bins, second_bins) = sweep(&bins, |accum, bin| SpatialBin {
+                bbox: accum.bbox.combine_bnd(bin.bbox),
+                entries: accum.entries + bin.entries,
+                exits: accum.exits + bin.exits,
+            });
+            for b in 0..(SPATIAL_BIN_COUNT - 1) {
+                let first_count = first_bins[b].entries;
+                let second_count = second_bins[b + 1].exits;
+                if first_count == 0 || second_count == 0 {
+                    continue;
+                }
+
+                let cost = 1.0
+                    + ((first_count as f64) * first_bins[b].bbox.surface_area()
+                        + (second_count as f64) * second_bins[b + 1].bbox.surface_area())
+                        / area;
+                if cost < best_cost {
+                    best_cost = cost;
+                    best = Some((
+                        cost,
+                        Split::Spatial {
+                            axis,
+                            position: start + ((b + 1) as f64) * bin_width,
+                        },
+                    ));
+                }
+            }
+        }
+
+        best
+    }
+
+    /// Splits the references into the two children.
+    fn partition(
+        &mut self,
+        references: Vec<Reference>,
+        split: Split,
+    ) -> (Vec<Reference>, Vec<Reference>) {
+        let mut first = Vec::new();
+        let mut second = Vec::new();
+
+        match split {
+            Split::Object {
+                axis,
+                bin,
+                centroid_bbox,
+            } => {
+                for reference in references {
+                    if object_bin(centroid_bbox, axis, reference.bbox) <= bin {
+                        first.push(reference);
+                    } else {
+                        second.push(reference);
+                    }
+                }
+            }
+            Split::Spatial { axis, position } => {
+                for reference in references {
+                    if reference.bbox.pmax[axis] <= position {
+                        first.push(reference);
+                    } else if reference.bbox.pmin[axis] >= position {
+                        second.push(reference);
+                    } else {
+                        // The reference straddles the plane, so it's split in two:
+                        let mut first_slab = reference.bbox;
+                        first_slab.pmax[axis] = position;
+                        let mut second_slab = reference.bbox;
+                        second_slab.pmin[axis] = position;
+
+                        let first_bbox = self.clip(reference, first_slab);
+                        let second_bbox = self.clip(reference, second_slab);
+                        if let (Some(_), Some(_)) = (first_bbox, second_bbox) {
+                            self.num_references += 1;
+                        }
+                        if let Some(bbox) = first_bbox {
+                            first.push(Reference { bbox, ..reference });
+                        }
+                        if let Some(bbox) = second_bbox {
+                            second.push(Reference { bbox, ..reference });
+                        }
+                    }
+                }
+            }
+        }
+
+        (first, second)
+    }
+
+    /// Returns the bounding box of the part of the reference inside of `bbox`, if there is any.
+    fn clip(&self, reference: Reference, bbox: BBox3<f64>) -> Option<BBox3<f64>> {
+        let bbox = bbox_overlap(reference.bbox, bbox)?;
+        let clipped = self.objects[reference.index].clip_bbox(bbox, self.user_data);
+        bbox_overlap(clipped, bbox)
+    }
+}
+
+/// Returns the bin of a reference when performing an object split.
+fn object_bin(centroid_bbox: BBox3<f64>, axis: usize, bbox: BBox3<f64>) -> usize {
+    let b = (OBJECT_BIN_COUNT as f64) * centroid_bbox.offset(bbox.centroid())[axis];
+    if b >= (OBJECT_BIN_COUNT as f64) {
+        OBJECT_BIN_COUNT - 1
+    } else {
+        b as usize
+    }
+}
+
+/// Returns the inclusive prefix and suffix "sums" of the bins.
+fn sweep<T: Copy>(bins: &[T], combine: impl Fn(T, &T) -> T) -> (Vec<T>, Vec<T>) {
+    let mut prefix = Vec::with_capacity(bins.len());
+    for (i, bin) in bins.iter().enumerate() {
+        prefix.push(if i == 0 {
+            *bin
+        } else {
+            combine(prefix[i - 1], bin)
+        });
+    }
+
+    let mut suffix = bins.to_vec();
+    for i in (0..(bins.len() - 1)).rev() {
+        suffix[i] = combine(suffix[i + 1], &bins[i]);
+    }
+
+    (prefix, suffix)
+}
+
+fn references_bbox(references: &[Reference]) -> BBox3<f64> {
+    references
+        .iter()
+        .fold(BBox3::new_initial(), |accum, reference| {
+            accum.combine_bnd(reference.bbox)
+        })
+}
@@ -0,0 +1,196 @@
+use crate::bvh::{BVHObject, Node, NodeType, BVH};
+use crate::error::{PrismError, PrismResult};
+use pmath::bbox::BBox3;
+use pmath::vector::Vec3;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"PBVH";
+/// Increment this whenever the layout (or the way BVHs are constructed) changes.
+const VERSION: u32 = 1;
+
+const LEAF_TAG: u8 = 0;
+const INTERNAL_TAG: u8 = 1;
+
+impl<Object: BVHObject> BVH<Object> {
+    /// Writes the BVH to `writer` as a versioned binary blob. The objects themselves aren't written, just
+    /// the order in which they are referenced by the leaves, so the same objects have to be passed to
+    /// `deserialize`. `path` is the file that is written to (for the errors).
+    pub fn serialize<W: Write>(&self, writer: &mut W, path: &str) -> PrismResult<()> {
+        self.write(writer).map_err(|err| PrismError::io(path, err))
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        write_u32(writer, VERSION)?;
+        write_u32(writer, self.spatial_splits as u32)?;
+        write_bbox(writer, self.bbox)?;
+
+        write_u64(writer, self.order.len() as u64)?;
+        for &index in &self.order {
+            write_u32(writer, index)?;
+        }
+
+        write_u64(writer, self.nodes.len() as u64)?;
+        for node in &self.nodes {
+            write_bbox(writer, node.bbox)?;
+            let (tag, values) = match node.node_type {
+                NodeType::Leaf { index, count } => (LEAF_TAG, [index, count, 0]),
+                NodeType::Internal {
+                    axis,
+                    first,
+                    second,
+                } => (INTERNAL_TAG, [axis, first, second]),
+            };
+            writer.write_all(&[tag])?;
+            for &value in values.iter() {
+                write_u64(writer, value as u64)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a BVH that was written by `serialize`. `objects` must be the same objects that the BVH was
+    /// originally constructed with (this is only partially validated). `path` is the file that is read
+    /// from (for the errors).
+    pub fn deserialize<R: Read>(
+        reader: &mut R,
+        objects: &[Object],
+        path: &str,
+    ) -> PrismResult<Self> {
+        let read_err = |err: io::Error| {
+            if err.kind() == io::ErrorKind::UnexpectedEof {
+                PrismError::parse(path, None, "Serialized BVH is truncated")
+            } else {
+                PrismError::io(path, err)
+            }
+        };
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).map_err(read_err)?;
+        if &magic != MAGIC {
+            return Err(PrismError::parse(path, None, "Not a serialized BVH"));
+        }
+        let version = read_u32(reader).map_err(read_err)?;
+        if version != VERSION {
+            return Err(PrismError::parse(
+                path,
+                None,
+                format!(
+                    "Serialized BVH has version {}, expected version {}",
+                    version, VERSION
+                ),
+            ));
+        }
+        let spatial_splits = read_u32(reader).map_err(read_err)? != 0;
+        let bbox = read_bbox(reader).map_err(read_err)?;
+
+        let num_order = read_u64(reader).map_err(read_err)? as usize;
+        let mut order = Vec::new();
+        for _ in 0..num_order {
+            let index = read_u32(reader).map_err(read_err)?;
+            if index as usize >= objects.len() {
+                return Err(PrismError::parse(
+                    path,
+                    None,
+                    format!(
+                        "Serialized BVH references object {} of {}",
+                        index,
+                        objects.len()
+                    ),
+                ));
+            }
+            order.push(index);
+        }
+
+        let num_nodes = read_u64(reader).map_err(read_err)? as usize;
+        if num_nodes == 0 {
+            return Err(PrismError::parse(path, None, "Serialized BVH has no nodes"));
+        }
+        let mut nodes = Vec::new();
+        for node_index in 0..num_nodes {
+            let node_bbox = read_bbox(reader).map_err(read_err)?;
+            let mut tag = [0u8; 1];
+            reader.read_exact(&mut tag).map_err(read_err)?;
+            let values = [
+                read_u64(reader).map_err(read_err)? as usize,
+                read_u64(reader).map_err(read_err)? as usize,
+                read_u64(reader).map_err(read_err)? as usize,
+            ];
+
+            // Children always come before their parents:
+            let node_type = match tag[0] {
+                LEAF_TAG if values[0] + values[1] <= order.len() => NodeType::Leaf {
+                    index: values[0],
+                    count: values[1],
+                },
+                INTERNAL_TAG
+                    if values[0] < 3 && values[1] < node_index && values[2] < node_index =>
+                {
+                    NodeType::Internal {
+                        axis: values[0],
+                        first: values[1],
+                        second: values[2],
+                    }
+                }
+                _ => {
+                    return Err(PrismError::parse(
+                        path,
+                        None,
+                        "Serialized BVH has an invalid node",
+                    ))
+                }
+            };
+            nodes.push(Node {
+                bbox: node_bbox,
+                node_type,
+            });
+        }
+
+        let mut bvh = Self::from_parts(objects, order, nodes, bbox);
+        bvh.spatial_splits = spatial_splits;
+        Ok(bvh)
+    }
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_bbox<W: Write>(writer: &mut W, bbox: BBox3<f64>) -> io::Result<()> {
+    for &p in [bbox.pmin, bbox.pmax].iter() {
+        for &v in [p.x, p.y, p.z].iter() {
+            write_u64(writer, v.to_bits())?;
+        }
+    }
+    Ok(())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_bbox<R: Read>(reader: &mut R) -> io::Result<BBox3<f64>> {
+    let mut read_vec3 = || -> io::Result<Vec3<f64>> {
+        Ok(Vec3 {
+            x: f64::from_bits(read_u64(reader)?),
+            y: f64::from_bits(read_u64(reader)?),
+            z: f64::from_bits(read_u64(reader)?),
+        })
+    };
+    let pmin = read_vec3()?;
+    let pmax = read_vec3()?;
+    Ok(BBox3 { pmin, pmax })
+}
@@ -0,0 +1,31 @@
+//! The stack used to traverse the BVHs. The nodes that still have to be visited are kept in a fixed
+//! size array, which is plenty for any reasonable tree. Degenerate trees (many objects with the same
+//! centroid, or spatial splits that keep duplicating the same references) can be a lot deeper than
+//! that though, so whatever doesn't fit goes to the heap instead.
+
+use arrayvec::{Array, ArrayVec};
+
+pub(super) struct TraversalStack<A: Array> {
+    inline: ArrayVec<A>,
+    // Only used once `inline` is full, so it always holds the most recently pushed entries:
+    overflow: Vec<A::Item>,
+}
+
+impl<A: Array> TraversalStack<A> {
+    pub fn new() -> Self {
+        TraversalStack {
+            inline: ArrayVec::new(),
+            overflow: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, item: A::Item) {
+        if let Err(err) = self.inline.try_push(item) {
+            self.overflow.push(err.element());
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<A::Item> {
+        self.overflow.pop().or_else(|| self.inline.pop())
+    }
+}
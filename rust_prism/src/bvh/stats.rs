@@ -0,0 +1,124 @@
+use crate::bvh::{bbox_overlap, BVHObject, NodeType, BVH};
+use pmath::bbox::BBox3;
+use std::fmt;
+
+/// Statistics describing the quality of a BVH. Useful for comparing different build algorithms
+/// (or changes to them).
+#[derive(Clone, Debug)]
+pub struct BVHStats {
+    pub num_nodes: usize,
+    pub num_leaves: usize,
+    pub num_objects: usize,
+    pub min_leaf_size: usize,
+    pub max_leaf_size: usize,
+    pub mean_leaf_size: f64,
+    /// The number of leaves at each depth (the root is at depth 0).
+    pub leaf_depth_histogram: Vec<usize>,
+    /// The SAH cost of the BVH, using `TRAVERSAL_COST` and `INTERSECT_COST`.
+    pub sah_cost: f64,
+    /// The sum of the surface areas of the overlap between the children of every internal
+    /// node, relative to the surface area of the root. The lower the better.
+    pub overlap: f64,
+    pub memory_usage: usize,
+}
+
+impl BVHStats {
+    /// The relative cost of traversing an internal node.
+    pub const TRAVERSAL_COST: f64 = 1.0;
+    /// The relative cost of intersecting an object in a leaf.
+    pub const INTERSECT_COST: f64 = 1.0;
+
+    pub fn max_depth(&self) -> usize {
+        self.leaf_depth_histogram.len().saturating_sub(1)
+    }
+}
+
+impl fmt::Display for BVHStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "BVH statistics:")?;
+        writeln!(f, "  nodes:        {}", self.num_nodes)?;
+        writeln!(f, "  leaves:       {}", self.num_leaves)?;
+        writeln!(f, "  objects:      {}", self.num_objects)?;
+        writeln!(
+            f,
+            "  leaf size:    {} min, {} max, {:.2} mean",
+            self.min_leaf_size, self.max_leaf_size, self.mean_leaf_size
+        )?;
+        writeln!(f, "  max depth:    {}", self.max_depth())?;
+        writeln!(f, "  SAH cost:     {:.3}", self.sah_cost)?;
+        writeln!(f, "  overlap:      {:.3}", self.overlap)?;
+        writeln!(f, "  memory:       {} bytes", self.memory_usage)?;
+        writeln!(f, "  leaves per depth:")?;
+        for (depth, &count) in self.leaf_depth_histogram.iter().enumerate() {
+            if count > 0 {
+                writeln!(f, "    {:>3}: {}", depth, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<Object: BVHObject> BVH<Object> {
+    /// Walks the entire BVH and collects statistics about its quality.
+    pub fn stats(&self) -> BVHStats {
+        let mut stats = BVHStats {
+            num_nodes: self.nodes.len(),
+            num_leaves: 0,
+            num_objects: self.objects.len(),
+            min_leaf_size: usize::MAX,
+            max_leaf_size: 0,
+            mean_leaf_size: 0.0,
+            leaf_depth_histogram: Vec::new(),
+            sah_cost: 0.0,
+            overlap: 0.0,
+            memory_usage: self.memory_usage(),
+        };
+
+        if self.nodes.is_empty() {
+            stats.min_leaf_size = 0;
+            return stats;
+        }
+
+        let root_area = self.bbox.surface_area();
+        let relative_area = |bbox: BBox3<f64>| {
+            if root_area > 0.0 {
+                bbox.surface_area() / root_area
+            } else {
+                1.0
+            }
+        };
+
+        let mut stack = vec![(self.root(), 0)];
+        while let Some((node_index, depth)) = stack.pop() {
+            let node = self.nodes[node_index];
+            match node.node_type {
+                NodeType::Leaf { count, .. } => {
+                    stats.num_leaves += 1;
+                    stats.min_leaf_size = stats.min_leaf_size.min(count);
+                    stats.max_leaf_size = stats.max_leaf_size.max(count);
+                    stats.sah_cost +=
+                        relative_area(node.bbox) * (count as f64) * BVHStats::INTERSECT_COST;
+
+                    if stats.leaf_depth_histogram.len() <= depth {
+                        stats.leaf_depth_histogram.resize(depth + 1, 0);
+                    }
+                    stats.leaf_depth_histogram[depth] += 1;
+                }
+                NodeType::Internal { first, second, .. } => {
+                    stats.sah_cost += relative_area(node.bbox) * BVHStats::TRAVERSAL_COST;
+                    if let Some(bbox) =
+                        bbox_overlap(self.nodes[first].bbox, self.nodes[second].bbox)
+                    {
+                        stats.overlap += relative_area(bbox);
+                    }
+
+                    stack.push((first, depth + 1));
+                    stack.push((second, depth + 1));
+                }
+            }
+        }
+
+        stats.mean_leaf_size = (self.objects.len() as f64) / (stats.num_leaves as f64);
+        stats
+    }
+}
@@ -1,28 +1,29 @@
 pub mod perspective;
 
+use crate::Scalar;
 use pmath::ray::{PrimaryRay, Ray, RayDiff};
 use pmath::vector::Vec2;
 
 #[derive(Clone, Copy, Debug)]
 pub struct CameraSample {
     /// Point on the film in raster space (pixel coordinate space)
-    pub p_film: Vec2<f64>,
+    pub p_film: Vec2<Scalar>,
     /// Random uniform sample value used for generating a sample on the lense
     /// Range is [0, 1)
-    pub p_lens: Vec2<f64>,
+    pub p_lens: Vec2<Scalar>,
     /// Whatever time step is associated with this point.
-    pub time: f64,
+    pub time: Scalar,
 }
 
 pub trait Camera: Send + Sync {
     /// Generates a single outgoing ray given a camera sample.
-    fn gen_ray(&self, sample: CameraSample) -> Ray<f64>;
+    fn gen_ray(&self, sample: CameraSample) -> Ray<Scalar>;
 
     /// Generates a primary ray, which is a ray with a dx and dy component for anti-aliasing
     ///
     /// Default implementation just uses the gen_ray function to generate dx and dy rays. These rays
     /// are generated by offseting the camera sample by one pixel in the x and y direction, respectively.
-    fn gen_primary_ray(&self, sample: CameraSample) -> PrimaryRay<f64> {
+    fn gen_primary_ray(&self, sample: CameraSample) -> PrimaryRay<Scalar> {
         let ray = self.gen_ray(sample);
 
         // Generates a CameraSample that is shifted in the x direction:
@@ -1,7 +1,9 @@
 use crate::camera::{Camera, CameraSample};
 use crate::transform::Transf;
-use pmath::bbox::BBox2;
+use crate::Scalar;
+use pmath::bbox::{BBox2, BBox3};
 use pmath::matrix::Mat4;
+use pmath::numbers::Float;
 use pmath::ray::{PrimaryRay, Ray, RayDiff};
 use pmath::sampling;
 use pmath::vector::{Vec2, Vec3};
@@ -94,36 +96,178 @@ impl PerspectiveCamera {
             dy_camera,
         }
     }
+
+    /// Returns a screen window with the same aspect ratio as the resolution, where the shorter axis
+    /// goes from -1 to 1 (so `fov` is the field-of-view along the shorter axis).
+    pub fn default_screen_window(pixel_res: Vec2<usize>) -> BBox2<f64> {
+        let aspect = (pixel_res.x as f64) / (pixel_res.y as f64);
+        if aspect >= 1.0 {
+            BBox2::from_pnts(
+                Vec2 {
+                    x: -aspect,
+                    y: -1.0,
+                },
+                Vec2 { x: aspect, y: 1.0 },
+            )
+        } else {
+            BBox2::from_pnts(
+                Vec2 {
+                    x: -1.0,
+                    y: -1.0 / aspect,
+                },
+                Vec2 {
+                    x: 1.0,
+                    y: 1.0 / aspect,
+                },
+            )
+        }
+    }
+
+    /// Constructs a camera (without depth of field) that looks along `direction_hint` and is positioned
+    /// so that all of `bbox` is visible with a small margin. Empty bounding boxes are treated as a unit
+    /// cube at the origin.
+    ///
+    /// # Arguments
+    /// * `bbox` - The bounding box to frame (in world space)
+    /// * `fov` - The field-of-view of the camera (in degrees)
+    /// * `direction_hint` - The direction the camera should look in
+    /// * `pixel_res` - The resolution of the camera
+    pub fn frame_bbox(
+        bbox: BBox3<f64>,
+        fov: f64,
+        direction_hint: Vec3<f64>,
+        pixel_res: Vec2<usize>,
+    ) -> Self {
+        // How much room to leave around the bounding box:
+        const MARGIN: f64 = 1.1;
+
+        let is_empty =
+            bbox.pmin.x > bbox.pmax.x || bbox.pmin.y > bbox.pmax.y || bbox.pmin.z > bbox.pmax.z;
+        let (center, radius) = if is_empty {
+            (Vec3::zero(), 3f64.sqrt() * 0.5)
+        } else {
+            (bbox.centroid(), 0.5 * bbox.diagonal().length())
+        };
+        // A single point (or an empty scene) still needs the camera to be somewhere:
+        let radius = if radius > 0.0 { radius } else { 1.0 };
+
+        let dir = if direction_hint.length2() > 0.0 {
+            direction_hint.normalize()
+        } else {
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            }
+        };
+
+        // Fit the bounding sphere of the box into the (shorter axis of the) field of view:
+        let half_fov = 0.5 * fov.to_radians();
+        let dist = MARGIN * radius / half_fov.sin();
+        let pos = center - dir.scale(dist);
+
+        // The up vector can't be parallel to the direction:
+        let up = if dir.y.abs() > 0.999 {
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            }
+        } else {
+            Vec3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            }
+        };
+
+        PerspectiveCamera::new(
+            Transf::new_lookat(up, center, pos),
+            fov,
+            0.0,
+            1.0,
+            Self::default_screen_window(pixel_res),
+            pixel_res,
+        )
+    }
+
+    /// The position of the camera in world space.
+    pub fn position(&self) -> Vec3<f64> {
+        self.camera_to_world.point(Vec3::zero())
+    }
+
+    /// Projects the segment between two points (in world space) onto the film, in raster space. Only
+    /// the part of the segment in front of the camera is projected (`None` if none of it is).
+    pub fn project_segment(&self, p0: Vec3<f64>, p1: Vec3<f64>) -> Option<(Vec2<f64>, Vec2<f64>)> {
+        // The near plane of `camera_to_screen`:
+        const NEAR: f64 = 1e-2;
+
+        let world_to_camera = self.camera_to_world.inverse();
+        let (mut p0, mut p1) = (world_to_camera.point(p0), world_to_camera.point(p1));
+        if p0.z < NEAR && p1.z < NEAR {
+            return None;
+        }
+        // Clip the segment to the near plane:
+        if p0.z < NEAR || p1.z < NEAR {
+            let t = (NEAR - p0.z) / (p1.z - p0.z);
+            let p = p0 + (p1 - p0).scale(t);
+            if p0.z < NEAR {
+                p0 = p;
+            } else {
+                p1 = p;
+            }
+        }
+
+        let camera_to_raster = self.screen_to_raster * self.camera_to_screen;
+        let project = |p| {
+            let p = camera_to_raster.mul_vec_proj(p);
+            Vec2 { x: p.x, y: p.y }
+        };
+        Some((project(p0), project(p1)))
+    }
+
+    /// The direction the camera looks in (in world space).
+    pub fn view_dir(&self) -> Vec3<f64> {
+        self.camera_to_world
+            .vector(Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            })
+            .normalize()
+    }
 }
 
 impl Camera for PerspectiveCamera {
-    fn gen_ray(&self, sample: CameraSample) -> Ray<f64> {
+    fn gen_ray(&self, sample: CameraSample) -> Ray<Scalar> {
+        // The ray is generated in f64 (the camera works in f64, see `Scalar`):
+        let (p_film, p_lens, time) = sample_to_f64(sample);
         // Camera point:
         let p_camera = self
             .raster_to_camera
-            .mul_vec_proj(Vec3::from_vec2(sample.p_film, 0.0));
+            .mul_vec_proj(Vec3::from_vec2(p_film, 0.0));
         // Calculate a ray from the origin of Camera space to the point on the camera:
-        let ray = Ray::new(Vec3::zero(), p_camera.normalize(), sample.time);
+        let ray = Ray::new(Vec3::zero(), p_camera.normalize(), time);
 
         // Check if there is a lens and, so, update the ray if that is the case:
         let ray = if self.lens_radius > 0.0 {
-            let p_lens = sampling::concentric_sample_disk(sample.p_lens).scale(self.lens_radius);
+            let p_lens = sampling::concentric_sample_disk(p_lens).scale(self.lens_radius);
             // The point on the place of focus:
             let ft = self.focal_dist / ray.dir.z;
             let p_focus = ray.point_at(ft);
             Ray::new(
                 Vec3::from_vec2(p_lens, 0.),
                 (p_focus - Vec3::from_vec2(p_lens, 0.)).normalize(),
-                sample.time,
+                time,
             )
         } else {
             ray
         };
 
-        self.camera_to_world.ray(ray)
+        self.camera_to_world.ray(ray).cast()
     }
 
-    fn gen_primary_ray(&self, sample: CameraSample) -> PrimaryRay<f64> {
+    fn gen_primary_ray(&self, sample: CameraSample) -> PrimaryRay<Scalar> {
         // return PrimaryRay {
         //     ray: Ray::new(Vec3 {x: -2.0, y: 0.0, z: 0.0 }, Vec3 { x: 1.0, y: 0.0, z: 0.0 }, 1.0),
         //     ray_diff: RayDiff {
@@ -134,24 +278,25 @@ impl Camera for PerspectiveCamera {
         //     }
         // };
 
+        let (p_film, p_lens, time) = sample_to_f64(sample);
         // Camera point:
         let p_camera = self
             .raster_to_camera
-            .mul_vec_proj(Vec3::from_vec2(sample.p_film, 0.0));
+            .mul_vec_proj(Vec3::from_vec2(p_film, 0.0));
         // Calculate a ray from the origin of Camera space to the point on the camera:
-        let ray = Ray::new(Vec3::zero(), p_camera.normalize(), sample.time);
+        let ray = Ray::new(Vec3::zero(), p_camera.normalize(), time);
 
         // Check whether or not there is a lens
         let prim_ray = if self.lens_radius > 0. {
             // Calculate the focus information as normal:
-            let p_lens = sampling::concentric_sample_disk(sample.p_lens).scale(self.lens_radius);
+            let p_lens = sampling::concentric_sample_disk(p_lens).scale(self.lens_radius);
 
             let ft = self.focal_dist / ray.dir.z;
             let p_focus = ray.point_at(ft);
             let ray = Ray::new(
                 Vec3::from_vec2(p_lens, 0.),
                 (p_focus - Vec3::from_vec2(p_lens, 0.)).normalize(),
-                sample.time,
+                time,
             );
 
             // Calculate the focus information in the dx direction:
@@ -197,6 +342,15 @@ impl Camera for PerspectiveCamera {
         // Don't forget to transform it back to world space!
         let mut prim_ray = self.camera_to_world.primary_ray(prim_ray);
         prim_ray.ray.dir = prim_ray.ray.dir.normalize();
-        prim_ray
+        prim_ray.cast()
     }
 }
+
+/// The position on the film and the lens, and the time of a sample in f64.
+fn sample_to_f64(sample: CameraSample) -> (Vec2<f64>, Vec2<f64>, f64) {
+    (
+        sample.p_film.cast(),
+        sample.p_lens.cast(),
+        sample.time.to_f64(),
+    )
+}
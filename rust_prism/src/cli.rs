@@ -0,0 +1,493 @@
+//! Parses the command line arguments of the renderer. Any values passed on the command line override the
+//! ones from the scene file.
+
+use pmath::vector::Vec2;
+use prism::fileio::scene::{IntegratorDesc, SampleSequenceDesc, SamplerModeDesc, SceneDesc};
+use prism::film::overlay::DebugOverlay;
+use prism::spectrum::{WhitePoint, MAX_TEMPERATURE, MIN_TEMPERATURE};
+use prism::threading::pixel_order::PixelOrder;
+use simple_error::{bail, SimpleResult};
+use std::path::Path;
+use std::time::Duration;
+
+pub const USAGE: &str = "\
+Usage: prism --scene <file> [options]
+       prism inspect <image>       Prints how an image written by the renderer was rendered
+
+Options:
+    --scene <file>          The scene file to render (required)
+    --out <path>            Where to write the image, as a png or an exr file (default: out.png)
+    --res <W>x<H>           Overrides the resolution
+    --spp <N>               Overrides the number of samples per pixel
+    --threads <N>           Overrides the number of threads to render with
+    --integrator <name>     Overrides the integrator: path, direct (the path tracer with a single
+                            bounce), ao (ambient occlusion), normal, or one of the debug integrators
+                            (depth, position, geom-normal, uv, attribute-id)
+    --sampler <name>        Overrides the sampler: pmj, pmj-shifted (which shifts a single sequence
+                            for the positions of every pixel), sobol, stratified, or random
+    --seed <N>              Overrides the seed of the sampler
+    --pixel-order <name>    Overrides the order the pixels of a tile are rendered in: scanline, morton,
+                            or hilbert (the image is the same, but the render time may differ)
+    --frames <A>-<B>        Overrides the frames of an animated scene (A-A renders a single frame). Every
+                            frame is written next to --out, with the number of the frame appended
+    --white-balance <white> Overrides the white point of the scene, either as a temperature (2700K) or
+                            as an xy chromaticity (0.46,0.41)
+    --crop <x0,y0,x1,y1>    Only writes the pixels from (x0, y0) up to (but not including) (x1, y1)
+    --debug-overlay <name>  Draws a wireframe over the image: bvh-level-<N> (the bounding boxes of the
+                            nodes of the scene's BVH at depth N) or tiles (the borders of the tiles)
+    --reference <png>       Compares the image (which has to be a png) with a reference image and fails
+                            if they differ (renders with a single thread). If PRISM_UPDATE_REFERENCES
+                            is set, the reference is replaced with the image instead
+    --time-limit <seconds>  Stops the render after this many seconds and writes what was rendered
+    --watch                 Keeps running and re-renders whenever the materials in the scene file change
+                            (requires the hot-reload feature)
+    --wavefront             Renders the path tracer a bounce at a time for every pixel of a tile
+                            (instead of a path at a time), which can be faster for complex scenes
+    --quiet                 Don't print anything other than errors
+    --verbose               Print extra information about the scene and the render
+    --help                  Print this message
+
+Exit codes:
+    2                       Invalid arguments
+    3                       A file couldn't be read or written
+    4                       A file (the scene file, a mesh, an image) is invalid
+    5                       The scene is inconsistent
+    6                       The render failed
+    7                       The image doesn't match the reference (see --reference)
+";
+
+/// The default maximum number of bounces when switching to the path tracer from the command line.
+const DEFAULT_MAX_BOUNCE: u32 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntegratorName {
+    Path,
+    /// The path tracer with a single bounce.
+    Direct,
+    AmbientOcclusion,
+    Normal,
+    Depth,
+    Position,
+    GeomNormal,
+    Uv,
+    AttributeId,
+}
+
+impl IntegratorName {
+    fn of(desc: &IntegratorDesc) -> Self {
+        match desc {
+            IntegratorDesc::Path { .. } => IntegratorName::Path,
+            IntegratorDesc::AmbientOcclusion { .. } => IntegratorName::AmbientOcclusion,
+            IntegratorDesc::Normal { .. } => IntegratorName::Normal,
+            IntegratorDesc::Depth { .. } => IntegratorName::Depth,
+            IntegratorDesc::Position => IntegratorName::Position,
+            IntegratorDesc::GeomNormal => IntegratorName::GeomNormal,
+            IntegratorDesc::Uv => IntegratorName::Uv,
+            IntegratorDesc::AttributeId => IntegratorName::AttributeId,
+        }
+    }
+
+    /// The integrator with default settings.
+    fn default_desc(self) -> IntegratorDesc {
+        match self {
+            IntegratorName::Path => path_desc(DEFAULT_MAX_BOUNCE),
+            IntegratorName::Direct => path_desc(1),
+            IntegratorName::AmbientOcclusion => IntegratorDesc::AmbientOcclusion {
+                max_distance: None,
+                samples: 1,
+            },
+            IntegratorName::Normal => IntegratorDesc::Normal {
+                geometric_normal: false,
+            },
+            IntegratorName::Depth => IntegratorDesc::Depth {
+                near: 0.0,
+                far: None,
+            },
+            IntegratorName::Position => IntegratorDesc::Position,
+            IntegratorName::GeomNormal => IntegratorDesc::GeomNormal,
+            IntegratorName::Uv => IntegratorDesc::Uv,
+            IntegratorName::AttributeId => IntegratorDesc::AttributeId,
+        }
+    }
+}
+
+/// The path tracer with default settings.
+fn path_desc(max_bounce: u32) -> IntegratorDesc {
+    IntegratorDesc::Path {
+        max_bounce,
+        light_samples: 1,
+        bsdf_samples: 1,
+        terminator_fix: false,
+        aovs: false,
+        max_direct: None,
+        max_indirect: None,
+        shadow_rr: None,
+        caustics: None,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CliArgs {
+    pub scene: String,
+    pub out: String,
+    pub res: Option<Vec2<usize>>,
+    pub spp: Option<u32>,
+    pub threads: Option<u32>,
+    pub integrator: Option<IntegratorName>,
+    pub seed: Option<u64>,
+    pub sampler: Option<(SampleSequenceDesc, SamplerModeDesc)>,
+    pub pixel_order: Option<PixelOrder>,
+    /// The first and last frame of the animation.
+    pub frames: Option<(u32, u32)>,
+    pub white_balance: Option<WhitePoint>,
+    /// The minimum (inclusive) and maximum (exclusive) pixel of the crop window.
+    pub crop: Option<(Vec2<usize>, Vec2<usize>)>,
+    pub debug_overlay: Option<DebugOverlay>,
+    /// The png the image is compared with after it's written.
+    pub reference: Option<String>,
+    pub time_limit: Option<Duration>,
+    /// Keep rendering and reload the materials when the scene file changes.
+    pub watch: bool,
+    /// Render the path tracer with the `WavefrontRenderer`.
+    pub wavefront: bool,
+    pub verbosity: Verbosity,
+}
+
+/// Parses the arguments (without the name of the executable). Returns `None` if `--help` was passed.
+pub fn parse_args<I: Iterator<Item = String>>(mut args: I) -> SimpleResult<Option<CliArgs>> {
+    let mut scene = None;
+    let mut out = None;
+    let mut res = None;
+    let mut spp = None;
+    let mut threads = None;
+    let mut integrator = None;
+    let mut seed = None;
+    let mut sampler = None;
+    let mut pixel_order = None;
+    let mut frames = None;
+    let mut white_balance = None;
+    let mut crop = None;
+    let mut debug_overlay = None;
+    let mut reference = None;
+    let mut time_limit = None;
+    let mut watch = false;
+    let mut wavefront = false;
+    let mut quiet = false;
+    let mut verbose = false;
+
+    while let Some(arg) = args.next() {
+        let mut value = || match args.next() {
+            Some(value) => Ok(value),
+            None => Err(simple_error::SimpleError::new(format!(
+                "{} expects a value",
+                arg
+            ))),
+        };
+
+        match arg.as_str() {
+            "--help" | "-h" => return Ok(None),
+            "--scene" => scene = Some(value()?),
+            "--out" => out = Some(value()?),
+            "--res" => res = Some(parse_res(&value()?)?),
+            "--spp" => spp = Some(parse_positive(&arg, &value()?)?),
+            "--threads" => threads = Some(parse_positive(&arg, &value()?)?),
+            "--integrator" => {
+                integrator = Some(match value()?.as_str() {
+                    "path" => IntegratorName::Path,
+                    "direct" => IntegratorName::Direct,
+                    "ao" => IntegratorName::AmbientOcclusion,
+                    "normal" => IntegratorName::Normal,
+                    "depth" => IntegratorName::Depth,
+                    "position" => IntegratorName::Position,
+                    "geom-normal" => IntegratorName::GeomNormal,
+                    "uv" => IntegratorName::Uv,
+                    "attribute-id" => IntegratorName::AttributeId,
+                    name => bail!(
+                        "Unknown integrator \"{}\" (expected one of: path, direct, ao, normal, depth, position, geom-normal, uv, attribute-id)",
+                        name
+                    ),
+                })
+            }
+            "--sampler" => {
+                sampler = Some(match value()?.as_str() {
+                    "pmj" => (SampleSequenceDesc::Pmj, SamplerModeDesc::Scrambled),
+                    "pmj-shifted" => (SampleSequenceDesc::Pmj, SamplerModeDesc::Shifted),
+                    "sobol" => (SampleSequenceDesc::Sobol, SamplerModeDesc::Scrambled),
+                    "stratified" => (SampleSequenceDesc::Stratified, SamplerModeDesc::Scrambled),
+                    "random" => (SampleSequenceDesc::Random, SamplerModeDesc::Scrambled),
+                    name => bail!(
+                        "Unknown sampler \"{}\" (expected one of: pmj, pmj-shifted, sobol, stratified, random)",
+                        name
+                    ),
+                })
+            }
+            "--seed" => {
+                let value = value()?;
+                seed = match value.parse() {
+                    Ok(seed) => Some(seed),
+                    Err(_) => bail!("--seed expects a non-negative integer, got \"{}\"", value),
+                }
+            }
+            "--pixel-order" => {
+                pixel_order = Some(match value()?.as_str() {
+                    "scanline" => PixelOrder::Scanline,
+                    "morton" => PixelOrder::Morton,
+                    "hilbert" => PixelOrder::Hilbert,
+                    name => bail!(
+                        "Unknown pixel order \"{}\" (expected one of: scanline, morton, hilbert)",
+                        name
+                    ),
+                })
+            }
+            "--frames" => frames = Some(parse_frames(&value()?)?),
+            "--white-balance" => white_balance = Some(parse_white_balance(&value()?)?),
+            "--crop" => crop = Some(parse_crop(&value()?)?),
+            "--debug-overlay" => debug_overlay = Some(parse_debug_overlay(&value()?)?),
+            "--reference" => reference = Some(value()?),
+            "--time-limit" => {
+                let value = value()?;
+                time_limit = match value.parse::<f64>() {
+                    Ok(secs) if secs > 0.0 && secs.is_finite() => {
+                        Some(Duration::from_secs_f64(secs))
+                    }
+                    _ => bail!(
+                        "--time-limit expects a positive number of seconds, got \"{}\"",
+                        value
+                    ),
+                }
+            }
+            "--watch" => {
+                if !cfg!(feature = "hot-reload") {
+                    bail!("--watch requires prism to be built with the hot-reload feature");
+                }
+                watch = true
+            }
+            "--wavefront" => wavefront = true,
+            "--quiet" | "-q" => quiet = true,
+            "--verbose" | "-v" => verbose = true,
+            _ => bail!("Unknown argument \"{}\" (see --help)", arg),
+        }
+    }
+
+    if watch && time_limit.is_some() {
+        bail!("--watch and --time-limit can't be used together");
+    }
+    if watch && wavefront {
+        bail!("--watch and --wavefront can't be used together");
+    }
+    if let Some(reference) = &reference {
+        if threads.is_some() || time_limit.is_some() || watch {
+            bail!("--reference can't be used with --threads, --time-limit, or --watch");
+        }
+        if !is_png(out.as_deref().unwrap_or("out.png")) || !is_png(reference) {
+            bail!("--reference can only compare png images");
+        }
+        // The image has to be the same for every run:
+        threads = Some(1);
+    }
+    if quiet && verbose {
+        bail!("--quiet and --verbose can't be used together");
+    }
+    let scene = match scene {
+        Some(scene) => scene,
+        None => bail!("No scene file was provided (use --scene <file>)"),
+    };
+
+    Ok(Some(CliArgs {
+        scene,
+        out: out.unwrap_or_else(|| String::from("out.png")),
+        res,
+        spp,
+        threads,
+        integrator,
+        seed,
+        sampler,
+        pixel_order,
+        frames,
+        white_balance,
+        crop,
+        debug_overlay,
+        reference,
+        time_limit,
+        watch,
+        wavefront,
+        verbosity: if quiet {
+            Verbosity::Quiet
+        } else if verbose {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        },
+    }))
+}
+
+impl CliArgs {
+    /// Overrides the values in the scene description with the ones passed on the command line.
+    pub fn apply(&self, desc: &mut SceneDesc) -> SimpleResult<()> {
+        let settings = &mut desc.settings;
+        if let Some(res) = self.res {
+            settings.res = (res.x, res.y);
+        }
+        if let Some(spp) = self.spp {
+            settings.spp = spp;
+        }
+        if let Some(threads) = self.threads {
+            settings.threads = Some(threads);
+        }
+        if let Some(seed) = self.seed {
+            settings.sampler.seed = seed;
+        }
+        if let Some((sequence, mode)) = self.sampler {
+            settings.sampler.sequence = sequence;
+            settings.sampler.mode = mode;
+        }
+        if let Some(pixel_order) = self.pixel_order {
+            settings.pixel_order = pixel_order;
+        }
+        if let Some(frames) = self.frames {
+            match &mut desc.animation {
+                Some(animation) => animation.frames = frames,
+                None => bail!("--frames can only be used with scenes that have an animation"),
+            }
+        }
+        if let Some(white_balance) = self.white_balance {
+            settings.white_balance = Some(white_balance);
+        }
+        // Keep the settings from the scene file if it already uses the same integrator:
+        if let Some(name) = self.integrator {
+            match (&mut settings.integrator, name) {
+                // Which is the path tracer for the direct integrator:
+                (IntegratorDesc::Path { max_bounce, .. }, IntegratorName::Direct) => {
+                    *max_bounce = 1
+                }
+                (integrator, name) if name != IntegratorName::of(integrator) => {
+                    *integrator = name.default_desc()
+                }
+                _ => {}
+            }
+        }
+
+        // Only now do we know the final resolution:
+        if let Some((pmin, pmax)) = self.crop {
+            if pmax.x > settings.res.0 || pmax.y > settings.res.1 {
+                bail!(
+                    "The crop window ({},{} to {},{}) is outside of the {}x{} image",
+                    pmin.x,
+                    pmin.y,
+                    pmax.x,
+                    pmax.y,
+                    settings.res.0,
+                    settings.res.1
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_positive(arg: &str, value: &str) -> SimpleResult<u32> {
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!("{} expects a positive integer, got \"{}\"", arg, value),
+    }
+}
+
+fn parse_res(value: &str) -> SimpleResult<Vec2<usize>> {
+    let mut parts = value.split('x');
+    match (
+        parts.next().map(str::parse::<usize>),
+        parts.next().map(str::parse::<usize>),
+        parts.next(),
+    ) {
+        (Some(Ok(x)), Some(Ok(y)), None) if x > 0 && y > 0 => Ok(Vec2 { x, y }),
+        _ => bail!(
+            "--res expects a resolution of the form WxH (for example 640x480), got \"{}\"",
+            value
+        ),
+    }
+}
+
+fn parse_frames(value: &str) -> SimpleResult<(u32, u32)> {
+    let mut parts = value.split('-');
+    match (
+        parts.next().map(str::parse::<u32>),
+        parts.next().map(str::parse::<u32>),
+        parts.next(),
+    ) {
+        (Some(Ok(first)), Some(Ok(last)), None) if first <= last => Ok((first, last)),
+        _ => bail!(
+            "--frames expects the first and last frame of the form A-B (with A <= B), got \"{}\"",
+            value
+        ),
+    }
+}
+
+fn parse_white_balance(value: &str) -> SimpleResult<WhitePoint> {
+    let temperature = value
+        .strip_suffix('K')
+        .and_then(|t| t.parse::<f64>().ok())
+        .filter(|t| (MIN_TEMPERATURE..=MAX_TEMPERATURE).contains(t));
+    if let Some(temperature) = temperature {
+        return Ok(WhitePoint::Temperature(temperature));
+    }
+    let coords: Result<Vec<f64>, _> = value.split(',').map(str::parse).collect();
+    match coords.as_ref().map(Vec::as_slice) {
+        Ok(&[x, y]) if x > 0.0 && y > 0.0 && x + y < 1.0 => Ok(WhitePoint::Xy((x, y))),
+        _ => bail!(
+            "--white-balance expects a temperature from {}K to {}K (like 2700K) or an xy chromaticity (like 0.46,0.41), got \"{}\"",
+            MIN_TEMPERATURE,
+            MAX_TEMPERATURE,
+            value
+        ),
+    }
+}
+
+fn is_png(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("png"))
+}
+
+fn parse_debug_overlay(value: &str) -> SimpleResult<DebugOverlay> {
+    const BVH_LEVEL: &str = "bvh-level-";
+    match value {
+        "tiles" => Ok(DebugOverlay::Tiles),
+        "light-bvh" => {
+            bail!("The light-bvh overlay isn't supported yet (no light picker uses a BVH)")
+        }
+        _ if value.starts_with(BVH_LEVEL) => match value[BVH_LEVEL.len()..].parse() {
+            Ok(level) => Ok(DebugOverlay::BvhLevel(level)),
+            Err(_) => bail!(
+                "--debug-overlay expects a non-negative depth after bvh-level-, got \"{}\"",
+                value
+            ),
+        },
+        _ => bail!(
+            "Unknown debug overlay \"{}\" (expected one of: bvh-level-<N>, tiles)",
+            value
+        ),
+    }
+}
+
+fn parse_crop(value: &str) -> SimpleResult<(Vec2<usize>, Vec2<usize>)> {
+    let coords: Result<Vec<usize>, _> = value.split(',').map(str::parse).collect();
+    match coords.as_ref().map(Vec::as_slice) {
+        Ok(&[x0, y0, x1, y1]) if x0 < x1 && y0 < y1 => {
+            Ok((Vec2 { x: x0, y: y0 }, Vec2 { x: x1, y: y1 }))
+        }
+        Ok(&[_, _, _, _]) => bail!("--crop expects x0 < x1 and y0 < y1, got \"{}\"", value),
+        _ => bail!(
+            "--crop expects four comma separated pixel coordinates (x0,y0,x1,y1), got \"{}\"",
+            value
+        ),
+    }
+}
@@ -0,0 +1,130 @@
+use crate::threading::RenderError;
+use simple_error::SimpleError;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// The error returned by the public loading and writing functions of the library (scene files, meshes,
+/// images) and by the scene.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PrismError {
+    /// A file couldn't be opened, read, or written.
+    Io { path: String, err: io::Error },
+    /// A file was read, but its contents aren't valid. `line` is where the problem is (if it's known).
+    Parse {
+        path: Option<String>,
+        line: Option<usize>,
+        msg: String,
+    },
+    /// The scene is inconsistent (a material that doesn't exist, an invalid setting, ...).
+    InvalidScene(String),
+    /// The command line arguments can't be used together (or with the scene).
+    Usage(String),
+    /// The render failed.
+    Render(String),
+    /// The image doesn't match the reference image it was compared with (see `ImageDiff`).
+    ReferenceMismatch(String),
+}
+
+/// The result type used with `PrismError`.
+pub type PrismResult<T> = Result<T, PrismError>;
+
+impl PrismError {
+    pub fn io(path: &str, err: io::Error) -> Self {
+        PrismError::Io {
+            path: path.to_owned(),
+            err,
+        }
+    }
+
+    pub fn parse(path: &str, line: Option<usize>, msg: impl Into<String>) -> Self {
+        PrismError::Parse {
+            path: Some(path.to_owned()),
+            line,
+            msg: msg.into(),
+        }
+    }
+
+    pub fn invalid_scene(msg: impl Into<String>) -> Self {
+        PrismError::InvalidScene(msg.into())
+    }
+
+    /// Sets the path of a parse error that doesn't have one yet (other errors are returned as they are).
+    pub fn with_path(self, path: &str) -> Self {
+        match self {
+            PrismError::Parse {
+                path: None,
+                line,
+                msg,
+            } => PrismError::Parse {
+                path: Some(path.to_owned()),
+                line,
+                msg,
+            },
+            err => err,
+        }
+    }
+
+    /// The exit code the command line renderer uses for the error (2 is used for invalid arguments).
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PrismError::Io { .. } => 3,
+            PrismError::Parse { .. } => 4,
+            PrismError::Usage(_) => 2,
+            PrismError::InvalidScene(_) => 5,
+            PrismError::Render(_) => 6,
+            PrismError::ReferenceMismatch(_) => 7,
+        }
+    }
+}
+
+impl fmt::Display for PrismError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PrismError::Io { path, err } => write!(f, "{}: {}", path, err),
+            PrismError::Parse { path, line, msg } => {
+                match (path, line) {
+                    (Some(path), Some(line)) => write!(f, "{}:{}: ", path, line)?,
+                    (Some(path), None) => write!(f, "{}: ", path)?,
+                    (None, Some(line)) => write!(f, "line {}: ", line)?,
+                    (None, None) => (),
+                }
+                write!(f, "{}", msg)
+            }
+            PrismError::InvalidScene(msg) => write!(f, "{}", msg),
+            PrismError::Usage(msg) => write!(f, "{}", msg),
+            PrismError::Render(msg) => write!(f, "render failed: {}", msg),
+            PrismError::ReferenceMismatch(msg) => write!(f, "doesn't match the reference: {}", msg),
+        }
+    }
+}
+
+impl Error for PrismError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PrismError::Io { err, .. } => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<RenderError> for PrismError {
+    fn from(err: RenderError) -> Self {
+        PrismError::Render(err.to_string())
+    }
+}
+
+// The code that still reports errors with `simple_error` (like `bail!`) describes problems with the scene:
+
+impl From<SimpleError> for PrismError {
+    fn from(err: SimpleError) -> Self {
+        PrismError::InvalidScene(err.as_str().to_owned())
+    }
+}
+
+impl From<PrismError> for SimpleError {
+    fn from(err: PrismError) -> Self {
+        SimpleError::new(err.to_string())
+    }
+}
@@ -0,0 +1,522 @@
+use crate::camera::perspective::PerspectiveCamera;
+use crate::error::{PrismError, PrismResult};
+use crate::fileio::ImportOptions;
+use crate::geometry::mesh::{Mesh, Triangle};
+use crate::shading::texture::ImageTexture;
+use crate::spectrum::Color;
+use crate::transform::graph::{NodeId, TransformGraph};
+use crate::transform::Transf;
+use crate::Scalar;
+use gltf::image::Format;
+use gltf::khr_lights_punctual::Kind;
+use pmath::bbox::BBox2;
+use pmath::matrix::Mat3x4;
+use pmath::vector::{Vec2, Vec3};
+use std::sync::Arc;
+
+/// A single placement of a mesh in the world.
+#[derive(Clone, Copy, Debug)]
+pub struct GltfInstance {
+    /// Index into `GltfScene::meshes`.
+    pub mesh: usize,
+    /// Index into `GltfScene::materials`, `None` if the primitive didn't specify one.
+    pub material: Option<usize>,
+    /// The node in `GltfScene::transforms` the instance is attached to.
+    pub node: NodeId,
+    /// The flattened object to world transform.
+    pub transf: Transf,
+}
+
+/// The parameters of a glTF metallic-roughness material. Textures are already
+/// loaded and converted to linear space.
+pub struct GltfMaterial {
+    pub name: Option<String>,
+    pub base_color: Color,
+    pub base_color_texture: Option<Arc<ImageTexture>>,
+    pub metallic: f64,
+    pub roughness: f64,
+    pub emissive: Color,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum GltfLightType {
+    Point,
+    Directional,
+    Spot {
+        inner_cone_angle: f64,
+        outer_cone_angle: f64,
+    },
+}
+
+/// A light from the KHR_lights_punctual extension. Directional and spot lights
+/// point down the -z axis of `transf`.
+#[derive(Clone, Copy, Debug)]
+pub struct GltfLight {
+    pub light_type: GltfLightType,
+    pub color: Color,
+    /// In candela for point and spot lights and in lux for directional lights (the renderer's units
+    /// are radiometric, see `LUMINOUS_EFFICACY`).
+    pub intensity: f64,
+    pub range: Option<f64>,
+    pub node: NodeId,
+    pub transf: Transf,
+}
+
+impl GltfLight {
+    pub fn position(&self) -> Vec3<f64> {
+        self.transf.point(Vec3::zero())
+    }
+
+    pub fn direction(&self) -> Vec3<f64> {
+        self.transf
+            .vector(Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            })
+            .normalize()
+    }
+}
+
+/// Everything that was loaded from a glTF (or GLB) file.
+pub struct GltfScene {
+    pub meshes: Vec<Mesh>,
+    pub instances: Vec<GltfInstance>,
+    pub materials: Vec<GltfMaterial>,
+    pub lights: Vec<GltfLight>,
+    /// The node hierarchy of the file. Every instance and light refers to one of its nodes. The nodes are
+    /// in the coordinate system of the file (see `ImportOptions::conversion_transf`).
+    pub transforms: TransformGraph,
+    /// The first camera found in the scene, if any.
+    pub camera: Option<PerspectiveCamera>,
+}
+
+/// Loads the glTF or GLB file at the designated path. Every primitive becomes its own mesh, and the
+/// node hierarchy is turned into a `TransformGraph` (with the world transforms of each instance and
+/// light already flattened).
+pub fn load_gltf(path: &str, res: Vec2<usize>, options: ImportOptions) -> PrismResult<GltfScene> {
+    let (document, buffers, images) = match gltf::import(path) {
+        Ok(result) => result,
+        Err(gltf::Error::Io(err)) => return Err(PrismError::io(path, err)),
+        Err(err) => return Err(PrismError::parse(path, None, err.to_string())),
+    };
+
+    // Which images are used as color data (and thus need to be linearized):
+    let mut srgb_images = vec![false; images.len()];
+    for material in document.materials() {
+        let pbr = material.pbr_metallic_roughness();
+        if let Some(info) = pbr.base_color_texture() {
+            srgb_images[info.texture().source().index()] = true;
+        }
+    }
+
+    let mut textures = Vec::with_capacity(images.len());
+    for (image, &srgb) in images.iter().zip(srgb_images.iter()) {
+        let channels = match image.format {
+            Format::R8 => 1,
+            Format::R8G8 => 2,
+            Format::R8G8B8 => 3,
+            Format::R8G8B8A8 => 4,
+            format => {
+                let msg = format!("unsupported image format {:?}", format);
+                return Err(PrismError::parse(path, None, msg));
+            }
+        };
+        textures.push(Arc::new(ImageTexture::from_u8(
+            &image.pixels,
+            channels,
+            Vec2 {
+                x: image.width as usize,
+                y: image.height as usize,
+            },
+            srgb,
+        )));
+    }
+
+    let materials = document
+        .materials()
+        .map(|material| {
+            let pbr = material.pbr_metallic_roughness();
+            let base_color = pbr.base_color_factor();
+            let emissive = material.emissive_factor();
+            GltfMaterial {
+                name: material.name().map(String::from),
+                base_color: Color {
+                    r: base_color[0] as Scalar,
+                    g: base_color[1] as Scalar,
+                    b: base_color[2] as Scalar,
+                },
+                base_color_texture: pbr
+                    .base_color_texture()
+                    .map(|info| textures[info.texture().source().index()].clone()),
+                metallic: pbr.metallic_factor() as f64,
+                roughness: pbr.roughness_factor() as f64,
+                emissive: Color {
+                    r: emissive[0] as Scalar,
+                    g: emissive[1] as Scalar,
+                    b: emissive[2] as Scalar,
+                },
+            }
+        })
+        .collect();
+
+    // Every glTF mesh can have multiple primitives, each of which we turn into a Mesh.
+    // This keeps track of the (mesh index, material) pairs for each glTF mesh:
+    let mut meshes = Vec::new();
+    let mut mesh_prims = Vec::with_capacity(document.meshes().len());
+    for gltf_mesh in document.meshes() {
+        let mut prims = Vec::new();
+        for primitive in gltf_mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                let msg = format!(
+                    "only triangle primitives are supported, found {:?}",
+                    primitive.mode()
+                );
+                return Err(PrismError::parse(path, None, msg));
+            }
+
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+            let mut pos: Vec<Vec3<f32>> = match reader.read_positions() {
+                Some(iter) => iter.map(|p| Vec3::from_arr(p)).collect(),
+                None => return Err(PrismError::parse(path, None, "primitive without positions")),
+            };
+            let mut nrm: Vec<Vec3<f32>> = reader
+                .read_normals()
+                .map_or(Vec::new(), |iter| iter.map(|n| Vec3::from_arr(n)).collect());
+            let uvs: Vec<Vec2<f32>> = reader.read_tex_coords(0).map_or(Vec::new(), |iter| {
+                // glTF places the uv origin at the top-left corner:
+                iter.into_f32()
+                    .map(|uv| Vec2 {
+                        x: uv[0],
+                        y: 1.0 - uv[1],
+                    })
+                    .collect()
+            });
+
+            let col: Vec<Vec3<f32>> = reader.read_colors(0).map_or(Vec::new(), |iter| {
+                iter.into_rgb_f32().map(|c| Vec3::from_arr(c)).collect()
+            });
+
+            // Non-indexed primitives are just a list of triangles:
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..pos.len() as u32).collect(),
+            };
+            if indices.len() % 3 != 0 {
+                let msg = "index count isn't a multiple of 3";
+                return Err(PrismError::parse(path, None, msg));
+            }
+            if indices.iter().any(|&i| i as usize >= pos.len()) {
+                return Err(PrismError::parse(path, None, "out of bounds index"));
+            }
+
+            let mut triangles: Vec<Triangle> = indices
+                .chunks_exact(3)
+                .map(|tri| Triangle {
+                    indices: [tri[0], tri[1], tri[2]],
+                    attribute_id: 0,
+                })
+                .collect();
+            options.convert_mesh(&mut triangles, &mut pos, &mut nrm, &mut []);
+
+            let has_nrm = !nrm.is_empty();
+            let mut mesh = Mesh::new(
+                triangles,
+                pos,
+                nrm,
+                Vec::new(),
+                uvs,
+                col,
+                options.max_triangles_per_leaf,
+                options.bvh_algorithm,
+            );
+            mesh.set_uv_projection(options.uv_projection);
+            if let Some(epsilon) = options.weld {
+                mesh.weld(epsilon);
+            }
+            if let (false, Some(angle)) = (has_nrm, options.smooth_normals) {
+                mesh.compute_smooth_normals(angle);
+            }
+            if options.compact {
+                mesh.compact();
+            }
+
+            prims.push((meshes.len(), primitive.material().index()));
+            meshes.push(mesh);
+        }
+        mesh_prims.push(prims);
+    }
+
+    let mut result = GltfScene {
+        meshes,
+        instances: Vec::new(),
+        materials,
+        lights: Vec::new(),
+        transforms: TransformGraph::new(),
+        camera: None,
+    };
+
+    let scene = match document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+    {
+        Some(scene) => scene,
+        None => return Err(PrismError::parse(path, None, "no scene")),
+    };
+    // The node of the first perspective camera, with its vertical field of view and aspect ratio:
+    let mut camera = None;
+    for node in scene.nodes() {
+        visit_node(&node, None, &mesh_prims, &mut camera, &mut result);
+    }
+
+    // The meshes are already converted, so the node transformations have to be converted as well:
+    let conversion = options.conversion_transf();
+    let conversion_inv = conversion.inverse();
+
+    result.transforms.update();
+    for instance in result.instances.iter_mut() {
+        instance.transf = conversion * result.transforms.get_world(instance.node) * conversion_inv;
+    }
+    for light in result.lights.iter_mut() {
+        light.transf = conversion * result.transforms.get_world(light.node);
+    }
+
+    if let Some((node, yfov, aspect_ratio)) = camera {
+        let aspect_ratio = aspect_ratio.map_or(res.x as f64 / res.y as f64, |a| a as f64);
+        let screen_window = BBox2 {
+            pmin: Vec2 {
+                x: -aspect_ratio,
+                y: -1.0,
+            },
+            pmax: Vec2 {
+                x: aspect_ratio,
+                y: 1.0,
+            },
+        };
+        // glTF cameras look down -z, ours look down +z:
+        let camera_to_world = conversion
+            * result.transforms.get_world(node)
+            * Transf::new_scale(Vec3 {
+                x: 1.0,
+                y: 1.0,
+                z: -1.0,
+            });
+        result.camera = Some(PerspectiveCamera::new(
+            camera_to_world,
+            (yfov as f64).to_degrees(),
+            0.0,
+            1.0,
+            screen_window,
+            res,
+        ));
+    }
+
+    Ok(result)
+}
+
+/// Recursively visits the node hierarchy, adding every node to the transform graph. The world
+/// transforms are filled in once the whole graph is built.
+fn visit_node(
+    node: &gltf::Node,
+    parent: Option<NodeId>,
+    mesh_prims: &[Vec<(usize, Option<usize>)>],
+    camera: &mut Option<(NodeId, f32, Option<f32>)>,
+    result: &mut GltfScene,
+) {
+    let node_id = result
+        .transforms
+        .add_node(parent, to_transf(node.transform().matrix()));
+
+    if let Some(mesh) = node.mesh() {
+        for &(mesh, material) in &mesh_prims[mesh.index()] {
+            result.instances.push(GltfInstance {
+                mesh,
+                material,
+                node: node_id,
+                transf: Transf::new_identity(),
+            });
+        }
+    }
+
+    if let (None, Some(gltf_camera)) = (&camera, node.camera()) {
+        if let gltf::camera::Projection::Perspective(proj) = gltf_camera.projection() {
+            *camera = Some((node_id, proj.yfov(), proj.aspect_ratio()));
+        }
+    }
+
+    if let Some(light) = node.light() {
+        let light_type = match light.kind() {
+            Kind::Point => GltfLightType::Point,
+            Kind::Directional => GltfLightType::Directional,
+            Kind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => GltfLightType::Spot {
+                inner_cone_angle: inner_cone_angle as f64,
+                outer_cone_angle: outer_cone_angle as f64,
+            },
+        };
+        let color = light.color();
+        result.lights.push(GltfLight {
+            light_type,
+            color: Color {
+                r: color[0] as Scalar,
+                g: color[1] as Scalar,
+                b: color[2] as Scalar,
+            },
+            intensity: light.intensity() as f64,
+            range: light.range().map(|r| r as f64),
+            node: node_id,
+            transf: Transf::new_identity(),
+        });
+    }
+
+    for child in node.children() {
+        visit_node(&child, Some(node_id), mesh_prims, camera, result);
+    }
+}
+
+/// glTF matrices are stored column-major, ours are row-major.
+fn to_transf(m: [[f32; 4]; 4]) -> Transf {
+    let m = |r: usize, c: usize| m[c][r] as f64;
+    Transf::from_mat3x4(Mat3x4::from_arr([
+        m(0, 0),
+        m(0, 1),
+        m(0, 2),
+        m(0, 3),
+        m(1, 0),
+        m(1, 1),
+        m(1, 2),
+        m(1, 3),
+        m(2, 0),
+        m(2, 1),
+        m(2, 2),
+        m(2, 3),
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Geometry;
+    use pmath::ray::Ray;
+    use std::fs;
+
+    /// Writes the file to the temporary directory and returns its path.
+    fn write_temp(name: &str, contents: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("prism_gltf_{}_{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn textured_cube_loads() {
+        // A unit cube with uvs and 16 bit indices:
+        let mut buffer = Vec::new();
+        for i in 0..8 {
+            for &bit in &[1, 2, 4] {
+                let coord: f32 = if i & bit != 0 { 1.0 } else { -1.0 };
+                buffer.extend_from_slice(&coord.to_le_bytes());
+            }
+        }
+        for i in 0..8 {
+            for &bit in &[1, 2] {
+                let uv: f32 = if i & bit != 0 { 1.0 } else { 0.0 };
+                buffer.extend_from_slice(&uv.to_le_bytes());
+            }
+        }
+        let faces: [[u16; 4]; 6] = [
+            [0, 1, 3, 2],
+            [4, 6, 7, 5],
+            [0, 4, 5, 1],
+            [2, 3, 7, 6],
+            [0, 2, 6, 4],
+            [1, 5, 7, 3],
+        ];
+        for face in faces.iter() {
+            for &i in &[face[0], face[1], face[2], face[0], face[2], face[3]] {
+                buffer.extend_from_slice(&i.to_le_bytes());
+            }
+        }
+        let bin_path = write_temp("cube.bin", &buffer);
+        let texels = [
+            255u8, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 255, 255, 255, 255,
+        ];
+        let png = lodepng::encode_memory(&texels, 2, 2, lodepng::ColorType::RGBA, 8).unwrap();
+        let png_path = write_temp("cube.png", &png);
+
+        let file_name = |path: &str| {
+            std::path::Path::new(path)
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_owned()
+        };
+        let gltf = format!(
+            r#"{{
+    "asset": {{ "version": "2.0" }},
+    "scene": 0,
+    "scenes": [{{ "nodes": [0, 1] }}],
+    "nodes": [
+        {{ "mesh": 0, "translation": [0.0, 0.0, 5.0] }},
+        {{ "camera": 0 }}
+    ],
+    "cameras": [{{ "type": "perspective", "perspective": {{ "yfov": 0.8, "znear": 0.01 }} }}],
+    "meshes": [{{
+        "primitives": [{{ "attributes": {{ "POSITION": 0, "TEXCOORD_0": 1 }}, "indices": 2, "material": 0 }}]
+    }}],
+    "materials": [{{
+        "name": "textured",
+        "pbrMetallicRoughness": {{ "baseColorTexture": {{ "index": 0 }}, "metallicFactor": 0.0 }}
+    }}],
+    "textures": [{{ "source": 0 }}],
+    "images": [{{ "uri": "{}" }}],
+    "buffers": [{{ "byteLength": {}, "uri": "{}" }}],
+    "bufferViews": [
+        {{ "buffer": 0, "byteOffset": 0, "byteLength": 96 }},
+        {{ "buffer": 0, "byteOffset": 96, "byteLength": 64 }},
+        {{ "buffer": 0, "byteOffset": 160, "byteLength": 72 }}
+    ],
+    "accessors": [
+        {{ "bufferView": 0, "componentType": 5126, "count": 8, "type": "VEC3",
+           "min": [-1.0, -1.0, -1.0], "max": [1.0, 1.0, 1.0] }},
+        {{ "bufferView": 1, "componentType": 5126, "count": 8, "type": "VEC2" }},
+        {{ "bufferView": 2, "componentType": 5123, "count": 36, "type": "SCALAR" }}
+    ]
+}}"#,
+            file_name(&png_path),
+            buffer.len(),
+            file_name(&bin_path)
+        );
+        let gltf_path = write_temp("cube.gltf", gltf.as_bytes());
+        let scene = load_gltf(&gltf_path, Vec2 { x: 64, y: 64 }, ImportOptions::default());
+        for path in &[&gltf_path, &bin_path, &png_path] {
+            fs::remove_file(path).unwrap();
+        }
+        let scene = scene.unwrap();
+
+        assert_eq!(scene.meshes.len(), 1);
+        assert_eq!(scene.meshes[0].num_triangles(), 12);
+        assert_eq!(scene.instances.len(), 1);
+        assert_eq!(scene.instances[0].material, Some(0));
+        assert_eq!(scene.materials.len(), 1);
+        assert_eq!(scene.materials[0].name.as_deref(), Some("textured"));
+        let texture = scene.materials[0].base_color_texture.as_ref().unwrap();
+        assert_eq!(texture.get_res(), Vec2 { x: 2, y: 2 });
+        assert!(scene.camera.is_some());
+
+        // The cube is placed in front of the camera:
+        let transf = scene.instances[0].transf;
+        assert_eq!(transf.point(Vec3::zero()).z, 5.0);
+        let dir = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        let ray = Ray::new(transf.inverse().point(Vec3::zero()), dir, 0.0);
+        let hit = scene.meshes[0].intersect(ray).unwrap();
+        assert!((hit.t - 4.0).abs() < 1e-6);
+    }
+}
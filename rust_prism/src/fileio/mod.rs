@@ -1,2 +1,154 @@
+#[cfg(feature = "gltf")]
+pub mod gltf;
 pub mod ply;
+mod pmesh;
 pub mod scene;
+
+use crate::bvh::BuildAlgorithm;
+use crate::geometry::mesh::{Triangle, UvProjection};
+use crate::transform::Transf;
+use pmath::matrix::Mat3x4;
+use pmath::vector::Vec3;
+use serde::Deserialize;
+
+/// The axis that points up in a file. The renderer uses y as up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum UpAxis {
+    Y,
+    Z,
+}
+
+impl Default for UpAxis {
+    fn default() -> Self {
+        UpAxis::Y
+    }
+}
+
+/// The handedness of the coordinate system of a file. The renderer uses a left-handed coordinate
+/// system (see `Transf::new_lookat`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum Handedness {
+    Left,
+    Right,
+}
+
+impl Default for Handedness {
+    fn default() -> Self {
+        Handedness::Left
+    }
+}
+
+/// Options that control how geometry is processed when it's imported.
+#[derive(Clone, Copy, Debug)]
+pub struct ImportOptions {
+    pub max_triangles_per_leaf: usize,
+    pub bvh_algorithm: BuildAlgorithm,
+    /// If set, meshes without normals get smooth normals generated for them.
+    /// The value is the crease angle (in degrees) above which edges are kept hard.
+    pub smooth_normals: Option<f64>,
+    /// If set, vertices within this distance of each other are merged and degenerate
+    /// triangles are removed.
+    pub weld: Option<f64>,
+    /// Store meshes in the compact (interleaved) representation.
+    pub compact: bool,
+    /// Cache the BVHs of meshes next to the file they were loaded from (as `<file>.bvhcache`),
+    /// so that they don't have to be constructed again the next time. Not used when welding.
+    pub bvh_cache: bool,
+    /// Cache the parsed buffers of PLY files next to the file (as `<file>.pmesh`), which loads much
+    /// faster than parsing the file again. The cache is rebuilt whenever the file changes.
+    pub mesh_cache: bool,
+    /// The up axis of the file, which is rotated onto the y-axis.
+    pub up_axis: UpAxis,
+    /// The handedness of the file. Right-handed files are mirrored along the z-axis (which also flips
+    /// the winding of the triangles).
+    pub handedness: Handedness,
+    /// Flips the winding of the triangles (on top of any flip from the handedness).
+    pub flip_winding: bool,
+    /// A uniform scale (has to be positive), for files that use different units.
+    pub scale: f64,
+    /// How uvs are generated for meshes that don't have any.
+    pub uv_projection: UvProjection,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions {
+            max_triangles_per_leaf: 4,
+            bvh_algorithm: BuildAlgorithm::Sah,
+            smooth_normals: None,
+            weld: None,
+            compact: false,
+            bvh_cache: false,
+            mesh_cache: false,
+            up_axis: UpAxis::Y,
+            handedness: Handedness::Left,
+            flip_winding: false,
+            scale: 1.0,
+            uv_projection: UvProjection::Fixed,
+        }
+    }
+}
+
+impl ImportOptions {
+    /// The transformation from the coordinate system of the file to the one of the renderer.
+    pub fn conversion_transf(&self) -> Transf {
+        let up = match self.up_axis {
+            UpAxis::Y => Transf::new_identity(),
+            // Rotates z onto y (and y onto -z):
+            UpAxis::Z => Transf::from_mat3x4(Mat3x4::from_arr([
+                1.0, 0.0, 0.0, 0.0, //
+                0.0, 0.0, 1.0, 0.0, //
+                0.0, -1.0, 0.0, 0.0,
+            ])),
+        };
+        let mirror = match self.handedness {
+            Handedness::Left => 1.0,
+            Handedness::Right => -1.0,
+        };
+        Transf::new_scale(Vec3 {
+            x: self.scale,
+            y: self.scale,
+            z: mirror * self.scale,
+        }) * up
+    }
+
+    /// Whether the winding of the triangles has to be flipped, either because it was asked for or
+    /// because the conversion mirrors the geometry.
+    pub fn flips_winding(&self) -> bool {
+        self.flip_winding ^ (self.handedness == Handedness::Right)
+    }
+
+    fn is_identity(&self) -> bool {
+        (self.up_axis == UpAxis::Y)
+            && (self.handedness == Handedness::Left)
+            && !self.flip_winding
+            && (self.scale == 1.0)
+    }
+
+    /// Converts the vertices and triangles of a mesh (before its BVH is constructed) from the coordinate
+    /// system of the file to the one of the renderer.
+    pub fn convert_mesh(
+        &self,
+        triangles: &mut [Triangle],
+        pos: &mut [Vec3<f32>],
+        nrm: &mut [Vec3<f32>],
+        tan: &mut [Vec3<f32>],
+    ) {
+        if self.is_identity() {
+            return;
+        }
+
+        let transf = self.conversion_transf();
+        transf.points_f32(pos);
+        transf.normals_f32(nrm);
+        transf.vectors_f32(tan);
+        for t in tan.iter_mut() {
+            *t = t.normalize();
+        }
+        if self.flips_winding() {
+            for triangle in triangles.iter_mut() {
+                triangle.indices.swap(1, 2);
+            }
+        }
+    }
+}
@@ -1,8 +1,12 @@
+use crate::error::{PrismError, PrismResult};
+use crate::fileio::{pmesh, ImportOptions};
 use crate::geometry::mesh::{Mesh, Triangle};
 use pmath::vector::{Vec2, Vec3};
 use rply;
-use simple_error::{bail, SimpleResult};
+use simple_error::bail;
 use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
 use std::mem::MaybeUninit;
 use std::os::raw;
 use std::ptr;
@@ -15,63 +19,94 @@ extern "C" fn error_cb(_: rply::p_ply, message: *const raw::c_char) {
     );
 }
 
+/// Returns the buffer the callback reads into, the index of the property that is read (see `set_read_cbs`),
+/// and the index of the element that is read.
+unsafe fn get_argument<'a, T>(
+    argument: rply::p_ply_argument,
+) -> Option<(&'a mut Vec<T>, usize, usize)> {
+    let mut buffer_ptr = ptr::null_mut();
+    let mut item_index = 0;
+    if rply::ply_get_argument_user_data(argument, &mut buffer_ptr, &mut item_index) == 0 {
+        // I think that the error_callback gets called so I don't have to log anything else
+        return None;
+    }
+    let mut index = 0;
+    if rply::ply_get_argument_element(argument, ptr::null_mut(), &mut index) == 0 {
+        return None;
+    }
+    Some((
+        &mut *(buffer_ptr as *mut Vec<T>),
+        item_index as usize,
+        index as usize,
+    ))
+}
+
+// The buffers are sized before reading, so an element that doesn't fit means that the file has more
+// elements than the header says, which is an error.
+
 extern "C" fn vec3_cb(argument: rply::p_ply_argument) -> raw::c_int {
-    let (item_index, buffer) = unsafe {
-        let mut item_index = MaybeUninit::uninit().assume_init();
-        let mut buffer_ptr = MaybeUninit::uninit().assume_init();
-        if rply::ply_get_argument_user_data(argument, &mut buffer_ptr, &mut item_index) == 0 {
-            // I think that the error_callback gets called so I don't have to log anything else
-            return 0;
-        }
-        (item_index as usize, &mut *(buffer_ptr as *mut Vec<f32>))
+    let (buffer, item_index, index) = match unsafe { get_argument::<Vec3<f32>>(argument) } {
+        Some(argument) => argument,
+        None => return 0,
     };
-
-    let index = unsafe {
-        let mut index = MaybeUninit::uninit().assume_init();
-        if rply::ply_get_argument_element(argument, ptr::null_mut(), &mut index) == 0 {
-            return 0;
+    match buffer.get_mut(index) {
+        Some(v) => {
+            v[item_index] = unsafe { rply::ply_get_argument_value(argument) } as f32;
+            1
         }
-        index as usize
-    };
-
-    let buff_index = 3 * index + item_index;
-    unsafe {
-        *buffer.get_unchecked_mut(buff_index) = rply::ply_get_argument_value(argument) as f32;
+        None => 0,
     }
-
-    1
 }
 
 extern "C" fn vec2_cb(argument: rply::p_ply_argument) -> raw::c_int {
-    let (item_index, buffer) = unsafe {
-        let mut item_index = MaybeUninit::uninit().assume_init();
-        let mut buffer_ptr = MaybeUninit::uninit().assume_init();
-        if rply::ply_get_argument_user_data(argument, &mut buffer_ptr, &mut item_index) == 0 {
-            // I think that the error_callback gets called so I don't have to log anything else
-            return 0;
-        }
-        (item_index as usize, &mut *(buffer_ptr as *mut Vec<f32>))
+    let (buffer, item_index, index) = match unsafe { get_argument::<Vec2<f32>>(argument) } {
+        Some(argument) => argument,
+        None => return 0,
     };
-
-    let index = unsafe {
-        let mut index = MaybeUninit::uninit().assume_init();
-        if rply::ply_get_argument_element(argument, ptr::null_mut(), &mut index) == 0 {
-            return 0;
+    match buffer.get_mut(index) {
+        Some(v) => {
+            v[item_index] = unsafe { rply::ply_get_argument_value(argument) } as f32;
+            1
         }
-        index as usize
-    };
+        None => 0,
+    }
+}
 
-    let buff_index = 2 * index + item_index;
-    unsafe {
-        *buffer.get_unchecked_mut(buff_index) = rply::ply_get_argument_value(argument) as f32;
+extern "C" fn edge_cb(argument: rply::p_ply_argument) -> raw::c_int {
+    let (buffer, item_index, index) = match unsafe { get_argument::<[u32; 2]>(argument) } {
+        Some(argument) => argument,
+        None => return 0,
+    };
+    match buffer.get_mut(index) {
+        Some(edge) => {
+            edge[item_index] = unsafe { rply::ply_get_argument_value(argument) } as u32;
+            1
+        }
+        None => 0,
     }
+}
 
-    1
+extern "C" fn attribute_cb(argument: rply::p_ply_argument) -> raw::c_int {
+    let (buffer, _, index) = match unsafe { get_argument::<u32>(argument) } {
+        Some(argument) => argument,
+        None => return 0,
+    };
+    let value = unsafe { rply::ply_get_argument_value(argument) };
+    match buffer.get_mut(index) {
+        Some(id) if value >= 0.0 => {
+            *id = value as u32;
+            1
+        }
+        _ => 0,
+    }
 }
 
 struct IndexBuffer {
-    all_triangles: bool,
+    // The indices of the face currently being read:
+    polygon: Vec<u32>,
     buffer: Vec<Triangle>,
+    // The face each triangle came from:
+    faces: Vec<usize>,
 }
 
 extern "C" fn index_cb(argument: rply::p_ply_argument) -> raw::c_int {
@@ -99,52 +134,239 @@ extern "C" fn index_cb(argument: rply::p_ply_argument) -> raw::c_int {
         (num_indices as usize, face_index)
     };
 
-    if num_indices != 3 {
-        buffer.all_triangles = false;
-        return 0;
-    }
-
+    // A negative face index means that we are reading the length of the list:
     if face_index < 0 {
+        buffer.polygon.clear();
         return 1;
     }
 
-    let index = unsafe {
-        let mut index = MaybeUninit::uninit().assume_init();
-        if rply::ply_get_argument_element(argument, ptr::null_mut(), &mut index) == 0 {
-            return 0;
+    buffer
+        .polygon
+        .push(unsafe { rply::ply_get_argument_value(argument) } as u32);
+
+    // Once the whole face has been read we triangulate it as a fan (which is only
+    // correct for convex faces, but that is what most exporters write anyways).
+    // Degenerate faces with fewer than 3 vertices are ignored.
+    if face_index as usize == num_indices - 1 {
+        let face = unsafe {
+            let mut face = 0i64;
+            if rply::ply_get_argument_element(argument, ptr::null_mut(), &mut face) == 0 {
+                return 0;
+            }
+            face as usize
+        };
+
+        let polygon = &buffer.polygon;
+        for i in 1..(polygon.len().max(2) - 1) {
+            buffer.buffer.push(Triangle {
+                indices: [polygon[0], polygon[i], polygon[i + 1]],
+                attribute_id: 0,
+            });
+            buffer.faces.push(face);
         }
-        index as usize
-    };
+    }
+
+    1
+}
 
-    //let buff_index = (face_index as usize) + num_indices * index;
+/// Registers `cb` to read the properties `names` of `element` into `buffer` (the callback is passed the
+/// index of the property in `names`), but only if the element has all of them, and returns whether it did.
+/// Partial sets of properties (like only `red` and `green`) are skipped, as the buffer is only sized when
+/// all of them are present.
+fn set_read_cbs<T>(
+    file: rply::p_ply,
+    element: &[u8],
+    names: &[&[u8]],
+    cb: rply::p_ply_read_cb,
+    buffer: &mut Vec<T>,
+) -> bool {
     unsafe {
-        *buffer
-            .buffer
-            .get_unchecked_mut(index)
-            .indices
-            .get_unchecked_mut(face_index as usize) = rply::ply_get_argument_value(argument) as u32;
+        let element = CStr::from_bytes_with_nul_unchecked(element);
+        if names.iter().any(|name| {
+            get_property_type(file, element, CStr::from_bytes_with_nul_unchecked(name)).is_none()
+        }) {
+            return false;
+        }
+        for (i, name) in names.iter().enumerate() {
+            rply::ply_set_read_cb(
+                file,
+                element.as_ptr(),
+                CStr::from_bytes_with_nul_unchecked(name).as_ptr(),
+                cb,
+                (buffer as *mut Vec<T>) as *mut raw::c_void,
+                i as raw::c_long,
+            );
+        }
     }
+    true
+}
 
-    1
+/// Returns the type of a property of a specific element, if it exists.
+fn get_property_type(
+    file: rply::p_ply,
+    element_name: &CStr,
+    property_name: &CStr,
+) -> Option<rply::e_ply_type> {
+    let mut element = ptr::null_mut();
+    loop {
+        element = unsafe { rply::ply_get_next_element(file, element) };
+        if ptr::eq(element, ptr::null()) {
+            return None;
+        }
+
+        let mut name = ptr::null();
+        unsafe {
+            rply::ply_get_element_info(element, &mut name, ptr::null_mut());
+            if CStr::from_ptr(name).eq(element_name) {
+                break;
+            }
+        }
+    }
+
+    let mut property = ptr::null_mut();
+    loop {
+        property = unsafe { rply::ply_get_next_property(element, property) };
+        if ptr::eq(property, ptr::null()) {
+            return None;
+        }
+
+        let mut name = ptr::null();
+        let mut prop_type = 0;
+        unsafe {
+            rply::ply_get_property_info(
+                property,
+                &mut name,
+                &mut prop_type,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            );
+            if CStr::from_ptr(name).eq(property_name) {
+                return Some(prop_type);
+            }
+        }
+    }
+}
+
+/// Converts an sRGB encoded value (in [0, 1]) to linear space.
+fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// The buffers of a PLY file as they were read (before any `ImportOptions` are applied).
+pub(super) struct PlyData {
+    pub triangles: Vec<Triangle>,
+    pub pos: Vec<Vec3<f32>>,
+    pub nrm: Vec<Vec3<f32>>,
+    pub tan: Vec<Vec3<f32>>,
+    pub uvs: Vec<Vec2<f32>>,
+    pub col: Vec<Vec3<f32>>,
+    pub creases: Vec<[u32; 2]>,
+}
+
+/// Loads the mesh at the designated path. rply takes care of the different storage
+/// formats (ascii and binary of either endianness) and converts all property types for us.
+/// Faces with more than 3 vertices are fan triangulated.
+pub fn load_mesh(path: &str, options: ImportOptions) -> PrismResult<Mesh> {
+    let data = if options.mesh_cache {
+        let cache_path = format!("{}.pmesh", path);
+        match pmesh::read_cache(path, &cache_path) {
+            Some(data) => data,
+            None => {
+                let data = read_ply(path)?;
+                // Not being able to write the cache isn't a reason to fail:
+                let _ = pmesh::write_cache(path, &cache_path, &data);
+                data
+            }
+        }
+    } else {
+        read_ply(path)?
+    };
+    let PlyData {
+        mut triangles,
+        mut pos,
+        mut nrm,
+        mut tan,
+        uvs,
+        col,
+        creases,
+    } = data;
+
+    options.convert_mesh(&mut triangles, &mut pos, &mut nrm, &mut tan);
+
+    let has_nrm = !nrm.is_empty();
+    // Welding changes the triangles, so the BVH is constructed again regardless:
+    let mut mesh = if options.bvh_cache && options.weld.is_none() {
+        Mesh::new_cached(
+            triangles,
+            pos,
+            nrm,
+            tan,
+            uvs,
+            col,
+            options.max_triangles_per_leaf,
+            options.bvh_algorithm,
+            &format!("{}.bvhcache", path),
+        )
+    } else {
+        Mesh::new(
+            triangles,
+            pos,
+            nrm,
+            tan,
+            uvs,
+            col,
+            options.max_triangles_per_leaf,
+            options.bvh_algorithm,
+        )
+    };
+    mesh.set_creases(creases);
+    mesh.set_uv_projection(options.uv_projection);
+    if let Some(epsilon) = options.weld {
+        mesh.weld(epsilon);
+    }
+    if let (false, Some(angle)) = (has_nrm, options.smooth_normals) {
+        mesh.compute_smooth_normals(angle);
+    }
+    if options.compact {
+        mesh.compact();
+    }
+
+    Ok(mesh)
 }
 
-/// Loads the mesh at the designated path:
-pub fn load_mesh(path: &str) -> SimpleResult<Mesh> {
+fn read_ply(path: &str) -> PrismResult<PlyData> {
     let file = if let Ok(cstr_path) = CString::new(path) {
         unsafe { rply::ply_open(cstr_path.as_ptr(), Some(error_cb), 0, ptr::null_mut()) }
     } else {
         bail!("Could not convert the following to a valid path: {}", path)
     };
+    if ptr::eq(file, ptr::null()) {
+        // rply doesn't say why, so open the file ourselves to find out:
+        let err = File::open(path).err().unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "couldn't open PLY file")
+        });
+        return Err(PrismError::io(path, err));
+    }
 
     unsafe {
         if rply::ply_read_header(file) == 0 {
-            bail!("Couldn't parse header for PLY file at: {}", path);
+            rply::ply_close(file);
+            return Err(PrismError::parse(
+                path,
+                None,
+                "couldn't parse the PLY header",
+            ));
         }
     }
 
     let mut element = ptr::null_mut();
     let mut num_vertices = 0;
-    let mut num_triangles = 0;
+    let mut num_faces = 0;
+    let mut num_edges = 0;
     loop {
         element = unsafe { rply::ply_get_next_element(file, element) };
         if ptr::eq(element, ptr::null()) {
@@ -162,274 +384,779 @@ pub fn load_mesh(path: &str) -> SimpleResult<Mesh> {
             if element_name.eq(CStr::from_bytes_with_nul_unchecked(b"vertex\0")) {
                 num_vertices = num_elements as usize;
             } else if element_name.eq(CStr::from_bytes_with_nul_unchecked(b"face\0")) {
-                num_triangles = num_elements as usize;
+                num_faces = num_elements as usize;
+            } else if element_name.eq(CStr::from_bytes_with_nul_unchecked(b"edge\0")) {
+                num_edges = num_elements as usize;
             }
         };
     }
 
-    if num_vertices == 0 || num_triangles == 0 {
-        bail!("No vertices or faces in the PLY file at: {}", path);
+    if num_vertices == 0 || num_faces == 0 {
+        unsafe {
+            rply::ply_close(file);
+        }
+        return Err(PrismError::parse(path, None, "no vertices or faces"));
     }
 
     let mut poss = Vec::new();
     let mut norms = Vec::new();
     let mut tans = Vec::new();
     let mut uvs = Vec::new();
+    let mut cols = Vec::new();
     let mut indices = IndexBuffer {
-        all_triangles: true,
+        polygon: Vec::new(),
         buffer: Vec::new(),
+        faces: Vec::new(),
     };
 
     // Get Position information:
 
-    let has_x = unsafe {
-        rply::ply_set_read_cb(
-            file,
-            CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"x\0").as_ptr(),
-            Some(vec3_cb),
-            (&mut poss as *mut Vec<Vec3<f32>>) as *mut raw::c_void,
-            0,
-        )
-    };
-    let has_y = unsafe {
-        rply::ply_set_read_cb(
-            file,
-            CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"y\0").as_ptr(),
-            Some(vec3_cb),
-            (&mut poss as *mut Vec<Vec3<f32>>) as *mut raw::c_void,
-            1,
-        )
-    };
-    let has_z = unsafe {
-        rply::ply_set_read_cb(
-            file,
-            CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"z\0").as_ptr(),
-            Some(vec3_cb),
-            (&mut poss as *mut Vec<Vec3<f32>>) as *mut raw::c_void,
-            2,
-        )
-    };
-    if has_x == 0 || has_y == 0 || has_z == 0 {
-        bail!("No position information in the PLY file at: {}", path);
+    if !set_read_cbs(
+        file,
+        b"vertex\0",
+        &[b"x\0", b"y\0", b"z\0"],
+        Some(vec3_cb),
+        &mut poss,
+    ) {
+        unsafe {
+            rply::ply_close(file);
+        }
+        return Err(PrismError::parse(path, None, "no vertex positions"));
     }
-
     // Make sure to reserve space for one more. This is needed because
     // embree needs to access vertex data is groups of 4.
-    poss.reserve_exact(num_vertices + 1);
-    unsafe {
-        poss.set_len(num_vertices + 1);
-    }
+    poss.resize(num_vertices + 1, Vec3::zero());
 
     // Get Normal information:
 
-    let has_nx = unsafe {
-        rply::ply_set_read_cb(
-            file,
-            CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"nx\0").as_ptr(),
-            Some(vec3_cb),
-            (&mut norms as *mut Vec<Vec3<f32>>) as *mut raw::c_void,
-            0,
-        )
-    };
-    let has_ny = unsafe {
-        rply::ply_set_read_cb(
+    if set_read_cbs(
+        file,
+        b"vertex\0",
+        &[b"nx\0", b"ny\0", b"nz\0"],
+        Some(vec3_cb),
+        &mut norms,
+    ) {
+        norms.resize(num_vertices, Vec3::zero());
+    }
+
+    // Get Tangent information:
+
+    if set_read_cbs(
+        file,
+        b"vertex\0",
+        &[b"tx\0", b"ty\0", b"tz\0"],
+        Some(vec3_cb),
+        &mut tans,
+    ) {
+        tans.resize(num_vertices, Vec3::zero());
+    }
+
+    // Get UV information:
+    // Note that there are many naming schemes for this value, the first one that is complete is used:
+
+    let uv_names: [[&[u8]; 2]; 4] = [
+        [b"u\0", b"v\0"],
+        [b"s\0", b"t\0"],
+        [b"texture_u\0", b"texture_v\0"],
+        [b"texture_s\0", b"texture_t\0"],
+    ];
+    if uv_names
+        .iter()
+        .any(|names| set_read_cbs(file, b"vertex\0", names, Some(vec2_cb), &mut uvs))
+    {
+        uvs.resize(num_vertices, Vec2::zero());
+    }
+
+    // Get Color information:
+
+    // Colors are usually stored as uchars, in which case they have to be normalized (and 8 bit colors
+    // are in sRGB, so they are converted to linear space as well):
+    let (col_scale, col_srgb) = match unsafe {
+        get_property_type(
             file,
-            CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"ny\0").as_ptr(),
-            Some(vec3_cb),
-            (&mut norms as *mut Vec<Vec3<f32>>) as *mut raw::c_void,
-            1,
+            CStr::from_bytes_with_nul_unchecked(b"vertex\0"),
+            CStr::from_bytes_with_nul_unchecked(b"red\0"),
         )
+    } {
+        Some(rply::e_ply_type_PLY_UCHAR) | Some(rply::e_ply_type_PLY_UINT8) => (1.0 / 255.0, true),
+        Some(rply::e_ply_type_PLY_USHORT) | Some(rply::e_ply_type_PLY_UINT16) => {
+            (1.0 / 65535.0, false)
+        }
+        _ => (1.0, false),
     };
-    let has_nz = unsafe {
+
+    if set_read_cbs(
+        file,
+        b"vertex\0",
+        &[b"red\0", b"green\0", b"blue\0"],
+        Some(vec3_cb),
+        &mut cols,
+    ) {
+        cols.resize(num_vertices, Vec3::zero());
+    }
+
+    // Get Index information:
+
+    let has_index = unsafe {
         rply::ply_set_read_cb(
             file,
-            CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"nz\0").as_ptr(),
-            Some(vec3_cb),
-            (&mut norms as *mut Vec<Vec3<f32>>) as *mut raw::c_void,
-            2,
+            CStr::from_bytes_with_nul_unchecked(b"face\0").as_ptr(),
+            CStr::from_bytes_with_nul_unchecked(b"vertex_indices\0").as_ptr(),
+            Some(index_cb),
+            (&mut indices as *mut IndexBuffer) as *mut raw::c_void,
+            0,
         )
     };
-    if has_nx != 0 && has_ny != 0 && has_nz != 0 {
-        norms.reserve_exact(num_vertices);
+    // Some exporters use vertex_index instead:
+    let has_index = has_index != 0
+        || unsafe {
+            rply::ply_set_read_cb(
+                file,
+                CStr::from_bytes_with_nul_unchecked(b"face\0").as_ptr(),
+                CStr::from_bytes_with_nul_unchecked(b"vertex_index\0").as_ptr(),
+                Some(index_cb),
+                (&mut indices as *mut IndexBuffer) as *mut raw::c_void,
+                0,
+            )
+        } != 0;
+    if !has_index {
         unsafe {
-            norms.set_len(num_vertices);
+            rply::ply_close(file);
         }
+        return Err(PrismError::parse(path, None, "no face indices"));
     }
 
-    // Get Tangent information:
+    // Get per face attribute ids (used for assigning different materials):
 
-    let has_tx = unsafe {
-        rply::ply_set_read_cb(
-            file,
-            CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"tx\0").as_ptr(),
-            Some(vec3_cb),
-            (&mut tans as *mut Vec<Vec3<f32>>) as *mut raw::c_void,
-            0,
-        )
+    let mut attribute_ids = Vec::new();
+    let has_attribute_id = [&b"material_index\0"[..], &b"attribute_id\0"[..]]
+        .iter()
+        .any(|name| unsafe {
+            rply::ply_set_read_cb(
+                file,
+                CStr::from_bytes_with_nul_unchecked(b"face\0").as_ptr(),
+                CStr::from_bytes_with_nul_unchecked(name).as_ptr(),
+                Some(attribute_cb),
+                (&mut attribute_ids as *mut Vec<u32>) as *mut raw::c_void,
+                0,
+            ) != 0
+        });
+    if has_attribute_id {
+        attribute_ids.resize(num_faces, 0);
+    }
+
+    // Get Edge information:
+    // Any edges listed in the file are treated as creases for subdivision.
+
+    let mut creases = Vec::new();
+    if set_read_cbs(
+        file,
+        b"edge\0",
+        &[b"vertex1\0", b"vertex2\0"],
+        Some(edge_cb),
+        &mut creases,
+    ) {
+        creases.resize(num_edges, [0, 0]);
+    }
+
+    // Most faces are going to be triangles, so this is a good guess:
+    indices.buffer.reserve(num_faces);
+
+    let result = unsafe { rply::ply_read(file) };
+    unsafe {
+        rply::ply_close(file);
+    }
+
+    if result == 0 {
+        return Err(PrismError::parse(path, None, "couldn't read the PLY data"));
+    }
+
+    if indices
+        .buffer
+        .iter()
+        .any(|tri| tri.indices.iter().any(|&i| i as usize >= num_vertices))
+    {
+        return Err(PrismError::parse(path, None, "out of bounds vertex index"));
+    }
+
+    if has_attribute_id {
+        for (triangle, &face) in indices.buffer.iter_mut().zip(indices.faces.iter()) {
+            triangle.attribute_id = attribute_ids[face];
+        }
+    }
+
+    for col in cols.iter_mut() {
+        *col = col.scale(col_scale);
+        if col_srgb {
+            *col = Vec3 {
+                x: srgb_to_linear(col.x),
+                y: srgb_to_linear(col.y),
+                z: srgb_to_linear(col.z),
+            };
+        }
+    }
+
+    if creases
+        .iter()
+        .any(|crease| crease.iter().any(|&i| i as usize >= num_vertices))
+    {
+        return Err(PrismError::parse(path, None, "out of bounds edge index"));
+    }
+
+    // Remove the extra position we added for embree:
+    poss.truncate(num_vertices);
+    Ok(PlyData {
+        triangles: indices.buffer,
+        pos: poss,
+        nrm: norms,
+        tan: tans,
+        uvs,
+        col: cols,
+        creases,
+    })
+}
+
+/// Writes the mesh to a PLY file at the designated path, either in ascii or in binary
+/// (little endian). Every vertex attribute the mesh has is written out, as are the
+/// per face attribute ids if more than one is used.
+pub fn write_mesh(mesh: &Mesh, path: &str, binary: bool) -> PrismResult<()> {
+    let data = mesh.get_data();
+
+    let storage_mode = if binary {
+        rply::e_ply_storage_mode__PLY_LITTLE_ENDIAN
+    } else {
+        rply::e_ply_storage_mode__PLY_ASCII
     };
-    let has_ty = unsafe {
-        rply::ply_set_read_cb(
-            file,
-            CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"ty\0").as_ptr(),
-            Some(vec3_cb),
-            (&mut tans as *mut Vec<Vec3<f32>>) as *mut raw::c_void,
-            1,
-        )
+    let file = if let Ok(cstr_path) = CString::new(path) {
+        unsafe {
+            rply::ply_create(
+                cstr_path.as_ptr(),
+                storage_mode,
+                Some(error_cb),
+                0,
+                ptr::null_mut(),
+            )
+        }
+    } else {
+        bail!("Could not convert the following to a valid path: {}", path)
     };
-    let has_tz = unsafe {
-        rply::ply_set_read_cb(
+    if ptr::eq(file, ptr::null()) {
+        return Err(PrismError::io(path, io::Error::last_os_error()));
+    }
+
+    // All of the vertex properties, each one is a list of names:
+    let mut properties: Vec<&[&str]> = vec![&["x\0", "y\0", "z\0"]];
+    if data.has_nrm() {
+        properties.push(&["nx\0", "ny\0", "nz\0"]);
+    }
+    if data.has_tan() {
+        properties.push(&["tx\0", "ty\0", "tz\0"]);
+    }
+    if data.has_uvs() {
+        properties.push(&["u\0", "v\0"]);
+    }
+    if data.has_col() {
+        properties.push(&["red\0", "green\0", "blue\0"]);
+    }
+
+    let has_attribute_ids = mesh.num_attributes() > 1;
+
+    let header_ok = unsafe {
+        let mut ok = rply::ply_add_element(
             file,
             CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"tz\0").as_ptr(),
-            Some(vec3_cb),
-            (&mut tans as *mut Vec<Vec3<f32>>) as *mut raw::c_void,
-            2,
-        )
+            data.num_vertices() as raw::c_long,
+        ) != 0;
+        for name in properties.iter().flat_map(|names| names.iter()) {
+            ok = ok
+                && rply::ply_add_scalar_property(
+                    file,
+                    CStr::from_bytes_with_nul_unchecked(name.as_bytes()).as_ptr(),
+                    rply::e_ply_type_PLY_FLOAT,
+                ) != 0;
+        }
+
+        ok = ok
+            && rply::ply_add_element(
+                file,
+                CStr::from_bytes_with_nul_unchecked(b"face\0").as_ptr(),
+                data.triangles.len() as raw::c_long,
+            ) != 0
+            && rply::ply_add_list_property(
+                file,
+                CStr::from_bytes_with_nul_unchecked(b"vertex_indices\0").as_ptr(),
+                rply::e_ply_type_PLY_UCHAR,
+                rply::e_ply_type_PLY_INT,
+            ) != 0;
+        if has_attribute_ids {
+            ok = ok
+                && rply::ply_add_scalar_property(
+                    file,
+                    CStr::from_bytes_with_nul_unchecked(b"attribute_id\0").as_ptr(),
+                    rply::e_ply_type_PLY_INT,
+                ) != 0;
+        }
+
+        if !data.creases.is_empty() {
+            ok = ok
+                && rply::ply_add_element(
+                    file,
+                    CStr::from_bytes_with_nul_unchecked(b"edge\0").as_ptr(),
+                    data.creases.len() as raw::c_long,
+                ) != 0
+                && rply::ply_add_scalar_property(
+                    file,
+                    CStr::from_bytes_with_nul_unchecked(b"vertex1\0").as_ptr(),
+                    rply::e_ply_type_PLY_INT,
+                ) != 0
+                && rply::ply_add_scalar_property(
+                    file,
+                    CStr::from_bytes_with_nul_unchecked(b"vertex2\0").as_ptr(),
+                    rply::e_ply_type_PLY_INT,
+                ) != 0;
+        }
+
+        ok && rply::ply_write_header(file) != 0
     };
-    if has_tx != 0 && has_ty != 0 && has_tz != 0 {
-        tans.reserve_exact(num_vertices + 1);
+    if !header_ok {
         unsafe {
-            tans.set_len(num_vertices + 1);
+            rply::ply_close(file);
         }
+        return Err(PrismError::io(
+            path,
+            io::Error::new(io::ErrorKind::Other, "couldn't write the PLY header"),
+        ));
     }
 
-    // Get UV information:
-    // Note that there are many naming schemes for this value:
+    // The values are written out in the same order the properties were added:
+    let mut values = Vec::new();
+    for i in 0..data.num_vertices() as u32 {
+        let pos = data.pos_at(i);
+        values.extend_from_slice(&[pos.x, pos.y, pos.z]);
+        if data.has_nrm() {
+            let nrm = data.nrm_at(i);
+            values.extend_from_slice(&[nrm.x, nrm.y, nrm.z]);
+        }
+        if data.has_tan() {
+            let tan = data.tan[i as usize];
+            values.extend_from_slice(&[tan.x, tan.y, tan.z]);
+        }
+        if data.has_uvs() {
+            let uv = data.uv_at(i);
+            values.extend_from_slice(&[uv.x, uv.y]);
+        }
+        if data.has_col() {
+            let col = data.col[i as usize];
+            values.extend_from_slice(&[col.x, col.y, col.z]);
+        }
+    }
+    let mut result = values
+        .into_iter()
+        .all(|v| unsafe { rply::ply_write(file, v as f64) } != 0);
 
-    let has_u = unsafe {
-        rply::ply_set_read_cb(
-            file,
-            CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"u\0").as_ptr(),
-            Some(vec2_cb),
-            (&mut uvs as *mut Vec<Vec2<f32>>) as *mut raw::c_void,
-            0,
-        )
-    };
-    let has_v = unsafe {
-        rply::ply_set_read_cb(
-            file,
-            CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"v\0").as_ptr(),
-            Some(vec2_cb),
-            (&mut uvs as *mut Vec<Vec2<f32>>) as *mut raw::c_void,
-            1,
-        )
-    };
+    for triangle in data.triangles.iter() {
+        result = result
+            && unsafe { rply::ply_write(file, 3.0) } != 0
+            && triangle
+                .indices
+                .iter()
+                .all(|&i| unsafe { rply::ply_write(file, i as f64) } != 0)
+            && (!has_attribute_ids
+                || unsafe { rply::ply_write(file, triangle.attribute_id as f64) } != 0);
+    }
 
-    let has_s = unsafe {
-        rply::ply_set_read_cb(
-            file,
-            CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"s\0").as_ptr(),
-            Some(vec2_cb),
-            (&mut uvs as *mut Vec<Vec2<f32>>) as *mut raw::c_void,
-            0,
-        )
-    };
-    let has_t = unsafe {
-        rply::ply_set_read_cb(
-            file,
-            CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"t\0").as_ptr(),
-            Some(vec2_cb),
-            (&mut uvs as *mut Vec<Vec2<f32>>) as *mut raw::c_void,
-            1,
-        )
-    };
+    for crease in data.creases.iter() {
+        result = result
+            && crease
+                .iter()
+                .all(|&i| unsafe { rply::ply_write(file, i as f64) } != 0);
+    }
 
-    let has_texture_u = unsafe {
-        rply::ply_set_read_cb(
-            file,
-            CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"texture_u\0").as_ptr(),
-            Some(vec2_cb),
-            (&mut uvs as *mut Vec<Vec2<f32>>) as *mut raw::c_void,
-            0,
-        )
-    };
-    let has_texture_v = unsafe {
-        rply::ply_set_read_cb(
-            file,
-            CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"texture_v\0").as_ptr(),
-            Some(vec2_cb),
-            (&mut uvs as *mut Vec<Vec2<f32>>) as *mut raw::c_void,
-            1,
-        )
-    };
+    let closed = unsafe { rply::ply_close(file) } != 0;
+    if !result || !closed {
+        return Err(PrismError::io(
+            path,
+            io::Error::new(io::ErrorKind::Other, "couldn't write the PLY data"),
+        ));
+    }
 
-    let has_texture_s = unsafe {
-        rply::ply_set_read_cb(
-            file,
-            CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"texture_s\0").as_ptr(),
-            Some(vec2_cb),
-            (&mut uvs as *mut Vec<Vec2<f32>>) as *mut raw::c_void,
-            0,
-        )
-    };
-    let has_texture_t = unsafe {
-        rply::ply_set_read_cb(
-            file,
-            CStr::from_bytes_with_nul_unchecked(b"vertex\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"texture_t\0").as_ptr(),
-            Some(vec2_cb),
-            (&mut uvs as *mut Vec<Vec2<f32>>) as *mut raw::c_void,
-            1,
-        )
-    };
+    Ok(())
+}
 
-    if (has_u != 0 && has_v != 0)
-        || (has_s != 0 && has_t != 0)
-        || (has_texture_u != 0 && has_texture_v != 0)
-        || (has_texture_s != 0 && has_texture_t != 0)
-    {
-        uvs.reserve_exact(num_vertices);
-        unsafe {
-            uvs.set_len(num_vertices);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::BuildAlgorithm;
+    use crate::fileio::{Handedness, UpAxis};
+    use crate::geometry::Geometry;
+    use pmath::bbox::BBox3;
+    use pmath::ray::Ray;
+    use std::fs;
+    use std::path::Path;
+
+    /// Writes the file to the temporary directory and returns its path.
+    fn write_temp(name: &str, contents: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("prism_ply_{}_{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn polygons_are_fan_triangulated() {
+        let path = write_temp(
+            "ngon.ply",
+            b"ply
+format ascii 1.0
+element vertex 5
+property float x
+property float y
+property float z
+element face 2
+property list uchar int vertex_indices
+end_header
+0 0 0
+1 0 0
+1 1 0
+0 1 0
+-1 0.5 0
+4 0 1 2 3
+5 0 1 2 3 4
+",
+        );
+        let data = read_ply(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let triangles: Vec<[u32; 3]> = data.triangles.iter().map(|tri| tri.indices).collect();
+        assert_eq!(
+            triangles,
+            vec![[0, 1, 2], [0, 2, 3], [0, 1, 2], [0, 2, 3], [0, 3, 4]]
+        );
+        assert_eq!(data.pos.len(), 5);
+        assert_eq!(data.pos[4].x, -1.0);
+        assert_eq!(data.pos[4].y, 0.5);
+    }
+
+    #[test]
+    fn cached_meshes_match_parsed_ones() {
+        let ply = |size: f64| {
+            format!(
+                "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\n\
+                 property float z\nelement face 1\nproperty list uchar int vertex_indices\nend_header\n\
+                 0 0 0\n{} 0 0\n0 {} 0\n3 0 1 2\n",
+                size, size
+            )
+        };
+        let path = write_temp("cached.ply", ply(1.5).as_bytes());
+        let cache_path = format!("{}.pmesh", path);
+        let _ = fs::remove_file(&cache_path);
+        let cached = ImportOptions {
+            mesh_cache: true,
+            ..ImportOptions::default()
+        };
+
+        let parsed = load_mesh(&path, ImportOptions::default())
+            .unwrap()
+            .get_bbox();
+        assert!(!Path::new(&cache_path).exists());
+        let first = load_mesh(&path, cached).unwrap().get_bbox();
+        assert!(Path::new(&cache_path).exists());
+        let second = load_mesh(&path, cached).unwrap().get_bbox();
+        for bbox in [first, second].iter() {
+            assert_eq!(bbox.pmin, parsed.pmin);
+            assert_eq!(bbox.pmax, parsed.pmax);
         }
+
+        // Changing the file invalidates the cache:
+        fs::write(&path, ply(12.25)).unwrap();
+        let changed = load_mesh(&path, cached).unwrap().get_bbox();
+        assert_eq!(changed.pmax.x, 12.25);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&cache_path).unwrap();
     }
 
-    // Get Index information:
+    #[test]
+    fn partial_colors_are_ignored() {
+        let path = write_temp(
+            "partial_color.ply",
+            b"ply
+format ascii 1.0
+element vertex 3
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0 255 0
+1 0 0 0 255
+0 1 0 128 128
+3 0 1 2
+",
+        );
+        let data = read_ply(&path).unwrap();
+        fs::remove_file(&path).unwrap();
 
-    let has_index = unsafe {
-        rply::ply_set_read_cb(
-            file,
-            CStr::from_bytes_with_nul_unchecked(b"face\0").as_ptr(),
-            CStr::from_bytes_with_nul_unchecked(b"vertex_indices\0").as_ptr(),
-            Some(index_cb),
-            (&mut indices as *mut IndexBuffer) as *mut raw::c_void,
-            0,
-        )
-    };
-    if has_index == 0 {
-        bail!("No face information in the PLY file at: {}", path);
+        assert!(data.col.is_empty());
+        assert_eq!(data.pos.len(), 3);
+        assert_eq!(data.pos[1].x, 1.0);
+        assert_eq!(data.pos[2].y, 1.0);
     }
 
-    indices.buffer.reserve_exact(num_triangles);
-    unsafe {
-        indices.buffer.set_len(num_triangles);
+    #[test]
+    fn uchar_colors_are_converted_to_linear() {
+        let path = write_temp(
+            "color.ply",
+            b"ply
+format ascii 1.0
+element vertex 3
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0 255 0 0
+1 0 0 0 255 0
+0 1 0 0 0 255
+3 0 1 2
+",
+        );
+        let data = read_ply(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(data.col.len(), 3);
+        assert!((data.col[0].x - 1.0).abs() < 1e-6);
+        assert_eq!(data.col[0].y, 0.0);
+        assert!((data.col[2].z - 1.0).abs() < 1e-6);
     }
 
-    let result = unsafe { rply::ply_read(file) };
+    #[test]
+    fn binary_big_endian_doubles() {
+        let mut contents = b"ply
+format binary_big_endian 1.0
+element vertex 3
+property double x
+property double y
+property double z
+element face 1
+property list uchar uint vertex_indices
+end_header
+"
+        .to_vec();
+        for v in &[0.0f64, 0.0, 0.0, 2.5, 0.0, 0.0, 0.0, -3.25, 1.0] {
+            contents.extend_from_slice(&v.to_be_bytes());
+        }
+        contents.push(3);
+        for i in &[0u32, 1, 2] {
+            contents.extend_from_slice(&i.to_be_bytes());
+        }
+        let path = write_temp("big_endian.ply", &contents);
+        let data = read_ply(&path).unwrap();
+        fs::remove_file(&path).unwrap();
 
-    // First check if there were any issues we can deduce:
-    if !indices.all_triangles {
-        bail!("Non triangular face detected in PLY file at: {}", path)
+        assert_eq!(data.triangles[0].indices, [0, 1, 2]);
+        assert_eq!(data.pos[1].x, 2.5);
+        assert_eq!(data.pos[2].y, -3.25);
+        assert_eq!(data.pos[2].z, 1.0);
     }
 
-    if result == 0 {
-        bail!("Issue when reading PLY file at: {}", path);
+    #[test]
+    fn out_of_bounds_indices_are_an_error() {
+        let path = write_temp(
+            "out_of_bounds.ply",
+            b"ply
+format ascii 1.0
+element vertex 3
+property float x
+property float y
+property float z
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0
+1 0 0
+0 1 0
+3 0 1 3
+",
+        );
+        let result = read_ply(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn written_meshes_read_back_the_same() {
+        let vec3 = |x, y, z| Vec3::<f32> { x, y, z };
+        let pos = vec![
+            vec3(0.0, 0.0, 0.0),
+            vec3(1.5, 0.0, -0.25),
+            vec3(1.0, 2.0, 0.125),
+            vec3(-0.1, 1.0, 3.0e-7),
+        ];
+        let nrm = vec![
+            vec3(0.0, 0.0, 1.0),
+            vec3(0.6, 0.0, 0.8),
+            vec3(0.0, 0.8, 0.6),
+            vec3(0.0, -1.0, 0.0),
+        ];
+        let tan = vec![
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.8, 0.0, -0.6),
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.0, 0.0, 1.0),
+        ];
+        let uvs = vec![
+            Vec2 { x: 0.0, y: 0.0 },
+            Vec2 { x: 1.0, y: 0.0 },
+            Vec2 { x: 1.0, y: 1.0 },
+            Vec2 {
+                x: 1.0 / 3.0,
+                y: 0.7,
+            },
+        ];
+        let triangles = vec![
+            Triangle {
+                indices: [0, 1, 2],
+                attribute_id: 0,
+            },
+            Triangle {
+                indices: [0, 2, 3],
+                attribute_id: 1,
+            },
+        ];
+        let mesh = Mesh::new(
+            triangles.clone(),
+            pos.clone(),
+            nrm.clone(),
+            tan.clone(),
+            uvs.clone(),
+            Vec::new(),
+            4,
+            BuildAlgorithm::Sah,
+        );
+
+        for &binary in &[false, true] {
+            let path = std::env::temp_dir().join(format!(
+                "prism_ply_{}_written_{}.ply",
+                std::process::id(),
+                binary
+            ));
+            let path = path.to_str().unwrap();
+            write_mesh(&mesh, path, binary).unwrap();
+            let data = read_ply(path).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(data.triangles.len(), triangles.len());
+            for (read, written) in data.triangles.iter().zip(triangles.iter()) {
+                assert_eq!(read.indices, written.indices);
+                assert_eq!(read.attribute_id, written.attribute_id);
+            }
+            let close = |a: Vec3<f32>, b: Vec3<f32>| (a - b).length() < 1e-6;
+            assert!(data.pos.iter().zip(pos.iter()).all(|(&a, &b)| close(a, b)));
+            assert!(data.nrm.iter().zip(nrm.iter()).all(|(&a, &b)| close(a, b)));
+            assert!(data.tan.iter().zip(tan.iter()).all(|(&a, &b)| close(a, b)));
+            assert_eq!(data.pos.len(), pos.len());
+            assert_eq!(data.nrm.len(), nrm.len());
+            assert_eq!(data.tan.len(), tan.len());
+            assert_eq!(data.uvs.len(), uvs.len());
+            for (a, b) in data.uvs.iter().zip(uvs.iter()) {
+                assert!((a.x - b.x).abs() < 1e-6 && (a.y - b.y).abs() < 1e-6);
+            }
+        }
     }
 
-    Ok(Mesh::new(indices.buffer, poss, norms, tans, uvs))
+    #[test]
+    fn z_up_and_y_up_cubes_match() {
+        // A box from (1, 0, 3) to (2, 2, 6), with every face wound so that its normal points outwards.
+        // A right-handed Z-up file stores the y-coordinate of the renderer as z (and the other way
+        // around), and winds the faces the other way (so that they point outwards in its coordinate
+        // system):
+        let faces = [
+            [0, 2, 3, 1],
+            [4, 5, 7, 6],
+            [0, 1, 5, 4],
+            [2, 6, 7, 3],
+            [0, 4, 6, 2],
+            [1, 3, 7, 5],
+        ];
+        let ply = |z_up: bool| {
+            let mut contents = String::from(
+                "ply\nformat ascii 1.0\nelement vertex 8\nproperty float x\nproperty float y\n\
+                 property float z\nelement face 6\nproperty list uchar int vertex_indices\nend_header\n",
+            );
+            for i in 0..8 {
+                let x = 1 + (i & 1);
+                let y = 2 * ((i >> 1) & 1);
+                let z = 3 + 3 * ((i >> 2) & 1);
+                let (y, z) = if z_up { (z, y) } else { (y, z) };
+                contents.push_str(&format!("{} {} {}\n", x, y, z));
+            }
+            for face in faces.iter() {
+                let mut face = *face;
+                if z_up {
+                    face.reverse();
+                }
+                contents.push_str(&format!(
+                    "4 {} {} {} {}\n",
+                    face[0], face[1], face[2], face[3]
+                ));
+            }
+            contents
+        };
+        let y_up_path = write_temp("y_up.ply", ply(false).as_bytes());
+        let z_up_path = write_temp("z_up.ply", ply(true).as_bytes());
+        let y_up = load_mesh(&y_up_path, ImportOptions::default()).unwrap();
+        let z_up = load_mesh(
+            &z_up_path,
+            ImportOptions {
+                up_axis: UpAxis::Z,
+                handedness: Handedness::Right,
+                ..ImportOptions::default()
+            },
+        )
+        .unwrap();
+        fs::remove_file(&y_up_path).unwrap();
+        fs::remove_file(&z_up_path).unwrap();
+
+        let expected = BBox3 {
+            pmin: Vec3 {
+                x: 1.0,
+                y: 0.0,
+                z: 3.0,
+            },
+            pmax: Vec3 {
+                x: 2.0,
+                y: 2.0,
+                z: 6.0,
+            },
+        };
+        for mesh in &[&y_up, &z_up] {
+            let bbox = mesh.get_bbox();
+            assert!((bbox.pmin - expected.pmin).length() < 1e-6, "{:?}", bbox);
+            assert!((bbox.pmax - expected.pmax).length() < 1e-6, "{:?}", bbox);
+        }
+
+        // Rays from outside of the box towards its center hit every face from the front:
+        let center = expected.centroid();
+        for axis in 0..3 {
+            for &sign in &[-1.0, 1.0] {
+                let mut offset = Vec3::zero();
+                offset[axis] = sign * 10.0;
+                let ray = Ray::new(center + offset, -offset, 0.0);
+                let y_up_n = y_up.intersect(ray).unwrap().n;
+                let z_up_n = z_up.intersect(ray).unwrap().n;
+                assert!(
+                    y_up_n.dot(offset.normalize().cast()) > 0.999,
+                    "{:?}",
+                    y_up_n
+                );
+                assert!((y_up_n - z_up_n).length() < 1e-9, "{:?}", z_up_n);
+            }
+        }
+    }
 }
@@ -0,0 +1,337 @@
+//! A binary cache of the buffers of a PLY file (`.pmesh`), so that large (especially ascii) files only
+//! have to be parsed once. Everything is stored little endian:
+//!
+//! - the magic bytes `PMESH\0\0\0` and the version of the format (`u32`),
+//! - a hash of the size and the modification time of the file the cache was made from (`u64`),
+//! - the number of triangles, positions, normals, tangents, uvs, colors, and creases (`u64` each),
+//! - the buffers themselves in that order (triangles as their 3 indices and attribute id).
+//!
+//! A cache that doesn't match the file (or that is truncated) is ignored, and the file is parsed again.
+
+use crate::fileio::ply::PlyData;
+use crate::geometry::mesh::Triangle;
+use pmath::vector::{Vec2, Vec3};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::time::UNIX_EPOCH;
+
+const MAGIC: &[u8; 8] = b"PMESH\0\0\0";
+// Has to change whenever the layout does:
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 8 + 4 + 8 + 7 * 8;
+
+/// A hash of the size and modification time of the file (`None` if they aren't available).
+fn source_key(source_path: &str) -> Option<u64> {
+    let metadata = fs::metadata(source_path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    // FNV-1a, as it's stable (unlike the hasher in the standard library):
+    let hash_bytes = |hash: u64, bytes: &[u8]| {
+        bytes.iter().fold(hash, |hash, &byte| {
+            (hash ^ (byte as u64)).wrapping_mul(0x100000001b3)
+        })
+    };
+    let mut hash = 0xcbf29ce484222325;
+    hash = hash_bytes(hash, &metadata.len().to_le_bytes());
+    hash = hash_bytes(hash, &modified.as_secs().to_le_bytes());
+    hash = hash_bytes(hash, &modified.subsec_nanos().to_le_bytes());
+    Some(hash)
+}
+
+/// Reads the buffers from the cache if it exists and was made from the current version of the file.
+pub(super) fn read_cache(source_path: &str, cache_path: &str) -> Option<PlyData> {
+    let key = source_key(source_path)?;
+    let bytes = fs::read(cache_path).ok()?;
+    if bytes.len() < HEADER_SIZE || &bytes[..8] != MAGIC {
+        return None;
+    }
+    let mut reader = Reader { bytes: &bytes[8..] };
+    if reader.u32()? != VERSION || reader.u64()? != key {
+        return None;
+    }
+    let mut counts = [0usize; 7];
+    for count in counts.iter_mut() {
+        *count = reader.u64()?.try_into().ok()?;
+    }
+    let [num_triangles, num_pos, num_nrm, num_tan, num_uvs, num_col, num_creases] = counts;
+    // Checked up front, so that a corrupted count can't allocate huge buffers:
+    let size = [
+        (num_triangles, 16),
+        (num_pos, 12),
+        (num_nrm, 12),
+        (num_tan, 12),
+        (num_uvs, 8),
+        (num_col, 12),
+        (num_creases, 8),
+    ]
+    .iter()
+    .try_fold(0usize, |size, &(count, stride)| {
+        size.checked_add(count.checked_mul(stride)?)
+    })?;
+    if reader.bytes.len() != size {
+        return None;
+    }
+
+    let triangles = (0..num_triangles)
+        .map(|_| {
+            Some(Triangle {
+                indices: [reader.u32()?, reader.u32()?, reader.u32()?],
+                attribute_id: reader.u32()?,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let pos = reader.vec3s(num_pos)?;
+    let nrm = reader.vec3s(num_nrm)?;
+    let tan = reader.vec3s(num_tan)?;
+    let uvs = (0..num_uvs)
+        .map(|_| {
+            Some(Vec2 {
+                x: reader.f32()?,
+                y: reader.f32()?,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let col = reader.vec3s(num_col)?;
+    let creases = (0..num_creases)
+        .map(|_| Some([reader.u32()?, reader.u32()?]))
+        .collect::<Option<Vec<_>>>()?;
+
+    // The same checks as when the file is parsed, in case the cache was damaged:
+    let in_bounds = |i: &u32| (*i as usize) < num_pos;
+    if !triangles
+        .iter()
+        .all(|tri| tri.indices.iter().all(in_bounds))
+        || !creases.iter().all(|crease| crease.iter().all(in_bounds))
+    {
+        return None;
+    }
+
+    Some(PlyData {
+        triangles,
+        pos,
+        nrm,
+        tan,
+        uvs,
+        col,
+        creases,
+    })
+}
+
+/// Writes the buffers to the cache, tagged with the current version of the file.
+pub(super) fn write_cache(source_path: &str, cache_path: &str, data: &PlyData) -> io::Result<()> {
+    let key = source_key(source_path).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::Other, "couldn't get the modification time")
+    })?;
+    let mut writer = BufWriter::new(File::create(cache_path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&key.to_le_bytes())?;
+    for &count in [
+        data.triangles.len(),
+        data.pos.len(),
+        data.nrm.len(),
+        data.tan.len(),
+        data.uvs.len(),
+        data.col.len(),
+        data.creases.len(),
+    ]
+    .iter()
+    {
+        writer.write_all(&(count as u64).to_le_bytes())?;
+    }
+
+    for triangle in data.triangles.iter() {
+        for &v in triangle.indices.iter() {
+            writer.write_all(&v.to_le_bytes())?;
+        }
+        writer.write_all(&triangle.attribute_id.to_le_bytes())?;
+    }
+    for buffer in [&data.pos, &data.nrm, &data.tan].iter() {
+        for v in buffer.iter() {
+            for &c in [v.x, v.y, v.z].iter() {
+                writer.write_all(&c.to_le_bytes())?;
+            }
+        }
+    }
+    for uv in data.uvs.iter() {
+        writer.write_all(&uv.x.to_le_bytes())?;
+        writer.write_all(&uv.y.to_le_bytes())?;
+    }
+    for v in data.col.iter() {
+        for &c in [v.x, v.y, v.z].iter() {
+            writer.write_all(&c.to_le_bytes())?;
+        }
+    }
+    for crease in data.creases.iter() {
+        writer.write_all(&crease[0].to_le_bytes())?;
+        writer.write_all(&crease[1].to_le_bytes())?;
+    }
+    writer.flush()
+}
+
+/// Reads little endian values from the front of a buffer.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < len {
+            return None;
+        }
+        let (value, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(value)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        Some(f32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn vec3s(&mut self, count: usize) -> Option<Vec<Vec3<f32>>> {
+        (0..count)
+            .map(|_| {
+                Some(Vec3 {
+                    x: self.f32()?,
+                    y: self.f32()?,
+                    z: self.f32()?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every value of every buffer (as bits, so that the comparisons are exact), with the length of the
+    /// buffers.
+    fn flatten(data: &PlyData) -> Vec<u32> {
+        let vec3s = |buffer: &[Vec3<f32>]| {
+            let mut bits = vec![buffer.len() as u32];
+            bits.extend(
+                buffer
+                    .iter()
+                    .flat_map(|v| vec![v.x, v.y, v.z])
+                    .map(f32::to_bits),
+            );
+            bits
+        };
+        let mut bits = vec![data.triangles.len() as u32];
+        for triangle in data.triangles.iter() {
+            bits.extend_from_slice(&triangle.indices);
+            bits.push(triangle.attribute_id);
+        }
+        bits.extend(vec3s(&data.pos));
+        bits.extend(vec3s(&data.nrm));
+        bits.extend(vec3s(&data.tan));
+        bits.push(data.uvs.len() as u32);
+        bits.extend(
+            data.uvs
+                .iter()
+                .flat_map(|uv| vec![uv.x.to_bits(), uv.y.to_bits()]),
+        );
+        bits.extend(vec3s(&data.col));
+        bits.push(data.creases.len() as u32);
+        bits.extend(data.creases.iter().flatten());
+        bits
+    }
+
+    fn test_data() -> PlyData {
+        let v = |i: usize| Vec3 {
+            x: i as f32 * 0.1,
+            y: -(i as f32) / 3.0,
+            z: f32::MAX / (i + 1) as f32,
+        };
+        PlyData {
+            triangles: vec![
+                Triangle {
+                    indices: [0, 1, 2],
+                    attribute_id: 0,
+                },
+                Triangle {
+                    indices: [2, 1, 3],
+                    attribute_id: 7,
+                },
+            ],
+            pos: (0..4).map(v).collect(),
+            nrm: (4..8).map(v).collect(),
+            tan: Vec::new(),
+            uvs: (0..4)
+                .map(|i| Vec2 {
+                    x: i as f32 / 7.0,
+                    y: 1e-30,
+                })
+                .collect(),
+            col: (8..12).map(v).collect(),
+            creases: vec![[1, 2]],
+        }
+    }
+
+    fn temp_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("prism_pmesh_{}_{}", std::process::id(), name));
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn buffers_round_trip_exactly() {
+        let (source, cache) = (temp_path("round_trip.ply"), temp_path("round_trip.pmesh"));
+        fs::write(&source, "the source file").unwrap();
+        let data = test_data();
+        write_cache(&source, &cache, &data).unwrap();
+        let read = read_cache(&source, &cache).unwrap();
+        assert_eq!(flatten(&read), flatten(&data));
+
+        // The values are stored little endian right after the header:
+        let bytes = fs::read(&cache).unwrap();
+        assert_eq!(&bytes[..8], MAGIC);
+        assert_eq!(&bytes[8..12], &VERSION.to_le_bytes());
+        assert_eq!(
+            &bytes[(HEADER_SIZE + 16)..(HEADER_SIZE + 20)],
+            &2u32.to_le_bytes()
+        );
+        fs::remove_file(&source).unwrap();
+        fs::remove_file(&cache).unwrap();
+    }
+
+    #[test]
+    fn stale_and_damaged_caches_are_ignored() {
+        let (source, cache) = (temp_path("stale.ply"), temp_path("stale.pmesh"));
+        fs::write(&source, "the source file").unwrap();
+        write_cache(&source, &cache, &test_data()).unwrap();
+        let bytes = fs::read(&cache).unwrap();
+        assert!(read_cache(&source, &cache).is_some());
+
+        // A truncated cache, or one with a different version:
+        fs::write(&cache, &bytes[..(bytes.len() - 1)]).unwrap();
+        assert!(read_cache(&source, &cache).is_none());
+        let mut other_version = bytes.clone();
+        other_version[8] += 1;
+        fs::write(&cache, &other_version).unwrap();
+        assert!(read_cache(&source, &cache).is_none());
+
+        // A triangle with an index past the positions:
+        let mut bad_index = bytes.clone();
+        bad_index[HEADER_SIZE..(HEADER_SIZE + 4)].copy_from_slice(&4u32.to_le_bytes());
+        fs::write(&cache, &bad_index).unwrap();
+        assert!(read_cache(&source, &cache).is_none());
+
+        // A source file that changed since the cache was written:
+        fs::write(&cache, &bytes).unwrap();
+        fs::write(&source, "the changed source file").unwrap();
+        assert!(read_cache(&source, &cache).is_none());
+        fs::remove_file(&source).unwrap();
+        assert!(read_cache(&source, &cache).is_none());
+        fs::remove_file(&cache).unwrap();
+    }
+}
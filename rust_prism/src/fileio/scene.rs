@@ -1 +1,1739 @@
+//! Loads scene description files. Scene files are written in RON and describe the render settings, the
+//! camera, the materials, the shapes (and what material they use) and the lights of a scene:
+//!
+//! ```ron
+//! (
+//!     settings: (res: (400, 400), spp: 16, integrator: Normal(geometric_normal: false)),
+//!     camera: (position: (0.0, 1.0, -4.0), look_at: (0.0, 0.0, 0.0), fov: 45.0),
+//!     materials: { "white": Matte(color: (0.8, 0.8, 0.8)) },
+//!     shapes: [(geometry: Sphere(radius: 1.0), material: "white")],
+//!     lights: [Point(position: (0.0, 4.0, 0.0), intensity: (10.0, 10.0, 10.0))],
+//! )
+//! ```
+//!
+//! Paths in the scene file (for instance, to meshes) are relative to the scene file. Shapes can follow
+//! the nodes of an `animation`, in which case every frame of the animation is rendered (see `Animation`).
 
+use crate::camera::perspective::PerspectiveCamera;
+use crate::error::{PrismError, PrismResult};
+use crate::fileio::{ply, Handedness, ImportOptions, UpAxis};
+use crate::film::png;
+use crate::geometry::cylinder::Cylinder;
+use crate::geometry::disk::Disk;
+use crate::geometry::mesh::UvProjection;
+use crate::geometry::rect::Rect;
+use crate::geometry::sphere::Sphere;
+use crate::geometry::{Geometry, SampleableGeometry};
+use crate::light::area::diffuse::DiffuseAreaLight;
+use crate::light::light_picker::LightPickerKind;
+use crate::light::point::Point;
+use crate::light::portal::{Portal, PortalLight};
+use crate::light::Light;
+use crate::sampler::{SampleSequence, SamplerMode};
+use crate::scene::{AcceleratorKind, Scene, SceneGeom, SceneLight, ScenePrim, SceneUpdate};
+use crate::shading::lobe::measured::MerlData;
+use crate::shading::material::glass::Glass;
+use crate::shading::material::matte::Matte;
+use crate::shading::material::measured::Measured;
+use crate::shading::material::plastic::Plastic;
+use crate::shading::material::{Material, MaterialPool, RoughnessRemap};
+use crate::spectrum::{
+    Color, WhiteBalance, WhitePoint, LUMINOUS_EFFICACY, MAX_TEMPERATURE, MIN_TEMPERATURE,
+};
+use crate::threading::pixel_order::PixelOrder;
+use crate::threading::RenderParam;
+use crate::transform::graph::{NodeId, TransformGraph};
+use crate::transform::keyframe::{Interpolation, Keyframes};
+use crate::transform::Transf;
+use crate::Scalar;
+use pmath::numbers::Float;
+use pmath::vector::{Vec2, Vec3};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::Arc;
+
+//
+// The scene file format:
+//
+
+/// The root of a scene file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneDesc {
+    pub settings: SettingsDesc,
+    /// If there is no camera, the camera is positioned so that the entire scene is visible.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub camera: Option<CameraDesc>,
+    #[serde(default)]
+    pub materials: BTreeMap<String, MaterialDesc>,
+    #[serde(default)]
+    pub shapes: Vec<ShapeDesc>,
+    #[serde(default)]
+    pub lights: Vec<LightDesc>,
+    /// If present, every frame of the animation is rendered (see `Animation`).
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub animation: Option<AnimationDesc>,
+    #[serde(default)]
+    pub units: UnitsDesc,
+}
+
+/// Optional fields that are written without `Some` (they are simply left out when they aren't needed):
+fn deserialize_some<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// What the units of the scene are. Lights given in photometric units (lumens, nits) and meshes in other
+/// units are converted to these.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UnitsDesc {
+    /// The length of a unit of the scene in meters.
+    #[serde(default = "default_scale")]
+    pub meters_per_unit: f64,
+}
+
+impl Default for UnitsDesc {
+    fn default() -> Self {
+        UnitsDesc {
+            meters_per_unit: default_scale(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SettingsDesc {
+    pub res: (usize, usize),
+    pub spp: u32,
+    #[serde(default)]
+    pub threads: Option<u32>,
+    #[serde(default)]
+    pub tile_size: Option<usize>,
+    /// The order the pixels of a tile are rendered in (see `PixelOrder`).
+    #[serde(default)]
+    pub pixel_order: PixelOrder,
+    /// Picks the lights that are sampled at every shading point (see `LightPickerKind`).
+    #[serde(default)]
+    pub light_picker: LightPickerKind,
+    /// The acceleration structure for the top level shapes (see `AcceleratorKind`).
+    #[serde(default)]
+    pub accelerator: AcceleratorKind,
+    pub integrator: IntegratorDesc,
+    #[serde(default)]
+    pub sampler: SamplerDesc,
+    /// A grayscale png (with the same resolution as the render) that scales the number of samples
+    /// taken in every part of the image (see `RenderParam::importance_map`).
+    #[serde(default)]
+    pub importance_map: Option<String>,
+    /// Also write the two halves of the samples (every other sample of every pixel) and the variance
+    /// estimated from their difference next to the image (see `Film::with_split`).
+    #[serde(default)]
+    pub split_buffers: bool,
+    /// Renders at this many times the resolution and downscales the image to `res` (see
+    /// `RenderParam::supersample`).
+    #[serde(default = "default_supersample")]
+    pub supersample: u32,
+    /// Camera rays that only see the environment (or the portals) are transparent, but the environment
+    /// still adds its color (see `Scene::set_transparent_environment`).
+    #[serde(default)]
+    pub transparent_environment: bool,
+    /// The color that should end up white in the image, for scenes lit by lights that aren't white
+    /// (see `WhiteBalance`).
+    #[serde(default)]
+    pub white_balance: Option<WhitePoint>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum IntegratorDesc {
+    Normal {
+        #[serde(default)]
+        geometric_normal: bool,
+    },
+    Path {
+        max_bounce: u32,
+        /// The number of light samples taken at every bounce.
+        #[serde(default = "default_num_samples")]
+        light_samples: u32,
+        /// The number of bsdf samples taken at every bounce (to hit lights).
+        #[serde(default = "default_num_samples")]
+        bsdf_samples: u32,
+        /// Hide the faceted shadow terminator on smooth shaded low-poly meshes (slightly biased).
+        #[serde(default)]
+        terminator_fix: bool,
+        /// Also write heatmaps of the number of bounces and the length of the paths, the emission,
+        /// direct, and indirect light separately, and the position (relative to the camera) and depth of
+        /// the first hit as exr files (next to the image).
+        #[serde(default)]
+        aovs: bool,
+        /// Limits the luminance of the direct light of every path (biased, see
+        /// `PathTracerParam::max_direct`).
+        #[serde(default)]
+        max_direct: Option<f64>,
+        /// Limits the luminance of the indirect light of every path, which removes most fireflies
+        /// (biased, see `PathTracerParam::max_indirect`).
+        #[serde(default)]
+        max_indirect: Option<f64>,
+        /// Randomly skips shadow rays of light samples that contribute less than this luminance
+        /// (unbiased, see `DirectLightParam::shadow_rr`).
+        #[serde(default)]
+        shadow_rr: Option<f64>,
+        /// Renders the caustics with photons instead (see `integrator::sppm`), which every sample per
+        /// pixel traces anew.
+        #[serde(default)]
+        caustics: Option<CausticsDesc>,
+    },
+    /// The camera space depth of the first hit, from black at `near` to white at `far` (which defaults
+    /// to the far side of the scene).
+    Depth {
+        #[serde(default)]
+        near: f64,
+        #[serde(default)]
+        far: Option<f64>,
+    },
+    /// The world space position of the first hit, relative to the bounding box of the scene.
+    Position,
+    /// The geometric normal of the first hit.
+    GeomNormal,
+    /// The uv coordinates of the first hit.
+    Uv,
+    /// A different color for every attribute id of every geometry.
+    AttributeId,
+    /// The fraction of the hemisphere above the first hit that isn't occluded within `max_distance`
+    /// (which defaults to a tenth of the diagonal of the scene).
+    AmbientOcclusion {
+        #[serde(default)]
+        max_distance: Option<f64>,
+        /// The number of rays traced for every sample.
+        #[serde(default = "default_num_samples")]
+        samples: u32,
+    },
+}
+
+fn default_num_samples() -> u32 {
+    1
+}
+
+/// See `CausticParam`.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CausticsDesc {
+    /// The number of photons traced every pass.
+    #[serde(default = "default_photons")]
+    pub photons: u32,
+    /// The most photons stored every pass, which bounds the memory of the photon map.
+    #[serde(default = "default_max_photons")]
+    pub max_photons: usize,
+    /// The radius the photons are gathered in at first (defaults to a hundredth of the diagonal of the
+    /// scene).
+    #[serde(default)]
+    pub radius: Option<f64>,
+    /// How fast the radius shrinks, between 0 (fast) and 1 (not at all).
+    #[serde(default = "default_alpha")]
+    pub alpha: f64,
+}
+
+fn default_photons() -> u32 {
+    100_000
+}
+
+fn default_max_photons() -> usize {
+    1_000_000
+}
+
+fn default_alpha() -> f64 {
+    2.0 / 3.0
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SamplerDesc {
+    #[serde(default)]
+    pub seed: u64,
+    /// The number of attempts when ensuring blue noise in the sample tables.
+    #[serde(default)]
+    pub blue_noise_count: u32,
+    /// The sequence the samples are drawn from.
+    #[serde(default)]
+    pub sequence: SampleSequenceDesc,
+    /// How the samples of the different pixels are decorrelated.
+    #[serde(default)]
+    pub mode: SamplerModeDesc,
+    /// Dither the samples of neighboring pixels with a blue noise mask (less visible noise at low sample
+    /// counts).
+    #[serde(default)]
+    pub dither: bool,
+}
+
+impl Default for SamplerDesc {
+    fn default() -> Self {
+        SamplerDesc {
+            seed: 0,
+            blue_noise_count: 0,
+            sequence: SampleSequenceDesc::Pmj,
+            mode: SamplerModeDesc::Scrambled,
+            dither: false,
+        }
+    }
+}
+
+/// See `SampleSequence`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum SampleSequenceDesc {
+    Pmj,
+    Random,
+    Sobol,
+    Stratified,
+}
+
+impl Default for SampleSequenceDesc {
+    fn default() -> Self {
+        SampleSequenceDesc::Pmj
+    }
+}
+
+/// See `SamplerMode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum SamplerModeDesc {
+    /// Every pixel draws its own sequence.
+    Scrambled,
+    /// The positions on the film come from a single sequence, shifted randomly for every pixel.
+    Shifted,
+}
+
+impl Default for SamplerModeDesc {
+    fn default() -> Self {
+        SamplerModeDesc::Scrambled
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraDesc {
+    pub position: (f64, f64, f64),
+    pub look_at: (f64, f64, f64),
+    #[serde(default = "default_up")]
+    pub up: (f64, f64, f64),
+    /// In degrees.
+    pub fov: f64,
+    #[serde(default)]
+    pub lens_radius: f64,
+    #[serde(default = "default_focal_dist")]
+    pub focal_dist: f64,
+}
+
+/// The field-of-view (in degrees) used when the scene doesn't have a camera.
+const DEFAULT_FOV: f64 = 45.0;
+
+fn default_up() -> (f64, f64, f64) {
+    (0.0, 1.0, 0.0)
+}
+
+fn default_focal_dist() -> f64 {
+    1.0
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum MaterialDesc {
+    /// Shapes with an emissive material are turned into area lights.
+    Matte {
+        color: (f64, f64, f64),
+        #[serde(default)]
+        emission: (f64, f64, f64),
+        /// The luminance of the emission in nits (cd/m^2). If present, `emission` only gives the hue
+        /// (black is white).
+        #[serde(default)]
+        nits: Option<f64>,
+    },
+    /// A measured BRDF from a MERL `.binary` file.
+    Measured { path: String },
+    /// A smooth dielectric like glass or water (see `Glass`).
+    Glass {
+        #[serde(default = "default_glass_color")]
+        color: (f64, f64, f64),
+        #[serde(default = "default_ior")]
+        ior: f64,
+    },
+    /// A diffuse surface under a glossy coating (see `Plastic`).
+    Plastic {
+        color: (f64, f64, f64),
+        #[serde(default = "default_reflect")]
+        reflect: (f64, f64, f64),
+        /// Between 0 (smooth) and 1 (rough).
+        roughness: f64,
+        /// How the roughness is turned into the alpha of the coating (`Squared` by default).
+        #[serde(default)]
+        remap: RoughnessRemap,
+    },
+}
+
+fn default_glass_color() -> (f64, f64, f64) {
+    (1.0, 1.0, 1.0)
+}
+
+fn default_ior() -> f64 {
+    1.5
+}
+
+fn default_reflect() -> (f64, f64, f64) {
+    (1.0, 1.0, 1.0)
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShapeDesc {
+    /// Names have to be unique, and allow the shape to be referred to after the scene was loaded.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub geometry: GeometryDesc,
+    /// Applied in order (so the last transformation in the list is applied last).
+    #[serde(default)]
+    pub transforms: Vec<TransformDesc>,
+    /// If present, the shape moves (linearly) from `transforms` when the shutter opens to these
+    /// transformations when the shutter closes.
+    #[serde(default)]
+    pub motion_transforms: Option<Vec<TransformDesc>>,
+    /// The name of one of the materials in the scene.
+    pub material: String,
+    /// The names of the materials of the attribute ids of a mesh (in order, starting at attribute id 0).
+    /// If there are any, every attribute id the mesh uses needs one. They can't be emissive.
+    #[serde(default)]
+    pub attribute_materials: Vec<String>,
+    #[serde(default = "default_visible")]
+    pub visible: bool,
+    /// Only render the shadows that fall on the shape (the image is written with alpha).
+    #[serde(default)]
+    pub shadow_catcher: bool,
+    /// If the material is emissive, whether the back side of the shape emits light as well (by default
+    /// only the side the normal points to does).
+    #[serde(default)]
+    pub two_sided_emission: bool,
+    /// The name of one of the nodes of the animation. The shape follows the node (its transformations
+    /// are applied before the ones of the node).
+    #[serde(default)]
+    pub node: Option<String>,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+fn default_supersample() -> u32 {
+    1
+}
+
+fn default_visible() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum GeometryDesc {
+    Sphere {
+        radius: f64,
+    },
+    Disk {
+        radius: f64,
+    },
+    Rect {
+        size: (f64, f64),
+    },
+    Cylinder {
+        radius: f64,
+        z_min: f64,
+        z_max: f64,
+    },
+    /// A PLY file.
+    Mesh {
+        path: String,
+        /// The conversion from the coordinate system of the file (see `ImportOptions`).
+        #[serde(default)]
+        up_axis: UpAxis,
+        #[serde(default)]
+        handedness: Handedness,
+        #[serde(default)]
+        flip_winding: bool,
+        #[serde(default = "default_scale")]
+        scale: f64,
+        /// The length of a unit of the file in meters, if it isn't the same as the scene's. The mesh is
+        /// scaled to the units of the scene (on top of `scale`).
+        #[serde(default)]
+        meters_per_unit: Option<f64>,
+        /// How uvs are generated if the mesh doesn't have any.
+        #[serde(default)]
+        uv_projection: UvProjection,
+        /// Cache the parsed mesh and its BVH next to the file (see `ImportOptions::mesh_cache`).
+        #[serde(default)]
+        cache: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum TransformDesc {
+    Translate((f64, f64, f64)),
+    Scale((f64, f64, f64)),
+    Rotate {
+        deg: f64,
+        axis: (f64, f64, f64),
+    },
+    /// A 3x4 matrix in row-major order.
+    Matrix([f64; 12]),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum LightDesc {
+    Point {
+        position: (f64, f64, f64),
+        intensity: (f64, f64, f64),
+        /// The luminous flux in lumens. If present, `intensity` only gives the hue (black is white).
+        #[serde(default)]
+        lumens: Option<f64>,
+    },
+    /// An environment with a constant radiance that is sampled through the portals (see
+    /// `PortalLight`).
+    Portal {
+        radiance: (f64, f64, f64),
+        /// The luminance in nits (cd/m^2). If present, `radiance` only gives the hue (black is white).
+        #[serde(default)]
+        nits: Option<f64>,
+        portals: Vec<PortalDesc>,
+    },
+}
+
+/// A rectangular portal. The normal (`edge_u` x `edge_v`) has to point into the interior.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PortalDesc {
+    pub corner: (f64, f64, f64),
+    pub edge_u: (f64, f64, f64),
+    pub edge_v: (f64, f64, f64),
+}
+
+/// The frames of an animation and the nodes that move the shapes.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnimationDesc {
+    /// The first and the last frame (both are rendered).
+    pub frames: (u32, u32),
+    /// The number of frames per second (the times of the keyframes are in seconds).
+    pub fps: f64,
+    /// Parents have to come before their children.
+    #[serde(default)]
+    pub nodes: Vec<NodeDesc>,
+}
+
+/// A transformation that changes over time, relative to the transformation of its parent.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeDesc {
+    /// Names have to be unique.
+    pub name: String,
+    /// The name of another node.
+    #[serde(default)]
+    pub parent: Option<String>,
+    #[serde(default)]
+    pub interpolation: Interpolation,
+    /// Sorted by time. There has to be at least one.
+    pub keys: Vec<KeyDesc>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyDesc {
+    /// In seconds.
+    pub time: f64,
+    /// Applied in order (like `ShapeDesc::transforms`).
+    #[serde(default)]
+    pub transforms: Vec<TransformDesc>,
+}
+
+//
+// Loading the scene:
+//
+
+/// Everything that was loaded from a scene file.
+pub struct LoadedScene {
+    pub scene: Scene,
+    pub materials: MaterialPool,
+    /// Maps the names of the materials in the scene file to their ids in `materials`.
+    pub material_ids: BTreeMap<String, u32>,
+    pub camera: PerspectiveCamera,
+    pub param: RenderParam,
+    pub integrator: IntegratorDesc,
+    /// Applied to the colors of the image before they are written.
+    pub white_balance: Option<WhiteBalance>,
+}
+
+/// Converts an error of the scene file parser. `location` is where in the scene description the error
+/// is (if it's known).
+fn parse_error(err: &ron::Error, location: Option<String>) -> PrismError {
+    // Errors that don't come from the parser itself (like unknown fields) don't have a position:
+    let line = match err.position.line {
+        0 => None,
+        line => Some(line),
+    };
+    let msg = err.code.to_string();
+    let msg = match location {
+        Some(location) => format!("error at `{}`: {}", location, msg),
+        None => msg,
+    };
+    PrismError::Parse {
+        path: None,
+        line,
+        msg,
+    }
+}
+
+/// Parses a scene file without loading any of the geometry it references. The errors don't have a path
+/// (see `PrismError::with_path`).
+pub fn parse_scene(source: &str) -> PrismResult<SceneDesc> {
+    let mut deserializer =
+        ron::de::Deserializer::from_str(source).map_err(|err| parse_error(&err, None))?;
+    let desc: SceneDesc = serde_path_to_error::deserialize(&mut deserializer)
+        .map_err(|err| parse_error(err.inner(), Some(err.path().to_string())))?;
+    deserializer.end().map_err(|err| parse_error(&err, None))?;
+    Ok(desc)
+}
+
+/// Loads the scene file at `path` and constructs everything needed to render it.
+pub fn load_scene(path: &str) -> PrismResult<LoadedScene> {
+    let source = fs::read_to_string(path).map_err(|err| PrismError::io(path, err))?;
+    let desc = parse_scene(&source).map_err(|err| err.with_path(path))?;
+    let base_dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    build_scene(&desc, base_dir)
+}
+
+/// Constructs everything needed to render the scene described by `desc`. Relative paths are relative to
+/// `base_dir`.
+pub fn build_scene(desc: &SceneDesc, base_dir: &Path) -> PrismResult<LoadedScene> {
+    let settings = &desc.settings;
+    if settings.res.0 == 0 || settings.res.1 == 0 {
+        return Err(PrismError::invalid_scene(
+            "Error in scene file at `settings.res`: resolution can't be zero",
+        ));
+    }
+    if let Some(tile_size) = settings.tile_size {
+        if !tile_size.is_power_of_two() {
+            return Err(PrismError::invalid_scene(
+                "Error in scene file at `settings.tile_size`: has to be a power of two",
+            ));
+        }
+    }
+    match settings.white_balance {
+        Some(WhitePoint::Temperature(t)) if !(MIN_TEMPERATURE..=MAX_TEMPERATURE).contains(&t) => return Err(PrismError::invalid_scene(format!("Error in scene file at `settings.white_balance`: the temperature has to be between {}K and {}K",
+            MIN_TEMPERATURE,
+            MAX_TEMPERATURE))),
+        Some(WhitePoint::Xy((x, y))) if !(x > 0.0 && y > 0.0 && x + y < 1.0) => return Err(PrismError::invalid_scene("Error in scene file at `settings.white_balance`: not a valid chromaticity")),
+        _ => (),
+    }
+    if settings.supersample == 0 {
+        return Err(PrismError::invalid_scene(
+            "Error in scene file at `settings.supersample`: has to be at least 1",
+        ));
+    }
+    let meters_per_unit = desc.units.meters_per_unit;
+    if !(meters_per_unit > 0.0) {
+        return Err(PrismError::invalid_scene(
+            "Error in scene file at `units.meters_per_unit`: has to be positive",
+        ));
+    }
+    if let IntegratorDesc::Path {
+        shadow_rr: Some(threshold),
+        ..
+    } = settings.integrator
+    {
+        if !(threshold > 0.0) {
+            return Err(PrismError::invalid_scene(
+                "Error in scene file at `settings.integrator.shadow_rr`: has to be positive",
+            ));
+        }
+    }
+    if let IntegratorDesc::Path {
+        caustics: Some(caustics),
+        ..
+    } = settings.integrator
+    {
+        if caustics.photons == 0 || caustics.max_photons == 0 {
+            return Err(PrismError::invalid_scene("Error in scene file at `settings.integrator.caustics`: `photons` and `max_photons` have to be positive"));
+        }
+        if let Some(radius) = caustics.radius {
+            if !(radius > 0.0) {
+                return Err(PrismError::invalid_scene("Error in scene file at `settings.integrator.caustics.radius`: has to be positive"));
+            }
+        }
+        if !(caustics.alpha > 0.0 && caustics.alpha <= 1.0) {
+            return Err(PrismError::invalid_scene("Error in scene file at `settings.integrator.caustics.alpha`: has to be between 0 (exclusive) and 1"));
+        }
+    }
+
+    let importance_map = match &settings.importance_map {
+        Some(path) => {
+            let image_path = base_dir.join(path);
+            let image_path = match image_path.to_str() {
+                Some(image_path) => image_path,
+                None => {
+                    return Err(PrismError::invalid_scene(
+                        "Error in scene file at `settings.importance_map`: invalid path",
+                    ))
+                }
+            };
+            let image = png::read_png(image_path)?;
+            if image.res()
+                != (Vec2 {
+                    x: settings.res.0,
+                    y: settings.res.1,
+                })
+            {
+                return Err(PrismError::invalid_scene("Error in scene file at `settings.importance_map`: has to have the same resolution as the render"));
+            }
+            Some(Arc::new(image))
+        }
+        None => None,
+    };
+
+    let param = RenderParam {
+        num_pixel_samples: settings.spp,
+        num_threads: settings.threads.unwrap_or(1),
+        sample_seed: settings.sampler.seed,
+        blue_noise_count: settings.sampler.blue_noise_count,
+        sample_sequence: match settings.sampler.sequence {
+            SampleSequenceDesc::Pmj => SampleSequence::Pmj,
+            SampleSequenceDesc::Random => SampleSequence::Random,
+            SampleSequenceDesc::Sobol => SampleSequence::Sobol,
+            SampleSequenceDesc::Stratified => SampleSequence::Stratified,
+        },
+        sampler_mode: match settings.sampler.mode {
+            SamplerModeDesc::Scrambled => SamplerMode::Scrambled,
+            SamplerModeDesc::Shifted => SamplerMode::Shifted,
+        },
+        blue_noise_dither: settings.sampler.dither,
+        res: Vec2 {
+            x: settings.res.0,
+            y: settings.res.1,
+        },
+        tile_size: settings.tile_size,
+        pixel_order: settings.pixel_order,
+        light_picker: settings.light_picker,
+        sample_dump: None,
+        importance_map,
+        split_buffers: settings.split_buffers,
+        supersample: settings.supersample,
+    };
+
+    // Materials are referenced by name:
+    let mut materials = MaterialPool::new();
+    let mut material_ids = BTreeMap::new();
+    for (name, material) in desc.materials.iter() {
+        let id = match material {
+            MaterialDesc::Matte {
+                color,
+                emission,
+                nits,
+            } => {
+                let emission = photometric(*emission, *nits, &format!("materials.{}.nits", name))?;
+                materials.add_material(Matte::new_emissive(to_color(*color), emission))
+            }
+            MaterialDesc::Measured { path } => {
+                materials.add_material(Measured::new(load_merl(path, base_dir, name)?))
+            }
+            MaterialDesc::Glass { color, ior } => {
+                materials.add_material(new_glass(*color, *ior, name)?)
+            }
+            MaterialDesc::Plastic {
+                color,
+                reflect,
+                roughness,
+                remap,
+            } => materials.add_material(new_plastic(*color, *reflect, *roughness, *remap, name)?),
+        };
+        material_ids.insert(name.clone(), id);
+    }
+
+    let mut prims: Vec<Arc<dyn ScenePrim>> = Vec::with_capacity(desc.shapes.len());
+    let mut named_geoms = Vec::new();
+    let mut material_users = Vec::with_capacity(desc.shapes.len());
+    let mut lights = Vec::new();
+    for (i, shape) in desc.shapes.iter().enumerate() {
+        let material_id = match material_ids.get(&shape.material) {
+            Some(&id) => id,
+            None => {
+                return Err(PrismError::invalid_scene(format!(
+                    "Error in scene file at `shapes[{}].material`: unknown material \"{}\"",
+                    i, shape.material
+                )))
+            }
+        };
+        materials.check_id(material_id)?;
+
+        let mut attribute_material_ids = Vec::with_capacity(shape.attribute_materials.len());
+        for (j, name) in shape.attribute_materials.iter().enumerate() {
+            let attribute_material_id = match material_ids.get(name) {
+                Some(&id) => id,
+                None => {
+                    return Err(PrismError::invalid_scene(format!(
+                        "Error in scene file at `shapes[{}].attribute_materials[{}]`: unknown material \"{}\"",
+                        i, j, name
+                    )))
+                }
+            };
+            materials.check_id(attribute_material_id)?;
+            if !materials
+                .get_material(attribute_material_id)
+                .emission()
+                .is_black()
+            {
+                return Err(PrismError::invalid_scene(format!("Error in scene file at `shapes[{}].attribute_materials[{}]`: material \"{}\" is emissive",
+                    i, j, name)));
+            }
+            attribute_material_ids.push(attribute_material_id);
+        }
+        // The path and the number of attribute ids of a mesh:
+        let mut mesh_attributes = None;
+        let (geom, sampleable_geom) = match &shape.geometry {
+            GeometryDesc::Sphere { radius } => share_geom(Sphere::new(*radius)),
+            GeometryDesc::Disk { radius } => share_geom(Disk::new(*radius)),
+            GeometryDesc::Rect { size } => share_geom(Rect::new(Vec2 {
+                x: size.0,
+                y: size.1,
+            })),
+            GeometryDesc::Cylinder {
+                radius,
+                z_min,
+                z_max,
+            } => share_geom(Cylinder::new(*radius, *z_min, *z_max)),
+            GeometryDesc::Mesh {
+                path,
+                up_axis,
+                handedness,
+                flip_winding,
+                scale,
+                meters_per_unit: file_meters_per_unit,
+                uv_projection,
+                cache,
+            } => {
+                if !(*scale > 0.0) {
+                    return Err(PrismError::invalid_scene(format!(
+                        "Error in scene file at `shapes[{}].geometry.scale`: has to be positive",
+                        i
+                    )));
+                }
+                let unit_scale = match file_meters_per_unit {
+                    Some(file_meters_per_unit) if !(*file_meters_per_unit > 0.0) => return Err(PrismError::invalid_scene(format!("Error in scene file at `shapes[{}].geometry.meters_per_unit`: has to be positive",
+                        i))),
+                    Some(file_meters_per_unit) => file_meters_per_unit / meters_per_unit,
+                    None => 1.0,
+                };
+                let mesh_path = base_dir.join(path);
+                let mesh_path = match mesh_path.to_str() {
+                    Some(mesh_path) => mesh_path,
+                    None => {
+                        return Err(PrismError::invalid_scene(format!(
+                            "Error in scene file at `shapes[{}].geometry.path`: invalid path",
+                            i
+                        )))
+                    }
+                };
+                let options = ImportOptions {
+                    up_axis: *up_axis,
+                    handedness: *handedness,
+                    flip_winding: *flip_winding,
+                    scale: scale * unit_scale,
+                    uv_projection: *uv_projection,
+                    mesh_cache: *cache,
+                    bvh_cache: *cache,
+                    ..ImportOptions::default()
+                };
+                let mesh = ply::load_mesh(mesh_path, options)?;
+                mesh_attributes = Some((path, mesh.num_attributes()));
+                share_geom(mesh)
+            }
+        };
+        if let Some((path, num_attributes)) = mesh_attributes {
+            let num_bound = attribute_material_ids.len() as u32;
+            if num_bound > 0 && num_attributes > num_bound {
+                let mesh_name = match &shape.name {
+                    Some(name) => format!("mesh \"{}\" ({})", name, path),
+                    None => format!("mesh {}", path),
+                };
+                let unbound: Vec<u32> = (num_bound..num_attributes).collect();
+                return Err(PrismError::invalid_scene(format!(
+                    "Error in scene file at `shapes[{}].attribute_materials`: {} has no material for attribute ids {:?}",
+                    i, mesh_name, unbound
+                )));
+            }
+        }
+
+        if shape.node.is_some() && desc.animation.is_none() {
+            return Err(PrismError::invalid_scene(format!(
+                "Error in scene file at `shapes[{}].node`: the scene doesn't have an animation",
+                i
+            )));
+        }
+        let transf = to_combined_transf(&shape.transforms);
+        let scene_geom =
+            SceneGeom::new_material(geom, materials.get_shared_material(material_id), transf)
+                .with_attribute_materials(
+                    attribute_material_ids
+                        .iter()
+                        .map(|&id| materials.get_shared_material(id))
+                        .collect(),
+                );
+        let scene_geom = match &shape.motion_transforms {
+            Some(motion_transforms) => {
+                scene_geom.with_motion(to_combined_transf(motion_transforms))
+            }
+            None => scene_geom,
+        };
+        let scene_geom = Arc::new(scene_geom);
+        material_users.push((material_id, scene_geom.clone(), None));
+        for (attribute_id, &attribute_material_id) in attribute_material_ids.iter().enumerate() {
+            material_users.push((
+                attribute_material_id,
+                scene_geom.clone(),
+                Some(attribute_id as u32),
+            ));
+        }
+        if let Some(name) = &shape.name {
+            named_geoms.push((i, name.as_str(), scene_geom.clone()));
+        } else if !shape.visible || shape.shadow_catcher {
+            return Err(PrismError::invalid_scene(format!("Error in scene file at `shapes[{}]`: only named shapes can be hidden or be shadow catchers",
+                i)));
+        }
+
+        // Emissive shapes are sampled like any other light:
+        let emission = materials.get_material(material_id).emission();
+        if !emission.is_black() {
+            if !attribute_material_ids.is_empty() {
+                return Err(PrismError::invalid_scene(format!("Error in scene file at `shapes[{}].attribute_materials`: emissive shapes can't have materials per attribute id",
+                    i)));
+            }
+            if shape.motion_transforms.is_some() {
+                return Err(PrismError::invalid_scene(format!("Error in scene file at `shapes[{}].motion_transforms`: emissive shapes can't move",
+                    i)));
+            }
+            let light: Arc<dyn Light> = Arc::new(
+                DiffuseAreaLight::new(sampleable_geom, transf, emission, scene_geom.geom_ref())
+                    .with_two_sided(shape.two_sided_emission),
+            );
+            lights.push(SceneLight::new(light, Transf::new_identity()));
+        }
+
+        prims.push(scene_geom);
+    }
+
+    for (i, light) in desc.lights.iter().enumerate() {
+        let light: Arc<dyn Light> = match light {
+            LightDesc::Point {
+                position,
+                intensity,
+                lumens,
+            } => {
+                let intensity = match lumens {
+                    // An isotropic point light emits its flux over 4pi steradians. The irradiance falls
+                    // off with the squared distance in units of the scene, not in meters:
+                    Some(lumens) => {
+                        let candela = lumens / (4.0 * std::f64::consts::PI);
+                        let intensity = photometric(
+                            *intensity,
+                            Some(candela),
+                            &format!("lights[{}].lumens", i),
+                        )?;
+                        intensity.scale(Scalar::from_f64(1.0 / (meters_per_unit * meters_per_unit)))
+                    }
+                    None => to_color(*intensity),
+                };
+                Arc::new(Point::new(to_vec3(*position).cast(), intensity))
+            }
+            LightDesc::Portal {
+                radiance,
+                nits,
+                portals,
+            } => {
+                if portals.is_empty() {
+                    return Err(PrismError::invalid_scene(format!("Error in scene file at `lights[{}].portals`: there has to be at least one portal",
+                        i)));
+                }
+                let mut scene_portals = Vec::with_capacity(portals.len());
+                for (j, portal) in portals.iter().enumerate() {
+                    let edge_u = to_vec3(portal.edge_u);
+                    let edge_v = to_vec3(portal.edge_v);
+                    let area = edge_u.cross(edge_v).length();
+                    if (area == 0.0) || (edge_u.dot(edge_v).abs() > 1e-6 * area) {
+                        return Err(PrismError::invalid_scene(format!("Error in scene file at `lights[{}].portals[{}]`: the edges have to be perpendicular and can't be zero",
+                            i,
+                            j)));
+                    }
+                    scene_portals.push(Portal::new(
+                        to_vec3(portal.corner).cast(),
+                        edge_u.cast(),
+                        edge_v.cast(),
+                    ));
+                }
+                let radiance = photometric(*radiance, *nits, &format!("lights[{}].nits", i))?;
+                Arc::new(PortalLight::new(radiance, scene_portals))
+            }
+        };
+        lights.push(SceneLight::new(light, Transf::new_identity()));
+    }
+
+    let mut scene = Scene::with_accelerator(settings.accelerator.build(&prims), lights);
+    for (material_id, scene_geom, attribute_id) in material_users {
+        match attribute_id {
+            Some(attribute_id) => {
+                scene.register_attribute_material_user(material_id, scene_geom, attribute_id)
+            }
+            None => scene.register_material_user(material_id, scene_geom),
+        }
+    }
+    for (i, name, scene_geom) in named_geoms {
+        if let Err(err) = scene.register_object(name, scene_geom) {
+            return Err(PrismError::invalid_scene(format!(
+                "Error in scene file at `shapes[{}].name`: {}",
+                i, err
+            )));
+        }
+        let shape = &desc.shapes[i];
+        scene.set_visible(name, shape.visible)?;
+        scene.set_shadow_catcher(name, shape.shadow_catcher)?;
+    }
+    scene.set_transparent_environment(desc.settings.transparent_environment);
+    scene.set_meters_per_unit(meters_per_unit);
+    let camera = match &desc.camera {
+        Some(camera) => build_camera(camera, param.film_res()),
+        None => PerspectiveCamera::frame_bbox(
+            scene.world_bound(),
+            DEFAULT_FOV,
+            Vec3 {
+                x: 0.0,
+                y: -0.35,
+                z: 1.0,
+            },
+            param.film_res(),
+        ),
+    };
+
+    Ok(LoadedScene {
+        scene,
+        materials,
+        material_ids,
+        camera,
+        param,
+        integrator: settings.integrator,
+        white_balance: settings.white_balance.map(WhiteBalance::new),
+    })
+}
+
+/// Compares the materials of two versions of a scene file and returns the updates needed to turn the
+/// scene built from `old` into one built from `new` (`material_ids` comes from the `LoadedScene`). Only
+/// materials that changed are updated. Adding or removing materials (or changing anything but the
+/// materials) requires the scene to be reloaded instead. Relative paths are relative to `base_dir`.
+pub fn diff_materials(
+    old: &SceneDesc,
+    new: &SceneDesc,
+    material_ids: &BTreeMap<String, u32>,
+    base_dir: &Path,
+) -> PrismResult<Vec<SceneUpdate>> {
+    if old.materials.len() != new.materials.len()
+        || old
+            .materials
+            .keys()
+            .any(|name| !new.materials.contains_key(name))
+    {
+        return Err(PrismError::invalid_scene(
+            "Materials can't be added or removed without reloading the scene",
+        ));
+    }
+
+    let mut updates = Vec::new();
+    for (name, material) in new.materials.iter() {
+        if old.materials[name] == *material {
+            continue;
+        }
+        let material: Arc<dyn Material> = match material {
+            MaterialDesc::Matte {
+                color,
+                emission,
+                nits,
+            } => {
+                let emission = photometric(*emission, *nits, &format!("materials.{}.nits", name))?;
+                Arc::new(Matte::new_emissive(to_color(*color), emission))
+            }
+            MaterialDesc::Measured { path } => {
+                Arc::new(Measured::new(load_merl(path, base_dir, name)?))
+            }
+            MaterialDesc::Glass { color, ior } => Arc::new(new_glass(*color, *ior, name)?),
+            MaterialDesc::Plastic {
+                color,
+                reflect,
+                roughness,
+                remap,
+            } => Arc::new(new_plastic(*color, *reflect, *roughness, *remap, name)?),
+        };
+        updates.push(SceneUpdate::Material {
+            material_id: material_ids[name],
+            material,
+        });
+    }
+    Ok(updates)
+}
+
+/// Creates the glass material `name`.
+fn new_glass(color: (f64, f64, f64), ior: f64, name: &str) -> PrismResult<Glass> {
+    if !(ior > 0.0) {
+        return Err(PrismError::invalid_scene(format!(
+            "Error in scene file at `materials.{}.ior`: has to be positive",
+            name
+        )));
+    }
+    Ok(Glass::new(to_color(color), Scalar::from_f64(ior)))
+}
+
+/// Creates the plastic material `name`.
+fn new_plastic(
+    color: (f64, f64, f64),
+    reflect: (f64, f64, f64),
+    roughness: f64,
+    remap: RoughnessRemap,
+    name: &str,
+) -> PrismResult<Plastic> {
+    if !(0.0..=1.0).contains(&roughness) {
+        return Err(PrismError::invalid_scene(format!(
+            "Error in scene file at `materials.{}.roughness`: has to be between 0 and 1",
+            name
+        )));
+    }
+    Ok(Plastic::new(
+        to_color(color),
+        to_color(reflect),
+        Scalar::from_f64(roughness),
+        remap,
+    ))
+}
+
+/// Loads the measured BRDF of the material `name`.
+fn load_merl(path: &str, base_dir: &Path, name: &str) -> PrismResult<Arc<MerlData>> {
+    let merl_path = base_dir.join(path);
+    let merl_path = match merl_path.to_str() {
+        Some(merl_path) => merl_path,
+        None => {
+            return Err(PrismError::invalid_scene(format!(
+                "Error in scene file at `materials.{}.path`: invalid path",
+                name
+            )))
+        }
+    };
+    Ok(Arc::new(MerlData::load(merl_path)?))
+}
+
+//
+// Animations:
+//
+
+/// Turns the description of an animated scene into the description of each of its frames. The nodes of
+/// the animation are kept in a `TransformGraph`, so moving a node moves everything below it.
+pub struct Animation {
+    frames: RangeInclusive<u32>,
+    fps: f64,
+    graph: TransformGraph,
+    // In the same order as the nodes in the description:
+    nodes: Vec<(NodeId, Keyframes)>,
+    // The index of every shape that follows a node, and the node it follows:
+    shape_nodes: Vec<(usize, NodeId)>,
+}
+
+impl Animation {
+    /// Checks the animation of the scene. Returns an error if the scene doesn't have one.
+    pub fn new(desc: &SceneDesc) -> PrismResult<Self> {
+        let animation = match &desc.animation {
+            Some(animation) => animation,
+            None => {
+                return Err(PrismError::invalid_scene(
+                    "The scene doesn't have an animation",
+                ))
+            }
+        };
+        if animation.frames.0 > animation.frames.1 {
+            return Err(PrismError::invalid_scene(
+                "Error in scene file at `animation.frames`: the first frame comes after the last",
+            ));
+        }
+        if !(animation.fps > 0.0) {
+            return Err(PrismError::invalid_scene(
+                "Error in scene file at `animation.fps`: has to be positive",
+            ));
+        }
+
+        let mut graph = TransformGraph::new();
+        let mut nodes = Vec::with_capacity(animation.nodes.len());
+        let mut node_ids = BTreeMap::new();
+        for (i, node) in animation.nodes.iter().enumerate() {
+            if node.keys.is_empty() {
+                return Err(PrismError::invalid_scene(format!("Error in scene file at `animation.nodes[{}].keys`: there has to be at least one key",
+                    i)));
+            }
+            if node
+                .keys
+                .windows(2)
+                .any(|pair| !(pair[0].time < pair[1].time))
+            {
+                return Err(PrismError::invalid_scene(format!("Error in scene file at `animation.nodes[{}].keys`: the times have to be increasing",
+                    i)));
+            }
+            let parent = match &node.parent {
+                Some(parent) => match node_ids.get(parent.as_str()) {
+                    Some(&parent) => Some(parent),
+                    None => return Err(PrismError::invalid_scene(format!("Error in scene file at `animation.nodes[{}].parent`: unknown node \"{}\" (parents have to come before their children)",
+                        i,
+                        parent))),
+                },
+                None => None,
+            };
+            let keyframes = Keyframes::new(
+                node.keys
+                    .iter()
+                    .map(|key| (key.time, to_combined_transf(&key.transforms)))
+                    .collect(),
+                node.interpolation,
+            );
+            let id = graph.add_node(parent, Transf::new_identity());
+            if node_ids.insert(node.name.as_str(), id).is_some() {
+                return Err(PrismError::invalid_scene(format!("Error in scene file at `animation.nodes[{}].name`: there already is a node called \"{}\"",
+                    i,
+                    node.name)));
+            }
+            nodes.push((id, keyframes));
+        }
+
+        let mut shape_nodes = Vec::new();
+        for (i, shape) in desc.shapes.iter().enumerate() {
+            let node = match &shape.node {
+                Some(node) => node,
+                None => continue,
+            };
+            let id = match node_ids.get(node.as_str()) {
+                Some(&id) => id,
+                None => {
+                    return Err(PrismError::invalid_scene(format!(
+                        "Error in scene file at `shapes[{}].node`: unknown node \"{}\"",
+                        i, node
+                    )))
+                }
+            };
+            if shape.motion_transforms.is_some() {
+                return Err(PrismError::invalid_scene(format!("Error in scene file at `shapes[{}].motion_transforms`: shapes that follow a node can't have motion transformations",
+                    i)));
+            }
+            shape_nodes.push((i, id));
+        }
+
+        Ok(Animation {
+            frames: animation.frames.0..=animation.frames.1,
+            fps: animation.fps,
+            graph,
+            nodes,
+            shape_nodes,
+        })
+    }
+
+    /// The frames that are rendered.
+    pub fn frames(&self) -> RangeInclusive<u32> {
+        self.frames.clone()
+    }
+
+    /// Returns the description of the scene at a frame: the shapes that follow a node are moved to where
+    /// the node is at the time of the frame, and the seed of the sampler is offset by the frame (so that
+    /// the noise changes from frame to frame, but every frame renders the same every time). `desc` has
+    /// to be the description the animation was created from.
+    pub fn frame_desc(&mut self, desc: &SceneDesc, frame: u32) -> SceneDesc {
+        let time = (frame as f64) / self.fps;
+        for (id, keyframes) in self.nodes.iter() {
+            self.graph.set_local(*id, keyframes.eval(time));
+        }
+        self.graph.update();
+
+        let mut frame_desc = desc.clone();
+        for &(i, id) in self.shape_nodes.iter() {
+            let shape = &mut frame_desc.shapes[i];
+            let transf = self.graph.get_world(id) * to_combined_transf(&shape.transforms);
+            shape.transforms = vec![to_matrix_desc(transf)];
+        }
+        let sampler = &mut frame_desc.settings.sampler;
+        sampler.seed = sampler.seed.wrapping_add(frame as u64);
+        frame_desc
+    }
+}
+
+fn build_camera(desc: &CameraDesc, res: Vec2<usize>) -> PerspectiveCamera {
+    let camera_to_world = Transf::new_lookat(
+        to_vec3(desc.up),
+        to_vec3(desc.look_at),
+        to_vec3(desc.position),
+    );
+
+    PerspectiveCamera::new(
+        camera_to_world,
+        desc.fov,
+        desc.lens_radius,
+        desc.focal_dist,
+        PerspectiveCamera::default_screen_window(res),
+        res,
+    )
+}
+
+/// Returns the geometry both as a regular geometry and as a geometry that can be sampled (in case it's
+/// used as an area light), with its surface area calculated.
+fn share_geom<G: SampleableGeometry>(
+    mut geom: G,
+) -> (Arc<dyn Geometry>, Arc<dyn SampleableGeometry>) {
+    geom.calc_surface_area();
+    let geom = Arc::new(geom);
+    (geom.clone(), geom)
+}
+
+/// Combines the transformations (the last transformation in the list is applied last).
+fn to_combined_transf(descs: &[TransformDesc]) -> Transf {
+    descs.iter().fold(Transf::new_identity(), |transf, &desc| {
+        to_transf(desc) * transf
+    })
+}
+
+fn to_transf(desc: TransformDesc) -> Transf {
+    match desc {
+        TransformDesc::Translate(v) => Transf::new_translate(to_vec3(v)),
+        TransformDesc::Scale(v) => Transf::new_scale(to_vec3(v)),
+        TransformDesc::Rotate { deg, axis } => Transf::new_rotate(deg, to_vec3(axis)),
+        TransformDesc::Matrix(m) => Transf::from_mat3x4(pmath::matrix::Mat3x4::from_arr(m)),
+    }
+}
+
+fn to_matrix_desc(transf: Transf) -> TransformDesc {
+    let frd = transf.get_frd();
+    let c = [
+        frd.get_column(0),
+        frd.get_column(1),
+        frd.get_column(2),
+        frd.get_column(3),
+    ];
+    TransformDesc::Matrix([
+        c[0].x, c[1].x, c[2].x, c[3].x, //
+        c[0].y, c[1].y, c[2].y, c[3].y, //
+        c[0].z, c[1].z, c[2].z, c[3].z,
+    ])
+}
+
+fn to_vec3(v: (f64, f64, f64)) -> Vec3<f64> {
+    Vec3 {
+        x: v.0,
+        y: v.1,
+        z: v.2,
+    }
+}
+
+fn to_color(c: (f64, f64, f64)) -> Color {
+    Color::from_vec3(to_vec3(c).cast())
+}
+
+/// Converts a photometric quantity (nits, candela, ...) with the hue of `color` to the radiometric one.
+/// Without a value the color is used as it is.
+fn photometric(color: (f64, f64, f64), value: Option<f64>, location: &str) -> PrismResult<Color> {
+    match value {
+        Some(value) if !(value >= 0.0) => {
+            return Err(PrismError::invalid_scene(format!(
+                "Error in scene file at `{}`: can't be negative",
+                location
+            )))
+        }
+        Some(value) => {
+            Ok(to_color(color).with_luminance(Scalar::from_f64(value / LUMINOUS_EFFICACY)))
+        }
+        None => Ok(to_color(color)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::film::ImagePixel;
+    use crate::filter::{GaussianFilter, PixelFilter};
+    use crate::integrator::normal::{NormalIntegrator, NormalIntegratorManager};
+    use crate::integrator::path_tracer::{
+        PathTracerIntegrator, PathTracerIntegratorManager, PathTracerParam,
+    };
+    use crate::light::DirectLightParam;
+    use crate::threading;
+    use crate::TEST_EPSILON;
+
+    #[test]
+    fn valid_scene_parses() {
+        let desc = parse_scene(
+            "(
+    settings: (
+        res: (64, 32),
+        spp: 4,
+        integrator: Path(max_bounce: 2),
+    ),
+)",
+        )
+        .unwrap();
+        assert_eq!(desc.settings.res, (64, 32));
+        assert_eq!(desc.settings.spp, 4);
+        assert!(desc.shapes.is_empty());
+    }
+
+    #[test]
+    fn syntax_errors_report_their_line() {
+        let err = parse_scene(
+            "(
+    settings: (
+        res: (64, 32),
+        spp: 4,
+        integrator: Path(max_bounce: 2;
+    ),
+)",
+        )
+        .unwrap_err();
+        match err {
+            PrismError::Parse { path, line, .. } => {
+                assert_eq!(path, None);
+                assert_eq!(line, Some(5));
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn unknown_fields_report_where_they_are() {
+        let err = parse_scene(
+            "(
+    settings: (
+        res: (64, 32),
+        spp: 4,
+        integrator: Path(max_bounce: 2),
+        sample_count: 4,
+    ),
+)",
+        )
+        .unwrap_err();
+        match err {
+            PrismError::Parse { msg, .. } => {
+                assert!(msg.contains("settings"), "{}", msg);
+                assert!(msg.contains("sample_count"), "{}", msg);
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn cameras_are_written_without_some() {
+        let desc = parse_scene(
+            "(
+    settings: (
+        res: (64, 32),
+        spp: 4,
+        integrator: Path(max_bounce: 2),
+    ),
+    camera: (
+        position: (0.0, 0.0, -3.0),
+        look_at: (0.0, 0.0, 0.0),
+        fov: 45.0,
+    ),
+)",
+        )
+        .unwrap();
+        assert_eq!(desc.camera.unwrap().fov, 45.0);
+    }
+
+    #[test]
+    fn example_scenes_parse() {
+        let scenes = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        let mut num_scenes = 0;
+        for dir in &[scenes.clone(), scenes.join("regression")] {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.extension().map_or(false, |ext| ext == "ron") {
+                    let source = fs::read_to_string(&path).unwrap();
+                    if let Err(err) = parse_scene(&source) {
+                        panic!("{}: {}", path.display(), err);
+                    }
+                    num_scenes += 1;
+                }
+            }
+        }
+        assert!(num_scenes > 0);
+    }
+
+    #[test]
+    fn example_scenes_render() {
+        let scenes = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        for name in &["sphere.ron", "cornell_box.ron"] {
+            let source = fs::read_to_string(scenes.join(name)).unwrap();
+            let mut desc = parse_scene(&source).unwrap();
+            desc.settings.res = (16, 12);
+            desc.settings.spp = 2;
+            let loaded = build_scene(&desc, &scenes).unwrap();
+            assert_eq!(loaded.param.res, Vec2 { x: 16, y: 12 });
+
+            let filter = PixelFilter::new(&GaussianFilter::new(Vec2 { x: 1.0, y: 1.0 }, 0.5));
+            let output = match loaded.integrator {
+                IntegratorDesc::Normal { geometric_normal } => {
+                    threading::render::<NormalIntegrator, NormalIntegratorManager>(
+                        &loaded.camera,
+                        filter,
+                        &loaded.scene,
+                        &loaded.materials,
+                        loaded.param.clone(),
+                        geometric_normal,
+                        None,
+                        None,
+                    )
+                }
+                IntegratorDesc::Path { max_bounce, .. } => {
+                    threading::render::<PathTracerIntegrator, PathTracerIntegratorManager>(
+                        &loaded.camera,
+                        filter,
+                        &loaded.scene,
+                        &loaded.materials,
+                        loaded.param.clone(),
+                        PathTracerParam {
+                            max_bounce,
+                            direct_light: DirectLightParam::default(),
+                            aovs: false,
+                            max_direct: None,
+                            max_indirect: None,
+                        },
+                        None,
+                        None,
+                    )
+                }
+                _ => panic!("{} uses an unexpected integrator", name),
+            };
+            let image = output.unwrap().film.to_image_buffer(ImagePixel::from_color);
+            assert_eq!(image.res(), Vec2 { x: 16, y: 12 });
+            // Something has to be visible:
+            let center = image.get_pixel(Vec2 { x: 8, y: 6 });
+            assert!(center.r + center.g + center.b > 0.0, "{}", name);
+        }
+    }
+
+    /// A scene with the settings every test uses, and the given materials, shapes, and lights.
+    fn units_scene(units: &str, materials: &str, shapes: &str, lights: &str) -> LoadedScene {
+        let desc = parse_scene(&format!(
+            "(
+    settings: (
+        res: (16, 16),
+        spp: 1,
+        integrator: Path(max_bounce: 1),
+    ),
+    units: ({}),
+    materials: {{ {} }},
+    shapes: [ {} ],
+    lights: [ {} ],
+)",
+            units, materials, shapes, lights
+        ))
+        .unwrap();
+        build_scene(&desc, &std::env::temp_dir()).unwrap()
+    }
+
+    #[test]
+    fn lumens_fall_off_with_the_squared_distance_in_meters() {
+        // The illuminance (in lux) of the first light at a point facing it:
+        let lux = |loaded: &LoadedScene, p: Vec3<Scalar>| {
+            let light = loaded.scene.get_light(0);
+            let (color, _, pdf) = light.sample(p, 0.0, &loaded.scene, Vec2 { x: 0.5, y: 0.5 });
+            (color.luminance() / pdf).to_f64() * LUMINOUS_EFFICACY
+        };
+        let at = |z: Scalar| Vec3 { x: 0.0, y: 0.0, z };
+
+        // An isotropic 1000 lumen light gives 1000 / (4 pi) lux at a meter:
+        let meters = units_scene(
+            "meters_per_unit: 1.0",
+            "",
+            "",
+            "Point(position: (0.0, 0.0, 0.0), intensity: (1.0, 0.5, 0.25), lumens: Some(1000.0))",
+        );
+        let one_meter = lux(&meters, at(1.0));
+        let tolerance = TEST_EPSILON.to_f64();
+        assert!((one_meter - 1000.0 / (4.0 * std::f64::consts::PI)).abs() < tolerance);
+        assert!((one_meter / lux(&meters, at(2.0)) - 4.0).abs() < tolerance);
+        // The intensity only gives the hue:
+        let (color, _, _) =
+            meters
+                .scene
+                .get_light(0)
+                .sample(at(1.0), 0.0, &meters.scene, Vec2 { x: 0.5, y: 0.5 });
+        assert!((color.g / color.r - 0.5).abs() < TEST_EPSILON);
+
+        // A meter is 100 units of a scene in centimeters:
+        let centimeters = units_scene(
+            "meters_per_unit: 0.01",
+            "",
+            "",
+            "Point(position: (0.0, 0.0, 0.0), intensity: (1.0, 1.0, 1.0), lumens: Some(1000.0))",
+        );
+        assert_eq!(centimeters.scene.meters_per_unit(), 0.01);
+        assert!((lux(&centimeters, at(100.0)) - one_meter).abs() < tolerance);
+        assert!((lux(&centimeters, at(200.0)) - one_meter / 4.0).abs() < tolerance);
+    }
+
+    #[test]
+    fn units_are_converted_and_checked() {
+        // Nits round trip through the radiometric emission:
+        let loaded = units_scene(
+            "",
+            "\"lamp\": Matte(color: (0.0, 0.0, 0.0), emission: (1.0, 0.0, 0.0), nits: Some(500.0)),",
+            "",
+            "",
+        );
+        assert_eq!(loaded.scene.meters_per_unit(), 1.0);
+        let lamp = loaded.materials.get_shared_material(1).emission();
+        assert!(
+            (lamp.luminance().to_f64() * LUMINOUS_EFFICACY - 500.0).abs() < TEST_EPSILON.to_f64()
+        );
+        assert_eq!((lamp.g, lamp.b), (0.0, 0.0));
+
+        // A mesh in millimeters, in a scene in centimeters, is a tenth of its size:
+        let mesh_path = std::env::temp_dir().join("prism_units_triangle.ply");
+        fs::write(
+            &mesh_path,
+            "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\n\
+             property float z\nelement face 1\nproperty list uchar int vertex_indices\nend_header\n\
+             0 0 0\n10 0 0\n0 20 0\n3 0 1 2\n",
+        )
+        .unwrap();
+        let shape = format!(
+            "(geometry: Mesh(path: {:?}, meters_per_unit: Some(0.001)), material: \"white\")",
+            mesh_path.to_str().unwrap()
+        );
+        let loaded = units_scene(
+            "meters_per_unit: 0.01",
+            "\"white\": Matte(color: (0.8, 0.8, 0.8)),",
+            &shape,
+            "",
+        );
+        let pmax = loaded.scene.world_bound().pmax;
+        assert!(
+            (pmax.x - 1.0).abs() < 1e-6 && (pmax.y - 2.0).abs() < 1e-6,
+            "{:?}",
+            pmax
+        );
+
+        for units in &["meters_per_unit: 0.0", "meters_per_unit: -1.0"] {
+            let desc = parse_scene(&format!(
+                "(settings: (res: (16, 16), spp: 1, integrator: Path(max_bounce: 1)), units: ({}))",
+                units
+            ))
+            .unwrap();
+            assert!(build_scene(&desc, Path::new(".")).is_err());
+        }
+    }
+
+    #[test]
+    fn plastic_roughness_is_checked() {
+        let desc = parse_scene(
+            "(
+    settings: (res: (16, 16), spp: 1, integrator: Path(max_bounce: 1)),
+    materials: {
+        \"smooth\": Plastic(color: (0.5, 0.1, 0.1), roughness: 0.0),
+        \"linear\": Plastic(color: (0.5, 0.1, 0.1), reflect: (0.5, 0.5, 0.5), roughness: 0.3, remap: Linear),
+    },
+)",
+        )
+        .unwrap();
+        assert_eq!(
+            desc.materials["smooth"],
+            MaterialDesc::Plastic {
+                color: (0.5, 0.1, 0.1),
+                reflect: (1.0, 1.0, 1.0),
+                roughness: 0.0,
+                remap: RoughnessRemap::Squared,
+            }
+        );
+        assert!(build_scene(&desc, Path::new(".")).is_ok());
+
+        let mut desc = desc;
+        desc.materials.insert(
+            "rough".to_owned(),
+            MaterialDesc::Plastic {
+                color: (0.5, 0.5, 0.5),
+                reflect: (1.0, 1.0, 1.0),
+                roughness: 1.5,
+                remap: RoughnessRemap::Squared,
+            },
+        );
+        assert!(matches!(
+            build_scene(&desc, Path::new(".")),
+            Err(PrismError::InvalidScene(_))
+        ));
+    }
+
+    #[test]
+    fn unbound_attribute_ids_are_reported() {
+        // A quad with attribute ids 0 and 2:
+        let mesh_path = std::env::temp_dir().join("prism_attribute_quad.ply");
+        fs::write(
+            &mesh_path,
+            "ply\nformat ascii 1.0\nelement vertex 4\nproperty float x\nproperty float y\n\
+             property float z\nelement face 2\nproperty list uchar int vertex_indices\n\
+             property int attribute_id\nend_header\n\
+             0 0 0\n1 0 0\n1 1 0\n0 1 0\n3 0 1 2 0\n3 0 2 3 2\n",
+        )
+        .unwrap();
+        let build = |attribute_materials: &str| {
+            let desc = parse_scene(&format!(
+                "(
+    settings: (res: (16, 16), spp: 1, integrator: Path(max_bounce: 1)),
+    materials: {{
+        \"white\": Matte(color: (0.8, 0.8, 0.8)),
+        \"red\": Matte(color: (0.8, 0.1, 0.1)),
+        \"lamp\": Matte(color: (0.0, 0.0, 0.0), emission: (1.0, 1.0, 1.0)),
+    }},
+    shapes: [
+        (
+            name: Some(\"quad\"),
+            geometry: Mesh(path: {:?}),
+            material: \"white\",
+            attribute_materials: [{}],
+        ),
+    ],
+)",
+                mesh_path.to_str().unwrap(),
+                attribute_materials
+            ))
+            .unwrap();
+            build_scene(&desc, Path::new("."))
+        };
+
+        // The whole mesh uses its material if there aren't any attribute materials:
+        assert!(build("").is_ok());
+        assert!(build("\"red\", \"white\", \"red\"").is_ok());
+        match build("\"red\"") {
+            Err(PrismError::InvalidScene(message)) => assert!(
+                message.contains("attribute_materials")
+                    && message.contains("\"quad\"")
+                    && message.contains("prism_attribute_quad.ply")
+                    && message.contains("[1, 2]"),
+                "{}",
+                message
+            ),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("the unbound attribute ids weren't reported"),
+        }
+        for attribute_materials in &["\"red\", \"blue\", \"red\"", "\"red\", \"lamp\", \"red\""] {
+            assert!(matches!(
+                build(attribute_materials),
+                Err(PrismError::InvalidScene(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use crate::error::{PrismError, PrismResult};
+use crate::film::metadata::ImageMetadata;
+use crate::film::{ImageBuffer, ImagePixel};
+use half::f16;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+/// How the values of a layer are stored in the exr file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelType {
+    /// 16 bit floats, which are plenty for things like normals or the bounce count (and half the size).
+    /// Values are rounded to the nearest half float (ties to even), small values become denormals and
+    /// values that are too large become infinity.
+    Half,
+    /// 32 bit floats, which have about 7 significant digits. That is more than enough for colors, but
+    /// world space positions far from the origin lose precision (see
+    /// `Film::position_to_image_buffer`).
+    Float,
+}
+
+impl PixelType {
+    fn id(self) -> i32 {
+        match self {
+            PixelType::Half => 1,
+            PixelType::Float => 2,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            PixelType::Half => 2,
+            PixelType::Float => 4,
+        }
+    }
+}
+
+/// One layer of an exr file. The channels of a layer are called `<name>.R`, `<name>.G`, and so on (or
+/// just `R`, `G`, ... if the name is empty, which is where viewers look for the image).
+#[derive(Clone, Copy, Debug)]
+pub struct ExrLayer<'a> {
+    pub name: &'a str,
+    pub image: &'a ImageBuffer,
+    pub pixel_type: PixelType,
+    /// Whether to write the alpha channel.
+    pub alpha: bool,
+}
+
+/// A single channel of the file (the channels are written in alphabetical order).
+struct Channel<'a> {
+    name: String,
+    image: &'a ImageBuffer,
+    pixel_type: PixelType,
+    value: fn(ImagePixel) -> f64,
+}
+
+/// Writes the layers (which all need to have the same resolution) to an uncompressed scanline exr file
+/// at the designated path.
+pub fn write_exr(layers: &[ExrLayer], path: &str) -> PrismResult<()> {
+    write_exr_with_metadata(layers, path, &ImageMetadata::new())
+}
+
+/// Same as `write_exr`, but also stores the metadata in the header of the file (as `string` attributes).
+pub fn write_exr_with_metadata(
+    layers: &[ExrLayer],
+    path: &str,
+    metadata: &ImageMetadata,
+) -> PrismResult<()> {
+    // The file can't be written if the layers don't fit together:
+    let invalid =
+        |msg: &str| PrismError::io(path, io::Error::new(io::ErrorKind::InvalidInput, msg));
+    let res = match layers.first() {
+        Some(layer) => layer.image.res,
+        None => return Err(invalid("no layers")),
+    };
+    if layers.iter().any(|layer| layer.image.res != res) {
+        return Err(invalid("the layers have different resolutions"));
+    }
+
+    let mut channels = Vec::new();
+    for layer in layers.iter() {
+        let mut add = |suffix: &str, value: fn(ImagePixel) -> f64| {
+            let name = if layer.name.is_empty() {
+                suffix.to_string()
+            } else {
+                format!("{}.{}", layer.name, suffix)
+            };
+            channels.push(Channel {
+                name,
+                image: layer.image,
+                pixel_type: layer.pixel_type,
+                value,
+            });
+        };
+        add("R", |p| p.r);
+        add("G", |p| p.g);
+        add("B", |p| p.b);
+        if layer.alpha {
+            add("A", |p| p.a);
+        }
+    }
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+    if channels.windows(2).any(|pair| pair[0].name == pair[1].name) {
+        return Err(invalid("two layers have the same name"));
+    }
+
+    let mut header = Vec::new();
+    // The magic number and the version (2, single part scanline file):
+    header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01]);
+    let long_names = channels.iter().any(|channel| channel.name.len() > 31);
+    let version: u32 = if long_names { 2 | 0x400 } else { 2 };
+    header.extend_from_slice(&version.to_le_bytes());
+
+    let mut chlist = Vec::new();
+    for channel in channels.iter() {
+        chlist.extend_from_slice(channel.name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&channel.pixel_type.id().to_le_bytes());
+        // pLinear and the reserved bytes, and then the x and y sampling:
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+    }
+    chlist.push(0);
+    write_attribute(&mut header, "channels", "chlist", &chlist);
+    // No compression:
+    write_attribute(&mut header, "compression", "compression", &[0]);
+    let mut window = Vec::new();
+    for &v in [0, 0, res.x as i32 - 1, res.y as i32 - 1].iter() {
+        window.extend_from_slice(&i32::to_le_bytes(v));
+    }
+    write_attribute(&mut header, "dataWindow", "box2i", &window);
+    write_attribute(&mut header, "displayWindow", "box2i", &window);
+    // Increasing y:
+    write_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    write_attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1.0f32.to_le_bytes(),
+    );
+    write_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    write_attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1.0f32.to_le_bytes(),
+    );
+    metadata.write_exr_attributes(&mut header);
+    header.push(0);
+
+    // Every scanline is its own block, which the offset table points to:
+    let line_size: usize = channels
+        .iter()
+        .map(|channel| channel.pixel_type.size() * res.x)
+        .sum();
+    let block_size = 8 + line_size;
+    let first_block = header.len() + 8 * res.y;
+
+    let mut data = Vec::with_capacity(first_block + block_size * res.y);
+    data.extend_from_slice(&header);
+    for y in 0..res.y {
+        data.extend_from_slice(&((first_block + y * block_size) as u64).to_le_bytes());
+    }
+    for y in 0..res.y {
+        data.extend_from_slice(&(y as i32).to_le_bytes());
+        data.extend_from_slice(&(line_size as i32).to_le_bytes());
+        for channel in channels.iter() {
+            let row = &channel.image.buffer[(y * res.x)..((y + 1) * res.x)];
+            for &pixel in row.iter() {
+                let value = (channel.value)(pixel);
+                match channel.pixel_type {
+                    PixelType::Half => {
+                        data.extend_from_slice(&f16::from_f64(value).to_bits().to_le_bytes())
+                    }
+                    PixelType::Float => data.extend_from_slice(&(value as f32).to_le_bytes()),
+                }
+            }
+        }
+    }
+
+    let mut file = File::create(path).map_err(|err| PrismError::io(path, err))?;
+    file.write_all(&data)
+        .map_err(|err| PrismError::io(path, err))
+}
+
+fn write_attribute(header: &mut Vec<u8>, name: &str, attr_type: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(attr_type.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pmath::vector::Vec2;
+    use std::convert::TryInto;
+    use std::fs;
+
+    fn half_round_trip(value: f64) -> f64 {
+        f16::from_f64(value).to_f64()
+    }
+
+    #[test]
+    fn half_floats_round_to_the_nearest_even() {
+        // Normal values have 11 significant bits:
+        for &value in [1.0, -0.3, 3.14159, 1234.5678, 65000.0, 6.2e-5].iter() {
+            let error = (half_round_trip(value) - value).abs();
+            assert!(error <= value.abs() * 2f64.powi(-11), "{}", value);
+        }
+        // Ties go to the even neighbour:
+        let ulp = 2f64.powi(-10);
+        assert_eq!(half_round_trip(1.0 + 0.5 * ulp), 1.0);
+        assert_eq!(half_round_trip(1.0 + 1.5 * ulp), 1.0 + 2.0 * ulp);
+        // Values below the smallest normal become denormals (with a fixed step of 2^-24):
+        let denormal = 3.3e-7;
+        assert!((half_round_trip(denormal) - denormal).abs() <= 2f64.powi(-25));
+        assert!(half_round_trip(denormal) > 0.0);
+        assert_eq!(half_round_trip(2f64.powi(-26)), 0.0);
+        // Values that are too large become infinity:
+        assert_eq!(half_round_trip(65504.0), 65504.0);
+        assert_eq!(half_round_trip(1e6), f64::INFINITY);
+        assert_eq!(half_round_trip(-1e6), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn channels_are_written_with_their_pixel_type() {
+        let res = Vec2 { x: 3, y: 2 };
+        let image = |scale: f64| ImageBuffer {
+            buffer: (0..(res.x * res.y))
+                .map(|i| ImagePixel {
+                    r: scale * (i as f64),
+                    g: 0.1,
+                    b: -0.5,
+                    a: 1.0,
+                })
+                .collect(),
+            res,
+        };
+        let (beauty, normal) = (image(1.0 / 3.0), image(0.1));
+        let path = std::env::temp_dir().join(format!("prism_exr_{}.exr", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_exr(
+            &[
+                ExrLayer {
+                    name: "",
+                    image: &beauty,
+                    pixel_type: PixelType::Float,
+                    alpha: true,
+                },
+                ExrLayer {
+                    name: "normal",
+                    image: &normal,
+                    pixel_type: PixelType::Half,
+                    alpha: false,
+                },
+            ],
+            path,
+        )
+        .unwrap();
+        let data = fs::read(path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        // Every channel of the list is its name followed by its type, and then 12 more bytes:
+        let start = data
+            .windows(15)
+            .position(|w| w == b"channels\0chlist")
+            .unwrap()
+            + 20;
+        let mut chlist = &data[start..];
+        let mut channels = Vec::new();
+        while chlist[0] != 0 {
+            let end = chlist.iter().position(|&b| b == 0).unwrap();
+            let name = String::from_utf8(chlist[..end].to_vec()).unwrap();
+            let pixel_type = i32::from_le_bytes(chlist[(end + 1)..(end + 5)].try_into().unwrap());
+            channels.push((name, pixel_type));
+            chlist = &chlist[(end + 17)..];
+        }
+        let expected = [
+            ("A", 2),
+            ("B", 2),
+            ("G", 2),
+            ("R", 2),
+            ("normal.B", 1),
+            ("normal.G", 1),
+            ("normal.R", 1),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(name, pixel_type)| (name.to_owned(), pixel_type))
+            .collect();
+        assert_eq!(channels, expected);
+
+        // The last scanline ends the file, and its last channel is normal.R (as half floats):
+        let line_size = res.x * (4 * 4 + 3 * 2);
+        let last_line = &data[(data.len() - line_size - 8)..];
+        assert_eq!(i32::from_le_bytes(last_line[..4].try_into().unwrap()), 1);
+        assert_eq!(
+            i32::from_le_bytes(last_line[4..8].try_into().unwrap()) as usize,
+            line_size
+        );
+        let normal_r = &last_line[(last_line.len() - 2 * res.x)..];
+        for x in 0..res.x {
+            let bits = u16::from_le_bytes(normal_r[(2 * x)..(2 * x + 2)].try_into().unwrap());
+            let value = 0.1 * ((res.x + x) as f64);
+            assert_eq!(f16::from_bits(bits), f16::from_f64(value));
+        }
+        let beauty_a = &last_line[8..(8 + 4 * res.x)];
+        for x in 0..res.x {
+            let value = f32::from_le_bytes(beauty_a[(4 * x)..(4 * x + 4)].try_into().unwrap());
+            assert_eq!(value, 1.0);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Text attributes stored in the image files, which describe how an image was rendered (the sample count,
+//! the integrator, how long it took, ...). Png files store them as `tEXt` chunks and exr files as `string`
+//! attributes in the header, so other tools can read them as well.
+
+use crate::error::{PrismError, PrismResult};
+use std::convert::TryInto;
+use std::fs;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+const EXR_MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+
+/// A list of keys and their values, in the order they were added.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImageMetadata {
+    entries: Vec<(String, String)>,
+}
+
+impl ImageMetadata {
+    pub fn new() -> Self {
+        ImageMetadata {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Adds an entry. Png files only support latin-1 text (and keys of at most 79 characters), so anything
+    /// else is replaced with `?` when writing png files.
+    pub fn push(&mut self, key: &str, value: impl Into<String>) {
+        self.entries.push((key.to_owned(), value.into()));
+    }
+
+    /// Returns the value of the first entry with the key.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(entry_key, _)| entry_key == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Inserts the entries as `tEXt` chunks right after the header of an encoded png file.
+    pub(super) fn insert_into_png(&self, png: &mut Vec<u8>) {
+        // The signature, followed by the IHDR chunk (13 bytes of data and 12 bytes of length, type, and crc):
+        const HEADER_END: usize = 8 + 12 + 13;
+
+        let mut chunks = Vec::new();
+        for (key, value) in self.iter() {
+            let mut data: Vec<u8> = key.chars().take(79).map(to_latin1).collect();
+            data.push(0);
+            data.extend(value.chars().map(to_latin1));
+
+            chunks.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            let crc_start = chunks.len();
+            chunks.extend_from_slice(b"tEXt");
+            chunks.extend_from_slice(&data);
+            let crc = crc32(&chunks[crc_start..]);
+            chunks.extend_from_slice(&crc.to_be_bytes());
+        }
+        png.splice(HEADER_END..HEADER_END, chunks);
+    }
+
+    /// Writes the entries as `string` attributes to the header of an exr file (which has to be followed by
+    /// the rest of the header).
+    pub(super) fn write_exr_attributes(&self, header: &mut Vec<u8>) {
+        for (key, value) in self.iter() {
+            header.extend_from_slice(key.as_bytes());
+            header.push(0);
+            header.extend_from_slice(b"string\0");
+            header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+            header.extend_from_slice(value.as_bytes());
+        }
+    }
+}
+
+/// Reads the metadata of a png or an exr file (which one is decided by the contents of the file). Images
+/// without any metadata return an empty list.
+pub fn read_metadata(path: &str) -> PrismResult<ImageMetadata> {
+    let bytes = fs::read(path).map_err(|err| PrismError::io(path, err))?;
+    let metadata = if bytes.starts_with(&PNG_SIGNATURE) {
+        read_png_chunks(&bytes[PNG_SIGNATURE.len()..])
+    } else if bytes.starts_with(&EXR_MAGIC) {
+        // The magic number is followed by the version and flags:
+        bytes.get(8..).and_then(read_exr_attributes)
+    } else {
+        return Err(PrismError::parse(path, None, "not a png or an exr file"));
+    };
+    metadata.ok_or_else(|| PrismError::parse(path, None, "the file is truncated"))
+}
+
+fn read_png_chunks(mut bytes: &[u8]) -> Option<ImageMetadata> {
+    let mut metadata = ImageMetadata::new();
+    loop {
+        let len = u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?) as usize;
+        let chunk_type = bytes.get(4..8)?;
+        let data = bytes.get(8..(8 + len))?;
+        if chunk_type == b"tEXt" {
+            // A chunk without a separator isn't valid, so it's skipped:
+            if let Some(separator) = data.iter().position(|&b| b == 0) {
+                let from_latin1 =
+                    |bytes: &[u8]| bytes.iter().map(|&b| b as char).collect::<String>();
+                metadata.push(
+                    &from_latin1(&data[..separator]),
+                    from_latin1(&data[(separator + 1)..]),
+                );
+            }
+        }
+        if chunk_type == b"IEND" {
+            return Some(metadata);
+        }
+        // The data is followed by the crc:
+        bytes = bytes.get((12 + len)..)?;
+    }
+}
+
+fn read_exr_attributes(mut bytes: &[u8]) -> Option<ImageMetadata> {
+    let mut metadata = ImageMetadata::new();
+    loop {
+        let name = read_str(&mut bytes)?;
+        // The header ends with an empty name:
+        if name.is_empty() {
+            return Some(metadata);
+        }
+        let attr_type = read_str(&mut bytes)?;
+        let size: usize = i32::from_le_bytes(bytes.get(..4)?.try_into().ok()?)
+            .try_into()
+            .ok()?;
+        let value = bytes.get(4..(4 + size))?;
+        if attr_type == "string" {
+            metadata.push(&name, String::from_utf8_lossy(value).into_owned());
+        }
+        bytes = &bytes[(4 + size)..];
+    }
+}
+
+/// Reads a null terminated string from the front of the bytes.
+fn read_str(bytes: &mut &[u8]) -> Option<String> {
+    let end = bytes.iter().position(|&b| b == 0)?;
+    let s = String::from_utf8_lossy(&bytes[..end]).into_owned();
+    *bytes = &bytes[(end + 1)..];
+    Some(s)
+}
+
+fn to_latin1(c: char) -> u8 {
+    if (c as u32) < 256 {
+        c as u8
+    } else {
+        b'?'
+    }
+}
+
+/// The crc used by png files (ISO 3309), computed a bit at a time as the chunks are small.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                0xedb88320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::film::exr::{self, ExrLayer, PixelType};
+    use crate::film::png::{self, BitDepth, Channels};
+    use crate::film::{ImageBuffer, ImagePixel};
+    use pmath::vector::Vec2;
+
+    fn temp_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("prism_metadata_{}_{}", std::process::id(), name));
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn test_image() -> ImageBuffer {
+        let res = Vec2 { x: 4, y: 3 };
+        ImageBuffer {
+            buffer: vec![ImagePixel::from_rgb(0.25, 0.5, 0.75); res.x * res.y],
+            res,
+        }
+    }
+
+    fn test_metadata() -> ImageMetadata {
+        let mut metadata = ImageMetadata::new();
+        metadata.push("Software", "prism 0.1.0");
+        metadata.push("prism:resolution", "4x3");
+        metadata.push("prism:spp", "16");
+        metadata.push("prism:seed", "13");
+        metadata.push("prism:renderTime", "0.125s");
+        metadata
+    }
+
+    #[test]
+    fn metadata_round_trips_through_png_and_exr() {
+        let image = test_image();
+        let metadata = test_metadata();
+
+        let png_path = temp_path("image.png");
+        png::write_png_with_metadata(
+            &image,
+            &png_path,
+            BitDepth::EIGHT,
+            Channels::RGBA,
+            &metadata,
+        )
+        .unwrap();
+        let from_png = read_metadata(&png_path);
+        // The chunks don't get in the way of the image:
+        let png_image = png::read_png(&png_path).unwrap();
+        fs::remove_file(&png_path).unwrap();
+        assert_eq!(from_png.unwrap(), metadata);
+        assert_eq!(png_image.res(), image.res());
+
+        let exr_path = temp_path("image.exr");
+        let layer = ExrLayer {
+            name: "",
+            image: &image,
+            pixel_type: PixelType::Half,
+            alpha: false,
+        };
+        exr::write_exr_with_metadata(&[layer], &exr_path, &metadata).unwrap();
+        let from_exr = read_metadata(&exr_path);
+        fs::remove_file(&exr_path).unwrap();
+        // The exr file has attributes of its own (like the channels), which aren't strings:
+        assert_eq!(from_exr.unwrap(), metadata);
+
+        // Images without metadata have an empty list:
+        png::write_png(&image, &png_path, BitDepth::EIGHT, Channels::RGB).unwrap();
+        let from_png = read_metadata(&png_path);
+        fs::remove_file(&png_path).unwrap();
+        assert!(from_png.unwrap().is_empty());
+    }
+
+    #[test]
+    fn png_text_is_latin1_and_invalid_files_are_errors() {
+        let mut metadata = ImageMetadata::new();
+        metadata.push("prism:scene", "caf\u{e9} \u{263a}.ron");
+        let path = temp_path("latin1.png");
+        png::write_png_with_metadata(
+            &test_image(),
+            &path,
+            BitDepth::EIGHT,
+            Channels::RGB,
+            &metadata,
+        )
+        .unwrap();
+        let read = read_metadata(&path).unwrap();
+        assert_eq!(read.get("prism:scene"), Some("caf\u{e9} ?.ron"));
+
+        // Truncated files and files that aren't images:
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..40]).unwrap();
+        assert!(read_metadata(&path).is_err());
+        fs::write(&path, b"not an image").unwrap();
+        assert!(read_metadata(&path).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(read_metadata(&path).is_err());
+    }
+}
@@ -1,13 +1,108 @@
 use crate::spectrum::Color;
-use pmath::vector::Vec2;
-use std::cell::Cell;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::Scalar;
+use pmath::numbers::Float;
+use pmath::vector::{Vec2, Vec3};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
 
+pub mod exr;
+pub mod metadata;
+pub mod overlay;
 pub mod png;
+pub mod resample;
+pub mod sample_dump;
+
+/// Auxiliary values that an integrator can record per sample (next to the color), which are averaged
+/// per pixel just like the color.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AovKind {
+    /// The number of surfaces a path hit before it was terminated.
+    BounceCount,
+    /// The total distance a path travelled (in world units).
+    PathLength,
+    /// 1 if the camera ray hit a surface (0 otherwise).
+    SurfaceHit,
+    /// The world space position of the surface the camera ray hit (0 if it didn't hit anything). The
+    /// positions are averaged over the samples that hit a surface (see `Film::position_to_image_buffer`).
+    PositionX,
+    PositionY,
+    PositionZ,
+}
+
+impl AovKind {
+    pub const COUNT: usize = 6;
+    pub const ALL: [AovKind; AovKind::COUNT] = [
+        AovKind::BounceCount,
+        AovKind::PathLength,
+        AovKind::SurfaceHit,
+        AovKind::PositionX,
+        AovKind::PositionY,
+        AovKind::PositionZ,
+    ];
+    /// The AOVs that describe the entire path (rather than the first hit), which are useful as heatmaps.
+    pub const PATH: [AovKind; 2] = [AovKind::BounceCount, AovKind::PathLength];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// A short name that can be used in file names.
+    pub fn name(self) -> &'static str {
+        match self {
+            AovKind::BounceCount => "bounces",
+            AovKind::PathLength => "path_length",
+            AovKind::SurfaceHit => "surface_hit",
+            AovKind::PositionX => "position_x",
+            AovKind::PositionY => "position_y",
+            AovKind::PositionZ => "position_z",
+        }
+    }
+}
+
+/// The kind of path that carried light to the camera, so that the image can be split into components
+/// for compositing. The components of a pixel add up to its color (up to floating point rounding).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LightPath {
+    /// Light emitted by a surface that a camera ray hit directly.
+    Emission,
+    /// Light that reached the first surface a camera ray hit directly from a light.
+    Direct,
+    /// Everything else (light that bounced off of at least one other surface).
+    Indirect,
+}
+
+impl LightPath {
+    pub const COUNT: usize = 3;
+    pub const ALL: [LightPath; LightPath::COUNT] =
+        [LightPath::Emission, LightPath::Direct, LightPath::Indirect];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// A short name that can be used in file names.
+    pub fn name(self) -> &'static str {
+        match self {
+            LightPath::Emission => "emission",
+            LightPath::Direct => "direct",
+            LightPath::Indirect => "indirect",
+        }
+    }
+}
 
 #[derive(Clone, Copy, Debug)]
 pub struct Pixel {
     pub color: Color,
+    // The sum of the coverage of every sample (0 means the sample only saw the transparent background).
+    pub alpha: f64,
+    // The sum of the auxiliary values of every sample (indexed by `AovKind`):
+    pub aux: [f64; AovKind::COUNT],
+    // The sum of the color of every sample split by the kind of path (indexed by `LightPath`):
+    pub light_paths: [Color; LightPath::COUNT],
     pub count: u32,
 }
 
@@ -16,6 +111,9 @@ impl Pixel {
     pub fn black() -> Self {
         Pixel {
             color: Color::black(),
+            alpha: 0.0,
+            aux: [0.0; AovKind::COUNT],
+            light_paths: [Color::black(); LightPath::COUNT],
             count: 0,
         }
     }
@@ -24,21 +122,89 @@ impl Pixel {
     pub fn white() -> Self {
         Pixel {
             color: Color::white(),
+            alpha: 0.0,
+            aux: [0.0; AovKind::COUNT],
+            light_paths: [Color::black(); LightPath::COUNT],
             count: 0,
         }
     }
 
     /// Creates a new pixel with the given spectrum.
     pub fn new(color: Color) -> Self {
-        Pixel { color, count: 0 }
+        Pixel {
+            color,
+            alpha: 0.0,
+            aux: [0.0; AovKind::COUNT],
+            light_paths: [Color::black(); LightPath::COUNT],
+            count: 0,
+        }
     }
 
-    /// Adds a sample to the pixel.
+    /// Adds an opaque sample to the pixel.
     pub fn add_sample(self, color: Color) -> Self {
+        self.add_sample_alpha(color, 1.0)
+    }
+
+    /// Adds a sample with the given coverage to the pixel.
+    pub fn add_sample_alpha(self, color: Color, alpha: Scalar) -> Self {
         Pixel {
             color: self.color + color,
+            alpha: self.alpha + alpha.to_f64(),
             count: self.count + 1,
+            ..self
+        }
+    }
+
+    /// Adds all of the samples of another pixel to this one.
+    pub fn merge(self, other: Pixel) -> Self {
+        let mut aux = self.aux;
+        for (aux, other) in aux.iter_mut().zip(other.aux.iter()) {
+            *aux += other;
+        }
+        let mut light_paths = self.light_paths;
+        for (color, &other) in light_paths.iter_mut().zip(other.light_paths.iter()) {
+            *color += other;
+        }
+        Pixel {
+            color: self.color + other.color,
+            alpha: self.alpha + other.alpha,
+            aux,
+            light_paths,
+            count: self.count + other.count,
+        }
+    }
+
+    /// Removes the samples of another pixel that were added to this one (the opposite of `merge`).
+    fn without(self, other: Pixel) -> Self {
+        let mut aux = self.aux;
+        for (aux, other) in aux.iter_mut().zip(other.aux.iter()) {
+            *aux -= other;
+        }
+        let mut light_paths = self.light_paths;
+        for (color, &other) in light_paths.iter_mut().zip(other.light_paths.iter()) {
+            *color = *color - other;
         }
+        Pixel {
+            color: self.color - other.color,
+            alpha: self.alpha - other.alpha,
+            aux,
+            light_paths,
+            count: self.count - other.count,
+        }
+    }
+
+    /// Adds the part of the color of the current sample that was carried by the given kind of path.
+    /// Like `add_aux`, this should be called at most once per kind for every call to `add_sample`.
+    pub fn add_light_path(mut self, kind: LightPath, color: Color) -> Self {
+        self.light_paths[kind.index()] += color;
+        self
+    }
+
+    /// Adds an auxiliary value for the current sample. This should be called at most once per kind for
+    /// every call to `add_sample` (samples that don't add a value count as 0).
+    pub fn add_aux(mut self, kind: AovKind, value: Scalar) -> Self {
+        self.aux[kind.index()] += value.to_f64();
+        self
     }
 
     /// Calculates the final color of the pixel.
@@ -46,13 +212,57 @@ impl Pixel {
         if self.count == 0 {
             self.color
         } else {
-            self.color.scale(1.0 / (self.count as f64))
+            self.color.scale(1.0 / (self.count as Scalar))
+        }
+    }
+
+    /// Calculates the final alpha of the pixel.
+    pub fn final_alpha(self) -> f64 {
+        if self.count == 0 {
+            self.alpha
+        } else {
+            self.alpha / (self.count as f64)
+        }
+    }
+
+    /// Calculates the final color of a single component of the pixel.
+    pub fn final_light_path(self, kind: LightPath) -> Color {
+        let color = self.light_paths[kind.index()];
+        if self.count == 0 {
+            color
+        } else {
+            color.scale(1.0 / (self.count as Scalar))
+        }
+    }
+
+    /// Calculates the average auxiliary value of the pixel.
+    pub fn final_aux(self, kind: AovKind) -> f64 {
+        let aux = self.aux[kind.index()];
+        if self.count == 0 {
+            aux
+        } else {
+            aux / (self.count as f64)
         }
     }
-}
 
-pub const TILE_DIM: usize = 16;
-pub const TILE_SIZE: usize = TILE_DIM * TILE_DIM;
+    /// The average position of the surfaces the camera rays of the pixel hit, or `None` if none of them
+    /// hit anything (see `AovKind::PositionX`).
+    pub fn final_position(self) -> Option<Vec3<f64>> {
+        let hits = self.aux[AovKind::SurfaceHit.index()];
+        if hits > 0.0 {
+            Some(
+                Vec3 {
+                    x: self.aux[AovKind::PositionX.index()],
+                    y: self.aux[AovKind::PositionY.index()],
+                    z: self.aux[AovKind::PositionZ.index()],
+                }
+                .scale(1.0 / hits),
+            )
+        } else {
+            None
+        }
+    }
+}
 
 /// Given an index, uniquely maps it to a 2d position.
 fn index_to_pos(index: u64, res: Vec2<usize>) -> Vec2<u32> {
@@ -66,63 +276,212 @@ fn index_to_pos(index: u64, res: Vec2<usize>) -> Vec2<u32> {
 // A FilmTile holds all of the information that a rendering thread needs from
 // the film buffer.
 pub struct FilmTile {
-    // The data in a specific tile.
-    pub data: [Pixel; TILE_SIZE],
+    // The data in a specific tile (in row-major order).
+    pub data: Vec<Pixel>,
     // The coordinate of the top left most pixel in the tile.
     pub pos: Vec2<usize>,
+    // The number of pixels in the tile along each axis. Tiles along the right and bottom edges
+    // of the film can be smaller than the tile dimension.
+    pub size: Vec2<usize>,
     // A unique seed for use with the samplers. Even if it's technically the same
     // tile, the seed will always be unique.
     pub seed: u64,
     // The index of the tile in the buffer.
     pub index: usize,
+    // When the tile was handed out (to measure how long it takes to render).
+    start: Instant,
+    // Every other sample of every pixel, if the film is split (see `Film::with_split`):
+    second_half: Option<Vec<Pixel>>,
+}
+
+impl FilmTile {
+    /// Whether the film keeps every other sample separately (see `Film::with_split`), in which case the
+    /// samples have to be added with `add_sample`.
+    pub fn is_split(&self) -> bool {
+        self.second_half.is_some()
+    }
+
+    /// Adds a single sample (a pixel that only has that sample) to the pixel `i` of the tile. If the film
+    /// is split, every other sample of the pixel is also added to the second half.
+    pub fn add_sample(&mut self, i: usize, sample: Pixel) {
+        if let Some(second_half) = &mut self.second_half {
+            if self.data[i].count % 2 == 1 {
+                second_half[i] = second_half[i].merge(sample);
+            }
+        }
+        self.data[i] = self.data[i].merge(sample);
+    }
+}
+
+/// The images of the two halves of the samples of a split film (see `Film::to_image_buffer_split`).
+pub struct SplitImageBuffers {
+    /// Every other sample of every pixel, starting with the first one.
+    pub a: ImageBuffer,
+    /// The rest of the samples.
+    pub b: ImageBuffer,
+    /// An estimate of the variance of every channel of the color of every pixel (of the image with all
+    /// of the samples), from the difference of the two halves.
+    pub variance: ImageBuffer,
 }
 
 // Manages the pixel buffer and the tile scheduler. For simple cases, the tile scheduler just moves
 // through the tiles in a linear fashion. But when adaptive sampling is implemented, these operations
 // will become more complex. Because it's in charge of adaptive sampling, the Film object is in charge
 // of ending the rendering process when it deems enough tiles to have been rendered.
+//
+// The time it takes to render every tile is recorded, and later passes hand out the most expensive
+// tiles first. That way the threads don't end up waiting on a single expensive tile that happened to
+// be handed out last.
 pub struct Film {
-    buffer: Vec<Cell<[Pixel; TILE_SIZE]>>, // The buffer that stores the tiles.
-    tile_res: Vec2<usize>,                 // The resolution in terms of tiles.
-    next_tile_index: AtomicUsize,          // The next tile to "hand out".
+    buffer: Vec<Mutex<Vec<Pixel>>>,  // The buffer that stores the tiles.
+    res: Vec2<usize>,                // The resolution in terms of pixels.
+    tile_dim: usize,                 // The number of pixels along each side of a tile.
+    tile_res: Vec2<usize>,           // The resolution in terms of tiles.
+    next_tile_index: AtomicUsize,    // The next tile to "hand out" (an index into `tile_order`).
+    num_tiles_complete: AtomicUsize, // The number of tiles that were finished.
+    pass: usize,                     // The number of times every tile was handed out.
+    tile_order: Vec<usize>,          // The order the tiles are handed out in.
+    tile_costs: Vec<AtomicU64>, // How long every tile took the last time (in ns, 0 if unknown).
+    // The second half of the samples of every tile (see `with_split`):
+    second_half: Option<Vec<Mutex<Vec<Pixel>>>>,
 }
 
 impl Film {
     /// Generates a new Film struct.
     ///
     /// # Arguments
-    /// * `tile_res` - The resolution, in tiles, of the Film
-    /// * `init_pixel` - What value every pixel in the Film should initially have
+    /// * `res` - The resolution, in pixels, of the Film
+    /// * `tile_dim` - The number of pixels along each side of a tile
+    /// * `pixel` - What value every pixel in the Film should initially have
     ///
     /// # Panics
-    /// If `tile_res` leads to the total number of tiles being zero, new will panic.
-    pub fn new(tile_res: Vec2<usize>, pixel: Pixel) -> Self {
-        let num_tiles = tile_res.x * tile_res.y;
-        //assert_ne!(num_tiles, 0);
-        Film {
-            buffer: vec![Cell::new([pixel; TILE_SIZE]); num_tiles],
+    /// If `tile_dim` isn't a power of two.
+    pub fn new(res: Vec2<usize>, tile_dim: usize, pixel: Pixel) -> Self {
+        assert!(tile_dim.is_power_of_two());
+        let tile_res = Vec2 {
+            x: (res.x + tile_dim - 1) / tile_dim,
+            y: (res.y + tile_dim - 1) / tile_dim,
+        };
+
+        let mut film = Film {
+            buffer: Vec::with_capacity(tile_res.x * tile_res.y),
+            res,
+            tile_dim,
             tile_res,
             next_tile_index: AtomicUsize::new(0),
+            num_tiles_complete: AtomicUsize::new(0),
+            pass: 0,
+            tile_order: (0..(tile_res.x * tile_res.y)).collect(),
+            tile_costs: (0..(tile_res.x * tile_res.y))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            second_half: None,
+        };
+        for index in 0..(tile_res.x * tile_res.y) {
+            let (_, size) = film.tile_bounds(index);
+            film.buffer.push(Mutex::new(vec![pixel; size.x * size.y]));
         }
+        film
     }
 
-    pub fn new_zero(tile_res: Vec2<usize>) -> Self {
-        let num_tiles = tile_res.x * tile_res.y;
-        //assert_ne!(num_tiles, 0);
-        Film {
-            buffer: vec![Cell::new([Pixel::black(); TILE_SIZE]); num_tiles],
-            tile_res,
-            next_tile_index: AtomicUsize::new(0),
+    pub fn new_zero(res: Vec2<usize>, tile_dim: usize) -> Self {
+        Self::new(res, tile_dim, Pixel::black())
+    }
+
+    /// Also keeps every other sample of every pixel separately, so that the image can be split into two
+    /// halves with independent samples (see `to_image_buffer_split`). This doubles the memory of the
+    /// film.
+    pub fn with_split(mut self) -> Self {
+        self.second_half = Some(
+            self.buffer
+                .iter_mut()
+                .map(|tile| Mutex::new(vec![Pixel::black(); tile.get_mut().unwrap().len()]))
+                .collect(),
+        );
+        self
+    }
+
+    /// The resolution, in pixels, of the film.
+    pub fn res(&self) -> Vec2<usize> {
+        self.res
+    }
+
+    pub fn tile_dim(&self) -> usize {
+        self.tile_dim
+    }
+
+    /// Returns the position of the top left most pixel of the tile and its size, clamped to the film.
+    fn tile_bounds(&self, index: usize) -> (Vec2<usize>, Vec2<usize>) {
+        let tile_pos = index_to_pos(index as u64, self.tile_res);
+        let pos = Vec2 {
+            x: tile_pos.x as usize,
+            y: tile_pos.y as usize,
         }
+        .scale(self.tile_dim);
+        let size = Vec2 {
+            x: self.tile_dim.min(self.res.x - pos.x),
+            y: self.tile_dim.min(self.res.y - pos.y),
+        };
+        (pos, size)
     }
 
-    /// Sets every pixel in the Film struct to zero.
+    /// Sets every pixel in the Film struct to zero and starts handing out tiles from the beginning.
     pub fn reset(&mut self) {
-        for tile in self.buffer.iter_mut() {
-            for pixel in tile.get().iter_mut() {
+        let second_half = self.second_half.iter_mut().flatten();
+        for tile in self.buffer.iter_mut().chain(second_half) {
+            for pixel in tile.get_mut().unwrap().iter_mut() {
                 *pixel = Pixel::black();
             }
         }
+        self.next_tile_index = AtomicUsize::new(0);
+        self.num_tiles_complete = AtomicUsize::new(0);
+        self.pass = 0;
+        // The scene usually changes little between restarts, so the costs are still a good guess:
+        self.order_by_cost();
+    }
+
+    /// Starts handing out every tile again (without clearing them), so that another pass can be
+    /// accumulated into the film. Tiles handed out in the new pass get different seeds.
+    pub fn next_pass(&mut self) {
+        self.next_tile_index = AtomicUsize::new(0);
+        self.num_tiles_complete = AtomicUsize::new(0);
+        self.pass += 1;
+        self.order_by_cost();
+    }
+
+    /// How long every tile took to render the last time it was rendered, in nanoseconds (0 if it
+    /// wasn't rendered yet). Indexed by the index of the tile.
+    pub fn tile_costs(&self) -> Vec<u64> {
+        self.tile_costs
+            .iter()
+            .map(|cost| cost.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Uses the costs of the tiles of another render (see `tile_costs`) to decide the order the tiles
+    /// are handed out in, like the next frame of an animation. Costs of a film with a different number
+    /// of tiles are ignored.
+    pub fn set_tile_costs(&mut self, costs: &[u64]) {
+        if costs.len() != self.tile_costs.len() {
+            return;
+        }
+        for (cost, &new_cost) in self.tile_costs.iter_mut().zip(costs.iter()) {
+            *cost.get_mut() = new_cost;
+        }
+        self.order_by_cost();
+    }
+
+    /// Hands out the most expensive tiles first (tiles that weren't rendered yet come after the ones
+    /// that were, in the order of their index). Must only be called before the tiles are handed out.
+    fn order_by_cost(&mut self) {
+        let costs = self.tile_costs();
+        self.tile_order
+            .sort_by(|&a, &b| costs[b].cmp(&costs[a]).then(a.cmp(&b)));
+    }
+
+    /// The current pass (starting at 0).
+    pub fn pass(&self) -> usize {
+        self.pass
     }
 
     // A thread safe function that returns a tile for a single thread to work with.
@@ -151,23 +510,44 @@ impl Film {
             }
         }
 
-        let pos_u32 = index_to_pos(old_tile as u64, self.tile_res);
+        // The seed only depends on the index of the tile, so the order doesn't change the image:
+        let index = self.tile_order[old_tile];
+        let (pos, size) = self.tile_bounds(index);
         return Some(FilmTile {
-            data: self.buffer[old_tile].get(),
-            pos: Vec2 {
-                x: pos_u32.x as usize,
-                y: pos_u32.y as usize,
-            }
-            .scale(TILE_DIM),
-            // We aren't doing anything fancy yet, so each tile gets hit once.
-            seed: old_tile as u64,
-            index: old_tile,
+            data: self.buffer[index].lock().unwrap().clone(),
+            pos,
+            size,
+            // Each tile gets hit once per pass.
+            seed: (self.pass * self.buffer.len() + index) as u64,
+            index,
+            start: Instant::now(),
+            second_half: self
+                .second_half
+                .as_ref()
+                .map(|second_half| second_half[index].lock().unwrap().clone()),
         });
     }
 
     /// Updates the buffer with the current tile with a given film tile.
     pub fn set_tile(&self, tile: FilmTile) {
-        self.buffer[tile.index].set(tile.data);
+        // At least 1ns, so that the tile counts as rendered:
+        let cost = (tile.start.elapsed().as_nanos() as u64).max(1);
+        self.tile_costs[tile.index].store(cost, Ordering::Relaxed);
+        *self.buffer[tile.index].lock().unwrap() = tile.data;
+        if let (Some(second_half), Some(data)) = (&self.second_half, tile.second_half) {
+            *second_half[tile.index].lock().unwrap() = data;
+        }
+        self.num_tiles_complete.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The total number of tiles in the film.
+    pub fn num_tiles(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// The number of tiles that were rendered and handed back with `set_tile`.
+    pub fn num_tiles_complete(&self) -> usize {
+        self.num_tiles_complete.load(Ordering::Relaxed)
     }
 
     /// Returns the current progress in terms of a percentage.
@@ -178,34 +558,149 @@ impl Film {
     }
 
     /// Given a function that converts XYZColor to an rgb value (in the form of an ImageBuffer),
-    /// returns an ImageBuffer.
-    pub fn to_image_buffer(&self, transf: fn(Color) -> ImagePixel) -> ImageBuffer {
-        let res = self.tile_res.scale(TILE_DIM);
+    /// returns an ImageBuffer. The alpha of every pixel comes from the film.
+    pub fn to_image_buffer(&self, transf: impl Fn(Color) -> ImagePixel) -> ImageBuffer {
+        self.map_pixels(|pixel| ImagePixel {
+            a: pixel.final_alpha(),
+            ..transf(pixel.final_color())
+        })
+    }
+
+    /// Same as `to_image_buffer`, but for the two halves of the samples of a split film (see
+    /// `with_split`), along with the variance of the color estimated from their difference. Returns
+    /// `None` if the film isn't split.
+    pub fn to_image_buffer_split(
+        &self,
+        transf: impl Fn(Color) -> ImagePixel,
+    ) -> Option<SplitImageBuffers> {
+        let second_half: Vec<Vec<Pixel>> = self
+            .second_half
+            .as_ref()?
+            .iter()
+            .map(|tile| tile.lock().unwrap().clone())
+            .collect();
+        let halves = |tile: usize, i: usize, pixel: &Pixel| {
+            let b = second_half[tile][i];
+            (pixel.without(b), b)
+        };
+        let to_image_pixel = |pixel: Pixel| ImagePixel {
+            a: pixel.final_alpha(),
+            ..transf(pixel.final_color())
+        };
+
+        Some(SplitImageBuffers {
+            a: self.map_tile_pixels(|tile, i, pixel| to_image_pixel(halves(tile, i, pixel).0)),
+            b: self.map_tile_pixels(|tile, i, pixel| to_image_pixel(halves(tile, i, pixel).1)),
+            // Both halves average (about) half of the samples, so the variance of their difference is
+            // twice the variance of a half, which is twice the variance of the average of all of them:
+            variance: self.map_tile_pixels(|tile, i, pixel| {
+                let (a, b) = halves(tile, i, pixel);
+                let d = a.final_color() - b.final_color();
+                let variance = |d: Scalar| 0.25 * d.to_f64() * d.to_f64();
+                ImagePixel::from_rgb(variance(d.r), variance(d.g), variance(d.b))
+            }),
+        })
+    }
+
+    /// Same as `to_image_buffer`, but only for a single component of the color (see `LightPath`).
+    pub fn light_path_to_image_buffer(
+        &self,
+        kind: LightPath,
+        transf: impl Fn(Color) -> ImagePixel,
+    ) -> ImageBuffer {
+        self.map_pixels(|pixel| ImagePixel {
+            a: pixel.final_alpha(),
+            ..transf(pixel.final_light_path(kind))
+        })
+    }
+
+    /// Returns the average auxiliary value of every pixel as a heatmap, where the largest value in the
+    /// film is red and 0 is black.
+    pub fn aov_to_image_buffer(&self, kind: AovKind) -> ImageBuffer {
+        let mut max_value = 0.0f64;
+        for tile in self.buffer.iter() {
+            for pixel in tile.lock().unwrap().iter() {
+                max_value = max_value.max(pixel.final_aux(kind));
+            }
+        }
+        let scale = if max_value > 0.0 {
+            1.0 / max_value
+        } else {
+            0.0
+        };
+        self.map_pixels(|pixel| heatmap(pixel.final_aux(kind) * scale))
+    }
+
+    /// Returns the average auxiliary value of every pixel (in every channel), for formats that can store
+    /// the values themselves (see `exr::write_exr`).
+    pub fn aov_values_to_image_buffer(&self, kind: AovKind) -> ImageBuffer {
+        self.map_pixels(|pixel| {
+            let value = pixel.final_aux(kind);
+            ImagePixel::from_rgb(value, value, value)
+        })
+    }
+
+    /// The average world space position of the surfaces the camera rays of every pixel hit (see
+    /// `Pixel::final_position`) relative to `origin`, with the fraction of the camera rays that hit a
+    /// surface in alpha. The positions are accumulated in 64 bit floats, but image files store at most
+    /// 32 bits, which are only accurate to about one part in ten million. Subtracting an origin close to
+    /// the scene (like the position of the camera) keeps positions in large scenes accurate.
+    pub fn position_to_image_buffer(&self, origin: Vec3<f64>) -> ImageBuffer {
+        self.map_pixels(|pixel| {
+            let p = pixel.final_position().map_or(Vec3::zero(), |p| p - origin);
+            ImagePixel {
+                r: p.x,
+                g: p.y,
+                b: p.z,
+                a: pixel.final_aux(AovKind::SurfaceHit),
+            }
+        })
+    }
+
+    /// The average camera space depth of the surfaces the camera rays of every pixel hit: the distance
+    /// from `origin` (the position of the camera) along `view_dir` (which has to be normalized), not the
+    /// distance to the camera. Pixels that didn't hit anything are 0 (with alpha like
+    /// `position_to_image_buffer`).
+    pub fn depth_to_image_buffer(&self, origin: Vec3<f64>, view_dir: Vec3<f64>) -> ImageBuffer {
+        // The depth is linear in the position, so the depth of the average position is the average depth:
+        self.map_pixels(|pixel| {
+            let depth = pixel
+                .final_position()
+                .map_or(0.0, |p| (p - origin).dot(view_dir));
+            ImagePixel {
+                r: depth,
+                g: depth,
+                b: depth,
+                a: pixel.final_aux(AovKind::SurfaceHit),
+            }
+        })
+    }
+
+    /// Converts every pixel in the film to an ImagePixel.
+    fn map_pixels(&self, mut f: impl FnMut(&Pixel) -> ImagePixel) -> ImageBuffer {
+        self.map_tile_pixels(|_, _, pixel| f(pixel))
+    }
+
+    /// Same as `map_pixels`, but `f` also gets the index of the tile and of the pixel in the tile.
+    fn map_tile_pixels(
+        &self,
+        mut f: impl FnMut(usize, usize, &Pixel) -> ImagePixel,
+    ) -> ImageBuffer {
+        let res = self.res;
         let mut buffer = vec![ImagePixel::zero(); res.x * res.y];
 
         // This doesn't have to be a particularly fast function, so it isn't.
 
-        for (i, tile) in self.buffer.iter().enumerate() {
-            let tile = tile.get();
-            let tile_pos = index_to_pos(i as u64, self.tile_res);
-            let pixel_corner = Vec2 {
-                x: tile_pos.x as usize,
-                y: tile_pos.y as usize,
-            }
-            .scale(TILE_DIM);
-            let mut pixel_pos = pixel_corner;
+        for (tile_index, tile) in self.buffer.iter().enumerate() {
+            let tile = tile.lock().unwrap();
+            let (pixel_corner, size) = self.tile_bounds(tile_index);
 
             for (i, pixel) in tile.iter().enumerate() {
-                let pixel_index = pixel_pos.y * res.x + pixel_pos.x;
-                let final_color = pixel.final_color();
-                //println!("{:?}", final_color);
-                buffer[pixel_index] = transf(final_color);
-                if (i + 1) % TILE_DIM == 0 {
-                    pixel_pos.y += 1;
-                    pixel_pos.x = pixel_corner.x;
-                } else {
-                    pixel_pos.x += 1;
-                }
+                let pixel_pos = Vec2 {
+                    x: pixel_corner.x + (i % size.x),
+                    y: pixel_corner.y + (i / size.x),
+                };
+                buffer[pixel_pos.y * res.x + pixel_pos.x] = f(tile_index, i, pixel);
             }
         }
 
@@ -213,9 +708,22 @@ impl Film {
     }
 }
 
-// Cell doesn't implement Sync. But, the way each tile is accessed means there shouldn't
-// be any race-conditions for the same Cell.
-unsafe impl Sync for Film {}
+/// Maps a value from 0 to 1 to a color going from black over blue, green and yellow to red.
+fn heatmap(t: f64) -> ImagePixel {
+    const COLORS: [(f64, f64, f64); 5] = [
+        (0.0, 0.0, 0.0),
+        (0.0, 0.0, 1.0),
+        (0.0, 1.0, 0.0),
+        (1.0, 1.0, 0.0),
+        (1.0, 0.0, 0.0),
+    ];
+    let t = t.max(0.0).min(1.0) * ((COLORS.len() - 1) as f64);
+    let i = (t as usize).min(COLORS.len() - 2);
+    let f = t - (i as f64);
+    let (r0, g0, b0) = COLORS[i];
+    let (r1, g1, b1) = COLORS[i + 1];
+    ImagePixel::from_rgb(r0 + (r1 - r0) * f, g0 + (g1 - g0) * f, b0 + (b1 - b0) * f)
+}
 
 //
 // The image buffer is an intermediate type that the pixel buffer converts to so that we can
@@ -227,6 +735,7 @@ pub struct ImagePixel {
     pub r: f64,
     pub g: f64,
     pub b: f64,
+    pub a: f64,
 }
 
 impl ImagePixel {
@@ -235,13 +744,255 @@ impl ImagePixel {
             r: 0.0,
             g: 0.0,
             b: 0.0,
+            a: 0.0,
+        }
+    }
+
+    /// An opaque pixel with the given color.
+    pub fn from_rgb(r: f64, g: f64, b: f64) -> Self {
+        ImagePixel { r, g, b, a: 1.0 }
+    }
+
+    /// An opaque pixel with the given color (see `Scalar`).
+    pub fn from_color(color: Color) -> Self {
+        Self::from_rgb(color.r.to_f64(), color.g.to_f64(), color.b.to_f64())
+    }
+
+    /// The color of the pixel (without the alpha).
+    pub fn to_color(self) -> Color {
+        Color {
+            r: Scalar::from_f64(self.r),
+            g: Scalar::from_f64(self.g),
+            b: Scalar::from_f64(self.b),
         }
     }
 }
 
-#[derive(Debug)]
+/// How much two images differ (see `ImageBuffer::diff`), over every channel of every pixel.
+#[derive(Clone, Copy, Debug)]
+pub struct ImageDiff {
+    pub mean_abs_error: f64,
+    /// The largest difference of any channel.
+    pub max_error: f64,
+}
+
+#[derive(Clone)]
 pub struct ImageBuffer {
     /// This is in row-major format
     buffer: Vec<ImagePixel>,
     res: Vec2<usize>,
 }
+
+// Printing every pixel isn't useful:
+impl fmt::Debug for ImageBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ImageBuffer")
+            .field("res", &self.res)
+            .finish()
+    }
+}
+
+impl ImageBuffer {
+    pub fn res(&self) -> Vec2<usize> {
+        self.res
+    }
+
+    /// # Panics
+    /// If the pixel isn't inside of the image.
+    pub fn get_pixel(&self, pos: Vec2<usize>) -> ImagePixel {
+        assert!(pos.x < self.res.x && pos.y < self.res.y);
+        self.buffer[pos.y * self.res.x + pos.x]
+    }
+
+    /// Returns the image with `f` applied to every pixel.
+    pub fn map(&self, f: impl Fn(ImagePixel) -> ImagePixel) -> ImageBuffer {
+        ImageBuffer {
+            buffer: self.buffer.iter().map(|&pixel| f(pixel)).collect(),
+            res: self.res,
+        }
+    }
+
+    /// Returns the part of the image from `pmin` (inclusive) to `pmax` (exclusive).
+    ///
+    /// # Panics
+    /// If the region isn't inside of the image.
+    pub fn crop(&self, pmin: Vec2<usize>, pmax: Vec2<usize>) -> ImageBuffer {
+        assert!(pmin.x < pmax.x && pmin.y < pmax.y);
+        assert!(pmax.x <= self.res.x && pmax.y <= self.res.y);
+
+        let res = Vec2 {
+            x: pmax.x - pmin.x,
+            y: pmax.y - pmin.y,
+        };
+        let mut buffer = Vec::with_capacity(res.x * res.y);
+        for y in pmin.y..pmax.y {
+            let row = y * self.res.x;
+            buffer.extend_from_slice(&self.buffer[(row + pmin.x)..(row + pmax.x)]);
+        }
+        ImageBuffer { buffer, res }
+    }
+
+    /// Compares the image with another one of the same resolution (`None` if the resolutions differ).
+    pub fn diff(&self, other: &ImageBuffer) -> Option<ImageDiff> {
+        if self.res != other.res {
+            return None;
+        }
+        let mut sum = 0.0;
+        let mut max_error: f64 = 0.0;
+        for (a, b) in self.buffer.iter().zip(other.buffer.iter()) {
+            for &error in [a.r - b.r, a.g - b.g, a.b - b.b, a.a - b.a].iter() {
+                sum += error.abs();
+                max_error = max_error.max(error.abs());
+            }
+        }
+        let num_values = (4 * self.buffer.len()).max(1);
+        Some(ImageDiff {
+            mean_abs_error: sum / (num_values as f64),
+            max_error,
+        })
+    }
+
+    /// Draws a line (one pixel wide) from `p0` to `p1` (in raster space, so the pixel (x, y) covers
+    /// [x, x + 1) by [y, y + 1)). The parts of the line outside of the image are skipped.
+    pub fn draw_line(&mut self, p0: Vec2<f64>, p1: Vec2<f64>, color: ImagePixel) {
+        if self.res.x == 0 || self.res.y == 0 {
+            return;
+        }
+        // Clip the line to the image (Liang-Barsky), so that far away endpoints don't take forever:
+        let d = p1 - p0;
+        let (mut t0, mut t1) = (0.0, 1.0);
+        for &(p, q) in [
+            (-d.x, p0.x),
+            (d.x, (self.res.x as f64) - p0.x),
+            (-d.y, p0.y),
+            (d.y, (self.res.y as f64) - p0.y),
+        ]
+        .iter()
+        {
+            if p == 0.0 {
+                if q < 0.0 {
+                    return;
+                }
+                continue;
+            }
+            let t = q / p;
+            if p < 0.0 {
+                t0 = f64::max(t0, t);
+            } else {
+                t1 = f64::min(t1, t);
+            }
+        }
+        if !(t0 <= t1) {
+            return;
+        }
+
+        let start = p0 + d.scale(t0);
+        let d = d.scale(t1 - t0);
+        let num_steps = d.x.abs().max(d.y.abs()).ceil().max(1.0) as usize;
+        for i in 0..=num_steps {
+            let p = start + d.scale((i as f64) / (num_steps as f64));
+            // Points on the right or bottom edge of the image belong to the last pixel:
+            let x = (p.x.max(0.0).floor() as usize).min(self.res.x - 1);
+            let y = (p.y.max(0.0).floor() as usize).min(self.res.y - 1);
+            self.buffer[y * self.res.x + x] = color;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg32;
+
+    // Every channel of every sample is uniform over {0, 1/8, ..., 15/8}, so all of the sums are exact:
+    const SAMPLE_VARIANCE: f64 = (16.0 * 16.0 - 1.0) / 12.0 / 64.0;
+
+    /// Renders `num_samples` random samples into every pixel of the film. The samples only depend on the
+    /// pixel, so every film gets the same ones.
+    fn fill(film: &Film, num_samples: u32) {
+        while let Some(mut tile) = film.get_tile() {
+            for i in 0..tile.data.len() {
+                let mut rng = Pcg32::seed_from_u64((tile.index * 4096 + i) as u64);
+                for _ in 0..num_samples {
+                    let mut channel = || (rng.gen_range(0, 16) as Scalar) / 8.0;
+                    let color = Color {
+                        r: channel(),
+                        g: channel(),
+                        b: channel(),
+                    };
+                    tile.add_sample(i, Pixel::black().add_sample(color));
+                }
+            }
+            film.set_tile(tile);
+        }
+    }
+
+    fn rgb(color: Color) -> ImagePixel {
+        ImagePixel::from_color(color)
+    }
+
+    fn to_rgb(pixel: ImagePixel) -> [f64; 3] {
+        [pixel.r, pixel.g, pixel.b]
+    }
+
+    #[test]
+    fn halves_average_to_the_whole_image() {
+        let res = Vec2 { x: 13, y: 7 };
+        let film = Film::new_zero(res, 4);
+        let split_film = Film::new_zero(res, 4).with_split();
+        assert!(film.to_image_buffer_split(rgb).is_none());
+        fill(&film, 16);
+        fill(&split_film, 16);
+
+        let image = film.to_image_buffer(rgb);
+        assert_eq!(
+            split_film
+                .to_image_buffer(rgb)
+                .diff(&image)
+                .unwrap()
+                .max_error,
+            0.0
+        );
+        let split = split_film.to_image_buffer_split(rgb).unwrap();
+        for y in 0..res.y {
+            for x in 0..res.x {
+                let pos = Vec2 { x, y };
+                let (a, b) = (split.a.get_pixel(pos), split.b.get_pixel(pos));
+                let whole = image.get_pixel(pos);
+                for ((a, b), whole) in to_rgb(a).iter().zip(to_rgb(b).iter()).zip(&to_rgb(whole)) {
+                    assert_eq!((a + b) / 2.0, *whole);
+                }
+                assert_eq!((a.a, b.a), (1.0, 1.0));
+            }
+        }
+    }
+
+    #[test]
+    fn variance_falls_with_the_sample_count() {
+        let res = Vec2 { x: 32, y: 32 };
+        for &num_samples in [16, 64, 256].iter() {
+            let film = Film::new_zero(res, 16).with_split();
+            fill(&film, num_samples);
+            let variance = film.to_image_buffer_split(rgb).unwrap().variance;
+            let mut mean = 0.0;
+            for y in 0..res.y {
+                for x in 0..res.x {
+                    mean += to_rgb(variance.get_pixel(Vec2 { x, y }))
+                        .iter()
+                        .sum::<f64>();
+                }
+            }
+            mean /= (3 * res.x * res.y) as f64;
+            // The variance of the average of the samples of a pixel:
+            let expected = SAMPLE_VARIANCE / (num_samples as f64);
+            assert!(
+                (mean / expected - 1.0).abs() < 0.1,
+                "{} samples: {} isn't {}",
+                num_samples,
+                mean,
+                expected
+            );
+        }
+    }
+}
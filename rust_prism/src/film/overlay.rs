@@ -0,0 +1,215 @@
+//! Wireframes drawn over a rendered image to debug the acceleration structures and the tiles visually.
+
+use crate::camera::perspective::PerspectiveCamera;
+use crate::film::{ImageBuffer, ImagePixel};
+use crate::scene::Scene;
+use pmath::bbox::BBox3;
+use pmath::vector::Vec2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugOverlay {
+    /// The bounding boxes of the nodes of the scene's BVH at the given depth (the root is at depth 0).
+    /// Only the BVH over the top level primitives is drawn, not the BVHs of the meshes themselves.
+    BvhLevel(u32),
+    /// The borders of the tiles the image is rendered in.
+    Tiles,
+}
+
+impl DebugOverlay {
+    const BVH_COLOR: ImagePixel = ImagePixel {
+        r: 0.0,
+        g: 1.0,
+        b: 0.0,
+        a: 1.0,
+    };
+    const TILE_COLOR: ImagePixel = ImagePixel {
+        r: 1.0,
+        g: 0.0,
+        b: 1.0,
+        a: 1.0,
+    };
+
+    /// Draws the overlay into the image, which has to be the image rendered with the camera (and with
+    /// tiles of size `tile_size`), downscaled by `supersample` (see `RenderParam::supersample`).
+    pub fn draw(
+        self,
+        image: &mut ImageBuffer,
+        scene: &Scene,
+        camera: &PerspectiveCamera,
+        tile_size: usize,
+        supersample: usize,
+    ) {
+        let supersample = supersample.max(1);
+        match self {
+            DebugOverlay::BvhLevel(level) => {
+                for (_, bbox) in scene.bvh_node_bounds().filter(|&(depth, _)| depth == level) {
+                    draw_bbox(image, camera, bbox, supersample, Self::BVH_COLOR);
+                }
+            }
+            DebugOverlay::Tiles => {
+                // The tiles are in the pixels of the film:
+                let film_res = Vec2 {
+                    x: image.res().x * supersample,
+                    y: image.res().y * supersample,
+                };
+                let line = |x0: usize, y0: usize, x1: usize, y1: usize| {
+                    // Through the center of the pixels on the border:
+                    let point = |x: usize, y: usize| Vec2 {
+                        x: ((x / supersample) as f64) + 0.5,
+                        y: ((y / supersample) as f64) + 0.5,
+                    };
+                    (point(x0, y0), point(x1, y1))
+                };
+                let tile_size = tile_size.max(1);
+                for x in (0..film_res.x).step_by(tile_size) {
+                    let (p0, p1) = line(x, 0, x, film_res.y.saturating_sub(1));
+                    image.draw_line(p0, p1, Self::TILE_COLOR);
+                }
+                for y in (0..film_res.y).step_by(tile_size) {
+                    let (p0, p1) = line(0, y, film_res.x.saturating_sub(1), y);
+                    image.draw_line(p0, p1, Self::TILE_COLOR);
+                }
+            }
+        }
+    }
+}
+
+/// Draws the 12 edges of the bounding box (in world space) as seen through the camera (which renders at
+/// `supersample` times the resolution of the image).
+fn draw_bbox(
+    image: &mut ImageBuffer,
+    camera: &PerspectiveCamera,
+    bbox: BBox3<f64>,
+    supersample: usize,
+    color: ImagePixel,
+) {
+    let scale = 1.0 / (supersample as f64);
+    // Empty boxes (of nodes without any primitives) have nothing to draw:
+    if bbox.pmin.x > bbox.pmax.x || bbox.pmin.y > bbox.pmax.y || bbox.pmin.z > bbox.pmax.z {
+        return;
+    }
+    // Corners whose indices differ in a single bit (a single axis) share an edge:
+    for i in 0..8 {
+        for &axis_bit in [1, 2, 4].iter() {
+            if i & axis_bit == 0 {
+                let (p0, p1) = (bbox.corner(i), bbox.corner(i | axis_bit));
+                if let Some((p0, p1)) = camera.project_segment(p0, p1) {
+                    image.draw_line(p0.scale(scale), p1.scale(scale), color);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::sphere::Sphere;
+    use crate::scene::{SceneGeom, ScenePrim};
+    use crate::shading::material::{MaterialPool, DEFAULT_MATERIAL_ID};
+    use crate::transform::Transf;
+    use pmath::vector::Vec3;
+    use std::sync::Arc;
+
+    fn black_image(res: Vec2<usize>) -> ImageBuffer {
+        ImageBuffer {
+            buffer: vec![ImagePixel::from_rgb(0.0, 0.0, 0.0); res.x * res.y],
+            res,
+        }
+    }
+
+    /// The image as rows of `#` (for pixels that were drawn) and `.` (for the rest).
+    fn to_mask(image: &ImageBuffer) -> String {
+        let res = image.res();
+        (0..res.y)
+            .map(|y| {
+                (0..res.x)
+                    .map(|x| {
+                        let p = image.get_pixel(Vec2 { x, y });
+                        if p.r + p.g + p.b > 0.0 {
+                            '#'
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn bvh_bounds_match_the_golden_image() {
+        let res = Vec2 { x: 24, y: 16 };
+        // The camera looks down the z-axis, and the box of the sphere goes from z = 2 to z = 3:
+        let camera = PerspectiveCamera::new(
+            Transf::new_identity(),
+            90.0,
+            0.0,
+            1.0,
+            PerspectiveCamera::default_screen_window(res),
+            res,
+        );
+        let materials = MaterialPool::new();
+        let sphere: Arc<dyn ScenePrim> = Arc::new(SceneGeom::new_material(
+            Arc::new(Sphere::new(0.5)),
+            materials.get_shared_material(DEFAULT_MATERIAL_ID),
+            Transf::new_translate(Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 2.5,
+            }),
+        ));
+        let scene = Scene::new(&[sphere], Vec::new());
+
+        let mut image = black_image(res);
+        DebugOverlay::BvhLevel(0).draw(&mut image, &scene, &camera, 8, 1);
+        // The front of the box projects to [10, 14] by [6, 10] and the back to [10.67, 13.33] by
+        // [6.67, 9.33]:
+        let golden = [
+            "........................",
+            "........................",
+            "........................",
+            "........................",
+            "........................",
+            "........................",
+            "..........#####.........",
+            "..........#..##.........",
+            "..........#..##.........",
+            "..........#####.........",
+            "..........#####.........",
+            "........................",
+            "........................",
+            "........................",
+            "........................",
+            "........................",
+        ];
+        assert_eq!(to_mask(&image), golden.join("\n"));
+
+        // Segments behind the camera aren't drawn, and the ones through the camera's plane are clipped:
+        let point = |x, z| Vec3 { x, y: 0.0, z };
+        assert!(camera
+            .project_segment(point(0.0, -1.0), point(1.0, -2.0))
+            .is_none());
+        let (p0, p1) = camera
+            .project_segment(point(1.0, -1.0), point(1.0, 2.0))
+            .unwrap();
+        assert!(p0.x.is_finite() && p0.x > p1.x && (p1.x - 16.0).abs() < 1e-9);
+        assert!((p0.y - 8.0).abs() < 1e-9 && (p1.y - 8.0).abs() < 1e-9);
+
+        // Tiles of 8 pixels on a film twice the size of the image:
+        let mut image = black_image(Vec2 { x: 12, y: 8 });
+        DebugOverlay::Tiles.draw(&mut image, &scene, &camera, 8, 2);
+        let golden = [
+            "############",
+            "#...#...#...",
+            "#...#...#...",
+            "#...#...#...",
+            "############",
+            "#...#...#...",
+            "#...#...#...",
+            "#...#...#...",
+        ];
+        assert_eq!(to_mask(&image), golden.join("\n"));
+    }
+}
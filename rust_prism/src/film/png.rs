@@ -1,8 +1,10 @@
+use crate::error::{PrismError, PrismResult};
+use crate::film::metadata::ImageMetadata;
 use crate::film::{ImageBuffer, ImagePixel};
 use lodepng::{self, ColorType};
-use simple_error::{bail, SimpleResult};
-use std::fs::File;
-use std::io::prelude::*;
+use pmath::vector::Vec2;
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
 
 /// How many bits to use to encode each channel of the image.
 #[derive(Clone, Copy, Debug)]
@@ -11,56 +13,112 @@ pub enum BitDepth {
     SIXTEEN,
 }
 
+/// Which channels to write to the png file.
+#[derive(Clone, Copy, Debug)]
+pub enum Channels {
+    RGB,
+    RGBA,
+}
+
 /// Given an image buffer, converts it to a png file and writes it to the specified path.
-pub fn write_png(image: &ImageBuffer, path: &str, bit_depth: BitDepth) -> SimpleResult<()> {
-    let png_buffer = match bit_depth {
+pub fn write_png(
+    image: &ImageBuffer,
+    path: &str,
+    bit_depth: BitDepth,
+    channels: Channels,
+) -> PrismResult<()> {
+    write_png_with_metadata(image, path, bit_depth, channels, &ImageMetadata::new())
+}
+
+/// Same as `write_png`, but also stores the metadata in the file (as `tEXt` chunks).
+pub fn write_png_with_metadata(
+    image: &ImageBuffer,
+    path: &str,
+    bit_depth: BitDepth,
+    channels: Channels,
+    metadata: &ImageMetadata,
+) -> PrismResult<()> {
+    // Encoding only fails if something is wrong with the image, which is reported as a write error:
+    let encode_error =
+        |err: lodepng::Error| PrismError::io(path, io::Error::new(io::ErrorKind::Other, err));
+    let color_type = match channels {
+        Channels::RGB => ColorType::RGB,
+        Channels::RGBA => ColorType::RGBA,
+    };
+    let mut png_buffer = match bit_depth {
         BitDepth::EIGHT => {
-            let mut buffer = Vec::with_capacity(image.buffer.len());
+            let mut buffer = Vec::with_capacity(image.buffer.len() * 4);
             for &image_pixel in image.buffer.iter() {
-                buffer.push(from_image_pixel_eight(image_pixel));
-            }
-            match lodepng::encode_memory(&buffer, image.res.x, image.res.y, ColorType::RGB, 8) {
-                Ok(result) => result,
-                Err(err) => bail!("Error creating png file: {}", err),
+                let pixel = from_image_pixel_eight(image_pixel);
+                buffer.extend_from_slice(match channels {
+                    Channels::RGB => &pixel[..3],
+                    Channels::RGBA => &pixel[..],
+                });
             }
+            lodepng::encode_memory(&buffer, image.res.x, image.res.y, color_type, 8)
+                .map_err(encode_error)?
         }
         BitDepth::SIXTEEN => {
-            let mut buffer = Vec::with_capacity(image.buffer.len());
+            let mut buffer = Vec::with_capacity(image.buffer.len() * 4);
             for &image_pixel in image.buffer.iter() {
-                buffer.push(from_image_pixel_sixteen(image_pixel));
-            }
-            match lodepng::encode_memory(&buffer, image.res.x, image.res.y, ColorType::RGB, 16) {
-                Ok(result) => result,
-                Err(err) => bail!("Error creating png data: {}", err),
+                let pixel = from_image_pixel_sixteen(image_pixel);
+                buffer.extend_from_slice(match channels {
+                    Channels::RGB => &pixel[..3],
+                    Channels::RGBA => &pixel[..],
+                });
             }
+            lodepng::encode_memory(&buffer, image.res.x, image.res.y, color_type, 16)
+                .map_err(encode_error)?
         }
     };
 
-    let mut file = match File::create(path) {
-        Ok(file) => file,
-        Err(err) => bail!("Error creating png file: {}", err),
-    };
+    metadata.insert_into_png(&mut png_buffer);
 
-    if let Err(err) = file.write_all(&png_buffer) {
-        bail!("Error writing png file: {}", err.to_string());
-    }
+    let mut file = File::create(path).map_err(|err| PrismError::io(path, err))?;
+    file.write_all(&png_buffer)
+        .map_err(|err| PrismError::io(path, err))
+}
 
-    Ok(())
+/// Reads a png file (of any format) into an image buffer.
+pub fn read_png(path: &str) -> PrismResult<ImageBuffer> {
+    let bytes = fs::read(path).map_err(|err| PrismError::io(path, err))?;
+    let bitmap =
+        lodepng::decode32(&bytes).map_err(|err| PrismError::parse(path, None, err.to_string()))?;
+    let to_f64 = |v: u8| (v as f64) / 255.0;
+    let buffer = bitmap
+        .buffer
+        .iter()
+        .map(|pixel| ImagePixel {
+            r: to_f64(pixel.r),
+            g: to_f64(pixel.g),
+            b: to_f64(pixel.b),
+            a: to_f64(pixel.a),
+        })
+        .collect();
+    Ok(ImageBuffer {
+        buffer,
+        res: Vec2 {
+            x: bitmap.width,
+            y: bitmap.height,
+        },
+    })
 }
 
-fn from_image_pixel_eight(pixel: ImagePixel) -> [u8; 3] {
+fn from_image_pixel_eight(pixel: ImagePixel) -> [u8; 4] {
     [
         f64_to_bitdepth(pixel.r, 8) as u8,
         f64_to_bitdepth(pixel.g, 8) as u8,
         f64_to_bitdepth(pixel.b, 8) as u8,
+        f64_to_bitdepth(pixel.a, 8) as u8,
     ]
 }
 
-fn from_image_pixel_sixteen(pixel: ImagePixel) -> [u16; 3] {
+fn from_image_pixel_sixteen(pixel: ImagePixel) -> [u16; 4] {
     [
         f64_to_bitdepth(pixel.r, 16) as u16,
         f64_to_bitdepth(pixel.g, 16) as u16,
         f64_to_bitdepth(pixel.b, 16) as u16,
+        f64_to_bitdepth(pixel.a, 16) as u16,
     ]
 }
 
@@ -0,0 +1,220 @@
+//! Resampling of images with a separable filter (for instance, to downscale supersampled renders). The
+//! image is filtered along x and then along y, so the filter has to be separable: `eval(p)` has to be the
+//! product of a function of `p.x` and a function of `p.y`, like all of the filters in `filter`.
+
+use crate::film::{ImageBuffer, ImagePixel};
+use crate::filter::{Filter, LanczosSincFilter};
+use pmath::vector::Vec2;
+
+impl ImageBuffer {
+    /// Resamples the image to the resolution `res` (in linear space, so it should happen before any tone
+    /// mapping). When downscaling, the filter is stretched to the size of the new pixels. Near the edges
+    /// of the image, the weights of the pixels that are left are normalized, so a constant image stays
+    /// exactly the same.
+    ///
+    /// # Panics
+    /// If either image is empty.
+    pub fn resample(&self, res: Vec2<usize>, filter: &impl Filter) -> ImageBuffer {
+        self.resample_weighted(res, filter, false)
+    }
+
+    /// Same as `resample`, but for images that store the variance of every pixel (see
+    /// `Film::to_image_buffer_split`): the result is the variance of the resampled image, assuming that
+    /// the pixels are independent.
+    pub fn resample_variance(&self, res: Vec2<usize>, filter: &impl Filter) -> ImageBuffer {
+        self.resample_weighted(res, filter, true)
+    }
+
+    /// Downscales the image by `factor` along both axes with a Lanczos-3 filter. If the resolution isn't a
+    /// multiple of `factor`, the new pixels cover slightly more than `factor` pixels.
+    pub fn downscale(&self, factor: usize) -> ImageBuffer {
+        self.resample(
+            Self::downscaled_res(self.res, factor),
+            &Self::downscale_filter(),
+        )
+    }
+
+    /// Same as `downscale`, but for images that store variances (see `resample_variance`).
+    pub fn downscale_variance(&self, factor: usize) -> ImageBuffer {
+        self.resample_variance(
+            Self::downscaled_res(self.res, factor),
+            &Self::downscale_filter(),
+        )
+    }
+
+    fn downscaled_res(res: Vec2<usize>, factor: usize) -> Vec2<usize> {
+        let factor = factor.max(1);
+        Vec2 {
+            x: (res.x / factor).max(1),
+            y: (res.y / factor).max(1),
+        }
+    }
+
+    fn downscale_filter() -> LanczosSincFilter {
+        LanczosSincFilter::new(Vec2 { x: 3.0, y: 3.0 }, 3.0)
+    }
+
+    fn resample_weighted(
+        &self,
+        res: Vec2<usize>,
+        filter: &impl Filter,
+        square_weights: bool,
+    ) -> ImageBuffer {
+        assert!(
+            self.res.x > 0 && self.res.y > 0 && res.x > 0 && res.y > 0,
+            "Can't resample an empty image"
+        );
+        let radius = filter.radius();
+        // Every pass writes the image transposed, so the second pass filters the columns:
+        let rows = resample_rows(
+            &self.buffer,
+            self.res,
+            res.x,
+            radius.x,
+            |x| filter.eval(Vec2 { x, y: 0.0 }),
+            square_weights,
+        );
+        let buffer = resample_rows(
+            &rows,
+            Vec2 {
+                x: self.res.y,
+                y: res.x,
+            },
+            res.y,
+            radius.y,
+            |y| filter.eval(Vec2 { x: 0.0, y }),
+            square_weights,
+        );
+        ImageBuffer { buffer, res }
+    }
+}
+
+/// Resamples every row of the image (with a resolution of `res`) to `width` pixels, and returns the result
+/// transposed (with a resolution of `res.y` by `width`).
+fn resample_rows(
+    buffer: &[ImagePixel],
+    res: Vec2<usize>,
+    width: usize,
+    radius: f64,
+    filter: impl Fn(f64) -> f64,
+    square_weights: bool,
+) -> Vec<ImagePixel> {
+    // The weights are the same for every row:
+    let scale = (res.x as f64) / (width as f64);
+    let filter_scale = scale.max(1.0);
+    let weights: Vec<(usize, Vec<f64>)> = (0..width)
+        .map(|x| {
+            let center = ((x as f64) + 0.5) * scale;
+            let start = (center - radius * filter_scale - 0.5).ceil().max(0.0) as usize;
+            let end = ((center + radius * filter_scale - 0.5).floor().max(0.0) as usize)
+                .min(res.x - 1)
+                .max(start);
+            let mut weights: Vec<f64> = (start..=end)
+                .map(|i| filter(((i as f64) + 0.5 - center) / filter_scale))
+                .collect();
+            let sum: f64 = weights.iter().sum();
+            // A filter that is zero for every pixel (which only happens with very narrow filters) falls
+            // back to the closest pixel:
+            if sum.abs() < 1e-12 {
+                let closest = (center as usize).min(res.x - 1);
+                return (closest, vec![1.0]);
+            }
+            for weight in weights.iter_mut() {
+                *weight /= sum;
+                if square_weights {
+                    *weight *= *weight;
+                }
+            }
+            (start, weights)
+        })
+        .collect();
+
+    let mut result = vec![ImagePixel::zero(); res.y * width];
+    for y in 0..res.y {
+        let row = &buffer[(y * res.x)..((y + 1) * res.x)];
+        for (x, (start, weights)) in weights.iter().enumerate() {
+            let mut pixel = ImagePixel::zero();
+            for (p, &weight) in row[*start..].iter().zip(weights.iter()) {
+                pixel.r += weight * p.r;
+                pixel.g += weight * p.g;
+                pixel.b += weight * p.b;
+                pixel.a += weight * p.a;
+            }
+            result[x * res.y + y] = pixel;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{BoxFilter, GaussianFilter};
+
+    fn constant_image(res: Vec2<usize>, pixel: ImagePixel) -> ImageBuffer {
+        ImageBuffer {
+            buffer: vec![pixel; res.x * res.y],
+            res,
+        }
+    }
+
+    fn assert_constant(image: &ImageBuffer, pixel: ImagePixel, eps: f64) {
+        for p in image.buffer.iter() {
+            for &(a, b) in [
+                (p.r, pixel.r),
+                (p.g, pixel.g),
+                (p.b, pixel.b),
+                (p.a, pixel.a),
+            ]
+            .iter()
+            {
+                assert!((a - b).abs() < eps, "{} != {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn constant_images_stay_constant() {
+        let pixel = ImagePixel {
+            r: 0.25,
+            g: 1.5,
+            b: 3.0,
+            a: 0.75,
+        };
+        let image = constant_image(Vec2 { x: 37, y: 23 }, pixel);
+        // Downscaling by factors that do and don't divide the resolution, and upscaling:
+        for &factor in [1, 2, 3, 4, 8].iter() {
+            let downscaled = image.downscale(factor);
+            assert_eq!(
+                downscaled.res(),
+                Vec2 {
+                    x: 37 / factor,
+                    y: 23 / factor
+                }
+            );
+            assert_constant(&downscaled, pixel, 1e-12);
+        }
+        let radius = Vec2 { x: 1.5, y: 1.5 };
+        for &res in [
+            Vec2 { x: 1, y: 1 },
+            Vec2 { x: 74, y: 50 },
+            Vec2 { x: 40, y: 9 },
+        ]
+        .iter()
+        {
+            assert_constant(&image.resample(res, &BoxFilter::new(radius)), pixel, 1e-12);
+            assert_constant(
+                &image.resample(res, &GaussianFilter::from_std_dev(radius, 0.5)),
+                pixel,
+                1e-12,
+            );
+        }
+
+        // The average of independent pixels has a smaller variance (but never a negative one):
+        let variance = constant_image(Vec2 { x: 37, y: 23 }, ImagePixel::from_rgb(1.0, 1.0, 1.0))
+            .downscale_variance(2);
+        for p in variance.buffer.iter() {
+            assert!(p.r > 0.0 && p.r < 0.5, "{}", p.r);
+        }
+    }
+}
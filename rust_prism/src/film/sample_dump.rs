@@ -0,0 +1,194 @@
+//! Records every individual sample of a rectangle of pixels, which is useful when analyzing how well a
+//! sampler converges (the film only stores the sum of the samples). See `RenderParam::sample_dump`.
+//!
+//! The file starts with the magic bytes `PSMP`, a version (u32), and the number of records (u64),
+//! followed by the records. Every record is the pixel (two u32s), the index of the sample in the pixel
+//! (u32), the radiance (three f64s), and the length of the path (f64). Everything is little endian.
+
+use crate::error::{PrismError, PrismResult};
+use crate::film::{AovKind, Pixel};
+use crate::spectrum::Color;
+use crate::Scalar;
+use pmath::numbers::Float;
+use pmath::vector::Vec2;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+const MAGIC: &[u8; 4] = b"PSMP";
+const VERSION: u32 = 1;
+const RECORD_SIZE: usize = 3 * 4 + 4 * 8;
+
+/// A single sample taken by the integrator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleRecord {
+    pub pixel: Vec2<u32>,
+    /// The index of the sample in the pixel.
+    pub sample: u32,
+    pub radiance: Color,
+    /// The total distance the path travelled. This is only recorded if the integrator records the
+    /// `AovKind::PathLength` AOV (otherwise it's 0).
+    pub path_length: f64,
+}
+
+impl SampleRecord {
+    /// Creates a record from the pixel returned by the integrator for a single sample.
+    pub fn from_sample(pixel: Vec2<usize>, sample: u32, sample_pixel: Pixel) -> Self {
+        SampleRecord {
+            pixel: Vec2 {
+                x: pixel.x as u32,
+                y: pixel.y as u32,
+            },
+            sample,
+            radiance: sample_pixel.color,
+            path_length: sample_pixel.final_aux(AovKind::PathLength),
+        }
+    }
+}
+
+/// The statistics of the samples of a single pixel.
+#[derive(Clone, Copy, Debug)]
+pub struct SampleStats {
+    pub count: u32,
+    /// Calculated the same way as `Pixel::final_color`, so that it matches the film exactly.
+    pub mean: Color,
+    /// The unbiased sample variance of every channel (0 if there is only one sample).
+    pub variance: Color,
+}
+
+/// Writes the records to `path` (in the order they are given).
+pub fn write_sample_dump(records: &[SampleRecord], path: &str) -> PrismResult<()> {
+    let io_error = |err: io::Error| PrismError::io(path, err);
+    let file = File::create(path).map_err(io_error)?;
+    let mut writer = BufWriter::new(file);
+
+    let mut header = Vec::with_capacity(16);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header.extend_from_slice(&(records.len() as u64).to_le_bytes());
+    writer.write_all(&header).map_err(io_error)?;
+
+    let mut buffer = [0u8; RECORD_SIZE];
+    for record in records.iter() {
+        let fields_u32 = [record.pixel.x, record.pixel.y, record.sample];
+        let fields_f64 = [
+            record.radiance.r.to_f64(),
+            record.radiance.g.to_f64(),
+            record.radiance.b.to_f64(),
+            record.path_length,
+        ];
+        for (i, field) in fields_u32.iter().enumerate() {
+            buffer[(i * 4)..(i * 4 + 4)].copy_from_slice(&field.to_le_bytes());
+        }
+        for (i, field) in fields_f64.iter().enumerate() {
+            let offset = 12 + i * 8;
+            buffer[offset..(offset + 8)].copy_from_slice(&field.to_le_bytes());
+        }
+        writer.write_all(&buffer).map_err(io_error)?;
+    }
+
+    writer.flush().map_err(io_error)
+}
+
+/// Loads the records written by `write_sample_dump`.
+pub fn load_sample_dump(path: &str) -> PrismResult<Vec<SampleRecord>> {
+    // A file that ends early is corrupt, anything else is an actual IO error:
+    let read_error = |err: io::Error| match err.kind() {
+        io::ErrorKind::UnexpectedEof => {
+            PrismError::parse(path, None, "the sample dump is truncated")
+        }
+        _ => PrismError::io(path, err),
+    };
+    let file = File::open(path).map_err(|err| PrismError::io(path, err))?;
+    let mut reader = BufReader::new(file);
+
+    let mut header = [0u8; 16];
+    reader.read_exact(&mut header).map_err(read_error)?;
+    if &header[..4] != MAGIC {
+        return Err(PrismError::parse(path, None, "not a sample dump"));
+    }
+    let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if version != VERSION {
+        let msg = format!("unsupported sample dump version ({})", version);
+        return Err(PrismError::parse(path, None, msg));
+    }
+    let mut count = [0u8; 8];
+    count.copy_from_slice(&header[8..]);
+    let count = u64::from_le_bytes(count) as usize;
+
+    let read_u32 = |buffer: &[u8], offset: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&buffer[offset..(offset + 4)]);
+        u32::from_le_bytes(bytes)
+    };
+    let read_f64 = |buffer: &[u8], offset: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&buffer[offset..(offset + 8)]);
+        f64::from_le_bytes(bytes)
+    };
+
+    let mut records = Vec::with_capacity(count);
+    let mut buffer = [0u8; RECORD_SIZE];
+    for _ in 0..count {
+        reader.read_exact(&mut buffer).map_err(read_error)?;
+        records.push(SampleRecord {
+            pixel: Vec2 {
+                x: read_u32(&buffer, 0),
+                y: read_u32(&buffer, 4),
+            },
+            sample: read_u32(&buffer, 8),
+            radiance: Color {
+                r: Scalar::from_f64(read_f64(&buffer, 12)),
+                g: Scalar::from_f64(read_f64(&buffer, 20)),
+                b: Scalar::from_f64(read_f64(&buffer, 28)),
+            },
+            path_length: read_f64(&buffer, 36),
+        });
+    }
+    Ok(records)
+}
+
+/// Calculates the mean and variance of every pixel in the records. The samples of a pixel are
+/// accumulated in the order of their sample index (like the film does).
+pub fn sample_stats(records: &[SampleRecord]) -> BTreeMap<(u32, u32), SampleStats> {
+    let mut pixels: BTreeMap<(u32, u32), Vec<(u32, Color)>> = BTreeMap::new();
+    for record in records.iter() {
+        pixels
+            .entry((record.pixel.x, record.pixel.y))
+            .or_insert_with(Vec::new)
+            .push((record.sample, record.radiance));
+    }
+
+    pixels
+        .into_iter()
+        .map(|(pixel, mut samples)| {
+            samples.sort_by_key(|&(sample, _)| sample);
+            let mean = samples
+                .iter()
+                .fold(Pixel::black(), |pixel, &(_, radiance)| {
+                    pixel.add_sample(radiance)
+                })
+                .final_color();
+
+            let n = samples.len();
+            let variance = if n > 1 {
+                let sum = samples.iter().fold(Color::black(), |sum, &(_, radiance)| {
+                    let d = radiance - mean;
+                    sum + d * d
+                });
+                sum.scale(1.0 / ((n - 1) as Scalar))
+            } else {
+                Color::black()
+            };
+
+            (
+                pixel,
+                SampleStats {
+                    count: n as u32,
+                    mean,
+                    variance,
+                },
+            )
+        })
+        .collect()
+}
@@ -5,8 +5,11 @@ use pmath::vector::Vec2;
 use std::hint;
 
 pub trait Filter {
+    /// The value of the filter at an offset `p` from the center of the pixel. It doesn't have to be
+    /// normalized.
     fn eval(&self, p: Vec2<f64>) -> f64;
-    fn get_radius(&self) -> Vec2<f64>;
+    /// The filter is zero outside of [-radius, radius] (in pixels).
+    fn radius(&self) -> Vec2<f64>;
 }
 
 //
@@ -29,7 +32,7 @@ impl Filter for BoxFilter {
         1.
     }
 
-    fn get_radius(&self) -> Vec2<f64> {
+    fn radius(&self) -> Vec2<f64> {
         self.radius
     }
 }
@@ -55,7 +58,7 @@ impl Filter for TriangleFilter {
         e.x * e.y
     }
 
-    fn get_radius(&self) -> Vec2<f64> {
+    fn radius(&self) -> Vec2<f64> {
         self.radius
     }
 }
@@ -72,6 +75,8 @@ pub struct GaussianFilter {
 }
 
 impl GaussianFilter {
+    /// A Gaussian `exp(-alpha * d^2)` that is cut off at `radius`. It's shifted down so that it reaches
+    /// zero at the radius (instead of dropping to zero abruptly).
     pub fn new(radius: Vec2<f64>, alpha: f64) -> Self {
         GaussianFilter {
             radius,
@@ -80,6 +85,12 @@ impl GaussianFilter {
         }
     }
 
+    /// A Gaussian with the given standard deviation (in pixels), cut off at `radius` (about three
+    /// standard deviations keeps most of it).
+    pub fn from_std_dev(radius: Vec2<f64>, std_dev: f64) -> Self {
+        Self::new(radius, 1. / (2. * std_dev * std_dev))
+    }
+
     fn gaussian(&self, d: f64, expv: f64) -> f64 {
         ((-self.alpha * d * d).exp() - expv).max(0.)
     }
@@ -90,28 +101,101 @@ impl Filter for GaussianFilter {
         self.gaussian(p.x, self.exp.x) * self.gaussian(p.y, self.exp.y)
     }
 
-    fn get_radius(&self) -> Vec2<f64> {
+    fn radius(&self) -> Vec2<f64> {
         self.radius
     }
 }
 
-const FILTER_TABLE_WIDTH: usize = 64;
+//
+// Lanczos Filter
+//
+
+#[derive(Clone, Copy)]
+pub struct LanczosSincFilter {
+    radius: Vec2<f64>,
+    tau: f64,
+}
+
+impl LanczosSincFilter {
+    /// A sinc windowed by a wider sinc (`sinc(x) * sinc(x / tau)`), which is cut off at `radius`. The
+    /// usual Lanczos-3 filter has a radius and a `tau` of 3.
+    pub fn new(radius: Vec2<f64>, tau: f64) -> Self {
+        LanczosSincFilter { radius, tau }
+    }
+
+    fn windowed_sinc(&self, x: f64, radius: f64) -> f64 {
+        let x = x.abs();
+        if x > radius {
+            0.
+        } else {
+            sinc(x) * sinc(x / self.tau)
+        }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    let x = std::f64::consts::PI * x;
+    if x.abs() < 1e-5 {
+        1.
+    } else {
+        x.sin() / x
+    }
+}
+
+impl Filter for LanczosSincFilter {
+    fn eval(&self, p: Vec2<f64>) -> f64 {
+        self.windowed_sinc(p.x, self.radius.x) * self.windowed_sinc(p.y, self.radius.y)
+    }
+
+    fn radius(&self) -> Vec2<f64> {
+        self.radius
+    }
+}
+
+/// The largest number of entries along each side of the table of a `PixelFilter`.
+pub const MAX_FILTER_TABLE_WIDTH: usize = 128;
+/// The number of entries along each side of the table of a `PixelFilter` created with `PixelFilter::new`.
+pub const DEFAULT_FILTER_TABLE_WIDTH: usize = 64;
+/// The number of points (along each side) the filter is evaluated at in every entry of the table.
+const FILTER_SUBSAMPLES: usize = 4;
 
 /// The pixel filter uses the technique described here:
 /// "Filter Importance Sampling" - Manfred Ernst, Marc Stamminger, Gunther Greiner
-/// Essentially we use a filter distribution to sample points on a pixel.
+/// Essentially we use a filter distribution to sample points on a pixel. Every sample only contributes
+/// to the pixel it was taken for (with a weight of one), even if its position lands in a neighboring
+/// pixel (or tile), so tiles never have to share samples. As the weights are always one, the filter
+/// doesn't change the brightness of the image, only how sharp it is.
+///
+/// The filter is tabulated over its support: the table stores the integral of the filter over every
+/// entry, and the positions are sampled uniformly within the entry that is picked.
 #[derive(Clone, Copy)]
 pub struct PixelFilter {
     // A CDF Py(x) that allows us to sample the x value:
-    cdf_x: [f64; FILTER_TABLE_WIDTH],
+    cdf_x: [f64; MAX_FILTER_TABLE_WIDTH],
     // A CDF P(v|u) that allows us to sample the y value:
-    cdf_y: [[f64; FILTER_TABLE_WIDTH]; FILTER_TABLE_WIDTH],
+    cdf_y: [[f64; MAX_FILTER_TABLE_WIDTH]; MAX_FILTER_TABLE_WIDTH],
+    // Only the first `width` entries of the tables are used:
+    width: usize,
     // Radius of the filter:
     radius: Vec2<f64>,
 }
 
 impl PixelFilter {
     pub fn new<T: Filter>(filter: &T) -> Self {
+        Self::with_table_width(filter, DEFAULT_FILTER_TABLE_WIDTH)
+    }
+
+    /// Same as `new`, but with `width` entries along each side of the table (at most
+    /// `MAX_FILTER_TABLE_WIDTH`). Wide filters with a lot of detail need larger tables.
+    ///
+    /// # Panics
+    /// If `width` is zero or too large, or if the filter is zero (or negative) everywhere.
+    pub fn with_table_width<T: Filter>(filter: &T, width: usize) -> Self {
+        assert!(
+            width > 0 && width <= MAX_FILTER_TABLE_WIDTH,
+            "The width of the filter table has to be from 1 to {}",
+            MAX_FILTER_TABLE_WIDTH
+        );
         // Filed in as follows:
         // x0: [y0, y1, y2, y3],
         // x1: [y0, y1, y2, y3],
@@ -119,93 +203,217 @@ impl PixelFilter {
         // x3: [y0, y1, y2, y3],
         // So, to index into pdf_xy, use [x][y] where y selects the row and x the entry in the row
 
-        let radius = filter.get_radius();
+        let radius = filter.radius();
+        let entry_size = Vec2 {
+            x: 2. * radius.x / (width as f64),
+            y: 2. * radius.y / (width as f64),
+        };
 
-        let pdf_xy = {
-            // First we should go through and discretize the filter:
-            let mut filter_entries = [[0.; FILTER_TABLE_WIDTH]; FILTER_TABLE_WIDTH];
-            for (x, row) in filter_entries.iter_mut().enumerate() {
-                for (y, entry) in row.iter_mut().enumerate() {
-                    let x = x as f64;
-                    let y = y as f64;
+        let mut pdf_xy = [[0.; MAX_FILTER_TABLE_WIDTH]; MAX_FILTER_TABLE_WIDTH];
+        for (x, row) in pdf_xy.iter_mut().take(width).enumerate() {
+            for (y, entry) in row.iter_mut().take(width).enumerate() {
+                // Average the filter over the entry (the center alone misses a lot of small filters):
+                let mut sum = 0.;
+                for i in 0..(FILTER_SUBSAMPLES * FILTER_SUBSAMPLES) {
+                    let sub = Vec2 {
+                        x: ((i % FILTER_SUBSAMPLES) as f64 + 0.5) / (FILTER_SUBSAMPLES as f64),
+                        y: ((i / FILTER_SUBSAMPLES) as f64 + 0.5) / (FILTER_SUBSAMPLES as f64),
+                    };
                     let p = Vec2 {
-                        x: (x + 0.5) / (FILTER_TABLE_WIDTH as f64) * (2. * radius.x) - radius.x,
-                        y: (y + 0.5) / (FILTER_TABLE_WIDTH as f64) * (2. * radius.y) - radius.y,
+                        x: (x as f64 + sub.x) * entry_size.x - radius.x,
+                        y: (y as f64 + sub.y) * entry_size.y - radius.y,
                     };
-                    *entry = filter.eval(p).abs();
+                    // Negative lobes can't be importance sampled, so only their magnitude is kept:
+                    sum += filter.eval(p).abs();
                 }
+                *entry = sum;
             }
+        }
 
-            // Now we want to normalize the entires by summing all of the table entries up and dividing each
-            // entry by this sum. So, that we have a pdf for a specific x, y value:
-            let filter_sum = filter_entries
-                .iter()
-                .fold(0., |total, row| total + row.iter().sum::<f64>());
-            filter_entries.iter_mut().for_each(|row| {
-                row.iter_mut().for_each(|entry| {
-                    *entry /= filter_sum;
-                });
+        // Normalize the entries so that they form a pdf over the entries of the table:
+        let filter_sum = pdf_xy
+            .iter()
+            .take(width)
+            .fold(0., |total, row| total + row.iter().take(width).sum::<f64>());
+        assert!(filter_sum > 0., "The filter can't be zero everywhere");
+        pdf_xy.iter_mut().take(width).for_each(|row| {
+            row.iter_mut().take(width).for_each(|entry| {
+                *entry /= filter_sum;
             });
-            filter_entries
-        };
+        });
 
-        // Now we want to calculate a marginal pdf for GETTING the x values (it's p_y(x))
-        let mut pdf_x = [0.; FILTER_TABLE_WIDTH];
-        for (x, x_row) in pdf_xy.iter().enumerate() {
-            pdf_x[x] = x_row.iter().sum();
-        }
-        // To sample the pdf_x distribution, we need to form a cdf (it's P_y(x)):
-        let mut cdf_x = [0.; FILTER_TABLE_WIDTH];
-        for (x, &pdf) in pdf_x.iter().enumerate() {
-            cdf_x[x..].iter_mut().for_each(|t| {
-                *t += pdf;
-            });
+        // Now we want to calculate a marginal pdf for GETTING the x values (it's p_y(x)), and a cdf to
+        // sample it (it's P_y(x)):
+        let mut pdf_x = [0.; MAX_FILTER_TABLE_WIDTH];
+        for (x, x_row) in pdf_xy.iter().take(width).enumerate() {
+            pdf_x[x] = x_row.iter().take(width).sum();
         }
-        // To sample the pdf_y value, we need to generate a table that, if given an x
-        // value from pdf_x, we get a y value from pdf_y (so we index into the table
+        let mut cdf_x = [0.; MAX_FILTER_TABLE_WIDTH];
+        build_cdf(&pdf_x[..width], &mut cdf_x[..width]);
+
+        // To sample the y value, we need a cdf for every x value from pdf_x (so we index into the table
         // with the x value):
-        let mut pdf_y = [[0.; FILTER_TABLE_WIDTH]; FILTER_TABLE_WIDTH];
-        for (x, x_row) in pdf_y.iter_mut().enumerate() {
-            for (y, val) in x_row.iter_mut().enumerate() {
-                *val = pdf_xy[x][y] / pdf_x[x];
-            }
-        }
-        // Now we want to turn this pdf into a cdf so we can sample it:
-        let mut cdf_y = [[0.; FILTER_TABLE_WIDTH]; FILTER_TABLE_WIDTH];
-        for (cdf_y_row, pdf_y_row) in cdf_y.iter_mut().zip(pdf_y.iter()) {
-            for (y, &prob) in pdf_y_row.iter().enumerate() {
-                cdf_y_row[y..].iter_mut().for_each(|t| {
-                    *t += prob;
-                });
-            }
+        let mut cdf_y = [[0.; MAX_FILTER_TABLE_WIDTH]; MAX_FILTER_TABLE_WIDTH];
+        for (x, cdf_y_row) in cdf_y.iter_mut().take(width).enumerate() {
+            build_cdf(&pdf_xy[x][..width], &mut cdf_y_row[..width]);
         }
 
         PixelFilter {
             cdf_x,
             cdf_y,
+            width,
             radius,
         }
     }
 
-    pub fn sample_pos(self, r: Vec2<f64>) -> Vec2<f64> {
+    pub fn radius(&self) -> Vec2<f64> {
+        self.radius
+    }
+
+    /// Returns the offset of a sample from the center of the pixel (anywhere within the radius of the
+    /// filter), distributed proportionally to the filter.
+    pub fn sample_pos(&self, r: Vec2<f64>) -> Vec2<f64> {
         // First, we sample the x-value:
-        let x = match self.cdf_x.iter().position(|&cdf| cdf > r.x) {
-            Some(x) => x,
-            _ => FILTER_TABLE_WIDTH / 2,
-        };
+        let (x, offset_x) = sample_cdf(&self.cdf_x[..self.width], r.x);
 
         // Using this x-value, we can now find the y-value:
-        let y = match self.cdf_y[x].iter().position(|&cdf| cdf >= r.y) {
-            Some(y) => y,
-            _ => FILTER_TABLE_WIDTH / 2,
-        };
+        let (y, offset_y) = sample_cdf(&self.cdf_y[x][..self.width], r.y);
 
-        // Convert these indices to x and y coordinates:
-        let x = x as f64;
-        let y = y as f64;
+        // Convert these indices (and where in the entries the sample is) to x and y coordinates:
+        let width = self.width as f64;
+        let x = x as f64 + offset_x;
+        let y = y as f64 + offset_y;
         Vec2 {
-            x: (x + 0.5) / (FILTER_TABLE_WIDTH as f64) * (2. * self.radius.x) - self.radius.x,
-            y: (y + 0.5) / (FILTER_TABLE_WIDTH as f64) * (2. * self.radius.y) - self.radius.y,
+            x: x / width * (2. * self.radius.x) - self.radius.x,
+            y: y / width * (2. * self.radius.y) - self.radius.y,
         }
     }
 }
+
+/// Fills `cdf` with the cdf of `pdf`, which doesn't have to be normalized. The last entry is exactly one
+/// (if the pdf is zero everywhere, the cdf is uniform).
+fn build_cdf(pdf: &[f64], cdf: &mut [f64]) {
+    let mut total = 0.;
+    for (c, &p) in cdf.iter_mut().zip(pdf.iter()) {
+        total += p;
+        *c = total;
+    }
+    let n = cdf.len();
+    for (i, c) in cdf.iter_mut().enumerate() {
+        *c = if total > 0. {
+            *c / total
+        } else {
+            ((i + 1) as f64) / (n as f64)
+        };
+    }
+    cdf[n - 1] = 1.;
+}
+
+/// Picks the entry of the cdf that `u` falls in, and where in the entry (from 0 to 1) it falls, so that
+/// the positions are continuous instead of snapping to the centers of the entries. Values of `u` past
+/// the last entry (which shouldn't happen, but `u` may be rounded up to one) pick the last entry.
+fn sample_cdf(cdf: &[f64], u: f64) -> (usize, f64) {
+    let i = match cdf.iter().position(|&c| c > u) {
+        Some(i) => i,
+        _ => cdf.len() - 1,
+    };
+    let start = if i == 0 { 0. } else { cdf[i - 1] };
+    let width = cdf[i] - start;
+    let offset = if width > 0. {
+        ((u - start) / width).max(0.).min(0.999999)
+    } else {
+        0.5
+    };
+    (i, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_positions_are_continuous_and_centered() {
+        // A box filter spreads the samples uniformly over the pixel:
+        let filter = PixelFilter::new(&BoxFilter::new(Vec2 { x: 0.5, y: 0.5 }));
+        for i in 0..1000 {
+            let u = (i as f64 + 0.5) / 1000.;
+            let p = filter.sample_pos(Vec2 { x: u, y: 1. - u });
+            assert!((p.x - (u - 0.5)).abs() < 1e-5, "{} {:?}", u, p);
+            assert!((p.y - (0.5 - u)).abs() < 1e-5, "{} {:?}", u, p);
+        }
+        // Random numbers that are rounded up to one end up at the edge of the filter (not the center):
+        let edge = filter.sample_pos(Vec2 { x: 1., y: 1. });
+        assert!(edge.x > 0.49 && edge.y > 0.49, "{:?}", edge);
+
+        // Samples of symmetric filters are centered on the pixel (or samples that land in neighboring
+        // pixels or tiles would shift it):
+        let filter = PixelFilter::new(&LanczosSincFilter::new(Vec2 { x: 3., y: 3. }, 3.));
+        const N: usize = 256;
+        let mut mean = Vec2::zero();
+        for i in 0..(N * N) {
+            let u = Vec2 {
+                x: ((i % N) as f64 + 0.5) / (N as f64),
+                y: ((i / N) as f64 + 0.5) / (N as f64),
+            };
+            let p = filter.sample_pos(u);
+            assert!(p.x.abs() <= 3. && p.y.abs() <= 3., "{:?}", p);
+            mean = mean + p.scale(1. / ((N * N) as f64));
+        }
+        assert!(mean.x.abs() < 1e-3 && mean.y.abs() < 1e-3, "{:?}", mean);
+    }
+
+    #[test]
+    fn gaussian_samples_match_the_truncated_gaussian() {
+        let radius = Vec2 { x: 1.5, y: 1. };
+        let gaussian = GaussianFilter::from_std_dev(radius, 0.5);
+        // The integral of the (one dimensional) filter from -radius.x to x:
+        let integral = |x: f64| {
+            const STEPS: usize = 10000;
+            let dx = (x + radius.x) / (STEPS as f64);
+            (0..STEPS)
+                .map(|i| gaussian.gaussian(-radius.x + (i as f64 + 0.5) * dx, gaussian.exp.x) * dx)
+                .sum::<f64>()
+        };
+        let total = integral(radius.x);
+
+        for &width in &[16, DEFAULT_FILTER_TABLE_WIDTH, MAX_FILTER_TABLE_WIDTH] {
+            let filter = PixelFilter::with_table_width(&gaussian, width);
+            const BINS: usize = 16;
+            const N: usize = 512;
+            let mut histogram = [0usize; BINS];
+            for i in 0..(N * N) {
+                let u = Vec2 {
+                    x: ((i % N) as f64 + 0.5) / (N as f64),
+                    y: ((i / N) as f64 + 0.5) / (N as f64),
+                };
+                let p = filter.sample_pos(u);
+                assert!(p.y.abs() <= radius.y, "{:?}", p);
+                let bin = ((p.x + radius.x) / (2. * radius.x) * (BINS as f64)) as usize;
+                histogram[bin.min(BINS - 1)] += 1;
+            }
+            for (bin, &count) in histogram.iter().enumerate() {
+                let start = (bin as f64) / (BINS as f64) * 2. * radius.x - radius.x;
+                let end = ((bin + 1) as f64) / (BINS as f64) * 2. * radius.x - radius.x;
+                let expected = (integral(end) - integral(start)) / total;
+                let prob = (count as f64) / ((N * N) as f64);
+                assert!(
+                    (prob - expected).abs() < 2e-3,
+                    "{} {}: {} {}",
+                    width,
+                    bin,
+                    prob,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn tables_can_not_be_too_wide() {
+        PixelFilter::with_table_width(
+            &BoxFilter::new(Vec2 { x: 0.5, y: 0.5 }),
+            MAX_FILTER_TABLE_WIDTH + 1,
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use crate::geometry::{analytic_interaction, phi, Geometry, SampleableGeometry, SurfaceSample};
+use crate::interaction::Interaction;
+use pmath;
+use pmath::bbox::BBox3;
+use pmath::ray::Ray;
+use pmath::vector::{Vec2, Vec3};
+use std::f64::consts::PI;
+
+/// An open cylinder around the z axis.
+#[derive(Clone, Copy, Debug)]
+pub struct Cylinder {
+    radius: f64,
+    z_min: f64,
+    z_max: f64,
+}
+
+impl Cylinder {
+    pub fn new(radius: f64, z_min: f64, z_max: f64) -> Self {
+        Cylinder {
+            radius,
+            z_min: z_min.min(z_max),
+            z_max: z_min.max(z_max),
+        }
+    }
+
+    fn hit(&self, ray: Ray<f64>) -> Option<f64> {
+        let a = ray.dir.x * ray.dir.x + ray.dir.y * ray.dir.y;
+        if a == 0.0 {
+            return None;
+        }
+        let b = 2.0 * (ray.dir.x * ray.org.x + ray.dir.y * ray.org.y);
+        let c = ray.org.x * ray.org.x + ray.org.y * ray.org.y - self.radius * self.radius;
+        let (t0, t1) = pmath::quadratic(a, b, c)?;
+
+        // Because the cylinder is open we may hit the inside of it:
+        let valid = |t: f64| {
+            let z = ray.org.z + t * ray.dir.z;
+            t > 0.0 && t < ray.t_far && z >= self.z_min && z <= self.z_max
+        };
+        if valid(t0) {
+            Some(t0)
+        } else if valid(t1) {
+            Some(t1)
+        } else {
+            None
+        }
+    }
+}
+
+impl Geometry for Cylinder {
+    fn intersect(&self, ray: Ray<f64>) -> Option<Interaction> {
+        let t = self.hit(ray)?;
+
+        // Reproject the point onto the surface to reduce the error:
+        let p = ray.point_at(t);
+        let dist = (p.x * p.x + p.y * p.y).sqrt();
+        let p = Vec3 {
+            x: p.x * self.radius / dist,
+            y: p.y * self.radius / dist,
+            z: p.z,
+        };
+        let n = Vec3 {
+            x: p.x / self.radius,
+            y: p.y / self.radius,
+            z: 0.0,
+        };
+
+        let uv = Vec2 {
+            x: phi(p) / (2.0 * PI),
+            y: (p.z - self.z_min) / (self.z_max - self.z_min),
+        };
+
+        let dpdu = Vec3 {
+            x: -2.0 * PI * p.y,
+            y: 2.0 * PI * p.x,
+            z: 0.0,
+        };
+        let dpdv = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: self.z_max - self.z_min,
+        };
+
+        Some(analytic_interaction(
+            ray,
+            t,
+            p,
+            Vec3 {
+                x: p.x,
+                y: p.y,
+                z: 0.0,
+            }
+            .abs()
+            .scale(pmath::gamma_f64(3)),
+            n,
+            uv,
+            dpdu,
+            dpdv,
+            dpdu.scale(1.0 / self.radius),
+            Vec3::zero(),
+        ))
+    }
+
+    fn intersect_test(&self, ray: Ray<f64>) -> bool {
+        self.hit(ray).is_some()
+    }
+
+    fn get_surface_area(&self) -> f64 {
+        2.0 * PI * self.radius * (self.z_max - self.z_min)
+    }
+
+    fn calc_surface_area(&mut self) -> f64 {
+        self.get_surface_area()
+    }
+
+    fn get_bbox(&self) -> BBox3<f64> {
+        BBox3::from_pnts(
+            Vec3 {
+                x: -self.radius,
+                y: -self.radius,
+                z: self.z_min,
+            },
+            Vec3 {
+                x: self.radius,
+                y: self.radius,
+                z: self.z_max,
+            },
+        )
+    }
+}
+
+impl SampleableGeometry for Cylinder {
+    fn sample_surface(&self, u: Vec2<f64>) -> SurfaceSample {
+        let z = self.z_min + u.x * (self.z_max - self.z_min);
+        let phi = 2.0 * PI * u.y;
+        let n = Vec3 {
+            x: phi.cos(),
+            y: phi.sin(),
+            z: 0.0,
+        };
+        SurfaceSample {
+            p: Vec3 {
+                x: n.x * self.radius,
+                y: n.y * self.radius,
+                z,
+            },
+            n,
+            pdf: 1.0 / self.get_surface_area(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interaction::IntrType;
+
+    #[test]
+    fn normals_are_unit_length_and_point_outwards() {
+        let cylinder = Cylinder::new(2.0, -1.0, 1.0);
+        for &(y, z) in [(0.0, 0.0), (0.5, -0.3), (1.5, 0.9), (-1.9, 0.1)].iter() {
+            let ray = Ray::new(
+                Vec3 { x: -5.0, y, z },
+                Vec3 {
+                    x: 1.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                0.0,
+            );
+            let int = cylinder.intersect(ray).unwrap();
+            assert!((int.n.length() - 1.0).abs() < 1e-9);
+            assert!(int.n.x < 0.0 && int.n.z == 0.0);
+            match int.intr_type {
+                IntrType::Geom(geom_intr) => {
+                    assert!((geom_intr.sdndu - geom_intr.dpdu.scale(0.5)).length() < 1e-9)
+                }
+                IntrType::Vol(_) => panic!("a cylinder has a surface"),
+            }
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use crate::geometry::{analytic_interaction, phi, Geometry, SampleableGeometry, SurfaceSample};
+use crate::interaction::Interaction;
+use pmath::bbox::BBox3;
+use pmath::ray::Ray;
+use pmath::sampling;
+use pmath::vector::{Vec2, Vec3};
+use std::f64::consts::PI;
+
+/// A disk centered at the origin in the xy plane, facing +z.
+#[derive(Clone, Copy, Debug)]
+pub struct Disk {
+    radius: f64,
+}
+
+impl Disk {
+    pub fn new(radius: f64) -> Self {
+        Disk { radius }
+    }
+
+    fn hit(&self, ray: Ray<f64>) -> Option<f64> {
+        if ray.dir.z == 0.0 {
+            return None;
+        }
+
+        let t = -ray.org.z / ray.dir.z;
+        if t <= 0.0 || t >= ray.t_far {
+            return None;
+        }
+
+        let p = ray.point_at(t);
+        if p.x * p.x + p.y * p.y > self.radius * self.radius {
+            return None;
+        }
+
+        Some(t)
+    }
+}
+
+impl Geometry for Disk {
+    fn intersect(&self, ray: Ray<f64>) -> Option<Interaction> {
+        let t = self.hit(ray)?;
+
+        let p = ray.point_at(t);
+        let p = Vec3 {
+            x: p.x,
+            y: p.y,
+            z: 0.0,
+        };
+        let n = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+
+        // The u coordinate goes around the z axis, v goes from the edge to the center:
+        let dist = (p.x * p.x + p.y * p.y).sqrt();
+        let uv = Vec2 {
+            x: phi(p) / (2.0 * PI),
+            y: 1.0 - dist / self.radius,
+        };
+
+        let dpdu = Vec3 {
+            x: -2.0 * PI * p.y,
+            y: 2.0 * PI * p.x,
+            z: 0.0,
+        };
+        let dpdv = if dist > 0.0 {
+            Vec3 {
+                x: p.x,
+                y: p.y,
+                z: 0.0,
+            }
+            .scale(-self.radius / dist)
+        } else {
+            Vec3::zero()
+        };
+
+        Some(analytic_interaction(
+            ray,
+            t,
+            p,
+            Vec3::zero(),
+            n,
+            uv,
+            dpdu,
+            dpdv,
+            Vec3::zero(),
+            Vec3::zero(),
+        ))
+    }
+
+    fn intersect_test(&self, ray: Ray<f64>) -> bool {
+        self.hit(ray).is_some()
+    }
+
+    // A ray that leaves the disk can't hit it again, so it's skipped entirely:
+
+    fn intersect_excluding(&self, ray: Ray<f64>, prim: Option<u32>) -> Option<Interaction> {
+        match prim {
+            Some(_) => None,
+            None => self.intersect(ray),
+        }
+    }
+
+    fn intersect_test_excluding(&self, ray: Ray<f64>, prim: Option<u32>) -> bool {
+        prim.is_none() && self.intersect_test(ray)
+    }
+
+    fn get_surface_area(&self) -> f64 {
+        PI * self.radius * self.radius
+    }
+
+    fn calc_surface_area(&mut self) -> f64 {
+        self.get_surface_area()
+    }
+
+    fn get_bbox(&self) -> BBox3<f64> {
+        BBox3::from_pnts(
+            Vec3 {
+                x: -self.radius,
+                y: -self.radius,
+                z: 0.0,
+            },
+            Vec3 {
+                x: self.radius,
+                y: self.radius,
+                z: 0.0,
+            },
+        )
+    }
+}
+
+impl SampleableGeometry for Disk {
+    fn sample_surface(&self, u: Vec2<f64>) -> SurfaceSample {
+        let pd = sampling::concentric_sample_disk(u).scale(self.radius);
+        SurfaceSample {
+            p: Vec3::from_vec2(pd, 0.0),
+            n: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            pdf: 1.0 / self.get_surface_area(),
+        }
+    }
+}
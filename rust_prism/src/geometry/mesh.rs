@@ -1,10 +1,51 @@
-use crate::bvh::{BVHObject, BVH};
-use crate::geometry::Geometry;
-use crate::interaction::{GeomSurf, SurfType, Surface};
+use crate::bvh::{BVHObject, BuildAlgorithm, TraversalControl, BVH};
+use crate::error::{PrismError, PrismResult};
+use crate::geometry::{Geometry, SampleableGeometry, SurfaceSample};
+use crate::interaction::{self, GeomIntr, Interaction, IntrType, TriplanarCoords};
+use crate::transform::Transf;
+use crate::Scalar;
+use arrayvec::ArrayVec;
+use half::f16;
 use pmath;
 use pmath::bbox::BBox3;
+use pmath::numbers::Float;
 use pmath::ray::Ray;
 use pmath::vector::{Vec2, Vec3};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+/// How the uvs of a mesh that doesn't have any are generated (see `ImportOptions::uv_projection`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum UvProjection {
+    /// Every triangle gets the uvs (0, 0), (1, 0), and (1, 1).
+    Fixed,
+    /// Every triangle gets its own uvs in the plane of the triangle (with u along its first edge) in
+    /// object space units, so that a texture has the same scale on every triangle.
+    Local,
+    /// The object space position, projected along the axis that is closest to the normal of the
+    /// triangle (see `planar_uv`).
+    Planar,
+    /// The same uvs as `Planar`, but the interactions also store the object space position and normal,
+    /// so textures can be blended across the three axes (see `TriplanarTexture`).
+    Triplanar,
+}
+
+impl Default for UvProjection {
+    fn default() -> Self {
+        UvProjection::Fixed
+    }
+}
+
+/// Projects the point along one of the axes (0 for x, 1 for y, and 2 for z) onto a uv coordinate.
+pub fn planar_uv(p: Vec3<f64>, axis: usize) -> Vec2<f64> {
+    match axis {
+        0 => Vec2 { x: p.z, y: p.y },
+        1 => Vec2 { x: p.x, y: p.z },
+        _ => Vec2 { x: p.x, y: p.y },
+    }
+}
 
 #[derive(Clone, Copy, Debug)]
 struct RayIntInfo {
@@ -38,11 +79,200 @@ impl RayIntInfo {
 }
 
 #[derive(Clone, Copy, Debug)]
-struct Triangle {
+pub struct Triangle {
     pub indices: [u32; 3],
+    // Used to assign different materials to different parts of a mesh.
+    pub attribute_id: u32,
 }
 
 impl Triangle {
+    /// Constructs the interaction of the ray with the triangle at `t`, where the point of intersection has
+    /// the barycentric coordinates `b`.
+    fn interaction(self, ray: Ray<f64>, t: f64, b: [f64; 3], mesh: &MeshData) -> Interaction {
+        let poss = self.pos(mesh);
+        // The hit point:
+        let p = poss[0].scale(b[0]) + poss[1].scale(b[1]) + poss[2].scale(b[2]);
+        let p_error =
+            (poss[0].scale(b[0]).abs() + poss[1].scale(b[1]).abs() + poss[2].scale(b[2]).abs())
+                .scale(pmath::gamma_f64(7));
+
+        // The edges along the triangle:
+        let dp02 = poss[0] - poss[2];
+        let dp12 = poss[1] - poss[2];
+        // The geometric normal of the hitpoint (according to just the position info)
+        let n = dp02.cross(dp12).normalize();
+
+        // Get the UV coordinates:
+        let uvs = if mesh.has_uvs() {
+            self.uvs(mesh)
+        } else {
+            Triangle::projected_uvs(poss, mesh.uv_projection)
+        };
+        // Calculate the uv point where we intersect now:
+        let uv = uvs[0].scale(b[0]) + uvs[1].scale(b[1]) + uvs[2].scale(b[2]);
+
+        // Matrix entries for calculating dpdu and dpdv:
+        let duv02 = uvs[0] - uvs[2];
+        let duv12 = uvs[1] - uvs[2];
+        let det = duv02[0] * duv12[1] - duv02[1] * duv12[0];
+        // Compare against the extent of the uvs so that this doesn't depend on their scale:
+        let uv_extent = duv02[0]
+            .abs()
+            .max(duv02[1].abs())
+            .max(duv12[0].abs())
+            .max(duv12[1].abs());
+        let is_degen_uv = det.abs() <= 1e-8 * uv_extent * uv_extent;
+        let inv_det = if is_degen_uv { 0. } else { 1. / det };
+
+        // Compute triangle partial derivatives:
+        // These vectors are parallel to the triangle:
+        let (dpdu, dpdv) = if is_degen_uv {
+            pmath::coord_system((poss[2] - poss[0]).cross(poss[1] - poss[0]))
+        } else {
+            // Solve the system:
+            let dpdu = (dp02.scale(duv12[1]) - dp12.scale(duv02[1])).scale(inv_det);
+            let dpdv = (dp02.scale(-duv12[0]) + dp12.scale(duv02[0])).scale(inv_det);
+            if dpdu.cross(dpdv).length2() == 0. {
+                pmath::coord_system((poss[2] - poss[0]).cross(poss[1] - poss[0]))
+            } else {
+                (dpdu, dpdv)
+            }
+        };
+
+        // TODO: texture stuff goes here
+
+        // Calculate the shading normals now:
+        let sn = if !mesh.has_nrm() {
+            n // No normal information was provided, so we use the calculated normal.
+        } else {
+            let norms = self.nrm(mesh);
+            let sn = norms[0].scale(b[0]) + norms[1].scale(b[1]) + norms[2].scale(b[2]);
+            if sn.length2() == 0. {
+                n
+            } else {
+                sn.normalize()
+            }
+        };
+        // Update n with the new shading normal from the provided normal:
+        let n = pmath::align(sn, n);
+
+        // Moves the point onto the tangent planes of the vertex normals (never inwards), so that shadow
+        // rays leave from the smooth surface the normals describe (Hanika, "Hacking the Shadow
+        // Terminator"):
+        let shadow_p = if !mesh.has_nrm() {
+            p
+        } else {
+            let poss = self.pos(mesh);
+            let norms = self.nrm(mesh);
+            (0..3).fold(p, |shadow_p, i| {
+                let vert_n = norms[i].normalize();
+                let dist = (p - poss[i]).dot(vert_n).min(0.0);
+                shadow_p - vert_n.scale(dist * b[i])
+            })
+        };
+
+        // Calculate the shading dndu and dndv values:
+        let (sdndu, sdndv) = if !mesh.has_nrm() {
+            (Vec3::zero(), Vec3::zero())
+        } else {
+            let norms = self.nrm(mesh);
+            let dn02 = norms[0] - norms[2];
+            let dn12 = norms[1] - norms[2];
+
+            if is_degen_uv {
+                let dn = (norms[2] - norms[0]).cross(norms[1] - norms[0]);
+                if dn.length2() == 0. {
+                    (Vec3::zero(), Vec3::zero())
+                } else {
+                    pmath::coord_system(dn)
+                }
+            } else {
+                let dndu = (dn02.scale(duv12[1]) - dn12.scale(duv02[1])).scale(inv_det);
+                let dndv = (dn02.scale(-duv12[0]) + dn12.scale(duv02[0])).scale(inv_det);
+                (dndu, dndv)
+            }
+        };
+
+        // Calculate the shading tangents:
+        let sdpdu = if mesh.has_tan() {
+            let tans = self.tan(mesh);
+            let st = tans[0].scale(b[0]) + tans[1].scale(b[1]) + tans[2].scale(b[2]);
+            if st.length2() == 0. {
+                dpdu.normalize() // Just the same dpdu value as before
+            } else {
+                st.normalize()
+            }
+        } else {
+            dpdu.normalize()
+        };
+
+        // Calculate the shaind bitangent:
+        let (sdpdu, sdpdv) = {
+            let sbt = if mesh.has_tan() && !mesh.tan_sgn.is_empty() {
+                sn.cross(sdpdu)
+                    .scale(mesh.tan_sgn[self.indices[0] as usize] as f64)
+            } else {
+                sn.cross(sdpdu)
+            };
+            if sbt.length2() > 0. {
+                (sbt.cross(sdpdu), sbt.normalize())
+            } else {
+                pmath::coord_system(sn)
+            }
+        };
+
+        let col = if mesh.has_col() {
+            let cols = self.col(mesh);
+            Some(cols[0].scale(b[0]) + cols[1].scale(b[1]) + cols[2].scale(b[2]))
+        } else {
+            None
+        };
+
+        let triplanar = match mesh.uv_projection {
+            UvProjection::Triplanar if !mesh.has_uvs() => Some(TriplanarCoords {
+                p: p.cast(),
+                n: n.cast(),
+            }),
+            _ => None,
+        };
+
+        let wo = -ray.dir;
+
+        // Everything up to here is computed in f64 (see `Scalar`):
+        let geom_intr = GeomIntr {
+            uv: uv.cast(),
+            dpdu: dpdu.cast(),
+            dpdv: dpdv.cast(),
+            sn: sn.cast(),
+            sdpdu: sdpdu.cast(),
+            sdpdv: sdpdv.cast(),
+            sdndu: sdndu.cast(),
+            sdndv: sdndv.cast(),
+            shadow_p: shadow_p.cast(),
+            col: col.map(Vec3::cast),
+            triplanar,
+        };
+
+        let (p, p_error) = interaction::point_to_scalar(p, p_error);
+        Interaction {
+            p,
+            p_error,
+            attribute_id: self.attribute_id,
+            // Set by the mesh (triangles don't know their index):
+            prim: 0,
+            bary: Vec2 {
+                x: Scalar::from_f64(b[1]),
+                y: Scalar::from_f64(b[2]),
+            },
+            n: n.cast(),
+            wo: wo.cast(),
+            t: Scalar::from_f64(t),
+            time: Scalar::from_f64(ray.time),
+            geom: None,
+            intr_type: IntrType::Geom(geom_intr),
+        }
+    }
+
     fn area(self, mesh: &MeshData) -> f64 {
         let pos = self.pos(mesh);
         let a = pos[1] - pos[0];
@@ -52,17 +282,17 @@ impl Triangle {
 
     fn pos(self, mesh: &MeshData) -> [Vec3<f64>; 3] {
         [
-            mesh.pos[self.indices[0] as usize].to_f64(),
-            mesh.pos[self.indices[1] as usize].to_f64(),
-            mesh.pos[self.indices[2] as usize].to_f64(),
+            mesh.pos_at(self.indices[0]).to_f64(),
+            mesh.pos_at(self.indices[1]).to_f64(),
+            mesh.pos_at(self.indices[2]).to_f64(),
         ]
     }
 
     fn nrm(self, mesh: &MeshData) -> [Vec3<f64>; 3] {
         [
-            mesh.nrm[self.indices[0] as usize].to_f64(),
-            mesh.nrm[self.indices[1] as usize].to_f64(),
-            mesh.nrm[self.indices[2] as usize].to_f64(),
+            mesh.nrm_at(self.indices[0]).to_f64(),
+            mesh.nrm_at(self.indices[1]).to_f64(),
+            mesh.nrm_at(self.indices[2]).to_f64(),
         ]
     }
 
@@ -74,13 +304,63 @@ impl Triangle {
         ]
     }
 
+    fn col(self, mesh: &MeshData) -> [Vec3<f64>; 3] {
+        [
+            mesh.col[self.indices[0] as usize].to_f64(),
+            mesh.col[self.indices[1] as usize].to_f64(),
+            mesh.col[self.indices[2] as usize].to_f64(),
+        ]
+    }
+
     fn uvs(self, mesh: &MeshData) -> [Vec2<f64>; 3] {
         [
-            mesh.uvs[self.indices[0] as usize].to_f64(),
-            mesh.uvs[self.indices[1] as usize].to_f64(),
-            mesh.uvs[self.indices[2] as usize].to_f64(),
+            mesh.uv_at(self.indices[0]).to_f64(),
+            mesh.uv_at(self.indices[1]).to_f64(),
+            mesh.uv_at(self.indices[2]).to_f64(),
         ]
     }
+
+    /// The uvs of the triangle if the mesh doesn't have any.
+    fn projected_uvs(poss: [Vec3<f64>; 3], projection: UvProjection) -> [Vec2<f64>; 3] {
+        let fixed = [
+            Vec2 { x: 0., y: 0. },
+            Vec2 { x: 1., y: 0. },
+            Vec2 { x: 1., y: 1. },
+        ];
+        let e1 = poss[1] - poss[0];
+        let e2 = poss[2] - poss[0];
+        let n = e1.cross(e2);
+        // Degenerate triangles can't be projected (and are never hit anyways):
+        if n.length2() == 0. {
+            return fixed;
+        }
+        match projection {
+            UvProjection::Fixed => fixed,
+            UvProjection::Local => {
+                let u = e1.normalize();
+                let v = n.normalize().cross(u);
+                [
+                    Vec2::zero(),
+                    Vec2 {
+                        x: e1.length(),
+                        y: 0.,
+                    },
+                    Vec2 {
+                        x: e2.dot(u),
+                        y: e2.dot(v),
+                    },
+                ]
+            }
+            UvProjection::Planar | UvProjection::Triplanar => {
+                let axis = n.abs().max_dim();
+                [
+                    planar_uv(poss[0], axis),
+                    planar_uv(poss[1], axis),
+                    planar_uv(poss[2], axis),
+                ]
+            }
+        }
+    }
 }
 
 impl BVHObject for Triangle {
@@ -166,7 +446,7 @@ impl BVHObject for Triangle {
         t_scaled * inv_sum_e > 0.
     }
 
-    fn intersect(&self, ray: Ray<f64>, mesh: &MeshData) -> Option<Surface> {
+    fn intersect(&self, ray: Ray<f64>, mesh: &MeshData) -> Option<Interaction> {
         let int_info = RayIntInfo::new(ray);
         let poss = self.pos(mesh);
 
@@ -248,164 +528,489 @@ impl BVHObject for Triangle {
             return None;
         }
 
-        // Baycentric coordinates:
-        let b = [e[0] * inv_sum_e, e[1] * inv_sum_e, e[2] * inv_sum_e];
-        // The hit point:
-        let p = poss[0].scale(b[0]) + poss[1].scale(b[1]) + poss[2].scale(b[2]);
-
-        // The edges along the triangle:
-        let dp02 = poss[0] - poss[2];
-        let dp12 = poss[1] - poss[2];
-        // The geometric normal of the hitpoint (according to just the position info)
-        let n = dp02.cross(dp12).normalize();
+        // Make sure that t is larger than the error bounds on it (so we don't get false hits
+        // behind the origin of the ray). This follows the derivation in PBRT (3rd edition).
+        let max_zt = pt[0].z.abs().max(pt[1].z.abs()).max(pt[2].z.abs());
+        let delta_z = pmath::gamma_f64(3) * max_zt;
 
-        // Get the UV coordinates:
-        let uvs = if mesh.has_uvs() {
-            self.uvs(mesh)
-        } else {
-            [
-                Vec2 { x: 0., y: 0. },
-                Vec2 { x: 1., y: 0. },
-                Vec2 { x: 1., y: 1. },
-            ]
-        };
-        // Calculate the uv point where we intersect now:
-        let uv = uvs[0].scale(b[0]) + uvs[1].scale(b[1]) + uvs[2].scale(b[2]);
+        let max_xt = pt[0].x.abs().max(pt[1].x.abs()).max(pt[2].x.abs());
+        let max_yt = pt[0].y.abs().max(pt[1].y.abs()).max(pt[2].y.abs());
+        let delta_x = pmath::gamma_f64(5) * (max_xt + max_zt);
+        let delta_y = pmath::gamma_f64(5) * (max_yt + max_zt);
 
-        // Matrix entries for calculating dpdu and dpdv:
-        let duv02 = uvs[0] - uvs[2];
-        let duv12 = uvs[1] - uvs[2];
-        let det = duv02[0] * duv12[1] - duv02[1] * duv12[0];
-        let is_degen_uv = det.abs() < 1e-8; // This is quite a hack, so we should do something about this
-        let inv_det = if is_degen_uv { 0. } else { 1. / det };
+        let delta_e =
+            2. * (pmath::gamma_f64(2) * max_xt * max_yt + delta_y * max_xt + delta_x * max_yt);
+        let max_e = e[0].abs().max(e[1].abs()).max(e[2].abs());
 
-        // Compute triangle partial derivatives:
-        // These vectors are parallel to the triangle:
-        let (dpdu, dpdv) = if is_degen_uv {
-            pmath::coord_system((poss[2] - poss[0]).cross(poss[1] - poss[0]))
-        } else {
-            // Solve the system:
-            let dpdu = (dp02.scale(duv12[1]) - dp12.scale(duv02[1])).scale(inv_det);
-            let dpdv = (dp02.scale(-duv12[0]) + dp12.scale(duv02[0])).scale(inv_det);
-            if dpdu.cross(dpdv).length2() == 0. {
-                pmath::coord_system((poss[2] - poss[0]).cross(poss[1] - poss[0]))
-            } else {
-                (dpdu, dpdv)
-            }
-        };
+        let delta_t = 3.
+            * (pmath::gamma_f64(3) * max_e * max_zt + delta_e * max_zt + delta_z * max_e)
+            * inv_sum_e.abs();
+        if t <= delta_t {
+            return None;
+        }
 
-        // TODO: texture stuff goes here
+        // Baycentric coordinates:
+        let b = [e[0] * inv_sum_e, e[1] * inv_sum_e, e[2] * inv_sum_e];
+        Some(self.interaction(ray, t, b, mesh))
+    }
 
-        // Calculate the shading normals now:
-        let sn = if mesh.has_nrm() {
-            n // No normal information was provided, so we use the calculated normal.
-        } else {
-            let norms = self.nrm(mesh);
-            let sn = norms[0].scale(b[0]) + norms[1].scale(b[1]) + norms[2].scale(b[2]);
-            if sn.length2() == 0. {
-                n
-            } else {
-                sn.normalize()
-            }
-        };
-        // Update n with the new shading normal from the provided normal:
-        let n = pmath::align(sn, n);
+    fn get_bbox(&self, mesh: &MeshData) -> BBox3<f64> {
+        let poss = self.pos(mesh);
+        BBox3::from_pnts(poss[0], poss[1]).combine_pnt(poss[2])
+    }
 
-        // Calculate the shading dndu and dndv values:
-        let (sdndu, sdndv) = if mesh.has_nrm() {
-            (Vec3::zero(), Vec3::zero())
-        } else {
-            let norms = self.nrm(mesh);
-            let dn02 = norms[0] - norms[2];
-            let dn12 = norms[1] - norms[2];
+    /// Clips the triangle against each of the planes of the bounding box and returns the bounding box
+    /// of what's left.
+    fn clip_bbox(&self, bbox: BBox3<f64>, mesh: &MeshData) -> BBox3<f64> {
+        // Every plane can add at most one vertex to the polygon:
+        let mut polygon: ArrayVec<[Vec3<f64>; 9]> = self.pos(mesh).iter().copied().collect();
+        for axis in 0..3 {
+            for &(plane, is_max) in [(bbox.pmin[axis], false), (bbox.pmax[axis], true)].iter() {
+                let inside = |p: Vec3<f64>| {
+                    if is_max {
+                        p[axis] <= plane
+                    } else {
+                        p[axis] >= plane
+                    }
+                };
 
-            if is_degen_uv {
-                let dn = (norms[2] - norms[0]).cross(norms[1] - norms[0]);
-                if dn.length2() == 0. {
-                    (Vec3::zero(), Vec3::zero())
-                } else {
-                    pmath::coord_system(dn)
+                let mut clipped = ArrayVec::<[_; 9]>::new();
+                for (i, &p0) in polygon.iter().enumerate() {
+                    let p1 = polygon[(i + 1) % polygon.len()];
+                    if inside(p0) {
+                        clipped.push(p0);
+                    }
+                    if inside(p0) != inside(p1) {
+                        let t = (plane - p0[axis]) / (p1[axis] - p0[axis]);
+                        let mut p = p0 + (p1 - p0).scale(t);
+                        p[axis] = plane;
+                        clipped.push(p);
+                    }
                 }
-            } else {
-                let dndu = (dn02.scale(duv12[1]) - dn12.scale(duv02[1])).scale(inv_det);
-                let dndv = (dn02.scale(-duv12[0]) + dn12.scale(duv02[0])).scale(inv_det);
-                (dndu, dndv)
-            }
-        };
-
-        // Calculate the shading tangents:
-        let sdpdu = if mesh.has_tan() {
-            let tans = self.tan(mesh);
-            let st = tans[0].scale(b[0]) + tans[1].scale(b[1]) + tans[2].scale(b[2]);
-            if st.length2() == 0. {
-                dpdu.normalize() // Just the same dpdu value as before
-            } else {
-                st.normalize()
-            }
-        } else {
-            dpdu.normalize()
-        };
-
-        // Calculate the shaind bitangent:
-        let (sdpdu, sdpdv) = {
-            let sbt = sn.cross(sdpdu);
-            if sbt.length2() > 0. {
-                (sbt.cross(sdpdu), sbt.normalize())
-            } else {
-                pmath::coord_system(sn)
+                polygon = clipped;
             }
-        };
-
-        let wo = -ray.dir;
-
-        let geom_surf = GeomSurf {
-            uv,
-            dpdu,
-            dpdv,
-            sn,
-            sdpdu,
-            sdpdv,
-            sdndu,
-            sdndv,
-        };
-
-        Some(Surface {
-            p,
-            n,
-            wo,
-            t,
-            time: ray.time,
-            surf_type: SurfType::Geom(geom_surf),
-        })
-    }
+        }
 
-    fn get_bbox(&self, mesh: &MeshData) -> BBox3<f64> {
-        let poss = self.pos(mesh);
-        BBox3::from_pnts(poss[0], poss[1]).combine_pnt(poss[2])
+        polygon
+            .iter()
+            .fold(BBox3::new_initial(), |accum, &p| accum.combine_pnt(p))
     }
 }
 
 // This represents the raw data that belongs to a mesh and gets passed to the triangle to
-struct MeshData {
+#[derive(Clone)]
+pub struct MeshData {
     pub triangles: Vec<Triangle>,
     pub pos: Vec<Vec3<f32>>,
     pub nrm: Vec<Vec3<f32>>,
     pub tan: Vec<Vec3<f32>>,
+    // The handedness of the tangent frame (1 or -1), may be empty even if tan isn't.
+    pub tan_sgn: Vec<f32>,
     pub uvs: Vec<Vec2<f32>>,
+    pub col: Vec<Vec3<f32>>,
+    // Edges that should stay sharp when subdividing.
+    pub creases: Vec<[u32; 2]>,
+    // If set, the positions, normals and uvs are stored here instead.
+    pub compact: Option<CompactVertices>,
+    // How uvs are generated if the mesh doesn't have any.
+    pub uv_projection: UvProjection,
 }
 
-impl MeshData {
-    fn has_nrm(&self) -> bool {
-        !self.nrm.is_empty()
-    }
+/// A single vertex of the compact representation: the normal is octahedron encoded
+/// (16 bits per component) and the uvs are stored as half floats.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct CompactVertex {
+    pos: [f32; 3],
+    nrm: u32,
+    uv: [u16; 2],
+}
 
-    fn has_tan(&self) -> bool {
-        !self.tan.is_empty()
-    }
+#[derive(Clone)]
+pub struct CompactVertices {
+    vertices: Vec<CompactVertex>,
+    has_nrm: bool,
+    has_uvs: bool,
+}
 
-    fn has_uvs(&self) -> bool {
-        !self.uvs.is_empty()
+/// Maps a unit vector onto the octahedron and packs the result into 32 bits.
+fn oct_encode(n: Vec3<f32>) -> u32 {
+    let l1 = n.x.abs() + n.y.abs() + n.z.abs();
+    if l1 == 0.0 {
+        return 0;
     }
+
+    let (x, y) = (n.x / l1, n.y / l1);
+    let (x, y) = if n.z < 0.0 {
+        ((1.0 - y.abs()) * x.signum(), (1.0 - x.abs()) * y.signum())
+    } else {
+        (x, y)
+    };
+
+    let quantize = |v: f32| ((v.max(-1.0).min(1.0) * 32767.0).round() as i16) as u16 as u32;
+    quantize(x) | (quantize(y) << 16)
+}
+
+fn oct_decode(e: u32) -> Vec3<f32> {
+    let x = (e as u16 as i16) as f32 / 32767.0;
+    let y = ((e >> 16) as u16 as i16) as f32 / 32767.0;
+    let z = 1.0 - x.abs() - y.abs();
+    let (x, y) = if z < 0.0 {
+        ((1.0 - y.abs()) * x.signum(), (1.0 - x.abs()) * y.signum())
+    } else {
+        (x, y)
+    };
+
+    let n = Vec3 { x, y, z };
+    if n.length2() == 0.0 {
+        n
+    } else {
+        n.normalize()
+    }
+}
+
+impl MeshData {
+    /// Returns a hash of everything that affects the construction of the bvh.
+    fn bvh_cache_key(&self, max_triangles_per_leaf: usize, build_algorithm: BuildAlgorithm) -> u64 {
+        // FNV-1a, as it's stable (unlike the hasher in the standard library):
+        let hash_bytes = |hash: u64, bytes: &[u8]| {
+            bytes.iter().fold(hash, |hash, &byte| {
+                (hash ^ (byte as u64)).wrapping_mul(0x100000001b3)
+            })
+        };
+
+        let mut hash = 0xcbf29ce484222325;
+        hash = hash_bytes(hash, &(max_triangles_per_leaf as u64).to_le_bytes());
+        hash = hash_bytes(hash, &[build_algorithm as u8]);
+        for triangle in &self.triangles {
+            for &index in triangle.indices.iter() {
+                hash = hash_bytes(hash, &index.to_le_bytes());
+            }
+        }
+        for i in 0..self.num_vertices() {
+            let p = self.pos_at(i as u32);
+            for &v in [p.x, p.y, p.z].iter() {
+                hash = hash_bytes(hash, &v.to_bits().to_le_bytes());
+            }
+        }
+        hash
+    }
+
+    pub fn has_nrm(&self) -> bool {
+        match &self.compact {
+            Some(compact) => compact.has_nrm,
+            None => !self.nrm.is_empty(),
+        }
+    }
+
+    pub fn has_tan(&self) -> bool {
+        !self.tan.is_empty()
+    }
+
+    pub fn has_uvs(&self) -> bool {
+        match &self.compact {
+            Some(compact) => compact.has_uvs,
+            None => !self.uvs.is_empty(),
+        }
+    }
+
+    pub fn has_col(&self) -> bool {
+        !self.col.is_empty()
+    }
+
+    pub fn num_vertices(&self) -> usize {
+        match &self.compact {
+            Some(compact) => compact.vertices.len(),
+            None => self.pos.len(),
+        }
+    }
+
+    pub fn pos_at(&self, index: u32) -> Vec3<f32> {
+        match &self.compact {
+            Some(compact) => Vec3::from_arr(compact.vertices[index as usize].pos),
+            None => self.pos[index as usize],
+        }
+    }
+
+    pub fn nrm_at(&self, index: u32) -> Vec3<f32> {
+        match &self.compact {
+            Some(compact) => oct_decode(compact.vertices[index as usize].nrm),
+            None => self.nrm[index as usize],
+        }
+    }
+
+    pub fn uv_at(&self, index: u32) -> Vec2<f32> {
+        match &self.compact {
+            Some(compact) => {
+                let uv = compact.vertices[index as usize].uv;
+                Vec2 {
+                    x: f16::from_bits(uv[0]).to_f32(),
+                    y: f16::from_bits(uv[1]).to_f32(),
+                }
+            }
+            None => self.uvs[index as usize],
+        }
+    }
+
+    /// Moves the positions, normals and uvs into a single interleaved buffer.
+    fn compact(&mut self) {
+        if self.compact.is_some() {
+            return;
+        }
+
+        let has_nrm = self.has_nrm();
+        let has_uvs = self.has_uvs();
+        let vertices = (0..self.pos.len())
+            .map(|i| CompactVertex {
+                pos: [self.pos[i].x, self.pos[i].y, self.pos[i].z],
+                nrm: if has_nrm { oct_encode(self.nrm[i]) } else { 0 },
+                uv: if has_uvs {
+                    [
+                        f16::from_f32(self.uvs[i].x).to_bits(),
+                        f16::from_f32(self.uvs[i].y).to_bits(),
+                    ]
+                } else {
+                    [0, 0]
+                },
+            })
+            .collect();
+
+        self.compact = Some(CompactVertices {
+            vertices,
+            has_nrm,
+            has_uvs,
+        });
+        self.pos = Vec::new();
+        self.nrm = Vec::new();
+        self.uvs = Vec::new();
+    }
+
+    /// Undoes `compact`, needed before modifying any of the vertex data.
+    fn expand(&mut self) {
+        if self.compact.is_none() {
+            return;
+        }
+
+        let num_verts = self.num_vertices() as u32;
+        let pos = (0..num_verts).map(|i| self.pos_at(i)).collect();
+        let nrm = if self.has_nrm() {
+            (0..num_verts).map(|i| self.nrm_at(i)).collect()
+        } else {
+            Vec::new()
+        };
+        let uvs = if self.has_uvs() {
+            (0..num_verts).map(|i| self.uv_at(i)).collect()
+        } else {
+            Vec::new()
+        };
+
+        self.pos = pos;
+        self.nrm = nrm;
+        self.uvs = uvs;
+        self.compact = None;
+    }
+
+    /// The number of bytes used by the vertex and triangle data.
+    fn memory_usage(&self) -> usize {
+        let compact = self.compact.as_ref().map_or(0, |compact| {
+            compact.vertices.len() * std::mem::size_of::<CompactVertex>()
+        });
+        compact
+            + self.triangles.len() * std::mem::size_of::<Triangle>()
+            + self.pos.len() * std::mem::size_of::<Vec3<f32>>()
+            + self.nrm.len() * std::mem::size_of::<Vec3<f32>>()
+            + self.tan.len() * std::mem::size_of::<Vec3<f32>>()
+            + self.tan_sgn.len() * std::mem::size_of::<f32>()
+            + self.uvs.len() * std::mem::size_of::<Vec2<f32>>()
+            + self.col.len() * std::mem::size_of::<Vec3<f32>>()
+            + self.creases.len() * std::mem::size_of::<[u32; 2]>()
+    }
+
+    /// Performs a single level of Loop subdivision. Normals and tangents are dropped as
+    /// they have to be recomputed anyways.
+    fn loop_subdivide(&self) -> MeshData {
+        let num_verts = self.pos.len();
+        let edge_key = |a: u32, b: u32| if a < b { (a, b) } else { (b, a) };
+
+        // Every edge is assigned a new vertex and keeps track of the vertices opposite to it:
+        let mut edges: HashMap<(u32, u32), (u32, Vec<u32>)> = HashMap::new();
+        for tri in self.triangles.iter() {
+            for i in 0..3 {
+                let a = tri.indices[i];
+                let b = tri.indices[(i + 1) % 3];
+                let c = tri.indices[(i + 2) % 3];
+                let next_index = (num_verts + edges.len()) as u32;
+                edges
+                    .entry(edge_key(a, b))
+                    .or_insert_with(|| (next_index, Vec::new()))
+                    .1
+                    .push(c);
+            }
+        }
+        let is_sharp = |key: &(u32, u32), opposite: &Vec<u32>| {
+            opposite.len() != 2 || self.creases.iter().any(|c| edge_key(c[0], c[1]) == *key)
+        };
+
+        // Neighbors of each vertex and the neighbors along sharp edges:
+        let mut neighbors = vec![Vec::new(); num_verts];
+        let mut sharp_neighbors = vec![Vec::new(); num_verts];
+        for (key, (_, opposite)) in edges.iter() {
+            neighbors[key.0 as usize].push(key.1);
+            neighbors[key.1 as usize].push(key.0);
+            if is_sharp(key, opposite) {
+                sharp_neighbors[key.0 as usize].push(key.1);
+                sharp_neighbors[key.1 as usize].push(key.0);
+            }
+        }
+
+        let num_new_verts = num_verts + edges.len();
+        let mut pos = vec![Vec3::zero(); num_new_verts];
+
+        // Update the original (even) vertices:
+        for i in 0..num_verts {
+            let p = self.pos[i].to_f64();
+            let sum = |verts: &[u32]| {
+                verts
+                    .iter()
+                    .fold(Vec3::zero(), |sum, &v| sum + self.pos[v as usize].to_f64())
+            };
+            pos[i] = match sharp_neighbors[i].len() {
+                // A vertex with more than two sharp edges is a corner and doesn't move:
+                n if n > 2 => p,
+                2 => p.scale(0.75) + sum(&sharp_neighbors[i]).scale(0.125),
+                _ => {
+                    let n = neighbors[i].len();
+                    if n == 0 {
+                        p
+                    } else {
+                        let beta = if n == 3 {
+                            3.0 / 16.0
+                        } else {
+                            3.0 / (8.0 * n as f64)
+                        };
+                        p.scale(1.0 - n as f64 * beta) + sum(&neighbors[i]).scale(beta)
+                    }
+                }
+            }
+            .to_f32();
+        }
+
+        // Create the new (odd) vertices:
+        for (key, (index, opposite)) in edges.iter() {
+            let a = self.pos[key.0 as usize].to_f64();
+            let b = self.pos[key.1 as usize].to_f64();
+            pos[*index as usize] = if is_sharp(key, opposite) {
+                (a + b).scale(0.5)
+            } else {
+                let c = self.pos[opposite[0] as usize].to_f64();
+                let d = self.pos[opposite[1] as usize].to_f64();
+                (a + b).scale(0.375) + (c + d).scale(0.125)
+            }
+            .to_f32();
+        }
+
+        // Other attributes are just linearly interpolated:
+        let uvs = if self.has_uvs() {
+            let mut uvs = self.uvs.clone();
+            uvs.resize(num_new_verts, Vec2::zero());
+            for (key, (index, _)) in edges.iter() {
+                let a = self.uvs[key.0 as usize].to_f64();
+                let b = self.uvs[key.1 as usize].to_f64();
+                uvs[*index as usize] = (a + b).scale(0.5).to_f32();
+            }
+            uvs
+        } else {
+            Vec::new()
+        };
+        let col = if self.has_col() {
+            let mut col = self.col.clone();
+            col.resize(num_new_verts, Vec3::zero());
+            for (key, (index, _)) in edges.iter() {
+                let a = self.col[key.0 as usize].to_f64();
+                let b = self.col[key.1 as usize].to_f64();
+                col[*index as usize] = (a + b).scale(0.5).to_f32();
+            }
+            col
+        } else {
+            Vec::new()
+        };
+
+        let mut triangles = Vec::with_capacity(self.triangles.len() * 4);
+        for tri in self.triangles.iter() {
+            let [a, b, c] = tri.indices;
+            let ab = edges[&edge_key(a, b)].0;
+            let bc = edges[&edge_key(b, c)].0;
+            let ca = edges[&edge_key(c, a)].0;
+            triangles.push(Triangle {
+                indices: [a, ab, ca],
+                attribute_id: tri.attribute_id,
+            });
+            triangles.push(Triangle {
+                indices: [b, bc, ab],
+                attribute_id: tri.attribute_id,
+            });
+            triangles.push(Triangle {
+                indices: [c, ca, bc],
+                attribute_id: tri.attribute_id,
+            });
+            triangles.push(Triangle {
+                indices: [ab, bc, ca],
+                attribute_id: tri.attribute_id,
+            });
+        }
+
+        // Creases are split in two along with their edge:
+        let mut creases = Vec::with_capacity(self.creases.len() * 2);
+        for crease in self.creases.iter() {
+            if let Some((index, _)) = edges.get(&edge_key(crease[0], crease[1])) {
+                creases.push([crease[0], *index]);
+                creases.push([*index, crease[1]]);
+            }
+        }
+
+        MeshData {
+            triangles,
+            pos,
+            nrm: Vec::new(),
+            tan: Vec::new(),
+            tan_sgn: Vec::new(),
+            uvs,
+            col,
+            creases,
+            compact: None,
+            uv_projection: self.uv_projection,
+        }
+    }
+
+    /// Appends a copy of every attribute of a vertex, returning the index of the copy.
+    /// Normals are left alone as they are usually what the copy is being made for.
+    fn duplicate_vertex(&mut self, index: usize) -> u32 {
+        let new_index = self.pos.len() as u32;
+        self.pos.push(self.pos[index]);
+        if self.has_tan() {
+            self.tan.push(self.tan[index]);
+        }
+        if !self.tan_sgn.is_empty() {
+            self.tan_sgn.push(self.tan_sgn[index]);
+        }
+        if self.has_uvs() {
+            self.uvs.push(self.uvs[index]);
+        }
+        if self.has_col() {
+            self.col.push(self.col[index]);
+        }
+        new_index
+    }
+}
+
+/// The maximum number of triangles `Mesh::subdivide` is allowed to produce.
+pub const MAX_SUBDIVISION_TRIANGLES: usize = 1 << 26;
+
+/// How much was removed by `Mesh::weld`.
+#[derive(Clone, Copy, Debug)]
+pub struct WeldReport {
+    pub vertices_removed: usize,
+    pub triangles_removed: usize,
 }
 
 pub struct Mesh {
@@ -413,8 +1018,33 @@ pub struct Mesh {
     mesh_data: MeshData,
     // The bvh of the mesh.
     bvh: BVH<Triangle>,
+    // Needed when the bvh has to be rebuilt.
+    max_triangles_per_leaf: usize,
+    build_algorithm: BuildAlgorithm,
     // The surface area of the mesh.
     surface_area: f64,
+    // The running sum of the areas of the triangles (used to sample the surface of the mesh).
+    area_cdf: Vec<f64>,
+}
+
+/// Loads the bvh from the cache if it exists and matches the key.
+fn read_bvh_cache(path: &str, key: u64, triangles: &[Triangle]) -> Option<BVH<Triangle>> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let mut cache_key = [0u8; 8];
+    reader.read_exact(&mut cache_key).ok()?;
+    if u64::from_le_bytes(cache_key) != key {
+        return None;
+    }
+    BVH::deserialize(&mut reader, triangles, path).ok()
+}
+
+fn write_bvh_cache(path: &str, key: u64, bvh: &BVH<Triangle>) -> PrismResult<()> {
+    let mut writer = BufWriter::new(File::create(path).map_err(|err| PrismError::io(path, err))?);
+    writer
+        .write_all(&key.to_le_bytes())
+        .map_err(|err| PrismError::io(path, err))?;
+    bvh.serialize(&mut writer, path)?;
+    writer.flush().map_err(|err| PrismError::io(path, err))
 }
 
 impl Mesh {
@@ -425,35 +1055,603 @@ impl Mesh {
         nrm: Vec<Vec3<f32>>,
         tan: Vec<Vec3<f32>>,
         uvs: Vec<Vec2<f32>>,
+        col: Vec<Vec3<f32>>,
+        max_triangles_per_leaf: usize,
+        build_algorithm: BuildAlgorithm,
+    ) -> Self {
+        let mesh_data = MeshData {
+            triangles,
+            pos,
+            nrm,
+            tan,
+            tan_sgn: Vec::new(),
+            uvs,
+            col,
+            creases: Vec::new(),
+            compact: None,
+            uv_projection: UvProjection::Fixed,
+        };
+        let bvh = BVH::build(
+            &mesh_data.triangles,
+            max_triangles_per_leaf,
+            &mesh_data,
+            build_algorithm,
+        );
+
+        Mesh {
+            mesh_data,
+            bvh,
+            max_triangles_per_leaf,
+            build_algorithm,
+            surface_area: -1.0,
+            area_cdf: Vec::new(),
+        }
+    }
+
+    /// Same as `new`, except that the bvh is loaded from the cache at `cache_path` if it was constructed
+    /// from the same triangles and positions (and with the same parameters). Otherwise the bvh is
+    /// constructed and written to the cache.
+    pub fn new_cached(
+        triangles: Vec<Triangle>,
+        pos: Vec<Vec3<f32>>,
+        nrm: Vec<Vec3<f32>>,
+        tan: Vec<Vec3<f32>>,
+        uvs: Vec<Vec2<f32>>,
+        col: Vec<Vec3<f32>>,
         max_triangles_per_leaf: usize,
+        build_algorithm: BuildAlgorithm,
+        cache_path: &str,
     ) -> Self {
         let mesh_data = MeshData {
             triangles,
             pos,
             nrm,
             tan,
+            tan_sgn: Vec::new(),
             uvs,
+            col,
+            creases: Vec::new(),
+            compact: None,
+            uv_projection: UvProjection::Fixed,
+        };
+
+        let key = mesh_data.bvh_cache_key(max_triangles_per_leaf, build_algorithm);
+        let bvh = match read_bvh_cache(cache_path, key, &mesh_data.triangles) {
+            Some(bvh) => bvh,
+            None => {
+                let bvh = BVH::build(
+                    &mesh_data.triangles,
+                    max_triangles_per_leaf,
+                    &mesh_data,
+                    build_algorithm,
+                );
+                // Not being able to write the cache isn't a problem, it just means that the
+                // bvh has to be constructed again next time:
+                let _ = write_bvh_cache(cache_path, key, &bvh);
+                bvh
+            }
         };
-        let bvh = BVH::new(&triangles, max_triangles_per_leaf, &mesh_data);
 
         Mesh {
             mesh_data,
             bvh,
+            max_triangles_per_leaf,
+            build_algorithm,
             surface_area: -1.0,
+            area_cdf: Vec::new(),
+        }
+    }
+
+    pub fn num_triangles(&self) -> usize {
+        self.mesh_data.triangles.len()
+    }
+
+    pub fn num_vertices(&self) -> usize {
+        self.mesh_data.num_vertices()
+    }
+
+    /// Switches the mesh to a more compact representation where the positions, normals
+    /// and uvs are interleaved in a single buffer. Normals and uvs lose some precision.
+    /// Any operation that modifies the mesh switches it back to the regular representation.
+    pub fn compact(&mut self) {
+        self.mesh_data.compact();
+    }
+
+    pub fn is_compact(&self) -> bool {
+        self.mesh_data.compact.is_some()
+    }
+
+    /// The number of bytes used by the mesh (including its bvh).
+    pub fn memory_usage(&self) -> usize {
+        self.mesh_data.memory_usage() + self.bvh.memory_usage()
+    }
+
+    /// The number of different attribute ids used by the triangles of the mesh.
+    pub fn num_attributes(&self) -> u32 {
+        self.mesh_data
+            .triangles
+            .iter()
+            .map(|tri| tri.attribute_id + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn get_data(&self) -> &MeshData {
+        &self.mesh_data
+    }
+
+    /// Sets how uvs are generated if the mesh doesn't have any.
+    pub fn set_uv_projection(&mut self, uv_projection: UvProjection) {
+        self.mesh_data.uv_projection = uv_projection;
+    }
+
+    /// Marks edges (pairs of vertex indices) that should stay sharp when subdividing.
+    pub fn set_creases(&mut self, creases: Vec<[u32; 2]>) {
+        self.mesh_data.creases = creases;
+    }
+
+    /// Returns a new mesh that has been refined `levels` times using Loop subdivision.
+    /// Boundary edges and creases are subdivided as cubic b-splines so they stay sharp.
+    /// Every level multiplies the triangle count by 4, so the number of levels is capped
+    /// to keep the result under `MAX_SUBDIVISION_TRIANGLES`.
+    pub fn subdivide(&self, levels: usize) -> Mesh {
+        let mut mesh_data = self.mesh_data.clone();
+        mesh_data.expand();
+        let mut level = 0;
+        while level < levels && mesh_data.triangles.len() * 4 <= MAX_SUBDIVISION_TRIANGLES {
+            mesh_data = mesh_data.loop_subdivide();
+            level += 1;
+        }
+        if level < levels {
+            eprintln!(
+                "Subdivision capped at {} levels instead of {} ({} triangles)",
+                level,
+                levels,
+                mesh_data.triangles.len()
+            );
+        }
+
+        let had_nrm = self.mesh_data.has_nrm();
+        let mut mesh = Mesh::new(
+            mesh_data.triangles,
+            mesh_data.pos,
+            Vec::new(),
+            Vec::new(),
+            mesh_data.uvs,
+            mesh_data.col,
+            self.max_triangles_per_leaf,
+            self.build_algorithm,
+        );
+        mesh.mesh_data.creases = mesh_data.creases;
+        if had_nrm {
+            mesh.compute_smooth_normals(180.0);
+        }
+        mesh
+    }
+
+    /// Computes area weighted vertex normals, replacing any normals the mesh may have had.
+    /// Faces whose normals differ by more than `angle_threshold_deg` don't share normals,
+    /// which keeps those edges hard (vertices along them are duplicated). Pass 180 to
+    /// smooth everything.
+    pub fn compute_smooth_normals(&mut self, angle_threshold_deg: f64) {
+        self.mesh_data.expand();
+        let mesh = &mut self.mesh_data;
+        let cos_threshold = angle_threshold_deg.to_radians().cos();
+
+        // The length of these normals is twice the area of the triangle:
+        let face_nrms: Vec<_> = mesh
+            .triangles
+            .iter()
+            .map(|tri| {
+                let pos = tri.pos(mesh);
+                (pos[1] - pos[0]).cross(pos[2] - pos[0])
+            })
+            .collect();
+        let unit_face_nrm = |face: usize| {
+            let n = face_nrms[face];
+            let len = n.length();
+            if len > 0.0 {
+                n.scale(1.0 / len)
+            } else {
+                Vec3::zero()
+            }
+        };
+
+        // All of the (face, corner) pairs that use a specific vertex:
+        let mut vert_faces = vec![Vec::new(); mesh.pos.len()];
+        for (face, tri) in mesh.triangles.iter().enumerate() {
+            for (corner, &index) in tri.indices.iter().enumerate() {
+                vert_faces[index as usize].push((face, corner));
+            }
+        }
+
+        let mut nrm = vec![Vec3::zero(); mesh.pos.len()];
+        let mut split = false;
+        for (vert, faces) in vert_faces.iter().enumerate() {
+            // The normals we've already assigned to copies of this vertex:
+            let mut assigned: Vec<(u32, Vec3<f64>)> = Vec::new();
+            for &(face, corner) in faces {
+                let face_nrm = unit_face_nrm(face);
+                let n = faces
+                    .iter()
+                    .filter(|&&(other, _)| face_nrm.dot(unit_face_nrm(other)) >= cos_threshold)
+                    .fold(Vec3::zero(), |n, &(other, _)| n + face_nrms[other]);
+                let n = if n.length2() > 0.0 {
+                    n.normalize()
+                } else if face_nrm.length2() > 0.0 {
+                    face_nrm
+                } else {
+                    Vec3 {
+                        x: 0.0,
+                        y: 0.0,
+                        z: 1.0,
+                    }
+                };
+
+                let index = match assigned.iter().find(|(_, other)| n.dot(*other) > 0.99999) {
+                    Some(&(index, _)) => index,
+                    None if assigned.is_empty() => {
+                        nrm[vert] = n.to_f32();
+                        vert as u32
+                    }
+                    None => {
+                        split = true;
+                        nrm.push(n.to_f32());
+                        mesh.duplicate_vertex(vert)
+                    }
+                };
+                if assigned.iter().all(|&(other, _)| other != index) {
+                    assigned.push((index, n));
+                }
+                mesh.triangles[face].indices[corner] = index;
+            }
+        }
+
+        mesh.nrm = nrm;
+        if split {
+            self.rebuild_bvh();
         }
     }
+
+    /// Computes per vertex tangents (and their handedness) from the positions and uvs,
+    /// replacing any tangents the mesh may have had. The tangents are orthogonalized
+    /// against the vertex normals (or the averaged face normals if the mesh has none).
+    /// Vertices whose uvs don't define a tangent get an arbitrary one.
+    pub fn compute_tangents(&mut self) {
+        self.mesh_data.expand();
+        let mesh = &mut self.mesh_data;
+        let num_verts = mesh.pos.len();
+
+        let mut tan = vec![Vec3::zero(); num_verts];
+        let mut bitan = vec![Vec3::zero(); num_verts];
+        let mut face_nrm = vec![Vec3::zero(); num_verts];
+        for tri in mesh.triangles.iter() {
+            let pos = tri.pos(mesh);
+            let dp1 = pos[1] - pos[0];
+            let dp2 = pos[2] - pos[0];
+            let n = dp1.cross(dp2);
+            for &index in tri.indices.iter() {
+                face_nrm[index as usize] = face_nrm[index as usize] + n;
+            }
+
+            if !mesh.has_uvs() {
+                continue;
+            }
+            let uvs = tri.uvs(mesh);
+            let duv1 = uvs[1] - uvs[0];
+            let duv2 = uvs[2] - uvs[0];
+            let det = duv1.x * duv2.y - duv2.x * duv1.y;
+            // Relative to the size of the uvs so that this doesn't depend on their scale:
+            let uv_scale = duv1.length2().max(duv2.length2());
+            if uv_scale == 0.0 || det.abs() <= 1e-12 * uv_scale {
+                continue;
+            }
+
+            // Not dividing by the magnitude of det weights this by the area of the triangle:
+            let t = (dp1.scale(duv2.y) - dp2.scale(duv1.y)).scale(det.signum());
+            let b = (dp2.scale(duv1.x) - dp1.scale(duv2.x)).scale(det.signum());
+            for &index in tri.indices.iter() {
+                tan[index as usize] = tan[index as usize] + t;
+                bitan[index as usize] = bitan[index as usize] + b;
+            }
+        }
+
+        let mut tan_sgn = vec![1.0; num_verts];
+        for i in 0..num_verts {
+            let n = if mesh.has_nrm() {
+                mesh.nrm[i].to_f64()
+            } else {
+                face_nrm[i]
+            };
+            if n.length2() == 0.0 {
+                tan[i] = Vec3 {
+                    x: 1.0,
+                    y: 0.0,
+                    z: 0.0,
+                };
+                continue;
+            }
+            let n = n.normalize();
+
+            // Gram-Schmidt:
+            let t = tan[i] - n.scale(n.dot(tan[i]));
+            let t = if t.length2() > 1e-20 {
+                t.normalize()
+            } else {
+                pmath::coord_system(n).0.normalize()
+            };
+
+            if n.cross(t).dot(bitan[i]) < 0.0 {
+                tan_sgn[i] = -1.0;
+            }
+            tan[i] = t;
+        }
+
+        mesh.tan = tan.into_iter().map(|t| t.to_f32()).collect();
+        mesh.tan_sgn = tan_sgn;
+    }
+
+    /// Merges vertices that are within `epsilon` of each other (averaging their attributes)
+    /// and removes triangles that end up degenerate: those whose indices collapse or whose
+    /// area is smaller than `epsilon` squared.
+    pub fn weld(&mut self, epsilon: f64) -> WeldReport {
+        self.mesh_data.expand();
+        let mesh = &mut self.mesh_data;
+        let num_verts = mesh.pos.len();
+        let num_tris = mesh.triangles.len();
+
+        // Hash the vertices into a grid with cells of size epsilon, so we only ever have
+        // to check the neighboring cells for duplicates:
+        let inv_cell = if epsilon > 0.0 { 1.0 / epsilon } else { 0.0 };
+        let cell_of = |p: Vec3<f64>| {
+            (
+                (p.x * inv_cell).floor() as i64,
+                (p.y * inv_cell).floor() as i64,
+                (p.z * inv_cell).floor() as i64,
+            )
+        };
+        let mut grid: HashMap<(i64, i64, i64), Vec<u32>> = HashMap::new();
+
+        // The new index of every old vertex and how many old vertices each new one merges:
+        let mut remap = Vec::with_capacity(num_verts);
+        let mut merged: Vec<usize> = Vec::new();
+        let mut pos = Vec::new();
+        for i in 0..num_verts {
+            let p = mesh.pos[i].to_f64();
+            let cell = cell_of(p);
+
+            let mut found = None;
+            'search: for x in -1..=1 {
+                for y in -1..=1 {
+                    for z in -1..=1 {
+                        let neighbor = (cell.0 + x, cell.1 + y, cell.2 + z);
+                        if let Some(candidates) = grid.get(&neighbor) {
+                            for &candidate in candidates {
+                                let first = mesh.pos[candidate as usize].to_f64();
+                                if (first - p).length() <= epsilon {
+                                    found = Some(candidate);
+                                    break 'search;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            // The grid stores the old index of the first vertex of each group:
+            let index = match found {
+                Some(first) => remap[first as usize],
+                None => {
+                    grid.entry(cell).or_insert_with(Vec::new).push(i as u32);
+                    merged.push(0);
+                    pos.push(Vec3::zero());
+                    pos.len() as u32 - 1
+                }
+            };
+            merged[index as usize] += 1;
+            pos[index as usize] = pos[index as usize] + p;
+            remap.push(index);
+        }
+
+        let num_new_verts = pos.len();
+        let average3 = |old: &[Vec3<f32>]| {
+            let mut new = vec![Vec3::zero(); num_new_verts];
+            for (i, v) in old.iter().enumerate() {
+                let index = remap[i] as usize;
+                new[index] = new[index] + v.to_f64();
+            }
+            new.iter()
+                .zip(merged.iter())
+                .map(|(v, &count)| v.scale(1.0 / (count as f64)).to_f32())
+                .collect::<Vec<_>>()
+        };
+
+        mesh.pos = pos
+            .iter()
+            .zip(merged.iter())
+            .map(|(p, &count)| p.scale(1.0 / (count as f64)).to_f32())
+            .collect();
+        if mesh.has_nrm() {
+            mesh.nrm = average3(&mesh.nrm)
+                .into_iter()
+                .map(|n| {
+                    let n = n.to_f64();
+                    if n.length2() > 0.0 {
+                        n.normalize().to_f32()
+                    } else {
+                        n.to_f32()
+                    }
+                })
+                .collect();
+        }
+        if mesh.has_tan() {
+            mesh.tan = average3(&mesh.tan);
+        }
+        if mesh.has_col() {
+            mesh.col = average3(&mesh.col);
+        }
+        if mesh.has_uvs() {
+            let mut uvs = vec![Vec2::zero(); num_new_verts];
+            for (i, uv) in mesh.uvs.iter().enumerate() {
+                let index = remap[i] as usize;
+                uvs[index] = uvs[index] + uv.to_f64();
+            }
+            mesh.uvs = uvs
+                .iter()
+                .zip(merged.iter())
+                .map(|(uv, &count)| uv.scale(1.0 / count as f64).to_f32())
+                .collect();
+        }
+        if !mesh.tan_sgn.is_empty() {
+            let mut tan_sgn = vec![1.0; num_new_verts];
+            for (i, &sgn) in mesh.tan_sgn.iter().enumerate() {
+                tan_sgn[remap[i] as usize] = sgn;
+            }
+            mesh.tan_sgn = tan_sgn;
+        }
+
+        let min_area = epsilon * epsilon;
+        let mut triangles = std::mem::take(&mut mesh.triangles);
+        for tri in triangles.iter_mut() {
+            for index in tri.indices.iter_mut() {
+                *index = remap[*index as usize];
+            }
+        }
+        triangles.retain(|tri| {
+            let [a, b, c] = tri.indices;
+            a != b && b != c && a != c && tri.area(mesh) > min_area
+        });
+        mesh.triangles = triangles;
+
+        for crease in mesh.creases.iter_mut() {
+            *crease = [remap[crease[0] as usize], remap[crease[1] as usize]];
+        }
+        mesh.creases.retain(|crease| crease[0] != crease[1]);
+
+        let report = WeldReport {
+            vertices_removed: num_verts - num_new_verts,
+            triangles_removed: num_tris - mesh.triangles.len(),
+        };
+
+        self.surface_area = -1.0;
+        self.area_cdf.clear();
+        self.rebuild_bvh();
+        report
+    }
+
+    /// Bakes the transformation into the mesh: the positions, normals (with the inverse transpose), and
+    /// tangents are transformed, and the bvh is constructed again. Transformations that mirror the mesh
+    /// also flip the winding of the triangles (and the handedness of the tangents), so that the normals
+    /// implied by the winding still agree with the vertex normals.
+    ///
+    /// Meshes are shared with the scene through an `Arc`, so this has to happen before the mesh is added
+    /// to a scene (which is then never left with a stale bvh).
+    pub fn transform(&mut self, transf: Transf) {
+        let was_compact = self.mesh_data.compact.is_some();
+        self.mesh_data.expand();
+        let mesh = &mut self.mesh_data;
+
+        transf.points_f32(&mut mesh.pos);
+        transf.normals_f32(&mut mesh.nrm);
+        transf.vectors_f32(&mut mesh.tan);
+        for t in mesh.tan.iter_mut() {
+            *t = t.normalize();
+        }
+        if transf.get_frd().determinant() < 0.0 {
+            for triangle in mesh.triangles.iter_mut() {
+                triangle.indices.swap(1, 2);
+            }
+            for sgn in mesh.tan_sgn.iter_mut() {
+                *sgn = -*sgn;
+            }
+        }
+
+        if was_compact {
+            self.mesh_data.compact();
+        }
+        self.surface_area = -1.0;
+        self.area_cdf.clear();
+        self.rebuild_bvh();
+    }
+
+    /// Replaces the positions of the vertices (for deforming meshes), refitting the bvh
+    /// instead of rebuilding it. Use `bvh_sah_cost` to decide when `rebuild` is worth it.
+    pub fn update_positions(&mut self, pos: Vec<Vec3<f32>>) {
+        self.mesh_data.expand();
+        assert_eq!(pos.len(), self.mesh_data.pos.len());
+
+        self.mesh_data.pos = pos;
+        self.surface_area = -1.0;
+        self.area_cdf.clear();
+        self.bvh.refit(&self.mesh_data);
+    }
+
+    pub fn bvh_sah_cost(&self) -> f64 {
+        self.bvh.sah_cost()
+    }
+
+    /// Rebuilds the bvh from scratch.
+    pub fn rebuild(&mut self) {
+        self.rebuild_bvh();
+    }
+
+    fn rebuild_bvh(&mut self) {
+        self.bvh = BVH::build(
+            &self.mesh_data.triangles,
+            self.max_triangles_per_leaf,
+            &self.mesh_data,
+            self.build_algorithm,
+        );
+    }
 }
 
 impl Geometry for Mesh {
-    fn intersect(&self, ray: Ray<f64>) -> Option<Surface> {
-        // Calculate the ray information:
-        self.bvh.intersect(ray, &self.mesh_data)
+    fn intersect(&self, ray: Ray<f64>) -> Option<Interaction> {
+        self.intersect_excluding(ray, None)
     }
 
     fn intersect_test(&self, ray: Ray<f64>) -> bool {
         self.bvh.intersect_test(ray, &self.mesh_data)
     }
 
+    fn intersect_excluding(&self, ray: Ray<f64>, prim: Option<u32>) -> Option<Interaction> {
+        self.bvh
+            .intersect_excluding(ray, &self.mesh_data, prim)
+            .map(|(interaction, prim)| Interaction {
+                prim,
+                ..interaction
+            })
+    }
+
+    fn intersect_test_excluding(&self, ray: Ray<f64>, prim: Option<u32>) -> bool {
+        self.bvh
+            .intersect_test_excluding(ray, &self.mesh_data, prim)
+    }
+
+    fn interaction_at(
+        &self,
+        ray: Ray<f64>,
+        prim: u32,
+        t: f64,
+        bary: Vec2<f64>,
+    ) -> Option<Interaction> {
+        let triangle = self.mesh_data.triangles.get(prim as usize)?;
+        let b = [1.0 - bary.x - bary.y, bary.x, bary.y];
+        Some(Interaction {
+            prim,
+            ..triangle.interaction(ray, t, b, &self.mesh_data)
+        })
+    }
+
+    fn intersect_all(
+        &self,
+        ray: Ray<f64>,
+        f: &mut dyn FnMut(Interaction) -> TraversalControl,
+    ) -> TraversalControl {
+        self.bvh
+            .intersect_all(ray, &self.mesh_data, &mut |interaction| f(interaction))
+    }
+
     fn get_surface_area(&self) -> f64 {
         self.surface_area
     }
@@ -464,16 +1662,711 @@ impl Geometry for Mesh {
             return self.surface_area;
         }
 
-        //let mesh_ref = self.get_ref();
-        self.surface_area = self
-            .mesh_data
+        let mesh_data = &self.mesh_data;
+        self.area_cdf = mesh_data
             .triangles
             .iter()
-            .fold(0.0, |sa, triangle| sa + triangle.area(&self.mesh_data));
+            .scan(0.0, |sa, triangle| {
+                *sa += triangle.area(mesh_data);
+                Some(*sa)
+            })
+            .collect();
+        self.surface_area = self.area_cdf.last().copied().unwrap_or(0.0);
         self.surface_area
     }
 
     fn get_bbox(&self) -> BBox3<f64> {
         self.bvh.get_bbox()
     }
+
+    fn num_triangles(&self) -> usize {
+        Mesh::num_triangles(self)
+    }
+
+    fn num_vertices(&self) -> usize {
+        Mesh::num_vertices(self)
+    }
+
+    fn memory_usage(&self) -> usize {
+        Mesh::memory_usage(self)
+    }
+}
+
+impl SampleableGeometry for Mesh {
+    /// Picks a triangle proportional to its area, and then a point on it uniformly.
+    ///
+    /// # Panics
+    /// If `calc_surface_area` wasn't called first.
+    fn sample_surface(&self, u: Vec2<f64>) -> SurfaceSample {
+        assert!(
+            self.surface_area >= 0.0,
+            "calc_surface_area has to be called before sampling the mesh"
+        );
+
+        let target = u.x * self.surface_area;
+        let index = self
+            .area_cdf
+            .partition_point(|&sa| sa <= target)
+            .min(self.area_cdf.len() - 1);
+        // Reuse the part of u.x that wasn't needed to pick the triangle:
+        let sa_start = if index == 0 {
+            0.0
+        } else {
+            self.area_cdf[index - 1]
+        };
+        let sa_triangle = self.area_cdf[index] - sa_start;
+        let ux = if sa_triangle > 0.0 {
+            ((target - sa_start) / sa_triangle).min(1.0)
+        } else {
+            0.0
+        };
+
+        let triangle = self.mesh_data.triangles[index];
+        let pos = triangle.pos(&self.mesh_data);
+        let b = pmath::sampling::uniform_sample_triangle(Vec2 { x: ux, y: u.y });
+        SurfaceSample {
+            p: pos[0].scale(b.x) + pos[1].scale(b.y) + pos[2].scale(1.0 - b.x - b.y),
+            n: (pos[1] - pos[0]).cross(pos[2] - pos[0]).normalize(),
+            pdf: 1.0 / self.surface_area,
+        }
+    }
+
+    /// Transforms every triangle, so this is exact for any transformation.
+    fn world_surface_area(&self, transf: Transf) -> f64 {
+        self.mesh_data
+            .triangles
+            .iter()
+            .map(|triangle| {
+                let pos = triangle.pos(&self.mesh_data);
+                let a = transf.vector(pos[1] - pos[0]);
+                let b = transf.vector(pos[2] - pos[0]);
+                a.cross(b).length() * 0.5
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::{Scene, SceneGeom, ScenePrim};
+    use crate::shading::material::{MaterialPool, DEFAULT_MATERIAL_ID};
+    use std::sync::Arc;
+
+    fn vec3(x: f32, y: f32, z: f32) -> Vec3<f32> {
+        Vec3 { x, y, z }
+    }
+
+    fn tri(indices: [u32; 3]) -> Triangle {
+        Triangle {
+            indices,
+            attribute_id: 0,
+        }
+    }
+
+    fn mesh_from(pos: Vec<Vec3<f32>>, triangles: Vec<Triangle>) -> Mesh {
+        Mesh::new(
+            triangles,
+            pos,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            4,
+            BuildAlgorithm::Sah,
+        )
+    }
+
+    #[test]
+    fn smooth_normals_of_a_flat_mesh_are_the_face_normal() {
+        let mut mesh = mesh_from(
+            vec![
+                vec3(0.0, 0.0, 0.0),
+                vec3(1.0, 0.0, 0.0),
+                vec3(1.0, 1.0, 0.0),
+                vec3(0.0, 1.0, 0.0),
+            ],
+            vec![tri([0, 1, 2]), tri([0, 2, 3])],
+        );
+        mesh.compute_smooth_normals(180.0);
+
+        let data = mesh.get_data();
+        assert!(data.has_nrm());
+        for i in 0..(data.num_vertices() as u32) {
+            let n = data.nrm_at(i).to_f64();
+            assert!(
+                (n - Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0
+                })
+                .length()
+                    < 1e-6,
+                "{:?}",
+                n
+            );
+        }
+    }
+
+    #[test]
+    fn welding_averages_the_merged_vertices() {
+        // Two triangles whose shared edge is slightly apart:
+        let mut mesh = mesh_from(
+            vec![
+                vec3(0.0, 0.0, 0.0),
+                vec3(1.0, 0.0, 0.0),
+                vec3(1.0, 1.0, 0.0),
+                vec3(0.0, 0.0, 0.002),
+                vec3(1.0, 1.0, 0.002),
+                vec3(0.0, 1.0, 0.0),
+            ],
+            vec![tri([0, 1, 2]), tri([3, 4, 5])],
+        );
+        let report = mesh.weld(0.01);
+        assert_eq!(report.vertices_removed, 2);
+        assert_eq!(report.triangles_removed, 0);
+
+        let data = mesh.get_data();
+        assert_eq!(data.num_vertices(), 4);
+        let mut zs: Vec<f32> = (0..4).map(|i| data.pos_at(i).z).collect();
+        zs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for (z, expected) in zs.iter().zip([0.0, 0.0, 0.001, 0.001].iter()) {
+            assert!((z - expected).abs() < 1e-6, "{:?}", zs);
+        }
+    }
+
+    #[test]
+    fn vertex_normals_are_only_used_if_present() {
+        let pos = vec![
+            vec3(0.0, 0.0, 0.0),
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.0, 1.0, 0.0),
+        ];
+        let ray = Ray::new(
+            Vec3 {
+                x: 0.25,
+                y: 0.25,
+                z: 1.0,
+            },
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+            0.0,
+        );
+
+        // Without normals, the geometric normal is used:
+        let flat = mesh_from(pos.clone(), vec![tri([0, 1, 2])]);
+        let interaction = flat.mesh_data.triangles[0]
+            .intersect(ray, &flat.mesh_data)
+            .unwrap();
+        assert!((interaction.shading_n() - interaction.n).length() < 1e-9);
+        assert_eq!(interaction.shadow_p(), interaction.p);
+
+        // Normals that tilt outwards (like those of a sphere) are interpolated, and the shadow point is
+        // moved outwards:
+        let mut smooth = mesh_from(pos, vec![tri([0, 1, 2])]);
+        smooth.mesh_data.nrm = vec![
+            vec3(-1.0, -1.0, 2.0).normalize(),
+            vec3(1.0, 0.0, 2.0).normalize(),
+            vec3(0.0, 1.0, 2.0).normalize(),
+        ];
+        let interaction = smooth.mesh_data.triangles[0]
+            .intersect(ray, &smooth.mesh_data)
+            .unwrap();
+        let sn = interaction.shading_n();
+        assert!((sn.length() - 1.0).abs() < 1e-6);
+        assert!(sn.z > 0.0 && sn.z < 1.0 - 1e-3, "{:?}", sn);
+        assert!(interaction.shadow_p().z > interaction.p.z);
+    }
+
+    /// A unit sphere with `n` rings and `2 * n` segments, with normals and uvs. The vertices along the
+    /// seam are duplicated (so that the uvs wrap around).
+    fn uv_sphere(n: u32) -> Mesh {
+        let mut pos = Vec::new();
+        let mut uvs = Vec::new();
+        for ring in 0..=n {
+            for segment in 0..=(2 * n) {
+                let u = (segment as f32) / ((2 * n) as f32);
+                let v = (ring as f32) / (n as f32);
+                let (phi, theta) = (u * 2.0 * std::f32::consts::PI, v * std::f32::consts::PI);
+                pos.push(vec3(
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                    theta.cos(),
+                ));
+                uvs.push(Vec2 { x: u, y: v });
+            }
+        }
+        let mut triangles = Vec::new();
+        let row = 2 * n + 1;
+        for ring in 0..n {
+            for segment in 0..(2 * n) {
+                let i = ring * row + segment;
+                triangles.push(tri([i, i + row, i + 1]));
+                triangles.push(tri([i + 1, i + row, i + row + 1]));
+            }
+        }
+        Mesh::new(
+            triangles,
+            pos.clone(),
+            pos,
+            Vec::new(),
+            uvs,
+            Vec::new(),
+            4,
+            BuildAlgorithm::Sah,
+        )
+    }
+
+    #[test]
+    fn tangents_of_a_uv_sphere_follow_the_uvs() {
+        let n = 16;
+        let mut mesh = uv_sphere(n);
+        mesh.compute_tangents();
+        let data = &mesh.mesh_data;
+        for (i, (&t, &p)) in data.tan.iter().zip(data.pos.iter()).enumerate() {
+            let (t, n) = (t.to_f64(), p.to_f64());
+            assert!((t.length() - 1.0).abs() < 1e-4);
+            assert!(t.dot(n).abs() < 1e-4);
+            // Away from the poles, the tangent points along increasing u (around the z-axis):
+            let xy_length = (n.x * n.x + n.y * n.y).sqrt();
+            if xy_length > 0.1 {
+                let du = Vec3 {
+                    x: -n.y / xy_length,
+                    y: n.x / xy_length,
+                    z: 0.0,
+                };
+                assert!(t.dot(du) > 0.99, "vertex {}: {:?}", i, t);
+                assert_eq!(data.tan_sgn[i], data.tan_sgn[0]);
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_uvs_get_an_arbitrary_tangent() {
+        let mut mesh = Mesh::new(
+            vec![tri([0, 1, 2])],
+            vec![
+                vec3(0.0, 0.0, 0.0),
+                vec3(1.0, 0.0, 0.0),
+                vec3(0.0, 1.0, 0.0),
+            ],
+            Vec::new(),
+            Vec::new(),
+            vec![Vec2 { x: 0.5, y: 0.5 }; 3],
+            Vec::new(),
+            4,
+            BuildAlgorithm::Sah,
+        );
+        mesh.compute_tangents();
+        for &t in &mesh.mesh_data.tan {
+            let t = t.to_f64();
+            assert!(!t.x.is_nan() && !t.y.is_nan() && !t.z.is_nan());
+            assert!((t.length() - 1.0).abs() < 1e-4);
+            assert!(t.z.abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn subdivided_icosahedron_converges_to_a_sphere() {
+        let g = (1.0 + 5f32.sqrt()) / 2.0;
+        let pos = vec![
+            vec3(-1.0, g, 0.0),
+            vec3(1.0, g, 0.0),
+            vec3(-1.0, -g, 0.0),
+            vec3(1.0, -g, 0.0),
+            vec3(0.0, -1.0, g),
+            vec3(0.0, 1.0, g),
+            vec3(0.0, -1.0, -g),
+            vec3(0.0, 1.0, -g),
+            vec3(g, 0.0, -1.0),
+            vec3(g, 0.0, 1.0),
+            vec3(-g, 0.0, -1.0),
+            vec3(-g, 0.0, 1.0),
+        ];
+        let faces = [
+            [0, 11, 5],
+            [0, 5, 1],
+            [0, 1, 7],
+            [0, 7, 10],
+            [0, 10, 11],
+            [1, 5, 9],
+            [5, 11, 4],
+            [11, 10, 2],
+            [10, 7, 6],
+            [7, 1, 8],
+            [3, 9, 4],
+            [3, 4, 2],
+            [3, 2, 6],
+            [3, 6, 8],
+            [3, 8, 9],
+            [4, 9, 5],
+            [2, 4, 11],
+            [6, 2, 10],
+            [8, 6, 7],
+            [9, 8, 1],
+        ];
+        let icosahedron = mesh_from(pos, faces.iter().map(|&f| tri(f)).collect());
+
+        // How far the surface (its vertices and the centers of its triangles) is from a sphere (relative
+        // to its radius):
+        let deviation = |mesh: &Mesh| {
+            let data = &mesh.mesh_data;
+            let centers = data.triangles.iter().map(|tri| {
+                let [p0, p1, p2] = tri.pos(data);
+                (p0 + p1 + p2).scale(1.0 / 3.0)
+            });
+            let radii: Vec<f64> = data
+                .pos
+                .iter()
+                .map(|p| p.to_f64())
+                .chain(centers)
+                .map(|p| p.length())
+                .collect();
+            let mean = radii.iter().sum::<f64>() / (radii.len() as f64);
+            radii
+                .iter()
+                .fold(0.0f64, |max, &r| max.max((r - mean).abs()))
+                / mean
+        };
+        let mut last_deviation = deviation(&icosahedron);
+        for levels in 1..=4 {
+            let subdivided = icosahedron.subdivide(levels);
+            assert_eq!(subdivided.num_triangles(), 20 * 4usize.pow(levels as u32));
+            let deviation = deviation(&subdivided);
+            assert!(
+                deviation < last_deviation,
+                "level {}: {}",
+                levels,
+                deviation
+            );
+            last_deviation = deviation;
+        }
+        // The limit surface is close to a sphere, but not exactly one:
+        assert!(last_deviation < 0.025);
+    }
+
+    #[test]
+    fn hits_far_from_the_origin_are_within_their_error_bounds() {
+        use rand::{Rng, SeedableRng};
+
+        let mesh = mesh_from(
+            vec![
+                vec3(1.0e6, 1.0e6, 1.0e6),
+                vec3(1.0e6 + 3.0, 1.0e6 + 0.5, 1.0e6 - 1.0),
+                vec3(1.0e6 + 0.25, 1.0e6 + 2.0, 1.0e6 + 1.5),
+            ],
+            vec![tri([0, 1, 2])],
+        );
+        let [p0, p1, p2] = mesh.mesh_data.triangles[0].pos(&mesh.mesh_data);
+        let n = (p1 - p0).cross(p2 - p0).normalize();
+
+        let mut rng = rand_pcg::Pcg32::seed_from_u64(3);
+        let mut num_hits = 0;
+        for _ in 0..1000 {
+            let (b1, b2): (f64, f64) = (rng.gen(), rng.gen());
+            let (b1, b2) = if b1 + b2 > 1.0 {
+                (1.0 - b1, 1.0 - b2)
+            } else {
+                (b1, b2)
+            };
+            let target = p0 + (p1 - p0).scale(b1) + (p2 - p0).scale(b2);
+            let side = if rng.gen::<bool>() { 1.0 } else { -1.0 };
+            let org = target
+                + n.scale(side * 10.0)
+                + Vec3 {
+                    x: rng.gen_range(-5.0, 5.0),
+                    y: rng.gen_range(-5.0, 5.0),
+                    z: rng.gen_range(-5.0, 5.0),
+                };
+            let int = match mesh.intersect(Ray::new(org, (target - org).normalize(), 0.0)) {
+                Some(int) => int,
+                None => continue,
+            };
+            num_hits += 1;
+            let (p, p_error) = (int.p.cast::<f64>(), int.p_error.cast::<f64>());
+
+            // The plane of the triangle passes through the bounds of the point:
+            let distance = n.dot(p - p0).abs();
+            assert!(distance <= n.abs().dot(p_error) * 1.01);
+
+            // Offsetting the point by the bounds keeps rays that leave the surface from hitting it again:
+            let offset = n.scale(side * n.abs().dot(p_error));
+            let dir = (n.scale(side)
+                + Vec3 {
+                    x: rng.gen_range(-1.0, 1.0),
+                    y: rng.gen_range(-1.0, 1.0),
+                    z: rng.gen_range(-1.0, 1.0),
+                })
+            .normalize();
+            if dir.dot(n.scale(side)) > 0.0 {
+                assert!(mesh.intersect(Ray::new(p + offset, dir, 0.0)).is_none());
+            }
+        }
+        assert!(num_hits > 900);
+    }
+
+    #[test]
+    fn small_uvs_arent_degenerate() {
+        let dpdu = |uv_scale: f32| {
+            let mesh = Mesh::new(
+                vec![tri([0, 1, 2])],
+                vec![
+                    vec3(0.0, 0.0, 0.0),
+                    vec3(1.0, 0.0, 0.0),
+                    vec3(0.0, 1.0, 0.0),
+                ],
+                Vec::new(),
+                Vec::new(),
+                vec![
+                    Vec2 { x: 0.0, y: 0.0 },
+                    Vec2 {
+                        x: uv_scale,
+                        y: 0.0,
+                    },
+                    Vec2 {
+                        x: 0.0,
+                        y: uv_scale,
+                    },
+                ],
+                Vec::new(),
+                4,
+                BuildAlgorithm::Sah,
+            );
+            let ray = Ray::new(
+                Vec3 {
+                    x: 0.25,
+                    y: 0.25,
+                    z: -1.0,
+                },
+                Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0,
+                },
+                0.0,
+            );
+            mesh.intersect(ray).unwrap().dpdu()
+        };
+        let expected = Vec3 {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        for &uv_scale in &[1.0, 1.0e-3, 1.0e-5] {
+            let dpdu = dpdu(uv_scale);
+            assert!((dpdu.scale(uv_scale as Scalar) - expected).length() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn baked_transforms_match_scene_transforms() {
+        let material = MaterialPool::new().get_shared_material(DEFAULT_MATERIAL_ID);
+        let scene = |mesh: Mesh, transf: Transf| {
+            let geom = SceneGeom::new_material(Arc::new(mesh), material.clone(), transf);
+            Scene::new(&[Arc::new(geom) as Arc<dyn ScenePrim>], Vec::new())
+        };
+        let offset = Transf::new_translate(Vec3 {
+            x: 3.0,
+            y: -1.0,
+            z: 2.0,
+        });
+        // A non-uniform scale, and one that mirrors the mesh (and flips its winding):
+        let transfs = [
+            offset
+                * Transf::new_scale(Vec3 {
+                    x: 2.0,
+                    y: 1.0,
+                    z: 0.5,
+                }),
+            offset
+                * Transf::new_scale(Vec3 {
+                    x: -1.0,
+                    y: 1.5,
+                    z: 1.0,
+                }),
+        ];
+        // Without vertex normals, the normals come from the winding of the triangles:
+        let sphere = |with_normals: bool| {
+            let sphere = uv_sphere(16);
+            if with_normals {
+                sphere
+            } else {
+                mesh_from(sphere.mesh_data.pos, sphere.mesh_data.triangles)
+            }
+        };
+        for &transf in transfs.iter() {
+            for &with_normals in &[true, false] {
+                let mut baked = sphere(with_normals);
+                baked.transform(transf);
+                let baked = scene(baked, Transf::new_identity());
+                let instanced = scene(sphere(with_normals), transf);
+
+                let mut num_hits = 0;
+                for y in 0..20 {
+                    for x in 0..20 {
+                        let ray = Ray::new(
+                            Vec3 {
+                                x: 0.53 + (x as Scalar) * 0.25,
+                                y: -3.47 + (y as Scalar) * 0.25,
+                                z: -10.0,
+                            },
+                            Vec3 {
+                                x: 0.0,
+                                y: 0.0,
+                                z: 1.0,
+                            },
+                            0.0,
+                        );
+                        match (baked.intersect(ray), instanced.intersect(ray)) {
+                            (Some(a), Some(b)) => {
+                                assert!((a.t - b.t).abs() < 1e-4, "{} {}", a.t, b.t);
+                                assert!((a.n - b.n).length() < 1e-4, "{:?} {:?}", a.n, b.n);
+                                // The baked vertex normals are normalized before they are interpolated, so
+                                // they differ slightly with a non-uniform scale:
+                                let (sn_a, sn_b) = (a.shading_n(), b.shading_n());
+                                assert!(sn_a.dot(sn_b) > 0.999, "{:?} {:?}", sn_a, sn_b);
+                                // The normals face away from the center of the sphere:
+                                let out = a.p - offset.point(Vec3::zero()).cast();
+                                assert!(a.n.dot(out) > 0.0 && sn_a.dot(out) > 0.0);
+                                num_hits += 1;
+                            }
+                            (None, None) => (),
+                            (a, b) => panic!("{:?} {:?}", a.map(|a| a.p), b.map(|b| b.p)),
+                        }
+                    }
+                }
+                assert!(num_hits > 50);
+            }
+        }
+    }
+
+    #[test]
+    fn refitted_meshes_hit_the_same_triangles_as_rebuilt_ones() {
+        // A grid in the xy-plane that is then bent into a wave:
+        let n = 20;
+        let mut pos = Vec::new();
+        for y in 0..=n {
+            for x in 0..=n {
+                pos.push(vec3(x as f32, y as f32, 0.0));
+            }
+        }
+        let mut triangles = Vec::new();
+        for y in 0..n {
+            for x in 0..n {
+                let i = y * (n + 1) + x;
+                triangles.push(tri([i, i + 1, i + n + 2]));
+                triangles.push(tri([i, i + n + 2, i + n + 1]));
+            }
+        }
+        let mut refitted = mesh_from(pos.clone(), triangles.clone());
+        let deformed: Vec<_> = pos
+            .iter()
+            .map(|p| vec3(p.x, p.y, (p.x * 0.5).sin() * 2.0 + p.y * 0.1))
+            .collect();
+        refitted.update_positions(deformed.clone());
+        let rebuilt = mesh_from(deformed, triangles);
+        assert!(refitted.bvh_sah_cost().is_finite());
+
+        let mut num_hits = 0;
+        for y in 0..40 {
+            for x in 0..40 {
+                let org = Vec3 {
+                    x: (x as f64) * 0.5 + 0.1,
+                    y: (y as f64) * 0.5 + 0.2,
+                    z: -10.0,
+                };
+                let ray = Ray::new(
+                    org,
+                    Vec3 {
+                        x: 0.05,
+                        y: 0.0,
+                        z: 1.0,
+                    }
+                    .normalize(),
+                    0.0,
+                );
+                let hit = |mesh: &Mesh| mesh.intersect(ray).map(|int| (int.prim, int.t));
+                assert_eq!(hit(&refitted), hit(&rebuilt));
+                num_hits += hit(&rebuilt).is_some() as u32;
+            }
+        }
+        assert!(num_hits > 1000);
+    }
+
+    #[test]
+    fn sah_cost_of_the_uv_sphere_is_unchanged() {
+        let stats = uv_sphere(32).bvh.stats();
+        assert_eq!(stats.num_objects, 2 * 32 * 64);
+        assert_eq!(stats.num_nodes, 2 * stats.num_leaves - 1);
+        assert_eq!(
+            stats.leaf_depth_histogram.iter().sum::<usize>(),
+            stats.num_leaves
+        );
+        assert!((stats.sah_cost - uv_sphere(32).bvh_sah_cost()).abs() < 1e-9);
+        // If this changes, the build produces a different (hopefully better) tree:
+        assert!(
+            (stats.sah_cost - 28.375420).abs() < 1e-5,
+            "{}",
+            stats.sah_cost
+        );
+    }
+
+    #[test]
+    fn cached_bvhs_give_the_same_hits_and_are_invalidated_by_changes() {
+        let path = std::env::temp_dir().join(format!("prism_bvh_cache_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let sphere = uv_sphere(16);
+        let new_cached = |pos: Vec<Vec3<f32>>| {
+            Mesh::new_cached(
+                sphere.mesh_data.triangles.clone(),
+                pos,
+                Vec::new(),
+                Vec::new(),
+                Vec::new(),
+                Vec::new(),
+                4,
+                BuildAlgorithm::Sah,
+                path,
+            )
+        };
+
+        // The first mesh writes the cache and the second one reads it:
+        let built = new_cached(sphere.mesh_data.pos.clone());
+        let key = built.mesh_data.bvh_cache_key(4, BuildAlgorithm::Sah);
+        let cached = read_bvh_cache(path, key, &built.mesh_data.triangles).unwrap();
+        for i in 0..100 {
+            let angle = (i as f64) * 0.1;
+            let ray = Ray::new(
+                Vec3 {
+                    x: 3.0 * angle.cos(),
+                    y: 3.0 * angle.sin(),
+                    z: 0.5 - 0.01 * (i as f64),
+                },
+                Vec3 {
+                    x: -angle.cos(),
+                    y: -angle.sin(),
+                    z: 0.0,
+                },
+                0.0,
+            );
+            let hit = |bvh: &BVH<Triangle>| {
+                bvh.intersect(ray, &built.mesh_data)
+                    .map(|interaction| (interaction.t, interaction.prim))
+            };
+            assert_eq!(hit(&cached), hit(&built.bvh));
+        }
+
+        // Moving a vertex changes the key, so the old cache is ignored and overwritten:
+        let mut pos = sphere.mesh_data.pos.clone();
+        pos[40].x += 0.25;
+        let moved = new_cached(pos);
+        let moved_key = moved.mesh_data.bvh_cache_key(4, BuildAlgorithm::Sah);
+        assert_ne!(moved_key, key);
+        assert!(read_bvh_cache(path, key, &moved.mesh_data.triangles).is_none());
+        assert!(read_bvh_cache(path, moved_key, &moved.mesh_data.triangles).is_some());
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -1,16 +1,63 @@
+pub mod cylinder;
+pub mod disk;
 pub mod mesh;
+pub mod rect;
+pub mod sphere;
 
-use crate::interaction::Interaction;
+use crate::bvh::TraversalControl;
+use crate::interaction::{self, GeomIntr, Interaction, IntrType};
+use crate::transform::Transf;
+use crate::Scalar;
 use pmath;
 use pmath::bbox::BBox3;
+use pmath::numbers::Float;
 use pmath::ray::Ray;
+use pmath::vector::{Vec2, Vec3};
 
 /// A geometry is something that can be intersected in the scene.
-pub trait Geometry: Sync + 'static {
+pub trait Geometry: Send + Sync + 'static {
     /// Perform the different intersections and whatnot:
     fn intersect(&self, ray: Ray<f64>) -> Option<Interaction>;
     fn intersect_test(&self, ray: Ray<f64>) -> bool;
 
+    /// Same as `intersect`, but never hits the primitive `prim` (see `Interaction::prim`), which is the
+    /// primitive a ray starts on. Only flat primitives can't be hit again by a ray that leaves them, so
+    /// by default nothing is skipped.
+    fn intersect_excluding(&self, ray: Ray<f64>, _prim: Option<u32>) -> Option<Interaction> {
+        self.intersect(ray)
+    }
+
+    /// Same as `intersect_test`, but never hits the primitive `prim` (see `intersect_excluding`).
+    fn intersect_test_excluding(&self, ray: Ray<f64>, _prim: Option<u32>) -> bool {
+        self.intersect_test(ray)
+    }
+
+    /// Constructs the interaction of the ray with the primitive `prim` at `t`, where `bary` are the
+    /// barycentric coordinates of the hit (see `Interaction::bary`), without searching for the hit again.
+    /// Analytic shapes are a single primitive, so by default they are simply intersected again.
+    fn interaction_at(
+        &self,
+        ray: Ray<f64>,
+        _prim: u32,
+        _t: f64,
+        _bary: Vec2<f64>,
+    ) -> Option<Interaction> {
+        self.intersect(ray)
+    }
+
+    /// Calls `f` for every intersection along the ray (in any order) until it returns `TraversalControl::Stop`.
+    /// By default, only the closest intersection is reported.
+    fn intersect_all(
+        &self,
+        ray: Ray<f64>,
+        f: &mut dyn FnMut(Interaction) -> TraversalControl,
+    ) -> TraversalControl {
+        match self.intersect(ray) {
+            Some(interaction) => f(interaction),
+            None => TraversalControl::Continue,
+        }
+    }
+
     /// Returns the surface area. If `calc_surface_area` wasn't called yet, or if a transform was applied that would
     /// change this, return -1.0.
     fn get_surface_area(&self) -> f64;
@@ -20,4 +67,101 @@ pub trait Geometry: Sync + 'static {
 
     /// Returns a bounding box of the geometry:
     fn get_bbox(&self) -> BBox3<f64>;
+
+    /// The number of triangles the geometry is made of (analytic shapes don't have any).
+    fn num_triangles(&self) -> usize {
+        0
+    }
+
+    /// The number of vertices the geometry is made of (analytic shapes don't have any).
+    fn num_vertices(&self) -> usize {
+        0
+    }
+
+    /// The (approximate) number of bytes used by the geometry.
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
+}
+
+/// A point sampled on the surface of a geometry.
+#[derive(Clone, Copy, Debug)]
+pub struct SurfaceSample {
+    pub p: Vec3<f64>,
+    pub n: Vec3<f64>,
+    /// The pdf with respect to surface area.
+    pub pdf: f64,
+}
+
+/// A geometry whose surface can be sampled uniformly by area (so it can be used as an area light).
+pub trait SampleableGeometry: Geometry {
+    fn sample_surface(&self, u: Vec2<f64>) -> SurfaceSample;
+
+    /// Returns the surface area after the geometry is transformed by `transf` (`calc_surface_area` has to
+    /// be called first). By default, the surface area is scaled by the average scale of the
+    /// transformation, which is only exact if it scales uniformly.
+    fn world_surface_area(&self, transf: Transf) -> f64 {
+        let m = transf.get_frd();
+        let det = m.get_column(0).dot(m.get_column(1).cross(m.get_column(2)));
+        self.get_surface_area() * det.abs().powf(2.0 / 3.0)
+    }
+}
+
+/// Constructs the interaction for analytic geometries, where the shading frame is the same
+/// as the geometric frame.
+fn analytic_interaction(
+    ray: Ray<f64>,
+    t: f64,
+    p: Vec3<f64>,
+    p_error: Vec3<f64>,
+    n: Vec3<f64>,
+    uv: Vec2<f64>,
+    dpdu: Vec3<f64>,
+    dpdv: Vec3<f64>,
+    dndu: Vec3<f64>,
+    dndv: Vec3<f64>,
+) -> Interaction {
+    let sdpdu = if dpdu.length2() > 0.0 {
+        dpdu.normalize()
+    } else {
+        pmath::coord_system(n).0.normalize()
+    };
+    let sdpdv = n.cross(sdpdu).normalize();
+
+    let (p, p_error) = interaction::point_to_scalar(p, p_error);
+    Interaction {
+        p,
+        p_error,
+        attribute_id: 0,
+        prim: 0,
+        bary: Vec2::zero(),
+        n: n.cast(),
+        wo: -ray.dir.cast(),
+        t: Scalar::from_f64(t),
+        time: Scalar::from_f64(ray.time),
+        geom: None,
+        intr_type: IntrType::Geom(GeomIntr {
+            uv: uv.cast(),
+            dpdu: dpdu.cast(),
+            dpdv: dpdv.cast(),
+            sn: n.cast(),
+            sdpdu: sdpdu.cast(),
+            sdpdv: sdpdv.cast(),
+            sdndu: dndu.cast(),
+            sdndv: dndv.cast(),
+            shadow_p: p,
+            col: None,
+            triplanar: None,
+        }),
+    }
+}
+
+/// Returns the azimuthal angle of a point in the range [0, 2pi).
+fn phi(p: Vec3<f64>) -> f64 {
+    let phi = p.y.atan2(p.x);
+    if phi < 0.0 {
+        phi + 2.0 * std::f64::consts::PI
+    } else {
+        phi
+    }
 }
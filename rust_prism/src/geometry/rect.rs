@@ -0,0 +1,125 @@
+use crate::geometry::{analytic_interaction, Geometry, SampleableGeometry, SurfaceSample};
+use crate::interaction::Interaction;
+use pmath::bbox::BBox3;
+use pmath::ray::Ray;
+use pmath::vector::{Vec2, Vec3};
+
+/// A rectangle centered at the origin in the xy plane, facing +z.
+#[derive(Clone, Copy, Debug)]
+pub struct Rect {
+    size: Vec2<f64>,
+}
+
+impl Rect {
+    pub fn new(size: Vec2<f64>) -> Self {
+        Rect { size }
+    }
+
+    fn hit(&self, ray: Ray<f64>) -> Option<f64> {
+        if ray.dir.z == 0.0 {
+            return None;
+        }
+
+        let t = -ray.org.z / ray.dir.z;
+        if t <= 0.0 || t >= ray.t_far {
+            return None;
+        }
+
+        let p = ray.point_at(t);
+        if p.x.abs() > 0.5 * self.size.x || p.y.abs() > 0.5 * self.size.y {
+            return None;
+        }
+
+        Some(t)
+    }
+}
+
+impl Geometry for Rect {
+    fn intersect(&self, ray: Ray<f64>) -> Option<Interaction> {
+        let t = self.hit(ray)?;
+
+        let p = ray.point_at(t);
+        let p = Vec3 {
+            x: p.x,
+            y: p.y,
+            z: 0.0,
+        };
+        let uv = Vec2 {
+            x: p.x / self.size.x + 0.5,
+            y: p.y / self.size.y + 0.5,
+        };
+
+        Some(analytic_interaction(
+            ray,
+            t,
+            p,
+            Vec3::zero(),
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            uv,
+            Vec3 {
+                x: self.size.x,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vec3 {
+                x: 0.0,
+                y: self.size.y,
+                z: 0.0,
+            },
+            Vec3::zero(),
+            Vec3::zero(),
+        ))
+    }
+
+    fn intersect_test(&self, ray: Ray<f64>) -> bool {
+        self.hit(ray).is_some()
+    }
+
+    // A ray that leaves the rect can't hit it again, so it's skipped entirely:
+
+    fn intersect_excluding(&self, ray: Ray<f64>, prim: Option<u32>) -> Option<Interaction> {
+        match prim {
+            Some(_) => None,
+            None => self.intersect(ray),
+        }
+    }
+
+    fn intersect_test_excluding(&self, ray: Ray<f64>, prim: Option<u32>) -> bool {
+        prim.is_none() && self.intersect_test(ray)
+    }
+
+    fn get_surface_area(&self) -> f64 {
+        self.size.x * self.size.y
+    }
+
+    fn calc_surface_area(&mut self) -> f64 {
+        self.get_surface_area()
+    }
+
+    fn get_bbox(&self) -> BBox3<f64> {
+        let half = Vec3::from_vec2(self.size.scale(0.5), 0.0);
+        BBox3::from_pnts(-half, half)
+    }
+}
+
+impl SampleableGeometry for Rect {
+    fn sample_surface(&self, u: Vec2<f64>) -> SurfaceSample {
+        SurfaceSample {
+            p: Vec3 {
+                x: (u.x - 0.5) * self.size.x,
+                y: (u.y - 0.5) * self.size.y,
+                z: 0.0,
+            },
+            n: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            pdf: 1.0 / self.get_surface_area(),
+        }
+    }
+}
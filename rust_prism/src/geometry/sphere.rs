@@ -0,0 +1,150 @@
+use crate::geometry::{analytic_interaction, phi, Geometry, SampleableGeometry, SurfaceSample};
+use crate::interaction::Interaction;
+use num_traits::clamp;
+use pmath;
+use pmath::bbox::BBox3;
+use pmath::ray::Ray;
+use pmath::sampling;
+use pmath::vector::{Vec2, Vec3};
+use std::f64::consts::PI;
+
+/// A sphere centered at the origin.
+#[derive(Clone, Copy, Debug)]
+pub struct Sphere {
+    radius: f64,
+}
+
+impl Sphere {
+    pub fn new(radius: f64) -> Self {
+        Sphere { radius }
+    }
+
+    /// Returns the closest valid t value of the intersection, if there is one.
+    fn hit(&self, ray: Ray<f64>) -> Option<f64> {
+        let a = ray.dir.length2();
+        let b = 2.0 * ray.dir.dot(ray.org);
+        let c = ray.org.length2() - self.radius * self.radius;
+        let (t0, t1) = pmath::quadratic(a, b, c)?;
+
+        if t0 > 0.0 && t0 < ray.t_far {
+            Some(t0)
+        } else if t1 > 0.0 && t1 < ray.t_far {
+            Some(t1)
+        } else {
+            None
+        }
+    }
+}
+
+impl Geometry for Sphere {
+    fn intersect(&self, ray: Ray<f64>) -> Option<Interaction> {
+        let t = self.hit(ray)?;
+
+        // Reproject the point onto the surface to reduce the error:
+        let p = ray.point_at(t);
+        let p = p.scale(self.radius / p.length());
+        let n = p.scale(1.0 / self.radius);
+
+        // The u coordinate goes around the z axis, v goes from the +z pole to the -z pole:
+        let phi = phi(p);
+        let cos_theta = clamp(n.z, -1.0, 1.0);
+        let theta = cos_theta.acos();
+        let uv = Vec2 {
+            x: phi / (2.0 * PI),
+            y: theta / PI,
+        };
+
+        let dpdu = Vec3 {
+            x: -2.0 * PI * p.y,
+            y: 2.0 * PI * p.x,
+            z: 0.0,
+        };
+        let dpdv = Vec3 {
+            x: p.z * phi.cos(),
+            y: p.z * phi.sin(),
+            z: -self.radius * theta.sin(),
+        }
+        .scale(PI);
+
+        // The normal is just p / r, so its derivatives follow directly:
+        let dndu = dpdu.scale(1.0 / self.radius);
+        let dndv = dpdv.scale(1.0 / self.radius);
+
+        Some(analytic_interaction(
+            ray,
+            t,
+            p,
+            p.abs().scale(pmath::gamma_f64(5)),
+            n,
+            uv,
+            dpdu,
+            dpdv,
+            dndu,
+            dndv,
+        ))
+    }
+
+    fn intersect_test(&self, ray: Ray<f64>) -> bool {
+        self.hit(ray).is_some()
+    }
+
+    fn get_surface_area(&self) -> f64 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    fn calc_surface_area(&mut self) -> f64 {
+        self.get_surface_area()
+    }
+
+    fn get_bbox(&self) -> BBox3<f64> {
+        let r = Vec3 {
+            x: self.radius,
+            y: self.radius,
+            z: self.radius,
+        };
+        BBox3::from_pnts(-r, r)
+    }
+}
+
+impl SampleableGeometry for Sphere {
+    fn sample_surface(&self, u: Vec2<f64>) -> SurfaceSample {
+        let n = sampling::uniform_sample_sphere(u);
+        SurfaceSample {
+            p: n.scale(self.radius),
+            n,
+            pdf: 1.0 / self.get_surface_area(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interaction::IntrType;
+
+    #[test]
+    fn normals_are_unit_length_and_point_outwards() {
+        let sphere = Sphere::new(2.0);
+        for &(x, y) in [(0.0, 0.0), (0.5, -0.3), (1.2, 1.1), (-1.9, 0.1)].iter() {
+            let ray = Ray::new(
+                Vec3 { x, y, z: -5.0 },
+                Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0,
+                },
+                0.0,
+            );
+            let int = sphere.intersect(ray).unwrap();
+            assert!((int.p.length() - 2.0).abs() < 1e-9);
+            assert!((int.n.length() - 1.0).abs() < 1e-9);
+            assert!((int.n - int.p.scale(0.5)).length() < 1e-9);
+            match int.intr_type {
+                IntrType::Geom(geom_intr) => {
+                    assert!((geom_intr.sdndu - geom_intr.dpdu.scale(0.5)).length() < 1e-9)
+                }
+                IntrType::Vol(_) => panic!("a sphere has a surface"),
+            }
+        }
+    }
+}
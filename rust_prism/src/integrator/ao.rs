@@ -0,0 +1,183 @@
+use crate::film::Pixel;
+use crate::integrator::{Integrator, IntegratorManager};
+use crate::light::light_picker::LightPicker;
+use crate::sampler::Sampler;
+use crate::scene::{PrimRef, Scene};
+use crate::shading::material::{MaterialPool, ShadingCoord};
+use crate::spectrum::Color;
+use crate::Scalar;
+use pmath::ray::{PrimaryRay, Ray};
+use pmath::sampling;
+
+/// See `IntegratorDesc::AmbientOcclusion`.
+#[derive(Clone, Copy, Debug)]
+pub struct AoParam {
+    /// Geometry farther away than this doesn't occlude the hit.
+    pub max_distance: Scalar,
+    /// The number of rays traced for every sample.
+    pub samples: u32,
+}
+
+pub struct AoIntegratorManager {
+    param: AoParam,
+}
+
+impl IntegratorManager<AoIntegrator> for AoIntegratorManager {
+    type InitParam = AoParam;
+
+    fn new(param: AoParam) -> Self {
+        AoIntegratorManager { param }
+    }
+
+    fn spawn_integrator(&self, _thread_id: u32) -> AoIntegrator {
+        AoIntegrator { param: self.param }
+    }
+}
+
+/// Returns the fraction of the (cosine weighted) hemisphere around the shading normal of the first hit
+/// that isn't occluded within the maximum distance. Materials and lights are ignored.
+pub struct AoIntegrator {
+    param: AoParam,
+}
+
+impl Integrator for AoIntegrator {
+    fn integrate(
+        &mut self,
+        prim_ray: PrimaryRay<Scalar>,
+        scene: &Scene,
+        _materials: &MaterialPool,
+        _light_picker: &dyn LightPicker,
+        sampler: &mut Sampler,
+        pixel: Pixel,
+    ) -> Pixel {
+        let interaction = match scene.intersect(prim_ray.ray) {
+            Some(interaction) => interaction,
+            // The background is transparent:
+            None => return pixel.add_sample_alpha(Color::black(), 0.0),
+        };
+
+        // Occlusion is measured on the side the camera sees:
+        let n = interaction.shading_n();
+        let n = if n.dot(prim_ray.ray.dir) > 0.0 { -n } else { n };
+        let frame = ShadingCoord::with_tangent(n, n, interaction.dpdu());
+        let exclude = PrimRef::from_interaction(&interaction);
+
+        let samples = self.param.samples.max(1);
+        let unoccluded = (0..samples)
+            .filter(|&i| {
+                let dir = sampling::cos_sample_hemisphere(sampler.sample_dimension(i));
+                let dir = frame.shading_to_world_vec(dir);
+                let ray = Ray::new_extent(
+                    interaction.shadow_p(),
+                    dir.scale(self.param.max_distance),
+                    interaction.time,
+                    1.0,
+                );
+                !scene.intersect_test_excluding(ray, exclude)
+            })
+            .count();
+
+        let visibility = (unoccluded as Scalar) / (samples as Scalar);
+        pixel.add_sample(Color::from_scalar(visibility))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::rect::Rect;
+    use crate::light::light_picker::uniform_one::UniformOne;
+    use crate::sampler::{SampleSequence, SampleTables};
+    use crate::scene::{SceneGeom, ScenePrim};
+    use crate::shading::material::DEFAULT_MATERIAL_ID;
+    use crate::transform::Transf;
+    use pmath::ray::RayDiff;
+    use pmath::vector::{Vec2, Vec3};
+    use std::sync::Arc;
+
+    /// A floor at z = 0 and, if `ceiling` is set, a much larger ceiling at z = 1.
+    fn floor_scene(ceiling: bool) -> Scene {
+        let materials = MaterialPool::new();
+        let rect = |half_size: f64, z: f64| {
+            let geom = SceneGeom::new_material(
+                Arc::new(Rect::new(Vec2 {
+                    x: half_size,
+                    y: half_size,
+                })),
+                materials.get_shared_material(DEFAULT_MATERIAL_ID),
+                Transf::new_translate(Vec3 { x: 0.0, y: 0.0, z }),
+            );
+            Arc::new(geom) as Arc<dyn ScenePrim>
+        };
+        let mut prims = vec![rect(1.0, 0.0)];
+        if ceiling {
+            prims.push(rect(1e4, 1.0));
+        }
+        Scene::new(&prims, Vec::new())
+    }
+
+    /// The ambient occlusion of the center of the floor, seen from below the ceiling.
+    fn floor_ao(scene: &Scene, max_distance: Scalar) -> Scalar {
+        const NUM_PIXEL_SAMPLES: u32 = 256;
+
+        let tables = SampleTables::new_sequence(SampleSequence::Sobol, 7, NUM_PIXEL_SAMPLES);
+        let mut sampler = Sampler::new(&tables);
+        sampler.start_pixel(0, 1, 0);
+        let mut integrator = AoIntegratorManager::new(AoParam {
+            max_distance,
+            samples: 4,
+        })
+        .spawn_integrator(0);
+
+        let org = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.5,
+        };
+        let dir = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: -1.0,
+        };
+        let mut pixel = Pixel::black();
+        for _ in 0..NUM_PIXEL_SAMPLES {
+            sampler.start_pixel_sample();
+            let prim_ray = PrimaryRay {
+                ray: Ray::new(org, dir, 0.0),
+                ray_diff: RayDiff {
+                    rx_org: org,
+                    rx_dir: dir,
+                    ry_org: org,
+                    ry_dir: dir,
+                },
+            };
+            pixel = integrator.integrate(
+                prim_ray,
+                scene,
+                &MaterialPool::new(),
+                &UniformOne::new(),
+                &mut sampler,
+                pixel,
+            );
+        }
+        pixel.final_color().g
+    }
+
+    #[test]
+    fn unoccluded_surfaces_are_white() {
+        assert!((floor_ao(&floor_scene(false), 2.0) - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn occlusion_matches_the_cosine_weighted_solid_angle() {
+        // A cosine weighted ray leaving the floor at an angle theta hits the ceiling (at height h) within
+        // the distance d when cos(theta) > h / d, which it does with probability 1 - (h / d)^2. The rest of
+        // the rays aren't occluded:
+        let scene = floor_scene(true);
+        let ao = floor_ao(&scene, 2.0);
+        assert!((ao - 0.25).abs() < 0.02, "{}", ao);
+
+        // The ceiling is out of reach:
+        assert!((floor_ao(&scene, 0.9) - 1.0).abs() < 1e-12);
+    }
+}
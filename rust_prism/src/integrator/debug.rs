@@ -0,0 +1,341 @@
+//! Integrators that visualize a single property of the first surface a camera ray hits. These are
+//! useful for checking that the loaders produce sensible geometry (normals, uvs, and so on).
+
+use crate::film::Pixel;
+use crate::integrator::{Integrator, IntegratorManager};
+use crate::interaction::{Interaction, IntrType};
+use crate::light::light_picker::LightPicker;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::shading::material::MaterialPool;
+use crate::spectrum::Color;
+use crate::Scalar;
+use pmath::bbox::BBox3;
+use pmath::ray::{PrimaryRay, Ray};
+use pmath::vector::Vec3;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Decides the color of a pixel from the first intersection of the camera ray.
+pub trait DebugView: Copy + Sync {
+    fn shade(self, ray: Ray<Scalar>, int: &Interaction) -> Color;
+}
+
+pub struct DebugIntegratorManager<V: DebugView> {
+    view: V,
+}
+
+impl<V: DebugView> IntegratorManager<DebugIntegrator<V>> for DebugIntegratorManager<V> {
+    type InitParam = V;
+
+    fn new(param: V) -> Self {
+        DebugIntegratorManager { view: param }
+    }
+
+    fn spawn_integrator(&self, _thread_id: u32) -> DebugIntegrator<V> {
+        DebugIntegrator { view: self.view }
+    }
+}
+
+/// Intersects the camera ray with the scene and lets the view decide the color. Rays that don't hit
+/// anything are black (and transparent).
+pub struct DebugIntegrator<V: DebugView> {
+    view: V,
+}
+
+impl<V: DebugView> Integrator for DebugIntegrator<V> {
+    fn integrate(
+        &mut self,
+        prim_ray: PrimaryRay<Scalar>,
+        scene: &Scene,
+        _materials: &MaterialPool,
+        _light_picker: &dyn LightPicker,
+        _sampler: &mut Sampler,
+        pixel: Pixel,
+    ) -> Pixel {
+        let ray = prim_ray.ray;
+        match scene.intersect(ray) {
+            Some(int) => pixel.add_sample(self.view.shade(ray, &int)),
+            // The background is transparent:
+            None => pixel.add_sample_alpha(Color::black(), 0.0),
+        }
+    }
+}
+
+pub type DepthIntegrator = DebugIntegrator<DepthView>;
+pub type PositionIntegrator = DebugIntegrator<PositionView>;
+pub type GeomNormalIntegrator = DebugIntegrator<GeomNormalView>;
+pub type UvIntegrator = DebugIntegrator<UvView>;
+pub type AttributeIdIntegrator = DebugIntegrator<AttributeIdView>;
+
+/// The camera space depth, where `near` is black and `far` is white.
+#[derive(Clone, Copy, Debug)]
+pub struct DepthView {
+    pub near: Scalar,
+    pub far: Scalar,
+    /// The direction the camera looks in (in world space, normalized).
+    pub view_dir: Vec3<Scalar>,
+}
+
+impl DebugView for DepthView {
+    fn shade(self, ray: Ray<Scalar>, int: &Interaction) -> Color {
+        let depth = (int.p - ray.org).dot(self.view_dir);
+        let t = ((depth - self.near) / (self.far - self.near))
+            .max(0.0)
+            .min(1.0);
+        Color { r: t, g: t, b: t }
+    }
+}
+
+/// The world space position, where the minimum of `bbox` is black and the maximum is white.
+#[derive(Clone, Copy, Debug)]
+pub struct PositionView {
+    pub bbox: BBox3<f64>,
+}
+
+impl DebugView for PositionView {
+    fn shade(self, _: Ray<Scalar>, int: &Interaction) -> Color {
+        Color::from_vec3(self.bbox.offset(int.p.cast()).cast()).clamp(0.0, 1.0)
+    }
+}
+
+/// The world space geometric normal (mapped from [-1, 1] to [0, 1]).
+#[derive(Clone, Copy, Debug)]
+pub struct GeomNormalView;
+
+impl DebugView for GeomNormalView {
+    fn shade(self, _: Ray<Scalar>, int: &Interaction) -> Color {
+        Color::from_vec3((Vec3::one() + int.n).scale(0.5))
+    }
+}
+
+/// The uv coordinates as red and green (only the fractional part, so tiling textures show up).
+#[derive(Clone, Copy, Debug)]
+pub struct UvView;
+
+impl DebugView for UvView {
+    fn shade(self, _: Ray<Scalar>, int: &Interaction) -> Color {
+        match int.intr_type {
+            IntrType::Geom(geom_intr) => Color {
+                r: geom_intr.uv.x - geom_intr.uv.x.floor(),
+                g: geom_intr.uv.y - geom_intr.uv.y.floor(),
+                b: 0.0,
+            },
+            IntrType::Vol(_) => Color::black(),
+        }
+    }
+}
+
+/// A random color for every attribute id of every geometry, so that different parts are easy to tell
+/// apart.
+#[derive(Clone, Copy, Debug)]
+pub struct AttributeIdView;
+
+impl DebugView for AttributeIdView {
+    fn shade(self, _: Ray<Scalar>, int: &Interaction) -> Color {
+        let mut hasher = DefaultHasher::new();
+        (int.geom, int.attribute_id).hash(&mut hasher);
+        let hash = hasher.finish();
+        let channel = |shift: u64| ((hash >> shift) & 0xff) as Scalar / 255.0;
+        Color {
+            r: channel(0),
+            g: channel(8),
+            b: channel(16),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::sphere::Sphere;
+    use crate::light::light_picker::uniform_one::UniformOne;
+    use crate::sampler::SampleTables;
+    use crate::scene::{SceneGeom, ScenePrim};
+    use crate::shading::material::DEFAULT_MATERIAL_ID;
+    use crate::transform::Transf;
+    use once_cell::sync::Lazy;
+    use pmath::ray::RayDiff;
+    use std::sync::Arc;
+
+    // A unit sphere at the origin, and another one at x = 3:
+    fn sphere_scene() -> Scene {
+        let materials = MaterialPool::new();
+        let sphere = |x: f64| {
+            let geom = SceneGeom::new_material(
+                Arc::new(Sphere::new(1.0)),
+                materials.get_shared_material(DEFAULT_MATERIAL_ID),
+                Transf::new_translate(Vec3 { x, y: 0.0, z: 0.0 }),
+            );
+            Arc::new(geom) as Arc<dyn ScenePrim>
+        };
+        Scene::new(&[sphere(0.0), sphere(3.0)], Vec::new())
+    }
+
+    // Building the tables is slow, and the integrators don't use any samples anyway:
+    static TABLES: Lazy<SampleTables> = Lazy::new(|| SampleTables::new(1, 0));
+
+    /// Shades a single camera ray with the view.
+    fn shade<V: DebugView>(view: V, scene: &Scene, org: Vec3<Scalar>, dir: Vec3<Scalar>) -> Pixel {
+        let mut integrator = DebugIntegratorManager::new(view).spawn_integrator(0);
+        let mut sampler = Sampler::new(&TABLES);
+        let prim_ray = PrimaryRay {
+            ray: Ray::new(org, dir, 0.0),
+            ray_diff: RayDiff {
+                rx_org: org,
+                rx_dir: dir,
+                ry_org: org,
+                ry_dir: dir,
+            },
+        };
+        integrator.integrate(
+            prim_ray,
+            scene,
+            &MaterialPool::new(),
+            &UniformOne::new(),
+            &mut sampler,
+            Pixel::new(Color::black()),
+        )
+    }
+
+    // A ray along +z that hits the first sphere at (0, 0, -1):
+    const FRONT: (Vec3<Scalar>, Vec3<Scalar>) = (
+        Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: -5.0,
+        },
+        Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        },
+    );
+    // A ray along +y that hits the first sphere at (0, -1, 0):
+    const BOTTOM: (Vec3<Scalar>, Vec3<Scalar>) = (
+        Vec3 {
+            x: 0.0,
+            y: -5.0,
+            z: 0.0,
+        },
+        Vec3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+    );
+    // A ray along +z that hits the second sphere at (3, 0, -1):
+    const OTHER: (Vec3<Scalar>, Vec3<Scalar>) = (
+        Vec3 {
+            x: 3.0,
+            y: 0.0,
+            z: -5.0,
+        },
+        Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        },
+    );
+    // A ray along +z that misses both spheres:
+    const MISS: (Vec3<Scalar>, Vec3<Scalar>) = (
+        Vec3 {
+            x: 0.0,
+            y: 5.0,
+            z: -5.0,
+        },
+        Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        },
+    );
+
+    fn assert_color(pixel: Pixel, r: Scalar, g: Scalar, b: Scalar) {
+        let c = pixel.color;
+        assert!(
+            (c.r - r).abs() < 1e-6 && (c.g - g).abs() < 1e-6 && (c.b - b).abs() < 1e-6,
+            "{:?} isn't ({}, {}, {})",
+            c,
+            r,
+            g,
+            b
+        );
+        assert_eq!(pixel.alpha, 1.0);
+    }
+
+    #[test]
+    fn depth_goes_from_near_to_far() {
+        let scene = sphere_scene();
+        let view = DepthView {
+            near: 2.0,
+            far: 6.0,
+            view_dir: FRONT.1,
+        };
+        assert_color(shade(view, &scene, FRONT.0, FRONT.1), 0.5, 0.5, 0.5);
+        // Anything closer than `near` is black:
+        assert_color(shade(view, &scene, BOTTOM.0, BOTTOM.1), 0.0, 0.0, 0.0);
+        let miss = shade(view, &scene, MISS.0, MISS.1);
+        assert_eq!((miss.color.r, miss.alpha), (0.0, 0.0));
+    }
+
+    #[test]
+    fn positions_are_relative_to_the_bbox() {
+        let scene = sphere_scene();
+        let view = PositionView {
+            bbox: BBox3 {
+                pmin: Vec3 {
+                    x: -1.0,
+                    y: -1.0,
+                    z: -1.0,
+                },
+                pmax: Vec3 {
+                    x: 1.0,
+                    y: 1.0,
+                    z: 1.0,
+                },
+            },
+        };
+        assert_color(shade(view, &scene, FRONT.0, FRONT.1), 0.5, 0.5, 0.0);
+        assert_color(shade(view, &scene, BOTTOM.0, BOTTOM.1), 0.5, 0.0, 0.5);
+        // Positions outside of the bbox are clamped:
+        assert_color(shade(view, &scene, OTHER.0, OTHER.1), 1.0, 0.5, 0.0);
+    }
+
+    #[test]
+    fn geometric_normals_are_mapped_to_colors() {
+        let scene = sphere_scene();
+        assert_color(
+            shade(GeomNormalView, &scene, FRONT.0, FRONT.1),
+            0.5,
+            0.5,
+            0.0,
+        );
+        assert_color(
+            shade(GeomNormalView, &scene, BOTTOM.0, BOTTOM.1),
+            0.5,
+            0.0,
+            0.5,
+        );
+    }
+
+    #[test]
+    fn uvs_are_red_and_green() {
+        let scene = sphere_scene();
+        // The bottom of the sphere is at phi = 3 pi / 2 and theta = pi / 2:
+        assert_color(shade(UvView, &scene, BOTTOM.0, BOTTOM.1), 0.75, 0.5, 0.0);
+    }
+
+    #[test]
+    fn attribute_ids_differ_between_geometry() {
+        let scene = sphere_scene();
+        let front = shade(AttributeIdView, &scene, FRONT.0, FRONT.1);
+        let bottom = shade(AttributeIdView, &scene, BOTTOM.0, BOTTOM.1);
+        let other = shade(AttributeIdView, &scene, OTHER.0, OTHER.1);
+        assert_color(bottom, front.color.r, front.color.g, front.color.b);
+        assert_ne!(
+            (front.color.r, front.color.g, front.color.b),
+            (other.color.r, other.color.g, other.color.b)
+        );
+    }
+}
@@ -1,17 +1,28 @@
+pub mod ao;
+pub mod debug;
 pub mod normal;
 pub mod path_tracer;
+pub mod preview;
+pub mod sppm;
 
 use crate::film::Pixel;
+use crate::interaction::Interaction;
 use crate::light::light_picker::LightPicker;
 use crate::sampler::Sampler;
 use crate::scene::Scene;
 use crate::shading::material::MaterialPool;
+use crate::Scalar;
 use pmath::ray::PrimaryRay;
 
 /// An `IntegratorManager` is used to spawn integrators for each thread and maintain any
 /// information that integrators across different threads may want to use. It is guaranteed
 /// that the IntegratorManager instance will exist until all threads have finished rendering.
 pub trait IntegratorManager<I: Integrator>: Sync {
+    /// The parameters that are used to construct the manager.
+    type InitParam;
+
+    fn new(param: Self::InitParam) -> Self;
+
     /// Spawns an integrator for a specific thread with the provided id.
     fn spawn_integrator(&self, thread_id: u32) -> I;
 }
@@ -21,16 +32,33 @@ pub trait Integrator {
     /// Given the primary ray (as a result of the camera), the scene, the sampler, and the
     /// pixel value already present at the point, integrates the specific pixel and returns
     /// the pixel value at the specified location.
-    fn integrate<LI, L>(
+    fn integrate(
         &mut self,
-        prim_ray: PrimaryRay<f64>,
+        prim_ray: PrimaryRay<Scalar>,
         scene: &Scene,
         materials: &MaterialPool,
-        light_picker: &L,
+        light_picker: &dyn LightPicker,
         sampler: &mut Sampler,
         pixel: Pixel,
-    ) -> Pixel
-    where
-        LI: Iterator<Item = (u32, f64)>,
-        L: LightPicker<LI>;
+    ) -> Pixel;
+
+    /// Same as `integrate`, except that the closest intersection of the camera ray (`first_hit`) is
+    /// already known (see `FirstHitCache`). Integrators that don't override this intersect the camera
+    /// ray again.
+    fn integrate_hit(
+        &mut self,
+        prim_ray: PrimaryRay<Scalar>,
+        _first_hit: Option<Interaction>,
+        scene: &Scene,
+        materials: &MaterialPool,
+        light_picker: &dyn LightPicker,
+        sampler: &mut Sampler,
+        pixel: Pixel,
+    ) -> Pixel {
+        self.integrate(prim_ray, scene, materials, light_picker, sampler, pixel)
+    }
+
+    /// Requests any sample arrays the integrator uses from the sampler (see `Sampler::request_1d_array`).
+    /// This is called once for every thread before rendering.
+    fn request_samples(&self, _sampler: &mut Sampler, _light_picker: &dyn LightPicker) {}
 }
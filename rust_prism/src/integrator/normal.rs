@@ -5,6 +5,7 @@ use crate::sampler::Sampler;
 use crate::scene::Scene;
 use crate::shading::material::MaterialPool;
 use crate::spectrum::Color;
+use crate::Scalar;
 use pmath::ray::PrimaryRay;
 use pmath::vector::Vec3;
 
@@ -12,15 +13,16 @@ pub struct NormalIntegratorManager {
     use_geom_normal: bool,
 }
 
-impl NormalIntegratorManager {
-    pub fn new(param: bool) -> Self {
+impl IntegratorManager<NormalIntegrator> for NormalIntegratorManager {
+    /// Whether to use the geometric normal instead of the shading normal.
+    type InitParam = bool;
+
+    fn new(param: bool) -> Self {
         NormalIntegratorManager {
             use_geom_normal: param,
         }
     }
-}
 
-impl IntegratorManager<NormalIntegrator> for NormalIntegratorManager {
     fn spawn_integrator(&self, _thread_id: u32) -> NormalIntegrator {
         NormalIntegrator {
             use_geom_normal: self.use_geom_normal,
@@ -34,31 +36,28 @@ pub struct NormalIntegrator {
 }
 
 impl Integrator for NormalIntegrator {
-    fn integrate<LI, L>(
+    fn integrate(
         &mut self,
-        prim_ray: PrimaryRay<f64>,
+        prim_ray: PrimaryRay<Scalar>,
         scene: &Scene,
         materials: &MaterialPool,
-        light_picker: &L,
+        light_picker: &dyn LightPicker,
         sampler: &mut Sampler,
         pixel: Pixel,
-    ) -> Pixel
-    where
-        LI: Iterator<Item = (u32, f64)>,
-        L: LightPicker<LI>,
-    {
+    ) -> Pixel {
         // Intersect the scene and get the normal at the intersection.
         let normal = match scene.intersect(prim_ray.ray) {
             Some(int) => {
                 let normal = if self.use_geom_normal {
                     int.n
                 } else {
-                    int.shading_n
+                    int.shading_n()
                 };
                 // We need the range to be between 0 and 1 (no hdr here).
                 (Vec3::one() + normal).scale(0.5)
             }
-            _ => Vec3::zero(),
+            // The background is transparent:
+            _ => return pixel.add_sample_alpha(Color::black(), 0.0),
         };
 
         // Add them to the pixel
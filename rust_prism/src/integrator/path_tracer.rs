@@ -1,93 +1,876 @@
-use crate::film::Pixel;
+use crate::film::{AovKind, LightPath, Pixel};
+use crate::integrator::sppm::{self, CausticPass};
 use crate::integrator::{Integrator, IntegratorManager};
+use crate::interaction::Interaction;
 use crate::light::light_picker::{self, LightPicker};
+use crate::light::DirectLightParam;
 use crate::sampler::Sampler;
-use crate::scene::Scene;
+use crate::scene::{PrimRef, Scene};
 use crate::shading::lobe::LobeType;
 use crate::shading::material::{MaterialPool, ShadingCoord};
 use crate::spectrum::Color;
+use crate::Scalar;
 use pmath::ray::{PrimaryRay, Ray};
+use pmath::vector::Vec3;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PathTracerParam {
+    /// The maximum number of bounces.
+    pub max_bounce: u32,
+    /// The number of light and bsdf samples taken at every bounce.
+    pub direct_light: DirectLightParam,
+    /// Whether to record the number of bounces and the length of every path and the position of the
+    /// first hit (see `AovKind`), and to split the color into emission, direct, and indirect light (see
+    /// `LightPath`).
+    pub aovs: bool,
+    /// Limits the luminance of every contribution of emitted and direct light (see `LightPath`). This
+    /// removes fireflies at the cost of bias (the image gets darker), and also dims legitimately bright
+    /// highlights, so it's usually left at `None`.
+    pub max_direct: Option<Scalar>,
+    /// Limits the luminance of every contribution of indirect light. Fireflies are mostly caused by
+    /// indirect light found through unlikely paths, so this removes most of them while keeping the direct
+    /// highlights intact. It's biased all the same: the indirect light gets darker the lower the limit.
+    pub max_indirect: Option<Scalar>,
+}
 
 pub struct PathTracerIntegratorManager {
-    max_bounce: u32,
+    param: PathTracerParam,
+    caustics: Option<Arc<CausticPass>>,
 }
 
 impl PathTracerIntegratorManager {
-    pub fn new(max_bounce: u32) -> Self {
-        PathTracerIntegratorManager { max_bounce }
+    /// Gathers the caustics from the photons of the pass instead of finding them by tracing paths (see
+    /// `integrator::sppm`).
+    pub fn with_caustics(mut self, caustics: Arc<CausticPass>) -> Self {
+        self.caustics = Some(caustics);
+        self
     }
 }
 
 impl IntegratorManager<PathTracerIntegrator> for PathTracerIntegratorManager {
+    type InitParam = PathTracerParam;
+
+    fn new(param: PathTracerParam) -> Self {
+        PathTracerIntegratorManager {
+            param,
+            caustics: None,
+        }
+    }
+
     fn spawn_integrator(&self, _thread_id: u32) -> PathTracerIntegrator {
         PathTracerIntegrator {
-            max_bounce: self.max_bounce,
+            max_bounce: self.param.max_bounce,
+            direct_light: self.param.direct_light,
+            aovs: self.param.aovs,
+            max_direct: self.param.max_direct,
+            max_indirect: self.param.max_indirect,
+            picked_lights: Vec::new(),
+            caustics: self.caustics.clone(),
         }
     }
 }
 
 pub struct PathTracerIntegrator {
     max_bounce: u32,
+    direct_light: DirectLightParam,
+    aovs: bool,
+    max_direct: Option<Scalar>,
+    max_indirect: Option<Scalar>,
+    // Reused every time lights are picked:
+    picked_lights: Vec<(u32, Scalar)>,
+    caustics: Option<Arc<CausticPass>>,
+}
+
+/// The state of a single path, which is advanced one bounce at a time by `PathTracerIntegrator::shade`.
+/// This way paths can either be traced one at a time (see `integrate`), or many at once with the rays of
+/// every bounce intersected together (see `WavefrontRenderer`).
+#[derive(Clone, Copy, Debug)]
+pub struct PathState {
+    /// The ray that has to be intersected next.
+    pub ray: Ray<Scalar>,
+    /// The primitive the ray starts on, which the ray has to skip (see `Scene::intersect_excluding`).
+    pub exclude: Option<PrimRef>,
+    color: Color,
+    // The color split by the kind of path that carried it:
+    light_paths: [Color; LightPath::COUNT],
+    throughput: Color,
+    alpha: Scalar,
+    // Whether or not we had a specular bounce just now
+    specular_bounce: bool,
+    // Whether the caustics were gathered at a surface of the path (see `integrator::sppm`), and whether
+    // every bounce since then was specular (so that the light found now is part of the caustics):
+    caustics_gathered: bool,
+    caustic_path: bool,
+    bounce_count: u32,
+    // The number of surfaces the path hit and the distance it travelled:
+    num_bounces: u32,
+    path_length: Scalar,
+    // Where the camera ray hit a surface:
+    first_hit: Option<Vec3<Scalar>>,
+    done: bool,
+}
+
+impl PathState {
+    /// Whether the path is complete (and can be added to the pixel with `PathTracerIntegrator::finish`).
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn add_light(&mut self, kind: LightPath, color: Color) {
+        self.color += color;
+        self.light_paths[kind as usize] += color;
+    }
 }
 
 impl Integrator for PathTracerIntegrator {
-    fn integrate<LI, L>(
+    fn integrate(
         &mut self,
-        prim_ray: PrimaryRay<f64>,
+        prim_ray: PrimaryRay<Scalar>,
         scene: &Scene,
         materials: &MaterialPool,
-        light_picker: &L,
+        light_picker: &dyn LightPicker,
         sampler: &mut Sampler,
         pixel: Pixel,
-    ) -> Pixel
-    where
-        LI: Iterator<Item = (u32, f64)>,
-        L: LightPicker<LI>,
-    {
-        let mut color_result = Color::black();
-        let mut throughput = Color::white();
-        let mut ray = prim_ray.ray;
-
-        // Whether or not we had a specular bounce just now
-        let mut specular_bounce = false;
-
-        for bounce_count in 0..self.max_bounce {
-            let interaction = match scene.intersect(ray) {
-                Some(int) => int,
-                None => break,
-            };
+    ) -> Pixel {
+        let first_hit = scene.intersect(prim_ray.ray);
+        self.integrate_hit(
+            prim_ray,
+            first_hit,
+            scene,
+            materials,
+            light_picker,
+            sampler,
+            pixel,
+        )
+    }
 
-            // Get the bsdf and updated interaction:
-            let (bsdf, interaction) = materials
-                .get_material(interaction.material_id)
-                .bsdf(interaction);
+    fn integrate_hit(
+        &mut self,
+        prim_ray: PrimaryRay<Scalar>,
+        first_hit: Option<Interaction>,
+        scene: &Scene,
+        materials: &MaterialPool,
+        light_picker: &dyn LightPicker,
+        sampler: &mut Sampler,
+        pixel: Pixel,
+    ) -> Pixel {
+        let mut path = self.start_path(prim_ray.ray);
+        let mut interaction = first_hit;
+        while !path.done {
+            self.shade(
+                &mut path,
+                interaction,
+                scene,
+                materials,
+                light_picker,
+                sampler,
+            );
+            if !path.done {
+                interaction = scene.intersect_excluding(path.ray, path.exclude);
+            }
+        }
+        self.finish(path, pixel)
+    }
 
-            // Sample the light(s):
-            color_result += throughput
-                * light_picker::sample_lights(
-                    interaction,
+    fn request_samples(&self, sampler: &mut Sampler, light_picker: &dyn LightPicker) {
+        light_picker::request_samples(sampler, light_picker, self.direct_light);
+    }
+}
+
+impl PathTracerIntegrator {
+    /// Starts a new path from the camera ray.
+    pub fn start_path(&self, ray: Ray<Scalar>) -> PathState {
+        PathState {
+            ray,
+            exclude: None,
+            color: Color::black(),
+            light_paths: [Color::black(); LightPath::COUNT],
+            throughput: Color::white(),
+            alpha: 1.0,
+            specular_bounce: false,
+            caustics_gathered: false,
+            caustic_path: false,
+            bounce_count: 0,
+            num_bounces: 0,
+            path_length: 0.0,
+            first_hit: None,
+            done: self.max_bounce == 0,
+        }
+    }
+
+    /// Shades the intersection of the path's current ray (`None` if it didn't hit anything) and samples
+    /// the next ray. Once the path is done, its ray isn't updated anymore.
+    pub fn shade(
+        &mut self,
+        path: &mut PathState,
+        interaction: Option<Interaction>,
+        scene: &Scene,
+        materials: &MaterialPool,
+        light_picker: &dyn LightPicker,
+        sampler: &mut Sampler,
+    ) {
+        debug_assert!(!path.done);
+        let ray = path.ray;
+        let bounce_count = path.bounce_count;
+
+        let interaction = match interaction {
+            Some(int) => int,
+            None => {
+                // Camera rays that don't hit anything see the (transparent) background, unless there
+                // is an environment (see `Scene::background_alpha`):
+                if bounce_count == 0 {
+                    path.alpha = scene.background_alpha();
+                }
+                // Like emitted light, infinite lights were already sampled at the previous bounce:
+                if (bounce_count == 0) || path.specular_bounce {
+                    let kind = if bounce_count == 0 {
+                        LightPath::Emission
+                    } else {
+                        LightPath::Indirect
+                    };
+                    let color = path.throughput * scene.escaped_radiance(ray.org, ray.dir);
+                    path.add_light(kind, self.clamp(kind, color));
+                }
+                path.done = true;
+                return;
+            }
+        };
+        path.num_bounces += 1;
+        path.path_length += interaction.t * ray.dir.length();
+        if bounce_count == 0 {
+            path.first_hit = Some(interaction.p);
+        }
+
+        // Shadow catchers only store how much light is blocked from reaching them:
+        if bounce_count == 0 {
+            if let Some(geom) = interaction.geom {
+                if scene.is_shadow_catcher(geom) {
+                    path.alpha = shadow_catcher_alpha(&interaction, ray.time, scene, sampler);
+                    path.done = true;
+                    return;
+                }
+            }
+        }
+
+        // Emitted light is only added when it couldn't have been found by sampling the lights at the
+        // previous bounce (otherwise it would be counted twice):
+        if (bounce_count == 0) || path.specular_bounce {
+            if let Some(light_id) = interaction.geom.and_then(|geom| scene.get_area_light(geom)) {
+                let light = scene.get_light(light_id);
+                // The photons already carried the caustics:
+                let is_caustic = path.caustic_path && light.emits_photons();
+                if !is_caustic && (light.is_two_sided() || (interaction.n.dot(-ray.dir) > 0.0)) {
+                    let kind = if bounce_count == 0 {
+                        LightPath::Emission
+                    } else {
+                        LightPath::Indirect
+                    };
+                    let color = path.throughput * light.eval(interaction.p, -ray.dir);
+                    path.add_light(kind, self.clamp(kind, color));
+                }
+            }
+        }
+
+        // Only the next ray skips the primitive, after that it can be hit again legitimately:
+        let prim = PrimRef::from_interaction(&interaction);
+
+        // Get the bsdf and updated interaction:
+        let material = scene.get_material_or_default(&interaction, materials);
+        let (bsdf, interaction) = material.bsdf(interaction);
+
+        // Sample the light(s):
+        sampler.start_bounce(bounce_count);
+        let kind = if bounce_count == 0 {
+            LightPath::Direct
+        } else {
+            LightPath::Indirect
+        };
+        let direct_light = path.throughput
+            * light_picker::sample_lights(
+                interaction,
+                bsdf,
+                ray.time,
+                scene,
+                sampler,
+                light_picker,
+                &mut self.picked_lights,
+                self.direct_light,
+            );
+        path.add_light(kind, self.clamp(kind, direct_light));
+
+        // Gather the caustics at the first surface that isn't specular:
+        let shading_coord = ShadingCoord::new(interaction);
+        let is_diffuse = sppm::is_diffuse(bsdf);
+        let gathers_caustics = self.caustics.is_some() && is_diffuse && !path.caustics_gathered;
+        if let Some(caustics) = self.caustics.as_ref().filter(|_| gathers_caustics) {
+            let color = path.throughput
+                * caustics.estimate(
+                    interaction.p,
+                    -ray.dir,
                     bsdf,
-                    ray.time,
-                    scene,
-                    sampler,
-                    light_picker,
+                    shading_coord,
+                    sampler.pixel_pos(),
                 );
+            path.add_light(LightPath::Indirect, self.clamp(LightPath::Indirect, color));
+            path.caustics_gathered = true;
+        }
 
-            // Sample the bsdf for the next ray:
-            let shading_coord = ShadingCoord::new(interaction);
-            let (bsdf_color, wi, bsdf_pdf, lobe_type) =
-                bsdf.sample(-ray.dir, sampler.sample(), LobeType::ALL, shading_coord);
+        // Sample the bsdf for the next ray:
+        let (bsdf_color, wi, bsdf_pdf, lobe_type) =
+            bsdf.sample(-ray.dir, sampler.sample(), LobeType::ALL, shading_coord);
 
-            if bsdf_color.is_black() || (bsdf_pdf == 0.0) {
-                break;
+        path.bounce_count += 1;
+        if bsdf_color.is_black() || (bsdf_pdf == 0.0) || (path.bounce_count == self.max_bounce) {
+            path.done = true;
+            return;
+        }
+
+        path.throughput =
+            (path.throughput * bsdf_color).scale(wi.dot(interaction.shading_n()).abs() / bsdf_pdf);
+        path.specular_bounce = lobe_type.contains(LobeType::SPECULAR);
+        // The photons only account for the light reflected by the surface they were gathered at through
+        // a lobe that isn't specular, and then only for light reaching it through specular bounces:
+        path.caustic_path = if gathers_caustics {
+            !path.specular_bounce
+        } else {
+            path.caustic_path && !is_diffuse
+        };
+        path.ray = Ray::new(interaction.p, wi, ray.time);
+        path.exclude = prim;
+    }
+
+    /// Limits the luminance of a contribution depending on the kind of path that carried it (see
+    /// `PathTracerParam::max_direct` and `PathTracerParam::max_indirect`), keeping its hue.
+    fn clamp(&self, kind: LightPath, color: Color) -> Color {
+        let max = match kind {
+            LightPath::Indirect => self.max_indirect,
+            _ => self.max_direct,
+        };
+        match max {
+            Some(max) if color.luminance() > max => color.scale(max / color.luminance()),
+            _ => color,
+        }
+    }
+
+    /// Adds a completed path to the pixel.
+    pub fn finish(&self, path: PathState, pixel: Pixel) -> Pixel {
+        debug_assert!(path.done);
+        let pixel = pixel.add_sample_alpha(path.color, path.alpha);
+        if self.aovs {
+            let pixel = LightPath::ALL.iter().fold(pixel, |pixel, &kind| {
+                pixel.add_light_path(kind, path.light_paths[kind as usize])
+            });
+            let pixel = pixel
+                .add_aux(AovKind::BounceCount, path.num_bounces as Scalar)
+                .add_aux(AovKind::PathLength, path.path_length);
+            match path.first_hit {
+                Some(p) => pixel
+                    .add_aux(AovKind::SurfaceHit, 1.0)
+                    .add_aux(AovKind::PositionX, p.x)
+                    .add_aux(AovKind::PositionY, p.y)
+                    .add_aux(AovKind::PositionZ, p.z),
+                None => pixel,
+            }
+        } else {
+            pixel
+        }
+    }
+}
+
+/// Returns the fraction of the (unoccluded) direct light at a point on a shadow catcher that is blocked by
+/// the rest of the scene. This is used as the alpha of the shadow.
+fn shadow_catcher_alpha(
+    interaction: &Interaction,
+    time: Scalar,
+    scene: &Scene,
+    sampler: &mut Sampler,
+) -> Scalar {
+    let mut unoccluded = 0.0;
+    let mut visible = 0.0;
+    for light_id in 0..(scene.num_lights() as u32) {
+        let light = scene.get_light(light_id);
+        let (light_color, light_point, light_pdf) =
+            light.sample(interaction.p, time, scene, sampler.sample());
+        if (light_pdf == 0.0) || light_color.is_black() {
+            continue;
+        }
+
+        let wi = light_point - interaction.p;
+        let contribution =
+            light_color.luminance() * wi.normalize().dot(interaction.n).abs() / light_pdf;
+        let shadow_ray = Ray::new_extent(interaction.p, wi, time, 1.0);
+        let tr = scene.transmittance_to(shadow_ray, light.get_geom());
+
+        unoccluded += contribution;
+        visible += contribution * tr.luminance();
+    }
+
+    if unoccluded > 0.0 {
+        (1.0 - visible / unoccluded).max(0.0).min(1.0)
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::BuildAlgorithm;
+    use crate::camera::{Camera, CameraSample};
+    use crate::fileio::scene::{build_scene, parse_scene};
+    use crate::film::ImagePixel;
+    use crate::filter::{BoxFilter, GaussianFilter, PixelFilter};
+    use crate::geometry::mesh::{Mesh, Triangle};
+    use crate::light::light_picker::uniform_one::UniformOne;
+    use crate::light::point::Point;
+    use crate::sampler::SampleTables;
+    use crate::scene::{SceneGeom, SceneLight, ScenePrim};
+    use crate::shading::material::matte::Matte;
+    use crate::shading::material::DEFAULT_MATERIAL_ID;
+    use crate::threading;
+    use crate::transform::Transf;
+    use crate::TEST_EPSILON;
+    use pmath::numbers::Float;
+    use pmath::ray::RayDiff;
+    use pmath::vector::Vec2;
+    use std::path::Path;
+
+    #[test]
+    fn enclosed_paths_use_every_bounce() {
+        const MAX_BOUNCE: u32 = 8;
+        // The camera is inside of a closed cube (from -2 to 2), so paths can only end by running out of
+        // bounces:
+        let corner = |i: usize| Vec3::<f32> {
+            x: if i & 1 == 0 { -2.0 } else { 2.0 },
+            y: if i & 2 == 0 { -2.0 } else { 2.0 },
+            z: if i & 4 == 0 { -2.0 } else { 2.0 },
+        };
+        let faces = [
+            [0, 1, 3, 2],
+            [4, 5, 7, 6],
+            [0, 1, 5, 4],
+            [2, 3, 7, 6],
+            [0, 2, 6, 4],
+            [1, 3, 7, 5],
+        ];
+        let triangles = faces
+            .iter()
+            .flat_map(|&[a, b, c, d]| vec![[a, b, c], [a, c, d]])
+            .map(|indices| Triangle {
+                indices,
+                attribute_id: 0,
+            })
+            .collect();
+        let cube = Mesh::new(
+            triangles,
+            (0..8).map(corner).collect(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            4,
+            BuildAlgorithm::Sah,
+        );
+        let materials = MaterialPool::new();
+        let cube = SceneGeom::new_material(
+            Arc::new(cube),
+            materials.get_shared_material(DEFAULT_MATERIAL_ID),
+            Transf::new_identity(),
+        );
+        let scene = Scene::new(&[Arc::new(cube) as Arc<dyn ScenePrim>], Vec::new());
+
+        let manager = PathTracerIntegratorManager::new(PathTracerParam {
+            max_bounce: MAX_BOUNCE,
+            direct_light: DirectLightParam::default(),
+            aovs: true,
+            max_direct: None,
+            max_indirect: None,
+        });
+        let mut integrator = manager.spawn_integrator(0);
+        let light_picker = UniformOne::new();
+        let tables = SampleTables::new(1, 0);
+        let mut sampler = Sampler::new(&tables);
+        integrator.request_samples(&mut sampler, &light_picker);
+        sampler.start_pixel(0, 1, 0);
+
+        let mut trace = |org: Vec3<Scalar>| {
+            let dir = Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            };
+            let prim_ray = PrimaryRay {
+                ray: Ray::new(org, dir, 0.0),
+                ray_diff: RayDiff {
+                    rx_org: org,
+                    rx_dir: dir,
+                    ry_org: org,
+                    ry_dir: dir,
+                },
+            };
+            let mut pixel = Pixel::new(Color::black());
+            for _ in 0..4 {
+                sampler.start_pixel_sample();
+                pixel = integrator.integrate(
+                    prim_ray,
+                    &scene,
+                    &materials,
+                    &light_picker,
+                    &mut sampler,
+                    pixel,
+                );
+            }
+            pixel
+        };
+
+        let inside = trace(Vec3::zero());
+        assert_eq!(inside.final_aux(AovKind::BounceCount), MAX_BOUNCE as f64);
+        // Every path goes from the center to the cube first, and then across the cube:
+        let max_length = 2.0 + 48f64.sqrt() * ((MAX_BOUNCE - 1) as f64);
+        let path_length = inside.final_aux(AovKind::PathLength);
+        assert!(path_length > 2.0 && path_length <= max_length);
+
+        let outside = trace(Vec3 {
+            x: 5.0,
+            y: 0.0,
+            z: 0.0,
+        });
+        assert_eq!(outside.final_aux(AovKind::BounceCount), 0.0);
+        assert_eq!(outside.final_aux(AovKind::PathLength), 0.0);
+        assert_eq!(outside.final_alpha(), 0.0);
+    }
+
+    // Asking for a material id that doesn't exist is a bug, which panics in debug builds. Release builds
+    // report it and render with the (gray) default material instead:
+    #[test]
+    #[cfg_attr(
+        debug_assertions,
+        should_panic(expected = "material id 99 doesn't exist")
+    )]
+    fn missing_material_ids_render_with_the_default_material() {
+        const MISSING_ID: u32 = 99;
+        let mut materials = MaterialPool::new();
+        let red = materials.add_material(Matte::new(Color {
+            r: 0.8,
+            g: 0.0,
+            b: 0.0,
+        }));
+        let blue = materials.add_material(Matte::new(Color {
+            r: 0.0,
+            g: 0.0,
+            b: 0.8,
+        }));
+        // A quad facing the camera, with a triangle for each attribute id:
+        let corner = |x, y| Vec3::<f32> { x, y, z: 2.0 };
+        let quad = Mesh::new(
+            vec![
+                Triangle {
+                    indices: [0, 2, 1],
+                    attribute_id: 0,
+                },
+                Triangle {
+                    indices: [0, 3, 2],
+                    attribute_id: 1,
+                },
+            ],
+            vec![
+                corner(-2.0, -2.0),
+                corner(2.0, -2.0),
+                corner(2.0, 2.0),
+                corner(-2.0, 2.0),
+            ],
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            4,
+            BuildAlgorithm::Sah,
+        );
+        let quad = SceneGeom::new_material(
+            Arc::new(quad),
+            materials.get_shared_material(blue),
+            Transf::new_identity(),
+        )
+        .with_attribute_materials(vec![
+            materials.get_shared_material(red),
+            materials.get_shared_material(MISSING_ID),
+        ]);
+        assert_eq!(materials.missing_ids(), vec![MISSING_ID]);
+        let light = Point::new(Vec3::zero(), Color::white().scale(4.0));
+        let scene = Scene::new(
+            &[Arc::new(quad) as Arc<dyn ScenePrim>],
+            vec![SceneLight::new(Arc::new(light), Transf::new_identity())],
+        );
+
+        let manager = PathTracerIntegratorManager::new(PathTracerParam {
+            max_bounce: 1,
+            direct_light: DirectLightParam::default(),
+            aovs: false,
+            max_direct: None,
+            max_indirect: None,
+        });
+        let mut integrator = manager.spawn_integrator(0);
+        let mut light_picker = UniformOne::new();
+        light_picker.set_scene_lights(1, &scene);
+        let tables = SampleTables::new(1, 0);
+        let mut sampler = Sampler::new(&tables);
+        integrator.request_samples(&mut sampler, &light_picker);
+        sampler.start_pixel(0, 1, 0);
+        let mut trace = |x: Scalar, y: Scalar| {
+            let org = Vec3 { x, y, z: 0.0 };
+            let dir = Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            };
+            let prim_ray = PrimaryRay {
+                ray: Ray::new(org, dir, 0.0),
+                ray_diff: RayDiff {
+                    rx_org: org,
+                    rx_dir: dir,
+                    ry_org: org,
+                    ry_dir: dir,
+                },
+            };
+            sampler.start_pixel_sample();
+            integrator
+                .integrate(
+                    prim_ray,
+                    &scene,
+                    &materials,
+                    &light_picker,
+                    &mut sampler,
+                    Pixel::new(Color::black()),
+                )
+                .final_color()
+        };
+
+        let bound = trace(1.0, -1.0);
+        assert!(
+            bound.r > 0.0 && bound.g == 0.0 && bound.b == 0.0,
+            "{:?}",
+            bound
+        );
+        let missing = trace(-1.0, 1.0);
+        assert!(
+            missing.r > 0.0 && missing.r == missing.g && missing.g == missing.b,
+            "{:?}",
+            missing
+        );
+        // The missing material is reported once, no matter how often it's shaded:
+        assert_eq!(materials.missing_ids(), vec![MISSING_ID]);
+    }
+
+    #[test]
+    fn light_paths_add_up_to_the_beauty() {
+        let scenes = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        let source = std::fs::read_to_string(scenes.join("cornell_box.ron")).unwrap();
+        let mut desc = parse_scene(&source).unwrap();
+        desc.settings.res = (24, 18);
+        desc.settings.spp = 4;
+        let loaded = build_scene(&desc, &scenes).unwrap();
+        let output = threading::render::<PathTracerIntegrator, PathTracerIntegratorManager>(
+            &loaded.camera,
+            PixelFilter::new(&GaussianFilter::new(Vec2 { x: 1.0, y: 1.0 }, 0.5)),
+            &loaded.scene,
+            &loaded.materials,
+            loaded.param.clone(),
+            PathTracerParam {
+                max_bounce: 4,
+                direct_light: DirectLightParam::default(),
+                aovs: true,
+                max_direct: None,
+                max_indirect: None,
+            },
+            None,
+            None,
+        )
+        .unwrap();
+
+        let to_pixel = ImagePixel::from_color;
+        let beauty = output.film.to_image_buffer(to_pixel);
+        let parts: Vec<_> = LightPath::ALL
+            .iter()
+            .map(|&kind| output.film.light_path_to_image_buffer(kind, to_pixel))
+            .collect();
+        // The camera sees the light, and the box is lit both directly and indirectly:
+        let mut totals = [0.0; LightPath::COUNT];
+        for y in 0..18 {
+            for x in 0..24 {
+                let pos = Vec2 { x, y };
+                let pixel = beauty.get_pixel(pos);
+                let mut sum = ImagePixel::zero();
+                for (total, part) in totals.iter_mut().zip(parts.iter()) {
+                    let part = part.get_pixel(pos);
+                    sum.r += part.r;
+                    sum.g += part.g;
+                    sum.b += part.b;
+                    *total += part.r + part.g + part.b;
+                }
+                let tolerance = TEST_EPSILON.to_f64() * (1.0 + pixel.r + pixel.g + pixel.b);
+                assert!((sum.r - pixel.r).abs() < tolerance, "{:?}", pos);
+                assert!((sum.g - pixel.g).abs() < tolerance, "{:?}", pos);
+                assert!((sum.b - pixel.b).abs() < tolerance, "{:?}", pos);
             }
+        }
+        assert!(totals.iter().all(|&total| total > 0.0), "{:?}", totals);
+    }
 
-            throughput = (throughput * bsdf_color * wi.dot(interaction.shading_n).abs())
-                .scale(1.0 / bsdf_pdf);
-            specular_bounce = lobe_type.contains(LobeType::SPECULAR);
-            ray = Ray::new(interaction.p, wi, ray.time);
+    #[test]
+    fn only_indirect_light_is_clamped() {
+        const MAX_INDIRECT: Scalar = 0.05;
+        let clamped = PathTracerIntegratorManager::new(PathTracerParam {
+            max_bounce: 4,
+            direct_light: DirectLightParam::default(),
+            aovs: true,
+            max_direct: None,
+            max_indirect: Some(MAX_INDIRECT),
+        })
+        .spawn_integrator(0);
+        // An artificial firefly keeps its hue but is limited, unless it's direct light:
+        let firefly = Color {
+            r: 1000.0,
+            g: 500.0,
+            b: 0.0,
+        };
+        let limited = clamped.clamp(LightPath::Indirect, firefly);
+        assert!((limited.luminance() - MAX_INDIRECT).abs() < 16.0 * Scalar::EPSILON);
+        assert!((limited.g / limited.r - 0.5).abs() < 16.0 * Scalar::EPSILON);
+        assert_eq!(clamped.clamp(LightPath::Direct, firefly), firefly);
+        assert_eq!(clamped.clamp(LightPath::Emission, firefly), firefly);
+
+        let scenes = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        let source = std::fs::read_to_string(scenes.join("cornell_box.ron")).unwrap();
+        let mut desc = parse_scene(&source).unwrap();
+        desc.settings.res = (24, 18);
+        desc.settings.spp = 4;
+        let loaded = build_scene(&desc, &scenes).unwrap();
+        let render = |max_indirect| {
+            threading::render::<PathTracerIntegrator, PathTracerIntegratorManager>(
+                &loaded.camera,
+                PixelFilter::new(&GaussianFilter::new(Vec2 { x: 1.0, y: 1.0 }, 0.5)),
+                &loaded.scene,
+                &loaded.materials,
+                loaded.param.clone(),
+                PathTracerParam {
+                    max_bounce: 4,
+                    direct_light: DirectLightParam::default(),
+                    aovs: true,
+                    max_direct: None,
+                    max_indirect,
+                },
+                None,
+                None,
+            )
+            .unwrap()
+            .film
+        };
+        let unclamped = render(None);
+        let clamped = render(Some(MAX_INDIRECT));
+
+        // Every pixel sample is the same in both renders, so the direct light (and the light itself) is
+        // exactly the same, while the indirect light gets darker:
+        let to_pixel = ImagePixel::from_color;
+        let luminance = |p: ImagePixel| p.to_color().luminance();
+        let mut darker = 0;
+        for &kind in &[LightPath::Emission, LightPath::Direct, LightPath::Indirect] {
+            let before = unclamped.light_path_to_image_buffer(kind, to_pixel);
+            let after = clamped.light_path_to_image_buffer(kind, to_pixel);
+            for y in 0..18 {
+                for x in 0..24 {
+                    let pos = Vec2 { x, y };
+                    let (before, after) = (before.get_pixel(pos), after.get_pixel(pos));
+                    if kind == LightPath::Indirect {
+                        // Every bounce can add indirect light (both from sampling the lights and from
+                        // hitting them), and every contribution is clamped by itself:
+                        assert!(luminance(after) <= luminance(before) + 1e-12, "{:?}", pos);
+                        assert!(luminance(after) <= 8.0 * MAX_INDIRECT, "{:?}", pos);
+                        if luminance(after) < luminance(before) - 1e-9 {
+                            darker += 1;
+                        }
+                    } else {
+                        assert_eq!((before.r, before.g, before.b), (after.r, after.g, after.b));
+                    }
+                }
+            }
         }
+        assert!(darker > 0);
+    }
+
+    #[test]
+    fn depths_unproject_to_the_positions() {
+        let scenes = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        let source = std::fs::read_to_string(scenes.join("cornell_box.ron")).unwrap();
+        let mut desc = parse_scene(&source).unwrap();
+        let res = Vec2 { x: 24, y: 18 };
+        desc.settings.res = (res.x, res.y);
+        desc.settings.spp = 4;
+        let loaded = build_scene(&desc, &scenes).unwrap();
+        let output = threading::render::<PathTracerIntegrator, PathTracerIntegratorManager>(
+            &loaded.camera,
+            PixelFilter::new(&BoxFilter::new(Vec2 { x: 0.5, y: 0.5 })),
+            &loaded.scene,
+            &loaded.materials,
+            loaded.param.clone(),
+            PathTracerParam {
+                max_bounce: 1,
+                direct_light: DirectLightParam::default(),
+                aovs: true,
+                max_direct: None,
+                max_indirect: None,
+            },
+            None,
+            None,
+        )
+        .unwrap();
+
+        let camera = &loaded.camera;
+        let (origin, view_dir) = (camera.position(), camera.view_dir());
+        let positions = output.film.position_to_image_buffer(origin);
+        let depths = output.film.depth_to_image_buffer(origin, view_dir);
+        // The point at a depth along the ray through a position on the film:
+        let unproject = |p_film: Vec2<f64>, depth: f64| {
+            let ray = camera.gen_ray(CameraSample {
+                p_film: p_film.cast(),
+                p_lens: Vec2 { x: 0.5, y: 0.5 },
+                time: 0.0,
+            });
+            let (org, dir) = (ray.org.cast::<f64>(), ray.dir.cast::<f64>());
+            org + dir.scale(depth / dir.dot(view_dir))
+        };
+        let mut num_hits = 0;
+        for y in 0..res.y {
+            for x in 0..res.x {
+                let (position, depth) = (
+                    positions.get_pixel(Vec2 { x, y }),
+                    depths.get_pixel(Vec2 { x, y }),
+                );
+                // Pixels that see through the open side of the box are partially transparent:
+                assert_eq!(position.a, depth.a);
+                if position.a < 1.0 {
+                    continue;
+                }
+                num_hits += 1;
 
-        Pixel::add_sample(color_result)
+                let center = Vec2 {
+                    x: (x as f64) + 0.5,
+                    y: (y as f64) + 0.5,
+                };
+                let p = Vec3 {
+                    x: position.r,
+                    y: position.g,
+                    z: position.b,
+                } + origin;
+                // The positions are averaged over the pixel, so they are only within a pixel of the ray
+                // through its center:
+                let footprint = (unproject(center + Vec2 { x: 1.0, y: 0.0 }, depth.r)
+                    - unproject(center, depth.r))
+                .length();
+                let error = (unproject(center, depth.r) - p).length();
+                assert!(error < footprint, "{:?}: {} {}", center, error, footprint);
+            }
+        }
+        assert!(num_hits > res.x * res.y / 2, "{}", num_hits);
     }
 }
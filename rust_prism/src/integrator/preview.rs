@@ -0,0 +1,207 @@
+use crate::film::Pixel;
+use crate::integrator::{Integrator, IntegratorManager};
+use crate::light::light_picker::uniform_one::UniformOne;
+use crate::light::light_picker::{self, LightPicker};
+use crate::light::DirectLightParam;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::shading::lobe::LobeType;
+use crate::shading::material::{MaterialPool, ShadingCoord};
+use crate::spectrum::Color;
+use crate::Scalar;
+use pmath::ray::PrimaryRay;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PreviewParam {
+    /// The radiance that arrives at every point from every direction (unoccluded), so that the parts of
+    /// the scene the lights don't reach aren't black.
+    pub ambient: Color,
+}
+
+impl Default for PreviewParam {
+    fn default() -> Self {
+        PreviewParam {
+            ambient: Color::white().scale(0.1),
+        }
+    }
+}
+
+pub struct PreviewIntegratorManager {
+    param: PreviewParam,
+}
+
+impl IntegratorManager<PreviewIntegrator> for PreviewIntegratorManager {
+    type InitParam = PreviewParam;
+
+    fn new(param: PreviewParam) -> Self {
+        PreviewIntegratorManager { param }
+    }
+
+    fn spawn_integrator(&self, _thread_id: u32) -> PreviewIntegrator {
+        PreviewIntegrator {
+            ambient: self.param.ambient,
+            picked_lights: Vec::new(),
+        }
+    }
+}
+
+/// A fast approximation for laying out scenes: only the direct light at the first surface is computed,
+/// with a single shadow ray to a single light (picked uniformly), plus a constant ambient term. There is
+/// no indirect light and no MIS (the bsdf is never sampled to hit the lights).
+pub struct PreviewIntegrator {
+    ambient: Color,
+    // Reused every time a light is picked:
+    picked_lights: Vec<(u32, Scalar)>,
+}
+
+impl PreviewIntegrator {
+    // Only light samples (see `estimate_direct_light`):
+    const DIRECT_LIGHT: DirectLightParam = DirectLightParam {
+        n_light_samples: 1,
+        n_bsdf_samples: 0,
+        terminator_fix: false,
+        shadow_rr: None,
+    };
+}
+
+impl Integrator for PreviewIntegrator {
+    fn integrate(
+        &mut self,
+        prim_ray: PrimaryRay<Scalar>,
+        scene: &Scene,
+        materials: &MaterialPool,
+        _light_picker: &dyn LightPicker,
+        sampler: &mut Sampler,
+        pixel: Pixel,
+    ) -> Pixel {
+        let ray = prim_ray.ray;
+        let interaction = match scene.intersect(ray) {
+            Some(int) => int,
+            None => {
+                return pixel.add_sample_alpha(
+                    scene.escaped_radiance(ray.org, ray.dir),
+                    scene.background_alpha(),
+                );
+            }
+        };
+
+        let mut color = Color::black();
+        if let Some(light_id) = interaction.geom.and_then(|geom| scene.get_area_light(geom)) {
+            let light = scene.get_light(light_id);
+            if light.is_two_sided() || (interaction.n.dot(-ray.dir) > 0.0) {
+                color += light.eval(interaction.p, -ray.dir);
+            }
+        }
+
+        let material = scene.get_material_or_default(&interaction, materials);
+        let (bsdf, interaction) = material.bsdf(interaction);
+
+        // A single light is picked, whichever light picker the renderer uses:
+        let mut light_picker = UniformOne::new();
+        light_picker.set_scene_lights(scene.num_lights() as u32, scene);
+        color += light_picker::sample_lights(
+            interaction,
+            bsdf,
+            ray.time,
+            scene,
+            sampler,
+            &light_picker,
+            &mut self.picked_lights,
+            Self::DIRECT_LIGHT,
+        );
+
+        // The ambient light is estimated with a single bsdf sample (without a shadow ray):
+        if !self.ambient.is_black() {
+            let shading_coord = ShadingCoord::new(interaction);
+            let (bsdf_color, wi, bsdf_pdf, _) =
+                bsdf.sample(-ray.dir, sampler.sample(), LobeType::ALL, shading_coord);
+            if bsdf_pdf > 0.0 {
+                color += (self.ambient * bsdf_color)
+                    .scale(wi.dot(interaction.shading_n()).abs() / bsdf_pdf);
+            }
+        }
+
+        pixel.add_sample(color)
+    }
+
+    fn request_samples(&self, sampler: &mut Sampler, _light_picker: &dyn LightPicker) {
+        // The same arrays as `light_picker::request_samples` with a single light:
+        sampler.request_2d_array(Self::DIRECT_LIGHT.n_light_samples as usize);
+        sampler.request_2d_array(Self::DIRECT_LIGHT.n_bsdf_samples as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::sphere::Sphere;
+    use crate::sampler::SampleTables;
+    use crate::scene::{SceneGeom, ScenePrim};
+    use crate::shading::material::DEFAULT_MATERIAL_ID;
+    use crate::transform::Transf;
+    use crate::TEST_EPSILON;
+    use pmath::ray::{Ray, RayDiff};
+    use pmath::vector::Vec3;
+    use std::sync::Arc;
+
+    #[test]
+    fn ambient_light_is_reflected_by_the_albedo() {
+        let materials = MaterialPool::new();
+        let sphere = SceneGeom::new_material(
+            Arc::new(Sphere::new(1.0)),
+            materials.get_shared_material(DEFAULT_MATERIAL_ID),
+            Transf::new_identity(),
+        );
+        let scene = Scene::new(&[Arc::new(sphere) as Arc<dyn ScenePrim>], Vec::new());
+
+        let ambient = Color {
+            r: 0.2,
+            g: 0.4,
+            b: 0.8,
+        };
+        let manager = PreviewIntegratorManager::new(PreviewParam { ambient });
+        let mut integrator = manager.spawn_integrator(0);
+        let light_picker = UniformOne::new();
+        let tables = SampleTables::new(1, 0);
+        let mut sampler = Sampler::new(&tables);
+        integrator.request_samples(&mut sampler, &light_picker);
+        sampler.start_pixel(0, 1, 0);
+
+        let org = Vec3 {
+            x: 0.1,
+            y: 0.2,
+            z: -5.0,
+        };
+        let dir = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        let prim_ray = PrimaryRay {
+            ray: Ray::new(org, dir, 0.0),
+            ray_diff: RayDiff {
+                rx_org: org,
+                rx_dir: dir,
+                ry_org: org,
+                ry_dir: dir,
+            },
+        };
+        for _ in 0..4 {
+            sampler.start_pixel_sample();
+            let pixel = integrator.integrate(
+                prim_ray,
+                &scene,
+                &materials,
+                &light_picker,
+                &mut sampler,
+                Pixel::new(Color::black()),
+            );
+            // Without any lights, a cosine weighted sample of the (gray) default material reflects half
+            // of the ambient light:
+            let expected = ambient.scale(0.5);
+            assert!((pixel.color.r - expected.r).abs() < TEST_EPSILON);
+            assert!((pixel.color.g - expected.g).abs() < TEST_EPSILON);
+            assert!((pixel.color.b - expected.b).abs() < TEST_EPSILON);
+        }
+    }
+}
@@ -0,0 +1,623 @@
+//! Caustics with a simplified form of stochastic progressive photon mapping ("Stochastic Progressive
+//! Photon Mapping", Hachisuka and Jensen 2009). Caustics are light that reaches a surface that isn't
+//! specular through specular bounces only (like the light a glass sphere focuses onto the floor). The
+//! path tracer can't find them from point lights at all, and only rarely from small area lights.
+//!
+//! Every pass first traces photons from the lights and stores the ones that hit a surface that isn't
+//! specular after one or more specular bounces. Then the path tracer renders a sample per pixel as
+//! usual, except that at the first surface of a path that isn't specular it adds the radiance of the
+//! photons around the hit point, and ignores the light it finds from there through specular bounces
+//! only (which is what the photons already estimated). Every pixel has its own radius that shrinks with
+//! every pass that found photons, so the caustics get sharper and converge.
+
+use crate::camera::Camera;
+use crate::film::Film;
+use crate::filter::PixelFilter;
+use crate::integrator::path_tracer::{PathTracerIntegratorManager, PathTracerParam};
+use crate::integrator::IntegratorManager;
+use crate::scene::{PrimRef, Scene};
+use crate::shading::lobe::LobeType;
+use crate::shading::material::{Bsdf, MaterialPool, ShadingCoord};
+use crate::spectrum::Color;
+use crate::threading::{CancellationToken, RenderError, RenderOutput, RenderProgress, Renderer};
+use crate::Scalar;
+use pmath::numbers::Float;
+use pmath::ray::Ray;
+use pmath::vector::{Vec2, Vec3};
+use rand::Rng;
+use rand_pcg::Pcg32;
+use rayon::prelude::*;
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+#[derive(Clone, Copy, Debug)]
+pub struct CausticParam {
+    /// The number of photons traced from the lights every pass.
+    pub photons: u32,
+    /// The most photons that are stored every pass (once it's reached, the pass doesn't trace any more
+    /// photons). This bounds the memory of the photon map to `PHOTON_SIZE` bytes per photon.
+    pub max_photons: usize,
+    /// The radius every pixel gathers photons in at the first pass.
+    pub radius: Scalar,
+    /// The fraction of the photons a pixel found that are kept when its radius shrinks (between 0 and
+    /// 1). Lower values shrink the radius faster, which is less blurry but noisier.
+    pub alpha: Scalar,
+    /// The most specular bounces a photon takes before it's stored.
+    pub max_bounce: u32,
+    /// The seed of the random numbers the photons are traced with.
+    pub seed: u64,
+}
+
+/// Traces photons from every light that can emit them (see `Light::emits_photons`) and renders the scene
+/// with the path tracer and the caustics of the photons (see the module). Every sample per pixel is a
+/// pass with its own photons.
+pub fn render(
+    renderer: &Renderer,
+    scene: &Scene,
+    materials: &MaterialPool,
+    camera: &dyn Camera,
+    filter: PixelFilter,
+    int_param: PathTracerParam,
+    param: CausticParam,
+    progress: Option<&(dyn Fn(RenderProgress) + Sync)>,
+    cancel: Option<&CancellationToken>,
+) -> Result<RenderOutput, RenderError> {
+    let render_param = renderer.param();
+    let num_passes = render_param.num_pixel_samples;
+    let mut film = renderer.new_film();
+    let lights = PhotonLights::new(scene);
+    let pixels = Arc::new(PixelRadii::new(
+        render_param.film_res(),
+        param.radius.to_f64(),
+    ));
+
+    let default_cancel = CancellationToken::new();
+    let cancel = cancel.unwrap_or(&default_cancel);
+
+    let start = Instant::now();
+    for pass in 0..num_passes {
+        if cancel.is_cancelled() {
+            break;
+        }
+        if pass > 0 {
+            film.next_pass();
+        }
+
+        let (photons, num_emitted) =
+            renderer.install(|| trace_photons(scene, materials, &lights, &param, pass));
+        let caustics = CausticPass {
+            map: PhotonMap::new(photons, Scalar::from_f64(pixels.max_radius())),
+            num_emitted,
+            pixels: pixels.clone(),
+            alpha: param.alpha,
+        };
+        let integrator_manager =
+            PathTracerIntegratorManager::new(int_param).with_caustics(Arc::new(caustics));
+        renderer.render_into(
+            &film,
+            scene,
+            materials,
+            camera,
+            filter,
+            &integrator_manager,
+            1,
+            None,
+            None,
+            cancel,
+        )?;
+
+        if let Some(progress) = progress {
+            report_progress(&film, pass + 1, num_passes, start, progress);
+        }
+    }
+    renderer.record_tile_costs(&film);
+
+    Ok(RenderOutput {
+        film,
+        cancelled: cancel.is_cancelled(),
+        samples: Vec::new(),
+    })
+}
+
+/// Reports the progress after `passes_done` of the passes.
+fn report_progress(
+    film: &Film,
+    passes_done: u32,
+    num_passes: u32,
+    start: Instant,
+    progress: &(dyn Fn(RenderProgress) + Sync),
+) {
+    let elapsed = start.elapsed();
+    let passes_left = num_passes.saturating_sub(passes_done);
+    progress(RenderProgress {
+        percent_complete: (passes_done as f64) / (num_passes.max(1) as f64),
+        tiles_done: film.num_tiles_complete(),
+        tiles_total: film.num_tiles(),
+        elapsed,
+        remaining: Some(elapsed.mul_f64((passes_left as f64) / (passes_done as f64))),
+        spp: passes_done,
+    });
+}
+
+/// Whether photons are stored on (and gathered at) a surface with the bsdf: any surface that isn't
+/// purely specular.
+pub fn is_diffuse(bsdf: &Bsdf) -> bool {
+    bsdf.num_contains_type(LobeType::DIFFUSE) + bsdf.num_contains_type(LobeType::GLOSSY) > 0
+}
+
+/// The photons of a single pass, which the path tracer gathers the caustics from.
+pub struct CausticPass {
+    map: PhotonMap,
+    // The number of photons traced in the pass (including those that weren't stored):
+    num_emitted: u64,
+    pixels: Arc<PixelRadii>,
+    alpha: Scalar,
+}
+
+impl CausticPass {
+    /// Estimates the radiance of the caustics that leaves the point `p` of a surface with the bsdf in
+    /// the direction `wo`, from the photons within the radius of the pixel. The radius of the pixel
+    /// shrinks if any were found.
+    pub fn estimate(
+        &self,
+        p: Vec3<Scalar>,
+        wo: Vec3<Scalar>,
+        bsdf: &Bsdf,
+        shading_coord: ShadingCoord,
+        pixel_pos: Option<Vec2<u32>>,
+    ) -> Color {
+        if self.num_emitted == 0 {
+            return Color::black();
+        }
+        let index = pixel_pos.map(|pos| self.pixels.index(pos));
+        let radius2 = Scalar::from_f64(match index {
+            Some(index) => self.pixels.radius2(index),
+            None => self.pixels.initial_radius2,
+        });
+
+        let mut sum = Color::black();
+        let mut num_found = 0u64;
+        self.map.for_each_near(p, radius2, |photon| {
+            let wi = to_vec3(photon.wi);
+            let power = Color {
+                r: photon.power[0] as Scalar,
+                g: photon.power[1] as Scalar,
+                b: photon.power[2] as Scalar,
+            };
+            sum += bsdf.eval(wo, wi, LobeType::ALL, shading_coord) * power;
+            num_found += 1;
+        });
+        if let Some(index) = index {
+            self.pixels.shrink(index, num_found, self.alpha.to_f64());
+        }
+
+        sum.scale(1.0 / (Scalar::PI * radius2 * (self.num_emitted as Scalar)))
+    }
+}
+
+/// The radius of every pixel (see `CausticPass::estimate`), and how many photons it accounts for.
+struct PixelRadii {
+    res: Vec2<usize>,
+    initial_radius2: f64,
+    // Both store the bits of an `f64`. Every pixel is only rendered by a single thread at a time:
+    radius2: Vec<AtomicU64>,
+    counts: Vec<AtomicU64>,
+}
+
+impl PixelRadii {
+    fn new(res: Vec2<usize>, radius: f64) -> Self {
+        let num_pixels = res.x * res.y;
+        let initial_radius2 = radius * radius;
+        PixelRadii {
+            res,
+            initial_radius2,
+            radius2: (0..num_pixels)
+                .map(|_| AtomicU64::new(initial_radius2.to_bits()))
+                .collect(),
+            counts: (0..num_pixels).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    fn index(&self, pos: Vec2<u32>) -> usize {
+        (pos.y as usize) * self.res.x + (pos.x as usize)
+    }
+
+    fn radius2(&self, index: usize) -> f64 {
+        f64::from_bits(self.radius2[index].load(Ordering::Relaxed))
+    }
+
+    /// The largest radius of any pixel.
+    fn max_radius(&self) -> f64 {
+        let max_radius2 = self
+            .radius2
+            .iter()
+            .map(|radius2| f64::from_bits(radius2.load(Ordering::Relaxed)))
+            .fold(0.0, f64::max);
+        max_radius2.sqrt()
+    }
+
+    /// Shrinks the radius of the pixel after `num_found` photons were found in it, keeping the
+    /// density of the photons the same (as if `alpha` of them were found in the smaller radius).
+    fn shrink(&self, index: usize, num_found: u64, alpha: f64) {
+        if num_found == 0 {
+            return;
+        }
+        let count = f64::from_bits(self.counts[index].load(Ordering::Relaxed));
+        let num_found = num_found as f64;
+        let new_count = count + alpha * num_found;
+        let radius2 = self.radius2(index) * new_count / (count + num_found);
+        self.counts[index].store(new_count.to_bits(), Ordering::Relaxed);
+        self.radius2[index].store(radius2.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// A photon that was stored on a surface.
+#[derive(Clone, Copy, Debug)]
+struct Photon {
+    p: [f32; 3],
+    // Points to where the photon came from:
+    wi: [f32; 3],
+    power: [f32; 3],
+}
+
+/// The number of bytes the photon map takes per photon (see `CausticParam::max_photons`).
+pub const PHOTON_SIZE: usize = mem::size_of::<Photon>() + mem::size_of::<u32>();
+
+fn to_array(v: Vec3<Scalar>) -> [f32; 3] {
+    [v.x as f32, v.y as f32, v.z as f32]
+}
+
+fn to_vec3(v: [f32; 3]) -> Vec3<Scalar> {
+    Vec3 {
+        x: v[0] as Scalar,
+        y: v[1] as Scalar,
+        z: v[2] as Scalar,
+    }
+}
+
+/// The photons, sorted into the cells of a uniform grid. Only the cells that have photons take up
+/// memory: the cells are hashed into a table with as many buckets as there are photons.
+struct PhotonMap {
+    // Sorted by bucket:
+    photons: Vec<Photon>,
+    // Where the photons of every bucket start in `photons` (with the end of the last one at the end):
+    bucket_starts: Vec<u32>,
+    cell_size: Scalar,
+}
+
+impl PhotonMap {
+    /// Sorts the photons into cells of size `cell_size`, which has to be at least as large as any
+    /// radius the photons are looked up in.
+    fn new(photons: Vec<Photon>, cell_size: Scalar) -> Self {
+        let mut map = PhotonMap {
+            photons: Vec::new(),
+            bucket_starts: vec![0; photons.len().next_power_of_two() + 1],
+            cell_size: cell_size.max(Scalar::MIN_POSITIVE),
+        };
+
+        // Counting sort by bucket:
+        let buckets: Vec<usize> = photons
+            .iter()
+            .map(|photon| map.bucket(map.cell(to_vec3(photon.p))))
+            .collect();
+        for &bucket in buckets.iter() {
+            map.bucket_starts[bucket + 1] += 1;
+        }
+        for i in 1..map.bucket_starts.len() {
+            map.bucket_starts[i] += map.bucket_starts[i - 1];
+        }
+        let mut next = map.bucket_starts.clone();
+        let mut sorted = photons.clone();
+        for (photon, &bucket) in photons.into_iter().zip(buckets.iter()) {
+            sorted[next[bucket] as usize] = photon;
+            next[bucket] += 1;
+        }
+        map.photons = sorted;
+        map
+    }
+
+    fn num_buckets(&self) -> usize {
+        self.bucket_starts.len() - 1
+    }
+
+    fn cell(&self, p: Vec3<Scalar>) -> [i64; 3] {
+        [
+            (p.x / self.cell_size).floor() as i64,
+            (p.y / self.cell_size).floor() as i64,
+            (p.z / self.cell_size).floor() as i64,
+        ]
+    }
+
+    fn bucket(&self, cell: [i64; 3]) -> usize {
+        let hash = (cell[0].wrapping_mul(73856093))
+            ^ (cell[1].wrapping_mul(19349663))
+            ^ (cell[2].wrapping_mul(83492791));
+        (hash as u64 as usize) & (self.num_buckets() - 1)
+    }
+
+    /// Calls `f` with every photon within the radius (given squared) of `p`.
+    fn for_each_near(&self, p: Vec3<Scalar>, radius2: Scalar, mut f: impl FnMut(&Photon)) {
+        if self.photons.is_empty() {
+            return;
+        }
+        let radius = radius2.sqrt();
+        let min = self.cell(p - Vec3::one().scale(radius));
+        let max = self.cell(p + Vec3::one().scale(radius));
+        // The radius isn't larger than a cell, so this covers at most 2 cells along every axis. Cells
+        // can end up in the same bucket, which has to be visited only once:
+        let mut visited = [usize::MAX; 8];
+        let mut num_visited = 0;
+        for x in min[0]..=max[0] {
+            for y in min[1]..=max[1] {
+                for z in min[2]..=max[2] {
+                    let bucket = self.bucket([x, y, z]);
+                    if visited[..num_visited].contains(&bucket) || num_visited == visited.len() {
+                        continue;
+                    }
+                    visited[num_visited] = bucket;
+                    num_visited += 1;
+
+                    let start = self.bucket_starts[bucket] as usize;
+                    let end = self.bucket_starts[bucket + 1] as usize;
+                    for photon in self.photons[start..end].iter() {
+                        if (to_vec3(photon.p) - p).length2() <= radius2 {
+                            f(photon);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The lights that emit photons, picked in proportion to their power.
+struct PhotonLights {
+    ids: Vec<u32>,
+    // The sum of the probabilities of the lights up to and including every light:
+    cdf: Vec<Scalar>,
+}
+
+impl PhotonLights {
+    fn new(scene: &Scene) -> Self {
+        let ids: Vec<u32> = (0..(scene.num_lights() as u32))
+            .filter(|&id| {
+                let light = scene.get_light(id);
+                light.emits_photons() && light.power().luminance() > 0.0
+            })
+            .collect();
+        let total: Scalar = ids
+            .iter()
+            .map(|&id| scene.get_light(id).power().luminance())
+            .sum();
+        let mut sum = 0.0;
+        let cdf = ids
+            .iter()
+            .map(|&id| {
+                sum += scene.get_light(id).power().luminance() / total;
+                sum
+            })
+            .collect();
+        PhotonLights { ids, cdf }
+    }
+
+    /// Picks a light with `u` in [0, 1), and returns it with the probability it was picked with.
+    fn pick(&self, u: Scalar) -> Option<(u32, Scalar)> {
+        let i = match self
+            .cdf
+            .binary_search_by(|c| c.partial_cmp(&u).unwrap_or(std::cmp::Ordering::Less))
+        {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        }
+        .min(self.ids.len().checked_sub(1)?);
+        let prev = if i == 0 { 0.0 } else { self.cdf[i - 1] };
+        Some((self.ids[i], self.cdf[i] - prev))
+    }
+}
+
+// The photons of a pass are traced in this many chunks, each with its own random numbers:
+const NUM_CHUNKS: u32 = 64;
+
+/// Traces the photons of a pass in parallel, and returns the photons that were stored along with the
+/// number of photons that were traced.
+fn trace_photons(
+    scene: &Scene,
+    materials: &MaterialPool,
+    lights: &PhotonLights,
+    param: &CausticParam,
+    pass: u32,
+) -> (Vec<Photon>, u64) {
+    if lights.ids.is_empty() {
+        return (Vec::new(), 0);
+    }
+    let chunks: Vec<(Vec<Photon>, u64)> = (0..NUM_CHUNKS)
+        .into_par_iter()
+        .map(|chunk| {
+            let num_photons = param.photons / NUM_CHUNKS
+                + if chunk < param.photons % NUM_CHUNKS {
+                    1
+                } else {
+                    0
+                };
+            let max_photons = (param.max_photons / (NUM_CHUNKS as usize)).max(1);
+            let mut rng = Pcg32::new(
+                param.seed ^ (((pass as u64) << 32) | (chunk as u64)),
+                0xda3e39cb94b95bdb,
+            );
+            let mut photons = Vec::new();
+            let mut num_emitted = 0;
+            for _ in 0..num_photons {
+                if photons.len() >= max_photons {
+                    break;
+                }
+                num_emitted += 1;
+                trace_photon(scene, materials, lights, param, &mut rng, &mut photons);
+            }
+            (photons, num_emitted)
+        })
+        .collect();
+
+    let num_emitted = chunks.iter().map(|(_, num_emitted)| num_emitted).sum();
+    let mut photons = Vec::with_capacity(chunks.iter().map(|(photons, _)| photons.len()).sum());
+    for (chunk, _) in chunks {
+        photons.extend(chunk);
+    }
+    (photons, num_emitted)
+}
+
+/// Traces a single photon from one of the lights, and stores it once it hits a surface that isn't
+/// specular after at least one specular bounce. Photons that hit such a surface first are dropped, as
+/// the path tracer already finds that light.
+fn trace_photon(
+    scene: &Scene,
+    materials: &MaterialPool,
+    lights: &PhotonLights,
+    param: &CausticParam,
+    rng: &mut Pcg32,
+    photons: &mut Vec<Photon>,
+) {
+    let (light_id, light_pdf) = match lights.pick(rng.gen()) {
+        Some(light) => light,
+        None => return,
+    };
+    let mut sample_2d = || Vec2 {
+        x: rng.gen::<Scalar>(),
+        y: rng.gen::<Scalar>(),
+    };
+    let time = sample_2d().x;
+    let (u_pos, u_dir) = (sample_2d(), sample_2d());
+    let (power, mut ray) = match scene.get_light(light_id).sample_le(time, u_pos, u_dir) {
+        Some(sample) => sample,
+        None => return,
+    };
+    let mut power = power.scale(1.0 / light_pdf);
+    let mut exclude = None;
+
+    for bounce in 0..=param.max_bounce {
+        let interaction = match scene.intersect_excluding(ray, exclude) {
+            Some(interaction) => interaction,
+            None => return,
+        };
+        let prim = PrimRef::from_interaction(&interaction);
+        let material = scene.get_material_or_default(&interaction, materials);
+        let (bsdf, interaction) = material.bsdf(interaction);
+
+        if is_diffuse(bsdf) {
+            if bounce > 0 {
+                photons.push(Photon {
+                    p: to_array(interaction.p),
+                    wi: to_array(-ray.dir.normalize()),
+                    power: [power.r as f32, power.g as f32, power.b as f32],
+                });
+            }
+            return;
+        }
+        if bounce == param.max_bounce {
+            return;
+        }
+
+        let shading_coord = ShadingCoord::new(interaction);
+        let (bsdf_color, wi, bsdf_pdf, _) =
+            bsdf.sample(-ray.dir, sample_2d(), LobeType::ALL, shading_coord);
+        if bsdf_color.is_black() || (bsdf_pdf == 0.0) {
+            return;
+        }
+        power = (power * bsdf_color).scale(wi.dot(interaction.shading_n()).abs() / bsdf_pdf);
+        ray = Ray::new(interaction.p, wi, time);
+        exclude = prim;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::sphere::Sphere;
+    use crate::light::point::Point;
+    use crate::scene::{SceneGeom, SceneLight, ScenePrim};
+    use crate::shading::material::DEFAULT_MATERIAL_ID;
+    use crate::transform::Transf;
+    use crate::TEST_EPSILON;
+
+    #[test]
+    fn emitted_power_is_conserved() {
+        let material = MaterialPool::new().get_shared_material(DEFAULT_MATERIAL_ID);
+        let prims: Vec<Arc<dyn ScenePrim>> = vec![Arc::new(SceneGeom::new_material(
+            Arc::new(Sphere::new(1.0)),
+            material,
+            Transf::new_identity(),
+        ))];
+        let intensities = [
+            Color::white().scale(10.0),
+            Color {
+                r: 4.0,
+                g: 1.0,
+                b: 0.5,
+            },
+        ];
+        let lights = intensities
+            .iter()
+            .enumerate()
+            .map(|(i, &intensity)| {
+                let position = Vec3 {
+                    x: 3.0 * (i as Scalar),
+                    y: 4.0,
+                    z: 0.0,
+                };
+                SceneLight::new(
+                    Arc::new(Point::new(position, intensity)),
+                    Transf::new_identity(),
+                )
+            })
+            .collect();
+        let scene = Scene::new(&prims, lights);
+        let photon_lights = PhotonLights::new(&scene);
+        let total_power = intensities.iter().fold(Color::black(), |sum, &intensity| {
+            sum + intensity.scale(4.0 * Scalar::PI)
+        });
+
+        // The power of every photon is scaled by the probability of its light, so the average power
+        // of the photons is the total power of the lights:
+        const NUM_PHOTONS: u32 = 10000;
+        let mut rng = Pcg32::new(0, 0xda3e39cb94b95bdb);
+        let mut sum = Color::black();
+        for _ in 0..NUM_PHOTONS {
+            let (light_id, light_pdf) = photon_lights.pick(rng.gen()).unwrap();
+            let u_pos = Vec2 {
+                x: rng.gen(),
+                y: rng.gen(),
+            };
+            let u_dir = Vec2 {
+                x: rng.gen(),
+                y: rng.gen(),
+            };
+            let (power, _) = scene
+                .get_light(light_id)
+                .sample_le(0.0, u_pos, u_dir)
+                .unwrap();
+            sum += power.scale(1.0 / light_pdf);
+        }
+        let average = sum.scale(1.0 / (NUM_PHOTONS as Scalar));
+        for &(a, b) in [
+            (average.r, total_power.r),
+            (average.g, total_power.g),
+            (average.b, total_power.b),
+        ]
+        .iter()
+        {
+            assert!((a - b).abs() < 0.02 * b, "{} vs {}", a, b);
+        }
+        // The lights are picked by their luminance, so the luminance of every photon is exact:
+        let power = scene
+            .get_light(0)
+            .sample_le(0.0, Vec2::zero(), Vec2::zero())
+            .unwrap()
+            .0;
+        let (_, light_pdf) = photon_lights.pick(0.0).unwrap();
+        assert!(
+            (power.scale(1.0 / light_pdf).luminance() - total_power.luminance()).abs()
+                < TEST_EPSILON
+        );
+    }
+}
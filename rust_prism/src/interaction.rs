@@ -1,17 +1,38 @@
+use crate::scene::GeomRef;
+use crate::Scalar;
+use pmath::numbers::Float;
 use pmath::vector::{Vec2, Vec3};
 
 /// Represents any information that we may need for
 #[derive(Clone, Copy, Debug)]
 pub struct GeomIntr {
-    uv: Vec2<f64>,   // uv coordinate at the intersection
-    dpdu: Vec3<f64>, // vectors parallel to the triangle
-    dpdv: Vec3<f64>,
-
-    sn: Vec3<f64>,    // the shading normal at this point
-    sdpdu: Vec3<f64>, // the shading dpdu, dpdv at this point
-    sdpdv: Vec3<f64>,
-    sdndu: Vec3<f64>, // the shading dndu, dndv at this point
-    sdndv: Vec3<f64>,
+    pub uv: Vec2<Scalar>,   // uv coordinate at the intersection
+    pub dpdu: Vec3<Scalar>, // vectors parallel to the triangle
+    pub dpdv: Vec3<Scalar>,
+
+    pub sn: Vec3<Scalar>,    // the shading normal at this point
+    pub sdpdu: Vec3<Scalar>, // the shading dpdu, dpdv at this point
+    pub sdpdv: Vec3<Scalar>,
+    pub sdndu: Vec3<Scalar>, // the shading dndu, dndv at this point
+    pub sdndv: Vec3<Scalar>,
+
+    // Where shadow rays should start to avoid the shadow terminator (p moved onto the tangent planes of
+    // the vertex normals for smooth shaded meshes, p otherwise):
+    pub shadow_p: Vec3<Scalar>,
+
+    // The interpolated vertex color (linear rgb) for meshes that have vertex colors:
+    pub col: Option<Vec3<Scalar>>,
+
+    // The object space position and normal for meshes with triplanar uvs (see `UvProjection`):
+    pub triplanar: Option<TriplanarCoords>,
+}
+
+/// The object space position and geometric normal of a hit, for textures that are projected along the
+/// axes of the object (which are unaffected by the transformation of the object).
+#[derive(Clone, Copy, Debug)]
+pub struct TriplanarCoords {
+    pub p: Vec3<Scalar>,
+    pub n: Vec3<Scalar>,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -25,11 +46,81 @@ pub enum IntrType {
 
 #[derive(Clone, Copy, Debug)]
 pub struct Interaction {
-    pub p: Vec3<f64>,  // intersection point
-    pub n: Vec3<f64>,  // geometric normal (of triangle)
-    pub wo: Vec3<f64>, // direction of intersection leaving the point
-    pub t: f64,        // the parametric parameter of the ray where the intersection happened
-    pub time: f64,     // the time period when the intersection happened
+    pub p: Vec3<Scalar>,       // intersection point
+    pub p_error: Vec3<Scalar>, // conservative bound on the absolute error of p
+    pub attribute_id: u32,     // which part of the geometry was hit (used for materials)
+    pub prim: u32, // which primitive of the geometry was hit (the triangle of a mesh, 0 otherwise)
+    pub bary: Vec2<Scalar>, // the barycentric coordinates (b1, b2) of the hit on a triangle (zero otherwise)
+    pub n: Vec3<Scalar>,    // geometric normal (of triangle)
+    pub wo: Vec3<Scalar>,   // direction of intersection leaving the point
+    pub t: Scalar,          // the parametric parameter of the ray where the intersection happened
+    pub time: Scalar,       // the time period when the intersection happened
+    pub geom: Option<GeomRef>, // the scene geometry that was hit (set by the scene, not the geometry)
 
     pub intr_type: IntrType, // the type of interaction where the intersection occurs
 }
+
+impl Interaction {
+    /// The shading normal at the intersection (the geometric normal for volume interactions).
+    pub fn shading_n(&self) -> Vec3<Scalar> {
+        match self.intr_type {
+            IntrType::Geom(geom_intr) => geom_intr.sn,
+            IntrType::Vol(_) => self.n,
+        }
+    }
+
+    /// The partial derivative of the position with respect to u (zero for volume interactions).
+    pub fn dpdu(&self) -> Vec3<Scalar> {
+        match self.intr_type {
+            IntrType::Geom(geom_intr) => geom_intr.dpdu,
+            IntrType::Vol(_) => Vec3::zero(),
+        }
+    }
+
+    /// The uv coordinate at the intersection (zero for volume interactions).
+    pub fn uv(&self) -> Vec2<Scalar> {
+        match self.intr_type {
+            IntrType::Geom(geom_intr) => geom_intr.uv,
+            IntrType::Vol(_) => Vec2::zero(),
+        }
+    }
+
+    /// Where shadow rays should start (see `GeomIntr::shadow_p`).
+    pub fn shadow_p(&self) -> Vec3<Scalar> {
+        match self.intr_type {
+            IntrType::Geom(geom_intr) => geom_intr.shadow_p,
+            IntrType::Vol(_) => self.p,
+        }
+    }
+
+    /// Moves `p` (a point on the surface) along the geometric normal by the bound on the error of the
+    /// intersection, to the side of `w`. Rays that leave the surface in the direction `w` from there can't
+    /// hit it again just because the intersection (or its rounding to `Scalar`) ended up on the wrong side.
+    pub fn offset_origin(&self, p: Vec3<Scalar>, w: Vec3<Scalar>) -> Vec3<Scalar> {
+        let offset = self.n.scale(self.n.abs().dot(self.p_error));
+        let offset = if self.n.dot(w) < 0.0 { -offset } else { offset };
+        // Adding the offset rounds as well, which mustn't move the point back:
+        let step = |v: Scalar, o: Scalar| {
+            if o > 0.0 {
+                (v + o).next_float_up()
+            } else if o < 0.0 {
+                (v + o).next_float_down()
+            } else {
+                v
+            }
+        };
+        Vec3 {
+            x: step(p.x, offset.x),
+            y: step(p.y, offset.y),
+            z: step(p.z, offset.z),
+        }
+    }
+}
+
+/// Converts a point computed in `f64` (the intersection of the geometry, see `Scalar`) and the bound on its
+/// error, adding the error of rounding it to the bound (which doesn't change anything if `Scalar` is `f64`).
+pub fn point_to_scalar(p: Vec3<f64>, p_error: Vec3<f64>) -> (Vec3<Scalar>, Vec3<Scalar>) {
+    let rounded: Vec3<Scalar> = p.cast();
+    let p_error = p_error + (rounded.cast::<f64>() - p).abs();
+    (rounded, p_error.cast())
+}
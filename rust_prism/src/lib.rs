@@ -0,0 +1,129 @@
+//! PRISM is a physically based renderer. The `prism-cli` binary renders scene files, but everything
+//! it does is available from this library as well, so the renderer can be embedded in other tools.
+//!
+//! A scene is built from geometry (with a material and a transformation) and lights, and is rendered
+//! with one of the integrators into a `Film`:
+//!
+//! ```
+//! use prism::camera::perspective::PerspectiveCamera;
+//! use prism::film::png::{self, BitDepth, Channels};
+//! use prism::film::ImagePixel;
+//! use prism::filter::{GaussianFilter, PixelFilter};
+//! use prism::geometry::sphere::Sphere;
+//! use prism::integrator::path_tracer::{
+//!     PathTracerIntegrator, PathTracerIntegratorManager, PathTracerParam,
+//! };
+//! use prism::light::light_picker::LightPickerKind;
+//! use prism::light::{point::Point, DirectLightParam};
+//! use prism::pmath::vector::{Vec2, Vec3};
+//! use prism::sampler::{SampleSequence, SamplerMode};
+//! use prism::scene::{Scene, SceneGeom, SceneLight, ScenePrim};
+//! use prism::shading::material::{matte::Matte, MaterialPool};
+//! use prism::spectrum::Color;
+//! use prism::threading::pixel_order::PixelOrder;
+//! use prism::threading::{self, RenderParam};
+//! use prism::transform::Transf;
+//! use std::sync::Arc;
+//!
+//! let mut materials = MaterialPool::new();
+//! let red = materials.add_material(Matte::new(Color { r: 0.8, g: 0.1, b: 0.1 }));
+//! let sphere = SceneGeom::new_material(
+//!     Arc::new(Sphere::new(1.0)),
+//!     materials.get_shared_material(red),
+//!     Transf::new_identity(),
+//! );
+//! let light = Point::new(Vec3 { x: 0.0, y: 4.0, z: -4.0 }, Color::white().scale(50.0));
+//! let prims: Vec<Arc<dyn ScenePrim>> = vec![Arc::new(sphere)];
+//! let scene = Scene::new(
+//!     &prims,
+//!     vec![SceneLight::new(Arc::new(light), Transf::new_identity())],
+//! );
+//!
+//! let param = RenderParam {
+//!     num_pixel_samples: 4,
+//!     num_threads: 4,
+//!     sample_seed: 0,
+//!     blue_noise_count: 0,
+//!     sample_sequence: SampleSequence::Pmj,
+//!     sampler_mode: SamplerMode::Scrambled,
+//!     blue_noise_dither: false,
+//!     res: Vec2 { x: 64, y: 48 },
+//!     tile_size: None,
+//!     pixel_order: PixelOrder::Scanline,
+//!     light_picker: LightPickerKind::All,
+//!     sample_dump: None,
+//!     importance_map: None,
+//!     split_buffers: false,
+//!     supersample: 1,
+//! };
+//! let view_dir = Vec3 { x: 0.0, y: 0.0, z: 1.0 };
+//! let res = param.film_res();
+//! let camera = PerspectiveCamera::frame_bbox(scene.world_bound(), 45.0, view_dir, res);
+//! let filter = PixelFilter::new(&GaussianFilter::new(Vec2 { x: 1.0, y: 1.0 }, 0.5));
+//! let int_param = PathTracerParam {
+//!     max_bounce: 8,
+//!     direct_light: DirectLightParam::default(),
+//!     aovs: false,
+//!     max_direct: None,
+//!     max_indirect: None,
+//! };
+//!
+//! let output = threading::render::<PathTracerIntegrator, PathTracerIntegratorManager>(
+//!     &camera, filter, &scene, &materials, param, int_param, None, None,
+//! )
+//! .unwrap();
+//! let image = output.film.to_image_buffer(ImagePixel::from_color);
+//!
+//! // The sphere is in the middle of the image:
+//! let center = image.get_pixel(Vec2 { x: 32, y: 24 });
+//! assert!(center.r > center.g && center.a == 1.0);
+//! let path = std::env::temp_dir().join("prism_sphere.png");
+//! png::write_png(&image, path.to_str().unwrap(), BitDepth::EIGHT, Channels::RGB).unwrap();
+//! ```
+//!
+//! Scene files can be loaded with `fileio::scene::load_scene`.
+
+// Clean this stuff up in the future...
+// This is here just for now.
+#![allow(dead_code)]
+
+pub mod bvh;
+pub mod camera;
+pub mod error;
+pub mod fileio;
+pub mod film;
+pub mod filter;
+pub mod geometry;
+pub mod integrator;
+pub mod interaction;
+pub mod light;
+pub mod progressive;
+pub mod sampler;
+pub mod scene;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+pub mod shading;
+pub mod spectrum;
+pub mod threading;
+pub mod transform;
+
+/// The math types used throughout the API.
+pub use pmath;
+
+/// The floating point type used for rendering: rays, interactions, the camera, materials, lights, and
+/// the integrators. Anything where the extra precision genuinely matters (the bounding box tests of the
+/// BVHs and the intersection of the geometry) always uses `f64`, independent of this.
+#[cfg(not(feature = "f32-render"))]
+pub type Scalar = f64;
+/// The floating point type used for rendering: rays, interactions, the camera, materials, lights, and
+/// the integrators. Anything where the extra precision genuinely matters (the bounding box tests of the
+/// BVHs and the intersection of the geometry) always uses `f64`, independent of this.
+#[cfg(feature = "f32-render")]
+pub type Scalar = f32;
+
+/// How close tests expect results computed in `Scalar` to be to the exact ones.
+#[cfg(all(test, not(feature = "f32-render")))]
+pub(crate) const TEST_EPSILON: Scalar = 1e-9;
+/// How close tests expect results computed in `Scalar` to be to the exact ones.
+#[cfg(all(test, feature = "f32-render"))]
+pub(crate) const TEST_EPSILON: Scalar = 1e-4;
@@ -1,26 +1,334 @@
-// use super::AreaLight;
-// use crate::geometry::}Interaction, Geometry};
-// use crate::spectrum::Spectrum;
-
-// // TODO:
-// // current implementation seems sort of shoddy and hacky. I want
-// // the geometry and light to be more intwined. As if there is a function
-// // that you can call to intersect a light and get's it's readiance from a specific direction
-// // in one step. Hmmm, let's think about this for a bit.
-
-// // For now the geometry attached to this type will be a generic.
-// // This could potentially get out of hand if we have a lot of geometry
-// // but seems best for now:
-// pub struct DiffuseLight<G: Geometry> {
-//     // The geometry of the light:
-//     geometry: G,
-//     // The base spectrum of the DiffuseLight
-//     radiance: Spectrum,
-// }
-
-// impl AreaLight for DiffuseLight {
-//     fn eval(int: Interaction, w: Vec3<f64>) -> Spectrum {
-//         // We simply check if the light is on the same side as the normal:
-
-//     }
-// }
+use crate::geometry::SampleableGeometry;
+use crate::interaction::Interaction;
+use crate::light::area::AreaLight;
+use crate::light::many_lights::LightBound;
+use crate::light::{Light, LightType};
+use crate::scene::{GeomRef, Scene};
+use crate::spectrum::Color;
+use crate::transform::Transf;
+use crate::Scalar;
+use pmath::bbox::BBox3;
+use pmath::numbers::Float;
+use pmath::ray::Ray;
+use pmath::sampling;
+use pmath::vector::{Vec2, Vec3};
+use std::sync::Arc;
+
+/// An area light that emits the same radiance in every direction from the front side (the side the
+/// normal points to) of its geometry, or from both sides if it's two-sided.
+pub struct DiffuseAreaLight {
+    geom: Arc<dyn SampleableGeometry>,
+    transf: Transf, // geom to world
+    radiance: Color,
+    // The geometry in the scene that this light belongs to:
+    geom_ref: GeomRef,
+    // The world space surface area (computed once, as the geometry and transformation can't change):
+    world_area: f64,
+    two_sided: bool,
+}
+
+impl DiffuseAreaLight {
+    const LIGHT_TYPE: LightType = LightType::AREA;
+
+    /// Constructs a new one-sided area light. `geom_ref` has to refer to the `SceneGeom` with the same geometry
+    /// and transformation, so that rays hitting the light can be identified.
+    ///
+    /// # Panics
+    /// If the surface area of the geometry wasn't calculated.
+    pub fn new(
+        geom: Arc<dyn SampleableGeometry>,
+        transf: Transf,
+        radiance: Color,
+        geom_ref: GeomRef,
+    ) -> Self {
+        assert!(geom.get_surface_area() >= 0.0);
+        let world_area = geom.world_surface_area(transf);
+        DiffuseAreaLight {
+            geom,
+            transf,
+            radiance,
+            geom_ref,
+            world_area,
+            two_sided: false,
+        }
+    }
+
+    /// Sets whether the back side of the geometry emits light as well (which doubles the power).
+    pub fn with_two_sided(mut self, two_sided: bool) -> Self {
+        self.two_sided = two_sided;
+        self
+    }
+
+    /// Returns the cosine between the world space normal of the light and `w` (pointing away from the
+    /// light), where the back side of a two-sided light counts as the front.
+    fn emitted_cos(&self, n: Vec3<f64>, w: Vec3<f64>) -> f64 {
+        let cos = n.dot(w);
+        if self.two_sided {
+            cos.abs()
+        } else {
+            cos
+        }
+    }
+
+    /// Returns the ratio of a world space area to the geometry space area at a point on the surface with
+    /// the geometry space normal `n`, along with the world space normal.
+    fn area_scale(&self, n: Vec3<f64>) -> (f64, Vec3<f64>) {
+        let m = self.transf.get_frd();
+        let det = m.get_column(0).dot(m.get_column(1).cross(m.get_column(2)));
+        let world_n = self.transf.get_nrm().mul_vec_zero(n);
+        (det.abs() * world_n.length(), world_n.normalize())
+    }
+}
+
+// The geometry is sampled and intersected in f64 (see `Scalar`):
+impl Light for DiffuseAreaLight {
+    fn sample(
+        &self,
+        point: Vec3<Scalar>,
+        _time: Scalar,
+        _scene: &Scene,
+        u: Vec2<Scalar>,
+    ) -> (Color, Vec3<Scalar>, Scalar) {
+        let point = point.cast::<f64>();
+        let surface_sample = self.geom.sample_surface(u.cast());
+        let light_point = self.transf.point(surface_sample.p);
+        let (area_scale, n) = self.area_scale(surface_sample.n);
+
+        let wi = light_point - point;
+        let dist2 = wi.length2();
+        // Only the front side emits light (unless the light is two-sided):
+        let cos_light = self.emitted_cos(n, -wi) / dist2.sqrt();
+        if (dist2 == 0.0) || (cos_light <= 0.0) {
+            return (Color::black(), light_point.cast(), 0.0);
+        }
+
+        // Convert the pdf from area to solid angle:
+        let pdf = (surface_sample.pdf / area_scale) * dist2 / cos_light;
+        (self.radiance, light_point.cast(), Scalar::from_f64(pdf))
+    }
+
+    fn pdf(&self, shading_point: Vec3<Scalar>, wi: Vec3<Scalar>) -> Scalar {
+        let (shading_point, wi) = (shading_point.cast::<f64>(), wi.cast::<f64>());
+        let geom_space_ray = self.transf.inverse().ray(Ray::new(shading_point, wi, 0.0));
+        let interaction = match self.geom.intersect(geom_space_ray) {
+            Some(interaction) => interaction,
+            None => return 0.0,
+        };
+
+        let light_point = self.transf.point(interaction.p.cast());
+        let (area_scale, n) = self.area_scale(interaction.n.cast());
+        let dist2 = (light_point - shading_point).length2();
+        let cos_light = self.emitted_cos(n, -wi.normalize());
+        if cos_light <= 0.0 {
+            return 0.0;
+        }
+
+        Scalar::from_f64(dist2 / (self.geom.get_surface_area() * area_scale * cos_light))
+    }
+
+    fn power(&self) -> Color {
+        let sides = if self.two_sided { 2.0 } else { 1.0 };
+        self.radiance
+            .scale(Scalar::from_f64(sides * f64::PI * self.world_area))
+    }
+
+    /// The caller is responsible for making sure `w` is on the front side of the light (see
+    /// `AreaLight::eval`).
+    fn eval(&self, _: Vec3<Scalar>, _: Vec3<Scalar>) -> Color {
+        self.radiance
+    }
+
+    fn is_delta(&self) -> bool {
+        Self::LIGHT_TYPE.contains(LightType::DELTA_POSITION)
+            || Self::LIGHT_TYPE.contains(LightType::DELTA_DIRECTION)
+    }
+
+    fn get_geom(&self) -> Option<GeomRef> {
+        Some(self.geom_ref)
+    }
+
+    fn get_centroid(&self) -> Vec3<Scalar> {
+        self.transf.point(self.geom.get_bbox().centroid()).cast()
+    }
+
+    /// The light can be anywhere on the geometry, and any geometry can face any direction.
+    fn get_bound(&self) -> LightBound {
+        let bbox = self.transf.bbox(self.geom.get_bbox());
+        LightBound::new_omni(
+            BBox3::from_pnts(bbox.pmin.cast(), bbox.pmax.cast()),
+            self.power().luminance(),
+        )
+    }
+
+    fn is_two_sided(&self) -> bool {
+        self.two_sided
+    }
+
+    fn emits_photons(&self) -> bool {
+        true
+    }
+
+    fn sample_le(
+        &self,
+        time: Scalar,
+        u_pos: Vec2<Scalar>,
+        u_dir: Vec2<Scalar>,
+    ) -> Option<(Color, Ray<Scalar>)> {
+        let u_dir = u_dir.cast::<f64>();
+        let surface_sample = self.geom.sample_surface(u_pos.cast());
+        let (area_scale, n) = self.area_scale(surface_sample.n);
+        let pdf_pos = surface_sample.pdf / area_scale;
+        if !(pdf_pos > 0.0) {
+            return None;
+        }
+
+        // Two-sided lights pick a side with the first half of `u_dir.x`:
+        let (side, u_dir, sides) = if self.two_sided {
+            let side = if u_dir.x < 0.5 { 1.0 } else { -1.0 };
+            let u_x = (2.0 * u_dir.x) % 1.0;
+            (side, Vec2 { x: u_x, y: u_dir.y }, 2.0)
+        } else {
+            (1.0, u_dir, 1.0)
+        };
+
+        // The directions are cosine weighted, so the cosine cancels out with the pdf (leaving pi):
+        let local_dir = sampling::cos_sample_hemisphere(u_dir);
+        let (s, t) = pmath::coord_system(n);
+        let dir = (s.normalize().scale(local_dir.x)
+            + t.normalize().scale(local_dir.y)
+            + n.scale(local_dir.z))
+        .scale(side);
+        let power = self
+            .radiance
+            .scale(Scalar::from_f64(sides * f64::PI / pdf_pos));
+        let light_point = self.transf.point(surface_sample.p);
+        Some((power, Ray::new(light_point.cast(), dir.cast(), time)))
+    }
+}
+
+impl AreaLight for DiffuseAreaLight {
+    fn eval(&self, int: Interaction, w: Vec3<Scalar>) -> Color {
+        if self.two_sided || (int.n.dot(w) > 0.0) {
+            self.radiance
+        } else {
+            Color::black()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::BuildAlgorithm;
+    use crate::geometry::mesh::{Mesh, Triangle};
+    use crate::geometry::rect::Rect;
+    use crate::geometry::Geometry;
+    use crate::scene::{SceneGeom, ScenePrim};
+    use crate::shading::material::{MaterialPool, DEFAULT_MATERIAL_ID};
+
+    /// A unit square in the xy plane (facing +z) as an analytic rectangle and as a mesh.
+    fn unit_squares() -> Vec<Arc<dyn SampleableGeometry>> {
+        let mut rect = Rect::new(Vec2 { x: 1.0, y: 1.0 });
+        rect.calc_surface_area();
+        let v = |x: f32, y: f32| Vec3 { x, y, z: 0.0 };
+        let triangles = vec![[0, 1, 2], [0, 2, 3]]
+            .into_iter()
+            .map(|indices| Triangle {
+                indices,
+                attribute_id: 0,
+            })
+            .collect();
+        let mut mesh = Mesh::new(
+            triangles,
+            vec![v(-0.5, -0.5), v(0.5, -0.5), v(0.5, 0.5), v(-0.5, 0.5)],
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            4,
+            BuildAlgorithm::Sah,
+        );
+        mesh.calc_surface_area();
+        vec![Arc::new(rect), Arc::new(mesh)]
+    }
+
+    fn area_light(geom: Arc<dyn SampleableGeometry>, transf: Transf) -> DiffuseAreaLight {
+        let material = MaterialPool::new().get_shared_material(DEFAULT_MATERIAL_ID);
+        let scene_geom = SceneGeom::new_material(geom.clone(), material, transf);
+        DiffuseAreaLight::new(geom, transf, Color::white(), scene_geom.geom_ref())
+    }
+
+    /// Estimates the irradiance at `point` (facing `n`) from the light with stratified light samples, the
+    /// same way the direct lighting does.
+    fn irradiance(light: &DiffuseAreaLight, point: Vec3<Scalar>, n: Vec3<Scalar>) -> Scalar {
+        const N: usize = 32;
+        let scene = Scene::new(&Vec::<Arc<dyn ScenePrim>>::new(), Vec::new());
+        let mut sum = 0.0;
+        for i in 0..(N * N) {
+            let u = Vec2 {
+                x: ((i % N) as Scalar + 0.5) / (N as Scalar),
+                y: ((i / N) as Scalar + 0.5) / (N as Scalar),
+            };
+            let (color, light_point, pdf) = light.sample(point, 0.0, &scene, u);
+            if pdf > 0.0 {
+                let cos = (light_point - point).normalize().dot(n).max(0.0);
+                sum += color.r * cos / pdf;
+            }
+        }
+        sum / ((N * N) as Scalar)
+    }
+
+    #[test]
+    fn scaling_a_light_scales_its_power_and_illumination() {
+        // Far enough away that the light is (almost) a point light:
+        let point = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 20.0,
+        };
+        let down = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: -1.0,
+        };
+        for geom in unit_squares() {
+            let light = area_light(geom.clone(), Transf::new_identity());
+            let scaled = area_light(
+                geom,
+                Transf::new_scale(Vec3 {
+                    x: 2.0,
+                    y: 2.0,
+                    z: 2.0,
+                }),
+            );
+            assert!((light.power().r - Scalar::PI).abs() < 1e-9);
+            assert!((scaled.power().r - 4.0 * Scalar::PI).abs() < 1e-9);
+            let ratio = irradiance(&scaled, point, down) / irradiance(&light, point, down);
+            assert!((ratio - 4.0).abs() < 0.02, "{}", ratio);
+        }
+    }
+
+    #[test]
+    fn only_two_sided_lights_emit_from_the_back() {
+        let front = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 2.0,
+        };
+        let back = -front;
+        let to_light = |p: Vec3<Scalar>| -p.normalize();
+        for geom in unit_squares() {
+            let light = area_light(geom.clone(), Transf::new_identity());
+            let front_light = irradiance(&light, front, to_light(front));
+            assert!(front_light > 0.0);
+            assert_eq!(irradiance(&light, back, to_light(back)), 0.0);
+            assert_eq!(light.pdf(back, to_light(back)), 0.0);
+
+            let two_sided = area_light(geom, Transf::new_identity()).with_two_sided(true);
+            let back_light = irradiance(&two_sided, back, to_light(back));
+            assert!((back_light - front_light).abs() < 1e-9 * front_light);
+            assert!(two_sided.pdf(back, to_light(back)) > 0.0);
+            assert!((two_sided.power().r - 2.0 * light.power().r).abs() < 1e-9);
+        }
+    }
+}
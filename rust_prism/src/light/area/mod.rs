@@ -4,8 +4,9 @@
 pub mod diffuse;
 
 use super::Light;
-use crate::geometry::GeomInteraction;
+use crate::interaction::Interaction;
 use crate::spectrum::Color;
+use crate::Scalar;
 use pmath::vector::Vec3;
 
 // An area light is a special type of light that is associated with some
@@ -14,5 +15,5 @@ use pmath::vector::Vec3;
 pub trait AreaLight: Light {
     // int: the point of interaction
     // w: the direction from which the light is coming (pointed away from the surface)
-    fn eval(&self, int: GeomInteraction, w: Vec3<f64>) -> Color;
+    fn eval(&self, int: Interaction, w: Vec3<Scalar>) -> Color;
 }
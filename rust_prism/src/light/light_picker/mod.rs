@@ -1,46 +1,143 @@
 pub mod uniform_all;
 pub mod uniform_one;
 
-use crate::geometry::GeomInteraction;
-use crate::light;
+use crate::interaction::Interaction;
+use crate::light::{self, DirectLightParam};
 use crate::sampler::Sampler;
 use crate::scene::Scene;
 use crate::shading::material::Bsdf;
 use crate::spectrum::Color;
+use crate::Scalar;
 use pmath::vector::Vec3;
+use serde::Deserialize;
+use uniform_all::UniformAll;
+use uniform_one::UniformOne;
 
-/// Generates an iterator to iterate over all of the lights that were chosen.
-pub trait LightPicker<I: Iterator<Item = (u32, f64)>> {
+/// Picks the lights to sample at a shading point.
+pub trait LightPicker: Sync {
     /// All lights in the scene are described using a Light ID starting from 0 to `num_lights` (exclusive).
     /// If any allocation is required, make sure to do that in this step.
     fn set_scene_lights(&mut self, num_lights: u32, scene: &Scene);
 
-    /// Picks a number of lights and returns an iterator to those lights.
+    /// The largest number of lights that `pick_lights` picks at once.
+    fn max_picked(&self) -> u32;
+
+    /// A hint for how many times more samples a light deserves than `DirectLightParam::n_light_samples`
+    /// (large area lights are usually worth more samples than small ones, for instance).
+    fn samples_for(&self, _light_id: u32) -> u32 {
+        1
+    }
+
+    /// The largest total of `samples_for` over the lights that `pick_lights` picks at once.
+    fn max_samples(&self) -> u32 {
+        self.max_picked()
+    }
+
+    /// Picks a number of lights, writing the light ids and the amount to scale their contribution by
+    /// into `picked` (which is cleared first). `u` is a uniform random number for pickers that pick
+    /// randomly. The caller keeps `picked` around between calls so that picking lights doesn't allocate.
     fn pick_lights(
         &self,
-        shading_point: Vec3<f64>,
-        normal: Vec3<f64>,
-        sampler: &mut Sampler,
+        shading_point: Vec3<Scalar>,
+        normal: Vec3<Scalar>,
+        u: Scalar,
         scene: &Scene,
-    ) -> I;
+        picked: &mut Vec<(u32, Scalar)>,
+    );
+}
+
+/// The light pickers the renderer can use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum LightPickerKind {
+    /// Every light is sampled at every shading point (see `UniformAll`).
+    All,
+    /// A single light is picked at random at every shading point (see `UniformOne`), which is cheaper in
+    /// scenes with many lights, but noisier.
+    One,
+}
+
+impl Default for LightPickerKind {
+    fn default() -> Self {
+        LightPickerKind::All
+    }
 }
 
-/// Samples all of the lights in a scene given a light picker.
-pub fn sample_lights<I: Iterator<Item = (u32, f64)>, L: LightPicker<I>>(
-    interaction: GeomInteraction,
+impl LightPickerKind {
+    /// Creates the light picker for the lights of the scene.
+    pub fn create(self, scene: &Scene) -> Box<dyn LightPicker> {
+        let mut light_picker: Box<dyn LightPicker> = match self {
+            LightPickerKind::All => Box::new(UniformAll::new()),
+            LightPickerKind::One => Box::new(UniformOne::new()),
+        };
+        light_picker.set_scene_lights(scene.num_lights() as u32, scene);
+        light_picker
+    }
+}
+
+// The sampler dimension used for picking the lights (see `Sampler::sample_dimension`):
+const LIGHT_PICK_DIMENSION: u32 = 0x1b873593;
+
+/// Requests the sample arrays that `sample_lights` uses (see `Sampler::request_2d_array`): one for the
+/// positions on the lights, and one for sampling the bsdf. They are only used at the first shading point
+/// of a path (later ones use regular samples).
+pub fn request_samples(
+    sampler: &mut Sampler,
+    light_picker: &dyn LightPicker,
+    param: DirectLightParam,
+) {
+    sampler.request_2d_array((light_picker.max_samples() * param.n_light_samples) as usize);
+    sampler.request_2d_array((light_picker.max_picked() * param.n_bsdf_samples) as usize);
+}
+
+/// Samples all of the lights in a scene given a light picker. `picked` is scratch space for the picked
+/// lights (see `LightPicker::pick_lights`). The lights are picked with a dimension of the sampler of its
+/// own at every bounce (see `Sampler::start_bounce`), so the choices don't alias with the samples of the
+/// bsdf.
+pub fn sample_lights(
+    interaction: Interaction,
     bsdf: &Bsdf,
-    time: f64,
+    time: Scalar,
     scene: &Scene,
     sampler: &mut Sampler,
-    light_picker: &L,
+    light_picker: &dyn LightPicker,
+    picked: &mut Vec<(u32, Scalar)>,
+    param: DirectLightParam,
 ) -> Color {
-    let light_iter = light_picker.pick_lights(interaction.p, interaction.shading_n, sampler, scene);
+    // Every light gets a stratum of `u`, so that the lights a pixel picks are stratified over its paths:
+    let u = sampler.sample_choice(LIGHT_PICK_DIMENSION, scene.num_lights() as u32);
+    // Use the stratified arrays if there are any left for this pixel sample:
+    let light_samples = sampler.next_2d_array();
+    let bsdf_samples = sampler.next_2d_array();
+
+    light_picker.pick_lights(interaction.p, interaction.shading_n(), u, scene, picked);
     let mut final_color = Color::black();
-    for (light_id, light_scale) in light_iter {
+    // Where the samples of the next light start in the arrays:
+    let mut offset = 0;
+    let mut bsdf_offset = 0;
+    for &(light_id, light_scale) in picked.iter() {
+        // Every light gets as many samples as the light picker recommends:
+        let param = DirectLightParam {
+            n_light_samples: param.n_light_samples * light_picker.samples_for(light_id),
+            ..param
+        };
+        let light_samples = light_samples.map(|array| (array, offset));
+        let bsdf_samples = bsdf_samples.map(|array| (array, bsdf_offset));
+        offset += param.n_light_samples as usize;
+        bsdf_offset += param.n_bsdf_samples as usize;
         // TODO: explore whether to make specular false.
-        final_color +=
-            light::estimate_direct_light(interaction, bsdf, time, sampler, scene, light_id, false)
-                .scale(light_scale);
+        final_color += light::estimate_direct_light(
+            interaction,
+            bsdf,
+            time,
+            sampler,
+            light_samples,
+            bsdf_samples,
+            scene,
+            light_id,
+            false,
+            param,
+        )
+        .scale(light_scale);
     }
 
     final_color
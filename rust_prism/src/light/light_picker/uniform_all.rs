@@ -1,53 +1,89 @@
 use crate::light::light_picker::LightPicker;
-use crate::sampler::Sampler;
 use crate::scene::Scene;
+use crate::Scalar;
 use pmath::vector::Vec3;
 
+/// Picks every light in the scene (with a weight of 1). Optionally, brighter lights get more samples than
+/// dimmer ones (see `with_sample_counts`).
 pub struct UniformAll {
     max_num_lights: u32,
+    // The most samples a single light can get (1 if every light gets the same number of samples):
+    max_light_samples: u32,
+    // The number of samples of every light (see `LightPicker::samples_for`):
+    samples: Vec<u32>,
 }
 
-/// This one just uniformly samples all available lights in a scene.
 impl UniformAll {
     pub fn new() -> Self {
-        UniformAll { max_num_lights: 0 }
+        UniformAll {
+            max_num_lights: 0,
+            max_light_samples: 1,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Gives every light a number of samples proportional to its power (relative to the average power
+    /// of the lights), from 1 up to `max_light_samples`. Delta lights always get a single sample, as
+    /// every sample of them is the same.
+    pub fn with_sample_counts(max_light_samples: u32) -> Self {
+        UniformAll {
+            max_light_samples: max_light_samples.max(1),
+            ..UniformAll::new()
+        }
     }
 }
 
-impl LightPicker<UniformAllIter> for UniformAll {
-    fn set_scene_lights(&mut self, num_lights: usize, _scene: &Scene) {
+impl LightPicker for UniformAll {
+    fn set_scene_lights(&mut self, num_lights: u32, scene: &Scene) {
         self.max_num_lights = num_lights;
+        self.samples.clear();
+        if self.max_light_samples == 1 {
+            self.samples.resize(num_lights as usize, 1);
+            return;
+        }
+
+        let powers: Vec<Scalar> = (0..num_lights)
+            .map(|light_id| scene.get_light(light_id).power().luminance().max(0.0))
+            .collect();
+        let average_power = powers.iter().sum::<Scalar>() / (num_lights.max(1) as Scalar);
+        let max_light_samples = self.max_light_samples;
+        self.samples.extend(
+            (0..num_lights)
+                .zip(powers.iter())
+                .map(|(light_id, &power)| {
+                    if scene.get_light(light_id).is_delta() || (average_power <= 0.0) {
+                        1
+                    } else {
+                        ((power / average_power).round() as u32)
+                            .max(1)
+                            .min(max_light_samples)
+                    }
+                }),
+        );
     }
 
-    fn pick_lights<'a>(
-        &mut self,
-        _shading_point: Vec3<f64>,
-        _normal: Vec3<f64>,
-        _sampler: &mut Sampler,
-        _scene: &Scene,
-    ) -> UniformAllIter {
-        // Fairly straight forward as it just goes through all of the lights uniformly
-        UniformAllIter {
-            max_num_lights: self.max_num_lights,
-            curr_light_num: 0,
-        }
+    fn max_picked(&self) -> u32 {
+        self.max_num_lights
     }
-}
 
-pub struct UniformAllIter {
-    max_num_lights: u32,
-    curr_light_num: u32,
-}
+    fn samples_for(&self, light_id: u32) -> u32 {
+        self.samples[light_id as usize]
+    }
 
-impl Iterator for UniformAllIter {
-    type Item = (u32, f64);
+    fn max_samples(&self) -> u32 {
+        self.samples.iter().sum()
+    }
 
-    fn next(&mut self) -> Option<(u32, f64)> {
-        if self.curr_light_num < self.max_num_lights {
-            self.curr_light_num += 1;
-            Some((self.curr_light_num - 1, 1.0))
-        } else {
-            None
-        }
+    fn pick_lights(
+        &self,
+        _shading_point: Vec3<Scalar>,
+        _normal: Vec3<Scalar>,
+        _u: Scalar,
+        _scene: &Scene,
+        picked: &mut Vec<(u32, Scalar)>,
+    ) {
+        // Fairly straight forward as it just goes through all of the lights uniformly
+        picked.clear();
+        picked.extend((0..self.max_num_lights).map(|light_id| (light_id, 1.0)));
     }
 }
@@ -3,6 +3,7 @@
 // Estevez and Kulla.
 
 use crate::light::Light;
+use crate::Scalar;
 use partition;
 use pmath::bbox::BBox3;
 use pmath::matrix::Mat3x4;
@@ -12,9 +13,9 @@ use pmath::vector::Vec3;
 /// A light cone represents the extent of a light
 #[derive(Copy, Clone, Debug)]
 pub struct Cone {
-    axis: Vec3<f64>,
-    theta_o: f64, // All angles are in radians
-    theta_e: f64,
+    axis: Vec3<Scalar>,
+    theta_o: Scalar, // All angles are in radians
+    theta_e: Scalar,
 }
 
 impl Cone {
@@ -28,16 +29,23 @@ impl Cone {
     }
 
     /// Construct a new `LightCone`:
-    pub fn new(axis: Vec3<f64>, theta_o: f64, theta_e: f64) -> Self {
+    pub fn new(axis: Vec3<Scalar>, theta_o: Scalar, theta_e: Scalar) -> Self {
         Cone {
             axis: axis.normalize(),
-            theta_o: theta_o.max(0.0).min(f64::PI), // Make sure theta_o is in [0, PI]
-            theta_e: theta_e.max(0.0).min(f64::PI_OVER_2), // Make sure theta_e is in [0, PI/2]
+            theta_o: theta_o.max(0.0).min(Scalar::PI), // Make sure theta_o is in [0, PI]
+            theta_e: theta_e.max(0.0).min(Scalar::PI_OVER_2), // Make sure theta_e is in [0, PI/2]
         }
     }
 
     /// Combines two `LightCone`s into one `LightCone` that encompasses everything.
     fn combine(self, b: Cone) -> Self {
+        // An initial cone doesn't have an axis to rotate around:
+        if self.axis == Vec3::zero() {
+            return b;
+        } else if b.axis == Vec3::zero() {
+            return self;
+        }
+
         // Ensure that a.theta_o > b.theta_o
         let (a, b) = if self.theta_o > b.theta_o {
             (self, b)
@@ -48,7 +56,7 @@ impl Cone {
         let theta_d = a.axis.dot(b.axis).acos();
         let theta_e = a.theta_e.max(b.theta_e);
 
-        if f64::PI.min(theta_d + b.theta_o) <= a.theta_o {
+        if Scalar::PI.min(theta_d + b.theta_o) <= a.theta_o {
             return Cone {
                 axis: a.axis,
                 theta_o: a.theta_o,
@@ -57,10 +65,10 @@ impl Cone {
         }
 
         let theta_o = (a.theta_o + theta_d + b.theta_o) * 0.5;
-        if f64::PI <= theta_o {
+        if Scalar::PI <= theta_o {
             return Cone {
                 axis: a.axis,
-                theta_o: f64::PI,
+                theta_o: Scalar::PI,
                 theta_e,
             };
         }
@@ -79,16 +87,16 @@ impl Cone {
         }
     }
 
-    fn surface_area_orientation_heuristic(self) -> f64 {
-        let theta_w = f64::PI.min(self.theta_o + self.theta_e);
+    fn surface_area_orientation_heuristic(self) -> Scalar {
+        let theta_w = Scalar::PI.min(self.theta_o + self.theta_e);
         let (sin_theta_o, cos_theta_o) = self.theta_o.sin_cos();
 
-        let a = 2.0 * f64::PI * (1.0 - cos_theta_o);
+        let a = 2.0 * Scalar::PI * (1.0 - cos_theta_o);
         let b = 2.0 * theta_w * sin_theta_o
             - (self.theta_o - 2.0 * theta_w).cos()
             - 2.0 * self.theta_o * sin_theta_o
             + cos_theta_o;
-        a + f64::PI_OVER_2 * b
+        a + Scalar::PI_OVER_2 * b
     }
 }
 
@@ -101,13 +109,15 @@ const MIN_LIGHT_LEAF_COUNT: usize = 4;
 /// to perform the necessary computations.
 #[derive(Clone, Copy, Debug)]
 pub struct ShadingInfo {
-    pub pos: Vec3<f64>, // In world space
-    pub nrm: Vec3<f64>, // In world space
+    pub pos: Vec3<Scalar>, // In world space
+    pub nrm: Vec3<Scalar>, // In world space
 }
 
 pub struct LightBVH {
     lights: Vec<LightInfo>, // An array of light indices
     nodes: Vec<Node>,
+    // The bounds of the two children of every internal node (see `ChildBounds`):
+    child_bounds: Vec<ChildBounds>,
 }
 
 impl LightBVH {
@@ -124,46 +134,105 @@ impl LightBVH {
                 centroid: light.get_centroid(),
             })
             .collect();
+        Self::from_lights(lights)
+    }
 
-        // Then construct the bvh recursively:
+    fn from_lights(mut lights: Vec<LightInfo>) -> Self {
+        // Construct the bvh recursively:
         let mut nodes = Vec::new();
+        let mut child_bounds = Vec::new();
         let mut ordered_lights = Vec::new();
-        Self::rec_construct_bvh(&mut lights, &mut ordered_lights, &mut nodes);
+        Self::rec_construct_bvh(
+            &mut lights,
+            &mut ordered_lights,
+            &mut nodes,
+            &mut child_bounds,
+        );
 
         nodes.shrink_to_fit();
+        child_bounds.shrink_to_fit();
         ordered_lights.shrink_to_fit();
 
         // Now go ahead and return them:
         LightBVH {
             lights: ordered_lights,
             nodes,
+            child_bounds,
         }
     }
 
+    /// The bounding box and depth of every node (the root is at depth 0), in no particular order.
+    pub fn node_bounds(&self) -> Vec<(u32, BBox3<Scalar>)> {
+        if self.nodes.is_empty() {
+            return Vec::new();
+        }
+        // Only the parents store the bounds of their children, so the root is the bound of every light:
+        let root_bbox = self
+            .lights
+            .iter()
+            .fold(BBox3::new_initial(), |bbox, light| {
+                bbox.combine_bnd(light.bound.bbox)
+            });
+        let mut bounds = Vec::with_capacity(self.nodes.len());
+        let mut stack = vec![(0, 0, root_bbox)];
+        while let Some((node_index, depth, bbox)) = stack.pop() {
+            bounds.push((depth, bbox));
+            if let Node::Internal {
+                left,
+                right,
+                child_bounds,
+            } = self.nodes[node_index]
+            {
+                let child_bounds = &self.child_bounds[child_bounds];
+                let child_bbox = |i: usize| BBox3 {
+                    pmin: Vec3 {
+                        x: child_bounds.pmin_x[i],
+                        y: child_bounds.pmin_y[i],
+                        z: child_bounds.pmin_z[i],
+                    },
+                    pmax: Vec3 {
+                        x: child_bounds.pmax_x[i],
+                        y: child_bounds.pmax_y[i],
+                        z: child_bounds.pmax_z[i],
+                    },
+                };
+                stack.push((left, depth + 1, child_bbox(0)));
+                stack.push((right, depth + 1, child_bbox(1)));
+            }
+        }
+        bounds
+    }
+
     // Given a shading point and a random value, returns the index of the light:
-    pub fn sample(&self, shading_info: ShadingInfo, u: f64) -> usize {
+    pub fn sample(&self, shading_info: ShadingInfo, u: Scalar) -> usize {
         self.rec_sample(0, shading_info, u)
     }
 
-    fn rec_sample(&self, curr_root: usize, shading_info: ShadingInfo, u: f64) -> usize {
+    fn rec_sample(&self, curr_root: usize, shading_info: ShadingInfo, u: Scalar) -> usize {
         let mut pdfs = [0.0; Self::MAX_LIGHT_PER_LEAF];
         match self.nodes[curr_root] {
             Node::Leaf {
-                bound,
                 light_index,
                 num_lights,
             } => {
                 for (l, pdfs) in self.lights[light_index..(light_index + num_lights)]
                     .iter()
-                    .zip(pdfs.iter())
+                    .zip(pdfs.iter_mut())
                 {
-                    *pdfs = Self::importance(l.bound, shading_info);
+                    *pdfs = ChildBounds::from_bound(l.bound).importance(shading_info)[0];
                 }
-                Self::sample_discrete_pdf(&pdfs, u)
+                let picked = Self::sample_discrete_pdf(&pdfs[..num_lights], u);
+                self.lights[light_index + picked].index
             }
-            Node::Internal { bound, left, right } => {
-                let i_left = Self::importance(bound, shading_info);
-                let i_right = Self::importance(bound, shading_info);
+            Node::Internal {
+                left,
+                right,
+                child_bounds,
+            } => {
+                let [i_left, i_right] = self.child_bounds[child_bounds].importance(shading_info);
+                if (i_left + i_right) <= 0.0 {
+                    return self.rec_sample(left, shading_info, u);
+                }
                 if u < i_left / (i_left + i_right) {
                     let u = u * (i_left + i_right) / i_left;
                     self.rec_sample(left, shading_info, u)
@@ -175,29 +244,12 @@ impl LightBVH {
         }
     }
 
-    // This isn't the most efficient implementation, but it's good enough for now...
-    fn importance(bound: LightBound, shading_info: ShadingInfo) -> f64 {
-        let bbox_center = bound.bbox.center();
-        let d = bbox_center - shading_info.pos;
-        let theta_i = {
-            let dot = d.normalize().dot(shading_info.nrm.normalize());
-            if dot < 0.0 {
-                dot.acos()
-            } else {
-                dot.acos()
-            }
-        };
-
-        let d2 = d.length2();
-        0.
-    }
-
     /// Given a collection of pdfs and a random value, return the light index that
     /// we had sampled here.
-    fn sample_discrete_pdf(pdfs: &[f64], u: f64) -> usize {
+    fn sample_discrete_pdf(pdfs: &[Scalar], u: Scalar) -> usize {
         // Normalize the bloody pdf by summing over them:
         let inv_total_pdfs = {
-            let total_pdfs: f64 = pdfs.iter().sum();
+            let total_pdfs: Scalar = pdfs.iter().sum();
             1.0 / total_pdfs
         };
         let mut curr_cdf = 0.0;
@@ -210,47 +262,60 @@ impl LightBVH {
         0
     }
 
-    /// Recursively constructs the bvh given a collection of lights.
+    /// Recursively constructs the bvh given a collection of lights, returning the index of the node that
+    /// was created and the bound over all of the lights.
     fn rec_construct_bvh(
         lights: &mut [LightInfo],
         ordered_lights: &mut Vec<LightInfo>,
         nodes: &mut Vec<Node>,
-    ) {
+        child_bounds: &mut Vec<ChildBounds>,
+    ) -> (usize, LightBound) {
         let global_bound = lights
             .iter()
             .fold(LightBound::new_initial(), |accum, light| {
                 accum.combine(light.bound)
             });
 
-        // Check the number of lights and see if we should make a leaf or not:
-        if lights.len() < MIN_LIGHT_LEAF_COUNT {
-            let light_index = ordered_lights.len();
-            ordered_lights.extend(lights.iter());
-            nodes.push(Node::Leaf {
-                bound: global_bound,
-                light_index,
-                num_lights: lights.len(),
-            })
-        }
+        // Check the number of lights and see if we should make a leaf or not. Otherwise, we can try
+        // to split (if it's worth the cost):
+        let split = if lights.len() < MIN_LIGHT_LEAF_COUNT {
+            None
+        } else {
+            Self::split_clusters(lights, global_bound)
+        };
 
-        // Otherwise, we can try to split:
-        match Self::split_clusters(lights, global_bound) {
+        let node_index = nodes.len();
+        match split {
             Some((left, right)) => {
+                // Reserve the node, so that the root ends up at index 0:
+                nodes.push(Node::Leaf {
+                    light_index: 0,
+                    num_lights: 0,
+                });
+
                 // We recursively build the left and right one:
-                Self::rec_construct_bvh(left, ordered_lights, nodes);
-                Self::rec_construct_bvh(right, ordered_lights, nodes);
+                let (left, left_bound) =
+                    Self::rec_construct_bvh(left, ordered_lights, nodes, child_bounds);
+                let (right, right_bound) =
+                    Self::rec_construct_bvh(right, ordered_lights, nodes, child_bounds);
+
+                nodes[node_index] = Node::Internal {
+                    left,
+                    right,
+                    child_bounds: child_bounds.len(),
+                };
+                child_bounds.push(ChildBounds::from_pair(left_bound, right_bound));
             }
             None => {
-                // Don't bother splitting (not worth the cost), so go ahead:
                 let light_index = ordered_lights.len();
                 ordered_lights.extend(lights.iter());
                 nodes.push(Node::Leaf {
-                    bound: global_bound,
                     light_index,
                     num_lights: lights.len(),
                 })
             }
         }
+        (node_index, global_bound)
     }
 
     /// Attempts to split the cluster along a given axis. Returns a pair if a split was performed. If no
@@ -268,7 +333,7 @@ impl LightBVH {
         let bbox_diagonal = global_bound.bbox.diagonal();
         let bbox_max_length = bbox_diagonal[bbox_diagonal.max_dim()];
 
-        let mut global_min_cost = f64::INFINITY;
+        let mut global_min_cost = Scalar::INFINITY;
         let mut global_min_bin = 0;
         let mut global_min_axis = 0;
 
@@ -280,8 +345,8 @@ impl LightBVH {
             // Go through all the lights and place them into different sets of buckets:
             for l in lights.iter() {
                 // Get the bucket index for the current primitive:
-                let b = (BIN_COUNT as f64) * global_bound.bbox.offset(l.centroid)[axis];
-                let b = if b >= (BIN_COUNT as f64) {
+                let b = (BIN_COUNT as Scalar) * global_bound.bbox.offset(l.centroid)[axis];
+                let b = if b >= (BIN_COUNT as Scalar) {
                     BIN_COUNT - 1
                 } else {
                     b.floor() as usize
@@ -290,7 +355,7 @@ impl LightBVH {
                 bins[b] = bins[b].combine(l.bound);
             }
 
-            let mut min_cost = f64::INFINITY;
+            let mut min_cost = Scalar::INFINITY;
             let mut min_bin = 0;
 
             // Compute the regularization factor so that thin bounds aren't taken:
@@ -340,8 +405,8 @@ impl LightBVH {
 
         // Now we go ahead and perform the partition:
         let (left_part, right_part) = partition::partition(lights, |l| {
-            let b = (BIN_COUNT as f64) * global_bound.bbox.offset(l.centroid)[global_min_axis];
-            let b = if b >= (BIN_COUNT as f64) {
+            let b = (BIN_COUNT as Scalar) * global_bound.bbox.offset(l.centroid)[global_min_axis];
+            let b = if b >= (BIN_COUNT as Scalar) {
                 BIN_COUNT - 1
             } else {
                 b.floor() as usize
@@ -353,30 +418,136 @@ impl LightBVH {
     }
 }
 
-/// The Node used when constructing the tree.
+/// The nodes of the tree. The bounds of the children of internal nodes are stored separately (see
+/// `ChildBounds`), as they are only needed for the importance.
 #[derive(Clone, Copy, Debug)]
 enum Node {
     Internal {
-        bound: LightBound,
         left: usize,
         right: usize,
+        // Index into `LightBVH::child_bounds`:
+        child_bounds: usize,
     },
     Leaf {
-        bound: LightBound,
         light_index: usize,
         num_lights: usize,
     },
 }
 
+/// The bounds of both children of an internal node. Every property is stored for the two children next
+/// to each other (instead of one `LightBound` per child), so that the importance of both children is
+/// computed together (and the compiler can use one SSE register for both).
+#[derive(Clone, Copy, Debug)]
+#[repr(C, align(16))]
+struct ChildBounds {
+    pmin_x: [Scalar; 2],
+    pmin_y: [Scalar; 2],
+    pmin_z: [Scalar; 2],
+    pmax_x: [Scalar; 2],
+    pmax_y: [Scalar; 2],
+    pmax_z: [Scalar; 2],
+    axis_x: [Scalar; 2],
+    axis_y: [Scalar; 2],
+    axis_z: [Scalar; 2],
+    cos_theta_o: [Scalar; 2],
+    cos_theta_e: [Scalar; 2],
+    power: [Scalar; 2],
+}
+
+impl ChildBounds {
+    fn from_pair(left: LightBound, right: LightBound) -> Self {
+        let pair = |f: fn(LightBound) -> Scalar| [f(left), f(right)];
+        ChildBounds {
+            pmin_x: pair(|b| b.bbox.pmin.x),
+            pmin_y: pair(|b| b.bbox.pmin.y),
+            pmin_z: pair(|b| b.bbox.pmin.z),
+            pmax_x: pair(|b| b.bbox.pmax.x),
+            pmax_y: pair(|b| b.bbox.pmax.y),
+            pmax_z: pair(|b| b.bbox.pmax.z),
+            axis_x: pair(|b| b.cone.axis.x),
+            axis_y: pair(|b| b.cone.axis.y),
+            axis_z: pair(|b| b.cone.axis.z),
+            cos_theta_o: pair(|b| b.cone.theta_o.cos()),
+            cos_theta_e: pair(|b| b.cone.theta_e.cos()),
+            power: pair(|b| b.power),
+        }
+    }
+
+    /// Only the first child is used (for the lights of a leaf), the second one has no power.
+    fn from_bound(bound: LightBound) -> Self {
+        ChildBounds::from_pair(bound, LightBound::new_initial())
+    }
+
+    /// The importance of both children as seen from the shading point (from Estevez and Kulla). The
+    /// angles are bounded conservatively using the bounding sphere of the boxes.
+    fn importance(&self, shading_info: ShadingInfo) -> [Scalar; 2] {
+        let mut result = [0.0; 2];
+        for i in 0..2 {
+            let half_x = 0.5 * (self.pmax_x[i] - self.pmin_x[i]);
+            let half_y = 0.5 * (self.pmax_y[i] - self.pmin_y[i]);
+            let half_z = 0.5 * (self.pmax_z[i] - self.pmin_z[i]);
+            let dx = self.pmin_x[i] + half_x - shading_info.pos.x;
+            let dy = self.pmin_y[i] + half_y - shading_info.pos.y;
+            let dz = self.pmin_z[i] + half_z - shading_info.pos.z;
+
+            // Don't let the distance get smaller than the radius of the box (when the point is inside):
+            let radius2 = half_x * half_x + half_y * half_y + half_z * half_z;
+            let d2 = (dx * dx + dy * dy + dz * dz).max(radius2);
+            let inv_d = 1.0 / d2.sqrt();
+
+            // The angle of the bounding sphere as seen from the point:
+            let theta_u = (radius2 / d2).sqrt().min(1.0).asin();
+            // The angle between the direction to the point and the axis of the cone:
+            let cos_theta =
+                -(dx * self.axis_x[i] + dy * self.axis_y[i] + dz * self.axis_z[i]) * inv_d;
+            let theta = cos_theta.max(-1.0).min(1.0).acos();
+            let theta_o = self.cos_theta_o[i].acos();
+            let theta_prime = (theta - theta_o - theta_u).max(0.0);
+            let within_cone = theta_prime < self.cos_theta_e[i].acos();
+
+            // The angle between the normal and the direction to the box:
+            let cos_i =
+                (dx * shading_info.nrm.x + dy * shading_info.nrm.y + dz * shading_info.nrm.z)
+                    * inv_d;
+            let theta_i = cos_i.max(-1.0).min(1.0).acos();
+            let theta_i_prime = (theta_i - theta_u).max(0.0);
+
+            result[i] = if within_cone {
+                self.power[i] * theta_i_prime.cos().abs() * theta_prime.cos() / d2
+            } else {
+                0.0
+            };
+        }
+        result
+    }
+}
+
 /// Describes the bound over a bunch of lights:
 #[derive(Clone, Copy, Debug)]
 pub struct LightBound {
-    pub bbox: BBox3<f64>,
+    pub bbox: BBox3<Scalar>,
     pub cone: Cone,
-    pub power: f64,
+    pub power: Scalar,
 }
 
 impl LightBound {
+    /// The bound of a light that can emit its power in every direction from anywhere in `bbox`.
+    pub fn new_omni(bbox: BBox3<Scalar>, power: Scalar) -> Self {
+        LightBound {
+            bbox,
+            cone: Cone::new(
+                Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0,
+                },
+                Scalar::PI,
+                Scalar::PI_OVER_2,
+            ),
+            power,
+        }
+    }
+
     fn new_initial() -> Self {
         LightBound {
             bbox: BBox3::new_initial(),
@@ -398,5 +569,247 @@ impl LightBound {
 struct LightInfo {
     index: usize,      // The index of the light
     bound: LightBound, // The bound over the lights
-    centroid: Vec3<f64>,
+    centroid: Vec3<Scalar>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::point::Point;
+    use crate::spectrum::Color;
+    use crate::TEST_EPSILON;
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg32;
+
+    /// The importance of a single bound, computed directly from the `LightBound` (the way it was done
+    /// before the bounds of the children were paired up).
+    fn importance(bound: LightBound, shading_info: ShadingInfo) -> Scalar {
+        let d = bound.bbox.centroid() - shading_info.pos;
+        let radius2 = bound.bbox.diagonal().scale(0.5).length2();
+        let d2 = d.length2().max(radius2);
+        let dir = d.scale(1.0 / d2.sqrt());
+
+        let theta_u = (radius2 / d2).sqrt().min(1.0).asin();
+        let theta = (-dir.dot(bound.cone.axis)).max(-1.0).min(1.0).acos();
+        let theta_prime = (theta - bound.cone.theta_o - theta_u).max(0.0);
+        if theta_prime >= bound.cone.theta_e {
+            return 0.0;
+        }
+        let theta_i = dir.dot(shading_info.nrm).max(-1.0).min(1.0).acos();
+        let theta_i_prime = (theta_i - theta_u).max(0.0);
+        bound.power * theta_i_prime.cos().abs() * theta_prime.cos() / d2
+    }
+
+    fn random_vec(rng: &mut Pcg32, scale: Scalar) -> Vec3<Scalar> {
+        Vec3 {
+            x: rng.gen_range(-scale, scale),
+            y: rng.gen_range(-scale, scale),
+            z: rng.gen_range(-scale, scale),
+        }
+    }
+
+    fn random_light(rng: &mut Pcg32, index: usize) -> LightInfo {
+        let centroid = random_vec(rng, 10.0);
+        let extent = Vec3 {
+            x: rng.gen_range(0.0, 0.5),
+            y: rng.gen_range(0.0, 0.5),
+            z: rng.gen_range(0.0, 0.5),
+        };
+        LightInfo {
+            index,
+            bound: LightBound {
+                bbox: BBox3 {
+                    pmin: centroid - extent,
+                    pmax: centroid + extent,
+                },
+                cone: Cone::new(
+                    random_vec(rng, 1.0),
+                    rng.gen_range(0.0, Scalar::PI),
+                    rng.gen_range(0.0, Scalar::PI_OVER_2),
+                ),
+                power: rng.gen_range(0.1, 10.0),
+            },
+            centroid,
+        }
+    }
+
+    fn random_shading_info(rng: &mut Pcg32) -> ShadingInfo {
+        ShadingInfo {
+            pos: random_vec(rng, 12.0),
+            nrm: random_vec(rng, 1.0).normalize(),
+        }
+    }
+
+    /// Turns the bound of one of the children back into a `LightBound`.
+    fn child_bound(child_bounds: &ChildBounds, i: usize) -> LightBound {
+        LightBound {
+            bbox: BBox3 {
+                pmin: Vec3 {
+                    x: child_bounds.pmin_x[i],
+                    y: child_bounds.pmin_y[i],
+                    z: child_bounds.pmin_z[i],
+                },
+                pmax: Vec3 {
+                    x: child_bounds.pmax_x[i],
+                    y: child_bounds.pmax_y[i],
+                    z: child_bounds.pmax_z[i],
+                },
+            },
+            cone: Cone {
+                axis: Vec3 {
+                    x: child_bounds.axis_x[i],
+                    y: child_bounds.axis_y[i],
+                    z: child_bounds.axis_z[i],
+                },
+                theta_o: child_bounds.cos_theta_o[i].acos(),
+                theta_e: child_bounds.cos_theta_e[i].acos(),
+            },
+            power: child_bounds.power[i],
+        }
+    }
+
+    /// Adds the probability of picking every light below the node (scaled by `prob`) to `probs`.
+    fn pick_probs(
+        bvh: &LightBVH,
+        node: usize,
+        shading_info: ShadingInfo,
+        prob: Scalar,
+        probs: &mut [Scalar],
+    ) {
+        match bvh.nodes[node] {
+            Node::Leaf {
+                light_index,
+                num_lights,
+            } => {
+                let lights = &bvh.lights[light_index..(light_index + num_lights)];
+                let importances: Vec<Scalar> = lights
+                    .iter()
+                    .map(|l| importance(l.bound, shading_info))
+                    .collect();
+                let total: Scalar = importances.iter().sum();
+                if total <= 0.0 {
+                    probs[lights[0].index] += prob;
+                    return;
+                }
+                for (l, i) in lights.iter().zip(importances) {
+                    probs[l.index] += prob * i / total;
+                }
+            }
+            Node::Internal {
+                left,
+                right,
+                child_bounds,
+            } => {
+                let child_bounds = &bvh.child_bounds[child_bounds];
+                let i_left = importance(child_bound(child_bounds, 0), shading_info);
+                let i_right = importance(child_bound(child_bounds, 1), shading_info);
+                if (i_left + i_right) <= 0.0 {
+                    pick_probs(bvh, left, shading_info, prob, probs);
+                } else {
+                    let p_left = i_left / (i_left + i_right);
+                    pick_probs(bvh, left, shading_info, prob * p_left, probs);
+                    pick_probs(bvh, right, shading_info, prob * (1.0 - p_left), probs);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn paired_importance_matches_single_bounds() {
+        let mut rng = Pcg32::seed_from_u64(3);
+        for _ in 0..1000 {
+            let left = random_light(&mut rng, 0).bound;
+            let right = random_light(&mut rng, 1).bound;
+            let shading_info = random_shading_info(&mut rng);
+            let paired = ChildBounds::from_pair(left, right).importance(shading_info);
+            let single = [
+                importance(left, shading_info),
+                importance(right, shading_info),
+            ];
+            for i in 0..2 {
+                assert!(
+                    (paired[i] - single[i]).abs() <= TEST_EPSILON * single[i].max(TEST_EPSILON),
+                    "{:?} {:?}",
+                    paired,
+                    single
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn picked_lights_follow_the_importance() {
+        const NUM_LIGHTS: usize = 200;
+        const N: usize = 1 << 16;
+        let mut rng = Pcg32::seed_from_u64(7);
+        let lights = (0..NUM_LIGHTS)
+            .map(|index| random_light(&mut rng, index))
+            .collect();
+        let bvh = LightBVH::from_lights(lights);
+        assert!(bvh.child_bounds.len() > 1);
+
+        for _ in 0..4 {
+            let shading_info = random_shading_info(&mut rng);
+            let mut expected = vec![0.0; NUM_LIGHTS];
+            pick_probs(&bvh, 0, shading_info, 1.0, &mut expected);
+
+            let mut counts = vec![0; NUM_LIGHTS];
+            for i in 0..N {
+                let u = ((i as Scalar) + 0.5) / (N as Scalar);
+                counts[bvh.sample(shading_info, u)] += 1;
+            }
+            for (&count, &p) in counts.iter().zip(expected.iter()) {
+                let picked = (count as Scalar) / (N as Scalar);
+                assert!((picked - p).abs() < 1e-3, "{} {}", picked, p);
+            }
+        }
+    }
+
+    #[test]
+    fn scene_lights_are_picked_by_their_bounds() {
+        const NUM_LIGHTS: usize = 50;
+        let mut rng = Pcg32::seed_from_u64(5);
+        let points: Vec<_> = (0..NUM_LIGHTS)
+            .map(|_| Point::new(random_vec(&mut rng, 10.0), Color::white()))
+            .collect();
+        let lights: Vec<&dyn Light> = points.iter().map(|light| light as &dyn Light).collect();
+        let bvh = LightBVH::new(&lights);
+
+        let (_, root) = bvh
+            .node_bounds()
+            .into_iter()
+            .find(|&(depth, _)| depth == 0)
+            .unwrap();
+        for light in &lights {
+            let c = light.get_centroid();
+            assert!(root.pmin.x <= c.x && root.pmin.y <= c.y && root.pmin.z <= c.z);
+            assert!(root.pmax.x >= c.x && root.pmax.y >= c.y && root.pmax.z >= c.z);
+        }
+
+        // Right below a light (and facing it), that light is picked the most (the bounds of the nodes
+        // above it are a lot larger, so the other lights are still picked as well):
+        let above = lights[0].get_centroid();
+        let shading_info = ShadingInfo {
+            pos: above
+                - Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.01,
+                },
+            nrm: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+        };
+        let mut counts = vec![0; NUM_LIGHTS];
+        for i in 0..1000 {
+            counts[bvh.sample(shading_info, ((i as Scalar) + 0.5) / 1000.0)] += 1;
+        }
+        assert!(
+            counts[1..].iter().all(|&count| count < counts[0]),
+            "{:?}",
+            counts
+        );
+    }
 }
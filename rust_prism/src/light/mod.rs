@@ -1,14 +1,20 @@
 pub mod area;
 pub mod light_picker;
-//pub mod many_lights;
+pub mod many_lights;
 pub mod point;
+pub mod portal;
+#[cfg(any(test, feature = "validation"))]
+pub mod validation;
 
-use crate::geometry::GeomInteraction;
-use crate::sampler::Sampler;
+use crate::interaction::Interaction;
+use crate::light::many_lights::LightBound;
+use crate::sampler::{SampleArray, Sampler};
 use crate::scene::{GeomRef, Scene};
 use crate::shading::lobe::LobeType;
 use crate::shading::material::{Bsdf, ShadingCoord};
 use crate::spectrum::Color;
+use crate::Scalar;
+use pmath::bbox::BBox3;
 use pmath::ray::Ray;
 use pmath::sampling;
 use pmath::vector::{Vec2, Vec3};
@@ -35,30 +41,30 @@ pub struct LightHandle {
 
 /// An interface for defining a light in the scene. Lights are transformed into world
 /// space when being committed to a scene.
-pub trait Light: Sync + 'static {
+pub trait Light: Send + Sync + 'static {
     /// Samples the light from a specific position (`point`) in world space, a `time` in case the light
     /// varies over time, the `scene` in case it needs it, and a random value (`u`) used to sample the light.
     ///
     /// Returns values in this order:
     /// *`Color`: potential (if no occlusion occurs) energy the light contributes
-    /// *`Vec3<f64>`: world space location of where the light will get hit (so one can calculate the wi value themselves)
-    /// *`f64`: the probability density for the light sample
+    /// *`Vec3<Scalar>`: world space location of where the light will get hit (so one can calculate the wi value themselves)
+    /// *`Scalar`: the probability density for the light sample
     fn sample(
         &self,
-        point: Vec3<f64>,
-        time: f64,
+        point: Vec3<Scalar>,
+        time: Scalar,
         scene: &Scene,
-        u: Vec2<f64>,
-    ) -> (Color, Vec3<f64>, f64);
+        u: Vec2<Scalar>,
+    ) -> (Color, Vec3<Scalar>, Scalar);
 
     /// Given a shading point and direction in world space, returns the pdf.
-    fn pdf(&self, shading_point: Vec3<f64>, wi: Vec3<f64>) -> f64;
+    fn pdf(&self, shading_point: Vec3<Scalar>, wi: Vec3<Scalar>) -> Scalar;
 
     /// Returns the total power of the light.
     fn power(&self) -> Color;
 
     /// Given a `point` on the light and direction (`w`) pointing away from the light, return the color.
-    fn eval(&self, point: Vec3<f64>, w: Vec3<f64>) -> Color;
+    fn eval(&self, point: Vec3<Scalar>, w: Vec3<Scalar>) -> Color;
 
     /// Whether or not the light is a delta (like a point light):
     fn is_delta(&self) -> bool;
@@ -68,7 +74,76 @@ pub trait Light: Sync + 'static {
     fn get_geom(&self) -> Option<GeomRef>;
 
     /// Returns the centroid of the light source:
-    fn get_centroid(&self) -> Vec3<f64>;
+    fn get_centroid(&self) -> Vec3<Scalar>;
+
+    /// Bounds where the light emits its power from, and in which directions (see `many_lights::LightBVH`). By
+    /// default, the light emits it in every direction from its centroid.
+    fn get_bound(&self) -> LightBound {
+        LightBound::new_omni(
+            BBox3::from_pnt(self.get_centroid()),
+            self.power().luminance(),
+        )
+    }
+
+    /// Whether an area light emits light from the back side of its geometry as well (otherwise only
+    /// the side the normal points to emits light). Only area lights have sides.
+    fn is_two_sided(&self) -> bool {
+        false
+    }
+
+    /// Whether the light is infinitely far away (like an environment), so that rays that leave the
+    /// scene hit it.
+    fn is_infinite(&self) -> bool {
+        false
+    }
+
+    /// Whether the light can emit photons (see `sample_le`).
+    fn emits_photons(&self) -> bool {
+        false
+    }
+
+    /// Samples a ray leaving the light (to trace a photon from it), where `u_pos` picks the point on the
+    /// light and `u_dir` the direction. Returns the emitted radiance divided by the pdfs of the point and
+    /// the direction (the power the photon carries), and the ray. Returns `None` if the sample doesn't
+    /// emit anything or the light can't emit photons (see `emits_photons`).
+    fn sample_le(
+        &self,
+        _time: Scalar,
+        _u_pos: Vec2<Scalar>,
+        _u_dir: Vec2<Scalar>,
+    ) -> Option<(Color, Ray<Scalar>)> {
+        None
+    }
+}
+
+/// How `estimate_direct_light` samples the lights: how many samples it takes of each strategy. Taking multiple light samples is
+/// usually a lot cheaper than taking more samples per pixel for large area lights.
+#[derive(Clone, Copy, Debug)]
+pub struct DirectLightParam {
+    pub n_light_samples: u32,
+    /// With no bsdf samples only the light is sampled (without MIS), which is faster but a lot noisier
+    /// for glossy surfaces.
+    pub n_bsdf_samples: u32,
+    /// Hides the shadow terminator on smooth shaded meshes: shadow rays start from the surface implied
+    /// by the vertex normals and light below the geometric horizon is faded out smoothly. This darkens
+    /// the terminator slightly, so it's biased.
+    pub terminator_fix: bool,
+    /// If set, shadow rays of light samples that would contribute less than this (in luminance) if the
+    /// light isn't occluded are only traced with a probability proportional to their contribution (and
+    /// scaled up if they are). This is unbiased, and saves a lot of shadow rays when many lights are
+    /// sampled at every point.
+    pub shadow_rr: Option<Scalar>,
+}
+
+impl Default for DirectLightParam {
+    fn default() -> Self {
+        DirectLightParam {
+            n_light_samples: 1,
+            n_bsdf_samples: 1,
+            terminator_fix: false,
+            shadow_rr: None,
+        }
+    }
 }
 
 /// Samples a light directly using MIS. If there is occlusion, false (and color is black), otherwise
@@ -79,17 +154,26 @@ pub trait Light: Sync + 'static {
 /// * `bsdf`: The bsdf at the point we are shading form.
 /// * `time`: The time
 /// * `sampler`: The sampler used to sample the bsdf and light.
+/// * `light_samples`: Stratified samples for the light (and the offset of the first one to use), see
+///   `light_picker::request_samples`. Regular samples are used once they run out.
+/// * `bsdf_samples`: The same as `light_samples`, but for sampling the bsdf. Stratifying these matters
+///   when taking more than one bsdf sample of glossy surfaces.
 /// * `scene`: The scene used for visibility testing and used by the light if necessary.
 /// * `light_id`: The light id of the light we are directly sampling.
 /// * `specular`: Whether to handle specular lobes or not.
+/// * `param`: How many light and bsdf samples to take (and whether to hide the shadow terminator or
+///   skip shadow rays).
 pub fn estimate_direct_light(
-    interaction: GeomInteraction,
+    interaction: Interaction,
     bsdf: &Bsdf,
-    time: f64,
+    time: Scalar,
     sampler: &mut Sampler,
+    light_samples: Option<(SampleArray, usize)>,
+    bsdf_samples: Option<(SampleArray, usize)>,
     scene: &Scene,
     light_id: u32,
     specular: bool,
+    param: DirectLightParam,
 ) -> Color {
     let light = scene.get_light(light_id);
     let lobe_type = if specular {
@@ -102,77 +186,617 @@ pub fn estimate_direct_light(
 
     let shading_coord = ShadingCoord::new(interaction);
 
-    // First we sample the light source:
-    let final_color = {
-        let (light_color, light_point, light_pdf) =
-            light.sample(interaction.p, time, scene, sampler.sample());
-        // We don't need to normalize this:
-        let wi = light_point - interaction.p;
-
-        // Then we evaluate the bsdf given this light sample:
-        if (light_pdf > 0.0) && !light_color.is_black() {
-            let bsdf_color = bsdf
-                .eval(interaction.wo, wi, lobe_type, shading_coord)
-                .scale(wi.dot(interaction.shading_n).abs());
-            let bsdf_pdf = bsdf.pdf(interaction.wo, wi, lobe_type, shading_coord);
-
-            if !bsdf_color.is_black() {
-                // If the path is unoccluded, we can go ahead and add it's attribute
-                if !scene.intersect_test(Ray::new_extent(interaction.p, wi, time, 1.0)) {
-                    if light.is_delta() {
-                        (bsdf_color * light_color).scale(1.0 / light_pdf)
-                    } else {
-                        let weight = sampling::power_heuristic(1, light_pdf, 1, bsdf_pdf);
-                        (bsdf_color * light_color).scale(weight / light_pdf)
-                    }
-                } else {
-                    Color::black()
+    // Every sample of a delta light is the same, and we only sample the bsdf if the light has geometry
+    // that can be hit (or is infinitely far away):
+    let n_light_samples = if light.is_delta() {
+        param.n_light_samples.min(1)
+    } else {
+        param.n_light_samples
+    };
+    let n_bsdf_samples = if light.get_geom().is_some() || light.is_infinite() {
+        param.n_bsdf_samples
+    } else {
+        0
+    };
+
+    let mut final_color = Color::black();
+    if n_light_samples > 0 {
+        let mut light_color = Color::black();
+        for i in 0..n_light_samples {
+            // Use the stratified samples as long as there are enough of them:
+            let u = match light_samples {
+                Some((array, offset)) if offset + (i as usize) < array.len() => {
+                    sampler.array_2d(array, offset + (i as usize))
                 }
+                _ => sampler.sample(),
+            };
+            let shadow_rr = param
+                .shadow_rr
+                .map(|threshold| (threshold, sampler.sample().x));
+            light_color += sample_light(
+                interaction,
+                bsdf,
+                time,
+                u,
+                scene,
+                light,
+                lobe_type,
+                shading_coord,
+                (n_light_samples, n_bsdf_samples),
+                param.terminator_fix,
+                shadow_rr,
+            );
+        }
+        final_color += light_color.scale(1.0 / (n_light_samples as Scalar));
+    }
+    if n_bsdf_samples > 0 {
+        let mut bsdf_color = Color::black();
+        for i in 0..n_bsdf_samples {
+            let u = match bsdf_samples {
+                Some((array, offset)) if offset + (i as usize) < array.len() => {
+                    sampler.array_2d(array, offset + (i as usize))
+                }
+                _ => sampler.sample(),
+            };
+            bsdf_color += sample_bsdf(
+                interaction,
+                bsdf,
+                time,
+                u,
+                scene,
+                light,
+                lobe_type,
+                shading_coord,
+                (n_light_samples, n_bsdf_samples),
+            );
+        }
+        final_color += bsdf_color.scale(1.0 / (n_bsdf_samples as Scalar));
+    }
+    final_color
+}
+
+/// Takes a single sample of the light for `estimate_direct_light`. `num_samples` is the number of light
+/// and bsdf samples that are taken in total (for the MIS weights). See `DirectLightParam` for
+/// `terminator_fix`. `shadow_rr` is the threshold of `DirectLightParam::shadow_rr` and the random number
+/// that decides whether the shadow ray is traced.
+fn sample_light(
+    interaction: Interaction,
+    bsdf: &Bsdf,
+    time: Scalar,
+    u: Vec2<Scalar>,
+    scene: &Scene,
+    light: &dyn Light,
+    lobe_type: LobeType,
+    shading_coord: ShadingCoord,
+    num_samples: (u32, u32),
+    terminator_fix: bool,
+    shadow_rr: Option<(Scalar, Scalar)>,
+) -> Color {
+    let (light_color, light_point, light_pdf) = light.sample(interaction.p, time, scene, u);
+    // We don't need to normalize this:
+    let wi = light_point - interaction.p;
+
+    // Then we evaluate the bsdf given this light sample:
+    if (light_pdf == 0.0) || light_color.is_black() {
+        return Color::black();
+    }
+    let terminator_scale = if terminator_fix {
+        terminator_shadowing(interaction.n, interaction.shading_n(), wi.normalize())
+    } else {
+        1.0
+    };
+    let bsdf_color = bsdf
+        .eval(interaction.wo, wi, lobe_type, shading_coord)
+        .scale(wi.dot(interaction.shading_n()).abs() * terminator_scale);
+    if bsdf_color.is_black() {
+        return Color::black();
+    }
+
+    let (n_light_samples, n_bsdf_samples) = num_samples;
+    let weight = if light.is_delta() || (n_bsdf_samples == 0) {
+        1.0
+    } else {
+        let bsdf_pdf = bsdf.pdf(interaction.wo, wi, lobe_type, shading_coord);
+        sampling::power_heuristic(n_light_samples, light_pdf, n_bsdf_samples, bsdf_pdf)
+    };
+    let color = (bsdf_color * light_color).scale(weight / light_pdf);
+
+    // Russian roulette: the shadow ray is traced with probability q, and the color divided by q to make
+    // up for the rays that aren't:
+    let color = match shadow_rr {
+        Some((threshold, u)) => {
+            let q = color.luminance() / threshold;
+            if q >= 1.0 {
+                color
+            } else if u < q {
+                color.scale(1.0 / q)
             } else {
-                Color::black()
+                return Color::black();
             }
-        } else {
-            Color::black()
         }
+        None => color,
+    };
+
+    // Surfaces between the point and the light can let some (colored) light through:
+    let shadow_p = if terminator_fix {
+        interaction.shadow_p()
+    } else {
+        interaction.p
     };
+    let shadow_p = interaction.offset_origin(shadow_p, light_point - shadow_p);
+    let shadow_ray = Ray::new_extent(shadow_p, light_point - shadow_p, time, 1.0);
+    let tr = scene.transmittance_to(shadow_ray, light.get_geom());
+    if tr.is_black() {
+        return Color::black();
+    }
+    tr * color
+}
 
-    // Then we sample the bsdf:
-
-    // We only sample the bsdf if the light isn't a delta light and has geometry:
-    if let Some(light_geom) = light.get_geom() {
-        let (bsdf_color, bsdf_wi, bsdf_pdf, sampled_lobe_type) =
-            bsdf.sample(interaction.wo, sampler.sample(), lobe_type, shading_coord);
-        let bsdf_color = bsdf_color.scale(bsdf_wi.dot(interaction.shading_n).abs());
-        let sampled_specular = sampled_lobe_type.contains(LobeType::SPECULAR);
-
-        if !bsdf_color.is_black() && (bsdf_pdf > 0.0) {
-            let weight = if !sampled_specular {
-                let light_pdf = light.pdf(interaction.p, bsdf_wi);
-                if light_pdf == 0.0 {
-                    // Nothing more to contribute to the final color, as the bsdf sample didn't:
-                    return final_color;
+/// The shadowing term from "Taming the Shadow Terminator" (Chiang et al. 2019), which smoothly fades out
+/// light that arrives below the geometric horizon (`n`) when the shading normal (`sn`) still faces it.
+/// `wi` has to be normalized.
+fn terminator_shadowing(n: Vec3<Scalar>, sn: Vec3<Scalar>, wi: Vec3<Scalar>) -> Scalar {
+    let cos_sn = sn.dot(wi);
+    let cos_n_sn = n.dot(sn);
+    if (cos_sn <= 0.0) || (cos_n_sn <= 0.0) {
+        return 1.0;
+    }
+    let g = (n.dot(wi) / (cos_sn * cos_n_sn)).max(0.0).min(1.0);
+    -g * g * g + g * g + g
+}
+
+/// Takes a single sample of the bsdf for `estimate_direct_light` (the light has to have geometry or be
+/// infinite).
+/// `num_samples` is the number of light and bsdf samples that are taken in total (for the MIS weights).
+fn sample_bsdf(
+    interaction: Interaction,
+    bsdf: &Bsdf,
+    time: Scalar,
+    u: Vec2<Scalar>,
+    scene: &Scene,
+    light: &dyn Light,
+    lobe_type: LobeType,
+    shading_coord: ShadingCoord,
+    num_samples: (u32, u32),
+) -> Color {
+    let light_geom = light.get_geom();
+    if light_geom.is_none() && !light.is_infinite() {
+        return Color::black();
+    }
+
+    let (bsdf_color, bsdf_wi, bsdf_pdf, sampled_lobe_type) =
+        bsdf.sample(interaction.wo, u, lobe_type, shading_coord);
+    let bsdf_color = bsdf_color.scale(bsdf_wi.dot(interaction.shading_n()).abs());
+    if bsdf_color.is_black() || (bsdf_pdf == 0.0) {
+        return Color::black();
+    }
+
+    let (n_light_samples, n_bsdf_samples) = num_samples;
+    let weight = if sampled_lobe_type.contains(LobeType::SPECULAR) || (n_light_samples == 0) {
+        1.0
+    } else {
+        let light_pdf = light.pdf(interaction.p, bsdf_wi);
+        if light_pdf == 0.0 {
+            // The bsdf sample can't hit the light:
+            return Color::black();
+        }
+        sampling::power_heuristic(n_bsdf_samples, bsdf_pdf, n_light_samples, light_pdf)
+    };
+
+    // See if our bsdf sample hits the light, and add it's contribution
+    // (only the front side of one-sided area lights emits light, and infinite lights are hit by
+    // leaving the scene):
+    let sample_ray = Ray::new(interaction.p, bsdf_wi, time);
+    match scene.intersect(sample_ray) {
+        Some(intersected_light_interaction)
+            if light_geom.is_some()
+                && (intersected_light_interaction.geom == light_geom)
+                && (light.is_two_sided()
+                    || (intersected_light_interaction.n.dot(-bsdf_wi) > 0.0)) =>
+        {
+            let light_color = light.eval(intersected_light_interaction.p, -bsdf_wi);
+            (light_color * bsdf_color).scale(weight / bsdf_pdf)
+        }
+        None if light.is_infinite() => {
+            let light_color = light.eval(interaction.p, -bsdf_wi);
+            (light_color * bsdf_color).scale(weight / bsdf_pdf)
+        }
+        _ => Color::black(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::camera::perspective::PerspectiveCamera;
+    use crate::film::{ImageBuffer, ImagePixel};
+    use crate::filter::{GaussianFilter, PixelFilter};
+    use crate::geometry::rect::Rect;
+    use crate::geometry::Geometry;
+    use crate::integrator::path_tracer::{
+        PathTracerIntegrator, PathTracerIntegratorManager, PathTracerParam,
+    };
+    use crate::interaction::Interaction;
+    use crate::light::area::diffuse::DiffuseAreaLight;
+    use crate::light::light_picker::LightPickerKind;
+    use crate::light::point::Point;
+    use crate::light::{estimate_direct_light, DirectLightParam};
+    use crate::sampler::{SampleSequence, SampleTables, Sampler, SamplerMode};
+    use crate::scene::{Scene, SceneGeom, SceneLight, ScenePrim};
+    use crate::shading::material::matte::Matte;
+    use crate::shading::material::{Bsdf, Material, MaterialPool, DEFAULT_MATERIAL_ID};
+    use crate::spectrum::Color;
+    use crate::threading::pixel_order::PixelOrder;
+    use crate::threading::{RenderParam, Renderer};
+    use crate::transform::Transf;
+    use crate::Scalar;
+    use pmath::bbox::BBox3;
+    use pmath::ray::Ray;
+    use pmath::vector::{Vec2, Vec3};
+    use std::sync::Arc;
+
+    // A surface that doesn't reflect anything, but lets some (colored) light through.
+    struct Tinted(Bsdf, Color);
+
+    impl Material for Tinted {
+        fn bsdf(&self, interaction: Interaction) -> (&Bsdf, Interaction) {
+            (&self.0, interaction)
+        }
+
+        fn transmittance(&self, _interaction: &Interaction) -> Color {
+            self.1
+        }
+    }
+
+    // A white floor lit by a point light, optionally with a red pane halfway between them:
+    fn render_floor(pane: bool) -> ImageBuffer {
+        let floor = SceneGeom::new_material(
+            Arc::new(Rect::new(Vec2 { x: 10.0, y: 10.0 })),
+            Arc::new(Matte::new(Color::white().scale(0.8))),
+            Transf::new_identity(),
+        );
+        let mut prims: Vec<Arc<dyn ScenePrim>> = vec![Arc::new(floor)];
+        if pane {
+            let red = Color {
+                r: 1.0,
+                g: 0.2,
+                b: 0.2,
+            };
+            prims.push(Arc::new(SceneGeom::new_material(
+                Arc::new(Rect::new(Vec2 { x: 2.0, y: 2.0 })),
+                Arc::new(Tinted(Bsdf::new_opaque(), red)),
+                Transf::new_translate(Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 2.0,
+                }),
+            )));
+        }
+        let light = Point::new(
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 4.0,
+            },
+            Color::white().scale(20.0),
+        );
+        let scene = Scene::new(
+            &prims,
+            vec![SceneLight::new(Arc::new(light), Transf::new_identity())],
+        );
+
+        let res = Vec2 { x: 32, y: 32 };
+        let floor_bbox = BBox3 {
+            pmin: Vec3 {
+                x: -3.0,
+                y: -3.0,
+                z: 0.0,
+            },
+            pmax: Vec3 {
+                x: 3.0,
+                y: 3.0,
+                z: 0.0,
+            },
+        };
+        let camera = PerspectiveCamera::frame_bbox(
+            floor_bbox,
+            45.0,
+            Vec3 {
+                x: 0.0,
+                y: 0.5,
+                z: -1.0,
+            },
+            res,
+        );
+        let renderer = Renderer::new(RenderParam {
+            num_pixel_samples: 4,
+            num_threads: 2,
+            sample_seed: 1,
+            blue_noise_count: 0,
+            sample_sequence: SampleSequence::Pmj,
+            sampler_mode: SamplerMode::Scrambled,
+            blue_noise_dither: false,
+            res,
+            tile_size: Some(8),
+            pixel_order: PixelOrder::Scanline,
+            light_picker: LightPickerKind::All,
+            sample_dump: None,
+            importance_map: None,
+            split_buffers: false,
+            supersample: 1,
+        })
+        .unwrap();
+        let output = renderer
+            .render::<PathTracerIntegrator, PathTracerIntegratorManager>(
+                &scene,
+                &MaterialPool::new(),
+                &camera,
+                PixelFilter::new(&GaussianFilter::new(Vec2 { x: 1.0, y: 1.0 }, 0.5)),
+                PathTracerParam {
+                    max_bounce: 1,
+                    direct_light: DirectLightParam::default(),
+                    aovs: false,
+                    max_direct: None,
+                    max_indirect: None,
+                },
+                None,
+                None,
+            )
+            .unwrap();
+        output.film.to_image_buffer(ImagePixel::from_color)
+    }
+
+    #[test]
+    fn colored_panes_cast_colored_shadows() {
+        let count_red_pixels = |image: &ImageBuffer| {
+            let mut count = 0;
+            for y in 0..32 {
+                for x in 0..32 {
+                    let pixel = image.get_pixel(Vec2 { x, y });
+                    if pixel.r > 0.01 && pixel.g < 0.5 * pixel.r {
+                        count += 1;
+                    }
+                }
+            }
+            count
+        };
+        assert_eq!(count_red_pixels(&render_floor(false)), 0);
+        assert!(count_red_pixels(&render_floor(true)) > 50);
+    }
+
+    #[test]
+    fn stratified_bsdf_samples_reduce_noise() {
+        // A white floor, with a light that covers the half of the sky with positive x. Without stratified
+        // samples, the number of bsdf samples that hit the light varies:
+        let floor = Arc::new(SceneGeom::new_material(
+            Arc::new(Rect::new(Vec2 { x: 10.0, y: 10.0 })),
+            Arc::new(Matte::new(Color::white().scale(0.8))),
+            Transf::new_identity(),
+        ));
+        let mut light_rect = Rect::new(Vec2 { x: 20.0, y: 40.0 });
+        light_rect.calc_surface_area();
+        let light_rect = Arc::new(light_rect);
+        let light_transf = Transf::new_translate(Vec3 {
+            x: 10.0,
+            y: 0.0,
+            z: 1.0,
+        });
+        let light_geom = Arc::new(SceneGeom::new_material(
+            light_rect.clone(),
+            MaterialPool::new().get_shared_material(DEFAULT_MATERIAL_ID),
+            light_transf,
+        ));
+        let light = DiffuseAreaLight::new(
+            light_rect,
+            light_transf,
+            Color::white(),
+            light_geom.geom_ref(),
+        )
+        .with_two_sided(true);
+        let prims: Vec<Arc<dyn ScenePrim>> = vec![floor, light_geom];
+        let scene = Scene::new(
+            &prims,
+            vec![SceneLight::new(Arc::new(light), Transf::new_identity())],
+        );
+
+        let interaction = scene
+            .intersect(Ray::new(
+                Vec3 {
+                    x: -2.0,
+                    y: 0.0,
+                    z: 0.5,
+                },
+                Vec3 {
+                    x: 2.0,
+                    y: 0.0,
+                    z: -0.5,
+                },
+                0.0,
+            ))
+            .unwrap();
+        let material = Matte::new(Color::white().scale(0.8));
+        let (bsdf, interaction) = material.bsdf(interaction);
+
+        let param = DirectLightParam {
+            n_light_samples: 0,
+            n_bsdf_samples: 4,
+            ..DirectLightParam::default()
+        };
+        let tables = SampleTables::new(1, 0);
+        let mut sampler = Sampler::new(&tables);
+        sampler.request_2d_array(4);
+        sampler.start_pixel(0, 1, 0);
+        // Four stratified bsdf samples, compared to a single one (for the same number of rays, the single
+        // sample estimates would be averaged in groups of four):
+        const N: usize = 1024;
+        let mut stratified = Vec::with_capacity(N);
+        let mut single = Vec::with_capacity(N);
+        for _ in 0..N {
+            sampler.start_pixel_sample();
+            let array = sampler.next_2d_array().unwrap();
+            for &(n_bsdf_samples, bsdf_samples) in &[(4, Some((array, 0))), (1, None)] {
+                let color = estimate_direct_light(
+                    interaction,
+                    bsdf,
+                    0.0,
+                    &mut sampler,
+                    None,
+                    bsdf_samples,
+                    &scene,
+                    0,
+                    false,
+                    DirectLightParam {
+                        n_bsdf_samples,
+                        ..param
+                    },
+                );
+                if n_bsdf_samples == 4 {
+                    stratified.push(color.r);
                 } else {
-                    sampling::power_heuristic(1, bsdf_pdf, 1, light_pdf)
+                    single.push(color.r);
                 }
-            } else {
-                1.0
+            }
+        }
+        let stats = |estimates: &[Scalar]| {
+            let mean = estimates.iter().sum::<Scalar>() / (N as Scalar);
+            let var = estimates
+                .iter()
+                .map(|e| (e - mean) * (e - mean))
+                .sum::<Scalar>()
+                / (N as Scalar);
+            (mean, var)
+        };
+        let (stratified_mean, stratified_var) = stats(&stratified);
+        let (single_mean, single_var) = stats(&single);
+        // Almost half of the light reflected by the floor comes from the light:
+        assert!((stratified_mean - 0.4).abs() < 0.01, "{}", stratified_mean);
+        assert!((single_mean - 0.4).abs() < 0.05, "{}", single_mean);
+        assert!(
+            stratified_var < 0.1 * single_var / 4.0,
+            "{} {}",
+            stratified_var,
+            single_var
+        );
+    }
+
+    #[test]
+    fn shadow_roulette_is_unbiased_and_skips_rays() {
+        // A floor lit by a grid of point lights of different intensities, some of which are hidden by a
+        // small occluder:
+        let material_pool = MaterialPool::new();
+        let mut prims: Vec<Arc<dyn ScenePrim>> = Vec::new();
+        for &(size, offset) in &[
+            (10.0, Vec3::zero()),
+            (
+                1.0,
+                Vec3 {
+                    x: 1.0,
+                    y: 0.0,
+                    z: 0.5,
+                },
+            ),
+        ] {
+            prims.push(Arc::new(SceneGeom::new_material(
+                Arc::new(Rect::new(Vec2 { x: size, y: size })),
+                material_pool.get_shared_material(DEFAULT_MATERIAL_ID),
+                Transf::new_translate(offset),
+            )));
+        }
+        let lights = (0..64)
+            .map(|i| {
+                let pos = Vec3 {
+                    x: ((i % 8) as Scalar) - 3.5,
+                    y: ((i / 8) as Scalar) - 3.5,
+                    z: 1.0 + ((i % 3) as Scalar),
+                };
+                let intensity = 0.2 * (1 + i % 7) as Scalar;
+                let light = Point::new(pos, Color::white().scale(intensity));
+                SceneLight::new(Arc::new(light), Transf::new_identity())
+            })
+            .collect::<Vec<_>>();
+        let num_lights = lights.len() as u32;
+        let scene = Scene::new(&prims, lights);
+
+        let interaction = scene
+            .intersect(Ray::new(
+                Vec3 {
+                    x: -1.0,
+                    y: 0.0,
+                    z: 1.0,
+                },
+                Vec3 {
+                    x: 1.0,
+                    y: 0.0,
+                    z: -1.0,
+                },
+                0.0,
+            ))
+            .unwrap();
+        let material = Matte::new(Color::white().scale(0.8));
+        let (bsdf, interaction) = material.bsdf(interaction);
+
+        let tables = SampleTables::new(1, 0);
+        let mut sampler = Sampler::new(&tables);
+        sampler.start_pixel(0, 1, 0);
+        let mut estimate = |sampler: &mut Sampler, shadow_rr| {
+            let param = DirectLightParam {
+                n_bsdf_samples: 0,
+                shadow_rr,
+                ..DirectLightParam::default()
             };
+            (0..num_lights)
+                .map(|light_id| {
+                    estimate_direct_light(
+                        interaction,
+                        bsdf,
+                        0.0,
+                        sampler,
+                        None,
+                        None,
+                        &scene,
+                        light_id,
+                        false,
+                        param,
+                    )
+                    .r
+                })
+                .collect::<Vec<_>>()
+        };
 
-            // See if our bsdf sample hits the light, and add it's contribution:
-            let sample_ray = Ray::new(interaction.p, bsdf_wi, time);
-            match scene.intersect(sample_ray) {
-                Some(intersected_light_interaction)
-                    if intersected_light_interaction.geom == light_geom =>
-                {
-                    let light_color = light.eval(intersected_light_interaction.p, -bsdf_wi);
-                    final_color + (light_color + bsdf_color).scale(weight / bsdf_pdf)
-                }
-                None => final_color,
+        // Point lights are exact without the roulette:
+        sampler.start_pixel_sample();
+        let exact = estimate(&mut sampler, None);
+        let exact_sum: Scalar = exact.iter().sum();
+        let num_lit = exact.iter().filter(|&&c| c > 0.0).count();
+        assert!(num_lit < exact.len());
+        let mut sorted = exact.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let threshold = sorted[sorted.len() - 4];
+
+        const N: usize = 4000;
+        let (mut sum, mut sum_sqr, mut num_traced) = (0.0, 0.0, 0);
+        for _ in 0..N {
+            sampler.start_pixel_sample();
+            let colors = estimate(&mut sampler, Some(threshold));
+            // Lights are never brighter than without the roulette, and only lit ones contribute:
+            for (&color, &exact) in colors.iter().zip(exact.iter()) {
+                assert!(color == 0.0 || (color >= exact - 1e-12 && exact > 0.0));
             }
-        } else {
-            final_color
+            num_traced += colors.iter().filter(|&&c| c > 0.0).count();
+            let total: Scalar = colors.iter().sum();
+            sum += total;
+            sum_sqr += total * total;
         }
-    } else {
-        final_color
+        let mean = sum / (N as Scalar);
+        let std_error = ((sum_sqr / (N as Scalar) - mean * mean) / (N as Scalar)).sqrt();
+        assert!(
+            (mean - exact_sum).abs() < 4.0 * std_error,
+            "{} {} {}",
+            mean,
+            exact_sum,
+            std_error
+        );
+        // Most of the lights are dim, so most shadow rays are skipped:
+        let traced = (num_traced as Scalar) / ((N * num_lit) as Scalar);
+        assert!(traced < 0.5, "{}", traced);
     }
 }
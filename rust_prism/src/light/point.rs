@@ -1,42 +1,82 @@
 use crate::light::{Light, LightType};
+use crate::scene::{GeomRef, Scene};
 use crate::spectrum::Color;
+use crate::Scalar;
 use pmath::numbers::Float;
+use pmath::ray::Ray;
+use pmath::sampling;
 use pmath::vector::{Vec2, Vec3};
 
-// /// A point light source.
-// pub struct Point {
-//     intensity: Color,
-// }
-
-// impl Point {
-//     const LIGHT_TYPE: LightType = LightType::DELTA_POSITION;
-
-//     pub fn new(intensity: Color) -> Self {
-//         Point { intensity }
-//     }
-// }
-
-// impl Light for Point {
-//     fn sample(&self, surface_point: Vec3<f64>, _: f64, u: Vec2<f64>) -> (Color, Vec3<f64>, f64) {
-//         let dist_sqrt = (-surface_point).length2();
-//         (self.intensity.div_scale(dist_sqrt), Vec3::zero(), 1.)
-//     }
-
-//     fn pdf(&self, _: Vec3<f64>, _: Vec3<f64>) -> f64 {
-//         // It is practically impossible to get pick the correct direction in this case:
-//         0.
-//     }
-
-//     fn power(&self) -> Color {
-//         self.intensity.scale(f64::PI * 4.)
-//     }
-
-//     fn is_delta(&self) -> bool {
-//         Self::LIGHT_TYPE.contains(LightType::DELTA_POSITION)
-//             || Self::LIGHT_TYPE.contains(LightType::DELTA_DIRECTION)
-//     }
-
-//     fn get_cone(&self) -> LightCone {
-//         todo!();
-//     }
-// }
+/// A point light source.
+pub struct Point {
+    position: Vec3<Scalar>,
+    intensity: Color,
+}
+
+impl Point {
+    const LIGHT_TYPE: LightType = LightType::DELTA_POSITION;
+
+    pub fn new(position: Vec3<Scalar>, intensity: Color) -> Self {
+        Point {
+            position,
+            intensity,
+        }
+    }
+}
+
+impl Light for Point {
+    fn sample(
+        &self,
+        point: Vec3<Scalar>,
+        _time: Scalar,
+        _scene: &Scene,
+        _u: Vec2<Scalar>,
+    ) -> (Color, Vec3<Scalar>, Scalar) {
+        let dist2 = (self.position - point).length2();
+        (self.intensity.div_scale(dist2), self.position, 1.0)
+    }
+
+    fn pdf(&self, _: Vec3<Scalar>, _: Vec3<Scalar>) -> Scalar {
+        // It is practically impossible to get pick the correct direction in this case:
+        0.0
+    }
+
+    fn power(&self) -> Color {
+        self.intensity.scale(Scalar::PI * 4.0)
+    }
+
+    fn eval(&self, _: Vec3<Scalar>, _: Vec3<Scalar>) -> Color {
+        // Can't be hit by a ray:
+        Color::black()
+    }
+
+    fn is_delta(&self) -> bool {
+        Self::LIGHT_TYPE.contains(LightType::DELTA_POSITION)
+            || Self::LIGHT_TYPE.contains(LightType::DELTA_DIRECTION)
+    }
+
+    fn get_geom(&self) -> Option<GeomRef> {
+        None
+    }
+
+    fn get_centroid(&self) -> Vec3<Scalar> {
+        self.position
+    }
+
+    fn emits_photons(&self) -> bool {
+        true
+    }
+
+    fn sample_le(
+        &self,
+        time: Scalar,
+        _u_pos: Vec2<Scalar>,
+        u_dir: Vec2<Scalar>,
+    ) -> Option<(Color, Ray<Scalar>)> {
+        let dir = sampling::uniform_sample_sphere(u_dir);
+        let power = self
+            .intensity
+            .div_scale(sampling::uniform_sphere_pdf::<Scalar>());
+        Some((power, Ray::new(self.position, dir, time)))
+    }
+}
@@ -0,0 +1,454 @@
+use crate::light::{Light, LightType};
+use crate::scene::{GeomRef, Scene};
+use crate::spectrum::Color;
+use crate::Scalar;
+use pmath::numbers::Float;
+use pmath::vector::{Vec2, Vec3};
+
+/// A rectangular opening (like a window) that the environment is seen through. The normal
+/// (`edge_u` x `edge_v`) has to point into the interior.
+#[derive(Clone, Copy, Debug)]
+pub struct Portal {
+    pub corner: Vec3<Scalar>,
+    /// The two edges have to be perpendicular.
+    pub edge_u: Vec3<Scalar>,
+    pub edge_v: Vec3<Scalar>,
+}
+
+impl Portal {
+    pub fn new(corner: Vec3<Scalar>, edge_u: Vec3<Scalar>, edge_v: Vec3<Scalar>) -> Self {
+        Portal {
+            corner,
+            edge_u,
+            edge_v,
+        }
+    }
+
+    fn normal(self) -> Vec3<Scalar> {
+        self.edge_u.cross(self.edge_v).normalize()
+    }
+
+    fn area(self) -> Scalar {
+        self.edge_u.cross(self.edge_v).length()
+    }
+
+    /// Whether the point is on the interior side of the portal (and so can see the environment through
+    /// it).
+    fn is_inside(self, point: Vec3<Scalar>) -> bool {
+        (point - self.corner).dot(self.normal()) > 0.0
+    }
+
+    /// Whether a ray from `point` (on the interior side) in direction `w` passes through the portal.
+    fn passes_through(self, point: Vec3<Scalar>, w: Vec3<Scalar>) -> bool {
+        let n = self.normal();
+        let cos = w.dot(n);
+        if cos >= 0.0 {
+            return false;
+        }
+        let t = (self.corner - point).dot(n) / cos;
+        let offset = point + w.scale(t) - self.corner;
+        let u = offset.dot(self.edge_u) / self.edge_u.length2();
+        let v = offset.dot(self.edge_v) / self.edge_v.length2();
+        (t > 0.0) && (u >= 0.0) && (u <= 1.0) && (v >= 0.0) && (v <= 1.0)
+    }
+}
+
+/// A rectangle as seen from a point, so that it can be sampled uniformly by solid angle. This is from
+/// "An Area-Preserving Parametrization for Spherical Rectangles" (Ureña et al. 2013).
+struct SphericalRect {
+    org: Vec3<Scalar>,
+    x: Vec3<Scalar>,
+    y: Vec3<Scalar>,
+    z: Vec3<Scalar>,
+    // The rectangle in the local frame of the point:
+    x0: Scalar,
+    x1: Scalar,
+    y0: Scalar,
+    y1: Scalar,
+    z0: Scalar,
+    b0: Scalar,
+    b1: Scalar,
+    k: Scalar,
+    solid_angle: Scalar,
+}
+
+impl SphericalRect {
+    fn new(org: Vec3<Scalar>, portal: Portal) -> Self {
+        let u_len = portal.edge_u.length();
+        let v_len = portal.edge_v.length();
+        let x = portal.edge_u.scale(1.0 / u_len);
+        let y = portal.edge_v.scale(1.0 / v_len);
+        let mut z = x.cross(y);
+
+        let d = portal.corner - org;
+        let mut z0 = d.dot(z);
+        // The rectangle always has to be on the negative side of the frame:
+        if z0 > 0.0 {
+            z = -z;
+            z0 = -z0;
+        }
+        let x0 = d.dot(x);
+        let y0 = d.dot(y);
+        let x1 = x0 + u_len;
+        let y1 = y0 + v_len;
+
+        // The normals of the planes through the origin and the edges of the rectangle:
+        let n0 = Vec3 {
+            x: 0.0,
+            y: z0,
+            z: -y0,
+        }
+        .normalize();
+        let n1 = Vec3 {
+            x: -z0,
+            y: 0.0,
+            z: x1,
+        }
+        .normalize();
+        let n2 = Vec3 {
+            x: 0.0,
+            y: -z0,
+            z: y1,
+        }
+        .normalize();
+        let n3 = Vec3 {
+            x: z0,
+            y: 0.0,
+            z: -x0,
+        }
+        .normalize();
+
+        // The interior angles of the spherical rectangle:
+        let g0 = (-n0.dot(n1)).max(-1.0).min(1.0).acos();
+        let g1 = (-n1.dot(n2)).max(-1.0).min(1.0).acos();
+        let g2 = (-n2.dot(n3)).max(-1.0).min(1.0).acos();
+        let g3 = (-n3.dot(n0)).max(-1.0).min(1.0).acos();
+        let k = 2.0 * Scalar::PI - g2 - g3;
+
+        SphericalRect {
+            org,
+            x,
+            y,
+            z,
+            x0,
+            x1,
+            y0,
+            y1,
+            z0,
+            b0: n0.z,
+            b1: n2.z,
+            k,
+            solid_angle: g0 + g1 - k,
+        }
+    }
+
+    /// Returns a point on the rectangle (the pdf is one over the solid angle).
+    fn sample(&self, u: Vec2<Scalar>) -> Vec3<Scalar> {
+        // Pick the x coordinate by the area of the spherical rectangle to the left of it:
+        let au = u.x * self.solid_angle + self.k;
+        let fu = (au.cos() * self.b0 - self.b1) / au.sin();
+        let cu = fu.signum() / (fu * fu + self.b0 * self.b0).sqrt();
+        let cu = cu.max(-1.0).min(1.0);
+        let xu = (-(cu * self.z0) / (1.0 - cu * cu).sqrt())
+            .max(self.x0)
+            .min(self.x1);
+
+        // Then pick the y coordinate along the segment at that x coordinate:
+        let d = (xu * xu + self.z0 * self.z0).sqrt();
+        let h0 = self.y0 / (d * d + self.y0 * self.y0).sqrt();
+        let h1 = self.y1 / (d * d + self.y1 * self.y1).sqrt();
+        let hv = h0 + u.y * (h1 - h0);
+        let hv2 = hv * hv;
+        let yv = if hv2 < 1.0 - 1e-6 {
+            hv * d / (1.0 - hv2).sqrt()
+        } else {
+            self.y1
+        };
+
+        self.org + self.x.scale(xu) + self.y.scale(yv) + self.z.scale(self.z0)
+    }
+}
+
+/// An environment that emits the same radiance from every direction, but that is only seen through
+/// a set of portals (like the windows of a room). Instead of sampling every direction, only the
+/// directions through the portals are sampled, which is a lot less noisy for interiors.
+///
+/// The portals are only used for sampling: rays that leave the scene in any direction see the
+/// environment.
+pub struct PortalLight {
+    radiance: Color,
+    portals: Vec<Portal>,
+}
+
+impl PortalLight {
+    const LIGHT_TYPE: LightType = LightType::INFINITE;
+
+    /// # Panics
+    /// If there are no portals.
+    pub fn new(radiance: Color, portals: Vec<Portal>) -> Self {
+        assert!(!portals.is_empty());
+        PortalLight { radiance, portals }
+    }
+
+    /// Returns the total solid angle of the portals that can be seen from `point`.
+    fn solid_angle(&self, point: Vec3<Scalar>) -> Scalar {
+        self.portals
+            .iter()
+            .filter(|portal| portal.is_inside(point))
+            .map(|&portal| SphericalRect::new(point, portal).solid_angle)
+            .sum()
+    }
+
+    /// Samples a direction from `point` through one of the portals (uniformly by solid angle), or returns
+    /// `None` if none of them can be seen.
+    fn sample_dir(&self, point: Vec3<Scalar>, u: Vec2<Scalar>) -> Option<Vec3<Scalar>> {
+        let total = self.solid_angle(point);
+        if total <= 0.0 {
+            return None;
+        }
+
+        // Pick a portal proportional to its solid angle (and reuse `u.x` to sample it):
+        let mut target = u.x * total;
+        let mut picked = None;
+        for &portal in self.portals.iter().filter(|portal| portal.is_inside(point)) {
+            let rect = SphericalRect::new(point, portal);
+            let solid_angle = rect.solid_angle;
+            if target < solid_angle {
+                picked = Some((target, solid_angle, rect));
+                break;
+            }
+            target -= solid_angle;
+            // Rounding can make the target pass every portal, in which case the last one is used:
+            picked = Some((solid_angle, solid_angle, rect));
+        }
+        let (target, solid_angle, rect) = picked?;
+        let u = Vec2 {
+            x: (target / solid_angle).max(0.0).min(Scalar::ONE_MINUS_EPS),
+            y: u.y,
+        };
+        Some((rect.sample(u) - point).normalize())
+    }
+}
+
+impl Light for PortalLight {
+    fn sample(
+        &self,
+        point: Vec3<Scalar>,
+        _time: Scalar,
+        scene: &Scene,
+        u: Vec2<Scalar>,
+    ) -> (Color, Vec3<Scalar>, Scalar) {
+        let wi = match self.sample_dir(point, u) {
+            Some(wi) => wi,
+            None => return (Color::black(), point, 0.0),
+        };
+
+        // Place the light far enough away that the shadow ray passes through everything in the scene
+        // (which may be empty):
+        let bound = scene.world_bound().combine_pnt(point.cast());
+        let dist =
+            (bound.centroid() - point.cast()).length() + bound.diagonal().length().max(0.0) + 1.0;
+        let dist = Scalar::from_f64(dist);
+
+        // Every portal the direction passes through could have sampled it:
+        (self.radiance, point + wi.scale(dist), self.pdf(point, wi))
+    }
+
+    fn pdf(&self, shading_point: Vec3<Scalar>, wi: Vec3<Scalar>) -> Scalar {
+        let total = self.solid_angle(shading_point);
+        if total <= 0.0 {
+            return 0.0;
+        }
+        let wi = wi.normalize();
+        let count = self
+            .portals
+            .iter()
+            .filter(|portal| {
+                portal.is_inside(shading_point) && portal.passes_through(shading_point, wi)
+            })
+            .count();
+        (count as Scalar) / total
+    }
+
+    /// The power that enters through the portals.
+    fn power(&self) -> Color {
+        let area: Scalar = self.portals.iter().map(|portal| portal.area()).sum();
+        self.radiance.scale(Scalar::PI * area)
+    }
+
+    fn eval(&self, _: Vec3<Scalar>, _: Vec3<Scalar>) -> Color {
+        self.radiance
+    }
+
+    fn is_delta(&self) -> bool {
+        Self::LIGHT_TYPE.contains(LightType::DELTA_POSITION)
+            || Self::LIGHT_TYPE.contains(LightType::DELTA_DIRECTION)
+    }
+
+    fn get_geom(&self) -> Option<GeomRef> {
+        None
+    }
+
+    fn get_centroid(&self) -> Vec3<Scalar> {
+        let sum = self.portals.iter().fold(Vec3::zero(), |sum, portal| {
+            sum + portal.corner + (portal.edge_u + portal.edge_v).scale(0.5)
+        });
+        sum.scale(1.0 / (self.portals.len() as Scalar))
+    }
+
+    fn is_infinite(&self) -> bool {
+        Self::LIGHT_TYPE.contains(LightType::INFINITE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pmath::sampling;
+
+    /// A `width` by 1 window in the plane z = 1 (starting at x = `x`), seen from the origin.
+    fn window(x: Scalar, width: Scalar) -> Portal {
+        Portal::new(
+            Vec3 { x, y: 0.5, z: 1.0 },
+            Vec3 {
+                x: width,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vec3 {
+                x: 0.0,
+                y: -1.0,
+                z: 0.0,
+            },
+        )
+    }
+
+    #[test]
+    fn solid_angle_of_a_centered_square() {
+        // A 2 by 2 square at a distance of 1 covers a sixth of the sphere:
+        let portal = Portal::new(
+            Vec3 {
+                x: -1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+            Vec3 {
+                x: 2.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vec3 {
+                x: 0.0,
+                y: -2.0,
+                z: 0.0,
+            },
+        );
+        let rect = SphericalRect::new(Vec3::zero(), portal);
+        assert!((rect.solid_angle - 2.0 * Scalar::PI / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn samples_are_uniform_by_solid_angle() {
+        let point = Vec3::zero();
+        let portal = window(-0.3, 1.0);
+        let rect = SphericalRect::new(point, portal);
+        // The part of the window left of x = 0:
+        let left = SphericalRect::new(point, window(-0.3, 0.3)).solid_angle / rect.solid_angle;
+
+        const N: usize = 64;
+        let mut num_left = 0;
+        for i in 0..N {
+            for j in 0..N {
+                let u = Vec2 {
+                    x: (i as Scalar + 0.5) / N as Scalar,
+                    y: (j as Scalar + 0.5) / N as Scalar,
+                };
+                let p = rect.sample(u);
+                assert!((p.z - 1.0).abs() < 1e-9);
+                assert!(portal.passes_through(point, (p - point).normalize()));
+                if p.x < 0.0 {
+                    num_left += 1;
+                }
+            }
+        }
+        let fraction = num_left as Scalar / (N * N) as Scalar;
+        assert!((fraction - left).abs() < 2.0 / N as Scalar);
+    }
+
+    #[test]
+    fn pdf_matches_the_samples() {
+        let point = Vec3::zero();
+        let light = PortalLight::new(Color::white(), vec![window(-1.5, 1.0), window(0.2, 2.0)]);
+        let total = light.solid_angle(point);
+
+        // The pdf integrates to one over the sphere:
+        const N: usize = 512;
+        let mut integral = 0.0;
+        for i in 0..N {
+            for j in 0..N {
+                let u = Vec2 {
+                    x: (i as Scalar + 0.5) / N as Scalar,
+                    y: (j as Scalar + 0.5) / N as Scalar,
+                };
+                let wi = sampling::uniform_sample_sphere(u);
+                integral += light.pdf(point, wi) / sampling::uniform_sphere_pdf::<Scalar>();
+            }
+        }
+        integral /= (N * N) as Scalar;
+        assert!((integral - 1.0).abs() < 0.02, "integral: {}", integral);
+
+        // Every sampled direction has the pdf of the solid angle, and the portals are picked
+        // proportional to their solid angle:
+        const M: usize = 32;
+        let mut num_first = 0;
+        for i in 0..M {
+            for j in 0..M {
+                let u = Vec2 {
+                    x: (i as Scalar + 0.5) / M as Scalar,
+                    y: (j as Scalar + 0.5) / M as Scalar,
+                };
+                let wi = light.sample_dir(point, u).unwrap();
+                assert!((light.pdf(point, wi) - 1.0 / total).abs() < 1e-9);
+                if light.portals[0].passes_through(point, wi) {
+                    num_first += 1;
+                }
+            }
+        }
+        let first = SphericalRect::new(point, light.portals[0]).solid_angle / total;
+        let fraction = num_first as Scalar / (M * M) as Scalar;
+        assert!((fraction - first).abs() < 2.0 / M as Scalar);
+    }
+
+    #[test]
+    fn the_end_of_the_range_picks_the_last_portal() {
+        let point = Vec3::zero();
+        let light = PortalLight::new(Color::white(), vec![window(-1.5, 1.0), window(0.2, 2.0)]);
+        for &x in &[Scalar::ONE_MINUS_EPS, 1.0] {
+            let wi = light.sample_dir(point, Vec2 { x, y: 0.5 }).unwrap();
+            assert!(wi.x.is_finite() && wi.y.is_finite() && wi.z.is_finite());
+            assert!(light.portals[1].passes_through(point, wi));
+        }
+    }
+
+    #[test]
+    fn no_samples_from_outside() {
+        let light = PortalLight::new(Color::white(), vec![window(0.0, 1.0)]);
+        let outside = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 2.0,
+        };
+        assert!(light.sample_dir(outside, Vec2 { x: 0.5, y: 0.5 }).is_none());
+        assert_eq!(
+            light.pdf(
+                outside,
+                Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: -1.0
+                }
+            ),
+            0.0
+        );
+    }
+}
@@ -0,0 +1,393 @@
+//! Monte Carlo checks that the sampling routines of a light agree with each other. A light whose `sample`
+//! and `pdf` disagree (say, one returns an area density and the other a solid angle density) doesn't
+//! crash anything, it just silently biases every image that uses MIS, so these are worth running for
+//! every new light type.
+
+use crate::light::Light;
+use crate::scene::Scene;
+use crate::Scalar;
+use pmath::numbers::Float;
+use pmath::sampling;
+use pmath::vector::Vec2;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg32;
+use simple_error::{bail, SimpleResult};
+
+/// The number of random receiver points the light is sampled from.
+const NUM_RECEIVERS: usize = 16;
+/// The relative difference allowed between the pdf returned by `sample` and the one returned by `pdf`.
+const PDF_TOLERANCE: Scalar = 1e-3;
+/// The fraction of samples whose pdfs may differ by more than `PDF_TOLERANCE`. The pdf of a sample that
+/// grazes the light is huge and very sensitive to round off (especially with f32), while mixing up the
+/// measures makes almost every sample disagree.
+const MAX_PDF_MISMATCHES: Scalar = 0.01;
+/// How many standard errors (plus a small relative error) a Monte Carlo estimate may be off by.
+const NUM_STD_ERRORS: Scalar = 5.0;
+const REL_TOLERANCE: Scalar = 0.01;
+
+/// The estimates computed by `validate_light` (these are also useful when a check fails).
+#[derive(Clone, Copy, Debug)]
+pub struct LightReport {
+    /// The integral of `pdf` over every direction, summed over the receiver points.
+    pub pdf_integral: Scalar,
+    /// What `pdf_integral` should be: the fraction of light samples with a non-zero pdf, summed over the
+    /// receiver points (so the number of receiver points if every one of them sees the light).
+    pub pdf_mass: Scalar,
+    /// The largest relative difference between the pdf returned by `sample` and `pdf`.
+    pub max_pdf_error: Scalar,
+    /// The fraction of samples for which the pdfs differ by more than `PDF_TOLERANCE`.
+    pub pdf_mismatches: Scalar,
+    /// The luminance of the power estimated from the light samples (`None` for infinite lights).
+    pub power_estimate: Option<Scalar>,
+}
+
+/// The running mean and variance of a Monte Carlo estimate (also used by `lobe::validation`).
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Estimate {
+    sum: Scalar,
+    sum2: Scalar,
+    count: usize,
+}
+
+impl Estimate {
+    pub(crate) fn add(&mut self, v: Scalar) {
+        self.sum += v;
+        self.sum2 += v * v;
+        self.count += 1;
+    }
+
+    pub(crate) fn mean(self) -> Scalar {
+        self.sum / (self.count as Scalar)
+    }
+
+    pub(crate) fn std_error(self) -> Scalar {
+        let n = self.count as Scalar;
+        let variance = (self.sum2 / n - self.mean() * self.mean()).max(0.0);
+        (variance / n).sqrt()
+    }
+}
+
+/// Checks, using `num_samples` samples from each of a number of random receiver points, that:
+/// 1. the density `pdf` integrates to the fraction of `sample` calls that return a non-zero pdf (so to 1
+///    if the receiver point sees the light),
+/// 2. `pdf` evaluated in the direction of a sample matches the pdf `sample` returned (for all but a few
+///    grazing samples),
+/// 3. `power` matches the power that leaves a sphere around the light (estimated from the samples).
+///
+/// The receiver points are on a sphere around both the scene and the light (which has to be lit by the
+/// light, so the scene should only contain the light's geometry). The third check assumes the light
+/// doesn't shadow itself (which holds for convex geometry) and is skipped for infinite lights. Delta
+/// lights can't be hit, so only the third check applies to them.
+pub fn validate_light(
+    light: &dyn Light,
+    scene: &Scene,
+    num_samples: usize,
+    seed: u64,
+) -> SimpleResult<LightReport> {
+    if num_samples == 0 {
+        bail!("Can't validate a light without any samples");
+    }
+    let mut rng = Pcg32::seed_from_u64(seed);
+    let mut rand_vec2 = || Vec2 {
+        x: rng.gen::<Scalar>(),
+        y: rng.gen::<Scalar>(),
+    };
+
+    // The scene is bounded in f64 (see `Scalar`):
+    let bound = scene.world_bound().combine_pnt(light.get_centroid().cast());
+    let center = bound.centroid().cast::<Scalar>();
+    let radius = Scalar::from_f64(bound.diagonal().length() + 1.0);
+
+    let mut report = LightReport {
+        pdf_integral: 0.0,
+        pdf_mass: 0.0,
+        max_pdf_error: 0.0,
+        pdf_mismatches: 0.0,
+        power_estimate: None,
+    };
+    // The variance of `pdf_integral` (the variances of the receivers add up):
+    let mut pdf_variance = 0.0;
+    for _ in 0..NUM_RECEIVERS {
+        let n = sampling::uniform_sample_sphere(rand_vec2());
+        let point = center + n.scale(radius);
+
+        let mut num_nonzero = 0;
+        for _ in 0..num_samples {
+            let (_, light_point, pdf) = light.sample(point, 0.0, scene, rand_vec2());
+            if pdf <= 0.0 {
+                continue;
+            }
+            num_nonzero += 1;
+
+            if !light.is_delta() {
+                let eval_pdf = light.pdf(point, light_point - point);
+                let error = (eval_pdf - pdf).abs() / pdf;
+                report.max_pdf_error = report.max_pdf_error.max(error);
+                if error > PDF_TOLERANCE {
+                    report.pdf_mismatches += 1.0;
+                }
+            }
+        }
+        report.pdf_mass += (num_nonzero as Scalar) / (num_samples as Scalar);
+
+        if !light.is_delta() {
+            // Integrate the pdf over every direction (with uniform directions):
+            let mut receiver_integral = Estimate::default();
+            for _ in 0..num_samples {
+                let wi = sampling::uniform_sample_sphere(rand_vec2());
+                receiver_integral
+                    .add(light.pdf(point, wi) / sampling::uniform_sphere_pdf::<Scalar>());
+            }
+            report.pdf_integral += receiver_integral.mean();
+            pdf_variance += receiver_integral.std_error().powi(2);
+        }
+    }
+
+    if !light.is_delta() {
+        let tolerance =
+            NUM_STD_ERRORS * pdf_variance.sqrt() + REL_TOLERANCE * report.pdf_mass.max(1.0);
+        if (report.pdf_integral - report.pdf_mass).abs() > tolerance {
+            bail!(
+                "The pdf of the light integrates to {} but {} was expected (is it a solid angle density?)",
+                report.pdf_integral,
+                report.pdf_mass
+            );
+        }
+        report.pdf_mismatches /= (NUM_RECEIVERS * num_samples) as Scalar;
+        if report.pdf_mismatches > MAX_PDF_MISMATCHES {
+            bail!(
+                "The pdf returned by sample and the pdf of the sampled direction differ for {}% of the samples (by up to {}%)",
+                report.pdf_mismatches * 100.0,
+                report.max_pdf_error * 100.0
+            );
+        }
+    }
+
+    if !light.is_infinite() {
+        // The irradiance arriving at the sphere from the inside (the point and the normal are in world
+        // space, so the power is as well). Every sample is taken from a different point, as lights don't
+        // have to emit the same amount of light in every direction:
+        let mut power = Estimate::default();
+        for _ in 0..(NUM_RECEIVERS * num_samples) {
+            let n = sampling::uniform_sample_sphere(rand_vec2());
+            let point = center + n.scale(radius);
+            let (color, light_point, pdf) = light.sample(point, 0.0, scene, rand_vec2());
+            if pdf > 0.0 {
+                let cos = -(light_point - point).normalize().dot(n);
+                power.add(color.luminance() * cos.max(0.0) / pdf);
+            } else {
+                power.add(0.0);
+            }
+        }
+
+        let area = 4.0 * Scalar::PI * radius * radius;
+        let estimate = area * power.mean();
+        let expected = light.power().luminance();
+        report.power_estimate = Some(estimate);
+        let tolerance = NUM_STD_ERRORS * area * power.std_error() + REL_TOLERANCE * expected;
+        if (estimate - expected).abs() > tolerance {
+            bail!(
+                "The power of the light is {} but the light samples add up to {}",
+                expected,
+                estimate
+            );
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::BuildAlgorithm;
+    use crate::geometry::mesh::{Mesh, Triangle};
+    use crate::geometry::rect::Rect;
+    use crate::geometry::sphere::Sphere;
+    use crate::geometry::{Geometry, SampleableGeometry};
+    use crate::light::area::diffuse::DiffuseAreaLight;
+    use crate::light::point::Point;
+    use crate::light::portal::{Portal, PortalLight};
+    use crate::scene::{GeomRef, SceneGeom, ScenePrim};
+    use crate::shading::material::{MaterialPool, DEFAULT_MATERIAL_ID};
+    use crate::spectrum::Color;
+    use crate::transform::Transf;
+    use pmath::vector::Vec3;
+    use std::sync::Arc;
+
+    const NUM_SAMPLES: usize = 4000;
+
+    /// A scene that only contains the geometry, and an area light on it.
+    fn area_light(
+        geom: Arc<dyn SampleableGeometry>,
+        transf: Transf,
+        two_sided: bool,
+    ) -> (DiffuseAreaLight, Scene) {
+        let material = MaterialPool::new().get_shared_material(DEFAULT_MATERIAL_ID);
+        let scene_geom = Arc::new(SceneGeom::new_material(geom.clone(), material, transf));
+        let light = DiffuseAreaLight::new(geom, transf, Color::white(), scene_geom.geom_ref())
+            .with_two_sided(two_sided);
+        let scene = Scene::new(&[scene_geom as Arc<dyn ScenePrim>], Vec::new());
+        (light, scene)
+    }
+
+    /// Some geometry, and whether it's closed.
+    fn area_geoms() -> Vec<(Arc<dyn SampleableGeometry>, bool)> {
+        let mut rect = Rect::new(Vec2 { x: 2.0, y: 1.0 });
+        rect.calc_surface_area();
+        let mut sphere = Sphere::new(0.5);
+        sphere.calc_surface_area();
+        // A tetrahedron, with every face pointing outwards:
+        let v = |x: f32, y: f32, z: f32| Vec3 { x, y, z };
+        let triangles = vec![[0, 2, 1], [0, 1, 3], [0, 3, 2], [1, 2, 3]]
+            .into_iter()
+            .map(|indices| Triangle {
+                indices,
+                attribute_id: 0,
+            })
+            .collect();
+        let mut mesh = Mesh::new(
+            triangles,
+            vec![
+                v(0.0, 0.0, 0.0),
+                v(1.0, 0.0, 0.0),
+                v(0.0, 1.0, 0.0),
+                v(0.0, 0.0, 1.0),
+            ],
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            4,
+            BuildAlgorithm::Sah,
+        );
+        mesh.calc_surface_area();
+        vec![
+            (Arc::new(rect), false),
+            (Arc::new(sphere), true),
+            (Arc::new(mesh), true),
+        ]
+    }
+
+    #[test]
+    fn every_light_passes() {
+        let transf = Transf::new_translate(Vec3 {
+            x: 1.0,
+            y: -2.0,
+            z: 0.5,
+        }) * Transf::new_scale(Vec3 {
+            x: 1.5,
+            y: 1.5,
+            z: 1.5,
+        });
+        for (geom, closed) in area_geoms() {
+            // The back of closed geometry is hidden behind its front, so it's always one-sided:
+            let sides: &[bool] = if closed { &[false] } else { &[false, true] };
+            for &two_sided in sides {
+                let (light, scene) = area_light(geom.clone(), transf, two_sided);
+                let report = validate_light(&light, &scene, NUM_SAMPLES, 1).unwrap();
+                assert!(report.pdf_mass > 0.0);
+                assert!(report.power_estimate.unwrap() > 0.0);
+            }
+        }
+
+        let empty = Scene::new(&[], Vec::new());
+        let point = Point::new(
+            Vec3 {
+                x: 0.5,
+                y: 1.0,
+                z: -1.0,
+            },
+            Color::white().scale(3.0),
+        );
+        validate_light(&point, &empty, NUM_SAMPLES, 2).unwrap();
+
+        // A window in the plane z = 0 (looking into the +z side):
+        let portal = PortalLight::new(
+            Color::white(),
+            vec![Portal::new(
+                Vec3::zero(),
+                Vec3 {
+                    x: 0.0,
+                    y: 1.0,
+                    z: 0.0,
+                },
+                Vec3 {
+                    x: 2.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+            )],
+        );
+        let report = validate_light(&portal, &empty, NUM_SAMPLES, 3).unwrap();
+        assert!(report.pdf_mass > 0.0 && report.power_estimate.is_none());
+    }
+
+    /// A light with mistakes: its pdf and its power are scaled.
+    struct Broken<L> {
+        light: L,
+        pdf_scale: Scalar,
+        power_scale: Scalar,
+    }
+
+    impl<L: Light> Light for Broken<L> {
+        fn sample(
+            &self,
+            point: Vec3<Scalar>,
+            time: Scalar,
+            scene: &Scene,
+            u: Vec2<Scalar>,
+        ) -> (Color, Vec3<Scalar>, Scalar) {
+            let (color, light_point, pdf) = self.light.sample(point, time, scene, u);
+            (color, light_point, pdf * self.pdf_scale)
+        }
+
+        fn pdf(&self, shading_point: Vec3<Scalar>, wi: Vec3<Scalar>) -> Scalar {
+            self.light.pdf(shading_point, wi) * self.pdf_scale
+        }
+
+        fn power(&self) -> Color {
+            self.light.power().scale(self.power_scale)
+        }
+
+        fn eval(&self, point: Vec3<Scalar>, w: Vec3<Scalar>) -> Color {
+            self.light.eval(point, w)
+        }
+
+        fn is_delta(&self) -> bool {
+            self.light.is_delta()
+        }
+
+        fn get_geom(&self) -> Option<GeomRef> {
+            self.light.get_geom()
+        }
+
+        fn get_centroid(&self) -> Vec3<Scalar> {
+            self.light.get_centroid()
+        }
+    }
+
+    #[test]
+    fn mismatched_pdfs_and_powers_are_caught() {
+        let (rect, _) = area_geoms().remove(0);
+        let (light, scene) = area_light(rect, Transf::new_identity(), false);
+        // A pdf that doesn't integrate to one (even if `sample` and `pdf` agree):
+        let broken = Broken {
+            light,
+            pdf_scale: 2.0,
+            power_scale: 1.0,
+        };
+        assert!(validate_light(&broken, &scene, NUM_SAMPLES, 1).is_err());
+
+        // The power of a point light is 4 pi times its intensity, not the intensity itself:
+        let empty = Scene::new(&[], Vec::new());
+        let point = Point::new(Vec3::zero(), Color::white());
+        let broken = Broken {
+            light: point,
+            pdf_scale: 1.0,
+            power_scale: 0.25 * Scalar::INV_PI,
+        };
+        assert!(validate_light(&broken, &empty, NUM_SAMPLES, 1).is_err());
+        assert!(validate_light(&broken, &empty, 0, 1).is_err());
+    }
+}
@@ -1,115 +1,967 @@
-// Clean this stuff up in the future...
-// This is here just for now.
-
-#![allow(dead_code)]
-
-mod bvh;
-mod camera;
-mod fileio;
-mod film;
-mod filter;
-mod geometry;
-mod integrator;
-mod interaction;
-mod light;
-mod sampler;
-mod scene;
-mod scripting;
-mod shading;
-mod spectrum;
-mod threading;
-mod transform;
-
-// use camera::perspective::PerspectiveCamera;
-// use geometry::Geometry;
-// use integrator::normal::{NormalIntegrator, NormalIntegratorManager};
-// use pmath::vector::{Vec2, Vec3};
-// use transform::Transf;
-
-// use pmj;
-
-// use rand::{Rng, SeedableRng};
-// use rand_pcg::Pcg32;
-
-// use std::time::Instant;
-
-// const MODEL: &'static str = "/home/dennis/Dev/rust_prism/test_files/sphere.ply";
+mod cli;
+#[cfg(feature = "hot-reload")]
+mod watcher;
+
+use cli::{CliArgs, Verbosity};
+use pmath::bbox::BBox3;
+use pmath::numbers::Float;
+use pmath::vector::Vec2;
+use prism::camera::perspective::PerspectiveCamera;
+use prism::error::{PrismError, PrismResult};
+use prism::fileio::scene::IntegratorDesc;
+use prism::film::exr::{ExrLayer, PixelType};
+use prism::film::metadata::ImageMetadata;
+use prism::film::{AovKind, ImageBuffer, ImagePixel, LightPath};
+use prism::integrator::ao::{AoIntegrator, AoIntegratorManager, AoParam};
+use prism::integrator::debug::{
+    AttributeIdIntegrator, AttributeIdView, DebugIntegratorManager, DepthIntegrator, DepthView,
+    GeomNormalIntegrator, GeomNormalView, PositionIntegrator, PositionView, UvIntegrator, UvView,
+};
+use prism::integrator::normal::{NormalIntegrator, NormalIntegratorManager};
+use prism::integrator::path_tracer::{
+    PathTracerIntegrator, PathTracerIntegratorManager, PathTracerParam,
+};
+use prism::integrator::sppm::{self, CausticParam};
+use prism::integrator::{Integrator, IntegratorManager};
+use prism::light::DirectLightParam;
+#[cfg(feature = "hot-reload")]
+use prism::progressive;
+use prism::spectrum::Color;
+use prism::threading::wavefront::WavefrontRenderer;
+use prism::{fileio, film, filter, scene, shading, threading, Scalar};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 
 fn main() {
-    // let mut mesh = fileio::ply::load_mesh(MODEL).unwrap();
-    // mesh.create_embree_geometry();
-    // //let mesh_pos = Transf::new_translate(Vec3 { x: 0.0, y: 0.0, z: 0.0 });
-    // //mesh.transform(mesh_pos);
-
-    // //let mesh_ref = scene::allocate_mesh(mesh);
-
-    // let mut scene = scene::Scene::new();
-    // let mesh_ref = scene.add_to_geom_pool(mesh); //mesh);
-    // scene.add_toplevel_geom(mesh_ref, 0);
-    // scene.build_scene();
-
-    // // let ray = pmath::ray::Ray::new(
-    // //     Vec3 {
-    // //         x: -3.0,
-    // //         y: 0.0,
-    // //         z: 0.0,
-    // //     },
-    // //     Vec3 {
-    // //         x: 1.0,
-    // //         y: 0.0,
-    // //         z: 0.0,
-    // //     },
-    // //     1.0,
-    // // );
-    // // println!("{:#?}", scene.intersect(ray));
-    // // return;
-
-    // let camera_pos = Transf::new_lookat(
-    //     Vec3 {
-    //         x: 0.0,
-    //         y: 1.0,
-    //         z: 0.0,
-    //     },
-    //     Vec3 {
-    //         x: 0.0,
-    //         y: 0.0,
-    //         z: 0.0,
-    //     },
-    //     Vec3 {
-    //         x: -2.0,
-    //         y: 0.0,
-    //         z: 0.0,
-    //     },
-    // );
-
-    // let bbox = pmath::bbox::BBox2::from_pnts(Vec2 { x: -1.0, y: -1.0 }, Vec2 { x: 1.0, y: 1.0 });
-    // let cam = PerspectiveCamera::new(camera_pos, 90.0, 0.0, 1.0, bbox, Vec2 { x: 400, y: 400 });
-
-    // let filter = filter::GaussianFilter::new(Vec2 { x: 1.0, y: 1.0 }, 0.5);
-    // let pixel_filter = filter::PixelFilter::new(&filter);
-    // let param = threading::RenderParam {
-    //     num_pixel_samples: 5,
-    //     num_threads: 64,
-    //     sample_seed: 13,
-    //     blue_noise_count: 3,
-    //     res: Vec2 { x: 400, y: 400 },
-    // };
-    // let now = Instant::now();
-    // let film = threading::render::<NormalIntegrator, NormalIntegratorManager>(
-    //     &cam,
-    //     pixel_filter,
-    //     &scene,
-    //     param,
-    //     true,
-    // )
-    // .unwrap();
-    // println!("Render time: {}", now.elapsed().as_nanos());
-
-    // let image_buffer = film.to_image_buffer(|color| film::ImagePixel {
-    //     r: color.r,
-    //     g: color.g,
-    //     b: color.b,
-    // });
-
-    // film::png::write_png(&image_buffer, "normal2.png", film::png::BitDepth::EIGHT).unwrap();
+    let mut raw_args = std::env::args().skip(1).peekable();
+    if raw_args.peek().map(String::as_str) == Some("inspect") {
+        raw_args.next();
+        let path = match (raw_args.next(), raw_args.next()) {
+            (Some(path), None) => path,
+            _ => {
+                eprintln!("error: inspect expects a single image\n\n{}", cli::USAGE);
+                process::exit(2);
+            }
+        };
+        if let Err(err) = inspect(&path) {
+            eprintln!("error: {}", err);
+            process::exit(err.exit_code());
+        }
+        return;
+    }
+
+    let args = match cli::parse_args(raw_args) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{}", cli::USAGE);
+            return;
+        }
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = run(&args) {
+        eprintln!("error: {}", err);
+        process::exit(err.exit_code());
+    }
+}
+
+fn run(args: &CliArgs) -> PrismResult<()> {
+    let source = fs::read_to_string(&args.scene).map_err(|err| PrismError::io(&args.scene, err))?;
+    let mut desc = fileio::scene::parse_scene(&source).map_err(|err| err.with_path(&args.scene))?;
+    // The arguments can only be checked against the scene once it is parsed:
+    args.apply(&mut desc)
+        .map_err(|err| PrismError::Usage(err.to_string()))?;
+    let scene_hash = hash_bytes(source.as_bytes());
+    if desc.animation.is_some() {
+        return render_animation(args, &desc, scene_hash);
+    }
+
+    let load_start = Instant::now();
+    let base_dir = Path::new(&args.scene)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    let loaded = fileio::scene::build_scene(&desc, base_dir)?;
+    if args.verbosity == Verbosity::Verbose {
+        eprintln!(
+            "Loaded {} in {:.2}s: {:?}",
+            args.scene,
+            load_start.elapsed().as_secs_f64(),
+            loaded.param
+        );
+        eprint!("{}", loaded.scene.statistics());
+    }
+
+    let pixel_filter = pixel_filter();
+    let cancel = start_cancellation(args)?;
+
+    #[cfg(feature = "hot-reload")]
+    {
+        if args.watch {
+            if caustic_param(&loaded).is_some() {
+                return Err(PrismError::Usage(
+                    "--watch can't be used with caustics".to_owned(),
+                ));
+            }
+            if loaded.param.supersample > 1 {
+                return Err(PrismError::Usage(
+                    "--watch can't be used with supersampling".to_owned(),
+                ));
+            }
+            return watch(args, &desc, loaded, pixel_filter, &cancel);
+        }
+    }
+
+    let progress = progress(args);
+    let render_start = Instant::now();
+    let output = if args.wavefront {
+        let int_param = match path_tracer_param(loaded.integrator) {
+            Some(int_param) => int_param,
+            None => {
+                return Err(PrismError::Usage(
+                    "--wavefront can only be used with the path integrator".to_owned(),
+                ))
+            }
+        };
+        if caustic_param(&loaded).is_some() {
+            return Err(PrismError::Usage(
+                "--wavefront can't be used with caustics".to_owned(),
+            ));
+        }
+        let renderer = WavefrontRenderer::new(loaded.param.clone())?;
+        renderer.render(
+            &loaded.scene,
+            &loaded.materials,
+            &loaded.camera,
+            pixel_filter,
+            int_param,
+            progress,
+            Some(&cancel),
+        )
+    } else {
+        let renderer = threading::Renderer::new(loaded.param.clone())?;
+        render(
+            &loaded,
+            Render {
+                renderer: &renderer,
+                scene: &loaded.scene,
+                materials: &loaded.materials,
+                camera: &loaded.camera,
+                filter: pixel_filter,
+                progress,
+                cancel: &cancel,
+            },
+        )
+    };
+    let output = output?;
+
+    if args.verbosity != Verbosity::Quiet {
+        eprintln!("Render time: {:.2}s", render_start.elapsed().as_secs_f64());
+        if output.cancelled {
+            eprintln!("Render was stopped early, writing the partial image");
+        }
+    }
+
+    let metadata = render_metadata(args, &loaded, &output, scene_hash, render_start.elapsed());
+    write_output(args, &args.out, &output, &loaded, &metadata)?;
+    match &args.reference {
+        Some(reference) => check_reference(args, reference),
+        None => Ok(()),
+    }
+}
+
+/// The most the image written with --reference can differ from the reference (on a scale from 0 to 1),
+/// on average and for any single channel. Renders are deterministic, so this only leaves room for
+/// differences in floating point math between platforms.
+const REFERENCE_MAX_MEAN_ERROR: f64 = 0.002;
+const REFERENCE_MAX_ERROR: f64 = 0.1;
+
+/// Compares the image that was written with the reference image (or replaces the reference with it if
+/// PRISM_UPDATE_REFERENCES is set).
+fn check_reference(args: &CliArgs, reference: &str) -> PrismResult<()> {
+    if env::var_os("PRISM_UPDATE_REFERENCES").is_some() {
+        fs::copy(&args.out, reference).map_err(|err| PrismError::io(reference, err))?;
+        if args.verbosity != Verbosity::Quiet {
+            eprintln!("Updated the reference {}", reference);
+        }
+        return Ok(());
+    }
+
+    // Both are compared as they were written, so that the image is quantized the same way:
+    let image = film::png::read_png(&args.out)?;
+    let diff = match image.diff(&film::png::read_png(reference)?) {
+        Some(diff) => diff,
+        None => {
+            let msg = format!("{} has a different resolution than {}", args.out, reference);
+            return Err(PrismError::ReferenceMismatch(msg));
+        }
+    };
+    if diff.mean_abs_error > REFERENCE_MAX_MEAN_ERROR || diff.max_error > REFERENCE_MAX_ERROR {
+        let msg = format!(
+            "{} differs from {} (mean error {:.5}, max error {:.5})",
+            args.out, reference, diff.mean_abs_error, diff.max_error
+        );
+        return Err(PrismError::ReferenceMismatch(msg));
+    }
+    if args.verbosity != Verbosity::Quiet {
+        eprintln!(
+            "Matches the reference {} (mean error {:.5}, max error {:.5})",
+            reference, diff.mean_abs_error, diff.max_error
+        );
+    }
+    Ok(())
+}
+
+/// Renders every frame of an animated scene (see `fileio::scene::Animation`) and writes them numbered by
+/// frame (out.png becomes out_0000.png, out_0001.png, ...). The scene is rebuilt for every frame, but the
+/// thread pool is only created once.
+fn render_animation(
+    args: &CliArgs,
+    desc: &fileio::scene::SceneDesc,
+    scene_hash: u64,
+) -> PrismResult<()> {
+    if args.wavefront {
+        return Err(PrismError::Usage(
+            "--wavefront can't be used to render animations".to_owned(),
+        ));
+    }
+    if args.watch {
+        return Err(PrismError::Usage(
+            "--watch can't be used to render animations".to_owned(),
+        ));
+    }
+    if args.reference.is_some() {
+        return Err(PrismError::Usage(
+            "--reference can't be used to render animations".to_owned(),
+        ));
+    }
+    let mut animation = fileio::scene::Animation::new(desc)?;
+    let base_dir = Path::new(&args.scene)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    let pixel_filter = pixel_filter();
+    let cancel = start_cancellation(args)?;
+    let progress = progress(args);
+
+    let animation_start = Instant::now();
+    let mut renderer: Option<threading::Renderer> = None;
+    for frame in animation.frames() {
+        if cancel.is_cancelled() {
+            break;
+        }
+        let frame_start = Instant::now();
+        let loaded = fileio::scene::build_scene(&animation.frame_desc(desc, frame), base_dir)?;
+        if args.verbosity == Verbosity::Verbose {
+            eprintln!(
+                "Loaded frame {} in {:.2}s",
+                frame,
+                frame_start.elapsed().as_secs_f64()
+            );
+        }
+        // Every frame has its own seed:
+        match &mut renderer {
+            Some(renderer) => renderer.set_sample_seed(loaded.param.sample_seed),
+            None => renderer = Some(threading::Renderer::new(loaded.param.clone())?),
+        }
+        let output = render(
+            &loaded,
+            Render {
+                renderer: renderer.as_ref().unwrap(),
+                scene: &loaded.scene,
+                materials: &loaded.materials,
+                camera: &loaded.camera,
+                filter: pixel_filter,
+                progress,
+                cancel: &cancel,
+            },
+        )?;
+
+        let path = aov_path(&args.out, &format!("{:04}", frame));
+        let mut metadata =
+            render_metadata(args, &loaded, &output, scene_hash, frame_start.elapsed());
+        metadata.push("prism:frame", frame.to_string());
+        write_output(args, &path, &output, &loaded, &metadata)?;
+        if args.verbosity != Verbosity::Quiet {
+            eprintln!(
+                "Frame {} took {:.2}s, wrote {}",
+                frame,
+                frame_start.elapsed().as_secs_f64(),
+                path
+            );
+            if output.cancelled {
+                eprintln!("Render was stopped early, the frame is incomplete");
+            }
+        }
+    }
+    if args.verbosity != Verbosity::Quiet {
+        eprintln!(
+            "Animation time: {:.2}s",
+            animation_start.elapsed().as_secs_f64()
+        );
+    }
+    Ok(())
+}
+
+fn pixel_filter() -> filter::PixelFilter {
+    let filter = filter::GaussianFilter::new(Vec2 { x: 1.0, y: 1.0 }, 0.5);
+    filter::PixelFilter::new(&filter)
+}
+
+/// Returns a token that is cancelled by Ctrl-C (and once the time limit is up). The render stops, but
+/// the partial image is still written.
+fn start_cancellation(args: &CliArgs) -> PrismResult<threading::CancellationToken> {
+    let cancel = threading::CancellationToken::new();
+    let handler_cancel = cancel.clone();
+    if let Err(err) = ctrlc::set_handler(move || handler_cancel.cancel()) {
+        let msg = format!("couldn't set the Ctrl-C handler: {}", err);
+        return Err(PrismError::Render(msg));
+    }
+    if let Some(time_limit) = args.time_limit {
+        let limit_cancel = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(time_limit);
+            limit_cancel.cancel();
+        });
+    }
+    Ok(cancel)
+}
+
+fn progress(args: &CliArgs) -> Option<&'static (dyn Fn(threading::RenderProgress) + Sync)> {
+    if args.verbosity == Verbosity::Quiet {
+        None
+    } else {
+        Some(&threading::print_progress)
+    }
+}
+
+/// Writes the image to `out`, and the AOVs next to it if the integrator produces them.
+fn write_output(
+    args: &CliArgs,
+    out: &str,
+    output: &threading::RenderOutput,
+    loaded: &fileio::scene::LoadedScene,
+    metadata: &ImageMetadata,
+) -> PrismResult<()> {
+    let film = &output.film;
+    let downscale = |image: ImageBuffer| output_downscale(image, loaded);
+    let tone_mapped = |image: ImageBuffer| output_tone_mapped(image, loaded);
+    let linear = ImagePixel::from_color;
+
+    let mut image_buffer = tone_mapped(film.to_image_buffer(linear));
+    if let Some(overlay) = args.debug_overlay {
+        overlay.draw(
+            &mut image_buffer,
+            &loaded.scene,
+            &loaded.camera,
+            loaded.param.tile_size(),
+            loaded.param.supersample.max(1) as usize,
+        );
+    }
+    write_image(
+        args,
+        out,
+        image_buffer,
+        loaded.scene.has_transparent_background(),
+        metadata,
+    )?;
+
+    // The variance needs the precision (and range) of floats, like the positions below:
+    if let Some(split) = film.to_image_buffer_split(linear) {
+        let alpha = loaded.scene.has_transparent_background();
+        write_image(
+            args,
+            &aov_path(out, "a"),
+            tone_mapped(split.a),
+            alpha,
+            metadata,
+        )?;
+        write_image(
+            args,
+            &aov_path(out, "b"),
+            tone_mapped(split.b),
+            alpha,
+            metadata,
+        )?;
+        let variance_path = exr_path(&aov_path(out, "variance"));
+        let variance = match loaded.param.supersample {
+            0 | 1 => split.variance,
+            supersample => split.variance.downscale_variance(supersample as usize),
+        };
+        write_image_as(
+            args,
+            &variance_path,
+            variance,
+            false,
+            PixelType::Float,
+            metadata,
+        )?;
+    }
+
+    // The heatmaps and light paths don't need the precision of the image, so exr files store them as half
+    // floats:
+    if let IntegratorDesc::Path { aovs: true, .. } = loaded.integrator {
+        for &kind in AovKind::PATH.iter() {
+            let path = aov_path(out, kind.name());
+            // Exr files store the values themselves, pngs a heatmap:
+            let image_buffer = if is_exr(&path) {
+                film.aov_values_to_image_buffer(kind)
+            } else {
+                film.aov_to_image_buffer(kind)
+            };
+            write_image_as(
+                args,
+                &path,
+                downscale(image_buffer),
+                false,
+                PixelType::Half,
+                metadata,
+            )?;
+        }
+        for &kind in LightPath::ALL.iter() {
+            write_image_as(
+                args,
+                &aov_path(out, kind.name()),
+                tone_mapped(film.light_path_to_image_buffer(kind, linear)),
+                loaded.scene.has_transparent_background(),
+                PixelType::Half,
+                metadata,
+            )?;
+        }
+
+        // Positions and depths need the precision (and range) of floats, so they're always written as
+        // exr files. The positions are relative to the camera (see `Film::position_to_image_buffer`):
+        let camera = &loaded.camera;
+        let position_path = exr_path(&aov_path(out, "position"));
+        let position = downscale(film.position_to_image_buffer(camera.position()));
+        write_image_as(
+            args,
+            &position_path,
+            position,
+            true,
+            PixelType::Float,
+            metadata,
+        )?;
+        let depth_path = exr_path(&aov_path(out, "depth"));
+        let depth = downscale(film.depth_to_image_buffer(camera.position(), camera.view_dir()));
+        write_image_as(args, &depth_path, depth, true, PixelType::Float, metadata)?;
+    }
+    Ok(())
+}
+
+/// Describes how the image was rendered, which is stored in every image that is written (see
+/// `film::metadata`). The commit is only known if PRISM_GIT_COMMIT was set when the renderer was built.
+fn render_metadata(
+    args: &CliArgs,
+    loaded: &fileio::scene::LoadedScene,
+    output: &threading::RenderOutput,
+    scene_hash: u64,
+    render_time: Duration,
+) -> ImageMetadata {
+    let param = &loaded.param;
+    let mut metadata = ImageMetadata::new();
+    metadata.push("Software", format!("prism {}", env!("CARGO_PKG_VERSION")));
+    if let Some(commit) = option_env!("PRISM_GIT_COMMIT") {
+        metadata.push("prism:commit", commit);
+    }
+    metadata.push("prism:scene", args.scene.as_str());
+    metadata.push("prism:sceneHash", format!("{:016x}", scene_hash));
+    metadata.push(
+        "prism:resolution",
+        format!("{}x{}", param.res.x, param.res.y),
+    );
+    if param.supersample > 1 {
+        metadata.push("prism:supersample", param.supersample.to_string());
+    }
+    // With an importance map, the number of samples is only the average (see `RenderParam`):
+    let spp = match param.importance_map {
+        Some(_) => format!(
+            "{} (on average, with an importance map)",
+            param.num_pixel_samples
+        ),
+        None => param.num_pixel_samples.to_string(),
+    };
+    metadata.push("prism:spp", spp);
+    metadata.push("prism:integrator", format!("{:?}", loaded.integrator));
+    metadata.push(
+        "prism:sampler",
+        format!("{:?} ({:?})", param.sample_sequence, param.sampler_mode),
+    );
+    metadata.push("prism:seed", param.sample_seed.to_string());
+    metadata.push("prism:threads", param.num_threads.to_string());
+    metadata.push(
+        "prism:renderTime",
+        format!("{:.3}s", render_time.as_secs_f64()),
+    );
+    metadata.push("prism:complete", (!output.cancelled).to_string());
+    let num_cores = core_affinity::get_core_ids().map_or(1, |ids| ids.len().max(1));
+    metadata.push(
+        "prism:machine",
+        format!(
+            "{} {} ({} cores)",
+            env::consts::OS,
+            env::consts::ARCH,
+            num_cores
+        ),
+    );
+    metadata
+}
+
+/// A hash of the scene file, so that it can be told whether an image was rendered from the current
+/// version of the file (FNV-1a, as it's stable unlike the hasher in the standard library).
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ (byte as u64)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Prints the metadata of an image written by the renderer (see `render_metadata`).
+fn inspect(path: &str) -> PrismResult<()> {
+    let metadata = film::metadata::read_metadata(path)?;
+    if metadata.is_empty() {
+        println!("{} doesn't have any metadata", path);
+    }
+    for (key, value) in metadata.iter() {
+        println!("{}: {}", key, value);
+    }
+    Ok(())
+}
+
+/// Downscales an image of the film to the resolution of the output, if the film was supersampled (see
+/// `RenderParam::supersample`).
+fn output_downscale(image: ImageBuffer, loaded: &fileio::scene::LoadedScene) -> ImageBuffer {
+    match loaded.param.supersample {
+        0 | 1 => image,
+        supersample => image.downscale(supersample as usize),
+    }
+}
+
+/// Tone maps an image of the film (with the colors in linear space) after downscaling it, so that the
+/// pixels are filtered before they're tone mapped.
+fn output_tone_mapped(image: ImageBuffer, loaded: &fileio::scene::LoadedScene) -> ImageBuffer {
+    let tone_map = output_tone_map(loaded);
+    output_downscale(image, loaded).map(|pixel| ImagePixel {
+        // The negative lobes of the filter can push the alpha out of range at hard edges:
+        a: pixel.a.max(0.0).min(1.0),
+        ..tone_map(pixel.to_color())
+    })
+}
+
+/// The path with its extension replaced by `.exr`.
+fn exr_path(path: &str) -> String {
+    Path::new(path)
+        .with_extension("exr")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Something that needs to know the type of the integrator, which is only known once the scene file is
+/// loaded (see `with_integrator`).
+trait WithIntegrator {
+    type Output;
+
+    fn run<I: Integrator, M: IntegratorManager<I>>(self, int_param: M::InitParam) -> Self::Output;
+}
+
+/// Runs `f` with the integrator described by `desc`. The debug integrators need the bounding box of the
+/// scene and the camera to pick their defaults.
+fn with_integrator<F: WithIntegrator>(
+    desc: IntegratorDesc,
+    world_bound: BBox3<f64>,
+    camera: &PerspectiveCamera,
+    f: F,
+) -> F::Output {
+    match desc {
+        IntegratorDesc::Normal { geometric_normal } => {
+            f.run::<NormalIntegrator, NormalIntegratorManager>(geometric_normal)
+        }
+        IntegratorDesc::Path { .. } => f.run::<PathTracerIntegrator, PathTracerIntegratorManager>(
+            path_tracer_param(desc).unwrap(),
+        ),
+        IntegratorDesc::Depth { near, far } => {
+            let view_dir = camera.view_dir();
+            // By default, the farthest corner of the scene is white:
+            let far = far.unwrap_or_else(|| {
+                (0..8)
+                    .map(|i| (world_bound.corner(i) - camera.position()).dot(view_dir))
+                    .fold(near, f64::max)
+            });
+            let far = if far.is_finite() && far > near {
+                far
+            } else {
+                near + 1.0
+            };
+            f.run::<DepthIntegrator, DebugIntegratorManager<DepthView>>(DepthView {
+                near: Scalar::from_f64(near),
+                far: Scalar::from_f64(far),
+                view_dir: view_dir.cast(),
+            })
+        }
+        IntegratorDesc::Position => f
+            .run::<PositionIntegrator, DebugIntegratorManager<PositionView>>(PositionView {
+                bbox: world_bound,
+            }),
+        IntegratorDesc::GeomNormal => {
+            f.run::<GeomNormalIntegrator, DebugIntegratorManager<GeomNormalView>>(GeomNormalView)
+        }
+        IntegratorDesc::Uv => f.run::<UvIntegrator, DebugIntegratorManager<UvView>>(UvView),
+        IntegratorDesc::AttributeId => {
+            f.run::<AttributeIdIntegrator, DebugIntegratorManager<AttributeIdView>>(AttributeIdView)
+        }
+        IntegratorDesc::AmbientOcclusion {
+            max_distance,
+            samples,
+        } => {
+            // By default, only geometry close to the hit (relative to the size of the scene) occludes it:
+            let max_distance = max_distance.unwrap_or_else(|| {
+                let max_distance = world_bound.diagonal().length() / 10.0;
+                if max_distance.is_finite() && max_distance > 0.0 {
+                    max_distance
+                } else {
+                    1.0
+                }
+            });
+            f.run::<AoIntegrator, AoIntegratorManager>(AoParam {
+                max_distance: Scalar::from_f64(max_distance),
+                samples,
+            })
+        }
+    }
+}
+
+/// The parameters of the path tracer, if `desc` describes it.
+fn path_tracer_param(desc: IntegratorDesc) -> Option<PathTracerParam> {
+    match desc {
+        IntegratorDesc::Path {
+            max_bounce,
+            light_samples,
+            bsdf_samples,
+            terminator_fix,
+            aovs,
+            max_direct,
+            max_indirect,
+            shadow_rr,
+            caustics: _,
+        } => Some(PathTracerParam {
+            max_bounce,
+            direct_light: DirectLightParam {
+                n_light_samples: light_samples,
+                n_bsdf_samples: bsdf_samples,
+                terminator_fix,
+                shadow_rr: shadow_rr.map(Scalar::from_f64),
+            },
+            aovs,
+            max_direct: max_direct.map(Scalar::from_f64),
+            max_indirect: max_indirect.map(Scalar::from_f64),
+        }),
+        _ => None,
+    }
+}
+
+/// The parameters of the photons (see `integrator::sppm`), if the scene is rendered with the path tracer
+/// and the caustics are rendered with photons.
+fn caustic_param(loaded: &fileio::scene::LoadedScene) -> Option<CausticParam> {
+    match loaded.integrator {
+        IntegratorDesc::Path {
+            max_bounce,
+            caustics: Some(caustics),
+            ..
+        } => Some(CausticParam {
+            photons: caustics.photons,
+            max_photons: caustics.max_photons,
+            radius: Scalar::from_f64(caustics.radius.unwrap_or_else(|| {
+                let radius = loaded.scene.world_bound().diagonal().length() / 100.0;
+                if radius.is_finite() && radius > 0.0 {
+                    radius
+                } else {
+                    1.0
+                }
+            })),
+            alpha: Scalar::from_f64(caustics.alpha),
+            max_bounce,
+            seed: loaded.param.sample_seed,
+        }),
+        _ => None,
+    }
+}
+
+/// Renders the scene with the integrator it describes (see `with_integrator`), or with the path tracer
+/// and photons if it renders caustics.
+fn render(
+    loaded: &fileio::scene::LoadedScene,
+    render: Render,
+) -> Result<threading::RenderOutput, threading::RenderError> {
+    match caustic_param(loaded) {
+        Some(param) => sppm::render(
+            render.renderer,
+            render.scene,
+            render.materials,
+            render.camera,
+            render.filter,
+            path_tracer_param(loaded.integrator).unwrap(),
+            param,
+            render.progress,
+            Some(render.cancel),
+        ),
+        None => with_integrator(
+            loaded.integrator,
+            loaded.scene.world_bound(),
+            &loaded.camera,
+            render,
+        ),
+    }
+}
+
+/// Renders the entire image in one go.
+struct Render<'a> {
+    renderer: &'a threading::Renderer,
+    scene: &'a scene::Scene,
+    materials: &'a shading::material::MaterialPool,
+    camera: &'a PerspectiveCamera,
+    filter: filter::PixelFilter,
+    progress: Option<&'a (dyn Fn(threading::RenderProgress) + Sync)>,
+    cancel: &'a threading::CancellationToken,
+}
+
+impl<'a> WithIntegrator for Render<'a> {
+    type Output = Result<threading::RenderOutput, threading::RenderError>;
+
+    fn run<I: Integrator, M: IntegratorManager<I>>(self, int_param: M::InitParam) -> Self::Output {
+        self.renderer.render::<I, M>(
+            self.scene,
+            self.materials,
+            self.camera,
+            self.filter,
+            int_param,
+            self.progress,
+            Some(self.cancel),
+        )
+    }
+}
+
+fn tone_map(color: Color) -> ImagePixel {
+    ImagePixel::from_color(color)
+}
+
+/// The tone map of the scene, which white balances the colors first if the scene has a white point.
+fn output_tone_map(loaded: &fileio::scene::LoadedScene) -> impl Fn(Color) -> ImagePixel + Copy {
+    let white_balance = loaded.white_balance;
+    move |color| match white_balance {
+        Some(white_balance) => tone_map(white_balance.apply(color)),
+        None => tone_map(color),
+    }
+}
+
+/// Writes the image to `path` (cropping it if needed), as an exr file (with 32 bit floats) if the path
+/// ends in `.exr` and as a png file otherwise.
+fn write_image(
+    args: &CliArgs,
+    path: &str,
+    image_buffer: ImageBuffer,
+    alpha: bool,
+    metadata: &ImageMetadata,
+) -> PrismResult<()> {
+    write_image_as(args, path, image_buffer, alpha, PixelType::Float, metadata)
+}
+
+/// Same as `write_image`, but with the type used for the pixels of exr files.
+fn write_image_as(
+    args: &CliArgs,
+    path: &str,
+    image_buffer: ImageBuffer,
+    alpha: bool,
+    pixel_type: PixelType,
+    metadata: &ImageMetadata,
+) -> PrismResult<()> {
+    let image_buffer = match args.crop {
+        Some((pmin, pmax)) => image_buffer.crop(pmin, pmax),
+        None => image_buffer,
+    };
+    if is_exr(path) {
+        let layer = ExrLayer {
+            name: "",
+            image: &image_buffer,
+            pixel_type,
+            alpha,
+        };
+        return film::exr::write_exr_with_metadata(&[layer], path, metadata);
+    }
+    // Only images with a transparent background need alpha (see `Scene::has_transparent_background`):
+    let channels = if alpha {
+        film::png::Channels::RGBA
+    } else {
+        film::png::Channels::RGB
+    };
+    film::png::write_png_with_metadata(
+        &image_buffer,
+        path,
+        film::png::BitDepth::EIGHT,
+        channels,
+        metadata,
+    )
+}
+
+fn is_exr(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("exr"))
+}
+
+/// Where to write an AOV: next to the image, with the name of the AOV appended (out.png becomes
+/// out_bounces.png, for instance).
+fn aov_path(out: &str, name: &str) -> String {
+    let out = Path::new(out);
+    let stem = out
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("out");
+    let file_name = match out.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => format!("{}_{}.{}", stem, name, ext),
+        None => format!("{}_{}", stem, name),
+    };
+    out.with_file_name(file_name).to_string_lossy().into_owned()
+}
+
+/// Renders the scene progressively and writes the image once all of the samples are in. After that, it
+/// waits for the scene file to change, applies any changes to the materials, and starts over.
+#[cfg(feature = "hot-reload")]
+fn watch(
+    args: &CliArgs,
+    desc: &fileio::scene::SceneDesc,
+    mut loaded: fileio::scene::LoadedScene,
+    filter: filter::PixelFilter,
+    cancel: &threading::CancellationToken,
+) -> PrismResult<()> {
+    let world_bound = loaded.scene.world_bound();
+    let camera = loaded.camera;
+    with_integrator(
+        loaded.integrator,
+        world_bound,
+        &camera,
+        Watch {
+            args,
+            desc,
+            loaded: &mut loaded,
+            filter,
+            cancel,
+        },
+    )
+}
+
+#[cfg(feature = "hot-reload")]
+struct Watch<'a> {
+    args: &'a CliArgs,
+    desc: &'a fileio::scene::SceneDesc,
+    loaded: &'a mut fileio::scene::LoadedScene,
+    filter: filter::PixelFilter,
+    cancel: &'a threading::CancellationToken,
+}
+
+#[cfg(feature = "hot-reload")]
+impl<'a> WithIntegrator for Watch<'a> {
+    type Output = PrismResult<()>;
+
+    fn run<I: Integrator, M: IntegratorManager<I>>(self, int_param: M::InitParam) -> Self::Output {
+        watch_with::<I, M>(
+            self.args,
+            self.desc,
+            self.loaded,
+            self.filter,
+            int_param,
+            self.cancel,
+        )
+    }
+}
+
+#[cfg(feature = "hot-reload")]
+fn watch_with<I: Integrator, M: IntegratorManager<I>>(
+    args: &CliArgs,
+    desc: &fileio::scene::SceneDesc,
+    loaded: &mut fileio::scene::LoadedScene,
+    filter: filter::PixelFilter,
+    int_param: M::InitParam,
+    cancel: &threading::CancellationToken,
+) -> PrismResult<()> {
+    let renderer = threading::Renderer::new(loaded.param.clone())?;
+    let num_passes = loaded.param.num_pixel_samples;
+    let alpha = loaded.scene.has_transparent_background();
+    let tone_map = output_tone_map(loaded);
+    let material_ids = &loaded.material_ids;
+    let mut session = progressive::ProgressiveSession::<I, M>::new(
+        renderer,
+        &mut loaded.scene,
+        &loaded.materials,
+        loaded.camera,
+        filter,
+        int_param,
+    )
+    // Only the materials change when the scene file is reloaded, so the camera rays hit the same
+    // surfaces:
+    .with_first_hit_cache(true);
+
+    let mut desc = desc.clone();
+    let base_dir = Path::new(&args.scene)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    let mut watcher = watcher::FileWatcher::new(&args.scene);
+    while !cancel.is_cancelled() {
+        // Errors in the scene file shouldn't stop the session, as they are likely to be fixed soon:
+        if watcher.poll() {
+            let reloaded = fs::read_to_string(&args.scene)
+                .map_err(|err| PrismError::io(&args.scene, err))
+                .and_then(|source| {
+                    fileio::scene::parse_scene(&source).map_err(|err| err.with_path(&args.scene))
+                })
+                .and_then(|new_desc| {
+                    let updates =
+                        fileio::scene::diff_materials(&desc, &new_desc, material_ids, base_dir)?;
+                    Ok((new_desc, updates))
+                });
+            match reloaded {
+                Ok((new_desc, updates)) => {
+                    if args.verbosity != Verbosity::Quiet {
+                        eprintln!(
+                            "Reloaded {} ({} materials changed)",
+                            args.scene,
+                            updates.len()
+                        );
+                    }
+                    for update in updates {
+                        session.queue_update(update);
+                    }
+                    desc = new_desc;
+                }
+                Err(err) => eprintln!("error: {}", err),
+            }
+        }
+        if let Err(err) = session.apply_updates() {
+            eprintln!("error: {}", err);
+        }
+
+        if session.num_passes() < num_passes {
+            session.step()?;
+            if session.num_passes() == num_passes {
+                let metadata = ImageMetadata::new();
+                write_image(
+                    args,
+                    &args.out,
+                    session.snapshot(tone_map),
+                    alpha,
+                    &metadata,
+                )?;
+                if args.verbosity != Verbosity::Quiet {
+                    eprintln!("Wrote {}, watching {} for changes", args.out, args.scene);
+                }
+            }
+        } else {
+            thread::sleep(std::time::Duration::from_millis(200));
+        }
+    }
+    Ok(())
 }
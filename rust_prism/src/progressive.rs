@@ -0,0 +1,461 @@
+use crate::camera::perspective::PerspectiveCamera;
+use crate::error::PrismResult;
+use crate::film::{Film, ImageBuffer, ImagePixel};
+use crate::filter::PixelFilter;
+use crate::integrator::preview::{PreviewIntegratorManager, PreviewParam};
+use crate::integrator::{Integrator, IntegratorManager};
+use crate::scene::{Scene, SceneUpdate};
+use crate::shading::material::MaterialPool;
+use crate::spectrum::Color;
+use crate::threading::first_hit::{FirstHitCache, FirstHits};
+use crate::threading::{CancellationToken, RenderError, Renderer};
+use std::marker::PhantomData;
+
+/// Continuously accumulates 1 spp passes over the entire frame, which is useful for interactive viewers.
+/// The current image can be retrieved at any point, and the session can be restarted (for instance, when
+/// the camera moves). Materials and lights can be updated between passes (see `queue_update`).
+///
+/// By default the first step after (re)starting renders a quick preview (see `PreviewIntegrator`), so that
+/// there is something to show right away. It's shown until the first actual pass is done, and isn't
+/// accumulated with the passes (see `with_preview`).
+///
+/// The first hits of the camera rays of the first pass can be cached (see `with_first_hit_cache`), so that
+/// the first pass after updating materials or lights doesn't have to trace the camera rays again.
+pub struct ProgressiveSession<'a, I: Integrator, M: IntegratorManager<I>> {
+    renderer: Renderer,
+    scene: &'a mut Scene,
+    materials: &'a MaterialPool,
+    // Changes to the scene that are applied before the next pass:
+    updates: Vec<SceneUpdate>,
+    camera: PerspectiveCamera,
+    filter: PixelFilter,
+    integrator_manager: M,
+    film: Film,
+    preview: Option<PreviewIntegratorManager>,
+    preview_film: Film,
+    // Whether the preview of the current film was rendered:
+    has_preview: bool,
+    first_hits: Option<FirstHitCache>,
+    // Whether the cache has the first hits of the first pass with the current camera:
+    has_first_hits: bool,
+    num_passes: u32,
+    /// Whether the film hasn't handed out any tiles yet.
+    fresh: bool,
+    cancel: CancellationToken,
+    _integrator: PhantomData<I>,
+}
+
+impl<'a, I: Integrator, M: IntegratorManager<I>> ProgressiveSession<'a, I, M> {
+    pub fn new(
+        renderer: Renderer,
+        scene: &'a mut Scene,
+        materials: &'a MaterialPool,
+        camera: PerspectiveCamera,
+        filter: PixelFilter,
+        int_param: M::InitParam,
+    ) -> Self {
+        let film = renderer.new_film();
+        let preview_film = renderer.new_film();
+        ProgressiveSession {
+            renderer,
+            scene,
+            materials,
+            updates: Vec::new(),
+            camera,
+            filter,
+            integrator_manager: M::new(int_param),
+            film,
+            preview: Some(PreviewIntegratorManager::new(PreviewParam::default())),
+            preview_film,
+            has_preview: false,
+            first_hits: None,
+            has_first_hits: false,
+            num_passes: 0,
+            fresh: true,
+            cancel: CancellationToken::new(),
+            _integrator: PhantomData,
+        }
+    }
+
+    /// Sets the parameters of the preview, or disables it with `None`.
+    pub fn with_preview(mut self, param: Option<PreviewParam>) -> Self {
+        self.preview = param.map(PreviewIntegratorManager::new);
+        self
+    }
+
+    /// Enables (or disables) caching the first hits of the camera rays of the first pass. The first pass
+    /// after a restart that only updated materials or lights (see `apply_updates`) then reuses them, which
+    /// is faster and gives the same image. The cache takes a couple of bytes per pixel.
+    pub fn with_first_hit_cache(mut self, enabled: bool) -> Self {
+        self.first_hits = if enabled {
+            Some(FirstHitCache::new(&self.film))
+        } else {
+            None
+        };
+        self.has_first_hits = false;
+        self
+    }
+
+    /// Renders one pass (every tile once, with a single sample per pixel) and accumulates it into the film.
+    /// The first step after (re)starting renders the preview instead (if there is one), which doesn't count
+    /// as a pass.
+    pub fn step(&mut self) -> Result<(), RenderError> {
+        if let (false, Some(preview)) = (self.has_preview, &self.preview) {
+            self.preview_film.reset();
+            let result = self.renderer.render_into(
+                &self.preview_film,
+                &*self.scene,
+                self.materials,
+                &self.camera,
+                self.filter,
+                preview,
+                1,
+                None,
+                None,
+                &self.cancel,
+            );
+            if result.is_err() {
+                self.cancel = CancellationToken::new();
+            } else {
+                self.has_preview = true;
+            }
+            return result;
+        }
+
+        // Every pass after the first one gets different seeds, so the passes converge (and only the camera
+        // rays of the first pass can be cached):
+        let first_pass = self.fresh;
+        if !self.fresh {
+            self.film.next_pass();
+        }
+        self.fresh = false;
+        let first_hits = match &self.first_hits {
+            Some(cache) if first_pass && self.has_first_hits => Some(FirstHits::Reuse(cache)),
+            Some(cache) if first_pass => Some(FirstHits::Record(cache)),
+            _ => None,
+        };
+
+        let result = self.renderer.render_into(
+            &self.film,
+            &*self.scene,
+            self.materials,
+            &self.camera,
+            self.filter,
+            &self.integrator_manager,
+            1,
+            first_hits,
+            None,
+            &self.cancel,
+        );
+
+        // A panic cancels the token, which shouldn't affect the next step:
+        if result.is_err() {
+            self.cancel = CancellationToken::new();
+        } else {
+            self.num_passes += 1;
+            // Tiles that weren't rendered don't have any records:
+            if let Some(FirstHits::Record(_)) = first_hits {
+                self.has_first_hits = !self.cancel.is_cancelled();
+            }
+        }
+        result
+    }
+
+    /// Returns the image accumulated so far (without affecting the session), or the preview if there
+    /// are no passes yet.
+    pub fn snapshot(&self, tone_map: impl Fn(Color) -> ImagePixel) -> ImageBuffer {
+        if (self.num_passes == 0) && self.has_preview {
+            self.preview_film.to_image_buffer(tone_map)
+        } else {
+            self.film.to_image_buffer(tone_map)
+        }
+    }
+
+    /// Clears the film and starts accumulating from scratch using the new camera.
+    pub fn restart(&mut self, camera: &PerspectiveCamera) {
+        self.camera = *camera;
+        self.reset_film();
+        // The camera rays are different now:
+        self.has_first_hits = false;
+        if let Some(cache) = &mut self.first_hits {
+            cache.clear();
+        }
+    }
+
+    fn reset_film(&mut self) {
+        self.film.reset();
+        self.num_passes = 0;
+        self.fresh = true;
+        self.has_preview = false;
+    }
+
+    /// Queues a change to the scene. It only takes effect once `apply_updates` is called.
+    pub fn queue_update(&mut self, update: SceneUpdate) {
+        self.updates.push(update);
+    }
+
+    /// Applies all of the queued updates to the scene and, if there were any, clears the film (as the
+    /// passes so far no longer match the scene). Returns whether anything was applied. Should be called
+    /// between passes.
+    ///
+    /// If one of the updates fails, the updates before it have still been applied (and the film is still
+    /// cleared), while the remaining updates are dropped.
+    pub fn apply_updates(&mut self) -> PrismResult<bool> {
+        if self.updates.is_empty() {
+            return Ok(false);
+        }
+        let updates = std::mem::replace(&mut self.updates, Vec::new());
+        self.reset_film();
+        for update in updates {
+            self.scene.apply_update(update)?;
+        }
+        Ok(true)
+    }
+
+    /// The number of passes that were accumulated so far.
+    pub fn num_passes(&self) -> u32 {
+        self.num_passes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::GaussianFilter;
+    use crate::geometry::sphere::Sphere;
+    use crate::integrator::path_tracer::{
+        PathTracerIntegrator, PathTracerIntegratorManager, PathTracerParam,
+    };
+    use crate::light::light_picker::LightPickerKind;
+    use crate::light::{point::Point, DirectLightParam};
+    use crate::sampler::{SampleSequence, SamplerMode};
+    use crate::scene::{SceneGeom, SceneLight, ScenePrim};
+    use crate::shading::material::matte::Matte;
+    use crate::shading::material::DEFAULT_MATERIAL_ID;
+    use crate::threading::pixel_order::PixelOrder;
+    use crate::threading::RenderParam;
+    use crate::transform::Transf;
+    use pmath::bbox::BBox3;
+    use pmath::vector::{Vec2, Vec3};
+    use std::sync::Arc;
+
+    // A sphere on a (much larger) ground sphere, lit by a point light:
+    fn sphere_scene(materials: &MaterialPool) -> Scene {
+        let sphere = SceneGeom::new_material(
+            Arc::new(Sphere::new(1.0)),
+            materials.get_shared_material(DEFAULT_MATERIAL_ID),
+            Transf::new_identity(),
+        );
+        let ground = SceneGeom::new_material(
+            Arc::new(Sphere::new(100.0)),
+            materials.get_shared_material(DEFAULT_MATERIAL_ID),
+            Transf::new_translate(Vec3 {
+                x: 0.0,
+                y: -101.0,
+                z: 0.0,
+            }),
+        );
+        let sphere = Arc::new(sphere);
+        let prims: Vec<Arc<dyn ScenePrim>> = vec![sphere.clone(), Arc::new(ground)];
+        let light = Point::new(
+            Vec3 {
+                x: 0.0,
+                y: 4.0,
+                z: -4.0,
+            },
+            Color::white().scale(50.0),
+        );
+        let mut scene = Scene::new(
+            &prims,
+            vec![SceneLight::new(Arc::new(light), Transf::new_identity())],
+        );
+        // Only the sphere picks up changes to the material:
+        scene.register_material_user(DEFAULT_MATERIAL_ID, sphere);
+        scene
+    }
+
+    fn render_param(num_pixel_samples: u32) -> RenderParam {
+        RenderParam {
+            num_pixel_samples,
+            num_threads: 2,
+            sample_seed: 5,
+            blue_noise_count: 0,
+            sample_sequence: SampleSequence::Pmj,
+            sampler_mode: SamplerMode::Scrambled,
+            blue_noise_dither: false,
+            res: Vec2 { x: 32, y: 24 },
+            tile_size: Some(8),
+            pixel_order: PixelOrder::Scanline,
+            light_picker: LightPickerKind::All,
+            sample_dump: None,
+            importance_map: None,
+            split_buffers: false,
+            supersample: 1,
+        }
+    }
+
+    fn camera() -> PerspectiveCamera {
+        PerspectiveCamera::frame_bbox(
+            BBox3 {
+                pmin: Vec3 {
+                    x: -1.0,
+                    y: -1.0,
+                    z: -1.0,
+                },
+                pmax: Vec3 {
+                    x: 1.0,
+                    y: 1.0,
+                    z: 1.0,
+                },
+            },
+            45.0,
+            Vec3 {
+                x: 0.0,
+                y: -0.3,
+                z: 1.0,
+            },
+            Vec2 { x: 32, y: 24 },
+        )
+    }
+
+    fn filter() -> PixelFilter {
+        PixelFilter::new(&GaussianFilter::new(Vec2 { x: 1.0, y: 1.0 }, 0.5))
+    }
+
+    fn int_param() -> PathTracerParam {
+        PathTracerParam {
+            max_bounce: 4,
+            direct_light: DirectLightParam::default(),
+            aovs: false,
+            max_direct: None,
+            max_indirect: None,
+        }
+    }
+
+    fn to_pixel(c: Color) -> ImagePixel {
+        ImagePixel::from_color(c)
+    }
+
+    // The sum of the red and of the green channel of every pixel:
+    fn red_and_green(image: &ImageBuffer) -> (f64, f64) {
+        let res = image.res();
+        let (mut red, mut green) = (0.0, 0.0);
+        for y in 0..res.y {
+            for x in 0..res.x {
+                let pixel = image.get_pixel(Vec2 { x, y });
+                red += pixel.r;
+                green += pixel.g;
+            }
+        }
+        (red, green)
+    }
+
+    fn mean(image: &ImageBuffer) -> f64 {
+        let res = image.res();
+        let mut sum = 0.0;
+        for y in 0..res.y {
+            for x in 0..res.x {
+                let pixel = image.get_pixel(Vec2 { x, y });
+                sum += pixel.r + pixel.g + pixel.b;
+            }
+        }
+        sum / ((3 * res.x * res.y) as f64)
+    }
+
+    #[test]
+    fn passes_converge_like_a_single_render() {
+        const NUM_PASSES: u32 = 16;
+        let materials = MaterialPool::new();
+        let mut scene = sphere_scene(&materials);
+        // The reference uses a different seed, as its first samples would be the same as the ones of the
+        // first pass (which would make the error of the first pass look a lot smaller than it is):
+        let mut reference_param = render_param(NUM_PASSES);
+        reference_param.sample_seed += 1;
+        let reference = Renderer::new(reference_param)
+            .unwrap()
+            .render::<PathTracerIntegrator, PathTracerIntegratorManager>(
+                &scene,
+                &materials,
+                &camera(),
+                filter(),
+                int_param(),
+                None,
+                None,
+            )
+            .unwrap();
+        let reference = reference.film.to_image_buffer(to_pixel);
+
+        let mut session =
+            ProgressiveSession::<PathTracerIntegrator, PathTracerIntegratorManager>::new(
+                Renderer::new(render_param(1)).unwrap(),
+                &mut scene,
+                &materials,
+                camera(),
+                filter(),
+                int_param(),
+            )
+            .with_preview(None);
+        session.step().unwrap();
+        let single_pass = session.snapshot(to_pixel);
+        for _ in 1..NUM_PASSES {
+            session.step().unwrap();
+        }
+        assert_eq!(session.num_passes(), NUM_PASSES);
+        let accumulated = session.snapshot(to_pixel);
+
+        // If every pass used the same samples, the error wouldn't go down at all:
+        let single_error = single_pass.diff(&reference).unwrap().mean_abs_error;
+        let error = accumulated.diff(&reference).unwrap().mean_abs_error;
+        assert!(error < 0.75 * single_error, "{} {}", error, single_error);
+        let (mean, reference_mean) = (mean(&accumulated), mean(&reference));
+        assert!(
+            (mean - reference_mean).abs() < 0.03 * reference_mean,
+            "{} {}",
+            mean,
+            reference_mean
+        );
+    }
+
+    #[test]
+    fn material_updates_show_up_in_the_next_pass() {
+        let materials = MaterialPool::new();
+        let mut scene = sphere_scene(&materials);
+        let mut session =
+            ProgressiveSession::<PathTracerIntegrator, PathTracerIntegratorManager>::new(
+                Renderer::new(render_param(1)).unwrap(),
+                &mut scene,
+                &materials,
+                camera(),
+                filter(),
+                int_param(),
+            )
+            .with_preview(None);
+        session.step().unwrap();
+        // Everything is gray and the light is white:
+        let (red, green) = red_and_green(&session.snapshot(to_pixel));
+        assert!(red > 0.0);
+        assert!((red - green).abs() < 1e-6 * red, "{} {}", red, green);
+
+        let red_matte = Matte::new(Color {
+            r: 0.8,
+            g: 0.1,
+            b: 0.1,
+        });
+        session.queue_update(SceneUpdate::Material {
+            material_id: DEFAULT_MATERIAL_ID,
+            material: Arc::new(red_matte),
+        });
+        // Queued updates don't do anything until they are applied:
+        session.step().unwrap();
+        assert_eq!(session.num_passes(), 2);
+        let (red, green) = red_and_green(&session.snapshot(to_pixel));
+        assert!((red - green).abs() < 1e-6 * red, "{} {}", red, green);
+
+        assert!(session.apply_updates().unwrap());
+        assert_eq!(session.num_passes(), 0);
+        assert!(!session.apply_updates().unwrap());
+        session.step().unwrap();
+        let (red, green) = red_and_green(&session.snapshot(to_pixel));
+        assert!(red > 1.5 * green, "{} {}", red, green);
+    }
+}
@@ -1,13 +1,72 @@
-use crate::film::TILE_SIZE;
+use crate::Scalar;
+use once_cell::sync::Lazy;
+use pmath::numbers::Float;
 use pmath::vector::Vec2;
 use pmj::{self, Sample};
-use rand::SeedableRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 use rand_pcg::Pcg32;
 
+/// Refers to an array of samples for the current pixel sample (see `Sampler::next_1d_array` and
+/// `Sampler::next_2d_array`).
+#[derive(Clone, Copy, Debug)]
+pub struct SampleArray {
+    index: usize,
+    len: usize,
+}
+
+impl SampleArray {
+    pub fn len(self) -> usize {
+        self.len
+    }
+}
+
+/// How the samples of the different pixels are decorrelated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SamplerMode {
+    /// Every pixel draws its own (scrambled) sequence from the tables.
+    Scrambled,
+    /// The positions on the film (see `Sampler::sample_film_pos`) of all pixels in a tile come from a
+    /// single sequence, shifted (modulo 1) by a random offset per pixel (a Cranley-Patterson rotation).
+    /// This keeps the stratification of the sequence while still decorrelating the pixels. Every other
+    /// dimension is the same as with `Scrambled`.
+    Shifted,
+}
+
+/// The sequence every pixel draws its samples from (before they are decorrelated, see `SamplerMode`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleSequence {
+    /// Progressive multi-jittered (0,2) sequences, looked up in precomputed tables.
+    Pmj,
+    /// Independent uniform samples (mostly useful as a baseline to compare the others with).
+    Random,
+    /// The first two dimensions of the Sobol' sequence, with a random digital shift for every pixel.
+    Sobol,
+    /// One jittered sample in every cell of a grid with (at least) as many cells as there are samples per
+    /// pixel. Samples past the end of the grid start over with a different permutation of the cells.
+    Stratified,
+}
+
+#[derive(Clone)]
 pub struct Sampler<'a> {
     pattern: u32, // The "pattern" is basically the pixel that the sample is being drawn for
     sample: u32,  // The sample is the index of the current sample for a specific pixel
     tables: &'a SampleTables, // All of the samples belong to this
+    // The pattern of the first pixel of the tile, and the position of the current pixel in the image
+    // (see `set_pixel_pos`):
+    tile_pattern: u32,
+    pixel_pos: Option<Vec2<u32>>,
+
+    // The index of the current pixel sample (path) in the pixel, used to generate the arrays:
+    pixel_sample: u32,
+    // The bounce of the current path (see `start_bounce`):
+    bounce: u32,
+    // The arrays requested before rendering, which are stratified again for every pixel sample, and the
+    // next one of each that will be handed out:
+    arrays_1d: Vec<Vec<f64>>,
+    arrays_2d: Vec<Vec<Vec2<f64>>>,
+    next_1d: usize,
+    next_2d: usize,
 }
 
 impl<'a> Sampler<'a> {
@@ -16,26 +75,295 @@ impl<'a> Sampler<'a> {
             pattern: 0,
             sample: 0,
             tables,
+            tile_pattern: 0,
+            pixel_pos: None,
+            pixel_sample: 0,
+            bounce: 0,
+            arrays_1d: Vec::new(),
+            arrays_2d: Vec::new(),
+            next_1d: 0,
+            next_2d: 0,
         }
     }
 
-    pub fn sample(&mut self) -> Vec2<f64> {
-        let res = self.tables.sample(self.pattern, self.sample);
+    pub fn sample(&mut self) -> Vec2<Scalar> {
+        let res = match (&self.tables.dither_mask, self.pixel_pos) {
+            // Every pixel of the tile draws the same sequence, shifted by the mask:
+            (Some(mask), Some(pixel_pos)) => {
+                let u = self.tables.sample(self.tile_pattern, self.sample);
+                mask.shift(u, pixel_pos, self.tile_pattern, self.sample)
+            }
+            _ => self.tables.sample(self.pattern, self.sample),
+        };
         self.sample += 1;
-        res
+        to_scalar_2d(res)
+    }
+
+    /// Returns the sample used for the position on the film, which has to be called after
+    /// `start_pixel_sample` (see `SamplerMode`).
+    pub fn sample_film_pos(&mut self) -> Vec2<Scalar> {
+        match (self.tables.mode, self.pixel_pos) {
+            (SamplerMode::Shifted, Some(pixel_pos)) => {
+                let u = self
+                    .tables
+                    .sample(self.tile_pattern, self.pixel_sample.saturating_sub(1));
+                let hash = SampleTables::hash_to_random_u32(pixel_pos.y, self.tile_pattern);
+                let hash = SampleTables::hash_to_random_u32(pixel_pos.x, hash);
+                let shift = Vec2 {
+                    x: SampleTables::hash_to_random_f32(hash, 0x3c6ef372) as f64,
+                    y: SampleTables::hash_to_random_f32(hash, 0xa54ff53a) as f64,
+                };
+                let wrap = |v: f64| (v - v.floor()).min(0.999990);
+                to_scalar_2d(Vec2 {
+                    x: wrap(u.x + shift.x),
+                    y: wrap(u.y + shift.y),
+                })
+            }
+            _ => self.sample(),
+        }
     }
 
     // Need to call when going to the next pixel
     pub fn next_pixel(&mut self) {
         self.pattern += 1;
         self.sample = 0;
+        self.pixel_sample = 0;
+        self.pixel_pos = None;
     }
 
-    // Need to call when going to next tile
-    pub fn start_tile(&mut self, tile_index: u32) {
-        self.pattern = tile_index * (TILE_SIZE as u32);
+    // Need to call when going to next tile. The seed should be unique to the tile (and the pass),
+    // and `tile_area` is the maximum number of pixels in a tile.
+    pub fn start_tile(&mut self, tile_seed: u32, tile_area: u32) {
+        self.pattern = tile_seed.wrapping_mul(tile_area);
+        self.tile_pattern = self.pattern;
         self.sample = 0;
+        self.pixel_sample = 0;
+        self.pixel_pos = None;
+    }
+
+    // Same as calling `start_tile` followed by `next_pixel` `pixel` times.
+    pub fn start_pixel(&mut self, tile_seed: u32, tile_area: u32, pixel: u32) {
+        self.tile_pattern = tile_seed.wrapping_mul(tile_area);
+        self.pattern = self.tile_pattern.wrapping_add(pixel);
+        self.sample = 0;
+        self.pixel_sample = 0;
+        self.pixel_pos = None;
+    }
+
+    /// Sets the position of the current pixel in the image (after `start_tile`, `next_pixel`, or
+    /// `start_pixel`). This is only used if the tables dither the samples (see
+    /// `SampleTables::with_dither`) or shift them (see `SamplerMode`).
+    pub fn set_pixel_pos(&mut self, pixel_pos: Vec2<usize>) {
+        self.pixel_pos = Some(Vec2 {
+            x: pixel_pos.x as u32,
+            y: pixel_pos.y as u32,
+        });
     }
+
+    /// The position of the current pixel in the image (see `set_pixel_pos`).
+    pub fn pixel_pos(&self) -> Option<Vec2<u32>> {
+        self.pixel_pos
+    }
+
+    /// Requests an array of `len` stratified samples for every pixel sample. This has to be called
+    /// before rendering, and the arrays are handed out by `next_1d_array` in the order they were requested.
+    pub fn request_1d_array(&mut self, len: usize) {
+        self.arrays_1d.push(vec![0.0; len]);
+    }
+
+    /// Same as `request_1d_array`, but for 2d samples (see `next_2d_array`).
+    pub fn request_2d_array(&mut self, len: usize) {
+        self.arrays_2d.push(vec![Vec2 { x: 0.0, y: 0.0 }; len]);
+    }
+
+    // Need to call before every pixel sample (path), generates new arrays.
+    pub fn start_pixel_sample(&mut self) {
+        if !self.arrays_1d.is_empty() || !self.arrays_2d.is_empty() {
+            let mut rng = Pcg32::new(
+                ((self.pattern as u64) << 32) | (self.pixel_sample as u64),
+                0xa02bdbf7bb3c0a7,
+            );
+            for array in self.arrays_1d.iter_mut() {
+                stratify_1d(array, &mut rng);
+            }
+            for array in self.arrays_2d.iter_mut() {
+                stratify_2d(array, &mut rng);
+            }
+        }
+        self.pixel_sample += 1;
+        self.bounce = 0;
+        self.next_1d = 0;
+        self.next_2d = 0;
+    }
+
+    /// Sets the bounce of the current path (0 for the point the camera ray hits), which selects the
+    /// samples `sample_dimension` returns. Starting a pixel sample goes back to bounce 0.
+    pub fn start_bounce(&mut self, bounce: u32) {
+        self.bounce = bounce;
+    }
+
+    /// Returns the sample of a fixed dimension for the current pixel sample and bounce. The samples
+    /// `sample` returns are used for whatever dimension asks next, so which ones end up being used for a
+    /// dimension depends on how many samples the earlier paths of the pixel took (and they can alias with
+    /// those of other dimensions). Here every dimension (at every bounce) has its own sequence instead,
+    /// which is stratified over the paths of the pixel. `dimension` can be any id, as long as different
+    /// uses don't share one. The samples aren't dithered or shifted (see `SamplerMode`).
+    pub fn sample_dimension(&self, dimension: u32) -> Vec2<Scalar> {
+        to_scalar_2d(self.sample_dimension_f64(dimension))
+    }
+
+    fn sample_dimension_f64(&self, dimension: u32) -> Vec2<f64> {
+        let dimension = SampleTables::hash_to_random_u32(self.bounce, dimension);
+        let pattern = SampleTables::hash_to_random_u32(self.pattern, dimension);
+        self.tables
+            .sample(pattern, self.pixel_sample.saturating_sub(1))
+    }
+
+    /// Returns a sample (of the dimension, see `sample_dimension`) for picking one of `len` options by
+    /// splitting [0, 1) into `len` strata. The strata are shuffled with a different permutation for every
+    /// pixel (and bounce), so that neighboring pixels don't go through the options in the same order,
+    /// while the options a pixel picks stay stratified over its paths. The sample keeps its position in
+    /// its stratum, so it is still uniformly distributed.
+    pub fn sample_choice(&self, dimension: u32, len: u32) -> Scalar {
+        let u = self.sample_dimension_f64(dimension).x;
+        if len <= 1 {
+            return to_scalar(u);
+        }
+        let scaled = u * (len as f64);
+        let stratum = (scaled as u32).min(len - 1);
+        let offset = scaled - (stratum as f64);
+        let seed = SampleTables::hash_to_random_u32(self.pattern, !dimension);
+        let seed = SampleTables::hash_to_random_u32(self.bounce, seed);
+        to_scalar(((permute(stratum, len, seed) as f64) + offset) / (len as f64))
+    }
+
+    /// Returns the next of the requested 1d arrays for the current pixel sample, or `None` if all of them
+    /// were already handed out (in which case `sample` should be used instead).
+    pub fn next_1d_array(&mut self) -> Option<SampleArray> {
+        let index = self.next_1d;
+        let array = self.arrays_1d.get(index)?;
+        self.next_1d += 1;
+        Some(SampleArray {
+            index,
+            len: array.len(),
+        })
+    }
+
+    /// Same as `next_1d_array`, but for the requested 2d arrays.
+    pub fn next_2d_array(&mut self) -> Option<SampleArray> {
+        let index = self.next_2d;
+        let array = self.arrays_2d.get(index)?;
+        self.next_2d += 1;
+        Some(SampleArray {
+            index,
+            len: array.len(),
+        })
+    }
+
+    /// Returns the `i`th sample of an array returned by `next_1d_array`.
+    pub fn array_1d(&self, array: SampleArray, i: usize) -> Scalar {
+        to_scalar(self.arrays_1d[array.index][i])
+    }
+
+    /// Returns the `i`th sample of an array returned by `next_2d_array`.
+    pub fn array_2d(&self, array: SampleArray, i: usize) -> Vec2<Scalar> {
+        to_scalar_2d(self.arrays_2d[array.index][i])
+    }
+}
+
+/// The samples are generated in `f64` and handed out as `Scalar`, which mustn't round them up to 1.
+fn to_scalar(u: f64) -> Scalar {
+    Scalar::from_f64(u).min(Scalar::ONE_MINUS_EPS)
+}
+
+fn to_scalar_2d(u: Vec2<f64>) -> Vec2<Scalar> {
+    Vec2 {
+        x: to_scalar(u.x),
+        y: to_scalar(u.y),
+    }
+}
+
+/// A permutation of [0, `len`) for every seed, which maps `index` to its new position without having to
+/// store the permutation ("Correlated Multi-Jittered Sampling", Kensler 2013).
+fn permute(index: u32, len: u32, seed: u32) -> u32 {
+    // The mask covers the next power of two, values outside of [0, len) are permuted again:
+    let mut mask = len - 1;
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+    let mut i = index;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & mask) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & mask) >> 1;
+        i = i.wrapping_mul(1 | (seed >> 27));
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & mask) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= mask;
+        i ^= i >> 5;
+        if i < len {
+            break;
+        }
+    }
+    (i + seed % len) % len
+}
+
+/// Maps the bits of a random integer to [0, 1).
+fn to_unit(bits: u32) -> f64 {
+    (bits as f64) * (1.0 / 4294967296.0)
+}
+
+/// Fills the array with one jittered sample per stratum (in random order).
+fn stratify_1d(array: &mut [f64], rng: &mut Pcg32) {
+    let inv_len = 1.0 / (array.len() as f64);
+    for (i, u) in array.iter_mut().enumerate() {
+        *u = ((i as f64) + rng.gen::<f64>()) * inv_len;
+    }
+    array.shuffle(rng);
+}
+
+/// Fills the array with jittered samples on a grid if the length is a square number, or with a latin
+/// hypercube otherwise (in random order).
+fn stratify_2d(array: &mut [Vec2<f64>], rng: &mut Pcg32) {
+    let len = array.len();
+    let inv_len = 1.0 / (len as f64);
+    let dim = (len as f64).sqrt().round() as usize;
+    if dim * dim == len {
+        let inv_dim = 1.0 / (dim as f64);
+        for (i, u) in array.iter_mut().enumerate() {
+            *u = Vec2 {
+                x: (((i % dim) as f64) + rng.gen::<f64>()) * inv_dim,
+                y: (((i / dim) as f64) + rng.gen::<f64>()) * inv_dim,
+            };
+        }
+    } else {
+        for (i, u) in array.iter_mut().enumerate() {
+            *u = Vec2 {
+                x: ((i as f64) + rng.gen::<f64>()) * inv_len,
+                y: ((i as f64) + rng.gen::<f64>()) * inv_len,
+            };
+        }
+        // Decorrelate the dimensions:
+        for i in (1..len).rev() {
+            let j = rng.gen_range(0, i + 1);
+            let y = array[i].y;
+            array[i].y = array[j].y;
+            array[j].y = y;
+        }
+    }
+    array.shuffle(rng);
 }
 
 /// The `Sampler` is no longer bound to each thread. Instead, each thread will receive a reference to a single
@@ -43,6 +371,13 @@ impl<'a> Sampler<'a> {
 /// a table lookup)
 pub struct SampleTables {
     samples: Vec<Sample>,
+    dither_mask: Option<&'static BlueNoiseMask>,
+    mode: SamplerMode,
+    sequence: SampleSequence,
+    // Scrambles the sequences that aren't looked up in the tables:
+    seed: u32,
+    // The number of cells along each axis of the grid of `SampleSequence::Stratified`:
+    grid_dim: u32,
 }
 
 // These values are taken from RenderMan's RixRNG implementation.
@@ -61,10 +396,100 @@ impl SampleTables {
             let mut table = pmj::generate(NUM_SAMPLES_PER_TABLE, blue_noise_retry_count, &mut rand);
             samples.append(&mut table);
         }
-        SampleTables { samples }
+        SampleTables {
+            samples,
+            dither_mask: None,
+            mode: SamplerMode::Scrambled,
+            sequence: SampleSequence::Pmj,
+            seed: (init_seed ^ (init_seed >> 32)) as u32,
+            grid_dim: 1,
+        }
+    }
+
+    /// Same as `new`, but the samples are drawn from `sequence` (`SampleSequence::Pmj` is the same as
+    /// `new` without blue noise). The grid of `SampleSequence::Stratified` is made for
+    /// `num_pixel_samples` samples per pixel. Only the PMJ sequences need the (slow to generate) tables.
+    pub fn new_sequence(sequence: SampleSequence, init_seed: u64, num_pixel_samples: u32) -> Self {
+        if sequence == SampleSequence::Pmj {
+            return Self::new(init_seed, 0);
+        }
+        SampleTables {
+            samples: Vec::new(),
+            dither_mask: None,
+            mode: SamplerMode::Scrambled,
+            sequence,
+            seed: (init_seed ^ (init_seed >> 32)) as u32,
+            grid_dim: (num_pixel_samples.max(1) as f64).sqrt().ceil() as u32,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: SamplerMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Dithers the samples of neighboring pixels with a blue noise mask ("Blue-noise Dithered Sampling",
+    /// Georgiev and Fajardo 2016): every pixel of a tile draws the same sequence, which is shifted
+    /// (modulo 1) by the mask. This way the error of neighboring pixels is different, so that at low
+    /// sample counts the noise is of a high frequency (which is a lot less visible). The pixels have to
+    /// tell the sampler where they are (see `Sampler::set_pixel_pos`).
+    pub fn with_dither(mut self) -> Self {
+        self.dither_mask = Some(&BLUE_NOISE_MASK);
+        self
     }
 
     fn sample(&self, pattern: u32, sample: u32) -> Vec2<f64> {
+        match self.sequence {
+            SampleSequence::Pmj => self.sample_pmj(pattern, sample),
+            SampleSequence::Random => self.sample_random(pattern, sample),
+            SampleSequence::Sobol => self.sample_sobol(pattern, sample),
+            SampleSequence::Stratified => self.sample_stratified(pattern, sample),
+        }
+    }
+
+    fn sample_random(&self, pattern: u32, sample: u32) -> Vec2<f64> {
+        let scramble = Self::hash_to_random_u32(pattern, self.seed);
+        Vec2 {
+            x: to_unit(Self::hash_to_random_u32(sample, scramble ^ 0x51633e2d)),
+            y: to_unit(Self::hash_to_random_u32(sample, scramble ^ 0x68bc21eb)),
+        }
+    }
+
+    /// The Sobol' (0,2) sequence with a random digital shift ("Efficient Multidimensional Sampling",
+    /// Kollig and Keller 2002).
+    fn sample_sobol(&self, pattern: u32, sample: u32) -> Vec2<f64> {
+        let scramble = Self::hash_to_random_u32(pattern, self.seed);
+        let mut y = Self::hash_to_random_u32(scramble, 0x967a889b);
+        let mut v = 1 << 31;
+        let mut i = sample;
+        while i != 0 {
+            if i & 1 != 0 {
+                y ^= v;
+            }
+            i >>= 1;
+            v ^= v >> 1;
+        }
+        Vec2 {
+            x: to_unit(sample.reverse_bits() ^ Self::hash_to_random_u32(scramble, 0x02e5be93)),
+            y: to_unit(y),
+        }
+    }
+
+    fn sample_stratified(&self, pattern: u32, sample: u32) -> Vec2<f64> {
+        let num_cells = self.grid_dim * self.grid_dim;
+        // Every pass over the grid visits the cells in a different order:
+        let pass = sample / num_cells;
+        let seed = Self::hash_to_random_u32(pass, Self::hash_to_random_u32(pattern, self.seed));
+        let cell = permute(sample % num_cells, num_cells, seed);
+        let jitter = |scramble: u32| to_unit(Self::hash_to_random_u32(sample, seed ^ scramble));
+        let inv_dim = 1.0 / (self.grid_dim as f64);
+        Vec2 {
+            x: (((cell % self.grid_dim) as f64) + jitter(0x51633e2d)) * inv_dim,
+            y: (((cell / self.grid_dim) as f64) + jitter(0x68bc21eb)) * inv_dim,
+        }
+    }
+
+    fn sample_pmj(&self, pattern: u32, sample: u32) -> Vec2<f64> {
         const TOTAL_NUM_SAMPLES: usize = NUM_TABLES * NUM_SAMPLES_PER_TABLE;
 
         // We ran out of samples:
@@ -173,3 +598,324 @@ impl SampleTables {
         f32::from_bits(i) - 1.0
     }
 }
+
+/// Generating the mask takes a while, so it's only done once (the first time it's needed). It doesn't
+/// depend on the seed of the render, as every sample looks it up at a different offset anyway.
+static BLUE_NOISE_MASK: Lazy<BlueNoiseMask> = Lazy::new(|| BlueNoiseMask::new(0x9e3779b97f4a7c15));
+
+/// A tileable blue noise mask: a value in [0, 1) for every pixel, so that pixels with similar values are
+/// far apart. The values are uniformly distributed.
+struct BlueNoiseMask {
+    values: Vec<f64>,
+}
+
+impl BlueNoiseMask {
+    const DIM: usize = 64;
+    // The standard deviation of the gaussian used for the energy (in pixels):
+    const SIGMA: f64 = 1.5;
+
+    /// Generates the mask with the void-and-cluster method (Ulichney 1993).
+    fn new(seed: u64) -> Self {
+        const NUM_PIXELS: usize = BlueNoiseMask::DIM * BlueNoiseMask::DIM;
+        let mut rng = Pcg32::seed_from_u64(seed);
+
+        // The contribution of a pixel to the energy of the pixels around it (on a torus):
+        let mut kernel = vec![0.0; NUM_PIXELS];
+        for y in 0..Self::DIM {
+            for x in 0..Self::DIM {
+                let dx = x.min(Self::DIM - x) as f64;
+                let dy = y.min(Self::DIM - y) as f64;
+                kernel[y * Self::DIM + x] =
+                    (-(dx * dx + dy * dy) / (2.0 * Self::SIGMA * Self::SIGMA)).exp();
+            }
+        }
+
+        struct Pattern<'k> {
+            ones: Vec<bool>,
+            energy: Vec<f64>,
+            kernel: &'k [f64],
+        }
+
+        impl<'k> Pattern<'k> {
+            fn set(&mut self, index: usize, one: bool) {
+                let dim = BlueNoiseMask::DIM;
+                let (px, py) = (index % dim, index / dim);
+                let sign = if one { 1.0 } else { -1.0 };
+                self.ones[index] = one;
+                for y in 0..dim {
+                    for x in 0..dim {
+                        let k = ((y + dim - py) % dim) * dim + ((x + dim - px) % dim);
+                        self.energy[y * dim + x] += sign * self.kernel[k];
+                    }
+                }
+            }
+
+            // The one with the highest energy:
+            fn tightest_cluster(&self) -> usize {
+                (0..self.ones.len())
+                    .filter(|&i| self.ones[i])
+                    .max_by(|&a, &b| self.energy[a].partial_cmp(&self.energy[b]).unwrap())
+                    .unwrap()
+            }
+
+            // The zero with the lowest energy:
+            fn largest_void(&self) -> usize {
+                (0..self.ones.len())
+                    .filter(|&i| !self.ones[i])
+                    .min_by(|&a, &b| self.energy[a].partial_cmp(&self.energy[b]).unwrap())
+                    .unwrap()
+            }
+        }
+
+        // Start with a random pattern that is spread out until it no longer changes:
+        let num_initial = NUM_PIXELS / 10;
+        let mut initial = Pattern {
+            ones: vec![false; NUM_PIXELS],
+            energy: vec![0.0; NUM_PIXELS],
+            kernel: &kernel,
+        };
+        let mut indices: Vec<usize> = (0..NUM_PIXELS).collect();
+        indices.shuffle(&mut rng);
+        for &i in &indices[..num_initial] {
+            initial.set(i, true);
+        }
+        loop {
+            let cluster = initial.tightest_cluster();
+            initial.set(cluster, false);
+            let void = initial.largest_void();
+            initial.set(void, true);
+            if void == cluster {
+                break;
+            }
+        }
+
+        // The ones of the initial pattern are ranked by removing the tightest clusters, and everything
+        // else by filling the largest voids:
+        let mut ranks = vec![0; NUM_PIXELS];
+        let mut pattern = Pattern {
+            ones: initial.ones.clone(),
+            energy: initial.energy.clone(),
+            kernel: &kernel,
+        };
+        for rank in (0..num_initial).rev() {
+            let cluster = pattern.tightest_cluster();
+            pattern.set(cluster, false);
+            ranks[cluster] = rank;
+        }
+        for rank in num_initial..NUM_PIXELS {
+            let void = initial.largest_void();
+            initial.set(void, true);
+            ranks[void] = rank;
+        }
+
+        BlueNoiseMask {
+            values: ranks
+                .into_iter()
+                .map(|rank| ((rank as f64) + 0.5) / (NUM_PIXELS as f64))
+                .collect(),
+        }
+    }
+
+    /// Shifts the `sample`th sample of a pixel by the mask. Every sample (and every tile) looks the mask
+    /// up at a different (random) offset, so that the shifts of the different dimensions aren't correlated.
+    fn shift(
+        &self,
+        u: Vec2<f64>,
+        pixel_pos: Vec2<u32>,
+        tile_pattern: u32,
+        sample: u32,
+    ) -> Vec2<f64> {
+        let dim = Self::DIM as u32;
+        let lookup = |scramble: u32| {
+            let offset = SampleTables::hash_to_random_u32(sample, tile_pattern ^ scramble);
+            let x = pixel_pos.x.wrapping_add(offset) % dim;
+            let y = pixel_pos.y.wrapping_add(offset >> 16) % dim;
+            self.values[(y * dim + x) as usize]
+        };
+        let wrap = |v: f64| {
+            let v = v - v.floor();
+            // Make sure that rounding doesn't take it to 1:
+            v.min(0.999990)
+        };
+        Vec2 {
+            x: wrap(u.x + lookup(0x3c6ef372)),
+            y: wrap(u.y + lookup(0xa54ff53a)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blue_noise_ranks_are_a_permutation() {
+        const NUM_PIXELS: usize = BlueNoiseMask::DIM * BlueNoiseMask::DIM;
+        let mut ranks: Vec<usize> = BLUE_NOISE_MASK
+            .values
+            .iter()
+            .map(|&v| (v * (NUM_PIXELS as f64)) as usize)
+            .collect();
+        ranks.sort_unstable();
+        assert_eq!(ranks, (0..NUM_PIXELS).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn blue_noise_shift_stays_in_the_unit_square() {
+        let mut rng = Pcg32::seed_from_u64(7);
+        for &u in &[0.0, 0.5, 0.999_999, 1.0 - f64::EPSILON / 2.0] {
+            for _ in 0..1000 {
+                let pixel_pos = Vec2 {
+                    x: rng.gen(),
+                    y: rng.gen(),
+                };
+                let shifted =
+                    BLUE_NOISE_MASK.shift(Vec2 { x: u, y: u }, pixel_pos, rng.gen(), rng.gen());
+                assert!(shifted.x >= 0.0 && shifted.x < 1.0);
+                assert!(shifted.y >= 0.0 && shifted.y < 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn permute_is_a_bijection() {
+        for &len in &[1, 2, 3, 7, 16, 100, 1000] {
+            for &seed in &[0, 1, 0x68bc21eb, u32::MAX] {
+                let mut permuted: Vec<u32> = (0..len).map(|i| permute(i, len, seed)).collect();
+                permuted.sort_unstable();
+                assert_eq!(permuted, (0..len).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    #[test]
+    fn sample_choice_is_stratified() {
+        let tables = SampleTables::new(3, 0);
+        let mut sampler = Sampler::new(&tables);
+        for pixel in 0..16 {
+            for &len in &[2, 4, 8, 16] {
+                // Every option is picked once by the first `len` paths of the pixel (the sequences are
+                // stratified for powers of two):
+                let mut picked = vec![0; len as usize];
+                sampler.start_pixel(5, 16, pixel);
+                for _ in 0..len {
+                    sampler.start_pixel_sample();
+                    let u = sampler.sample_choice(2, len);
+                    assert!(u >= 0.0 && u < 1.0);
+                    picked[(u * (len as Scalar)) as usize] += 1;
+                }
+                assert!(picked.iter().all(|&n| n == 1), "{:?}", picked);
+            }
+        }
+    }
+
+    #[test]
+    fn arrays_are_handed_out_once_per_pixel_sample() {
+        let tables = SampleTables::new(3, 0);
+        let mut sampler = Sampler::new(&tables);
+        sampler.request_1d_array(4);
+        sampler.request_2d_array(4);
+        sampler.request_2d_array(6);
+        sampler.start_pixel(5, 16, 3);
+
+        let strata = |mut strata: Vec<usize>| {
+            strata.sort_unstable();
+            strata
+        };
+        let mut previous = None;
+        for _ in 0..3 {
+            sampler.start_pixel_sample();
+            let array_1d = sampler.next_1d_array().unwrap();
+            assert!(sampler.next_1d_array().is_none());
+            let grid = sampler.next_2d_array().unwrap();
+            let latin = sampler.next_2d_array().unwrap();
+            assert!(sampler.next_2d_array().is_none());
+            assert_eq!((array_1d.len(), grid.len(), latin.len()), (4, 4, 6));
+
+            // Every stratum gets exactly one sample:
+            let us: Vec<Scalar> = (0..4).map(|i| sampler.array_1d(array_1d, i)).collect();
+            assert_eq!(
+                strata(us.iter().map(|&u| (u * 4.0) as usize).collect()),
+                vec![0, 1, 2, 3]
+            );
+            let cells = (0..4).map(|i| {
+                let u = sampler.array_2d(grid, i);
+                ((u.y * 2.0) as usize) * 2 + ((u.x * 2.0) as usize)
+            });
+            assert_eq!(strata(cells.collect()), vec![0, 1, 2, 3]);
+            let xs = (0..6).map(|i| (sampler.array_2d(latin, i).x * 6.0) as usize);
+            let ys = (0..6).map(|i| (sampler.array_2d(latin, i).y * 6.0) as usize);
+            assert_eq!(strata(xs.collect()), (0..6).collect::<Vec<_>>());
+            assert_eq!(strata(ys.collect()), (0..6).collect::<Vec<_>>());
+
+            // Every pixel sample gets new samples:
+            assert_ne!(previous, Some(us.clone()));
+            previous = Some(us);
+        }
+    }
+
+    /// The cells of a `dim` by `dim` grid the first samples of a pixel fall into (sorted).
+    fn cells(tables: &SampleTables, pattern: u32, dim: u32) -> Vec<u32> {
+        let mut cells: Vec<u32> = (0..dim * dim)
+            .map(|i| {
+                let u = tables.sample(pattern, i);
+                assert!(
+                    u.x >= 0.0 && u.x < 1.0 && u.y >= 0.0 && u.y < 1.0,
+                    "{:?}",
+                    u
+                );
+                ((u.y * (dim as f64)) as u32) * dim + ((u.x * (dim as f64)) as u32)
+            })
+            .collect();
+        cells.sort_unstable();
+        cells
+    }
+
+    #[test]
+    fn sobol_and_stratified_samples_cover_every_cell() {
+        let sobol = SampleTables::new_sequence(SampleSequence::Sobol, 3, 16);
+        let stratified = SampleTables::new_sequence(SampleSequence::Stratified, 3, 16);
+        for pattern in 0..64 {
+            // The first 4^k samples of the Sobol' sequence have one sample in every cell of a 2^k by 2^k grid:
+            for &dim in &[1, 2, 4, 8] {
+                assert_eq!(
+                    cells(&sobol, pattern, dim),
+                    (0..dim * dim).collect::<Vec<_>>()
+                );
+            }
+            assert_eq!(cells(&stratified, pattern, 4), (0..16).collect::<Vec<_>>());
+        }
+
+        // Every pass over the grid covers it again, in a different order:
+        let stratified = &stratified;
+        let pass = |pass: u32| (0..16).map(move |i| stratified.sample(0, pass * 16 + i));
+        let mut next: Vec<u32> = pass(1)
+            .map(|u| ((u.y * 4.0) as u32) * 4 + ((u.x * 4.0) as u32))
+            .collect();
+        assert_ne!(pass(0).collect::<Vec<_>>(), pass(1).collect::<Vec<_>>());
+        next.sort_unstable();
+        assert_eq!(next, (0..16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn random_samples_are_uniform() {
+        const NUM_SAMPLES: u32 = 1 << 16;
+        let tables = SampleTables::new_sequence(SampleSequence::Random, 5, 1);
+        let mut histogram = [0u32; 16];
+        for i in 0..NUM_SAMPLES {
+            let u = tables.sample(i % 7, i);
+            assert!(
+                u.x >= 0.0 && u.x < 1.0 && u.y >= 0.0 && u.y < 1.0,
+                "{:?}",
+                u
+            );
+            histogram[((u.y * 4.0) as usize) * 4 + ((u.x * 4.0) as usize)] += 1;
+        }
+        // The expected count is 4096 per cell, with a standard deviation of about 62:
+        for &count in histogram.iter() {
+            assert!((3700..4500).contains(&count), "{:?}", histogram);
+        }
+        // Different pixels get different samples:
+        assert_ne!(tables.sample(0, 0), tables.sample(1, 0));
+    }
+}
@@ -1,19 +1,30 @@
-use crate::bvh::{BVHObject, BVH};
+use crate::bvh::{BVHObject, BVHStats, TraversalControl, BVH};
+use crate::error::PrismResult;
 use crate::geometry::Geometry;
 use crate::interaction::{GeomIntr, Interaction};
 use crate::light::Light;
-use crate::shading::material::Material;
-use crate::transform::Transf;
+use crate::shading::material::{Material, MaterialPool, DEFAULT_MATERIAL_ID};
+use crate::spectrum::Color;
+use crate::transform::graph::{NodeId, TransformGraph};
+use crate::transform::{AnimatedTransf, Transf};
+use crate::Scalar;
 use pmath::bbox::BBox3;
+use pmath::numbers::Float;
 use pmath::ray::Ray;
-use std::sync::{Arc, Mutex};
+use pmath::vector::{Vec2, Vec3};
+use serde::Deserialize;
+use simple_error::bail;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
 
 //
 // ScenePrim
 //
 
 /// A public trait that represents a scene primitive
-trait ScenePrim {
+pub trait ScenePrim: Send + Sync {
     fn get_transf(&self) -> Transf;
     fn get_light(&self) -> Option<Arc<dyn Light>>;
 
@@ -22,8 +33,44 @@ trait ScenePrim {
     fn get_prim_at(&self, i: usize) -> &dyn ScenePrim;
 
     fn get_bbox(&self) -> BBox3<f64>;
-    fn intersect(&self, ray: Ray<f64>) -> Option<Interaction>;
-    fn intersect_test(&self, ray: Ray<f64>) -> bool;
+    /// Intersects the ray with the primitive, skipping the primitive `exclude` of a geometry (see
+    /// `Scene::intersect_excluding`).
+    fn intersect(&self, ray: Ray<f64>, exclude: Option<PrimRef>) -> Option<Interaction>;
+    fn intersect_test(&self, ray: Ray<f64>, exclude: Option<PrimRef>) -> bool;
+
+    /// Multiplies `tr` by the transmittance of every surface the ray passes through (except for the
+    /// geometry `exclude`). Returns `TraversalControl::Stop` once `tr` is black.
+    fn transmittance(
+        &self,
+        ray: Ray<f64>,
+        exclude: Option<GeomRef>,
+        tr: &mut Color,
+    ) -> TraversalControl;
+
+    /// Calls `f` for every geometry instance in the primitive (including itself).
+    fn for_each_geom(&self, f: &mut dyn FnMut(&Arc<dyn Geometry>));
+
+    /// Adds every `SceneGeom` in the primitive (including itself) to `geoms`, so that the scene can look
+    /// up the geometry an interaction belongs to (see `Scene::get_material`).
+    fn collect_scene_geoms(self: Arc<Self>, geoms: &mut HashMap<GeomRef, Arc<SceneGeom>>);
+
+    /// Constructs the interaction of a recorded hit (see `HitRecord`) with the geometry `hit.geom`,
+    /// which has to be part of this primitive, without intersecting the ray again.
+    fn interaction_at(&self, ray: Ray<f64>, hit: &SurfaceHit) -> Option<Interaction>;
+}
+
+/// Maps every geometry in the primitives (including the ones in instances) to the primitive that
+/// contains it.
+fn prims_by_geom(prims: &[Arc<dyn ScenePrim>]) -> HashMap<GeomRef, Arc<dyn ScenePrim>> {
+    let mut prims_by_geom = HashMap::new();
+    for prim in prims {
+        let mut geoms = HashMap::new();
+        prim.clone().collect_scene_geoms(&mut geoms);
+        for geom_ref in geoms.keys() {
+            prims_by_geom.insert(*geom_ref, prim.clone());
+        }
+    }
+    prims_by_geom
 }
 
 //
@@ -37,60 +84,91 @@ pub struct SceneLight {
 }
 
 impl SceneLight {
+    pub fn new(light: Arc<dyn Light>, transf: Transf) -> Self {
+        SceneLight { light, transf }
+    }
+
     /// Returns the light as a reference and the transformation of the light (in scene space).
     pub fn get_light_transf(&self) -> (&dyn Light, Transf) {
         (self.light.as_ref(), self.transf)
     }
 }
 
-/// Stores all of the lights in a scene.
-pub struct SceneLightRegistrar {
-    light_list: Mutex<Vec<SceneLight>>,
-}
+//
+// SceneGeom
+//
 
-impl SceneLightRegistrar {
-    /// Constructs a new SceneLightRegistrar.
-    fn new() -> Self {
-        SceneLightRegistrar {
-            light_list: Mutex::new(Vec::new()),
-        }
-    }
+/// Identifies a `SceneGeom`, so one can tell which geometry an interaction belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct GeomRef(u32);
 
-    /// Adds a light to the registrar, returning the index of said light.
-    fn register_light(&self, light: &Arc<dyn Light>, transf: Transf) -> usize {
-        let light_list = self.light_list.lock().unwrap();
-        let index = light_list.len();
-        light_list.push(SceneLight {
-            light: light.clone(),
-            transf,
-        });
-        index
+impl GeomRef {
+    /// Returns a `GeomRef` that hasn't been handed out before.
+    fn next() -> Self {
+        static NEXT_GEOM_REF: AtomicU32 = AtomicU32::new(0);
+        GeomRef(NEXT_GEOM_REF.fetch_add(1, Ordering::Relaxed))
     }
+}
+
+/// Identifies a single primitive (the triangle of a mesh) of a `SceneGeom`, so that a ray that leaves the
+/// primitive can skip it. Instances share the `GeomRef` of their geometry, so the primitive is skipped in
+/// every instance (which only matters if the ray would hit the same triangle of another instance next).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PrimRef {
+    pub geom: GeomRef,
+    pub prim: u32,
+}
 
-    /// Updates the transform of the light in the registrar by multiplying on the left side.
-    fn update_ligth(&self, index: usize, transf: Transf) {
-        // Acquire a lock for the vector:
-        let light_list = self.light_list.lock().unwrap();
-        let scene_light = light_list.get_mut(index).unwrap();
-        scene_light.transf = transf * scene_light.transf;
+impl PrimRef {
+    /// The primitive that was hit (`None` if the interaction doesn't belong to the scene).
+    pub fn from_interaction(interaction: &Interaction) -> Option<Self> {
+        interaction.geom.map(|geom| PrimRef {
+            geom,
+            prim: interaction.prim,
+        })
     }
 }
 
-static SCENE_LIGHT_REGISTRAR: SceneLightRegistrar = SceneLightRegistrar::new();
+/// A compact record of the closest intersection of a ray with the scene, which is enough to construct
+/// the interaction again (see `Scene::interaction_from_hit`) as long as the geometry doesn't change.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HitRecord {
+    // `None` if the ray didn't hit anything:
+    hit: Option<SurfaceHit>,
+}
 
-// Lights are a little different from everything. I need to be able to access all light sources in a scene quickly
-// in a single list. This is to make light sampling easier. So, how do we do that? Well, we need to keep some sort of
-// "light stack" of transformations. As this could happen to multiple objects outside of regular lights, I'll attach
-// support for this to the ScenePrimitive itself as well.
+/// Where a ray hit the surface of a geometry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SurfaceHit {
+    pub geom: GeomRef,
+    /// The primitive of the geometry that was hit (see `Interaction::prim`).
+    pub prim: u32,
+    /// Where along the ray the hit is.
+    pub t: f64,
+    /// The barycentric coordinates of the hit on the primitive (see `Interaction::bary`).
+    pub bary: Vec2<f64>,
+}
 
-//
-// SceneGeom
-//
+impl HitRecord {
+    pub fn new(interaction: &Option<Interaction>) -> Self {
+        HitRecord {
+            hit: interaction.as_ref().and_then(|int| {
+                int.geom.map(|geom| SurfaceHit {
+                    geom,
+                    prim: int.prim,
+                    t: int.t.to_f64(),
+                    bary: int.bary.cast(),
+                })
+            }),
+        }
+    }
+}
 
 /// A `SceneGeometry` can either be a light source (e.g. a mesh light) or an object with a material.
 
 enum SceneGeomType {
-    Material(Arc<dyn Material>),
+    // Can be replaced through `Scene::update_material` between renders:
+    Material(RwLock<Arc<dyn Material>>),
     Light(Arc<dyn Light>),
 }
 
@@ -99,6 +177,16 @@ pub struct SceneGeom {
     geom: Arc<dyn Geometry>,
     scene_geom_type: SceneGeomType,
     transf: Transf, // geom to world
+    // If the geometry moves while the shutter is open (`transf` is then the start of the motion):
+    motion: Option<AnimatedTransf>,
+    geom_ref: GeomRef,
+    // The materials of the attribute ids of the geometry (ids past the end use the main material). These
+    // can be replaced through `Scene::update_material` as well:
+    attribute_materials: Vec<RwLock<Arc<dyn Material>>>,
+
+    // These can be changed through the scene after it was built:
+    visible: AtomicBool,
+    material_override: RwLock<Option<Arc<dyn Material>>>,
 }
 
 impl SceneGeom {
@@ -110,8 +198,13 @@ impl SceneGeom {
     ) -> Self {
         SceneGeom {
             geom,
-            scene_geom_type: SceneGeomType::Material(material),
+            scene_geom_type: SceneGeomType::Material(RwLock::new(material)),
             transf,
+            motion: None,
+            geom_ref: GeomRef::next(),
+            attribute_materials: Vec::new(),
+            visible: AtomicBool::new(true),
+            material_override: RwLock::new(None),
         }
     }
 
@@ -121,6 +214,87 @@ impl SceneGeom {
             geom,
             scene_geom_type: SceneGeomType::Light(light),
             transf,
+            motion: None,
+            geom_ref: GeomRef::next(),
+            attribute_materials: Vec::new(),
+            visible: AtomicBool::new(true),
+            material_override: RwLock::new(None),
+        }
+    }
+
+    /// Makes the geometry move from its transformation when the shutter opens to `end_transf` when the
+    /// shutter closes.
+    pub fn with_motion(mut self, end_transf: Transf) -> Self {
+        self.motion = Some(AnimatedTransf::new(self.transf, end_transf));
+        self
+    }
+
+    /// Gives every attribute id of the geometry its own material (the material at the index of the id).
+    /// Attribute ids without one use the material of the geometry. Light sources don't have materials, so
+    /// this doesn't change them.
+    pub fn with_attribute_materials(mut self, materials: Vec<Arc<dyn Material>>) -> Self {
+        self.attribute_materials = materials.into_iter().map(RwLock::new).collect();
+        self
+    }
+
+    /// Places the geometry at a node of a transform graph (which has to be up to date), including any
+    /// motion of the node or its ancestors.
+    pub fn with_node(mut self, graph: &TransformGraph, node: NodeId) -> Self {
+        let motion = graph.get_world_motion(node);
+        self.transf = motion.get_start();
+        self.motion = if graph.is_animated(node) {
+            Some(motion)
+        } else {
+            None
+        };
+        self
+    }
+
+    pub fn geom_ref(&self) -> GeomRef {
+        self.geom_ref
+    }
+
+    /// Returns the geom to world transformation at the given time.
+    fn transf_at(&self, time: f64) -> Transf {
+        match self.motion {
+            Some(motion) => motion.interpolate(time),
+            None => self.transf,
+        }
+    }
+
+    /// The primitive of this geometry that has to be skipped.
+    fn excluded_prim(&self, exclude: Option<PrimRef>) -> Option<u32> {
+        exclude
+            .filter(|exclude| exclude.geom == self.geom_ref)
+            .map(|exclude| exclude.prim)
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible.load(Ordering::Relaxed)
+    }
+
+    /// Returns the material of the part of the geometry with the attribute id (taking any override into
+    /// account), or `None` if the geometry is a light source.
+    pub fn get_material(&self, attribute_id: u32) -> Option<Arc<dyn Material>> {
+        if let Some(material) = self.material_override.read().unwrap().as_ref() {
+            return Some(material.clone());
+        }
+        let material = match self.material_slot(Some(attribute_id)) {
+            Some(material) => material,
+            None => self.material_slot(None)?,
+        };
+        let material = material.read().unwrap().clone();
+        Some(material)
+    }
+
+    /// The material of the attribute id (`None` being the main material), if the geometry has one.
+    fn material_slot(&self, attribute_id: Option<u32>) -> Option<&RwLock<Arc<dyn Material>>> {
+        match (&self.scene_geom_type, attribute_id) {
+            (SceneGeomType::Light(_), _) => None,
+            (SceneGeomType::Material(material), None) => Some(material),
+            (SceneGeomType::Material(_), Some(attribute_id)) => {
+                self.attribute_materials.get(attribute_id as usize)
+            }
         }
     }
 }
@@ -131,8 +305,8 @@ impl ScenePrim for SceneGeom {
     }
 
     fn get_light(&self) -> Option<Arc<dyn Light>> {
-        match self.scene_geom_type {
-            SceneGeomType::Light(light) => Some(light),
+        match &self.scene_geom_type {
+            SceneGeomType::Light(light) => Some(light.clone()),
             _ => None,
         }
     }
@@ -146,19 +320,92 @@ impl ScenePrim for SceneGeom {
     }
 
     fn get_bbox(&self) -> BBox3<f64> {
-        self.transf.bbox(self.geom.get_bbox())
+        // Moving geometry has to be bounded over the entire time the shutter is open:
+        match self.motion {
+            Some(motion) => motion.bound_motion(self.geom.get_bbox()),
+            None => self.transf.bbox(self.geom.get_bbox()),
+        }
+    }
+
+    fn intersect(&self, ray: Ray<f64>, exclude: Option<PrimRef>) -> Option<Interaction> {
+        if !self.is_visible() {
+            return None;
+        }
+        let transf = self.transf_at(ray.time);
+        let geom_space_ray = transf.inverse().ray(ray);
+        self.geom
+            .intersect_excluding(geom_space_ray, self.excluded_prim(exclude))
+            .map(|o| Interaction {
+                geom: Some(self.geom_ref),
+                ..transf.interaction(o)
+            })
     }
 
-    fn intersect(&self, ray: Ray<f64>) -> Option<Interaction> {
-        let geom_space_ray = self.transf.inverse().ray(ray);
+    fn intersect_test(&self, ray: Ray<f64>, exclude: Option<PrimRef>) -> bool {
+        if !self.is_visible() {
+            return false;
+        }
+        let geom_space_ray = self.transf_at(ray.time).inverse().ray(ray);
         self.geom
-            .intersect(geom_space_ray)
-            .map(|o| self.transf.interaction(o))
+            .intersect_test_excluding(geom_space_ray, self.excluded_prim(exclude))
     }
 
-    fn intersect_test(&self, ray: Ray<f64>) -> bool {
-        let geom_space_ray = self.transf.inverse().ray(ray);
-        self.geom.intersect_test(geom_space_ray)
+    fn transmittance(
+        &self,
+        ray: Ray<f64>,
+        exclude: Option<GeomRef>,
+        tr: &mut Color,
+    ) -> TraversalControl {
+        if !self.is_visible() || (exclude == Some(self.geom_ref)) {
+            return TraversalControl::Continue;
+        }
+        // Lights are always opaque:
+        if let SceneGeomType::Light(_) = self.scene_geom_type {
+            if !self.intersect_test(ray, None) {
+                return TraversalControl::Continue;
+            }
+            *tr = Color::black();
+            return TraversalControl::Stop;
+        }
+
+        let transf = self.transf_at(ray.time);
+        let geom_space_ray = transf.inverse().ray(ray);
+        self.geom.intersect_all(geom_space_ray, &mut |interaction| {
+            let interaction = Interaction {
+                geom: Some(self.geom_ref),
+                ..transf.interaction(interaction)
+            };
+            if let Some(material) = self.get_material(interaction.attribute_id) {
+                *tr = *tr * material.transmittance(&interaction);
+            }
+            if tr.is_black() {
+                TraversalControl::Stop
+            } else {
+                TraversalControl::Continue
+            }
+        })
+    }
+
+    fn for_each_geom(&self, f: &mut dyn FnMut(&Arc<dyn Geometry>)) {
+        f(&self.geom);
+    }
+
+    fn collect_scene_geoms(self: Arc<Self>, geoms: &mut HashMap<GeomRef, Arc<SceneGeom>>) {
+        geoms.insert(self.geom_ref, self);
+    }
+
+    fn interaction_at(&self, ray: Ray<f64>, hit: &SurfaceHit) -> Option<Interaction> {
+        if (hit.geom != self.geom_ref) || !self.is_visible() {
+            return None;
+        }
+        let transf = self.transf_at(ray.time);
+        let geom_space_ray = transf.inverse().ray(ray);
+        self.geom
+            .interaction_at(geom_space_ray, hit.prim, hit.t, hit.bary)
+            .map(|o| Interaction {
+                geom: Some(self.geom_ref),
+                ..transf.interaction(o)
+            })
     }
 }
 
@@ -168,7 +415,51 @@ impl ScenePrim for SceneGeom {
 
 pub struct SceneBVH {
     bvh: BVH<Arc<dyn ScenePrim>>,
+    // The primitive every geometry of the instance belongs to (see `interaction_at`):
+    prims_by_geom: HashMap<GeomRef, Arc<dyn ScenePrim>>,
     transf: Transf,
+    // If the instance moves while the shutter is open (`transf` is then the start of the motion):
+    motion: Option<AnimatedTransf>,
+}
+
+impl SceneBVH {
+    /// Constructs an instance of the primitives.
+    pub fn new(prims: &[Arc<dyn ScenePrim>], transf: Transf) -> Self {
+        SceneBVH {
+            bvh: BVH::new(prims, 1, &None),
+            prims_by_geom: prims_by_geom(prims),
+            transf,
+            motion: None,
+        }
+    }
+
+    /// Makes the instance move from its transformation when the shutter opens to `end_transf` when the
+    /// shutter closes.
+    pub fn with_motion(mut self, end_transf: Transf) -> Self {
+        self.motion = Some(AnimatedTransf::new(self.transf, end_transf));
+        self
+    }
+
+    /// Places the instance at a node of a transform graph (which has to be up to date), including any
+    /// motion of the node or its ancestors.
+    pub fn with_node(mut self, graph: &TransformGraph, node: NodeId) -> Self {
+        let motion = graph.get_world_motion(node);
+        self.transf = motion.get_start();
+        self.motion = if graph.is_animated(node) {
+            Some(motion)
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Returns the instance to world transformation at the given time.
+    fn transf_at(&self, time: f64) -> Transf {
+        match self.motion {
+            Some(motion) => motion.interpolate(time),
+            None => self.transf,
+        }
+    }
 }
 
 impl ScenePrim for SceneBVH {
@@ -189,19 +480,56 @@ impl ScenePrim for SceneBVH {
     }
 
     fn get_bbox(&self) -> BBox3<f64> {
-        self.transf.bbox(self.bvh.get_bbox())
+        match self.motion {
+            Some(motion) => motion.bound_motion(self.bvh.get_bbox()),
+            None => self.transf.bbox(self.bvh.get_bbox()),
+        }
     }
 
-    fn intersect(&self, ray: Ray<f64>) -> Option<Interaction> {
-        let geom_space_ray = self.transf.inverse().ray(ray);
+    fn intersect(&self, ray: Ray<f64>, exclude: Option<PrimRef>) -> Option<Interaction> {
+        let transf = self.transf_at(ray.time);
+        let geom_space_ray = transf.inverse().ray(ray);
         self.bvh
-            .intersect(geom_space_ray, &())
-            .map(|o| self.transf.interaction(o))
+            .intersect(geom_space_ray, &exclude)
+            .map(|o| transf.interaction(o))
     }
 
-    fn intersect_test(&self, ray: Ray<f64>) -> bool {
-        let geom_space_ray = self.transf.inverse().ray(ray);
-        self.bvh.intersect_test(geom_space_ray, &())
+    fn intersect_test(&self, ray: Ray<f64>, exclude: Option<PrimRef>) -> bool {
+        let geom_space_ray = self.transf_at(ray.time).inverse().ray(ray);
+        self.bvh.intersect_test(geom_space_ray, &exclude)
+    }
+
+    fn transmittance(
+        &self,
+        ray: Ray<f64>,
+        exclude: Option<GeomRef>,
+        tr: &mut Color,
+    ) -> TraversalControl {
+        let geom_space_ray = self.transf_at(ray.time).inverse().ray(ray);
+        self.bvh.visit_all(geom_space_ray, |prim| {
+            prim.transmittance(geom_space_ray, exclude, tr)
+        })
+    }
+
+    fn for_each_geom(&self, f: &mut dyn FnMut(&Arc<dyn Geometry>)) {
+        for prim in self.bvh.get_objects() {
+            prim.for_each_geom(f);
+        }
+    }
+
+    fn collect_scene_geoms(self: Arc<Self>, geoms: &mut HashMap<GeomRef, Arc<SceneGeom>>) {
+        for prim in self.bvh.get_objects() {
+            prim.clone().collect_scene_geoms(geoms);
+        }
+    }
+
+    fn interaction_at(&self, ray: Ray<f64>, hit: &SurfaceHit) -> Option<Interaction> {
+        let prim = self.prims_by_geom.get(&hit.geom)?;
+        let transf = self.transf_at(ray.time);
+        let geom_space_ray = transf.inverse().ray(ray);
+        prim.as_ref()
+            .interaction_at(geom_space_ray, hit)
+            .map(|o| transf.interaction(o))
     }
 }
 
@@ -209,36 +537,37 @@ impl ScenePrim for SceneBVH {
 // The "SceneBVHObject" is just an Arc<dyn ScenePrim>:
 
 impl BVHObject for Arc<dyn ScenePrim> {
-    type UserData = ();
+    // The primitive the ray has to skip:
+    type UserData = Option<PrimRef>;
 
     fn get_bbox(&self, _: &Self::UserData) -> BBox3<f64> {
         self.as_ref().get_bbox()
     }
 
-    fn intersect_test(&self, ray: Ray<f64>, _: &Self::UserData) -> bool {
-        self.as_ref().intersect_test(ray)
+    fn intersect_test(&self, ray: Ray<f64>, exclude: &Self::UserData) -> bool {
+        self.as_ref().intersect_test(ray, *exclude)
     }
 
-    fn intersect(&self, ray: Ray<f64>, _: &Self::UserData) -> Option<Interaction> {
-        self.as_ref().intersect(ray)
+    fn intersect(&self, ray: Ray<f64>, exclude: &Self::UserData) -> Option<Interaction> {
+        self.as_ref().intersect(ray, *exclude)
     }
 }
 
 impl ScenePrim for Arc<dyn ScenePrim> {
     fn get_transf(&self) -> Transf {
-        self.get_transf()
+        self.as_ref().get_transf()
     }
 
     fn get_light(&self) -> Option<Arc<dyn Light>> {
-        self.get_light()
+        self.as_ref().get_light()
     }
 
     fn num_prims(&self) -> usize {
-        self.num_prims()
+        self.as_ref().num_prims()
     }
 
     fn get_prim_at(&self, i: usize) -> &dyn ScenePrim {
-        self.get_prim_at(i)
+        self.as_ref().get_prim_at(i)
     }
 
     fn get_bbox(&self) -> BBox3<f64> {
@@ -246,11 +575,1394 @@ impl ScenePrim for Arc<dyn ScenePrim> {
         self.as_ref().get_bbox()
     }
 
-    fn intersect_test(&self, ray: Ray<f64>) -> bool {
-        self.as_ref().intersect_test(ray)
+    fn intersect_test(&self, ray: Ray<f64>, exclude: Option<PrimRef>) -> bool {
+        self.as_ref().intersect_test(ray, exclude)
+    }
+
+    fn intersect(&self, ray: Ray<f64>, exclude: Option<PrimRef>) -> Option<Interaction> {
+        self.as_ref().intersect(ray, exclude)
+    }
+
+    fn transmittance(
+        &self,
+        ray: Ray<f64>,
+        exclude: Option<GeomRef>,
+        tr: &mut Color,
+    ) -> TraversalControl {
+        self.as_ref().transmittance(ray, exclude, tr)
+    }
+
+    fn for_each_geom(&self, f: &mut dyn FnMut(&Arc<dyn Geometry>)) {
+        self.as_ref().for_each_geom(f)
+    }
+
+    fn collect_scene_geoms(self: Arc<Self>, geoms: &mut HashMap<GeomRef, Arc<SceneGeom>>) {
+        (*self).clone().collect_scene_geoms(geoms)
+    }
+
+    fn interaction_at(&self, ray: Ray<f64>, hit: &SurfaceHit) -> Option<Interaction> {
+        self.as_ref().interaction_at(ray, hit)
+    }
+}
+
+//
+// Accelerator
+//
+
+/// Finds the top level primitives of a scene that a ray hits. The scene only goes through this trait, so
+/// that other acceleration structures can be used in place of the BVH (see `Scene::with_accelerator`).
+/// Instances and motion are handled by the primitives themselves (see `SceneBVH`).
+pub trait Accelerator: Send + Sync {
+    /// The primitives the accelerator was built over.
+    fn get_prims(&self) -> &[Arc<dyn ScenePrim>];
+    /// The bounding box of all of the primitives.
+    fn get_bbox(&self) -> BBox3<f64>;
+
+    /// Returns the closest intersection of the ray, skipping the primitive `exclude` (see
+    /// `Scene::intersect_excluding`).
+    fn intersect(&self, ray: Ray<f64>, exclude: Option<PrimRef>) -> Option<Interaction>;
+    fn intersect_test(&self, ray: Ray<f64>, exclude: Option<PrimRef>) -> bool;
+    /// Visits every primitive the ray may pass through (in any order) until `f` returns
+    /// `TraversalControl::Stop` (see `ScenePrim::transmittance`).
+    fn visit_all(&self, ray: Ray<f64>, f: &mut dyn FnMut(&dyn ScenePrim) -> TraversalControl);
+
+    /// The bounding box and depth of every node of the accelerator, if it has any (see
+    /// `Scene::bvh_node_bounds`).
+    fn node_bounds(&self) -> Vec<(u32, BBox3<f64>)> {
+        Vec::new()
+    }
+
+    /// Statistics of the structure, if it's a BVH.
+    fn stats(&self) -> Option<BVHStats> {
+        None
+    }
+}
+
+impl Accelerator for BVH<Arc<dyn ScenePrim>> {
+    fn get_prims(&self) -> &[Arc<dyn ScenePrim>] {
+        self.get_objects()
+    }
+
+    fn get_bbox(&self) -> BBox3<f64> {
+        self.get_bbox()
+    }
+
+    fn intersect(&self, ray: Ray<f64>, exclude: Option<PrimRef>) -> Option<Interaction> {
+        self.intersect(ray, &exclude)
+    }
+
+    fn intersect_test(&self, ray: Ray<f64>, exclude: Option<PrimRef>) -> bool {
+        self.intersect_test(ray, &exclude)
+    }
+
+    fn visit_all(&self, ray: Ray<f64>, f: &mut dyn FnMut(&dyn ScenePrim) -> TraversalControl) {
+        self.visit_all(ray, |prim| f(prim.as_ref()));
     }
 
-    fn intersect(&self, ray: Ray<f64>) -> Option<Interaction> {
-        self.as_ref().intersect(ray)
+    fn node_bounds(&self) -> Vec<(u32, BBox3<f64>)> {
+        self.node_bounds().collect()
+    }
+
+    fn stats(&self) -> Option<BVHStats> {
+        Some(self.stats())
+    }
+}
+
+/// The simplest possible accelerator: every ray is tested against every top level primitive. This is
+/// only fast enough for a handful of primitives, but it doesn't depend on the BVH at all, which makes
+/// it useful for checking the BVH against.
+pub struct LinearAccel {
+    prims: Vec<Arc<dyn ScenePrim>>,
+    bbox: BBox3<f64>,
+}
+
+impl LinearAccel {
+    pub fn new(prims: &[Arc<dyn ScenePrim>]) -> Self {
+        let bbox = prims.iter().fold(BBox3::new_initial(), |bbox, prim| {
+            bbox.combine_bnd(ScenePrim::get_bbox(prim.as_ref()))
+        });
+        LinearAccel {
+            prims: prims.to_vec(),
+            bbox,
+        }
+    }
+}
+
+impl Accelerator for LinearAccel {
+    fn get_prims(&self) -> &[Arc<dyn ScenePrim>] {
+        &self.prims
+    }
+
+    fn get_bbox(&self) -> BBox3<f64> {
+        self.bbox
+    }
+
+    fn intersect(&self, ray: Ray<f64>, exclude: Option<PrimRef>) -> Option<Interaction> {
+        let mut ray = ray;
+        let mut hit = None;
+        for prim in &self.prims {
+            // Because the extent is updated, every new hit is a closer hit:
+            if let Some(interaction) = prim.as_ref().intersect(ray, exclude) {
+                ray.t_far = interaction.t.to_f64();
+                hit = Some(interaction);
+            }
+        }
+        hit
+    }
+
+    fn intersect_test(&self, ray: Ray<f64>, exclude: Option<PrimRef>) -> bool {
+        self.prims
+            .iter()
+            .any(|prim| prim.as_ref().intersect_test(ray, exclude))
+    }
+
+    fn visit_all(&self, _: Ray<f64>, f: &mut dyn FnMut(&dyn ScenePrim) -> TraversalControl) {
+        for prim in &self.prims {
+            if f(prim.as_ref()) == TraversalControl::Stop {
+                return;
+            }
+        }
+    }
+}
+
+/// The acceleration structures a scene can be built with (see `Scene::with_accelerator`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum AcceleratorKind {
+    /// A BVH over the top level primitives.
+    Bvh,
+    /// Every ray is tested against every top level primitive (see `LinearAccel`).
+    Linear,
+}
+
+impl Default for AcceleratorKind {
+    fn default() -> Self {
+        AcceleratorKind::Bvh
+    }
+}
+
+impl AcceleratorKind {
+    /// Builds the accelerator over the top level primitives.
+    pub fn build(self, prims: &[Arc<dyn ScenePrim>]) -> Box<dyn Accelerator> {
+        match self {
+            AcceleratorKind::Bvh => Box::new(BVH::new(prims, 1, &None)),
+            AcceleratorKind::Linear => Box::new(LinearAccel::new(prims)),
+        }
+    }
+}
+
+//
+// Scene
+//
+
+/// The scene that gets rendered: all of the top level primitives and all of the lights (in world space).
+///
+/// Geometry can be registered under a name, which allows it to be hidden, have its material overridden,
+/// or be turned into a shadow catcher after the scene was built. None of these change the bounding boxes
+/// of the primitives, so the BVH never has to be rebuilt: hidden geometry just never reports an
+/// intersection.
+pub struct Scene {
+    accel: Box<dyn Accelerator>,
+    // The bounding box of the top level primitives (see `world_bound`), which lights use when sampling:
+    world_bound: BBox3<f64>,
+    lights: Vec<SceneLight>,
+    // Maps geometry to the area light it belongs to:
+    area_lights: HashMap<GeomRef, u32>,
+    // The lights that rays leaving the scene hit:
+    infinite_lights: Vec<u32>,
+    // Every geometry in the scene (including the ones in instances), to find their materials:
+    geoms: HashMap<GeomRef, Arc<SceneGeom>>,
+    // The top level primitive every geometry belongs to (see `interaction_from_hit`):
+    prims_by_geom: HashMap<GeomRef, Arc<dyn ScenePrim>>,
+    objects: HashMap<String, Arc<SceneGeom>>,
+    shadow_catchers: HashSet<GeomRef>,
+    // Whether camera rays that only see the infinite lights are transparent:
+    transparent_environment: bool,
+    // The geometry that uses each material (from a `MaterialPool`), so materials can be updated. The
+    // attribute id is `None` for the main material of the geometry:
+    material_users: HashMap<u32, Vec<(Arc<SceneGeom>, Option<u32>)>>,
+    // The length of a unit of the scene in meters:
+    meters_per_unit: f64,
+}
+
+/// A change to the scene that doesn't require the BVH to be rebuilt.
+#[non_exhaustive]
+pub enum SceneUpdate {
+    Material {
+        material_id: u32,
+        material: Arc<dyn Material>,
+    },
+    Light {
+        light_id: u32,
+        light: Arc<dyn Light>,
+    },
+}
+
+impl Scene {
+    /// Constructs a new scene from the top level primitives and the lights in it.
+    pub fn new(prims: &[Arc<dyn ScenePrim>], lights: Vec<SceneLight>) -> Self {
+        Self::with_accelerator(AcceleratorKind::Bvh.build(prims), lights)
+    }
+
+    /// Same as `new`, but with the top level primitives in an acceleration structure that was already
+    /// built (instead of the default BVH).
+    pub fn with_accelerator(accel: Box<dyn Accelerator>, lights: Vec<SceneLight>) -> Self {
+        let area_lights = lights
+            .iter()
+            .enumerate()
+            .filter_map(|(light_id, scene_light)| {
+                scene_light
+                    .light
+                    .get_geom()
+                    .map(|geom| (geom, light_id as u32))
+            })
+            .collect();
+        let infinite_lights = lights
+            .iter()
+            .enumerate()
+            .filter(|(_, scene_light)| scene_light.light.is_infinite())
+            .map(|(light_id, _)| light_id as u32)
+            .collect();
+        let world_bound = accel
+            .get_prims()
+            .iter()
+            .fold(BBox3::new_initial(), |bbox, prim| {
+                bbox.combine_bnd(ScenePrim::get_bbox(prim.as_ref()))
+            });
+        let mut geoms = HashMap::new();
+        for prim in accel.get_prims() {
+            prim.clone().collect_scene_geoms(&mut geoms);
+        }
+        let prims_by_geom = prims_by_geom(accel.get_prims());
+        Scene {
+            accel,
+            world_bound,
+            lights,
+            area_lights,
+            infinite_lights,
+            geoms,
+            prims_by_geom,
+            objects: HashMap::new(),
+            shadow_catchers: HashSet::new(),
+            transparent_environment: false,
+            material_users: HashMap::new(),
+            meters_per_unit: 1.0,
+        }
+    }
+
+    /// Registers the geometry under `name` so it can be referred to later. The geometry should be
+    /// part of the scene (either directly or as part of an instance).
+    pub fn register_object(&mut self, name: &str, geom: Arc<SceneGeom>) -> PrismResult<GeomRef> {
+        if self.objects.contains_key(name) {
+            bail!("An object named \"{}\" already exists in the scene", name);
+        }
+        let geom_ref = geom.geom_ref();
+        self.objects.insert(name.to_owned(), geom);
+        Ok(geom_ref)
+    }
+
+    /// Returns the material of the geometry at the interaction (see `SceneGeom::get_material`), or `None`
+    /// if the geometry is a light source or isn't part of the scene.
+    pub fn get_material(&self, interaction: &Interaction) -> Option<Arc<dyn Material>> {
+        self.geoms
+            .get(&interaction.geom?)?
+            .get_material(interaction.attribute_id)
+    }
+
+    /// Returns the material the integrators shade the interaction with. Geometry without a material (a
+    /// light source) is shaded with the default material of the pool.
+    pub fn get_material_or_default(
+        &self,
+        interaction: &Interaction,
+        materials: &MaterialPool,
+    ) -> Arc<dyn Material> {
+        self.get_material(interaction)
+            .unwrap_or_else(|| materials.get_shared_material(DEFAULT_MATERIAL_ID))
+    }
+
+    /// Returns the geometry registered under `name`.
+    pub fn get_object(&self, name: &str) -> Option<GeomRef> {
+        self.objects.get(name).map(|geom| geom.geom_ref())
+    }
+
+    fn find_object(&self, name: &str) -> PrismResult<&SceneGeom> {
+        match self.objects.get(name) {
+            Some(geom) => Ok(geom.as_ref()),
+            None => bail!("No object named \"{}\" in the scene", name),
+        }
+    }
+
+    /// Hides (or shows) the object. Hidden objects don't show up in any ray (including shadow rays).
+    pub fn set_visible(&mut self, name: &str, visible: bool) -> PrismResult<()> {
+        self.find_object(name)?
+            .visible
+            .store(visible, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Replaces the material of the object with a material from the pool.
+    pub fn override_material(
+        &mut self,
+        name: &str,
+        materials: &MaterialPool,
+        material_id: u32,
+    ) -> PrismResult<()> {
+        materials.check_id(material_id)?;
+        let material = materials.get_shared_material(material_id);
+        *self.find_object(name)?.material_override.write().unwrap() = Some(material);
+        Ok(())
+    }
+
+    /// A shadow catcher only renders the shadows that fall on it (over a transparent background), which
+    /// is useful for compositing renders onto photographs.
+    pub fn set_shadow_catcher(&mut self, name: &str, shadow_catcher: bool) -> PrismResult<()> {
+        let geom_ref = self.find_object(name)?.geom_ref();
+        if shadow_catcher {
+            self.shadow_catchers.insert(geom_ref);
+        } else {
+            self.shadow_catchers.remove(&geom_ref);
+        }
+        Ok(())
+    }
+
+    /// Records that the geometry uses the material with the given id, so that `update_material` can
+    /// replace it later on.
+    pub fn register_material_user(&mut self, material_id: u32, geom: Arc<SceneGeom>) {
+        self.material_users
+            .entry(material_id)
+            .or_insert_with(Vec::new)
+            .push((geom, None));
+    }
+
+    /// Records that the material of an attribute id of the geometry (see
+    /// `SceneGeom::with_attribute_materials`) is the material with the given id.
+    pub fn register_attribute_material_user(
+        &mut self,
+        material_id: u32,
+        geom: Arc<SceneGeom>,
+        attribute_id: u32,
+    ) {
+        self.material_users
+            .entry(material_id)
+            .or_insert_with(Vec::new)
+            .push((geom, Some(attribute_id)));
+    }
+
+    /// Replaces the material with the given id on every geometry that uses it (see
+    /// `register_material_user`). Material overrides still take precedence. This must not be called
+    /// while rendering.
+    ///
+    /// Area lights are built from the emission of a material when the scene is loaded, so the emission
+    /// of a material can't be changed this way.
+    pub fn update_material(
+        &mut self,
+        material_id: u32,
+        material: Arc<dyn Material>,
+    ) -> PrismResult<()> {
+        let users = match self.material_users.get(&material_id) {
+            Some(users) => users,
+            // Nothing uses the material, so there is nothing to update:
+            None => return Ok(()),
+        };
+        for (geom, attribute_id) in users {
+            if let Some(old_material) = geom.material_slot(*attribute_id) {
+                let mut old_material = old_material.write().unwrap();
+                if old_material.emission() != material.emission() {
+                    bail!(
+                        "Can't change the emission of material {} without reloading the scene",
+                        material_id
+                    );
+                }
+                *old_material = material.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the light with the given id, keeping its transformation. The new light has to belong to
+    /// the same geometry as the old one (if any), and be infinite if the old one was. This must not be
+    /// called while rendering.
+    pub fn update_light(&mut self, light_id: u32, light: Arc<dyn Light>) -> PrismResult<()> {
+        let scene_light = match self.lights.get_mut(light_id as usize) {
+            Some(scene_light) => scene_light,
+            None => bail!("No light with id {} in the scene", light_id),
+        };
+        if scene_light.light.get_geom() != light.get_geom() {
+            bail!(
+                "Light {} can't be moved to different geometry without reloading the scene",
+                light_id
+            );
+        }
+        if scene_light.light.is_infinite() != light.is_infinite() {
+            bail!(
+                "Light {} can't be changed to or from an infinite light without reloading the scene",
+                light_id
+            );
+        }
+        scene_light.light = light;
+        Ok(())
+    }
+
+    pub fn apply_update(&mut self, update: SceneUpdate) -> PrismResult<()> {
+        match update {
+            SceneUpdate::Material {
+                material_id,
+                material,
+            } => self.update_material(material_id, material),
+            SceneUpdate::Light { light_id, light } => self.update_light(light_id, light),
+        }
+    }
+
+    pub fn is_shadow_catcher(&self, geom: GeomRef) -> bool {
+        self.shadow_catchers.contains(&geom)
+    }
+
+    /// Whether the scene has any shadow catchers (and so the image should be saved with alpha).
+    pub fn has_shadow_catchers(&self) -> bool {
+        !self.shadow_catchers.is_empty()
+    }
+
+    /// Sets whether camera rays that don't hit anything are transparent even though they see an infinite
+    /// light (which still contributes its color, so the color is premultiplied by the alpha). This is
+    /// for compositing renders lit by an environment over a backplate.
+    pub fn set_transparent_environment(&mut self, transparent_environment: bool) {
+        self.transparent_environment = transparent_environment;
+    }
+
+    /// Sets the length of a unit of the scene in meters (1 by default). This doesn't change the scene, it
+    /// records what the units of the positions and distances of the scene are.
+    pub fn set_meters_per_unit(&mut self, meters_per_unit: f64) {
+        self.meters_per_unit = meters_per_unit;
+    }
+
+    /// The length of a unit of the scene in meters.
+    pub fn meters_per_unit(&self) -> f64 {
+        self.meters_per_unit
+    }
+
+    /// The coverage (alpha) of camera rays that don't hit anything: 0 unless they see an infinite light
+    /// (that isn't transparent, see `set_transparent_environment`).
+    pub fn background_alpha(&self) -> Scalar {
+        if self.has_infinite_lights() && !self.transparent_environment {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Whether parts of the image can be transparent (and so the image should be saved with alpha).
+    pub fn has_transparent_background(&self) -> bool {
+        self.has_shadow_catchers() || (self.background_alpha() == 0.0)
+    }
+
+    pub fn get_bbox(&self) -> BBox3<f64> {
+        self.accel.get_bbox()
+    }
+
+    /// The union of the bounding boxes of all of the top level primitives (in world space). If the scene
+    /// is empty, the bounding box is empty as well (its minimum is greater than its maximum).
+    pub fn world_bound(&self) -> BBox3<f64> {
+        self.world_bound
+    }
+
+    /// The bounding box (in world space) and depth of every node of the BVH over the top level primitives
+    /// (see `BVH::node_bounds`). Empty if the scene uses an accelerator without nodes.
+    pub fn bvh_node_bounds(&self) -> impl Iterator<Item = (u32, BBox3<f64>)> {
+        self.accel.node_bounds().into_iter()
+    }
+
+    pub fn num_lights(&self) -> usize {
+        self.lights.len()
+    }
+
+    pub fn get_light(&self, light_id: u32) -> &dyn Light {
+        self.lights[light_id as usize].light.as_ref()
+    }
+
+    /// Returns the id of the area light that the geometry belongs to (if it's an area light).
+    pub fn get_area_light(&self, geom: GeomRef) -> Option<u32> {
+        self.area_lights.get(&geom).copied()
+    }
+
+    /// Returns the radiance a ray that leaves the scene sees in the direction `dir` (from every infinite
+    /// light).
+    pub fn escaped_radiance(&self, org: Vec3<Scalar>, dir: Vec3<Scalar>) -> Color {
+        self.infinite_lights
+            .iter()
+            .fold(Color::black(), |color, &light_id| {
+                color + self.get_light(light_id).eval(org, -dir)
+            })
+    }
+
+    /// Whether rays that leave the scene see any light.
+    pub fn has_infinite_lights(&self) -> bool {
+        !self.infinite_lights.is_empty()
+    }
+
+    pub fn intersect(&self, ray: Ray<Scalar>) -> Option<Interaction> {
+        self.accel.intersect(ray.cast(), None)
+    }
+
+    /// Same as `intersect`, but never hits the primitive `exclude`. Rays that leave a surface are offset,
+    /// but can still hit the primitive they start on when it's hit at a grazing angle. Only pass the
+    /// primitive the ray starts on (see `PrimRef::from_interaction`): rays can hit curved primitives
+    /// and other parts of concave geometry again further along.
+    pub fn intersect_excluding(
+        &self,
+        ray: Ray<Scalar>,
+        exclude: Option<PrimRef>,
+    ) -> Option<Interaction> {
+        self.accel.intersect(ray.cast(), exclude)
+    }
+
+    /// Constructs the interaction recorded by `hit` again, which is a lot cheaper than intersecting the
+    /// ray with the scene: the interaction is computed directly from the primitive and barycentric
+    /// coordinates of the hit, without traversing the scene (and rays that missed aren't traced at all).
+    /// `ray` has to be the ray the record was made for.
+    pub fn interaction_from_hit(&self, ray: Ray<Scalar>, hit: HitRecord) -> Option<Interaction> {
+        let hit = hit.hit?;
+        self.prims_by_geom
+            .get(&hit.geom)?
+            .as_ref()
+            .interaction_at(ray.cast(), &hit)
+    }
+
+    /// Intersects a batch of rays with the scene, replacing the contents of `hits` with the closest
+    /// intersection of every ray (in the same order). Every ray skips its primitive (see
+    /// `intersect_excluding`).
+    pub fn intersect_batch(
+        &self,
+        rays: &[(Ray<Scalar>, Option<PrimRef>)],
+        hits: &mut Vec<Option<Interaction>>,
+    ) {
+        hits.clear();
+        hits.extend(
+            rays.iter()
+                .map(|&(ray, exclude)| self.intersect_excluding(ray, exclude)),
+        );
+    }
+
+    pub fn intersect_test(&self, ray: Ray<Scalar>) -> bool {
+        self.accel.intersect_test(ray.cast(), None)
+    }
+
+    /// Same as `intersect_test`, but never hits the primitive `exclude` (see `intersect_excluding`).
+    pub fn intersect_test_excluding(&self, ray: Ray<Scalar>, exclude: Option<PrimRef>) -> bool {
+        self.accel.intersect_test(ray.cast(), exclude)
+    }
+
+    /// Returns the fraction of light that makes it along the ray, accounting for every transparent
+    /// surface in the way. Use this instead of `intersect_test` for shadow rays when materials
+    /// can be (partially) transparent.
+    pub fn transmittance(&self, ray: Ray<Scalar>) -> Color {
+        self.transmittance_to(ray, None)
+    }
+
+    /// Same as `transmittance`, but ignores the geometry `target` (the geometry of the light a shadow
+    /// ray is traced towards). A shadow ray that ends exactly on the surface of an area light would
+    /// otherwise hit the light itself every so often because of floating point error, which darkens the
+    /// light close to its edges. The light can't shadow itself this way, which only matters for
+    /// non-convex lights.
+    pub fn transmittance_to(&self, ray: Ray<Scalar>, target: Option<GeomRef>) -> Color {
+        let ray = ray.cast();
+        let mut tr = Color::white();
+        self.accel
+            .visit_all(ray, &mut |prim| prim.transmittance(ray, target, &mut tr));
+        tr
+    }
+
+    /// Collects statistics about the scene. Geometry that is instanced multiple times is only counted
+    /// once for the number of triangles, vertices and memory usage.
+    pub fn statistics(&self) -> SceneStatistics {
+        let mut stats = SceneStatistics {
+            num_instances: 0,
+            num_geometries: 0,
+            num_triangles: 0,
+            num_vertices: 0,
+            num_lights: self.lights.len(),
+            geometry_memory: 0,
+            bvh: self.accel.stats(),
+            world_bbox: self.get_bbox(),
+        };
+
+        let mut visited = HashSet::new();
+        for prim in self.accel.get_prims() {
+            prim.for_each_geom(&mut |geom| {
+                stats.num_instances += 1;
+                if visited.insert(Arc::as_ptr(geom) as *const () as usize) {
+                    stats.num_geometries += 1;
+                    stats.num_triangles += geom.num_triangles();
+                    stats.num_vertices += geom.num_vertices();
+                    stats.geometry_memory += geom.memory_usage();
+                }
+            });
+        }
+
+        stats
+    }
+}
+
+/// Statistics describing a scene, see `Scene::statistics`.
+#[derive(Clone, Debug)]
+pub struct SceneStatistics {
+    pub num_instances: usize,
+    /// The number of unique geometries (which may be instanced multiple times).
+    pub num_geometries: usize,
+    pub num_triangles: usize,
+    pub num_vertices: usize,
+    pub num_lights: usize,
+    /// The number of bytes used by the geometry (including the BVHs of meshes).
+    pub geometry_memory: usize,
+    /// Statistics of the top level BVH (`None` if the scene uses a different accelerator).
+    pub bvh: Option<BVHStats>,
+    pub world_bbox: BBox3<f64>,
+}
+
+impl fmt::Display for SceneStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Scene statistics:")?;
+        writeln!(f, "  instances:    {}", self.num_instances)?;
+        writeln!(f, "  geometries:   {}", self.num_geometries)?;
+        writeln!(f, "  triangles:    {}", self.num_triangles)?;
+        writeln!(f, "  vertices:     {}", self.num_vertices)?;
+        writeln!(f, "  lights:       {}", self.num_lights)?;
+        writeln!(
+            f,
+            "  memory:       {:.2} MiB",
+            (self.geometry_memory as f64) / ((1 << 20) as f64)
+        )?;
+        writeln!(
+            f,
+            "  bounds:       ({:.3}, {:.3}, {:.3}) to ({:.3}, {:.3}, {:.3})",
+            self.world_bbox.pmin.x,
+            self.world_bbox.pmin.y,
+            self.world_bbox.pmin.z,
+            self.world_bbox.pmax.x,
+            self.world_bbox.pmax.y,
+            self.world_bbox.pmax.z
+        )?;
+        match &self.bvh {
+            Some(bvh) => write!(f, "Top level {}", bvh),
+            None => write!(f, "Top level: not a BVH"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::BuildAlgorithm;
+    use crate::camera::perspective::PerspectiveCamera;
+    use crate::film::{ImageBuffer, ImagePixel};
+    use crate::filter::{BoxFilter, PixelFilter};
+    use crate::geometry::mesh::{Mesh, Triangle};
+    use crate::geometry::sphere::Sphere;
+    use crate::integrator::normal::{NormalIntegrator, NormalIntegratorManager};
+    use crate::light::light_picker::LightPickerKind;
+    use crate::light::point::Point;
+    use crate::sampler::{SampleSequence, SamplerMode};
+    use crate::shading::material::Bsdf;
+    use crate::threading::{pixel_order::PixelOrder, RenderParam, Renderer};
+    use crate::TEST_EPSILON;
+
+    /// A grid of unit spheres.
+    fn spheres() -> Vec<Arc<dyn ScenePrim>> {
+        let material = MaterialPool::new().get_shared_material(DEFAULT_MATERIAL_ID);
+        (0..8)
+            .map(|i| {
+                let transf = Transf::new_translate(Vec3 {
+                    x: ((i % 4) as f64) * 3.0,
+                    y: ((i / 4) as f64) * 3.0,
+                    z: (i as f64) * 0.5,
+                });
+                let geom =
+                    SceneGeom::new_material(Arc::new(Sphere::new(1.0)), material.clone(), transf);
+                Arc::new(geom) as Arc<dyn ScenePrim>
+            })
+            .collect()
+    }
+
+    #[test]
+    fn linear_accelerator_matches_bvh() {
+        let prims = spheres();
+        let bvh_scene = Scene::new(&prims, Vec::new());
+        let linear_scene =
+            Scene::with_accelerator(AcceleratorKind::Linear.build(&prims), Vec::new());
+        assert_eq!(
+            format!("{:?}", bvh_scene.world_bound()),
+            format!("{:?}", linear_scene.world_bound())
+        );
+
+        let mut num_hits = 0;
+        for y in 0..20 {
+            for x in 0..40 {
+                let org = Vec3 {
+                    x: -2.0 + 0.3 * (x as Scalar),
+                    y: -2.0 + 0.4 * (y as Scalar),
+                    z: -5.0,
+                };
+                let ray = Ray::new(
+                    org,
+                    Vec3 {
+                        x: 0.01,
+                        y: 0.02,
+                        z: 1.0,
+                    },
+                    0.0,
+                );
+                let bvh_hit = bvh_scene.intersect(ray).map(|int| (int.geom, int.t));
+                let linear_hit = linear_scene.intersect(ray).map(|int| (int.geom, int.t));
+                assert_eq!(bvh_hit, linear_hit);
+                assert_eq!(
+                    bvh_scene.intersect_test(ray),
+                    linear_scene.intersect_test(ray)
+                );
+                assert_eq!(
+                    bvh_scene.transmittance(ray),
+                    linear_scene.transmittance(ray)
+                );
+                num_hits += bvh_hit.is_some() as u32;
+            }
+        }
+        assert!(num_hits > 0);
+    }
+
+    #[test]
+    fn interaction_from_hit_matches_intersect() {
+        use crate::bvh::BuildAlgorithm;
+        use crate::geometry::mesh::{Mesh, Triangle};
+
+        let material = MaterialPool::new().get_shared_material(DEFAULT_MATERIAL_ID);
+        let vec3 = |x, y, z| Vec3 { x, y, z };
+        let vec3_f32 = |x, y, z| Vec3::<f32> { x, y, z };
+        // A tilted quad in an instance, next to the spheres:
+        let mesh = Mesh::new(
+            vec![
+                Triangle {
+                    indices: [0, 1, 2],
+                    attribute_id: 0,
+                },
+                Triangle {
+                    indices: [0, 2, 3],
+                    attribute_id: 1,
+                },
+            ],
+            vec![
+                vec3_f32(0.0, 0.0, 0.0),
+                vec3_f32(4.0, 0.0, 1.0),
+                vec3_f32(4.0, 4.0, 0.0),
+                vec3_f32(0.0, 4.0, -1.0),
+            ],
+            vec![
+                vec3_f32(0.0, 0.0, 1.0),
+                vec3_f32(0.3, 0.0, 1.0).normalize(),
+                vec3_f32(0.3, 0.3, 1.0).normalize(),
+                vec3_f32(0.0, 0.3, 1.0).normalize(),
+            ],
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            1,
+            BuildAlgorithm::Sah,
+        );
+        let quad = SceneGeom::new_material(
+            Arc::new(mesh),
+            material,
+            Transf::new_translate(vec3(-1.0, -1.0, 0.0)),
+        );
+        let instance = SceneBVH::new(
+            &[Arc::new(quad) as Arc<dyn ScenePrim>],
+            Transf::new_translate(vec3(0.0, 0.0, 4.0)),
+        );
+        let mut prims = spheres();
+        prims.push(Arc::new(instance));
+        let scene = Scene::new(&prims, Vec::new());
+
+        let mut num_hits = 0;
+        let mut num_second_triangle_hits = 0;
+        for y in 0..20 {
+            for x in 0..40 {
+                let ray = Ray::new(
+                    vec3(-2.0 + 0.3 * (x as f64), -2.0 + 0.4 * (y as f64), -5.0),
+                    vec3(0.01, 0.02, 1.0),
+                    0.0,
+                )
+                .cast();
+                let hit = scene.intersect(ray);
+                let from_hit = scene.interaction_from_hit(ray, HitRecord::new(&hit));
+                match (hit, from_hit) {
+                    (None, None) => (),
+                    (Some(hit), Some(from_hit)) => {
+                        assert_eq!(hit.geom, from_hit.geom);
+                        assert_eq!(hit.prim, from_hit.prim);
+                        assert_eq!(hit.attribute_id, from_hit.attribute_id);
+                        assert_eq!(hit.t, from_hit.t);
+                        assert!((hit.p - from_hit.p).length() < TEST_EPSILON);
+                        assert!((hit.n - from_hit.n).length() < TEST_EPSILON);
+                        assert!((hit.shading_n() - from_hit.shading_n()).length() < TEST_EPSILON);
+                        assert!((hit.uv() - from_hit.uv()).length() < TEST_EPSILON);
+                        num_hits += 1;
+                        num_second_triangle_hits += (hit.prim == 1) as u32;
+                    }
+                    (hit, from_hit) => panic!("{:?} isn't {:?}", from_hit, hit),
+                }
+            }
+        }
+        assert!(num_hits > 0);
+        assert!(num_second_triangle_hits > 0);
+    }
+
+    #[test]
+    fn overriding_with_a_missing_material_is_an_error() {
+        use crate::error::PrismError;
+
+        let materials = MaterialPool::new();
+        let sphere = Arc::new(SceneGeom::new_material(
+            Arc::new(Sphere::new(1.0)),
+            materials.get_shared_material(DEFAULT_MATERIAL_ID),
+            Transf::new_identity(),
+        ));
+        let mut scene = Scene::new(&[sphere.clone() as Arc<dyn ScenePrim>], Vec::new());
+        scene.register_object("sphere", sphere).unwrap();
+
+        assert!(scene
+            .override_material("sphere", &materials, DEFAULT_MATERIAL_ID)
+            .is_ok());
+        assert!(matches!(
+            scene.override_material("sphere", &materials, 1),
+            Err(PrismError::InvalidScene(_))
+        ));
+    }
+
+    /// A material that lets a fixed fraction of the light straight through.
+    struct Translucent {
+        bsdf: Bsdf,
+        tr: Color,
+    }
+
+    impl Material for Translucent {
+        fn bsdf(&self, interaction: Interaction) -> (&Bsdf, Interaction) {
+            (&self.bsdf, interaction)
+        }
+
+        fn transmittance(&self, _interaction: &Interaction) -> Color {
+            self.tr
+        }
+    }
+
+    #[test]
+    fn stacked_translucent_quads_multiply_their_transmittance() {
+        use crate::geometry::rect::Rect;
+
+        let quads = |tr: &[Color]| -> Vec<Arc<dyn ScenePrim>> {
+            tr.iter()
+                .enumerate()
+                .map(|(i, &tr)| {
+                    let material: Arc<dyn Material> = Arc::new(Translucent {
+                        bsdf: Bsdf::new_opaque(),
+                        tr,
+                    });
+                    let geom = SceneGeom::new_material(
+                        Arc::new(Rect::new(Vec2 { x: 1.0, y: 1.0 })),
+                        material,
+                        Transf::new_translate(Vec3 {
+                            x: 0.0,
+                            y: 0.0,
+                            z: (i + 1) as f64,
+                        }),
+                    );
+                    Arc::new(geom) as Arc<dyn ScenePrim>
+                })
+                .collect()
+        };
+        let ray = Ray::new(
+            Vec3 {
+                x: 0.1,
+                y: -0.2,
+                z: 0.0,
+            },
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            0.0,
+        );
+
+        let half = Color::white().scale(0.5);
+        let scene = Scene::new(&quads(&[half, half, half]), Vec::new());
+        let tr = scene.transmittance(ray);
+        assert!((tr.r - 0.125).abs() < 1e-12 && (tr.g - 0.125).abs() < 1e-12);
+
+        // Anything opaque in the way blocks all of the light:
+        let scene = Scene::new(&quads(&[half, Color::black(), half]), Vec::new());
+        assert!(scene.transmittance(ray).is_black());
+    }
+
+    #[test]
+    fn statistics_count_everything_in_the_scene() {
+        let material = MaterialPool::new().get_shared_material(DEFAULT_MATERIAL_ID);
+        let mesh = |pos: Vec<Vec3<f32>>, indices: &[[u32; 3]]| {
+            let triangles = indices
+                .iter()
+                .map(|&indices| Triangle {
+                    indices,
+                    attribute_id: 0,
+                })
+                .collect();
+            Arc::new(Mesh::new(
+                triangles,
+                pos,
+                Vec::new(),
+                Vec::new(),
+                Vec::new(),
+                Vec::new(),
+                4,
+                BuildAlgorithm::Sah,
+            ))
+        };
+        let v = |x: f32, y: f32, z: f32| Vec3 { x, y, z };
+        let quad = mesh(
+            vec![
+                v(0.0, 0.0, 0.0),
+                v(1.0, 0.0, 0.0),
+                v(1.0, 1.0, 0.0),
+                v(0.0, 1.0, 0.0),
+            ],
+            &[[0, 1, 2], [0, 2, 3]],
+        );
+        let tetrahedron = mesh(
+            vec![
+                v(0.0, 0.0, 0.0),
+                v(1.0, 0.0, 0.0),
+                v(0.0, 1.0, 0.0),
+                v(0.0, 0.0, 1.0),
+            ],
+            &[[0, 2, 1], [0, 1, 3], [0, 3, 2], [1, 2, 3]],
+        );
+
+        // The quad is instanced twice:
+        let translate = |x: f64| Transf::new_translate(Vec3 { x, y: 0.0, z: 0.0 });
+        let prims: Vec<Arc<dyn ScenePrim>> = vec![
+            Arc::new(SceneGeom::new_material(
+                quad.clone(),
+                material.clone(),
+                translate(0.0),
+            )),
+            Arc::new(SceneGeom::new_material(
+                quad,
+                material.clone(),
+                translate(2.0),
+            )),
+            Arc::new(SceneGeom::new_material(
+                tetrahedron,
+                material,
+                translate(4.0),
+            )),
+        ];
+        let lights = (0..3)
+            .map(|i| {
+                let light = Point::new(
+                    Vec3 {
+                        x: i as Scalar,
+                        y: 3.0,
+                        z: 0.0,
+                    },
+                    Color::white(),
+                );
+                SceneLight::new(Arc::new(light), Transf::new_identity())
+            })
+            .collect();
+        let stats = Scene::new(&prims, lights).statistics();
+
+        assert_eq!(stats.num_instances, 3);
+        assert_eq!(stats.num_geometries, 2);
+        assert_eq!(stats.num_triangles, 6);
+        assert_eq!(stats.num_vertices, 8);
+        assert_eq!(stats.num_lights, 3);
+        assert!(stats.geometry_memory > 0);
+        assert_eq!(stats.bvh.unwrap().num_objects, 3);
+        assert_eq!(stats.world_bbox.pmin.x, 0.0);
+        assert_eq!(stats.world_bbox.pmax.x, 5.0);
+    }
+
+    #[test]
+    fn hidden_objects_disappear_from_the_image() {
+        let material = MaterialPool::new().get_shared_material(DEFAULT_MATERIAL_ID);
+        let sphere = |x: f64| {
+            let transf = Transf::new_translate(Vec3 { x, y: 0.0, z: 0.0 });
+            Arc::new(SceneGeom::new_material(
+                Arc::new(Sphere::new(1.0)),
+                material.clone(),
+                transf,
+            ))
+        };
+        let (left, right) = (sphere(-1.5), sphere(1.5));
+        let prims: Vec<Arc<dyn ScenePrim>> = vec![left.clone(), right.clone()];
+        let mut scene = Scene::new(&prims, Vec::new());
+        scene.register_object("left", left).unwrap();
+        scene.register_object("right", right).unwrap();
+
+        let res = Vec2 { x: 32, y: 16 };
+        let camera = PerspectiveCamera::frame_bbox(
+            scene.world_bound(),
+            45.0,
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            res,
+        );
+        let renderer = Renderer::new(RenderParam {
+            num_pixel_samples: 1,
+            num_threads: 1,
+            sample_seed: 0,
+            blue_noise_count: 0,
+            sample_sequence: SampleSequence::Pmj,
+            sampler_mode: SamplerMode::Scrambled,
+            blue_noise_dither: false,
+            res,
+            tile_size: Some(8),
+            pixel_order: PixelOrder::Scanline,
+            light_picker: LightPickerKind::All,
+            sample_dump: None,
+            importance_map: None,
+            split_buffers: false,
+            supersample: 1,
+        })
+        .unwrap();
+        // The alpha of every pixel is the fraction of the camera rays that hit anything:
+        let render = |scene: &Scene| {
+            let filter = PixelFilter::new(&BoxFilter::new(Vec2 { x: 0.5, y: 0.5 }));
+            let output = renderer
+                .render::<NormalIntegrator, NormalIntegratorManager>(
+                    scene,
+                    &MaterialPool::new(),
+                    &camera,
+                    filter,
+                    false,
+                    None,
+                    None,
+                )
+                .unwrap();
+            output
+                .film
+                .to_image_buffer(|_| ImagePixel::from_rgb(0.0, 0.0, 0.0))
+        };
+        let coverage = |image: &ImageBuffer, xs: std::ops::Range<usize>| {
+            let mut sum = 0.0;
+            for y in 0..res.y {
+                for x in xs.clone() {
+                    sum += image.get_pixel(Vec2 { x, y }).a;
+                }
+            }
+            sum
+        };
+
+        let both = render(&scene);
+        assert!(coverage(&both, 0..16) > 0.0);
+        assert!(coverage(&both, 16..32) > 0.0);
+
+        scene.set_visible("right", false).unwrap();
+        let left_only = render(&scene);
+        assert_eq!(coverage(&left_only, 0..16), coverage(&both, 0..16));
+        assert_eq!(coverage(&left_only, 16..32), 0.0);
+
+        scene.set_visible("right", true).unwrap();
+        assert_eq!(render(&scene).diff(&both).unwrap().max_error, 0.0);
+        assert!(scene.set_visible("middle", false).is_err());
+    }
+
+    #[test]
+    fn moving_objects_are_hit_over_the_entire_shutter() {
+        // A sphere that moves from x = -4 to x = 4 while the shutter is open, next to static spheres
+        // (so that it doesn't end up alone in the BVH):
+        let material = MaterialPool::new().get_shared_material(DEFAULT_MATERIAL_ID);
+        let translate = |x: f64, y: f64| Transf::new_translate(Vec3 { x, y, z: 0.0 });
+        let moving = SceneGeom::new_material(
+            Arc::new(Sphere::new(0.5)),
+            material.clone(),
+            translate(-4.0, 0.0),
+        )
+        .with_motion(translate(4.0, 0.0));
+        let mut prims: Vec<Arc<dyn ScenePrim>> = vec![Arc::new(moving)];
+        for i in 0..8 {
+            let x = -7.0 + 2.0 * (i as f64);
+            prims.push(Arc::new(SceneGeom::new_material(
+                Arc::new(Sphere::new(0.5)),
+                material.clone(),
+                translate(x, 3.0),
+            )));
+        }
+        let scene = Scene::new(&prims, Vec::new());
+        assert!(scene.world_bound().pmin.x <= -4.5 && scene.world_bound().pmax.x >= 4.5);
+
+        // The camera rays of a scanline through the sphere, where every ray is traced at the time the
+        // sphere is in front of it (so the streak has to be unbroken), and half a unit ahead of it:
+        for i in 0..=400 {
+            let time = (i as Scalar) / 400.0;
+            let x = -4.0 + 8.0 * time;
+            let ray = |x: Scalar| {
+                Ray::new(
+                    Vec3 { x, y: 0.0, z: -5.0 },
+                    Vec3 {
+                        x: 0.0,
+                        y: 0.0,
+                        z: 1.0,
+                    },
+                    time,
+                )
+            };
+            assert!(scene.intersect_test(ray(x)), "{}", time);
+            assert!(scene.intersect(ray(x)).is_some(), "{}", time);
+            assert!(!scene.intersect_test(ray(x + 1.0)), "{}", time);
+        }
+    }
+
+    #[test]
+    fn moving_a_parent_node_moves_its_children() {
+        let material = MaterialPool::new().get_shared_material(DEFAULT_MATERIAL_ID);
+        let translate = |x: f64| Transf::new_translate(Vec3 { x, y: 0.0, z: 0.0 });
+        let mut graph = TransformGraph::new();
+        let parent = graph.add_node(None, translate(-2.0));
+        let child = graph.add_node(Some(parent), translate(0.5));
+        let build = |graph: &TransformGraph| {
+            let geom = SceneGeom::new_material(
+                Arc::new(Sphere::new(1.0)),
+                material.clone(),
+                Transf::new_identity(),
+            )
+            .with_node(graph, child);
+            let prims: Vec<Arc<dyn ScenePrim>> = vec![Arc::new(geom)];
+            Scene::new(&prims, Vec::new())
+        };
+
+        graph.update();
+        let before = build(&graph);
+        assert!((before.world_bound().centroid().x + 1.5).abs() < 1e-9);
+
+        graph.set_local(parent, translate(1.0));
+        graph.update();
+        let after = build(&graph);
+        assert!((after.world_bound().centroid().x - 1.5).abs() < 1e-9);
+        assert!((graph.get_world(child).point(Vec3::zero()).x - 1.5).abs() < 1e-9);
+
+        // The same camera sees the sphere in the left half of the image and then in the right half:
+        let res = Vec2 { x: 32, y: 16 };
+        let camera = PerspectiveCamera::frame_bbox(
+            before.world_bound().combine_bnd(after.world_bound()),
+            45.0,
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            res,
+        );
+        let renderer = Renderer::new(RenderParam {
+            num_pixel_samples: 1,
+            num_threads: 1,
+            sample_seed: 0,
+            blue_noise_count: 0,
+            sample_sequence: SampleSequence::Pmj,
+            sampler_mode: SamplerMode::Scrambled,
+            blue_noise_dither: false,
+            res,
+            tile_size: Some(8),
+            pixel_order: PixelOrder::Scanline,
+            light_picker: LightPickerKind::All,
+            sample_dump: None,
+            importance_map: None,
+            split_buffers: false,
+            supersample: 1,
+        })
+        .unwrap();
+        let coverage = |scene: &Scene, xs: std::ops::Range<usize>| {
+            let filter = PixelFilter::new(&BoxFilter::new(Vec2 { x: 0.5, y: 0.5 }));
+            let image = renderer
+                .render::<NormalIntegrator, NormalIntegratorManager>(
+                    scene,
+                    &MaterialPool::new(),
+                    &camera,
+                    filter,
+                    false,
+                    None,
+                    None,
+                )
+                .unwrap()
+                .film
+                .to_image_buffer(|_| ImagePixel::from_rgb(0.0, 0.0, 0.0));
+            let mut sum = 0.0;
+            for y in 0..res.y {
+                for x in xs.clone() {
+                    sum += image.get_pixel(Vec2 { x, y }).a;
+                }
+            }
+            sum
+        };
+        assert!(coverage(&before, 0..16) > 0.0);
+        assert_eq!(coverage(&before, 16..32), 0.0);
+        assert_eq!(coverage(&after, 0..16), 0.0);
+        assert!(coverage(&after, 16..32) > 0.0);
+    }
+
+    #[test]
+    fn bounce_rays_skip_the_triangle_they_start_on() {
+        use rand::{Rng, SeedableRng};
+        use rand_pcg::Pcg32;
+
+        // A floor (triangles 0 and 1) that meets a wall at z = 10 (triangles 2 and 3), far away from the
+        // origin so that the hits have some round off:
+        let corners = [
+            (-10.0, 0.0, -10.0),
+            (10.0, 0.0, -10.0),
+            (10.0, 0.0, 10.0),
+            (-10.0, 0.0, 10.0),
+            (10.0, 10.0, 10.0),
+            (-10.0, 10.0, 10.0),
+        ];
+        let triangles = [[0, 1, 2], [0, 2, 3], [3, 2, 4], [3, 4, 5]]
+            .iter()
+            .map(|&indices| Triangle {
+                indices,
+                attribute_id: 0,
+            })
+            .collect();
+        let corner = Mesh::new(
+            triangles,
+            corners.iter().map(|&(x, y, z)| Vec3 { x, y, z }).collect(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            4,
+            BuildAlgorithm::Sah,
+        );
+        let transf = Transf::new_translate(Vec3 {
+            x: 1234.5,
+            y: 17.3,
+            z: -987.6,
+        });
+        let materials = MaterialPool::new();
+        let geom = Arc::new(SceneGeom::new_material(
+            Arc::new(corner),
+            materials.get_shared_material(DEFAULT_MATERIAL_ID),
+            transf,
+        ));
+        let other = Arc::new(SceneGeom::new_material(
+            Arc::new(Sphere::new(1.0)),
+            materials.get_shared_material(DEFAULT_MATERIAL_ID),
+            Transf::new_identity(),
+        ));
+        let prims: Vec<Arc<dyn ScenePrim>> = vec![geom, other.clone()];
+        let scene = Scene::new(&prims, Vec::new());
+
+        let mut rng = Pcg32::seed_from_u64(3);
+        let mut num_wall_hits = 0;
+        for _ in 0..2000 {
+            let (x, z) = (rng.gen_range(-9.0, 9.0), rng.gen_range(-9.0, 9.0));
+            let camera_ray = Ray::new(
+                transf.point(Vec3 { x, y: 1.0, z }),
+                transf.vector(Vec3 {
+                    x: 0.0,
+                    y: -1.0,
+                    z: 0.0,
+                }),
+                0.0,
+            )
+            .cast();
+            let floor = scene.intersect(camera_ray).unwrap();
+            assert!(floor.prim < 2);
+
+            // Leave the floor almost parallel to it, towards the wall:
+            let wi = transf
+                .vector(Vec3 {
+                    x: rng.gen_range(-0.1, 0.1),
+                    y: rng.gen_range(1e-7, 1e-5),
+                    z: 1.0,
+                })
+                .cast();
+            let bounce = Ray::new(floor.p, wi, 0.0);
+            // The ray can hit the other triangle of the floor, or the wall (or miss everything if it
+            // starts just below the floor):
+            let exclude = PrimRef::from_interaction(&floor);
+            let hit = match scene.intersect_excluding(bounce, exclude) {
+                Some(hit) => hit,
+                None => continue,
+            };
+            assert_ne!(hit.prim, floor.prim);
+            if hit.prim < 2 {
+                continue;
+            }
+            num_wall_hits += 1;
+
+            // Excluding the triangle of the wall that is hit lets the ray through it, but only if it's
+            // the triangle of the same geometry:
+            let wall = PrimRef::from_interaction(&hit).unwrap();
+            assert!(scene
+                .intersect_excluding(bounce, Some(wall))
+                .map_or(true, |through| through.prim != hit.prim));
+            let other_wall = PrimRef {
+                geom: other.geom_ref(),
+                ..wall
+            };
+            let same = scene.intersect_excluding(bounce, Some(other_wall)).unwrap();
+            // Unless the ray starts just below the floor (which only the triangle it starts on is
+            // excluded for):
+            if same.prim != floor.prim {
+                assert_eq!((same.prim, same.t), (hit.prim, hit.t));
+            }
+            assert!(scene.intersect_test_excluding(bounce, Some(other_wall)));
+        }
+        assert!(num_wall_hits > 1900, "{}", num_wall_hits);
+    }
+
+    #[test]
+    fn lights_are_shaded_with_the_default_material() {
+        use crate::shading::material::matte::Matte;
+
+        let mut materials = MaterialPool::new();
+        let red = Color {
+            r: 1.0,
+            g: 0.0,
+            b: 0.0,
+        };
+        let glowing = materials.add_material(Matte::new_emissive(Color::white(), red));
+        let translate = |x| Transf::new_translate(Vec3 { x, y: 0.0, z: 0.0 });
+        let lit = SceneGeom::new_material(
+            Arc::new(Sphere::new(1.0)),
+            materials.get_shared_material(glowing),
+            translate(0.0),
+        );
+        let light = SceneGeom::new_light(
+            Arc::new(Sphere::new(1.0)),
+            Arc::new(Point::new(Vec3::zero(), Color::white())),
+            translate(3.0),
+        );
+        let scene = Scene::new(
+            &[
+                Arc::new(lit) as Arc<dyn ScenePrim>,
+                Arc::new(light) as Arc<dyn ScenePrim>,
+            ],
+            Vec::new(),
+        );
+
+        let emission_at = |x| {
+            let ray = Ray::new(
+                Vec3 { x, y: 0.0, z: -5.0 },
+                Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0,
+                },
+                0.0,
+            );
+            let interaction = scene.intersect(ray).unwrap();
+            scene
+                .get_material_or_default(&interaction, &materials)
+                .emission()
+        };
+        assert_eq!(emission_at(0.0), red);
+        assert_eq!(emission_at(3.0), Color::black());
     }
 }
@@ -0,0 +1,93 @@
+//! Indices of refraction that vary with the wavelength, which is what makes glass disperse light into a
+//! rainbow.
+
+use crate::Scalar;
+
+/// The index of refraction of a dielectric as a function of the wavelength.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IorSpectrum {
+    /// The same index of refraction for every wavelength (no dispersion).
+    Constant(Scalar),
+    /// Cauchy's equation: `n = a + b / λ²` (with λ in micrometers).
+    Cauchy { a: Scalar, b: Scalar },
+    /// The Sellmeier equation: `n² = 1 + Σ b_i λ² / (λ² - c_i)` (with λ in micrometers, so `c` is in
+    /// square micrometers).
+    Sellmeier { b: [Scalar; 3], c: [Scalar; 3] },
+}
+
+impl IorSpectrum {
+    /// Schott N-BK7, the most common optical glass.
+    pub const BK7: IorSpectrum = IorSpectrum::Sellmeier {
+        b: [1.03961212, 0.231792344, 1.01046945],
+        c: [0.00600069867, 0.0200179144, 103.560653],
+    };
+    /// Fused silica (quartz glass).
+    pub const FUSED_SILICA: IorSpectrum = IorSpectrum::Sellmeier {
+        b: [0.6961663, 0.4079426, 0.8974794],
+        c: [0.0046791482, 0.0135120631, 97.9340025],
+    };
+    /// Diamond, which disperses light a lot more than glass does.
+    pub const DIAMOND: IorSpectrum = IorSpectrum::Sellmeier {
+        b: [4.3356, 0.3306, 0.0],
+        c: [0.011236, 0.030276, 0.0],
+    };
+
+    /// The wavelength (in nanometers) that a single index of refraction usually refers to (the
+    /// yellow helium d-line).
+    pub const REFERENCE_WAVELENGTH: Scalar = 587.56;
+
+    /// Evaluates the index of refraction at a wavelength (in nanometers).
+    pub fn eta(self, wavelength: Scalar) -> Scalar {
+        let l = wavelength * 1e-3;
+        let l2 = l * l;
+        match self {
+            IorSpectrum::Constant(eta) => eta,
+            IorSpectrum::Cauchy { a, b } => a + b / l2,
+            IorSpectrum::Sellmeier { b, c } => {
+                let n2 = 1.0
+                    + b.iter()
+                        .zip(c.iter())
+                        .map(|(&b, &c)| b * l2 / (l2 - c))
+                        .sum::<Scalar>();
+                n2.max(1.0).sqrt()
+            }
+        }
+    }
+
+    /// Whether the index of refraction depends on the wavelength (so paths that refract through it have
+    /// to be traced at a single wavelength).
+    pub fn is_dispersive(self) -> bool {
+        match self {
+            IorSpectrum::Constant(_) => false,
+            _ => true,
+        }
+    }
+}
+
+impl Default for IorSpectrum {
+    fn default() -> Self {
+        IorSpectrum::Constant(1.5)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sellmeier_matches_the_catalog() {
+        // The refractive indices from the Schott catalog at the F, d, and C lines:
+        let bk7 = IorSpectrum::BK7;
+        assert!((bk7.eta(IorSpectrum::REFERENCE_WAVELENGTH) - 1.5168).abs() < 1e-4);
+        assert!((bk7.eta(486.13) - 1.52238).abs() < 1e-4);
+        assert!((bk7.eta(656.27) - 1.51432).abs() < 1e-4);
+        let silica = IorSpectrum::FUSED_SILICA;
+        assert!((silica.eta(IorSpectrum::REFERENCE_WAVELENGTH) - 1.4585).abs() < 1e-4);
+
+        // Blue light is refracted more than red light:
+        assert!(bk7.is_dispersive());
+        assert!(IorSpectrum::DIAMOND.eta(450.0) > IorSpectrum::DIAMOND.eta(650.0));
+        assert!(!IorSpectrum::Constant(1.33).is_dispersive());
+        assert_eq!(IorSpectrum::Constant(1.33).eta(450.0), 1.33);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::shading::lobe::{abs_cos_theta, cos_theta, Lobe, LobeType};
+use crate::spectrum::Color;
+use crate::Scalar;
+use pmath::vector::{Vec2, Vec3};
+
+//
+// Specular Dielectric
+//
+// A smooth boundary between air and a dielectric (like glass or water). Light is either reflected or
+// refracted, with the probability given by the fresnel reflectance.
+
+pub struct SpecularDielectric {
+    color: Color,
+    // The index of refraction below the surface (the side opposite to the normal), with air above it:
+    eta: Scalar,
+}
+
+impl SpecularDielectric {
+    const LOBE_TYPE: LobeType = LobeType::from_bits_truncate(
+        LobeType::REFLECTION.bits | LobeType::TRANSMISSION.bits | LobeType::SPECULAR.bits,
+    );
+
+    /// The lobe doesn't scale the radiance by the relative index of refraction squared when light is
+    /// refracted, so that it's the same for light traced from the camera and from the lights (like
+    /// photons). The scales cancel out for light that leaves a closed object again anyway.
+    pub fn new(color: Color, eta: Scalar) -> Self {
+        SpecularDielectric { color, eta }
+    }
+}
+
+impl Lobe for SpecularDielectric {
+    fn contains_type(&self, lobe_type: LobeType) -> bool {
+        Self::LOBE_TYPE.contains(lobe_type)
+    }
+
+    fn get_type(&self) -> LobeType {
+        Self::LOBE_TYPE
+    }
+
+    fn eval(&self, _wo: Vec3<Scalar>, _wi: Vec3<Scalar>) -> Color {
+        // The chance of picking exactly the reflected or refracted direction is zero:
+        Color::black()
+    }
+
+    fn pdf(&self, _wo: Vec3<Scalar>, _wi: Vec3<Scalar>) -> Scalar {
+        0.0
+    }
+
+    fn sample(&self, wo: Vec3<Scalar>, u: Vec2<Scalar>) -> (Color, Vec3<Scalar>, Scalar) {
+        let reflectance = fr_dielectric(cos_theta(wo), 1.0, self.eta);
+        if u.x < reflectance {
+            let wi = Vec3 {
+                x: -wo.x,
+                y: -wo.y,
+                z: wo.z,
+            };
+            let color = self.color.scale(reflectance / abs_cos_theta(wi));
+            return (color, wi, reflectance);
+        }
+
+        // The normal and the ratio of the indices of refraction on the side of `wo`:
+        let (n, eta) = if cos_theta(wo) > 0.0 {
+            (
+                Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0,
+                },
+                1.0 / self.eta,
+            )
+        } else {
+            (
+                Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: -1.0,
+                },
+                self.eta,
+            )
+        };
+        match pmath::refract(wo, n, eta) {
+            Some(wi) => {
+                let transmittance = 1.0 - reflectance;
+                let color = self.color.scale(transmittance / abs_cos_theta(wi));
+                (color, wi, transmittance)
+            }
+            // Total internal reflection (in which case the reflectance is 1 and this can't happen):
+            None => (Color::black(), Vec3::zero(), 0.0),
+        }
+    }
+}
+
+/// The fresnel reflectance of unpolarized light at the boundary between two dielectrics, where
+/// `eta_i` is the index of refraction on the side of the normal and `eta_t` on the other side.
+/// `cos_theta_i` is the cosine between the incident direction and the normal.
+pub fn fr_dielectric(cos_theta_i: Scalar, eta_i: Scalar, eta_t: Scalar) -> Scalar {
+    let cos_theta_i = cos_theta_i.max(-1.0).min(1.0);
+    // Light coming from the other side sees the indices the other way around:
+    let (cos_theta_i, eta_i, eta_t) = if cos_theta_i < 0.0 {
+        (-cos_theta_i, eta_t, eta_i)
+    } else {
+        (cos_theta_i, eta_i, eta_t)
+    };
+
+    let sin_theta_i = (1.0 - cos_theta_i * cos_theta_i).max(0.0).sqrt();
+    let sin_theta_t = eta_i / eta_t * sin_theta_i;
+    if sin_theta_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_theta_t = (1.0 - sin_theta_t * sin_theta_t).max(0.0).sqrt();
+
+    let r_parl = ((eta_t * cos_theta_i) - (eta_i * cos_theta_t))
+        / ((eta_t * cos_theta_i) + (eta_i * cos_theta_t));
+    let r_perp = ((eta_i * cos_theta_i) - (eta_t * cos_theta_t))
+        / ((eta_i * cos_theta_i) + (eta_t * cos_theta_t));
+    (r_parl * r_parl + r_perp * r_perp) / 2.0
+}
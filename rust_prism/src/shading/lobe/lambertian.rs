@@ -1,5 +1,6 @@
 use crate::shading::lobe::{Lobe, LobeType};
 use crate::spectrum::Color;
+use crate::Scalar;
 use pmath::numbers::Float;
 use pmath::vector::Vec3;
 
@@ -13,7 +14,8 @@ pub struct LambertianReflection {
 }
 
 impl LambertianReflection {
-    const LOBE_TYPE: LobeType = LobeType::REFLECTION | LobeType::DIFFUSE;
+    const LOBE_TYPE: LobeType =
+        LobeType::from_bits_truncate(LobeType::REFLECTION.bits | LobeType::DIFFUSE.bits);
 
     pub fn new(r_scale: Color) -> Self {
         LambertianReflection { r_scale }
@@ -30,15 +32,15 @@ impl Lobe for LambertianReflection {
         Self::LOBE_TYPE
     }
 
-    fn eval(&self, wo: Vec3<f64>, wi: Vec3<f64>) -> Color {
-        self.r_scale.scale(f64::INV_PI)
+    fn eval(&self, wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Color {
+        self.r_scale.scale(Scalar::INV_PI)
     }
 
-    // fn rho_hd(&self, wo: Vec3<f64>, samples: &[Vec2<f64>]) -> RGBSpectrum {
+    // fn rho_hd(&self, wo: Vec3<Scalar>, samples: &[Vec2<Scalar>]) -> RGBSpectrum {
     //     self.r_scale
     // }
 
-    // fn rho_hh(&self, samples0: &[Vec2<f64>], samples1: &[Vec2<f64>]) -> RGBSpectrum {
+    // fn rho_hh(&self, samples0: &[Vec2<Scalar>], samples1: &[Vec2<Scalar>]) -> RGBSpectrum {
     //     self.r_scale
     // }
 }
@@ -52,7 +54,8 @@ pub struct LambertianTransmission {
 }
 
 impl LambertianTransmission {
-    const LOBE_TYPE: LobeType = LobeType::REFLECTION | LobeType::DIFFUSE;
+    const LOBE_TYPE: LobeType =
+        LobeType::from_bits_truncate(LobeType::REFLECTION.bits | LobeType::DIFFUSE.bits);
 
     pub fn new(t_scale: Color) -> Self {
         LambertianTransmission { t_scale }
@@ -68,15 +71,15 @@ impl Lobe for LambertianTransmission {
         Self::LOBE_TYPE
     }
 
-    fn eval(&self, wo: Vec3<f64>, wi: Vec3<f64>) -> Color {
-        self.t_scale.scale(f64::INV_PI)
+    fn eval(&self, wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Color {
+        self.t_scale.scale(Scalar::INV_PI)
     }
 
-    // fn rho_hd(&self, wo: Vec3<f64>, samples: &[Vec2<f64>]) -> RGBSpectrum {
+    // fn rho_hd(&self, wo: Vec3<Scalar>, samples: &[Vec2<Scalar>]) -> RGBSpectrum {
     //     self.t_scale
     // }
 
-    // fn rho_hh(&self, samples0: &[Vec2<f64>], samples1: &[Vec2<f64>]) -> RGBSpectrum {
+    // fn rho_hh(&self, samples0: &[Vec2<Scalar>], samples1: &[Vec2<Scalar>]) -> RGBSpectrum {
     //     self.t_scale
     // }
 }
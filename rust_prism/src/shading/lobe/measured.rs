@@ -0,0 +1,521 @@
+use crate::error::{PrismError, PrismResult};
+use crate::shading::lobe::{Lobe, LobeType};
+use crate::spectrum::Color;
+use crate::Scalar;
+use pmath::numbers::Float;
+use pmath::sampling;
+use pmath::vector::{Vec2, Vec3};
+use std::fs;
+use std::sync::Arc;
+
+//
+// Measured Reflection
+//
+// An isotropic BRDF measured from a real material, in the format of the MERL database ("A Data-Driven
+// Reflectance Model", Matusik et al. 2003). The BRDF is stored in the half/difference angle
+// parameterization of Rusinkiewicz ("A New Change of Variables for Efficient BRDF Representation").
+
+const THETA_H_RES: usize = 90;
+const THETA_D_RES: usize = 90;
+const PHI_D_RES: usize = 180;
+const TABLE_SIZE: usize = THETA_H_RES * THETA_D_RES * PHI_D_RES;
+// Every channel is stored with its own scale:
+const CHANNEL_SCALE: [Scalar; 3] = [1.0 / 1500.0, 1.15 / 1500.0, 1.66 / 1500.0];
+
+// The resolution of the sampling tables. There is a table over the incoming hemisphere (in theta and
+// phi relative to the outgoing direction) for every slice of outgoing theta:
+const SAMPLE_THETA_O_RES: usize = 16;
+const SAMPLE_THETA_RES: usize = 32;
+const SAMPLE_PHI_RES: usize = 64;
+// Some samples are cosine weighted, so that directions the tables miss can still be sampled:
+const COS_SAMPLE_FRACTION: Scalar = 0.1;
+
+/// The data of a measured BRDF along with the tables used to sample it. This is shared by every lobe
+/// that uses it, as it's fairly large.
+pub struct MerlData {
+    // All of the red values, then all of the green values, then all of the blue values:
+    table: Vec<Scalar>,
+    // For every outgoing theta slice, the cdf over the incoming theta (`SAMPLE_THETA_RES + 1` values),
+    // and for every incoming theta, the cdf over the incoming phi (`SAMPLE_PHI_RES + 1` values):
+    marginal_cdfs: Vec<Scalar>,
+    conditional_cdfs: Vec<Scalar>,
+}
+
+impl MerlData {
+    /// Loads a `.binary` file from the MERL database and builds the sampling tables.
+    pub fn load(path: &str) -> PrismResult<Self> {
+        let bytes = fs::read(path).map_err(|err| PrismError::io(path, err))?;
+        if bytes.len() < 12 {
+            return Err(PrismError::parse(path, None, "measured brdf is truncated"));
+        }
+        let read_i32 = |offset: usize| {
+            let mut value = [0u8; 4];
+            value.copy_from_slice(&bytes[offset..(offset + 4)]);
+            i32::from_le_bytes(value)
+        };
+        let dims = [read_i32(0), read_i32(4), read_i32(8)];
+        if dims != [THETA_H_RES as i32, THETA_D_RES as i32, PHI_D_RES as i32] {
+            return Err(PrismError::parse(
+                path,
+                None,
+                format!(
+                    "measured brdf has an unsupported resolution ({} x {} x {})",
+                    dims[0], dims[1], dims[2]
+                ),
+            ));
+        }
+        if bytes.len() != 12 + 3 * TABLE_SIZE * 8 {
+            return Err(PrismError::parse(path, None, "measured brdf is truncated"));
+        }
+
+        let table = bytes[12..]
+            .chunks_exact(8)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut value = [0u8; 8];
+                value.copy_from_slice(chunk);
+                // Missing measurements are negative:
+                Scalar::from_f64(f64::from_le_bytes(value)).max(0.0) * CHANNEL_SCALE[i / TABLE_SIZE]
+            })
+            .collect();
+        Ok(Self::new(table))
+    }
+
+    /// Builds the sampling tables for the data (three channels of `TABLE_SIZE` values each).
+    fn new(table: Vec<Scalar>) -> Self {
+        // The tables are built by evaluating the data itself:
+        let mut data = MerlData {
+            table,
+            marginal_cdfs: Vec::new(),
+            conditional_cdfs: Vec::new(),
+        };
+
+        let mut marginal_cdfs = Vec::with_capacity(SAMPLE_THETA_O_RES * (SAMPLE_THETA_RES + 1));
+        let mut conditional_cdfs =
+            Vec::with_capacity(SAMPLE_THETA_O_RES * SAMPLE_THETA_RES * (SAMPLE_PHI_RES + 1));
+        let mut row = Vec::with_capacity(SAMPLE_PHI_RES);
+        let mut row_sums = Vec::with_capacity(SAMPLE_THETA_RES);
+        for slice in 0..SAMPLE_THETA_O_RES {
+            let theta_o =
+                (slice as Scalar + 0.5) / (SAMPLE_THETA_O_RES as Scalar) * Scalar::PI_OVER_2;
+            let wo = spherical_dir(theta_o, 0.0);
+
+            row_sums.clear();
+            for t in 0..SAMPLE_THETA_RES {
+                let theta_i =
+                    (t as Scalar + 0.5) / (SAMPLE_THETA_RES as Scalar) * Scalar::PI_OVER_2;
+                // The sine converts from solid angle to theta and phi:
+                let jacobian = theta_i.cos() * theta_i.sin();
+                row.clear();
+                row.extend((0..SAMPLE_PHI_RES).map(|p| {
+                    let phi_i = (p as Scalar + 0.5) / (SAMPLE_PHI_RES as Scalar) * 2.0 * Scalar::PI;
+                    let wi = spherical_dir(theta_i, phi_i);
+                    data.lookup(wo, wi).luminance().max(0.0) * jacobian
+                }));
+                row_sums.push(row.iter().sum());
+                push_cdf(&mut conditional_cdfs, &row);
+            }
+            push_cdf(&mut marginal_cdfs, &row_sums);
+        }
+
+        data.marginal_cdfs = marginal_cdfs;
+        data.conditional_cdfs = conditional_cdfs;
+        data
+    }
+
+    /// Evaluates the BRDF (`wo` and `wi` are in shading space and on the same side of the surface).
+    fn lookup(&self, wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Color {
+        let (theta_h, theta_d, phi_d) = half_diff_angles(wo, wi);
+
+        // The half angle is stored non-linearly, so there is more precision near the highlight:
+        let theta_h_pos = (theta_h / Scalar::PI_OVER_2).max(0.0).sqrt() * (THETA_H_RES as Scalar);
+        let theta_d_pos = theta_d / Scalar::PI_OVER_2 * (THETA_D_RES as Scalar);
+        let phi_d_pos = phi_d / Scalar::PI * (PHI_D_RES as Scalar);
+
+        let (th0, th1, th_t) = lerp_indices(theta_h_pos, THETA_H_RES, false);
+        let (td0, td1, td_t) = lerp_indices(theta_d_pos, THETA_D_RES, false);
+        // Phi is periodic:
+        let (pd0, pd1, pd_t) = lerp_indices(phi_d_pos, PHI_D_RES, true);
+
+        let mut channels = [0.0; 3];
+        for (c, channel) in channels.iter_mut().enumerate() {
+            let value = |th: usize, td: usize, pd: usize| {
+                self.table[c * TABLE_SIZE + (th * THETA_D_RES + td) * PHI_D_RES + pd]
+            };
+            let lerp = |a: Scalar, b: Scalar, t: Scalar| a + (b - a) * t;
+            let at_th = |th: usize| {
+                lerp(
+                    lerp(value(th, td0, pd0), value(th, td0, pd1), pd_t),
+                    lerp(value(th, td1, pd0), value(th, td1, pd1), pd_t),
+                    td_t,
+                )
+            };
+            *channel = lerp(at_th(th0), at_th(th1), th_t);
+        }
+        Color {
+            r: channels[0],
+            g: channels[1],
+            b: channels[2],
+        }
+    }
+
+    /// Returns the sampling tables for the outgoing direction (with a positive z).
+    fn slice(&self, wo: Vec3<Scalar>) -> (&[Scalar], &[Scalar]) {
+        let theta_o = wo.z.max(-1.0).min(1.0).acos();
+        let slice = ((theta_o / Scalar::PI_OVER_2 * (SAMPLE_THETA_O_RES as Scalar)) as usize)
+            .min(SAMPLE_THETA_O_RES - 1);
+        let marginal_len = SAMPLE_THETA_RES + 1;
+        let conditional_len = SAMPLE_THETA_RES * (SAMPLE_PHI_RES + 1);
+        (
+            &self.marginal_cdfs[(slice * marginal_len)..((slice + 1) * marginal_len)],
+            &self.conditional_cdfs[(slice * conditional_len)..((slice + 1) * conditional_len)],
+        )
+    }
+
+    /// The pdf (with respect to solid angle) of sampling `wi` from the tables. Both directions have a
+    /// positive z.
+    fn table_pdf(&self, wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Scalar {
+        let theta_i = wi.z.max(-1.0).min(1.0).acos();
+        let sin_theta_i = theta_i.sin();
+        if sin_theta_i == 0.0 {
+            return 0.0;
+        }
+        let phi_i = relative_phi(wo, wi);
+
+        let t = ((theta_i / Scalar::PI_OVER_2 * (SAMPLE_THETA_RES as Scalar)) as usize)
+            .min(SAMPLE_THETA_RES - 1);
+        let p = ((phi_i / (2.0 * Scalar::PI) * (SAMPLE_PHI_RES as Scalar)) as usize)
+            .min(SAMPLE_PHI_RES - 1);
+        let (marginal, conditional) = self.slice(wo);
+        let row = &conditional[(t * (SAMPLE_PHI_RES + 1))..((t + 1) * (SAMPLE_PHI_RES + 1))];
+        let cell_prob = (marginal[t + 1] - marginal[t]) * (row[p + 1] - row[p]);
+
+        // Convert from the probability of the cell to a density over theta and phi, and then to one
+        // over solid angle:
+        let cell_area = (Scalar::PI_OVER_2 / (SAMPLE_THETA_RES as Scalar))
+            * (2.0 * Scalar::PI / (SAMPLE_PHI_RES as Scalar));
+        cell_prob / cell_area / sin_theta_i
+    }
+
+    /// Samples an incoming direction from the tables (`wo` has a positive z).
+    fn table_sample(&self, wo: Vec3<Scalar>, u: Vec2<Scalar>) -> Vec3<Scalar> {
+        let (marginal, conditional) = self.slice(wo);
+        let (t, u_theta) = sample_cdf(marginal, u.x);
+        let row = &conditional[(t * (SAMPLE_PHI_RES + 1))..((t + 1) * (SAMPLE_PHI_RES + 1))];
+        let (p, u_phi) = sample_cdf(row, u.y);
+
+        let theta_i = (t as Scalar + u_theta) / (SAMPLE_THETA_RES as Scalar) * Scalar::PI_OVER_2;
+        let phi_o = wo.y.atan2(wo.x);
+        let phi_i = (p as Scalar + u_phi) / (SAMPLE_PHI_RES as Scalar) * 2.0 * Scalar::PI + phi_o;
+        spherical_dir(theta_i, phi_i)
+    }
+}
+
+pub struct MeasuredReflection {
+    data: Arc<MerlData>,
+}
+
+impl MeasuredReflection {
+    const LOBE_TYPE: LobeType =
+        LobeType::from_bits_truncate(LobeType::REFLECTION.bits | LobeType::GLOSSY.bits);
+
+    pub fn new(data: Arc<MerlData>) -> Self {
+        MeasuredReflection { data }
+    }
+}
+
+impl Lobe for MeasuredReflection {
+    fn contains_type(&self, lobe_type: LobeType) -> bool {
+        Self::LOBE_TYPE.contains(lobe_type)
+    }
+
+    fn get_type(&self) -> LobeType {
+        Self::LOBE_TYPE
+    }
+
+    fn eval(&self, wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Color {
+        if wo.z * wi.z <= 0.0 {
+            return Color::black();
+        }
+        let (wo, wi) = upper_hemisphere(wo, wi);
+        self.data.lookup(wo, wi)
+    }
+
+    fn sample(&self, wo: Vec3<Scalar>, u: Vec2<Scalar>) -> (Color, Vec3<Scalar>, Scalar) {
+        if wo.z == 0.0 {
+            return (Color::black(), Vec3::zero(), 0.0);
+        }
+        let (up_wo, _) = upper_hemisphere(wo, wo);
+        let wi = if u.x < COS_SAMPLE_FRACTION {
+            let u = Vec2 {
+                x: u.x / COS_SAMPLE_FRACTION,
+                y: u.y,
+            };
+            sampling::cos_sample_hemisphere(u)
+        } else {
+            let u = Vec2 {
+                x: ((u.x - COS_SAMPLE_FRACTION) / (1.0 - COS_SAMPLE_FRACTION))
+                    .min(Scalar::ONE_MINUS_EPS),
+                y: u.y,
+            };
+            self.data.table_sample(up_wo, u)
+        };
+        // Make sure it's on the same side as wo:
+        let wi = if wo.z < 0.0 {
+            Vec3 {
+                x: wi.x,
+                y: wi.y,
+                z: -wi.z,
+            }
+        } else {
+            wi
+        };
+        (self.eval(wo, wi), wi, self.pdf(wo, wi))
+    }
+
+    fn pdf(&self, wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Scalar {
+        if wo.z * wi.z <= 0.0 {
+            return 0.0;
+        }
+        let (wo, wi) = upper_hemisphere(wo, wi);
+        COS_SAMPLE_FRACTION * sampling::cos_sphere_pdf(wi.z)
+            + (1.0 - COS_SAMPLE_FRACTION) * self.data.table_pdf(wo, wi)
+    }
+}
+
+/// Flips both directions so that they have a positive z (they have to be on the same side).
+fn upper_hemisphere(wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> (Vec3<Scalar>, Vec3<Scalar>) {
+    if wo.z < 0.0 {
+        (
+            Vec3 {
+                x: wo.x,
+                y: wo.y,
+                z: -wo.z,
+            },
+            Vec3 {
+                x: wi.x,
+                y: wi.y,
+                z: -wi.z,
+            },
+        )
+    } else {
+        (wo, wi)
+    }
+}
+
+fn spherical_dir(theta: Scalar, phi: Scalar) -> Vec3<Scalar> {
+    Vec3 {
+        x: theta.sin() * phi.cos(),
+        y: theta.sin() * phi.sin(),
+        z: theta.cos(),
+    }
+}
+
+/// Returns the azimuth of `wi` relative to that of `wo` in [0, 2pi).
+fn relative_phi(wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Scalar {
+    let phi = wi.y.atan2(wi.x) - wo.y.atan2(wo.x);
+    let phi = phi.rem_euclid(2.0 * Scalar::PI);
+    if phi >= 2.0 * Scalar::PI {
+        0.0
+    } else {
+        phi
+    }
+}
+
+/// Converts a pair of directions (with a positive z) to the half angle (theta) and the difference
+/// angles (theta and phi, where phi is folded into [0, pi) using reciprocity).
+fn half_diff_angles(wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> (Scalar, Scalar, Scalar) {
+    let h = (wo + wi).normalize();
+    let theta_h = h.z.max(-1.0).min(1.0).acos();
+    let phi_h = h.y.atan2(h.x);
+
+    // Rotate wi so that the half vector is the z axis:
+    let (sin_phi, cos_phi) = (-phi_h).sin_cos();
+    let d = Vec3 {
+        x: wi.x * cos_phi - wi.y * sin_phi,
+        y: wi.x * sin_phi + wi.y * cos_phi,
+        z: wi.z,
+    };
+    let (sin_theta, cos_theta) = (-theta_h).sin_cos();
+    let d = Vec3 {
+        x: d.x * cos_theta + d.z * sin_theta,
+        y: d.y,
+        z: -d.x * sin_theta + d.z * cos_theta,
+    };
+
+    let theta_d = d.z.max(-1.0).min(1.0).acos();
+    let mut phi_d = d.y.atan2(d.x);
+    if phi_d < 0.0 {
+        phi_d += Scalar::PI;
+    }
+    (theta_h, theta_d, phi_d)
+}
+
+/// Returns the two indices to interpolate between for a continuous index `pos`, along with the
+/// interpolation factor.
+fn lerp_indices(pos: Scalar, res: usize, periodic: bool) -> (usize, usize, Scalar) {
+    let pos = pos.max(0.0);
+    let i0 = (pos as usize).min(res - 1);
+    let t = (pos - (i0 as Scalar)).min(1.0);
+    let i1 = if periodic {
+        (i0 + 1) % res
+    } else {
+        (i0 + 1).min(res - 1)
+    };
+    (i0, i1, t)
+}
+
+/// Appends the normalized cdf of `values` (which has one more value). If all of the values are zero,
+/// the cdf is uniform.
+fn push_cdf(cdfs: &mut Vec<Scalar>, values: &[Scalar]) {
+    let sum: Scalar = values.iter().sum();
+    let n = values.len() as Scalar;
+    let mut cdf = 0.0;
+    cdfs.push(0.0);
+    for (i, &value) in values.iter().enumerate() {
+        cdf += value;
+        cdfs.push(if sum > 0.0 {
+            cdf / sum
+        } else {
+            (i + 1) as Scalar / n
+        });
+    }
+}
+
+/// Picks an entry of a cdf (created by `push_cdf`) with `u` in [0, 1). Returns the index of the entry
+/// and `u` remapped to [0, 1) within it.
+fn sample_cdf(cdf: &[Scalar], u: Scalar) -> (usize, Scalar) {
+    let n = cdf.len() - 1;
+    // The last entry that is at most u (skipping entries with a probability of 0):
+    let index = (cdf.partition_point(|&c| c <= u).max(1) - 1).min(n - 1);
+    let width = cdf[index + 1] - cdf[index];
+    let remapped = if width > 0.0 {
+        (u - cdf[index]) / width
+    } else {
+        0.0
+    };
+    (index, remapped.max(0.0).min(Scalar::ONE_MINUS_EPS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TEST_EPSILON;
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg32;
+
+    /// The raw values of a glossy BRDF that also depends on the difference angles.
+    fn raw_value(c: usize, th: usize, td: usize, pd: usize) -> Scalar {
+        let highlight = (-(th as Scalar) / 8.0).exp();
+        let phi = (pd as Scalar) / (PHI_D_RES as Scalar) * 2.0 * Scalar::PI;
+        (1.0 + c as Scalar)
+            * (0.05 + highlight)
+            * (1.0 + 0.3 * phi.cos())
+            * (1.0 + 0.002 * td as Scalar)
+    }
+
+    fn synthetic_data() -> MerlData {
+        let mut table = Vec::with_capacity(3 * TABLE_SIZE);
+        for c in 0..3 {
+            for th in 0..THETA_H_RES {
+                for td in 0..THETA_D_RES {
+                    table.extend((0..PHI_D_RES).map(|pd| raw_value(c, th, td, pd)));
+                }
+            }
+        }
+        MerlData::new(table)
+    }
+
+    fn random_dir(rng: &mut Pcg32) -> Vec3<Scalar> {
+        sampling::cos_sample_hemisphere(Vec2 {
+            x: rng.gen(),
+            y: rng.gen(),
+        })
+    }
+
+    #[test]
+    fn eval_is_reciprocal() {
+        let lobe = MeasuredReflection::new(Arc::new(synthetic_data()));
+        let mut rng = Pcg32::seed_from_u64(11);
+        for _ in 0..1000 {
+            let (wo, wi) = (random_dir(&mut rng), random_dir(&mut rng));
+            let (a, b) = (lobe.eval(wo, wi), lobe.eval(wi, wo));
+            assert!(a.r > 0.0);
+            assert!((a.r - b.r).abs() < TEST_EPSILON * a.r, "{:?} {:?}", a, b);
+            assert!((a.b - b.b).abs() < TEST_EPSILON * a.b, "{:?} {:?}", a, b);
+            // Both sides of the surface are the same, and light isn't transmitted:
+            let flip = |w: Vec3<Scalar>| Vec3 { z: -w.z, ..w };
+            assert_eq!(lobe.eval(flip(wo), flip(wi)).r, a.r);
+            assert!(lobe.eval(wo, flip(wi)).is_black());
+        }
+    }
+
+    #[test]
+    fn samples_match_the_pdf() {
+        let lobe = MeasuredReflection::new(Arc::new(synthetic_data()));
+        let mut rng = Pcg32::seed_from_u64(5);
+        for _ in 0..8 {
+            let wo = random_dir(&mut rng);
+            for _ in 0..100 {
+                let u = Vec2 {
+                    x: rng.gen(),
+                    y: rng.gen(),
+                };
+                let (color, wi, pdf) = lobe.sample(wo, u);
+                assert!(wi.z > 0.0 && pdf > 0.0);
+                assert!((pdf - lobe.pdf(wo, wi)).abs() < 1e-9 * pdf);
+                assert_eq!(color.r, lobe.eval(wo, wi).r);
+            }
+
+            // The pdf integrates to 1 over the hemisphere (estimated with cosine weighted directions):
+            const N: usize = 20000;
+            let integral: Scalar = (0..N)
+                .map(|_| {
+                    let wi = random_dir(&mut rng);
+                    lobe.pdf(wo, wi) / sampling::cos_sphere_pdf(wi.z)
+                })
+                .sum::<Scalar>()
+                / (N as Scalar);
+            assert!((integral - 1.0).abs() < 0.05, "{}", integral);
+        }
+    }
+
+    #[test]
+    fn binary_files_are_loaded_with_the_channel_scales() {
+        let mut bytes = Vec::with_capacity(12 + 3 * TABLE_SIZE * 8);
+        for &dim in &[THETA_H_RES, THETA_D_RES, PHI_D_RES] {
+            bytes.extend_from_slice(&(dim as i32).to_le_bytes());
+        }
+        for i in 0..(3 * TABLE_SIZE) {
+            // Every 7th measurement is missing:
+            let value = if i % 7 == 0 { -1.0 } else { (i % 1000) as f64 };
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        let path = std::env::temp_dir().join("prism_measured_test.binary");
+        let path = path.to_str().unwrap();
+        fs::write(path, &bytes).unwrap();
+        let data = MerlData::load(path).unwrap();
+        for &i in &[0, 1, 7, 999, TABLE_SIZE + 3, 2 * TABLE_SIZE + 10] {
+            let raw = if i % 7 == 0 {
+                0.0
+            } else {
+                (i % 1000) as Scalar
+            };
+            assert_eq!(data.table[i], raw * CHANNEL_SCALE[i / TABLE_SIZE]);
+        }
+
+        fs::write(path, &bytes[..(bytes.len() - 8)]).unwrap();
+        assert!(matches!(
+            MerlData::load(path),
+            Err(PrismError::Parse { .. })
+        ));
+        bytes[0] = 91;
+        fs::write(path, &bytes).unwrap();
+        assert!(matches!(
+            MerlData::load(path),
+            Err(PrismError::Parse { .. })
+        ));
+        fs::remove_file(path).unwrap();
+        assert!(matches!(MerlData::load(path), Err(PrismError::Io { .. })));
+    }
+}
@@ -1,175 +1,262 @@
-// use crate::math::numbers::Float;
-// use crate::math::vector::{Vec2, Vec3};
-// use crate::shading::lobe::specular::Fresnel;
-// use crate::shading::lobe::{
-//     abs_cos_theta, cos2_phi, cos2_theta, sin2_phi, tan2_theta, tan_theta, Lobe, LobeType,
-// };
-// // use crate::spectrum::RGBSpectrum;
-
-// // Converts the roughness values between 0 and 1 to an alpha value for
-// // use later down the line:
-// fn roughness_to_alpha(roughness: f64) -> f64 {
-//     let roughness = roughness.max(1e-3);
-//     let x = roughness.ln();
-//     // a + bx + cx^2 + dx^3 + ex^4:
-//     1.62142 + 0.819955 * x + 0.1734 * x * x + 0.0171201 * x * x * x + 0.000640711 * x * x * x * x
-// }
-
-// // Defines a distribution for a microfacet surface (NDF and lambda function):
-// trait MicrofacetDistribution {
-//     fn ndf(&self, wh: Vec3<f64>) -> f64;
-//     fn lambda(&self, w: Vec3<f64>) -> f64;
-//     // Sometimes referred to g2 in other literature:
-//     fn g(&self, w: Vec3<f64>, wh: Vec3<f64>) -> f64 {
-//         1. / (1. + self.lambda(w) + self.lambda(wh))
-//     }
-//     // Smith's masking function:
-//     fn g1(&self, w: Vec3<f64>) -> f64 {
-//         1. / (1. + self.lambda(w))
-//     }
-// }
-
-// // The Beckmann Distribution
-// #[derive(Clone, Copy, Debug)]
-// struct Beckmann {
-//     alpha: Vec2<f64>,
-// }
-
-// impl Beckmann {
-//     // As the Beckmann distribution is anisotropic, we need to define the roughness
-//     // at the major axis of the elipsoid:
-//     pub fn new(roughness: Vec2<f64>) -> Self {
-//         Beckmann {
-//             alpha: Vec2 {
-//                 x: roughness_to_alpha(roughness.x),
-//                 y: roughness_to_alpha(roughness.y),
-//             },
-//         }
-//     }
-// }
-
-// impl MicrofacetDistribution for Beckmann {
-//     fn ndf(&self, wh: Vec3<f64>) -> f64 {
-//         let tan2_theta = tan2_theta(wh);
-//         // Check if wh "grazes" the surface
-//         if tan2_theta.is_infinite() {
-//             return 0.;
-//         }
-
-//         let cos4_theta = cos2_theta(wh) * cos2_theta(wh);
-
-//         (-tan2_theta
-//             * (cos2_phi(wh) / (self.alpha.x * self.alpha.x)
-//                 + sin2_phi(wh) / (self.alpha.y * self.alpha.y)))
-//             .exp()
-//             / (f64::PI * self.alpha.x * self.alpha.y * cos4_theta)
-//     }
-
-//     fn lambda(&self, w: Vec3<f64>) -> f64 {
-//         // Polynomial estimation of the actual formula:
-//         let abs_tan_theta = tan_theta(w).abs();
-//         if abs_tan_theta.is_infinite() {
-//             return 0.;
-//         }
-//         // A new alpha value for the direction w (using alpha_x and alpha_y):
-//         let alpha = (cos2_phi(w) * self.alpha.x * self.alpha.x
-//             + sin2_phi(w) * self.alpha.y * self.alpha.y)
-//             .sqrt();
-//         let a = 1. / (alpha * abs_tan_theta);
-//         if a >= 1.6 {
-//             return 0.;
-//         }
-//         (1. - 1.259 * a + 0.396 * a * a) / (3.535 * a + 2.181 * a * a)
-//     }
-// }
-
-// #[derive(Clone, Copy, Debug)]
-// struct TrowbridgeReitz {
-//     alpha: Vec2<f64>,
-// }
-
-// impl TrowbridgeReitz {
-//     pub fn new(roughness: Vec2<f64>) -> Self {
-//         TrowbridgeReitz {
-//             alpha: Vec2 {
-//                 x: roughness_to_alpha(roughness.x),
-//                 y: roughness_to_alpha(roughness.y),
-//             },
-//         }
-//     }
-// }
-
-// impl MicrofacetDistribution for TrowbridgeReitz {
-//     fn ndf(&self, wh: Vec3<f64>) -> f64 {
-//         let tan2_theta = tan2_theta(wh);
-//         // Check if wh "grazes" the surface
-//         if tan2_theta.is_infinite() {
-//             return 0.;
-//         }
-//         let cos4_theta = cos2_theta(wh) * cos2_theta(wh);
-//         let e = (cos2_phi(wh) / (self.alpha_x * self.alpha_x)
-//             + sin2_phi(wh) / (self.alpha_y * self.alpha_y))
-//             * tan2_theta;
-//         1. / (f64::PI * self.alpha_x * self.alpha_y * cos4_theta * (1. + e) * (1. + e))
-//     }
-
-//     fn lambda(&self, w: Vec3<f64>) -> f64 {
-//         let abs_tan_theta = tan_theta(w).abs();
-//         if abs_tan_theta.is_infinite() {
-//             return 0.;
-//         }
-//         // Calculate the alpha value like we did with Beckmann:
-//         let alpha = (cos2_phi(w) * self.alpha_x * self.alpha_x
-//             + sin2_phi(w) * self.alpha_y * self.alpha_y)
-//             .sqrt();
-//         let alpha2_tan2_theta = (alpha * abs_tan_theta) * (alpha * abs_tan_theta);
-//         (-1. + (1. + alpha2_tan2_theta).sqrt()) / 2.
-//     }
-// }
-
-// The bottom two microfacet models use the microfacet distribution above
+use crate::shading::lobe::specular::Fresnel;
+use crate::shading::lobe::{
+    abs_cos_theta, cos2_phi, cos2_theta, is_in_same_hemisphere, sin2_phi, tan2_theta, tan_theta,
+    Lobe, LobeType,
+};
+use crate::spectrum::Color;
+use crate::Scalar;
+use pmath::numbers::Float;
+use pmath::vector::{Vec2, Vec3};
+
+// The distributions take alpha directly, materials convert their roughness with a
+// `RoughnessRemap` (which also clamps it to `MIN_ALPHA`).
+
+// Returns the direction with the given spherical coordinates (in shading space):
+fn spherical_direction(cos_theta: Scalar, phi: Scalar) -> Vec3<Scalar> {
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    Vec3 {
+        x: sin_theta * phi.cos(),
+        y: sin_theta * phi.sin(),
+        z: cos_theta,
+    }
+}
+
+// Samples the azimuth of an anisotropic distribution. Returns phi and 1 / alpha^2 in that direction:
+fn sample_phi(alpha: Vec2<Scalar>, u: Scalar) -> (Scalar, Scalar) {
+    let mut phi = (alpha.y / alpha.x * (2. * Scalar::PI * u + Scalar::PI_OVER_2).tan()).atan();
+    if u > 0.5 {
+        phi += Scalar::PI;
+    }
+    let (sin_phi, cos_phi) = phi.sin_cos();
+    let inv_alpha2 =
+        cos_phi * cos_phi / (alpha.x * alpha.x) + sin_phi * sin_phi / (alpha.y * alpha.y);
+    (phi, inv_alpha2)
+}
+
+// Defines a distribution for a microfacet surface (NDF and lambda function):
+pub trait MicrofacetDistribution: Send + Sync + 'static {
+    fn ndf(&self, wh: Vec3<Scalar>) -> Scalar;
+    fn lambda(&self, w: Vec3<Scalar>) -> Scalar;
+    // Samples a microfacet normal on the side of wo, proportional to ndf(wh) * cos(theta_h):
+    fn sample_wh(&self, wo: Vec3<Scalar>, u: Vec2<Scalar>) -> Vec3<Scalar>;
+    // Sometimes referred to g2 in other literature:
+    fn g(&self, wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Scalar {
+        1. / (1. + self.lambda(wo) + self.lambda(wi))
+    }
+    // Smith's masking function:
+    fn g1(&self, w: Vec3<Scalar>) -> Scalar {
+        1. / (1. + self.lambda(w))
+    }
+    // The pdf of the microfacet normals `sample_wh` returns:
+    fn pdf(&self, _wo: Vec3<Scalar>, wh: Vec3<Scalar>) -> Scalar {
+        self.ndf(wh) * abs_cos_theta(wh)
+    }
+}
+
+// The Beckmann Distribution
+#[derive(Clone, Copy, Debug)]
+pub struct Beckmann {
+    alpha: Vec2<Scalar>,
+}
+
+impl Beckmann {
+    // As the Beckmann distribution is anisotropic, we need to define the alpha
+    // at the major axis of the elipsoid:
+    pub fn new(alpha: Vec2<Scalar>) -> Self {
+        Beckmann { alpha }
+    }
+}
+
+impl MicrofacetDistribution for Beckmann {
+    fn ndf(&self, wh: Vec3<Scalar>) -> Scalar {
+        let tan2_theta = tan2_theta(wh);
+        // Check if wh "grazes" the surface
+        if tan2_theta.is_infinite() {
+            return 0.;
+        }
+
+        let cos4_theta = cos2_theta(wh) * cos2_theta(wh);
+
+        (-tan2_theta
+            * (cos2_phi(wh) / (self.alpha.x * self.alpha.x)
+                + sin2_phi(wh) / (self.alpha.y * self.alpha.y)))
+            .exp()
+            / (Scalar::PI * self.alpha.x * self.alpha.y * cos4_theta)
+    }
+
+    fn lambda(&self, w: Vec3<Scalar>) -> Scalar {
+        // Polynomial estimation of the actual formula:
+        let abs_tan_theta = tan_theta(w).abs();
+        if abs_tan_theta.is_infinite() {
+            return 0.;
+        }
+        // A new alpha value for the direction w (using alpha_x and alpha_y):
+        let alpha = (cos2_phi(w) * self.alpha.x * self.alpha.x
+            + sin2_phi(w) * self.alpha.y * self.alpha.y)
+            .sqrt();
+        let a = 1. / (alpha * abs_tan_theta);
+        if a >= 1.6 {
+            return 0.;
+        }
+        (1. - 1.259 * a + 0.396 * a * a) / (3.535 * a + 2.181 * a * a)
+    }
+
+    fn sample_wh(&self, wo: Vec3<Scalar>, u: Vec2<Scalar>) -> Vec3<Scalar> {
+        // Invert the cdf of the slopes (1 - u.x is never 0):
+        let log_sample = (1. - u.x).ln();
+        let (phi, tan2_theta) = if self.alpha.x == self.alpha.y {
+            (
+                2. * Scalar::PI * u.y,
+                -self.alpha.x * self.alpha.x * log_sample,
+            )
+        } else {
+            let (phi, inv_alpha2) = sample_phi(self.alpha, u.y);
+            (phi, -log_sample / inv_alpha2)
+        };
+        let wh = spherical_direction(1. / (1. + tan2_theta).sqrt(), phi);
+        if is_in_same_hemisphere(wo, wh) {
+            wh
+        } else {
+            -wh
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TrowbridgeReitz {
+    alpha: Vec2<Scalar>,
+}
+
+impl TrowbridgeReitz {
+    pub fn new(alpha: Vec2<Scalar>) -> Self {
+        TrowbridgeReitz { alpha }
+    }
+}
+
+impl MicrofacetDistribution for TrowbridgeReitz {
+    fn ndf(&self, wh: Vec3<Scalar>) -> Scalar {
+        let tan2_theta = tan2_theta(wh);
+        // Check if wh "grazes" the surface
+        if tan2_theta.is_infinite() {
+            return 0.;
+        }
+        let cos4_theta = cos2_theta(wh) * cos2_theta(wh);
+        let e = (cos2_phi(wh) / (self.alpha.x * self.alpha.x)
+            + sin2_phi(wh) / (self.alpha.y * self.alpha.y))
+            * tan2_theta;
+        1. / (Scalar::PI * self.alpha.x * self.alpha.y * cos4_theta * (1. + e) * (1. + e))
+    }
+
+    fn lambda(&self, w: Vec3<Scalar>) -> Scalar {
+        let abs_tan_theta = tan_theta(w).abs();
+        if abs_tan_theta.is_infinite() {
+            return 0.;
+        }
+        // Calculate the alpha value like we did with Beckmann:
+        let alpha = (cos2_phi(w) * self.alpha.x * self.alpha.x
+            + sin2_phi(w) * self.alpha.y * self.alpha.y)
+            .sqrt();
+        let alpha2_tan2_theta = (alpha * abs_tan_theta) * (alpha * abs_tan_theta);
+        (-1. + (1. + alpha2_tan2_theta).sqrt()) / 2.
+    }
+
+    fn sample_wh(&self, wo: Vec3<Scalar>, u: Vec2<Scalar>) -> Vec3<Scalar> {
+        let (phi, tan2_theta) = if self.alpha.x == self.alpha.y {
+            (
+                2. * Scalar::PI * u.y,
+                self.alpha.x * self.alpha.x * u.x / (1. - u.x),
+            )
+        } else {
+            let (phi, inv_alpha2) = sample_phi(self.alpha, u.y);
+            (phi, u.x / ((1. - u.x) * inv_alpha2))
+        };
+        let wh = spherical_direction(1. / (1. + tan2_theta).sqrt(), phi);
+        if is_in_same_hemisphere(wo, wh) {
+            wh
+        } else {
+            -wh
+        }
+    }
+}
+
+// The microfacet lobe uses the microfacet distribution above
 // and uses the Torrance–Sparrow model.
 
-// struct MicrofacetReflection<F: Fresnel, M: MicrofacetDistribution> {
-//     microfacet: M, // the distribution
-//     fresnel: F,    // the fresnel reflection
-//     r_scale: RGBSpectrum,
-// }
-
-// impl<F: Fresnel, M: MicrofacetDistribution> MicrofacetReflection<F, M> {
-//     pub fn new(r_scale: RGBSpectrum, microfacet: M, fresnel: F) -> Self {
-//         MicrofacetReflection {
-//             microfacet,
-//             fresnel,
-//             r_scale,
-//         }
-//     }
-// }
-
-// impl<F: Fresnel, M: MicrofacetDistribution> BxDF for MicrofacetReflection<F, M> {
-//     const BXDF_TYPE: BxDFType = BxDFType::REFLECTION | BxDFType::GLOSSY;
-
-//     // Evaluate the Torrance-Sparrow BRDF here:
-//     fn f(&self, wo: Vec3<f64>, wi: Vec3<f64>) -> RGBSpectrum {
-//         let cos_theta_o = abs_cos_theta(wo);
-//         let cos_theta_i = abs_cos_theta(wi);
-//         // Evaluate edge cases when grazing the surface:
-//         if cos_theta_i == 0. || cos_theta_o == 0. {
-//             return RGBSpectrum::black();
-//         }
-//         // Calculate the half-angle vector:
-//         let wh = wi + wo;
-//         if wh == Vec3::zero() {
-//             return RGBSpectrum::black();
-//         }
-//         let wh = wh.normalize();
-//         // Evaluate the fresnel value and microfacet distribution:
-//         let f = self.fresnel.eval(wi.dot(wh));
-//         // Number of microfacets that are visible:
-//         let visible = self.microfacet.d(wh) * self.microfacet.g(wo, wi);
-//         // The final result:
-//         (self.r_scale * f)
-//             .scale(visible)
-//             .div_scale(4. * cos_theta_o * cos_theta_i)
-//     }
-// }
+pub struct MicrofacetReflection<F: Fresnel, M: MicrofacetDistribution> {
+    microfacet: M, // the distribution
+    fresnel: F,    // the fresnel reflection
+    r_scale: Color,
+}
+
+impl<F: Fresnel, M: MicrofacetDistribution> MicrofacetReflection<F, M> {
+    const LOBE_TYPE: LobeType =
+        LobeType::from_bits_truncate(LobeType::REFLECTION.bits | LobeType::GLOSSY.bits);
+
+    pub fn new(r_scale: Color, microfacet: M, fresnel: F) -> Self {
+        MicrofacetReflection {
+            microfacet,
+            fresnel,
+            r_scale,
+        }
+    }
+}
+
+impl<F, M> Lobe for MicrofacetReflection<F, M>
+where
+    F: Fresnel + Send + Sync + 'static,
+    M: MicrofacetDistribution,
+{
+    fn contains_type(&self, lobe_type: LobeType) -> bool {
+        Self::LOBE_TYPE.contains(lobe_type)
+    }
+
+    fn get_type(&self) -> LobeType {
+        Self::LOBE_TYPE
+    }
+
+    // Evaluate the Torrance-Sparrow BRDF here:
+    fn eval(&self, wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Color {
+        let cos_theta_o = abs_cos_theta(wo);
+        let cos_theta_i = abs_cos_theta(wi);
+        // Evaluate edge cases when grazing the surface:
+        if cos_theta_i == 0. || cos_theta_o == 0. {
+            return Color::black();
+        }
+        // Calculate the half-angle vector:
+        let wh = wi + wo;
+        if wh == Vec3::zero() {
+            return Color::black();
+        }
+        let wh = wh.normalize();
+        // Evaluate the fresnel value and microfacet distribution:
+        let f = self.fresnel.eval(wi.dot(wh));
+        // Number of microfacets that are visible:
+        let visible = self.microfacet.ndf(wh) * self.microfacet.g(wo, wi);
+        // The final result:
+        (self.r_scale * f)
+            .scale(visible)
+            .div_scale(4. * cos_theta_o * cos_theta_i)
+    }
+
+    fn sample(&self, wo: Vec3<Scalar>, u: Vec2<Scalar>) -> (Color, Vec3<Scalar>, Scalar) {
+        if wo.z == 0. {
+            return (Color::black(), Vec3::zero(), 0.);
+        }
+        // Reflect wo about a sampled microfacet normal:
+        let wh = self.microfacet.sample_wh(wo, u);
+        let wi = pmath::reflect(wo, wh);
+        if !is_in_same_hemisphere(wo, wi) {
+            return (Color::black(), wi, 0.);
+        }
+        (self.eval(wo, wi), wi, self.pdf(wo, wi))
+    }
+
+    fn pdf(&self, wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Scalar {
+        if !is_in_same_hemisphere(wo, wi) {
+            return 0.;
+        }
+        let wh = (wo + wi).normalize();
+        // Change of variables from the microfacet normal to the reflected direction:
+        self.microfacet.pdf(wo, wh) / (4. * wo.dot(wh))
+    }
+}
@@ -1,10 +1,15 @@
+pub mod dielectric;
 pub mod lambertian;
-//pub mod microfacet;
-//pub mod oren_nayar;
-//pub mod specular;
+pub mod measured;
+pub mod microfacet;
+pub mod oren_nayar;
+pub mod rotated;
+pub mod specular;
+#[cfg(any(test, feature = "validation"))]
+pub mod validation;
 
-use crate::geometry::GeomInteraction;
 use crate::spectrum::Color;
+use crate::Scalar;
 use bitflags::bitflags;
 use num_traits::clamp;
 use pmath::sampling;
@@ -26,25 +31,24 @@ bitflags! {
 }
 
 /// This is a trait that represents brdf (reflections) and btdf (transmissions).
-pub trait Lobe {
+pub trait Lobe: Send + Sync + 'static {
     /// Returns whether or not the lobe has these types present.
     /// This will be redundant as hell, but rust does not support fields
     /// in traits.
     fn contains_type(&self, lobe_type: LobeType) -> bool;
     /// Returns the lobe type:
     fn get_type(&self) -> LobeType;
-    /// Evaluates the lobe (wo and wi are in shading space). It also includes an interaction
-    /// in case the lobe needed extra information with regards to it.
-    fn eval(&self, wo: Vec3<f64>, wi: Vec3<f64>, interaction: GeomInteraction) -> Color;
+    /// Returns whether or not every type of the lobe is allowed by the `lobe_type` filter (so that, for
+    /// instance, `LobeType::ALL` matches every lobe).
+    fn matches_type(&self, lobe_type: LobeType) -> bool {
+        lobe_type.contains(self.get_type())
+    }
+    /// Evaluates the lobe (wo and wi are in shading space).
+    fn eval(&self, wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Color;
     /// Sampling the lobe and also works when we have a delta function
     /// (for instance, with perfectly specular surfaces). Note that wo is in shading space.
     /// If the trait isn't implemented, it uses a cosine hemisphere sampling technique.
-    fn sample(
-        &self,
-        wo: Vec3<f64>,
-        u: Vec2<f64>,
-        interaction: GeomInteraction,
-    ) -> (Color, Vec3<f64>, f64) {
+    fn sample(&self, wo: Vec3<Scalar>, u: Vec2<Scalar>) -> (Color, Vec3<Scalar>, Scalar) {
         // If wo.z < 0 then it's not on the side of the normal. Because we are sampling
         // a hemisphere in the shading space, we need to flip around the final z result
         // to make sure it's on the same side as wo:
@@ -64,7 +68,7 @@ pub trait Lobe {
     /// the outgoing directions. Both of which are in shading space and point away from
     /// the surface.
     /// If the trait isn't implemented, it assumes a cosine weighted hemisphere.
-    fn pdf(&self, wo: Vec3<f64>, wi: Vec3<f64>, interaction: GeomInteraction) -> f64 {
+    fn pdf(&self, wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Scalar {
         if is_in_same_hemisphere(wo, wi) {
             sampling::cos_sphere_pdf(abs_cos_theta(wi))
         } else {
@@ -78,31 +82,31 @@ pub trait Lobe {
 
 // Returns whether or not two rays are in the same hemisphere in
 // shading space:
-fn is_in_same_hemisphere(w: Vec3<f64>, wp: Vec3<f64>) -> bool {
+fn is_in_same_hemisphere(w: Vec3<Scalar>, wp: Vec3<Scalar>) -> bool {
     w.z * wp.z > 0.
 }
 
-fn cos_theta(w: Vec3<f64>) -> f64 {
+fn cos_theta(w: Vec3<Scalar>) -> Scalar {
     w.z
 }
 
-fn cos2_theta(w: Vec3<f64>) -> f64 {
+fn cos2_theta(w: Vec3<Scalar>) -> Scalar {
     w.z * w.z
 }
 
-fn abs_cos_theta(w: Vec3<f64>) -> f64 {
+fn abs_cos_theta(w: Vec3<Scalar>) -> Scalar {
     w.z.abs()
 }
 
-fn sin2_theta(w: Vec3<f64>) -> f64 {
+fn sin2_theta(w: Vec3<Scalar>) -> Scalar {
     (1. - cos2_theta(w)).max(0.)
 }
 
-fn sin_theta(w: Vec3<f64>) -> f64 {
+fn sin_theta(w: Vec3<Scalar>) -> Scalar {
     sin2_theta(w).sqrt()
 }
 
-fn cos_phi(w: Vec3<f64>) -> f64 {
+fn cos_phi(w: Vec3<Scalar>) -> Scalar {
     let sin_theta = sin_theta(w);
     if sin_theta == 0. {
         1.
@@ -111,7 +115,7 @@ fn cos_phi(w: Vec3<f64>) -> f64 {
     }
 }
 
-fn sin_phi(w: Vec3<f64>) -> f64 {
+fn sin_phi(w: Vec3<Scalar>) -> Scalar {
     let sin_theta = sin_theta(w);
     if sin_theta == 0. {
         0.
@@ -120,27 +124,27 @@ fn sin_phi(w: Vec3<f64>) -> f64 {
     }
 }
 
-fn cos2_phi(w: Vec3<f64>) -> f64 {
+fn cos2_phi(w: Vec3<Scalar>) -> Scalar {
     let cos_phi = cos_phi(w);
     cos_phi * cos_phi
 }
 
-fn sin2_phi(w: Vec3<f64>) -> f64 {
+fn sin2_phi(w: Vec3<Scalar>) -> Scalar {
     let sin_phi = sin_phi(w);
     sin_phi * sin_phi
 }
 
-fn cos_dphi(w0: Vec3<f64>, w1: Vec3<f64>) -> f64 {
+fn cos_dphi(w0: Vec3<Scalar>, w1: Vec3<Scalar>) -> Scalar {
     let w0 = Vec2::from_vec3(w0);
     let w1 = Vec2::from_vec3(w1);
     let v = w0.dot(w1) / (w0.length2() * w1.length2()).sqrt();
     clamp(v, -1., 1.)
 }
 
-fn tan_theta(w: Vec3<f64>) -> f64 {
+fn tan_theta(w: Vec3<Scalar>) -> Scalar {
     sin_theta(w) / cos_theta(w)
 }
 
-fn tan2_theta(w: Vec3<f64>) -> f64 {
+fn tan2_theta(w: Vec3<Scalar>) -> Scalar {
     sin2_theta(w) / cos2_theta(w)
 }
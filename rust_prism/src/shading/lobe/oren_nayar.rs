@@ -1,22 +1,24 @@
-use crate::math::numbers::Float;
-use crate::math::vector::Vec3;
 use crate::shading::lobe::{abs_cos_theta, cos_phi, sin_phi, sin_theta, Lobe, LobeType};
-use crate::spectrum::RGBSpectrum;
+use crate::spectrum::Color;
+use crate::Scalar;
+use pmath::numbers::Float;
+use pmath::vector::Vec3;
 
 pub struct OrenNayar {
-    r_scale: RGBSpectrum,
+    r_scale: Color,
     // Used by the OrenNayar formula:
-    a: f64,
-    b: f64,
+    a: Scalar,
+    b: Scalar,
 }
 
 impl OrenNayar {
-    const LOBE_TYPE: LobeType = LobeType::REFLECTION | LobeType::DIFFUSE;
+    const LOBE_TYPE: LobeType =
+        LobeType::from_bits_truncate(LobeType::REFLECTION.bits | LobeType::DIFFUSE.bits);
 
     // r_scale: how much we scale the result by (abledo)
     // sigma: the standard deviation of the distribution of roughness.
     //        In other words, the roughness. If it's zero, it's basically lambertian
-    pub fn new(r_scale: RGBSpectrum, sigma: f64) -> Self {
+    pub fn new(r_scale: Color, sigma: Scalar) -> Self {
         let sigma = sigma.to_radians();
         let sigma2 = sigma * sigma;
         OrenNayar {
@@ -36,7 +38,7 @@ impl Lobe for OrenNayar {
         Self::LOBE_TYPE
     }
 
-    fn eval(&self, wo: Vec3<f64>, wi: Vec3<f64>) -> RGBSpectrum {
+    fn eval(&self, wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Color {
         let sin_theta_o = sin_theta(wo);
         let sin_theta_i = sin_theta(wi);
 
@@ -54,7 +56,7 @@ impl Lobe for OrenNayar {
             (sin_theta_i, sin_theta_o / abs_cos_theta(wo))
         };
 
-        let scaling_factor = f64::INV_PI * (self.a + self.b * max_cos * sin_alpha * tan_beta);
+        let scaling_factor = Scalar::INV_PI * (self.a + self.b * max_cos * sin_alpha * tan_beta);
         self.r_scale.scale(scaling_factor)
     }
 }
@@ -0,0 +1,225 @@
+use crate::shading::lobe::{Lobe, LobeType};
+use crate::spectrum::Color;
+use crate::Scalar;
+use pmath::vector::{Vec2, Vec3};
+
+//
+// Rotated Lobe
+//
+// Rotates the tangent frame of another lobe around the normal. This is what controls the direction of
+// anisotropic lobes (like brushed metal). Rotations preserve solid angle, so the pdf stays consistent
+// with sampling.
+
+pub struct RotatedLobe<L: Lobe> {
+    lobe: L,
+    cos_rot: Scalar,
+    sin_rot: Scalar,
+}
+
+impl<L: Lobe> RotatedLobe<L> {
+    /// Rotates the frame of `lobe` by `rotation` (in radians, counter clockwise around the normal).
+    pub fn new(lobe: L, rotation: Scalar) -> Self {
+        let (sin_rot, cos_rot) = rotation.sin_cos();
+        RotatedLobe {
+            lobe,
+            cos_rot,
+            sin_rot,
+        }
+    }
+
+    /// Transforms a direction from the shading space to the rotated frame of the lobe.
+    fn to_lobe(&self, w: Vec3<Scalar>) -> Vec3<Scalar> {
+        Vec3 {
+            x: w.x * self.cos_rot + w.y * self.sin_rot,
+            y: -w.x * self.sin_rot + w.y * self.cos_rot,
+            z: w.z,
+        }
+    }
+
+    /// Transforms a direction from the rotated frame of the lobe back to the shading space.
+    fn from_lobe(&self, w: Vec3<Scalar>) -> Vec3<Scalar> {
+        Vec3 {
+            x: w.x * self.cos_rot - w.y * self.sin_rot,
+            y: w.x * self.sin_rot + w.y * self.cos_rot,
+            z: w.z,
+        }
+    }
+}
+
+impl<L: Lobe> Lobe for RotatedLobe<L> {
+    fn contains_type(&self, lobe_type: LobeType) -> bool {
+        self.lobe.contains_type(lobe_type)
+    }
+
+    fn get_type(&self) -> LobeType {
+        self.lobe.get_type()
+    }
+
+    fn eval(&self, wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Color {
+        self.lobe.eval(self.to_lobe(wo), self.to_lobe(wi))
+    }
+
+    fn sample(&self, wo: Vec3<Scalar>, u: Vec2<Scalar>) -> (Color, Vec3<Scalar>, Scalar) {
+        let (color, wi, pdf) = self.lobe.sample(self.to_lobe(wo), u);
+        (color, self.from_lobe(wi), pdf)
+    }
+
+    fn pdf(&self, wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Scalar {
+        self.lobe.pdf(self.to_lobe(wo), self.to_lobe(wi))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pmath::numbers::Float;
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg32;
+
+    /// A cosine weighted lobe that prefers azimuths close to the x axis: directions within 45 degrees of
+    /// it are 4 times as likely as the rest. It's sampled exactly (the color is the pdf).
+    struct Stretched;
+
+    impl Stretched {
+        const X_PROB: Scalar = 0.8;
+
+        /// The density of the azimuth.
+        fn phi_pdf(phi: Scalar) -> Scalar {
+            if phi.cos().abs() >= phi.sin().abs() {
+                Self::X_PROB / Scalar::PI
+            } else {
+                (1.0 - Self::X_PROB) / Scalar::PI
+            }
+        }
+    }
+
+    impl Lobe for Stretched {
+        fn contains_type(&self, lobe_type: LobeType) -> bool {
+            (LobeType::REFLECTION | LobeType::GLOSSY).contains(lobe_type)
+        }
+
+        fn get_type(&self) -> LobeType {
+            LobeType::REFLECTION | LobeType::GLOSSY
+        }
+
+        fn eval(&self, wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Color {
+            let pdf = self.pdf(wo, wi);
+            Color {
+                r: pdf,
+                g: pdf,
+                b: pdf,
+            }
+        }
+
+        fn sample(&self, wo: Vec3<Scalar>, u: Vec2<Scalar>) -> (Color, Vec3<Scalar>, Scalar) {
+            // Both wedges along an axis together cover half of the azimuths:
+            let (v, start) = if u.y < Self::X_PROB {
+                (u.y / Self::X_PROB, -Scalar::PI / 4.0)
+            } else {
+                (
+                    (u.y - Self::X_PROB) / (1.0 - Self::X_PROB),
+                    Scalar::PI / 4.0,
+                )
+            };
+            let t = v * Scalar::PI;
+            let phi = if t < Scalar::PI_OVER_2 {
+                start + t
+            } else {
+                start + Scalar::PI + (t - Scalar::PI_OVER_2)
+            };
+            let sin_theta = u.x.sqrt();
+            let wi = Vec3 {
+                x: sin_theta * phi.cos(),
+                y: sin_theta * phi.sin(),
+                z: (1.0 - u.x).max(0.0).sqrt(),
+            };
+            (self.eval(wo, wi), wi, self.pdf(wo, wi))
+        }
+
+        fn pdf(&self, _wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Scalar {
+            if wi.z <= 0.0 {
+                return 0.0;
+            }
+            2.0 * wi.z * Self::phi_pdf(wi.y.atan2(wi.x))
+        }
+    }
+
+    #[test]
+    fn rotated_samples_follow_the_pdf() {
+        const THETA_BINS: usize = 8;
+        const PHI_BINS: usize = 16;
+        const NUM_SAMPLES: usize = 100_000;
+        let wo = Vec3 {
+            x: 0.3,
+            y: -0.2,
+            z: 0.9,
+        }
+        .normalize();
+        let mut rng = Pcg32::seed_from_u64(3);
+
+        for &rotation in &[0.0, Scalar::PI_OVER_2, 1.0] {
+            let lobe = RotatedLobe::new(Stretched, rotation);
+            let mut counts = vec![0.0; THETA_BINS * PHI_BINS];
+            let (mut along_x, mut along_y) = (0.0, 0.0);
+            for _ in 0..NUM_SAMPLES {
+                let u = Vec2 {
+                    x: rng.gen(),
+                    y: rng.gen(),
+                };
+                let (color, wi, pdf) = lobe.sample(wo, u);
+                assert!((pdf - lobe.pdf(wo, wi)).abs() < 1e-9 * pdf);
+                assert!((color.r - lobe.eval(wo, wi).r).abs() < 1e-9 * pdf);
+                along_x += wi.x.abs();
+                along_y += wi.y.abs();
+
+                let theta = wi.z.max(-1.0).min(1.0).acos();
+                let phi = wi.y.atan2(wi.x).rem_euclid(2.0 * Scalar::PI);
+                let t = ((theta / Scalar::PI_OVER_2 * THETA_BINS as Scalar) as usize)
+                    .min(THETA_BINS - 1);
+                let p =
+                    ((phi / (2.0 * Scalar::PI) * PHI_BINS as Scalar) as usize).min(PHI_BINS - 1);
+                counts[t * PHI_BINS + p] += 1.0;
+            }
+
+            // Rotating by 90 degrees stretches the lobe along y instead of x:
+            if rotation == 0.0 {
+                assert!(along_x > 1.5 * along_y);
+            } else if rotation == Scalar::PI_OVER_2 {
+                assert!(along_y > 1.5 * along_x);
+            }
+
+            // The expected number of samples in every bin: the cosine weighted theta integrates to the
+            // difference of sin^2, and the azimuth is integrated numerically:
+            let mut chi2 = 0.0;
+            for t in 0..THETA_BINS {
+                let sin2 = |t: usize| {
+                    let theta = (t as Scalar) / (THETA_BINS as Scalar) * Scalar::PI_OVER_2;
+                    theta.sin() * theta.sin()
+                };
+                let theta_prob = sin2(t + 1) - sin2(t);
+                for p in 0..PHI_BINS {
+                    const STEPS: usize = 64;
+                    let width = 2.0 * Scalar::PI / (PHI_BINS as Scalar);
+                    let phi_prob: Scalar = (0..STEPS)
+                        .map(|i| {
+                            let phi =
+                                (p as Scalar + (i as Scalar + 0.5) / (STEPS as Scalar)) * width;
+                            Stretched::phi_pdf(phi - rotation) * width / (STEPS as Scalar)
+                        })
+                        .sum();
+                    let expected = theta_prob * phi_prob * (NUM_SAMPLES as Scalar);
+                    let observed = counts[t * PHI_BINS + p];
+                    chi2 += (observed - expected) * (observed - expected) / expected;
+                }
+            }
+            // Far in the tail of the chi-square distribution with 127 degrees of freedom:
+            let dof = (THETA_BINS * PHI_BINS - 1) as Scalar;
+            assert!(
+                chi2 < dof + 5.0 * (2.0 * dof).sqrt(),
+                "{} {}",
+                rotation,
+                chi2
+            );
+        }
+    }
+}
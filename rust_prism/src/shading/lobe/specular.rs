@@ -1,13 +1,14 @@
-use crate::math::util::{align, refract};
-use crate::math::vector::{Vec2, Vec3};
+use crate::shading::ior::IorSpectrum;
+use crate::shading::lobe::dielectric::fr_dielectric;
 use crate::shading::lobe::{abs_cos_theta, cos_theta, Lobe, LobeType};
-use crate::spectrum::RGBSpectrum;
-
+use crate::spectrum::Color;
+use crate::Scalar;
 use num_traits::clamp;
+use pmath::vector::{Vec2, Vec3};
 
 // Computes the fresnel reflectance given the cosine of the incident angle.
 pub trait Fresnel {
-    fn eval(&self, cos_theta_i: f64) -> RGBSpectrum;
+    fn eval(&self, cos_theta_i: Scalar) -> Color;
 }
 
 //
@@ -18,19 +19,25 @@ pub trait Fresnel {
 // They include: glass, water, etc.
 #[derive(Clone, Copy)]
 pub struct Dielectric {
-    eta_i: f64,
-    eta_t: f64,
+    eta_i: Scalar,
+    eta_t: Scalar,
 }
 
 impl Dielectric {
-    pub fn new(eta_i: f64, eta_t: f64) -> Self {
+    pub fn new(eta_i: Scalar, eta_t: Scalar) -> Self {
         Dielectric { eta_i, eta_t }
     }
+
+    // Evaluates a dispersive index of refraction (for the transmitted medium) at the wavelength of the
+    // path (in nanometers).
+    pub fn from_ior(eta_i: Scalar, ior: IorSpectrum, wavelength: Scalar) -> Self {
+        Dielectric::new(eta_i, ior.eta(wavelength))
+    }
 }
 
 impl Fresnel for Dielectric {
-    fn eval(&self, cos_theta_i: f64) -> RGBSpectrum {
-        RGBSpectrum::from_scalar(fr_dielectric(cos_theta_i, self.eta_i, self.eta_t))
+    fn eval(&self, cos_theta_i: Scalar) -> Color {
+        Color::from_scalar(fr_dielectric(cos_theta_i, self.eta_i, self.eta_t))
     }
 }
 
@@ -42,19 +49,19 @@ impl Fresnel for Dielectric {
 // They have complex indices of refraction: n + ki, with k being the "absorbtion coefficient".
 #[derive(Clone, Copy)]
 pub struct Conductor {
-    eta_i: RGBSpectrum,
-    eta_t: RGBSpectrum,
-    k: RGBSpectrum,
+    eta_i: Color,
+    eta_t: Color,
+    k: Color,
 }
 
 impl Conductor {
-    pub fn new(eta_i: RGBSpectrum, eta_t: RGBSpectrum, k: RGBSpectrum) -> Self {
+    pub fn new(eta_i: Color, eta_t: Color, k: Color) -> Self {
         Conductor { eta_i, eta_t, k }
     }
 }
 
 impl Fresnel for Conductor {
-    fn eval(&self, cos_theta_i: f64) -> RGBSpectrum {
+    fn eval(&self, cos_theta_i: Scalar) -> Color {
         fr_conductor(cos_theta_i.abs(), self.eta_i, self.eta_t, self.k)
     }
 }
@@ -75,66 +82,18 @@ impl PerfectMirror {
 
 impl Fresnel for PerfectMirror {
     // This will always return 1. so it perfectly reflects all light:
-    fn eval(&self, cos_theta_i: f64) -> RGBSpectrum {
-        RGBSpectrum::from_scalar(1.)
+    fn eval(&self, _cos_theta_i: Scalar) -> Color {
+        Color::from_scalar(1.)
     }
 }
 
-// Calculates the Fresnel Reflectance of a dielectric:
-//
-// As we are assuming the light isn't polarized, we take the average
-// of the Fresnel reflectance of the light that is parallel polarized (s-polarized)
-// and perpendicular polarized (p-polarized):
-//
-// cos_theta_I: the cosine of the incident angle
-// eta_i: index of refraction of the incident medium (whatever material we are coming from)
-// eta_t: index of refraction of the transmitted medium (whatever material we are entering)
-pub fn fr_dielectric(cos_theta_i: f64, eta_i: f64, eta_t: f64) -> f64 {
-    let cos_theta_i = clamp(cos_theta_i, -1., 1.);
-    let cos_theta_t = 5.;
-
-    // Check if we are entering (the ray is outside of, so the cosine is positive):
-    // If we are not entering, then we need to make sure we update eta_i and eta_t. In
-    // other words, we "flip" our perspective:
-    let (cos_theta_i, eta_i, eta_t) = if cos_theta_i < 0. {
-        (cos_theta_i.abs(), eta_t, eta_i) // swap them and make cos_theta_i positive
-    } else {
-        (cos_theta_i, eta_i, eta_t) // they remain unchanged
-    };
-
-    // This is just the identity: cos^2 + sin^2 = 1
-    let sin_theta_i = (1. - cos_theta_i * cos_theta_i).max(0.).sqrt();
-    // Using Snell's law to find the sin of the transmitted angle
-    let sin_theta_t = eta_i / eta_t * sin_theta_i;
-    // Check for total internal reflection:
-    if sin_theta_t > 1. {
-        return 1.;
-    }
-    // Once again, apply the identity:
-    let cos_theta_t = (1. - sin_theta_t * sin_theta_t).max(0.).sqrt();
-
-    // Apply Fresnel's equations for reflectance:
-    let refl_parl = ((eta_t * cos_theta_i) - (eta_i * cos_theta_t))
-        / ((eta_t * cos_theta_i) + (eta_i * cos_theta_t));
-    let refl_perp = ((eta_i * cos_theta_i) - (eta_t * cos_theta_t))
-        / ((eta_i * cos_theta_i) + (eta_t * cos_theta_t));
-
-    // Average the result as we are dealing with unpolarized light
-    (refl_parl * refl_parl + refl_perp * refl_perp) / 2.
-}
-
 // This calculates the fresnel reflectance given a conductor. This means
 // we need to provide an absorbtion factor. If k == 0, then this acts like
 // fr_dielectric.
 //
 // NOTE: The cos_theta_i value is measured with respect to the normal being on the
 // same side as w_i (incident). That means we don't do the flip like above.
-pub fn fr_conductor(
-    cos_theta_i: f64,
-    eta_i: RGBSpectrum,
-    eta_t: RGBSpectrum,
-    k: RGBSpectrum,
-) -> RGBSpectrum {
+pub fn fr_conductor(cos_theta_i: Scalar, eta_i: Color, eta_t: Color, k: Color) -> Color {
     let cos_theta_i = clamp(cos_theta_i, -1., 1.);
     let eta = eta_t / eta_i;
     let eta_k = k / eta_i;
@@ -146,14 +105,14 @@ pub fn fr_conductor(
     let eta2 = eta * eta;
     let eta_k2 = eta_k * eta_k;
 
-    let t0 = eta2 - eta_k2 - RGBSpectrum::from_scalar(sin2_theta_i);
+    let t0 = eta2 - eta_k2 - Color::from_scalar(sin2_theta_i);
     let a2_plus_b2 = (t0 * t0 + (eta2 * eta_k2).scale(4.)).sqrt();
-    let t1 = a2_plus_b2 + RGBSpectrum::from_scalar(cos2_theta_i);
+    let t1 = a2_plus_b2 + Color::from_scalar(cos2_theta_i);
     let a = (a2_plus_b2 + t0).scale(0.5).sqrt();
     let t2 = a.scale(cos_theta_i * 2.);
     let rs = (t1 - t2) / (t1 + t2);
 
-    let t3 = a2_plus_b2.scale(cos2_theta_i) + RGBSpectrum::from_scalar(sin2_theta_i * sin2_theta_i);
+    let t3 = a2_plus_b2.scale(cos2_theta_i) + Color::from_scalar(sin2_theta_i * sin2_theta_i);
     let t4 = t2.scale(sin2_theta_i);
     let rp = rs * (t3 - t4) / (t3 + t4);
 
@@ -165,22 +124,23 @@ pub fn fr_conductor(
 //
 // Defines how light reflects from an object:
 
-struct SpecularReflection<F: Fresnel> {
+pub struct SpecularReflection<F: Fresnel> {
     // Defines the type of reflection:
     fresnel: F,
     // Scales the reflected color:
-    r_scale: RGBSpectrum,
+    r_scale: Color,
 }
 
 impl<F: Fresnel> SpecularReflection<F> {
-    const LOBE_TYPE: LobeType = LobeType::REFLECTION | LobeType::SPECULAR;
+    const LOBE_TYPE: LobeType =
+        LobeType::from_bits_truncate(LobeType::REFLECTION.bits | LobeType::SPECULAR.bits);
 
-    pub fn new(r_scale: RGBSpectrum, fresnel: F) -> Self {
+    pub fn new(r_scale: Color, fresnel: F) -> Self {
         SpecularReflection { fresnel, r_scale }
     }
 }
 
-impl<F: Fresnel> Lobe for SpecularReflection<F> {
+impl<F: Fresnel + Send + Sync + 'static> Lobe for SpecularReflection<F> {
     fn contains_type(&self, lobe_type: LobeType) -> bool {
         Self::LOBE_TYPE.contains(lobe_type)
     }
@@ -189,18 +149,18 @@ impl<F: Fresnel> Lobe for SpecularReflection<F> {
         Self::LOBE_TYPE
     }
 
-    fn eval(&self, wo: Vec3<f64>, wi: Vec3<f64>) -> RGBSpectrum {
+    fn eval(&self, _wo: Vec3<Scalar>, _wi: Vec3<Scalar>) -> Color {
         // This always returns black (even, if by some miracle, we hit the right direction
         // straight on)
-        RGBSpectrum::black()
+        Color::black()
     }
 
-    fn pdf(&self, wo: Vec3<f64>, wi: Vec3<f64>) -> f64 {
+    fn pdf(&self, _wo: Vec3<Scalar>, _wi: Vec3<Scalar>) -> Scalar {
         // Just like above, this will always return 0 as we won't hit the correct angle
         0.
     }
 
-    fn sample(&self, wo: Vec3<f64>, sample: Vec2<f64>) -> (RGBSpectrum, Vec3<f64>, f64) {
+    fn sample(&self, wo: Vec3<Scalar>, _u: Vec2<Scalar>) -> (Color, Vec3<Scalar>, Scalar) {
         // This is basically calling reflect(wo, n) with n = (0, 0, 1)
         let wi = Vec3 {
             x: -wo.x,
@@ -226,19 +186,20 @@ pub struct SpecularTransmission {
     // Conductors don't transmit light, so we need a dielectric:
     fresnel: Dielectric,
     // Scales the reflected color:
-    t_scale: RGBSpectrum,
-    eta_above: f64,
-    eta_below: f64,
+    t_scale: Color,
+    eta_above: Scalar,
+    eta_below: Scalar,
 }
 
 impl SpecularTransmission {
-    const LOBE_TYPE: LobeType = LobeType::TRANSMISSION | LobeType::SPECULAR;
+    const LOBE_TYPE: LobeType =
+        LobeType::from_bits_truncate(LobeType::TRANSMISSION.bits | LobeType::SPECULAR.bits);
 
     // In pbrt we define a transport mode, but we aren't using bidirectional techniques this case,
     // so we can ignore that.
     // eta_above: index of refraction above the surface we are intersecting (based on normal)
     // eta_below: index of refraction below the surface we are interescting (based on normal)
-    pub fn new(t_scale: RGBSpectrum, eta_above: f64, eta_below: f64) -> Self {
+    pub fn new(t_scale: Color, eta_above: Scalar, eta_below: Scalar) -> Self {
         SpecularTransmission {
             fresnel: Dielectric::new(eta_above, eta_below),
             t_scale,
@@ -246,6 +207,17 @@ impl SpecularTransmission {
             eta_below,
         }
     }
+
+    // Same as `new`, but the index of refraction below the surface depends on the wavelength of the
+    // path (in nanometers).
+    pub fn new_dispersive(
+        t_scale: Color,
+        eta_above: Scalar,
+        ior_below: IorSpectrum,
+        wavelength: Scalar,
+    ) -> Self {
+        Self::new(t_scale, eta_above, ior_below.eta(wavelength))
+    }
 }
 
 impl Lobe for SpecularTransmission {
@@ -257,17 +229,17 @@ impl Lobe for SpecularTransmission {
         Self::LOBE_TYPE
     }
 
-    fn eval(&self, wo: Vec3<f64>, wi: Vec3<f64>) -> RGBSpectrum {
+    fn eval(&self, _wo: Vec3<Scalar>, _wi: Vec3<Scalar>) -> Color {
         // See SpecularReflection:
-        RGBSpectrum::black()
+        Color::black()
     }
 
-    fn pdf(&self, wo: Vec3<f64>, wi: Vec3<f64>) -> f64 {
+    fn pdf(&self, _wo: Vec3<Scalar>, _wi: Vec3<Scalar>) -> Scalar {
         // See SpecularReflection:
         0.
     }
 
-    fn sample(&self, wo: Vec3<f64>, sample: Vec2<f64>) -> (RGBSpectrum, Vec3<f64>, f64) {
+    fn sample(&self, wo: Vec3<Scalar>, _u: Vec2<Scalar>) -> (Color, Vec3<Scalar>, Scalar) {
         // Pick the correct eta_i and eta_t depending on the directin of w_o compared to the
         // normal:
         let (eta_i, eta_t) = if cos_theta(wo) > 0. {
@@ -277,26 +249,23 @@ impl Lobe for SpecularTransmission {
         };
 
         // We need w_i (the outgoing ray). Just call refract for this (similar to glsl's refract function).
-        let wi = match refract(
+        let n = pmath::align(
             wo,
-            align(
-                Vec3 {
-                    x: 0.,
-                    y: 0.,
-                    z: 1.,
-                },
-                wo,
-            ),
-            eta_i / eta_t,
-        ) {
+            Vec3 {
+                x: 0.,
+                y: 0.,
+                z: 1.,
+            },
+        );
+        let wi = match pmath::refract(wo, n, eta_i / eta_t) {
             Some(w) => w,
-            None => return (RGBSpectrum::black(), Vec3::zero(), 1.),
+            // Total internal reflection, nothing is transmitted:
+            None => return (Color::black(), Vec3::zero(), 0.),
         };
 
         let pdf = 1.;
-        let spectrum = (self.t_scale
-            * (RGBSpectrum::from_scalar(1.) - self.fresnel.eval(cos_theta(wi))))
-        .scale((eta_i * eta_i) / (eta_t * eta_t * abs_cos_theta(wi)));
+        let spectrum = (self.t_scale * (Color::from_scalar(1.) - self.fresnel.eval(cos_theta(wi))))
+            .scale((eta_i * eta_i) / (eta_t * eta_t * abs_cos_theta(wi)));
         (spectrum, wi, pdf)
     }
 }
@@ -311,15 +280,17 @@ pub struct SpecularFresnal {
     // Again, we only focus on the dielectric case (conductors have no transmission):
     fresnel: Dielectric,
     // Scales for transmission:
-    t_scale: RGBSpectrum,
+    t_scale: Color,
     // Scales for reflection:
-    r_scale: RGBSpectrum,
-    eta_above: f64,
-    eta_below: f64,
+    r_scale: Color,
+    eta_above: Scalar,
+    eta_below: Scalar,
 }
 
 impl SpecularFresnal {
-    const LOBE_TYPE: LobeType = LobeType::REFLECTION | LobeType::TRANSMISSION | LobeType::SPECULAR;
+    const LOBE_TYPE: LobeType = LobeType::from_bits_truncate(
+        LobeType::REFLECTION.bits | LobeType::TRANSMISSION.bits | LobeType::SPECULAR.bits,
+    );
 
     // In pbrt we define a transport mode, but we aren't using bidirectional techniques this case,
     // so we can ignore that.
@@ -327,7 +298,7 @@ impl SpecularFresnal {
     // trans_scale: the scaling factor for the transmitted portion
     // eta_above: index of refraction above the surface we are intersecting (based on normal)
     // eta_below: index of refraction below the surface we are interescting (based on normal)
-    pub fn new(t_scale: RGBSpectrum, r_scale: RGBSpectrum, eta_above: f64, eta_below: f64) -> Self {
+    pub fn new(t_scale: Color, r_scale: Color, eta_above: Scalar, eta_below: Scalar) -> Self {
         SpecularFresnal {
             fresnel: Dielectric::new(eta_above, eta_below),
             t_scale,
@@ -336,6 +307,18 @@ impl SpecularFresnal {
             eta_below,
         }
     }
+
+    // Same as `new`, but the index of refraction below the surface depends on the wavelength of the
+    // path (in nanometers).
+    pub fn new_dispersive(
+        t_scale: Color,
+        r_scale: Color,
+        eta_above: Scalar,
+        ior_below: IorSpectrum,
+        wavelength: Scalar,
+    ) -> Self {
+        Self::new(t_scale, r_scale, eta_above, ior_below.eta(wavelength))
+    }
 }
 
 impl Lobe for SpecularFresnal {
@@ -347,13 +330,53 @@ impl Lobe for SpecularFresnal {
         Self::LOBE_TYPE
     }
 
-    fn eval(&self, wo: Vec3<f64>, wi: Vec3<f64>) -> RGBSpectrum {
+    fn eval(&self, _wo: Vec3<Scalar>, _wi: Vec3<Scalar>) -> Color {
         // See SpecularReflection:
-        RGBSpectrum::black()
+        Color::black()
     }
 
-    fn pdf(&self, wo: Vec3<f64>, wi: Vec3<f64>) -> f64 {
+    fn pdf(&self, _wo: Vec3<Scalar>, _wi: Vec3<Scalar>) -> Scalar {
         // See SpecularReflection:
         0.
     }
+
+    fn sample(&self, wo: Vec3<Scalar>, u: Vec2<Scalar>) -> (Color, Vec3<Scalar>, Scalar) {
+        // Reflect or refract with the probability given by the fresnel reflectance:
+        let reflectance = self.fresnel.eval(cos_theta(wo)).r;
+        if u.x < reflectance {
+            let wi = Vec3 {
+                x: -wo.x,
+                y: -wo.y,
+                z: wo.z,
+            };
+            let spectrum = self.r_scale.scale(reflectance / abs_cos_theta(wi));
+            return (spectrum, wi, reflectance);
+        }
+
+        // See SpecularTransmission:
+        let (eta_i, eta_t) = if cos_theta(wo) > 0. {
+            (self.eta_above, self.eta_below)
+        } else {
+            (self.eta_below, self.eta_above)
+        };
+        let n = pmath::align(
+            wo,
+            Vec3 {
+                x: 0.,
+                y: 0.,
+                z: 1.,
+            },
+        );
+        match pmath::refract(wo, n, eta_i / eta_t) {
+            Some(wi) => {
+                let transmittance = 1. - reflectance;
+                let spectrum = self
+                    .t_scale
+                    .scale(transmittance * (eta_i * eta_i) / (eta_t * eta_t * abs_cos_theta(wi)));
+                (spectrum, wi, transmittance)
+            }
+            // Total internal reflection (in which case the reflectance is 1 and this can't happen):
+            None => (Color::black(), Vec3::zero(), 0.),
+        }
+    }
 }
@@ -0,0 +1,373 @@
+//! Monte Carlo checks of the sampling routines of a lobe (the counterpart of `light::validation`). Mixing
+//! up the space a direction is in or the measure a pdf is in rarely produces an obviously wrong image,
+//! so these are worth running for every new lobe.
+
+use crate::light::validation::Estimate;
+use crate::shading::lobe::{abs_cos_theta, Lobe, LobeType};
+use crate::spectrum::Color;
+use crate::Scalar;
+use pmath::sampling;
+use pmath::vector::Vec2;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg32;
+use simple_error::{bail, SimpleResult};
+
+/// The number of random outgoing directions the pdf is integrated for.
+const NUM_DIRECTIONS: usize = 16;
+/// The relative difference allowed between values that should be the same (up to round off).
+const EVAL_TOLERANCE: Scalar = 1e-3;
+/// How many standard errors (plus a small relative error) a Monte Carlo estimate may be off by.
+const NUM_STD_ERRORS: Scalar = 5.0;
+const REL_TOLERANCE: Scalar = 0.01;
+
+/// The estimates computed by `validate_lobe` (these are also useful when a check fails).
+#[derive(Clone, Copy, Debug)]
+pub struct LobeReport {
+    /// The largest relative difference between `eval(wo, wi)` and `eval(wi, wo)`.
+    pub max_reciprocity_error: Scalar,
+    /// The hemispherical-hemispherical reflectance (the fraction of the light arriving uniformly from
+    /// every direction that is scattered).
+    pub rho_hh: Color,
+    /// The integral of `pdf` over every direction, summed over the outgoing directions.
+    pub pdf_integral: Scalar,
+    /// What `pdf_integral` should be: the fraction of samples with a non-zero pdf, summed over the
+    /// outgoing directions.
+    pub pdf_mass: Scalar,
+    /// The largest relative difference between the color and pdf returned by `sample` and `eval` and
+    /// `pdf`.
+    pub max_sample_error: Scalar,
+}
+
+/// Returns the largest relative difference between the channels of two colors.
+fn rel_error(a: Color, b: Color) -> Scalar {
+    (0..3)
+        .map(|i| {
+            let scale = a[i].abs().max(b[i].abs());
+            if scale == 0.0 {
+                0.0
+            } else {
+                (a[i] - b[i]).abs() / scale
+            }
+        })
+        .fold(0.0, Scalar::max)
+}
+
+/// Checks, using `num_samples` samples, that:
+/// 1. the lobe is reciprocal: `eval(wo, wi)` matches `eval(wi, wo)` (skipped for transmission, which
+///    isn't reciprocal when the indices of refraction differ),
+/// 2. the lobe doesn't create energy: the hemispherical-hemispherical reflectance is at most 1,
+/// 3. `pdf` integrates to the fraction of `sample` calls that return a non-zero pdf (so to 1 if every
+///    sampled direction is valid),
+/// 4. the color and pdf `sample` returns match `eval` and `pdf` in the sampled direction.
+///
+/// Specular lobes can't be evaluated, so only the second check applies to them. The directions are in
+/// shading space (the outgoing directions are above the surface).
+pub fn validate_lobe(lobe: &dyn Lobe, num_samples: usize, seed: u64) -> SimpleResult<LobeReport> {
+    if num_samples == 0 {
+        bail!("Can't validate a lobe without any samples");
+    }
+    let mut rng = Pcg32::seed_from_u64(seed);
+    let mut rand_vec2 = || Vec2 {
+        x: rng.gen::<Scalar>(),
+        y: rng.gen::<Scalar>(),
+    };
+
+    let specular = lobe.contains_type(LobeType::SPECULAR);
+    let reciprocal = !lobe.contains_type(LobeType::TRANSMISSION);
+
+    let mut report = LobeReport {
+        max_reciprocity_error: 0.0,
+        rho_hh: Color::black(),
+        pdf_integral: 0.0,
+        pdf_mass: 0.0,
+        max_sample_error: 0.0,
+    };
+
+    // The outgoing directions are cosine weighted, so that the average of the directional albedos is
+    // the hemispherical-hemispherical reflectance:
+    let mut rho_hh = [Estimate::default(); 3];
+    for _ in 0..num_samples {
+        let wo = sampling::cos_sample_hemisphere(rand_vec2());
+        let (color, wi, pdf) = lobe.sample(wo, rand_vec2());
+        if pdf <= 0.0 {
+            rho_hh.iter_mut().for_each(|estimate| estimate.add(0.0));
+            continue;
+        }
+        let weight = color.scale(abs_cos_theta(wi) / pdf);
+        for (i, estimate) in rho_hh.iter_mut().enumerate() {
+            estimate.add(weight[i]);
+        }
+
+        if !specular {
+            report.max_sample_error = report
+                .max_sample_error
+                .max(rel_error(color, lobe.eval(wo, wi)))
+                .max(rel_error(
+                    Color::from_scalar(pdf),
+                    Color::from_scalar(lobe.pdf(wo, wi)),
+                ));
+            if reciprocal {
+                report.max_reciprocity_error = report
+                    .max_reciprocity_error
+                    .max(rel_error(lobe.eval(wo, wi), lobe.eval(wi, wo)));
+            }
+        }
+    }
+    report.rho_hh = Color {
+        r: rho_hh[0].mean(),
+        g: rho_hh[1].mean(),
+        b: rho_hh[2].mean(),
+    };
+    for (i, estimate) in rho_hh.iter().enumerate() {
+        let tolerance = NUM_STD_ERRORS * estimate.std_error() + REL_TOLERANCE;
+        if estimate.mean() > 1.0 + tolerance {
+            bail!(
+                "The lobe scatters more light than it receives (channel {} of rho_hh is {})",
+                i,
+                estimate.mean()
+            );
+        }
+    }
+
+    if specular {
+        return Ok(report);
+    }
+    if report.max_sample_error > EVAL_TOLERANCE {
+        bail!(
+            "The color or pdf returned by sample and eval or pdf differ by up to {}%",
+            report.max_sample_error * 100.0
+        );
+    }
+    if report.max_reciprocity_error > EVAL_TOLERANCE {
+        bail!(
+            "The lobe isn't reciprocal, swapping the directions changes it by up to {}%",
+            report.max_reciprocity_error * 100.0
+        );
+    }
+
+    // Integrate the pdf over every direction (with uniform directions) for a couple of outgoing
+    // directions:
+    let mut pdf_variance = 0.0;
+    for _ in 0..NUM_DIRECTIONS {
+        let wo = sampling::uniform_sample_hemisphere(rand_vec2());
+
+        let num_nonzero = (0..num_samples)
+            .filter(|_| lobe.sample(wo, rand_vec2()).2 > 0.0)
+            .count();
+        report.pdf_mass += (num_nonzero as Scalar) / (num_samples as Scalar);
+
+        let mut integral = Estimate::default();
+        for _ in 0..num_samples {
+            let wi = sampling::uniform_sample_sphere(rand_vec2());
+            integral.add(lobe.pdf(wo, wi) / sampling::uniform_sphere_pdf::<Scalar>());
+        }
+        report.pdf_integral += integral.mean();
+        // The variances of the directions add up:
+        pdf_variance += integral.std_error().powi(2);
+    }
+    let tolerance = NUM_STD_ERRORS * pdf_variance.sqrt() + REL_TOLERANCE * report.pdf_mass.max(1.0);
+    if (report.pdf_integral - report.pdf_mass).abs() > tolerance {
+        bail!(
+            "The pdf of the lobe integrates to {} but {} was expected (is it a solid angle density?)",
+            report.pdf_integral,
+            report.pdf_mass
+        );
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shading::lobe::dielectric::SpecularDielectric;
+    use crate::shading::lobe::lambertian::LambertianReflection;
+    use crate::shading::lobe::microfacet::{Beckmann, MicrofacetReflection, TrowbridgeReitz};
+    use crate::shading::lobe::oren_nayar::OrenNayar;
+    use crate::shading::lobe::rotated::RotatedLobe;
+    use crate::shading::lobe::specular::{
+        Conductor, Dielectric, PerfectMirror, SpecularFresnal, SpecularReflection,
+        SpecularTransmission,
+    };
+    use pmath::numbers::Float;
+    use pmath::vector::Vec3;
+
+    const NUM_SAMPLES: usize = 4000;
+
+    /// A cosine weighted lobe that can be broken in different ways.
+    struct Broken {
+        albedo: Scalar,
+        // The pdf `pdf` returns is scaled by this (but not the one returned by `sample`):
+        pdf_scale: Scalar,
+        // Makes the lobe depend on the direction of `wo` only:
+        one_sided: bool,
+    }
+
+    impl Lobe for Broken {
+        fn contains_type(&self, lobe_type: LobeType) -> bool {
+            (LobeType::REFLECTION | LobeType::DIFFUSE).contains(lobe_type)
+        }
+
+        fn get_type(&self) -> LobeType {
+            LobeType::REFLECTION | LobeType::DIFFUSE
+        }
+
+        fn eval(&self, wo: Vec3<Scalar>, _wi: Vec3<Scalar>) -> Color {
+            let scale = if self.one_sided { 2.0 * wo.z } else { 1.0 };
+            Color::from_scalar(self.albedo * scale * Scalar::INV_PI)
+        }
+
+        fn sample(&self, wo: Vec3<Scalar>, u: Vec2<Scalar>) -> (Color, Vec3<Scalar>, Scalar) {
+            let wi = sampling::cos_sample_hemisphere(u);
+            (self.eval(wo, wi), wi, sampling::cos_sphere_pdf(wi.z))
+        }
+
+        fn pdf(&self, _wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> Scalar {
+            if wi.z <= 0.0 {
+                return 0.0;
+            }
+            self.pdf_scale * sampling::cos_sphere_pdf(wi.z)
+        }
+    }
+
+    #[test]
+    fn existing_lobes_pass() {
+        let color = Color {
+            r: 0.9,
+            g: 0.5,
+            b: 0.1,
+        };
+        let lambertian = validate_lobe(&LambertianReflection::new(color), NUM_SAMPLES, 1).unwrap();
+        // The hemispherical-hemispherical reflectance of a Lambertian lobe is its albedo:
+        for i in 0..3 {
+            assert!((lambertian.rho_hh[i] - color[i]).abs() < crate::TEST_EPSILON);
+        }
+        assert!((lambertian.pdf_integral - lambertian.pdf_mass).abs() < 0.1);
+
+        let rotated = RotatedLobe::new(LambertianReflection::new(color), 0.7);
+        validate_lobe(&rotated, NUM_SAMPLES, 2).unwrap();
+
+        // Only the energy is checked for specular lobes:
+        for &eta in &[1.5, 1.0 / 1.33] {
+            let glass = SpecularDielectric::new(Color::from_scalar(1.0), eta);
+            let report = validate_lobe(&glass, NUM_SAMPLES, 3).unwrap();
+            assert!((report.rho_hh.r - 1.0).abs() < 0.01, "{:?}", report);
+        }
+    }
+
+    #[test]
+    fn oren_nayar_passes() {
+        let color = Color::from_scalar(0.8);
+        for (i, &sigma) in [0.0, 20.0, 60.0].iter().enumerate() {
+            let report =
+                validate_lobe(&OrenNayar::new(color, sigma), NUM_SAMPLES, 5 + i as u64).unwrap();
+            // Roughness darkens the lobe:
+            assert!(report.rho_hh.r <= 0.8 + 0.01, "{}: {:?}", sigma, report);
+        }
+        // Without any roughness it's Lambertian:
+        let report = validate_lobe(&OrenNayar::new(color, 0.0), NUM_SAMPLES, 8).unwrap();
+        assert!(
+            (report.rho_hh.r - 0.8).abs() < crate::TEST_EPSILON,
+            "{:?}",
+            report
+        );
+    }
+
+    #[test]
+    fn microfacet_lobes_pass() {
+        let color = Color::from_scalar(1.0);
+        let alphas = [
+            Vec2 { x: 0.1, y: 0.1 },
+            Vec2 { x: 0.25, y: 0.25 },
+            Vec2 { x: 0.8, y: 0.8 },
+            Vec2 { x: 0.1, y: 0.5 },
+        ];
+        for (i, &alpha) in alphas.iter().enumerate() {
+            let seed = 10 + 2 * i as u64;
+            let ggx =
+                MicrofacetReflection::new(color, TrowbridgeReitz::new(alpha), PerfectMirror::new());
+            let report = validate_lobe(&ggx, NUM_SAMPLES, seed).unwrap();
+            assert!(report.pdf_mass > 0.0, "{:?}", report);
+
+            let beckmann =
+                MicrofacetReflection::new(color, Beckmann::new(alpha), Dielectric::new(1.0, 1.5));
+            validate_lobe(&beckmann, NUM_SAMPLES, seed + 1).unwrap();
+        }
+    }
+
+    #[test]
+    fn specular_lobes_pass() {
+        // A perfect mirror reflects everything:
+        let mirror = SpecularReflection::new(Color::from_scalar(1.0), PerfectMirror::new());
+        let report = validate_lobe(&mirror, NUM_SAMPLES, 20).unwrap();
+        assert!(
+            (report.rho_hh.r - 1.0).abs() < crate::TEST_EPSILON,
+            "{:?}",
+            report
+        );
+
+        // Gold:
+        let conductor = Conductor::new(
+            Color::from_scalar(1.0),
+            Color {
+                r: 0.143,
+                g: 0.374,
+                b: 1.442,
+            },
+            Color {
+                r: 3.983,
+                g: 2.385,
+                b: 1.603,
+            },
+        );
+        let report = validate_lobe(
+            &SpecularReflection::new(Color::from_scalar(1.0), conductor),
+            NUM_SAMPLES,
+            21,
+        )
+        .unwrap();
+        assert!(report.rho_hh.r > report.rho_hh.b, "{:?}", report);
+
+        let transmission = SpecularTransmission::new(Color::from_scalar(1.0), 1.0, 1.5);
+        validate_lobe(&transmission, NUM_SAMPLES, 22).unwrap();
+
+        // Everything is either reflected or transmitted (and the transmitted radiance is compressed by
+        // the relative index of refraction squared):
+        let white = Color::from_scalar(1.0);
+        let fresnel = SpecularFresnal::new(white, white, 1.0, 1.5);
+        let report = validate_lobe(&fresnel, NUM_SAMPLES, 23).unwrap();
+        assert!(
+            report.rho_hh.r < 1.0 && report.rho_hh.r > 1.0 / (1.5 * 1.5),
+            "{:?}",
+            report
+        );
+    }
+
+    #[test]
+    fn broken_lobes_are_caught() {
+        let correct = Broken {
+            albedo: 0.8,
+            pdf_scale: 1.0,
+            one_sided: false,
+        };
+        validate_lobe(&correct, NUM_SAMPLES, 4).unwrap();
+
+        let bright = Broken {
+            albedo: 1.2,
+            ..correct
+        };
+        assert!(validate_lobe(&bright, NUM_SAMPLES, 4).is_err());
+        let wrong_pdf = Broken {
+            pdf_scale: 2.0,
+            ..correct
+        };
+        assert!(validate_lobe(&wrong_pdf, NUM_SAMPLES, 4).is_err());
+        let one_sided = Broken {
+            albedo: 0.4,
+            one_sided: true,
+            ..correct
+        };
+        assert!(validate_lobe(&one_sided, NUM_SAMPLES, 4).is_err());
+        assert!(validate_lobe(&correct, 0, 4).is_err());
+    }
+}
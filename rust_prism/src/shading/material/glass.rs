@@ -0,0 +1,28 @@
+use crate::interaction::Interaction;
+use crate::shading::lobe::dielectric::SpecularDielectric;
+use crate::shading::material::{Bsdf, Material};
+use crate::spectrum::Color;
+use crate::Scalar;
+
+/// A smooth dielectric (like glass or water) that reflects and refracts light, which is the same across
+/// the entire surface. Shadow rays don't pass through it, so the light it focuses (the caustics) is only
+/// found by paths that happen to hit a light after refracting, or with photons (see `integrator::sppm`).
+pub struct Glass {
+    bsdf: Bsdf,
+}
+
+impl Glass {
+    /// `color` tints the reflected and refracted light, and `ior` is the index of refraction inside of
+    /// the surface (the side opposite to the normal).
+    pub fn new(color: Color, ior: Scalar) -> Self {
+        let mut bsdf = Bsdf::new(ior);
+        bsdf.add_lobe(SpecularDielectric::new(color, ior));
+        Glass { bsdf }
+    }
+}
+
+impl Material for Glass {
+    fn bsdf(&self, interaction: Interaction) -> (&Bsdf, Interaction) {
+        (&self.bsdf, interaction)
+    }
+}
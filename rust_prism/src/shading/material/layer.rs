@@ -0,0 +1,118 @@
+use crate::interaction::Interaction;
+use crate::shading::material::{hit_random, Bsdf, Material};
+use crate::spectrum::Color;
+use crate::Scalar;
+use std::sync::Arc;
+
+/// A dielectric coating (like a clear varnish) over a base material. Every hit picks the coating with
+/// the probability of light reflecting off of the coating in the outgoing direction (Schlick's
+/// approximation of the Fresnel reflectance) and the base otherwise. This ignores the light the coating
+/// absorbs and any light bouncing between the two layers, but gets the characteristic look of coatings
+/// that reflect a lot more at grazing angles.
+pub struct LayerMaterial {
+    coating: Arc<dyn Material>,
+    base: Arc<dyn Material>,
+    // The reflectance of the coating at normal incidence:
+    r0: Scalar,
+}
+
+impl LayerMaterial {
+    /// `eta` is the index of refraction of the coating (relative to the outside).
+    pub fn new(coating: Arc<dyn Material>, base: Arc<dyn Material>, eta: Scalar) -> Self {
+        let r0 = ((eta - 1.0) / (eta + 1.0)).powi(2);
+        LayerMaterial { coating, base, r0 }
+    }
+
+    /// The fraction of the light that reflects off of the coating.
+    fn fresnel(&self, cos_theta: Scalar) -> Scalar {
+        let m = (1.0 - cos_theta.abs().min(1.0)).max(0.0);
+        self.r0 + (1.0 - self.r0) * m.powi(5)
+    }
+
+    fn salt(&self) -> u64 {
+        self as *const Self as u64
+    }
+}
+
+impl Material for LayerMaterial {
+    fn bsdf(&self, interaction: Interaction) -> (&Bsdf, Interaction) {
+        let fresnel = self.fresnel(interaction.wo.dot(interaction.shading_n()));
+        if hit_random(interaction.p, interaction.wo, self.salt()) < fresnel {
+            self.coating.bsdf(interaction)
+        } else {
+            self.base.bsdf(interaction)
+        }
+    }
+
+    /// Light has to pass through both layers.
+    fn transmittance(&self, interaction: &Interaction) -> Color {
+        self.coating.transmittance(interaction) * self.base.transmittance(interaction)
+    }
+
+    /// The base shines through the coating.
+    fn emission(&self) -> Color {
+        self.base.emission()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::sphere::Sphere;
+    use crate::geometry::Geometry;
+    use crate::shading::material::matte::Matte;
+    use pmath::ray::Ray;
+    use pmath::sampling;
+    use pmath::vector::{Vec2, Vec3};
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg32;
+
+    /// Hits of a unit sphere from random directions, where the rays are `offset` away from the center of
+    /// the sphere (so the larger it is, the more grazing the hits are).
+    fn hits(offset: Scalar, n: usize) -> Vec<Interaction> {
+        let mut rng = Pcg32::seed_from_u64(9);
+        (0..n)
+            .map(|_| {
+                let dir = sampling::uniform_sample_sphere(Vec2 {
+                    x: rng.gen::<Scalar>(),
+                    y: rng.gen::<Scalar>(),
+                });
+                let side = dir
+                    .cross(Vec3 {
+                        x: 0.6,
+                        y: 0.0,
+                        z: 0.8,
+                    })
+                    .normalize();
+                let ray = Ray::new(dir.scale(5.0) + side.scale(offset), -dir, 0.0);
+                Sphere::new(1.0).intersect(ray.cast()).unwrap()
+            })
+            .collect()
+    }
+
+    /// The fraction of the hits that use the bsdf of the coating.
+    fn coating_fraction(eta: Scalar, hits: &[Interaction]) -> Scalar {
+        let coating: Arc<dyn Material> = Arc::new(Matte::new(Color::white()));
+        let base: Arc<dyn Material> = Arc::new(Matte::new(Color::black()));
+        let layer = LayerMaterial::new(coating.clone(), base, eta);
+        let picked = hits
+            .iter()
+            .filter(|&&hit| std::ptr::eq(layer.bsdf(hit).0, coating.bsdf(hit).0))
+            .count();
+        (picked as Scalar) / (hits.len() as Scalar)
+    }
+
+    #[test]
+    fn coatings_reflect_more_at_grazing_angles() {
+        let head_on = hits(0.0, 4000);
+        // A coating with the same index of refraction as the outside isn't there:
+        assert_eq!(coating_fraction(1.0, &head_on), 0.0);
+        // Glass reflects 4% of the light at normal incidence:
+        let picked = coating_fraction(1.5, &head_on);
+        assert!((picked - 0.04).abs() < 0.01, "{}", picked);
+
+        // At an angle of about 84 degrees, the reflectance is about 0.6:
+        let picked = coating_fraction(1.5, &hits(0.995, 4000));
+        assert!(picked > 0.5 && picked < 0.7, "{}", picked);
+    }
+}
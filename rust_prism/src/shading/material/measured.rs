@@ -0,0 +1,23 @@
+use crate::interaction::Interaction;
+use crate::shading::lobe::measured::{MeasuredReflection, MerlData};
+use crate::shading::material::{Bsdf, Material};
+use std::sync::Arc;
+
+/// A material with a measured BRDF (see `MerlData`), which is the same across the entire surface.
+pub struct Measured {
+    bsdf: Bsdf,
+}
+
+impl Measured {
+    pub fn new(data: Arc<MerlData>) -> Self {
+        let mut bsdf = Bsdf::new_opaque();
+        bsdf.add_lobe(MeasuredReflection::new(data));
+        Measured { bsdf }
+    }
+}
+
+impl Material for Measured {
+    fn bsdf(&self, interaction: Interaction) -> (&Bsdf, Interaction) {
+        (&self.bsdf, interaction)
+    }
+}
@@ -0,0 +1,136 @@
+use crate::interaction::{Interaction, IntrType};
+use crate::shading::material::{hit_random, Bsdf, Material};
+use crate::shading::texture::Texture;
+use crate::spectrum::Color;
+use crate::Scalar;
+use std::sync::Arc;
+
+/// Blends two materials with a mask (a dusty metal, for instance). Every hit picks one of the two
+/// materials: `b` with the probability given by `factor` (evaluated at the uv of the hit) and `a`
+/// otherwise, so that on average the surface scatters like the blend of the two. A factor of exactly 0
+/// or 1 gives `a` or `b` respectively.
+pub struct MixMaterial {
+    a: Arc<dyn Material>,
+    b: Arc<dyn Material>,
+    factor: Arc<dyn Texture<Scalar>>,
+}
+
+impl MixMaterial {
+    pub fn new(
+        a: Arc<dyn Material>,
+        b: Arc<dyn Material>,
+        factor: Arc<dyn Texture<Scalar>>,
+    ) -> Self {
+        MixMaterial { a, b, factor }
+    }
+
+    fn salt(&self) -> u64 {
+        self as *const Self as u64
+    }
+}
+
+impl Material for MixMaterial {
+    fn bsdf(&self, interaction: Interaction) -> (&Bsdf, Interaction) {
+        let factor = self.factor.eval(interaction.uv());
+        if hit_random(interaction.p, interaction.wo, self.salt()) < factor {
+            self.b.bsdf(interaction)
+        } else {
+            self.a.bsdf(interaction)
+        }
+    }
+
+    fn transmittance(&self, interaction: &Interaction) -> Color {
+        let factor = match interaction.intr_type {
+            IntrType::Geom(geom_intr) => self.factor.eval(geom_intr.uv),
+            IntrType::Vol(_) => 0.0,
+        };
+        // Shadow rays don't pick a material, they see the blend:
+        self.a
+            .transmittance(interaction)
+            .lerp(self.b.transmittance(interaction), factor)
+    }
+
+    /// Area lights are built from the emission of the material (which can't vary across the surface),
+    /// so mixed materials only emit light if both materials emit the same.
+    fn emission(&self) -> Color {
+        let (a, b) = (self.a.emission(), self.b.emission());
+        if a == b {
+            a
+        } else {
+            Color::black()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::sphere::Sphere;
+    use crate::geometry::Geometry;
+    use crate::shading::material::matte::Matte;
+    use crate::shading::texture::ConstantTexture;
+    use pmath::ray::Ray;
+    use pmath::sampling;
+    use pmath::vector::Vec2;
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg32;
+
+    /// Hits of a unit sphere from random directions.
+    fn hits(n: usize) -> Vec<Interaction> {
+        let mut rng = Pcg32::seed_from_u64(7);
+        (0..n)
+            .map(|_| {
+                let dir = sampling::uniform_sample_sphere(Vec2 {
+                    x: rng.gen::<Scalar>(),
+                    y: rng.gen::<Scalar>(),
+                });
+                let ray = Ray::new(dir.scale(5.0), -dir, 0.0);
+                Sphere::new(1.0).intersect(ray.cast()).unwrap()
+            })
+            .collect()
+    }
+
+    fn matte(r: Scalar) -> Arc<dyn Material> {
+        Arc::new(Matte::new(Color { r, g: 0.5, b: 0.5 }))
+    }
+
+    fn mix(a: &Arc<dyn Material>, b: &Arc<dyn Material>, factor: Scalar) -> Arc<dyn Material> {
+        Arc::new(MixMaterial::new(
+            a.clone(),
+            b.clone(),
+            Arc::new(ConstantTexture::new(factor)),
+        ))
+    }
+
+    /// The fraction of the hits that use the bsdf of `child`.
+    fn fraction(material: &dyn Material, child: &dyn Material, hits: &[Interaction]) -> Scalar {
+        let picked = hits
+            .iter()
+            .filter(|&&hit| std::ptr::eq(material.bsdf(hit).0, child.bsdf(hit).0))
+            .count();
+        (picked as Scalar) / (hits.len() as Scalar)
+    }
+
+    #[test]
+    fn factors_of_zero_and_one_give_the_children() {
+        let (a, b) = (matte(0.1), matte(0.9));
+        let hits = hits(2000);
+        assert_eq!(fraction(mix(&a, &b, 0.0).as_ref(), a.as_ref(), &hits), 1.0);
+        assert_eq!(fraction(mix(&a, &b, 1.0).as_ref(), b.as_ref(), &hits), 1.0);
+    }
+
+    #[test]
+    fn children_are_picked_with_the_factor() {
+        let (a, b, c) = (matte(0.1), matte(0.5), matte(0.9));
+        let hits = hits(4000);
+        let mixed = mix(&a, &b, 0.3);
+        assert!((fraction(mixed.as_ref(), b.as_ref(), &hits) - 0.3).abs() < 0.03);
+
+        // Nested mixes pick their children independently of each other:
+        let nested = mix(&mix(&a, &b, 0.5), &c, 0.5);
+        for child in [&a, &b].iter() {
+            let picked = fraction(nested.as_ref(), child.as_ref(), &hits);
+            assert!((picked - 0.25).abs() < 0.03, "{}", picked);
+        }
+    }
+}
@@ -1,33 +1,110 @@
+pub mod glass;
+pub mod layer;
 pub mod matte;
+pub mod measured;
+pub mod mix;
 pub mod plastic;
 
-use crate::geometry::GeomSurface;
+use crate::error::{PrismError, PrismResult};
+use crate::interaction::Interaction;
 use crate::shading::lobe::{Lobe, LobeType};
+use crate::shading::material::matte::Matte;
 use crate::spectrum::Color;
+use crate::Scalar;
 use arrayvec::ArrayVec;
+use num_traits::clamp;
+use pmath::numbers::Float;
 use pmath::vector::{Vec2, Vec3};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
 
-/// A MaterialPool holds all of the materials during rendering.
+/// A MaterialPool holds all of the materials during rendering. Every pool starts out with a default
+/// material (a mid-gray matte) at `DEFAULT_MATERIAL_ID`, which is used for ids that don't exist.
 pub struct MaterialPool {
-    materials: Vec<Box<dyn Material>>,
+    materials: Vec<Arc<dyn Material>>,
+    // The ids that didn't exist, so that they are only reported once:
+    reported_ids: Mutex<HashSet<u32>>,
 }
 
+/// The id of the default material of every `MaterialPool`.
+pub const DEFAULT_MATERIAL_ID: u32 = 0;
+
 impl MaterialPool {
     pub fn new() -> Self {
+        let default: Arc<dyn Material> = Arc::new(Matte::new(Color::white().scale(0.5)));
         MaterialPool {
-            materials: Vec::new(),
+            materials: vec![default],
+            reported_ids: Mutex::new(HashSet::new()),
         }
     }
 
     /// Adds a material to the material pool, returns a material_id.
     pub fn add_material<M: Material>(&mut self, material: M) -> u32 {
         let material_id = self.materials.len() as u32;
-        self.materials.push(Box::new(material));
+        self.materials.push(Arc::new(material));
         material_id
     }
 
+    /// Returns an error if the pool doesn't have a material with the id. Ids are checked when they enter
+    /// the scene (see `build_scene` and `Scene::override_material`), so that rendering never has to.
+    pub fn check_id(&self, material_id: u32) -> PrismResult<()> {
+        if (material_id as usize) < self.materials.len() {
+            Ok(())
+        } else {
+            Err(PrismError::InvalidScene(format!(
+                "material id {} doesn't exist (there are {} materials)",
+                material_id,
+                self.materials.len()
+            )))
+        }
+    }
+
+    /// Returns the material with the id. An id that doesn't exist (see `check_id`) is a bug, which panics in
+    /// debug builds. Release builds report it (once per id) and use the default material instead.
     pub fn get_material(&self, material_id: u32) -> &dyn Material {
-        &self.materials[material_id as usize]
+        self.materials[self.checked_id(material_id)].as_ref()
+    }
+
+    /// Returns the material so that it can be shared (for instance, with a `SceneGeom`).
+    pub fn get_shared_material(&self, material_id: u32) -> Arc<dyn Material> {
+        self.materials[self.checked_id(material_id)].clone()
+    }
+
+    /// The ids that were asked for but don't exist (in increasing order).
+    pub fn missing_ids(&self) -> Vec<u32> {
+        let reported_ids = self
+            .reported_ids
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let mut missing_ids: Vec<u32> = reported_ids.iter().copied().collect();
+        missing_ids.sort_unstable();
+        missing_ids
+    }
+
+    /// The index of the material with the id, or of the default material if it doesn't exist.
+    fn checked_id(&self, material_id: u32) -> usize {
+        let index = material_id as usize;
+        if index < self.materials.len() {
+            return index;
+        }
+        debug_assert!(
+            false,
+            "material id {} doesn't exist (there are {} materials)",
+            material_id,
+            self.materials.len()
+        );
+        let mut reported_ids = self
+            .reported_ids
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if reported_ids.insert(material_id) {
+            eprintln!(
+                "Material id {} doesn't exist, using the default material instead",
+                material_id
+            );
+        }
+        DEFAULT_MATERIAL_ID as usize
     }
 }
 
@@ -39,35 +116,124 @@ impl MaterialPool {
 // I don't know, I'll figure something out.
 
 /// A material defines how to interact with surfaces when a ray hits it
-pub trait Material {
+pub trait Material: Send + Sync + 'static {
     /// Returns a reference to the bsdf and an interaction if this should be updated.
     /// This may be due to bump mapping, for instance.
-    fn bsdf(&self, interaction: GeomInteraction) -> (&Bsdf, GeomInteraction);
+    fn bsdf(&self, interaction: Interaction) -> (&Bsdf, Interaction);
+
+    /// Returns how much light passes straight through the surface at the interaction (used for
+    /// alpha-cutouts and colored shadows). By default, materials are opaque.
+    fn transmittance(&self, _interaction: &Interaction) -> Color {
+        Color::black()
+    }
+
+    /// Returns the radiance the surface emits. Geometry with an emissive material is turned into an
+    /// area light when the scene is loaded.
+    fn emission(&self) -> Color {
+        Color::black()
+    }
+}
+
+/// The smallest microfacet alpha materials pass to their lobes. Below it the distribution is so close to a
+/// delta that its pdfs overflow (producing fireflies and NaNs), so it should be a specular lobe instead.
+pub const MIN_ALPHA: Scalar = 1e-3;
+
+/// How the roughness of a material (between 0 and 1) is turned into the alpha of a microfacet distribution.
+/// This happens in the materials, so that the lobes only ever see alpha.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum RoughnessRemap {
+    /// `alpha = roughness^2`, which makes the roughness look perceptually linear.
+    Squared,
+    /// `alpha = roughness`.
+    Linear,
+}
+
+impl Default for RoughnessRemap {
+    fn default() -> Self {
+        RoughnessRemap::Squared
+    }
+}
+
+impl RoughnessRemap {
+    /// Returns the alpha for the roughness, clamped to `MIN_ALPHA` (and to 1).
+    pub fn to_alpha(self, roughness: Scalar) -> Scalar {
+        let roughness = clamp(roughness, 0.0, 1.0);
+        let alpha = match self {
+            RoughnessRemap::Squared => roughness * roughness,
+            RoughnessRemap::Linear => roughness,
+        };
+        // Also catches a NaN roughness:
+        alpha.max(MIN_ALPHA)
+    }
+}
+
+/// Returns a number in [0, 1) for picking between the child materials of a material (see `MixMaterial`),
+/// as materials don't have access to the sampler. The hit point and the outgoing direction are different
+/// for every sample, so the choice is as well. `salt` makes the choices of the different materials
+/// independent of each other (in case they are nested).
+fn hit_random(p: Vec3<Scalar>, wo: Vec3<Scalar>, salt: u64) -> Scalar {
+    let mut hash = salt ^ 0xcbf29ce484222325;
+    for v in [p.x, p.y, p.z, wo.x, wo.y, wo.z].iter() {
+        hash = (hash ^ v.to_f64().to_bits()).wrapping_mul(0x100000001b3);
+    }
+    // The splitmix64 finalizer, so that every bit depends on every input bit:
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^= hash >> 31;
+    // The top 53 bits fit into the mantissa exactly:
+    ((hash >> 11) as Scalar) / ((1u64 << 53) as Scalar)
 }
 
 /// Used to convert to and from shading coordinate space:
 #[derive(Clone, Copy, Debug)]
 pub struct ShadingCoord {
-    geometry_n: Vec3<f64>,
-    n: Vec3<f64>,
-    s: Vec3<f64>,
-    t: Vec3<f64>,
+    geometry_n: Vec3<Scalar>,
+    n: Vec3<Scalar>,
+    s: Vec3<Scalar>,
+    t: Vec3<Scalar>,
 }
 
 impl ShadingCoord {
     /// Given an interaction, can construct a new shading coordinate system
-    pub fn new(interaction: GeomInteraction) -> Self {
-        let s = interaction.dpdu.normalize();
+    pub fn new(interaction: Interaction) -> Self {
+        Self::with_tangent(interaction.n, interaction.shading_n(), interaction.dpdu())
+    }
+
+    /// Constructs a shading coordinate system from an explicit tangent (for instance, one authored
+    /// with the mesh instead of dpdu). The tangent is made perpendicular to the shading normal `n`
+    /// (which has to be normalized). If the tangent is (close to) zero or parallel to `n`, which
+    /// happens with collapsed uvs, an arbitrary tangent is used instead.
+    pub fn with_tangent(geometry_n: Vec3<Scalar>, n: Vec3<Scalar>, tangent: Vec3<Scalar>) -> Self {
+        const MIN_REL_LENGTH2: Scalar = 1e-12;
+
+        let s = tangent - n.scale(n.dot(tangent));
+        let s2 = s.length2();
+        let s = if s2.is_finite() && (s2 > MIN_REL_LENGTH2 * tangent.length2()) {
+            s.scale(1.0 / s2.sqrt())
+        } else {
+            pmath::coord_system(n).0.normalize()
+        };
+        let t = n.cross(s);
+
+        debug_assert!(
+            ((s.length2() - 1.0).abs() < 1e-6)
+                && ((t.length2() - 1.0).abs() < 1e-6)
+                && (s.dot(n).abs() < 1e-6),
+            "shading frame isn't orthonormal (n: {:?}, s: {:?}, t: {:?})",
+            n,
+            s,
+            t
+        );
         ShadingCoord {
-            geometry_n: interaction.n,
-            n: interaction.shading_n,
+            geometry_n,
+            n,
             s,
-            t: interaction.shading_n.cross(s),
+            t,
         }
     }
 
     /// Transforms a vector from world space to shading space.
-    pub fn world_to_shading_vec(self, v: Vec3<f64>) -> Vec3<f64> {
+    pub fn world_to_shading_vec(self, v: Vec3<Scalar>) -> Vec3<Scalar> {
         Vec3 {
             x: v.dot(self.s),
             y: v.dot(self.t),
@@ -76,7 +242,7 @@ impl ShadingCoord {
     }
 
     /// Transforms a vector from shading space to world space.
-    pub fn shading_to_world_vec(self, v: Vec3<f64>) -> Vec3<f64> {
+    pub fn shading_to_world_vec(self, v: Vec3<Scalar>) -> Vec3<Scalar> {
         Vec3 {
             x: (self.s.x * v.x) + (self.t.x * v.y) + (self.n.x * v.z),
             y: (self.s.y * v.x) + (self.t.y * v.y) + (self.n.y * v.z),
@@ -86,7 +252,7 @@ impl ShadingCoord {
 
     // wo and wi are in SHADING SPACE. Used to detect if the incoming direction
     // (wi) is coming from behind. This would mean the light shouldn't be incorporated
-    pub fn is_reflect(self, wo: Vec3<f64>, wi: Vec3<f64>) -> bool {
+    pub fn is_reflect(self, wo: Vec3<Scalar>, wi: Vec3<Scalar>) -> bool {
         wo.dot(self.geometry_n) * wi.dot(self.geometry_n) > 0.0
     }
 }
@@ -94,10 +260,9 @@ impl ShadingCoord {
 /// The maximum number of lobes per bsdf.
 pub const MAX_NUM_LOBES: usize = 8;
 
-#[derive(Clone)]
 pub struct Bsdf {
     lobes: ArrayVec<[Box<dyn Lobe>; MAX_NUM_LOBES]>,
-    eta: f64,
+    eta: Scalar,
 }
 
 impl Bsdf {
@@ -110,7 +275,7 @@ impl Bsdf {
     }
 
     /// Creates a new bsdf with a given refractive index (`eta`).
-    pub fn new(eta: f64) -> Self {
+    pub fn new(eta: Scalar) -> Self {
         Bsdf {
             lobes: ArrayVec::new(),
             eta,
@@ -136,8 +301,8 @@ impl Bsdf {
     /// Evaluate the lobe, with `wo` and `wi` in world space.
     pub fn eval(
         &self,
-        wo: Vec3<f64>,
-        wi: Vec3<f64>,
+        wo: Vec3<Scalar>,
+        wi: Vec3<Scalar>,
         lobe_type: LobeType,
         shading_coord: ShadingCoord,
     ) -> Color {
@@ -148,7 +313,7 @@ impl Bsdf {
         self.lobes
             .iter()
             .fold(Color::black(), |result_color, lobe| {
-                let matches = lobe.contains_type(lobe_type);
+                let matches = lobe.matches_type(lobe_type);
                 // Checks that, if it's reflected then we have a reflection lobe and if it's
                 // not reflected we have a transmission lobe.
                 let valid_direction = (is_reflect && lobe.contains_type(LobeType::REFLECTION))
@@ -165,11 +330,11 @@ impl Bsdf {
     /// Evaluate the lobe, with `wo` and `wi` in world space.
     pub fn pdf(
         &self,
-        wo: Vec3<f64>,
-        wi: Vec3<f64>,
+        wo: Vec3<Scalar>,
+        wi: Vec3<Scalar>,
         lobe_type: LobeType,
         shading_coord: ShadingCoord,
-    ) -> f64 {
+    ) -> Scalar {
         let shading_wo = shading_coord.world_to_shading_vec(wo);
         let shading_wi = shading_coord.world_to_shading_vec(wi);
 
@@ -178,29 +343,33 @@ impl Bsdf {
             .lobes
             .iter()
             .fold((0.0, 0u32), |(pdf_sum, count), lobe| {
-                if lobe.contains_type(lobe_type) {
+                if lobe.matches_type(lobe_type) {
                     (pdf_sum + lobe.pdf(shading_wo, shading_wi), count + 1)
                 } else {
                     (pdf_sum, count)
                 }
             });
-        pdf / (num_has_type as f64)
+        if num_has_type == 0 {
+            0.0
+        } else {
+            pdf / (num_has_type as Scalar)
+        }
     }
 
     /// Samples the bsdf given a `wo` in world space.
     /// Returns, in the following order: resulting throughput, wi (world space), pdf, lobe type of lobe samples:
     pub fn sample(
         &self,
-        wo: Vec3<f64>,
-        u: Vec2<f64>,
+        wo: Vec3<Scalar>,
+        u: Vec2<Scalar>,
         lobe_type: LobeType,
         shading_coord: ShadingCoord,
-    ) -> (Color, Vec3<f64>, f64, LobeType) {
+    ) -> (Color, Vec3<Scalar>, Scalar, LobeType) {
         // First, make sure we only consider lobes that match with the specified LobeType.
         let mut potential_lobes: ArrayVec<[_; MAX_NUM_LOBES]> = ArrayVec::new();
-        for &lobe in &self.lobes {
-            if lobe.contains_type(lobe_type) {
-                potential_lobes.push(&*lobe);
+        for lobe in &self.lobes {
+            if lobe.matches_type(lobe_type) {
+                potential_lobes.push(lobe.as_ref());
             }
         }
         let num_has_type = potential_lobes.len();
@@ -209,13 +378,10 @@ impl Bsdf {
         }
 
         // TODO: pick a wiser selection algorithm for lobes.
-        let selected_lobe_index = ((u.x * (num_has_type as f64)) as usize).min(num_has_type - 1);
+        let (selected_lobe_index, remapped_u) = pick_lobe(u.x, num_has_type);
         let selected_lobe = potential_lobes[selected_lobe_index];
-
-        // We still want to use u.x, so we have to remap it so that u can still
-        // be between 0 and 1.
         let u = Vec2 {
-            x: u.x * (num_has_type - selected_lobe_index) as f64,
+            x: remapped_u,
             y: u.y,
         };
 
@@ -234,10 +400,10 @@ impl Bsdf {
                     if index == selected_lobe_index {
                         pdf_sum
                     } else {
-                        pdf_sum + lobe.pdf(wo, wi)
+                        pdf_sum + lobe.pdf(shading_wo, shading_wi)
                     }
                 })
-                / (num_has_type as f64) // Averaging, remember?
+                / (num_has_type as Scalar) // Averaging, remember?
         } else {
             selected_pdf
         };
@@ -245,7 +411,7 @@ impl Bsdf {
         // Now we calculate the throughput by summing the contributions from each of the lobes.
         let color = if !sampled_lobe_type.contains(LobeType::SPECULAR) {
             // Check if they are on the same side relative to the normal (reflected):
-            let is_reflect = wi.dot(self.geometry_n) * wo.dot(self.geometry_n) > 0.;
+            let is_reflect = shading_coord.is_reflect(wo, wi);
             potential_lobes
                 .iter()
                 .enumerate()
@@ -254,9 +420,9 @@ impl Bsdf {
                         && ((is_reflect && lobe.contains_type(LobeType::REFLECTION))
                             || (!is_reflect && lobe.contains_type(LobeType::TRANSMISSION)))
                     {
-                        selected_color + lobe.eval(shading_wo, shading_wi)
+                        color + lobe.eval(shading_wo, shading_wi)
                     } else {
-                        selected_color
+                        color
                     }
                 })
         } else {
@@ -266,3 +432,248 @@ impl Bsdf {
         (color, wi, pdf, sampled_lobe_type)
     }
 }
+
+/// Uniformly picks one of `count` lobes with `u` (in [0, 1)). Returns the index of the lobe and `u`
+/// remapped back to [0, 1), so that it can still be used to sample the lobe.
+///
+/// # Panics
+/// If `count` is 0.
+fn pick_lobe(u: Scalar, count: usize) -> (usize, Scalar) {
+    assert!(count > 0);
+    let scaled_u = u * (count as Scalar);
+    let index = (scaled_u as usize).min(count - 1);
+    // The largest Scalar smaller than 1:
+    let remapped_u = (scaled_u - (index as Scalar))
+        .max(0.0)
+        .min(1.0 - Scalar::EPSILON / 2.0);
+    (index, remapped_u)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::sphere::Sphere;
+    use crate::geometry::Geometry;
+    use crate::shading::lobe::lambertian::LambertianReflection;
+    use crate::TEST_EPSILON;
+    use pmath::ray::Ray;
+
+    #[test]
+    fn lobe_type_filters_match_every_type_of_the_lobe() {
+        let mut bsdf = Bsdf::new_opaque();
+        bsdf.add_lobe(LambertianReflection::new(Color {
+            r: 0.5,
+            g: 0.25,
+            b: 0.0,
+        }));
+        let z = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        let shading_coord = ShadingCoord::with_tangent(z, z, Vec3::zero());
+        let wo = Vec3 {
+            x: 0.6,
+            y: 0.0,
+            z: 0.8,
+        };
+        let wi = Vec3 {
+            x: -0.6,
+            y: 0.0,
+            z: 0.8,
+        };
+
+        let mut non_specular = LobeType::ALL;
+        non_specular.remove(LobeType::SPECULAR);
+        for &lobe_type in &[
+            LobeType::ALL,
+            non_specular,
+            LobeType::REFLECTION | LobeType::DIFFUSE,
+        ] {
+            let color = bsdf.eval(wo, wi, lobe_type, shading_coord);
+            assert!((color.r - 0.5 / Scalar::PI).abs() < 1e-12);
+            assert!(bsdf.pdf(wo, wi, lobe_type, shading_coord) > 0.0);
+            let (color, _, pdf, _) =
+                bsdf.sample(wo, Vec2 { x: 0.3, y: 0.7 }, lobe_type, shading_coord);
+            assert!(!color.is_black() && pdf > 0.0);
+        }
+        // A filter without the diffuse type doesn't match it:
+        assert!(bsdf
+            .eval(wo, wi, LobeType::REFLECTION, shading_coord)
+            .is_black());
+        assert_eq!(bsdf.pdf(wo, wi, LobeType::GLOSSY, shading_coord), 0.0);
+    }
+
+    #[test]
+    fn missing_material_ids_use_the_default_material() {
+        let mut materials = MaterialPool::new();
+        let red = materials.add_material(Matte::new(Color {
+            r: 1.0,
+            g: 0.0,
+            b: 0.0,
+        }));
+        assert!(materials.check_id(DEFAULT_MATERIAL_ID).is_ok());
+        assert!(materials.check_id(red).is_ok());
+        assert!(matches!(
+            materials.check_id(red + 1),
+            Err(PrismError::InvalidScene(_))
+        ));
+
+        let default = materials.get_shared_material(DEFAULT_MATERIAL_ID);
+        assert!(!Arc::ptr_eq(&materials.get_shared_material(red), &default));
+        assert!(materials.missing_ids().is_empty());
+    }
+
+    // Asking for an id that doesn't exist is a bug, so it only falls back to the default material in
+    // release builds:
+    #[test]
+    #[cfg_attr(
+        debug_assertions,
+        should_panic(expected = "material id 2 doesn't exist")
+    )]
+    fn missing_material_ids_are_reported_once() {
+        let mut materials = MaterialPool::new();
+        let red = materials.add_material(Matte::new(Color {
+            r: 1.0,
+            g: 0.0,
+            b: 0.0,
+        }));
+        let default = materials.get_shared_material(DEFAULT_MATERIAL_ID);
+        for _ in 0..3 {
+            assert!(Arc::ptr_eq(
+                &materials.get_shared_material(red + 1),
+                &default
+            ));
+        }
+        assert_eq!(materials.missing_ids(), vec![red + 1]);
+    }
+
+    #[test]
+    fn roughness_is_remapped_to_a_clamped_alpha() {
+        let squared = RoughnessRemap::default();
+        assert_eq!(squared, RoughnessRemap::Squared);
+        assert!((squared.to_alpha(0.5) - 0.25).abs() < TEST_EPSILON);
+        assert!((squared.to_alpha(0.1) - 0.01).abs() < TEST_EPSILON);
+        assert_eq!(squared.to_alpha(1.0), 1.0);
+        assert!((RoughnessRemap::Linear.to_alpha(0.5) - 0.5).abs() < TEST_EPSILON);
+
+        // Smooth (and invalid) roughnesses are clamped:
+        for &remap in &[RoughnessRemap::Squared, RoughnessRemap::Linear] {
+            assert_eq!(remap.to_alpha(0.0), MIN_ALPHA);
+            assert_eq!(remap.to_alpha(-1.0), MIN_ALPHA);
+            assert_eq!(remap.to_alpha(Scalar::NAN), MIN_ALPHA);
+            assert_eq!(remap.to_alpha(2.0), 1.0);
+        }
+        // Squaring keeps the low roughnesses sharp (the lowest one that isn't clamped is sqrt(MIN_ALPHA)):
+        assert_eq!(squared.to_alpha(0.03), MIN_ALPHA);
+        assert!(squared.to_alpha(0.04) > MIN_ALPHA);
+    }
+
+    #[test]
+    fn pick_lobe_selects_every_lobe_with_the_averaged_pdf() {
+        const N: usize = 10_000;
+        for count in 1..=MAX_NUM_LOBES {
+            let mut picked = vec![0usize; count];
+            for i in 0..N {
+                let u = ((i as Scalar) + 0.5) / (N as Scalar);
+                let (index, remapped_u) = pick_lobe(u, count);
+                assert!(remapped_u >= 0.0 && remapped_u < 1.0);
+                picked[index] += 1;
+            }
+            let probs: Vec<Scalar> = picked
+                .iter()
+                .map(|&n| (n as Scalar) / (N as Scalar))
+                .collect();
+            assert!((probs.iter().sum::<Scalar>() - 1.0).abs() < 1e-12);
+            // `Bsdf::sample` and `Bsdf::pdf` divide by the number of lobes, so every lobe has to be
+            // picked that often:
+            for &prob in &probs {
+                assert!((prob - 1.0 / (count as Scalar)).abs() < 1e-3);
+            }
+        }
+        let (index, remapped_u) = pick_lobe(1.0 - Scalar::EPSILON / 2.0, 3);
+        assert_eq!(index, 2);
+        assert!(remapped_u < 1.0);
+    }
+
+    #[test]
+    fn sampled_pdf_matches_the_bsdf_pdf() {
+        let mut bsdf = Bsdf::new_opaque();
+        for &r in &[0.25, 0.5, 0.75] {
+            bsdf.add_lobe(LambertianReflection::new(Color { r, g: r, b: r }));
+        }
+        let z = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        let shading_coord = ShadingCoord::with_tangent(z, z, Vec3::zero());
+        let wo = Vec3 {
+            x: 0.0,
+            y: 0.6,
+            z: 0.8,
+        };
+        for &ux in &[0.1, 0.5, 0.9] {
+            let (_, wi, pdf, _) =
+                bsdf.sample(wo, Vec2 { x: ux, y: 0.3 }, LobeType::ALL, shading_coord);
+            assert!(pdf > 0.0);
+            assert!((pdf - bsdf.pdf(wo, wi, LobeType::ALL, shading_coord)).abs() < 1e-12);
+        }
+    }
+
+    fn assert_orthonormal(coord: ShadingCoord) {
+        let (s, t, n) = (coord.s, coord.t, coord.n);
+        for &(a, b) in &[(s, t), (t, n), (n, s)] {
+            assert!(a.dot(b).abs() < 1e-9, "{:?}", coord);
+        }
+        for &v in &[s, t, n] {
+            assert!((v.length() - 1.0).abs() < 1e-9, "{:?}", coord);
+        }
+    }
+
+    #[test]
+    fn degenerate_tangents_give_orthonormal_frames() {
+        // dpdu of a sphere is zero at its poles:
+        let ray = Ray::new(
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 5.0,
+            },
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+            0.0,
+        );
+        let pole = Sphere::new(1.0).intersect(ray).unwrap();
+        assert_eq!(pole.dpdu().length(), 0.0);
+        assert_orthonormal(ShadingCoord::new(pole));
+
+        let n = Vec3 {
+            x: 0.0,
+            y: 0.6,
+            z: 0.8,
+        };
+        let tiny = Vec3 {
+            x: 1e-9,
+            y: 0.0,
+            z: 0.0,
+        };
+        for &tangent in &[Vec3::zero(), n.scale(3.0), n.scale(3.0) + tiny] {
+            assert_orthonormal(ShadingCoord::with_tangent(n, n, tangent));
+        }
+
+        // A tangent that isn't perpendicular to the shading normal keeps its direction within the
+        // tangent plane:
+        let tangent = Vec3 {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        } + n.scale(2.0);
+        let coord = ShadingCoord::with_tangent(n, n, tangent);
+        assert_orthonormal(coord);
+        assert!((coord.s.x - 1.0).abs() < 1e-9);
+    }
+}
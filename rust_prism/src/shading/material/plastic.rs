@@ -1,47 +1,93 @@
-// Need to add Microfacet support for this to work properly. So yeah
+use crate::interaction::Interaction;
+use crate::shading::lobe::lambertian::LambertianReflection;
+use crate::shading::lobe::microfacet::{MicrofacetReflection, TrowbridgeReitz};
+use crate::shading::lobe::specular::Dielectric;
+use crate::shading::material::{Bsdf, Material, RoughnessRemap};
+use crate::spectrum::Color;
+use crate::Scalar;
+use pmath::vector::Vec2;
 
-// use crate::geometry::Interaction;
-// use crate::memory::allocators::DynStackAlloc;
-// use crate::shading::lobe::lambertian::LambertianReflection;
-// use crate::shading::lobe::Lobe;
-// use crate::shading::material::{Bsdf, Material};
-// use crate::spectrum::RGBSpectrum;
+/// The index of refraction of the coating of the plastic.
+const PLASTIC_IOR: Scalar = 1.5;
 
-// pub struct Plastic {
-//     color_diffuse: RGBSpectrum,
-//     color_reflect: RGBSpectrum,
-//     roughness: f64,
-// }
+/// A diffuse surface under a glossy dielectric coating (a GGX lobe), which is the same across the entire
+/// surface.
+pub struct Plastic {
+    bsdf: Bsdf,
+}
 
-// impl Plastic {
-//     pub fn new(color_diffuse: RGBSpectrum, color_reflect: RGBSpectrum, roughness: f64) -> Self {
-//         Plastic {
-//             color_diffuse,
-//             color_reflect,
-//             roughness,
-//         }
-//     }
-// }
+impl Plastic {
+    /// `color_diffuse` is the color of the surface and `color_reflect` tints the reflections of the coating.
+    /// The roughness (between 0 and 1) is turned into the alpha of the coating with `remap`.
+    pub fn new(
+        color_diffuse: Color,
+        color_reflect: Color,
+        roughness: Scalar,
+        remap: RoughnessRemap,
+    ) -> Self {
+        let mut bsdf = Bsdf::new_opaque();
+        if !color_diffuse.is_black() {
+            bsdf.add_lobe(LambertianReflection::new(color_diffuse));
+        }
+        if !color_reflect.is_black() {
+            let alpha = remap.to_alpha(roughness);
+            bsdf.add_lobe(MicrofacetReflection::new(
+                color_reflect,
+                TrowbridgeReitz::new(Vec2 { x: alpha, y: alpha }),
+                Dielectric::new(1.0, PLASTIC_IOR),
+            ));
+        }
+        Plastic { bsdf }
+    }
+}
 
-// impl Material for Plastic {
-//     // I'll worry about generating bssrdfs later:
-//     fn compute_bsdf<'a>(
-//         &self,
-//         interaction: Interaction,
-//         memory: &'a DynStackAlloc,
-//         use_multiple_lobes: bool,
-//     ) -> Bsdf<'a> {
-//         let mut bsdf = Bsdf::new_empty(interaction, 1.);
-//         // First we need to check how many lobes we should allocate:
-//         if self.color_diffuse != 0. {
-//             let lobe = memory.push(LambertianReflection::new(self.color_diffuse)) as &'a dyn Lobe;
-//             bsdf.add_lobe(lobe);
-//         }
+impl Material for Plastic {
+    fn bsdf(&self, interaction: Interaction) -> (&Bsdf, Interaction) {
+        (&self.bsdf, interaction)
+    }
+}
 
-//         if self.color_reflect != 0. {
-//             // First, we need some sort of fresnel dielectric:
-//             let fresnel =  Dielectric::new(1., 1.5);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shading::lobe::LobeType;
+    use crate::shading::material::ShadingCoord;
+    use pmath::sampling;
+    use pmath::vector::Vec3;
 
-//         }
-//     }
-// }
+    #[test]
+    fn smooth_plastic_has_no_nans() {
+        let z = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        let shading_coord = ShadingCoord::with_tangent(z, z, Vec3::zero());
+        let color = Color::from_scalar(0.5);
+        for &remap in &[RoughnessRemap::Squared, RoughnessRemap::Linear] {
+            let plastic = Plastic::new(color, Color::white(), 0.0, remap);
+            let is_finite = |c: Color| c.r.is_finite() && c.g.is_finite() && c.b.is_finite();
+            for i in 0..64 {
+                let u = |j: u32| Vec2 {
+                    x: ((i * 7 + j) % 64) as Scalar / 64.0 + 1.0 / 128.0,
+                    y: ((i * 13 + j * 5) % 64) as Scalar / 64.0,
+                };
+                let wo = sampling::uniform_sample_hemisphere(u(0));
+                // The mirror direction is where a (nearly) delta distribution explodes:
+                let mirror = Vec3 {
+                    x: -wo.x,
+                    y: -wo.y,
+                    z: wo.z,
+                };
+                let other = sampling::uniform_sample_hemisphere(u(1));
+                for &wi in &[mirror, other, z] {
+                    let f = plastic.bsdf.eval(wo, wi, LobeType::ALL, shading_coord);
+                    let pdf = plastic.bsdf.pdf(wo, wi, LobeType::ALL, shading_coord);
+                    assert!(is_finite(f) && pdf.is_finite(), "{:?} {:?}", wo, wi);
+                }
+                let (f, wi, pdf, _) = plastic.bsdf.sample(wo, u(2), LobeType::ALL, shading_coord);
+                assert!(is_finite(f) && pdf.is_finite(), "{:?} {:?}", wo, wi);
+            }
+        }
+    }
+}
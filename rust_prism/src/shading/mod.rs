@@ -1,2 +1,4 @@
+pub mod ior;
 pub mod lobe;
 pub mod material;
+pub mod texture;
@@ -0,0 +1,215 @@
+use crate::geometry::mesh::planar_uv;
+use crate::interaction::GeomIntr;
+use crate::spectrum::Color;
+use crate::Scalar;
+use pmath::vector::Vec2;
+use std::sync::Arc;
+
+/// A texture is anything that can be looked up given a uv coordinate.
+pub trait Texture<T>: Sync + Send {
+    fn eval(&self, uv: Vec2<Scalar>) -> T;
+
+    /// Looks up the texture at an intersection. Only textures that depend on more than the uv coordinate
+    /// (like `VertexColorTexture`) need to override this.
+    fn eval_intr(&self, intr: &GeomIntr) -> T {
+        self.eval(intr.uv)
+    }
+}
+
+/// A texture that returns the same value regardless of the uv coordinate.
+#[derive(Clone, Copy, Debug)]
+pub struct ConstantTexture<T> {
+    value: T,
+}
+
+impl<T> ConstantTexture<T> {
+    pub fn new(value: T) -> Self {
+        ConstantTexture { value }
+    }
+}
+
+impl<T: Copy + Sync + Send> Texture<T> for ConstantTexture<T> {
+    fn eval(&self, _: Vec2<Scalar>) -> T {
+        self.value
+    }
+}
+
+/// A texture backed by an image. Lookups wrap (repeat) and are bilinearly filtered.
+pub struct ImageTexture {
+    texels: Vec<Color>,
+    res: Vec2<usize>,
+}
+
+impl ImageTexture {
+    pub fn new(texels: Vec<Color>, res: Vec2<usize>) -> Self {
+        assert_eq!(texels.len(), res.x * res.y);
+        ImageTexture { texels, res }
+    }
+
+    /// Constructs an image texture from 8 bit per channel data with the given number of
+    /// channels (1 through 4, extra channels are ignored). If `srgb` is set, the values are
+    /// converted to linear space.
+    pub fn from_u8(data: &[u8], channels: usize, res: Vec2<usize>, srgb: bool) -> Self {
+        let to_linear = |v: u8| {
+            let v = v as Scalar / 255.0;
+            if !srgb {
+                v
+            } else if v <= 0.04045 {
+                v / 12.92
+            } else {
+                ((v + 0.055) / 1.055).powf(2.4)
+            }
+        };
+
+        let texels = data
+            .chunks_exact(channels)
+            .map(|texel| match channels {
+                1 | 2 => Color::from_scalar(to_linear(texel[0])),
+                _ => Color {
+                    r: to_linear(texel[0]),
+                    g: to_linear(texel[1]),
+                    b: to_linear(texel[2]),
+                },
+            })
+            .collect();
+
+        ImageTexture::new(texels, res)
+    }
+
+    pub fn get_res(&self) -> Vec2<usize> {
+        self.res
+    }
+
+    fn texel(&self, x: isize, y: isize) -> Color {
+        let x = x.rem_euclid(self.res.x as isize) as usize;
+        let y = y.rem_euclid(self.res.y as isize) as usize;
+        self.texels[x + y * self.res.x]
+    }
+}
+
+impl Texture<Color> for ImageTexture {
+    fn eval(&self, uv: Vec2<Scalar>) -> Color {
+        // The v coordinate points up, the image is stored from the top down:
+        let x = uv.x * (self.res.x as Scalar) - 0.5;
+        let y = (1.0 - uv.y) * (self.res.y as Scalar) - 0.5;
+
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let dx = x - x0;
+        let dy = y - y0;
+        let (x0, y0) = (x0 as isize, y0 as isize);
+
+        let top = self.texel(x0, y0).lerp(self.texel(x0 + 1, y0), dx);
+        let bottom = self.texel(x0, y0 + 1).lerp(self.texel(x0 + 1, y0 + 1), dx);
+        top.lerp(bottom, dy)
+    }
+}
+
+/// A texture that returns the interpolated vertex color of the mesh that was hit (as read from the
+/// red, green, and blue properties of a PLY file, for instance). Geometry without vertex colors gets the
+/// fallback color instead.
+#[derive(Clone, Copy, Debug)]
+pub struct VertexColorTexture {
+    fallback: Color,
+}
+
+impl VertexColorTexture {
+    pub fn new(fallback: Color) -> Self {
+        VertexColorTexture { fallback }
+    }
+}
+
+impl Texture<Color> for VertexColorTexture {
+    fn eval(&self, _: Vec2<Scalar>) -> Color {
+        self.fallback
+    }
+
+    fn eval_intr(&self, intr: &GeomIntr) -> Color {
+        match intr.col {
+            Some(col) => Color {
+                r: col.x,
+                g: col.y,
+                b: col.z,
+            },
+            None => self.fallback,
+        }
+    }
+}
+
+/// A checkerboard of two colors with squares of `1 / scale` uv units, which is useful to check the uvs
+/// of a mesh.
+#[derive(Clone, Copy, Debug)]
+pub struct CheckerTexture {
+    even: Color,
+    odd: Color,
+    scale: Scalar,
+}
+
+impl CheckerTexture {
+    pub fn new(even: Color, odd: Color, scale: Scalar) -> Self {
+        CheckerTexture { even, odd, scale }
+    }
+}
+
+impl Texture<Color> for CheckerTexture {
+    fn eval(&self, uv: Vec2<Scalar>) -> Color {
+        let x = (uv.x * self.scale).floor() as i64;
+        let y = (uv.y * self.scale).floor() as i64;
+        if (x + y).rem_euclid(2) == 0 {
+            self.even
+        } else {
+            self.odd
+        }
+    }
+}
+
+/// Blends a texture projected along the x, y, and z axes of the object by how much the normal faces each
+/// of them, which hides the seams and stretching of planar projections on meshes without uvs (see
+/// `UvProjection::Triplanar`). Interactions without triplanar coordinates just use their uv.
+pub struct TriplanarTexture {
+    texture: Arc<dyn Texture<Color>>,
+    // The number of uv units per object space unit:
+    scale: Scalar,
+    // Higher values make the transitions between the axes sharper:
+    sharpness: Scalar,
+}
+
+impl TriplanarTexture {
+    pub fn new(texture: Arc<dyn Texture<Color>>, scale: Scalar, sharpness: Scalar) -> Self {
+        TriplanarTexture {
+            texture,
+            scale,
+            sharpness,
+        }
+    }
+}
+
+impl Texture<Color> for TriplanarTexture {
+    fn eval(&self, uv: Vec2<Scalar>) -> Color {
+        self.texture.eval(uv)
+    }
+
+    fn eval_intr(&self, intr: &GeomIntr) -> Color {
+        let coords = match intr.triplanar {
+            Some(coords) => coords,
+            None => return self.texture.eval_intr(intr),
+        };
+        let weights = [
+            coords.n.x.abs().powf(self.sharpness),
+            coords.n.y.abs().powf(self.sharpness),
+            coords.n.z.abs().powf(self.sharpness),
+        ];
+        let sum = weights[0] + weights[1] + weights[2];
+        let p = coords.p.scale(self.scale);
+        let mut color = Color::black();
+        for (axis, &weight) in weights.iter().enumerate() {
+            if weight > 0. {
+                color += self
+                    .texture
+                    .eval(planar_uv(p.cast(), axis).cast())
+                    .scale(weight / sum);
+            }
+        }
+        color
+    }
+}
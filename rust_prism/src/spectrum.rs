@@ -1,19 +1,26 @@
 // Represents color in the renderer:
 
+use crate::Scalar;
 use num_traits::clamp;
-
+use pmath::numbers::Float;
 use pmath::vector::Vec3;
+use serde::Deserialize;
 use std::ops::{Add, AddAssign, Div, Index, Mul, Sub};
 
-#[derive(Clone, Copy, Debug)]
+/// The luminous efficacy (in lumens per watt) used to convert photometric quantities (lumens, nits) to
+/// the radiometric ones the renderer uses. This is exact for light of 555nm and treats every color as if
+/// its luminance were light of that wavelength.
+pub const LUMINOUS_EFFICACY: f64 = 683.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Color {
-    pub r: f64,
-    pub g: f64,
-    pub b: f64,
+    pub r: Scalar,
+    pub g: Scalar,
+    pub b: Scalar,
 }
 
 impl Color {
-    pub fn from_vec3(v: Vec3<f64>) -> Self {
+    pub fn from_vec3(v: Vec3<Scalar>) -> Self {
         Color {
             r: v.x,
             g: v.y,
@@ -21,7 +28,7 @@ impl Color {
         }
     }
 
-    pub fn from_scalar(s: f64) -> Self {
+    pub fn from_scalar(s: Scalar) -> Self {
         Color { r: s, g: s, b: s }
     }
 
@@ -43,7 +50,7 @@ impl Color {
     }
 
     // Multiplies all of the components by the scale value:
-    pub fn scale(self, s: f64) -> Self {
+    pub fn scale(self, s: Scalar) -> Self {
         Color {
             r: self.r * s,
             g: self.g * s,
@@ -52,7 +59,7 @@ impl Color {
     }
 
     // Divides all of the components by the scale value:
-    pub fn div_scale(self, s: f64) -> Self {
+    pub fn div_scale(self, s: Scalar) -> Self {
         Color {
             r: self.r / s,
             g: self.g / s,
@@ -60,6 +67,21 @@ impl Color {
         }
     }
 
+    // The luminance of the (linear sRGB) color:
+    pub fn luminance(self) -> Scalar {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    // Scales the color to the given luminance, keeping its hue (black is treated as white):
+    pub fn with_luminance(self, luminance: Scalar) -> Self {
+        let current = self.luminance();
+        if current > 0.0 {
+            self.scale(luminance / current)
+        } else {
+            Color::from_scalar(luminance)
+        }
+    }
+
     pub fn is_black(self) -> bool {
         self.r == 0. && self.g == 0. && self.b == 0.
     }
@@ -72,7 +94,7 @@ impl Color {
         }
     }
 
-    pub fn pow(self, p: f64) -> Self {
+    pub fn pow(self, p: Scalar) -> Self {
         Color {
             r: self.r.powf(p),
             g: self.g.powf(p),
@@ -88,11 +110,11 @@ impl Color {
         }
     }
 
-    pub fn lerp(self, s2: Self, t: f64) -> Self {
+    pub fn lerp(self, s2: Self, t: Scalar) -> Self {
         self.scale(1. - t) + s2.scale(t)
     }
 
-    pub fn clamp(self, low: f64, high: f64) -> Self {
+    pub fn clamp(self, low: Scalar, high: Scalar) -> Self {
         Color {
             r: clamp(self.r, low, high),
             g: clamp(self.g, low, high),
@@ -160,9 +182,9 @@ impl Mul for Color {
 }
 
 impl Index<usize> for Color {
-    type Output = f64;
+    type Output = Scalar;
 
-    fn index(&self, i: usize) -> &f64 {
+    fn index(&self, i: usize) -> &Scalar {
         match i {
             0 => &self.r,
             1 => &self.g,
@@ -171,3 +193,226 @@ impl Index<usize> for Color {
         }
     }
 }
+
+//
+// White balance
+//
+
+/// A 3x3 matrix (an array of its rows), used for the conversions between color spaces.
+pub type ColorMatrix = [[f64; 3]; 3];
+
+// Linear sRGB (with its D65 white) to CIE XYZ and back:
+const SRGB_TO_XYZ: ColorMatrix = [
+    [0.4124564, 0.3575761, 0.1804375],
+    [0.2126729, 0.7151522, 0.0721750],
+    [0.0193339, 0.1191920, 0.9503041],
+];
+const XYZ_TO_SRGB: ColorMatrix = [
+    [3.2404542, -1.5371385, -0.4985314],
+    [-0.9692660, 1.8760108, 0.0415560],
+    [0.0556434, -0.2040259, 1.0572252],
+];
+
+// CIE XYZ to the cone responses of the Bradford transform and back:
+const BRADFORD: ColorMatrix = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+const BRADFORD_INV: ColorMatrix = [
+    [0.9869929, -0.1470543, 0.1599627],
+    [0.4323053, 0.5183603, 0.0492912],
+    [-0.0085287, 0.0400428, 0.9684867],
+];
+
+/// The xy chromaticity of the D65 white point of sRGB.
+pub const D65: (f64, f64) = (0.31271, 0.32902);
+
+/// The range of temperatures (in kelvin) `WhitePoint::Temperature` supports.
+pub const MIN_TEMPERATURE: f64 = 1667.0;
+pub const MAX_TEMPERATURE: f64 = 25000.0;
+
+fn mat_mul(a: ColorMatrix, b: ColorMatrix) -> ColorMatrix {
+    let mut m = [[0.0; 3]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    m
+}
+
+fn mat_vec(m: ColorMatrix, v: [f64; 3]) -> [f64; 3] {
+    let row = |i: usize| m[i][0] * v[0] + m[i][1] * v[1] + m[i][2] * v[2];
+    [row(0), row(1), row(2)]
+}
+
+/// The white point of a scene: the color that should end up white in the image.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum WhitePoint {
+    /// The color of a black body of this temperature (in kelvin, from `MIN_TEMPERATURE` to
+    /// `MAX_TEMPERATURE`). 6500K is close to, but not exactly, D65 (which lies slightly off of the
+    /// locus of the black bodies).
+    Temperature(f64),
+    /// An xy chromaticity.
+    Xy((f64, f64)),
+}
+
+impl WhitePoint {
+    /// The xy chromaticity of the white point. Temperatures are converted with the approximation of the
+    /// Planckian locus by Kim et al. (and clamped to the range it covers).
+    pub fn xy(self) -> (f64, f64) {
+        let t = match self {
+            WhitePoint::Xy(xy) => return xy,
+            WhitePoint::Temperature(t) => clamp(t, MIN_TEMPERATURE, MAX_TEMPERATURE),
+        };
+        let (t1, t2, t3) = (1e3 / t, 1e6 / (t * t), 1e9 / (t * t * t));
+        let x = if t <= 4000.0 {
+            -0.2661239 * t3 - 0.2343589 * t2 + 0.8776956 * t1 + 0.179910
+        } else {
+            -3.0258469 * t3 + 2.1070379 * t2 + 0.2226347 * t1 + 0.240390
+        };
+        let (x2, x3) = (x * x, x * x * x);
+        let y = if t <= 2222.0 {
+            -1.1063814 * x3 - 1.34811020 * x2 + 2.18555832 * x - 0.20219683
+        } else if t <= 4000.0 {
+            -0.9549476 * x3 - 1.37418593 * x2 + 2.09137015 * x - 0.16748867
+        } else {
+            3.0817580 * x3 - 5.87338670 * x2 + 3.75112997 * x - 0.37001483
+        };
+        (x, y)
+    }
+}
+
+/// The XYZ value (with a luminance of 1) of a chromaticity.
+pub fn xy_to_xyz((x, y): (f64, f64)) -> [f64; 3] {
+    [x / y, 1.0, (1.0 - x - y) / y]
+}
+
+/// The Bradford chromatic adaptation from the white point `from` to the white point `to` (both as xy
+/// chromaticities), which maps XYZ values seen under the first white to the XYZ values that look the
+/// same under the second one.
+pub fn bradford(from: (f64, f64), to: (f64, f64)) -> ColorMatrix {
+    let from = mat_vec(BRADFORD, xy_to_xyz(from));
+    let to = mat_vec(BRADFORD, xy_to_xyz(to));
+    let scale = [
+        [to[0] / from[0], 0.0, 0.0],
+        [0.0, to[1] / from[1], 0.0],
+        [0.0, 0.0, to[2] / from[2]],
+    ];
+    mat_mul(BRADFORD_INV, mat_mul(scale, BRADFORD))
+}
+
+/// Applies a matrix from `bradford` to an XYZ value.
+pub fn adapt_xyz(adaptation: ColorMatrix, xyz: [f64; 3]) -> [f64; 3] {
+    mat_vec(adaptation, xyz)
+}
+
+/// Maps the (linear sRGB) colors of a render so that the white point of the scene becomes the D65 white of
+/// sRGB. Renders lit by warm lights would look orange otherwise.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WhiteBalance {
+    matrix: ColorMatrix,
+}
+
+impl WhiteBalance {
+    pub fn new(white: WhitePoint) -> Self {
+        WhiteBalance {
+            matrix: mat_mul(XYZ_TO_SRGB, mat_mul(bradford(white.xy(), D65), SRGB_TO_XYZ)),
+        }
+    }
+
+    pub fn apply(&self, color: Color) -> Color {
+        let [r, g, b] = mat_vec(
+            self.matrix,
+            [color.r.to_f64(), color.g.to_f64(), color.b.to_f64()],
+        );
+        Color {
+            r: Scalar::from_f64(r),
+            g: Scalar::from_f64(g),
+            b: Scalar::from_f64(b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The white points used by Bruce Lindbloom's published Bradford matrices:
+    const D65_XYZ: [f64; 3] = [0.95047, 1.0, 1.08883];
+    const D50_XYZ: [f64; 3] = [0.96422, 1.0, 0.82521];
+
+    fn to_xy(xyz: [f64; 3]) -> (f64, f64) {
+        let sum = xyz[0] + xyz[1] + xyz[2];
+        (xyz[0] / sum, xyz[1] / sum)
+    }
+
+    fn assert_close(a: [f64; 3], b: [f64; 3], eps: f64) {
+        for i in 0..3 {
+            assert!((a[i] - b[i]).abs() < eps, "{:?} != {:?}", a, b);
+        }
+    }
+
+    fn rgb(color: Color) -> [f64; 3] {
+        [color.r.to_f64(), color.g.to_f64(), color.b.to_f64()]
+    }
+
+    #[test]
+    fn bradford_matches_the_published_matrices() {
+        let d65_to_d50 = bradford(to_xy(D65_XYZ), to_xy(D50_XYZ));
+        let published = [
+            [1.0478112, 0.0228866, -0.0501270],
+            [0.0295424, 0.9904844, -0.0170491],
+            [-0.0092345, 0.0150436, 0.7521316],
+        ];
+        for i in 0..3 {
+            assert_close(d65_to_d50[i], published[i], 1e-4);
+        }
+
+        // The whites map onto each other, and the primaries of sRGB onto the ones of sRGB adapted to D50:
+        assert_close(adapt_xyz(d65_to_d50, D65_XYZ), D50_XYZ, 1e-4);
+        let red = [SRGB_TO_XYZ[0][0], SRGB_TO_XYZ[1][0], SRGB_TO_XYZ[2][0]];
+        assert_close(
+            adapt_xyz(d65_to_d50, red),
+            [0.4360747, 0.2225045, 0.0139322],
+            1e-4,
+        );
+
+        // Adapting back undoes the adaptation:
+        let d50_to_d65 = bradford(to_xy(D50_XYZ), to_xy(D65_XYZ));
+        let xyz = [0.3, 0.5, 0.2];
+        assert_close(adapt_xyz(d50_to_d65, adapt_xyz(d65_to_d50, xyz)), xyz, 1e-6);
+    }
+
+    #[test]
+    fn temperatures_lie_on_the_planckian_locus() {
+        // Illuminant A is a black body at 2856K:
+        let (x, y) = WhitePoint::Temperature(2856.0).xy();
+        assert!((x - 0.4476).abs() < 1e-3 && (y - 0.4074).abs() < 1e-3);
+        let (x, y) = WhitePoint::Temperature(5000.0).xy();
+        assert!((x - 0.3451).abs() < 1e-3 && (y - 0.3516).abs() < 1e-3);
+        assert_eq!(
+            WhitePoint::Temperature(1e5).xy(),
+            WhitePoint::Temperature(MAX_TEMPERATURE).xy()
+        );
+    }
+
+    #[test]
+    fn d65_white_balance_changes_nothing() {
+        let balance = WhiteBalance::new(WhitePoint::Xy(D65));
+        let color = balance.apply(Color {
+            r: 0.2,
+            g: 0.7,
+            b: 1.3,
+        });
+        assert_close(rgb(color), [0.2, 0.7, 1.3], 1e-6);
+
+        // A warm white becomes white (up to the rounding of the sRGB matrices, whose white is slightly off
+        // of `D65`):
+        let balance = WhiteBalance::new(WhitePoint::Temperature(2856.0));
+        let [r, g, b] = mat_vec(XYZ_TO_SRGB, xy_to_xyz(WhitePoint::Temperature(2856.0).xy()));
+        let white = balance.apply(Color::from_vec3(Vec3 { x: r, y: g, z: b }.cast()));
+        assert_close(rgb(white), [1.0; 3], 1e-3);
+    }
+}
@@ -0,0 +1,52 @@
+//! Caches the closest intersections of the camera rays of a pass, so that the pass can be shaded again
+//! without tracing the camera rays through the entire scene. This is useful when only the materials or
+//! lights change between restarts (see `ProgressiveSession`): the camera rays of the first pass are the
+//! same after every restart, and so are their intersections.
+
+use crate::film::Film;
+use crate::scene::HitRecord;
+use std::sync::{Mutex, MutexGuard};
+
+/// The hit records of every sample of a pass, stored per tile.
+pub struct FirstHitCache {
+    // Indexed by the index of the tile, the records are in the order the samples are taken:
+    tiles: Vec<Mutex<Vec<HitRecord>>>,
+}
+
+impl FirstHitCache {
+    /// Creates an empty cache for the tiles of the film.
+    pub fn new(film: &Film) -> Self {
+        FirstHitCache {
+            tiles: (0..film.num_tiles())
+                .map(|_| Mutex::new(Vec::new()))
+                .collect(),
+        }
+    }
+
+    /// Clears the records of every tile.
+    pub fn clear(&mut self) {
+        for tile in self.tiles.iter_mut() {
+            tile.get_mut()
+                .unwrap_or_else(|err| err.into_inner())
+                .clear();
+        }
+    }
+
+    /// Returns the records of a tile. Every tile is only rendered by a single thread at a time, so this
+    /// never has to wait.
+    pub(super) fn tile(&self, index: usize) -> MutexGuard<'_, Vec<HitRecord>> {
+        self.tiles[index]
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// How a render uses a `FirstHitCache`.
+#[derive(Clone, Copy)]
+pub enum FirstHits<'a> {
+    /// Records the first hit of every sample (replacing whatever the cache had for the tile).
+    Record(&'a FirstHitCache),
+    /// Uses the recorded first hits instead of intersecting the camera rays. The camera, the samples of
+    /// the pass, and the geometry all have to be the same as when they were recorded.
+    Reuse(&'a FirstHitCache),
+}
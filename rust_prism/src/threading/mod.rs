@@ -0,0 +1,1496 @@
+pub mod first_hit;
+pub mod pixel_order;
+pub mod wavefront;
+
+use crate::camera::{Camera, CameraSample};
+use crate::film::sample_dump::SampleRecord;
+use crate::film::{Film, ImageBuffer, Pixel};
+use crate::filter::PixelFilter;
+use crate::integrator::{Integrator, IntegratorManager};
+use crate::light::light_picker::{LightPicker, LightPickerKind};
+use crate::sampler::{SampleSequence, SampleTables, Sampler, SamplerMode};
+use crate::scene::{HitRecord, Scene};
+use crate::shading::material::MaterialPool;
+use crate::threading::first_hit::FirstHits;
+use crate::threading::pixel_order::PixelOrder;
+use core_affinity;
+use pmath::vector::Vec2;
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the progress callback is invoked.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+/// The weight given to the most recent tile rate when estimating the remaining time.
+const PROGRESS_RATE_WEIGHT: f64 = 0.2;
+
+/// A snapshot of how far along a render is, passed to the progress callback.
+#[derive(Clone, Copy, Debug)]
+pub struct RenderProgress {
+    /// Between 0 and 1.
+    pub percent_complete: f64,
+    pub tiles_done: usize,
+    pub tiles_total: usize,
+    pub elapsed: Duration,
+    /// An estimate based on a moving average of the rate at which tiles are completed. `None` until
+    /// any tiles were completed.
+    pub remaining: Option<Duration>,
+    /// The number of samples per pixel that are currently being taken.
+    pub spp: u32,
+}
+
+/// Prints the progress as a single line that is updated in place. Can be passed as the progress callback
+/// to `render`.
+pub fn print_progress(progress: RenderProgress) {
+    let remaining = match progress.remaining {
+        Some(remaining) => format!("{:.1}s", remaining.as_secs_f64()),
+        None => String::from("?"),
+    };
+    eprint!(
+        "\r{:6.2}% ({}/{} tiles, {} spp), elapsed: {:.1}s, remaining: {}    ",
+        progress.percent_complete * 100.0,
+        progress.tiles_done,
+        progress.tiles_total,
+        progress.spp,
+        progress.elapsed.as_secs_f64(),
+        remaining
+    );
+    if progress.tiles_done == progress.tiles_total {
+        eprintln!();
+    }
+    let _ = std::io::stderr().flush();
+}
+
+/// Used to stop a render that is in progress (from any thread). Cloning it results in a token that
+/// cancels the same render.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Asks the render to stop as soon as possible.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Describes why a render failed.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum RenderError {
+    /// The render threads couldn't be created.
+    ThreadPool(String),
+    /// One of the render parameters is invalid.
+    InvalidParam(String),
+    /// A render thread panicked. `pixel` is the pixel it was working on (if it was working on one).
+    WorkerPanic {
+        thread_id: u32,
+        tile: Option<usize>,
+        pixel: Option<Vec2<usize>>,
+        message: String,
+    },
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderError::ThreadPool(message) => {
+                write!(f, "couldn't create the render threads: {}", message)
+            }
+            RenderError::InvalidParam(message) => {
+                write!(f, "invalid render parameter: {}", message)
+            }
+            RenderError::WorkerPanic {
+                thread_id,
+                tile,
+                pixel,
+                message,
+            } => {
+                write!(f, "render thread {} panicked", thread_id)?;
+                if let Some(tile) = tile {
+                    write!(f, " on tile {}", tile)?;
+                }
+                if let Some(pixel) = pixel {
+                    write!(f, " at pixel ({}, {})", pixel.x, pixel.y)?;
+                }
+                write!(f, ": {}", message)
+            }
+        }
+    }
+}
+
+impl Error for RenderError {}
+
+/// The result of a render.
+pub struct RenderOutput {
+    pub film: Film,
+    /// Whether the render was cancelled, in which case the film is only partially complete.
+    pub cancelled: bool,
+    /// The samples recorded if `RenderParam::sample_dump` was set, sorted by pixel (in scanline order)
+    /// and then by sample.
+    pub samples: Vec<SampleRecord>,
+}
+
+/// Collects the samples that every thread records (see `RenderParam::sample_dump`).
+struct SampleDump {
+    pmin: Vec2<usize>,
+    pmax: Vec2<usize>,
+    records: Mutex<Vec<SampleRecord>>,
+}
+
+impl SampleDump {
+    fn new((pmin, pmax): (Vec2<usize>, Vec2<usize>)) -> Self {
+        SampleDump {
+            pmin,
+            pmax,
+            records: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn contains(&self, pixel: Vec2<usize>) -> bool {
+        pixel.x >= self.pmin.x
+            && pixel.y >= self.pmin.y
+            && pixel.x < self.pmax.x
+            && pixel.y < self.pmax.y
+    }
+
+    /// Moves the samples recorded by a single thread into the dump.
+    fn append(&self, records: &mut Vec<SampleRecord>) {
+        let mut all_records = self.records.lock().unwrap_or_else(|err| err.into_inner());
+        all_records.append(records);
+    }
+
+    fn into_records(self) -> Vec<SampleRecord> {
+        let mut records = self
+            .records
+            .into_inner()
+            .unwrap_or_else(|err| err.into_inner());
+        records.sort_by_key(|record| (record.pixel.y, record.pixel.x, record.sample));
+        records
+    }
+}
+
+/// Basic parameters used independent of the integrator used.
+#[derive(Clone, Debug)]
+pub struct RenderParam {
+    /// The number of samples to perform for each pixel
+    pub num_pixel_samples: u32,
+    /// The number of threads to render with
+    pub num_threads: u32,
+    /// The seed to use when generating sample tables
+    pub sample_seed: u64,
+    /// The number of attempts when ensuring blue noise in the sampler
+    pub blue_noise_count: u32,
+    /// The sequence the samples are drawn from.
+    pub sample_sequence: SampleSequence,
+    /// How the samples of the different pixels are decorrelated.
+    pub sampler_mode: SamplerMode,
+    /// Dithers the samples of neighboring pixels with a blue noise mask, which makes the noise a lot less
+    /// visible at low sample counts (see `SampleTables::with_dither`).
+    pub blue_noise_dither: bool,
+    /// Resolution of the image (the film can be larger, see `supersample`):
+    pub res: Vec2<usize>,
+    /// The number of pixels along each side of a tile (has to be a power of two). If `None`, it's
+    /// picked based on the resolution and the number of threads.
+    pub tile_size: Option<usize>,
+    /// The order the pixels of a tile are rendered in (which doesn't change the image).
+    pub pixel_order: PixelOrder,
+    /// Picks the lights that are sampled at every shading point.
+    pub light_picker: LightPickerKind,
+    /// If set, every sample taken in the pixels from the first (inclusive) up to the second (exclusive)
+    /// coordinate of the film (see `film_res`) is recorded in `RenderOutput::samples` (see
+    /// `film::sample_dump`). Only used by `Renderer::render` and `WavefrontRenderer::render`.
+    pub sample_dump: Option<(Vec2<usize>, Vec2<usize>)>,
+    /// A grayscale image (with the same resolution as the render) that scales the number of samples
+    /// taken in every tile by its average brightness in the tile, so that the samples are spent where
+    /// they matter. The image is normalized so that the total number of samples stays the same.
+    pub importance_map: Option<Arc<ImageBuffer>>,
+    /// Keeps every other sample of every pixel separately (see `Film::with_split`), so that the image can
+    /// be split into two independent halves (and the variance of every pixel estimated).
+    pub split_buffers: bool,
+    /// The film is rendered at this many times the resolution (along each axis), so that the image can
+    /// be downscaled to `res` afterwards (see `ImageBuffer::downscale`). 1 renders at `res` directly.
+    pub supersample: u32,
+}
+
+impl RenderParam {
+    /// The smallest and largest tile size picked when `tile_size` isn't specified.
+    const MIN_TILE_SIZE: usize = 8;
+    const MAX_TILE_SIZE: usize = 64;
+    /// When picking the tile size, make sure that every thread gets at least this many tiles so that
+    /// the work is balanced.
+    const MIN_TILES_PER_THREAD: usize = 16;
+
+    /// The resolution of the film, which is larger than `res` when supersampling.
+    pub fn film_res(&self) -> Vec2<usize> {
+        let supersample = self.supersample.max(1) as usize;
+        Vec2 {
+            x: self.res.x * supersample,
+            y: self.res.y * supersample,
+        }
+    }
+
+    /// The tile size that is used when rendering.
+    pub fn tile_size(&self) -> usize {
+        if let Some(tile_size) = self.tile_size {
+            return tile_size;
+        }
+
+        let film_res = self.film_res();
+        let num_pixels = film_res.x * film_res.y;
+        let min_num_tiles = Self::MIN_TILES_PER_THREAD * (self.num_threads.max(1) as usize);
+        let mut tile_size = Self::MAX_TILE_SIZE;
+        while tile_size > Self::MIN_TILE_SIZE
+            && num_pixels / (tile_size * tile_size) < min_num_tiles
+        {
+            tile_size /= 2;
+        }
+        tile_size
+    }
+}
+
+fn new_sample_tables(param: &RenderParam) -> SampleTables {
+    let sample_tables = match param.sample_sequence {
+        SampleSequence::Pmj => SampleTables::new(param.sample_seed, param.blue_noise_count),
+        sequence => {
+            SampleTables::new_sequence(sequence, param.sample_seed, param.num_pixel_samples)
+        }
+    }
+    .with_mode(param.sampler_mode);
+    if param.blue_noise_dither {
+        sample_tables.with_dither()
+    } else {
+        sample_tables
+    }
+}
+
+/// Renders frames using a pool of threads that is created once, so that repeated renders (interactive
+/// re-rendering, animations) don't have to pay for creating threads every time.
+pub struct Renderer {
+    param: RenderParam,
+    sample_tables: SampleTables,
+    importance: Option<ImportanceMap>,
+    pool: rayon::ThreadPool,
+    // How long every tile took in the last render (see `Film::tile_costs`), so that the next render
+    // (of the next frame of an animation, say) can hand out the expensive tiles first:
+    tile_costs: Mutex<Vec<u64>>,
+}
+
+impl Renderer {
+    /// Creates the thread pool and the sample tables used by every render.
+    pub fn new(param: RenderParam) -> Result<Self, RenderError> {
+        if let Some(tile_size) = param.tile_size {
+            if !tile_size.is_power_of_two() {
+                return Err(RenderError::InvalidParam(format!(
+                    "the tile size ({}) has to be a power of two",
+                    tile_size
+                )));
+            }
+        }
+
+        let importance = match &param.importance_map {
+            Some(image) => Some(ImportanceMap::new(image, param.res, param.supersample)?),
+            None => None,
+        };
+
+        let num_threads = param.num_threads.max(1) as usize;
+
+        // Check if we will go ahead and bind threads (that is, if we can or not):
+        let core_ids = match core_affinity::get_core_ids() {
+            // If there are fewer cores than threads demanded, than don't bother binding threads:
+            Some(ids) if ids.len() >= num_threads => Some(ids),
+            _ => None,
+        };
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .thread_name(|index| format!("prism render {}", index))
+            .start_handler(move |index| {
+                if let Some(core_ids) = &core_ids {
+                    core_affinity::set_for_current(core_ids[index]);
+                }
+            })
+            .build();
+        let pool = match pool {
+            Ok(pool) => pool,
+            Err(err) => return Err(RenderError::ThreadPool(err.to_string())),
+        };
+
+        Ok(Renderer {
+            sample_tables: new_sample_tables(&param),
+            param,
+            importance,
+            pool,
+            tile_costs: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn param(&self) -> RenderParam {
+        self.param.clone()
+    }
+
+    /// Changes the seed of the sample tables, so that the frames of an animation can be rendered with
+    /// different samples without creating a new thread pool.
+    pub fn set_sample_seed(&mut self, sample_seed: u64) {
+        if sample_seed != self.param.sample_seed {
+            self.param.sample_seed = sample_seed;
+            self.sample_tables = new_sample_tables(&self.param);
+        }
+    }
+
+    /// Renders the scene. If `progress` is provided, it's called roughly every 250ms (and once more when
+    /// the render is done). If `cancel` is provided and gets cancelled, the render stops early and whatever
+    /// was rendered up to that point is returned.
+    ///
+    /// If any of the render threads panic, the others are stopped (by cancelling `cancel`) and an error
+    /// describing where the panic happened is returned.
+    pub fn render<I: Integrator, M: IntegratorManager<I>>(
+        &self,
+        scene: &Scene,
+        materials: &MaterialPool,
+        camera: &dyn Camera,
+        filter: PixelFilter,
+        int_param: M::InitParam,
+        progress: Option<&(dyn Fn(RenderProgress) + Sync)>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<RenderOutput, RenderError> {
+        let film = self.new_film();
+        let integrator_manager = M::new(int_param);
+        let sample_dump = self.param.sample_dump.map(SampleDump::new);
+
+        let default_cancel = CancellationToken::new();
+        let cancel = cancel.unwrap_or(&default_cancel);
+
+        self.render_tiles(
+            &film,
+            scene,
+            materials,
+            camera,
+            filter,
+            &integrator_manager,
+            self.param.num_pixel_samples,
+            sample_dump.as_ref(),
+            None,
+            progress,
+            cancel,
+        )?;
+        self.record_tile_costs(&film);
+
+        Ok(RenderOutput {
+            film,
+            cancelled: cancel.is_cancelled(),
+            samples: sample_dump.map_or(Vec::new(), SampleDump::into_records),
+        })
+    }
+
+    /// Creates an empty film with the resolution of the renderer. The tiles that were the most expensive
+    /// in the last render (see `record_tile_costs`) are handed out first.
+    pub fn new_film(&self) -> Film {
+        let mut film = Film::new_zero(self.param.film_res(), self.param.tile_size());
+        if self.param.split_buffers {
+            film = film.with_split();
+        }
+        film.set_tile_costs(
+            &self
+                .tile_costs
+                .lock()
+                .unwrap_or_else(|err| err.into_inner()),
+        );
+        film
+    }
+
+    /// Remembers how long every tile of the film took to render, for the films created afterwards.
+    pub fn record_tile_costs(&self, film: &Film) {
+        *self
+            .tile_costs
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = film.tile_costs();
+    }
+
+    /// Runs `op` in the thread pool of the renderer, so that any parallel iterators (of rayon) it uses
+    /// run on the threads of the renderer.
+    pub fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        self.pool.install(op)
+    }
+
+    /// Same as `render`, except that the samples are accumulated into an existing film (every tile that
+    /// the film still hands out gets `num_pixel_samples` more samples per pixel). The first hits of the
+    /// camera rays can be recorded or reused with `first_hits` (see `FirstHitCache`).
+    pub fn render_into<I: Integrator, M: IntegratorManager<I>>(
+        &self,
+        film: &Film,
+        scene: &Scene,
+        materials: &MaterialPool,
+        camera: &dyn Camera,
+        filter: PixelFilter,
+        integrator_manager: &M,
+        num_pixel_samples: u32,
+        first_hits: Option<FirstHits>,
+        progress: Option<&(dyn Fn(RenderProgress) + Sync)>,
+        cancel: &CancellationToken,
+    ) -> Result<(), RenderError> {
+        self.render_tiles(
+            film,
+            scene,
+            materials,
+            camera,
+            filter,
+            integrator_manager,
+            num_pixel_samples,
+            None,
+            first_hits,
+            progress,
+            cancel,
+        )
+    }
+
+    fn render_tiles<I: Integrator, M: IntegratorManager<I>>(
+        &self,
+        film: &Film,
+        scene: &Scene,
+        materials: &MaterialPool,
+        camera: &dyn Camera,
+        filter: PixelFilter,
+        integrator_manager: &M,
+        num_pixel_samples: u32,
+        sample_dump: Option<&SampleDump>,
+        first_hits: Option<FirstHits>,
+        progress: Option<&(dyn Fn(RenderProgress) + Sync)>,
+        cancel: &CancellationToken,
+    ) -> Result<(), RenderError> {
+        let light_picker = self.param.light_picker.create(scene);
+        let light_picker_ref: &dyn LightPicker = &*light_picker;
+
+        self.run_workers(
+            film,
+            num_pixel_samples,
+            progress,
+            cancel,
+            |id, mut sampler, location| {
+                // The integrator for this render lives with the worker it was spawned for:
+                let integrator = integrator_manager.spawn_integrator(id);
+                integrator.request_samples(&mut sampler, light_picker_ref);
+                thread_render(
+                    id,
+                    camera,
+                    filter,
+                    sampler,
+                    film,
+                    scene,
+                    materials,
+                    light_picker_ref,
+                    num_pixel_samples,
+                    self.param.pixel_order,
+                    self.importance.as_ref(),
+                    integrator,
+                    sample_dump,
+                    first_hits,
+                    cancel,
+                    location,
+                );
+            },
+        )
+    }
+
+    /// Runs `work` on every thread of the pool (with the id of the thread, a sampler, and where the
+    /// thread is in the film) while the calling thread reports the progress of `film`. If `work` panics
+    /// on any of the threads, `cancel` is cancelled and the panic is returned as an error.
+    fn run_workers<F>(
+        &self,
+        film: &Film,
+        num_pixel_samples: u32,
+        progress: Option<&(dyn Fn(RenderProgress) + Sync)>,
+        cancel: &CancellationToken,
+        work: F,
+    ) -> Result<(), RenderError>
+    where
+        F: Fn(u32, Sampler, &mut RenderLocation) + Sync,
+    {
+        let sample_tables_ref = &self.sample_tables;
+        let work_ref = &work;
+
+        // Only the first panic is reported:
+        let error = Mutex::new(None);
+        let error_ref = &error;
+
+        // The scope runs on the calling thread, which reports the progress while the workers render:
+        self.pool.in_place_scope(|s| {
+            // Every worker pulls tiles from the film until there are none left:
+            for _ in 0..self.pool.current_num_threads() {
+                s.spawn(move |_| {
+                    let id = rayon::current_thread_index().unwrap_or(0) as u32;
+                    let mut location = RenderLocation::default();
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        work_ref(id, Sampler::new(sample_tables_ref), &mut location);
+                    }));
+
+                    if let Err(payload) = result {
+                        cancel.cancel();
+                        let mut error = error_ref.lock().unwrap_or_else(|err| err.into_inner());
+                        if error.is_none() {
+                            *error = Some(RenderError::WorkerPanic {
+                                thread_id: id,
+                                tile: location.tile,
+                                pixel: location.pixel,
+                                message: panic_message(payload.as_ref()),
+                            });
+                        }
+                    }
+                });
+            }
+
+            if let Some(progress) = progress {
+                monitor_progress(film, num_pixel_samples, cancel, progress);
+            }
+        });
+
+        match error.into_inner().unwrap_or_else(|err| err.into_inner()) {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Renders a single frame. When rendering more than one frame, create a `Renderer` instead so that the
+/// threads are reused.
+pub fn render<I: Integrator, M: IntegratorManager<I>>(
+    camera: &dyn Camera,
+    filter: PixelFilter,
+    scene: &Scene,
+    materials: &MaterialPool,
+    param: RenderParam,
+    int_param: M::InitParam,
+    progress: Option<&(dyn Fn(RenderProgress) + Sync)>,
+    cancel: Option<&CancellationToken>,
+) -> Result<RenderOutput, RenderError> {
+    Renderer::new(param)?.render::<I, M>(
+        scene, materials, camera, filter, int_param, progress, cancel,
+    )
+}
+
+/// The importance of every pixel (see `RenderParam::importance_map`), normalized so that the average is 1.
+struct ImportanceMap {
+    res: Vec2<usize>,
+    values: Vec<f64>,
+    // Every pixel of the map covers this many pixels of the film along each axis (see
+    // `RenderParam::supersample`):
+    supersample: usize,
+}
+
+impl ImportanceMap {
+    fn new(image: &ImageBuffer, res: Vec2<usize>, supersample: u32) -> Result<Self, RenderError> {
+        if image.res() != res {
+            return Err(RenderError::InvalidParam(format!(
+                "the importance map ({}x{}) has to have the same resolution as the render ({}x{})",
+                image.res().x,
+                image.res().y,
+                res.x,
+                res.y
+            )));
+        }
+
+        let mut values = Vec::with_capacity(res.x * res.y);
+        for y in 0..res.y {
+            for x in 0..res.x {
+                let pixel = image.get_pixel(Vec2 { x, y });
+                values.push(((pixel.r + pixel.g + pixel.b) / 3.0).max(0.0));
+            }
+        }
+        let mean = values.iter().sum::<f64>() / (values.len().max(1) as f64);
+        if mean <= 0.0 {
+            return Err(RenderError::InvalidParam(String::from(
+                "the importance map can't be completely black",
+            )));
+        }
+        for value in values.iter_mut() {
+            *value /= mean;
+        }
+        Ok(ImportanceMap {
+            res,
+            values,
+            supersample: supersample.max(1) as usize,
+        })
+    }
+
+    /// Returns the number of samples every pixel of the tile gets (`spp` scaled by the average
+    /// importance of the tile). Every tile gets at least one sample.
+    fn tile_samples(&self, pos: Vec2<usize>, size: Vec2<usize>, spp: u32) -> u32 {
+        let mut sum = 0.0;
+        for y in pos.y..(pos.y + size.y) {
+            let row = (y / self.supersample) * self.res.x;
+            sum += (pos.x..(pos.x + size.x))
+                .map(|x| self.values[row + (x / self.supersample)])
+                .sum::<f64>();
+        }
+        let mean = sum / ((size.x * size.y).max(1) as f64);
+        ((mean * (spp as f64)).round() as u32).max(1)
+    }
+}
+
+/// Where a render thread is in the film, so that it can be reported if it panics.
+#[derive(Clone, Copy, Debug, Default)]
+struct RenderLocation {
+    tile: Option<usize>,
+    pixel: Option<Vec2<usize>>,
+}
+
+/// Retrieves the message passed to `panic!`, if there is one.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        String::from(*message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("unknown panic")
+    }
+}
+
+/// Periodically reports the progress of the film until every tile is done or the render is cancelled, and
+/// then reports it one last time.
+fn monitor_progress(
+    film: &Film,
+    spp: u32,
+    cancel: &CancellationToken,
+    progress: &(dyn Fn(RenderProgress) + Sync),
+) {
+    let tiles_total = film.num_tiles();
+    let start = Instant::now();
+    let mut last_report = start;
+    let mut last_tiles_done = 0;
+    // In tiles per second:
+    let mut tile_rate: Option<f64> = None;
+
+    let report = |tiles_done: usize, tile_rate: Option<f64>| {
+        let remaining = match tile_rate {
+            Some(rate) if rate > 0.0 => Some(Duration::from_secs_f64(
+                ((tiles_total - tiles_done) as f64) / rate,
+            )),
+            _ => None,
+        };
+        progress(RenderProgress {
+            percent_complete: if tiles_total == 0 {
+                1.0
+            } else {
+                (tiles_done as f64) / (tiles_total as f64)
+            },
+            tiles_done,
+            tiles_total,
+            elapsed: start.elapsed(),
+            remaining,
+            spp,
+        });
+    };
+
+    while film.num_tiles_complete() < tiles_total && !cancel.is_cancelled() {
+        // Sleep in small increments so that we don't hold up the render once it's done:
+        std::thread::sleep(Duration::from_millis(10));
+        let now = Instant::now();
+        let dt = now.duration_since(last_report);
+        if dt < PROGRESS_INTERVAL {
+            continue;
+        }
+
+        let tiles_done = film.num_tiles_complete().min(tiles_total);
+        let rate = ((tiles_done - last_tiles_done) as f64) / dt.as_secs_f64();
+        tile_rate = Some(match tile_rate {
+            Some(tile_rate) => {
+                PROGRESS_RATE_WEIGHT * rate + (1.0 - PROGRESS_RATE_WEIGHT) * tile_rate
+            }
+            None => rate,
+        });
+        last_report = now;
+        last_tiles_done = tiles_done;
+
+        report(tiles_done, tile_rate);
+    }
+
+    report(film.num_tiles_complete().min(tiles_total), tile_rate);
+}
+
+/// The render function is the function that loops over specified tiles until the film
+/// returns `None` for the tiles.
+///
+/// # Arguments
+/// * `id` - The id of the current thread.
+/// * `camera` - The camera that is being used to render the scene.
+/// * `filter` - The filter used when sampling points on the film.
+/// * `sampler` - The sampler that is being used by the integrator.
+/// * `film` - The film being rendered to.
+/// * `scene` - The scene being rendered.
+/// * `materials` - The materials used by the scene.
+/// * `light_picker` - Picks the lights to sample at every shading point.
+/// * `num_pixel_samples` - The number of samples to perform per pixel
+/// * `pixel_order` - The order the pixels of a tile are rendered in.
+/// * `importance` - If set, scales the number of samples of every tile.
+/// * `integrator` - The integrator to be used by this specific thread
+/// * `sample_dump` - If set, the samples of the pixels in it are recorded.
+/// * `first_hits` - If set, the first hits of the camera rays are recorded or reused.
+/// * `cancel` - Checked between pixels, the thread returns once it's cancelled.
+/// * `location` - Updated with the tile and pixel that are being rendered.
+fn thread_render<I: Integrator>(
+    _id: u32,
+    camera: &dyn Camera,
+    filter: PixelFilter,
+    mut sampler: Sampler,
+    film: &Film,
+    scene: &Scene,
+    materials: &MaterialPool,
+    light_picker: &dyn LightPicker,
+    num_pixel_samples: u32,
+    pixel_order: PixelOrder,
+    importance: Option<&ImportanceMap>,
+    mut integrator: I,
+    sample_dump: Option<&SampleDump>,
+    first_hits: Option<FirstHits>,
+    cancel: &CancellationToken,
+    location: &mut RenderLocation,
+) {
+    // The samples of this thread are only added to the dump once it's done:
+    let mut records = Vec::new();
+    let mut tile_order = Vec::new();
+    let tile_area = (film.tile_dim() * film.tile_dim()) as u32;
+
+    while !cancel.is_cancelled() {
+        // When getting the next tile, we also check if any tiles are left in this pass.
+        let mut film_tile = match film.get_tile() {
+            Some(film_tile) => film_tile,
+            _ => break,
+        };
+
+        location.tile = Some(film_tile.index);
+        let tile_samples = importance.map_or(num_pixel_samples, |importance| {
+            importance.tile_samples(film_tile.pos, film_tile.size, num_pixel_samples)
+        });
+
+        // The hit records of the tile, in the order the samples are taken:
+        let mut hit_records = match first_hits {
+            Some(FirstHits::Record(cache)) | Some(FirstHits::Reuse(cache)) => {
+                Some(cache.tile(film_tile.index))
+            }
+            None => None,
+        };
+        if let (Some(FirstHits::Record(_)), Some(records)) = (first_hits, &mut hit_records) {
+            records.clear();
+        }
+        let mut hit_index = 0;
+
+        pixel_order.tile_order(film_tile.size, &mut tile_order);
+        for &i in tile_order.iter() {
+            // Keep whatever was rendered of the tile so far:
+            if cancel.is_cancelled() {
+                break;
+            }
+            // Make sure we are able to retrieve the next pixel position:
+            let pixel_index = Vec2 {
+                x: film_tile.pos.x + (i % film_tile.size.x),
+                y: film_tile.pos.y + (i / film_tile.size.x),
+            };
+            location.pixel = Some(pixel_index);
+            // The samples of the pixel only depend on its index, not on the order:
+            sampler.start_pixel(film_tile.seed as u32, tile_area, i as u32);
+            sampler.set_pixel_pos(pixel_index);
+            let pixel_pos = Vec2 {
+                x: pixel_index.x as f64 + 0.5,
+                y: pixel_index.y as f64 + 0.5,
+            };
+
+            let dump_pixel = sample_dump.map_or(false, |dump| dump.contains(pixel_index));
+
+            // Loop over all of the paths:
+            for sample in 0..tile_samples {
+                sampler.start_pixel_sample();
+
+                // Generate a camera ray:
+                let camera_sample = CameraSample {
+                    p_film: (pixel_pos + filter.sample_pos(sampler.sample_film_pos().cast()))
+                        .cast(),
+                    p_lens: sampler.sample(),
+                    time: sampler.sample().x,
+                };
+                let prim_ray = camera.gen_primary_ray(camera_sample);
+
+                // Find the first hit if it's recorded (or has to be):
+                let first_hit = match (first_hits, &mut hit_records) {
+                    (Some(FirstHits::Record(_)), Some(records)) => {
+                        let first_hit = scene.intersect(prim_ray.ray);
+                        records.push(HitRecord::new(&first_hit));
+                        Some(first_hit)
+                    }
+                    (Some(FirstHits::Reuse(_)), Some(records)) => {
+                        let first_hit = match records.get(hit_index) {
+                            Some(&hit) => scene.interaction_from_hit(prim_ray.ray, hit),
+                            None => scene.intersect(prim_ray.ray),
+                        };
+                        hit_index += 1;
+                        Some(first_hit)
+                    }
+                    _ => None,
+                };
+                let mut integrate = |pixel: Pixel| match first_hit {
+                    Some(first_hit) => integrator.integrate_hit(
+                        prim_ray,
+                        first_hit,
+                        scene,
+                        materials,
+                        light_picker,
+                        &mut sampler,
+                        pixel,
+                    ),
+                    None => integrator.integrate(
+                        prim_ray,
+                        scene,
+                        materials,
+                        light_picker,
+                        &mut sampler,
+                        pixel,
+                    ),
+                };
+
+                // Now go ahead and integrate for this ray:
+                if dump_pixel || film_tile.is_split() {
+                    // Integrate the sample on its own, so that its value can be recorded (or added to
+                    // the right half):
+                    let sample_pixel = integrate(Pixel::black());
+                    if dump_pixel {
+                        records.push(SampleRecord::from_sample(pixel_index, sample, sample_pixel));
+                    }
+                    film_tile.add_sample(i, sample_pixel);
+                } else {
+                    film_tile.data[i] = integrate(film_tile.data[i]);
+                }
+            }
+        }
+
+        film.set_tile(film_tile);
+        location.pixel = None;
+    }
+
+    if let Some(sample_dump) = sample_dump {
+        sample_dump.append(&mut records);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::perspective::PerspectiveCamera;
+    use crate::film::sample_dump;
+    use crate::film::ImagePixel;
+    use crate::filter::{BoxFilter, GaussianFilter, LanczosSincFilter, TriangleFilter};
+    use crate::geometry::sphere::Sphere;
+    use crate::integrator::normal::{NormalIntegrator, NormalIntegratorManager};
+    use crate::scene::{SceneGeom, ScenePrim};
+    use crate::shading::material::DEFAULT_MATERIAL_ID;
+    use crate::spectrum::Color;
+    use crate::transform::Transf;
+    use crate::Scalar;
+    use pmath::numbers::Float;
+    use pmath::ray::PrimaryRay;
+    use pmath::vector::Vec3;
+    use std::collections::{HashMap, HashSet};
+    use std::thread::ThreadId;
+
+    fn sphere_scene() -> (Scene, MaterialPool) {
+        let materials = MaterialPool::new();
+        let sphere = SceneGeom::new_material(
+            Arc::new(Sphere::new(1.0)),
+            materials.get_shared_material(DEFAULT_MATERIAL_ID),
+            Transf::new_identity(),
+        );
+        let prims: Vec<Arc<dyn ScenePrim>> = vec![Arc::new(sphere)];
+        (Scene::new(&prims, Vec::new()), materials)
+    }
+
+    fn test_param() -> RenderParam {
+        RenderParam {
+            num_pixel_samples: 4,
+            num_threads: 2,
+            sample_seed: 3,
+            blue_noise_count: 0,
+            sample_sequence: SampleSequence::Pmj,
+            sampler_mode: SamplerMode::Scrambled,
+            blue_noise_dither: false,
+            res: Vec2 { x: 64, y: 48 },
+            tile_size: Some(8),
+            pixel_order: PixelOrder::Scanline,
+            light_picker: LightPickerKind::All,
+            sample_dump: None,
+            importance_map: None,
+            split_buffers: false,
+            supersample: 1,
+        }
+    }
+
+    /// The scene of `sphere_scene`, framed by a camera with the resolution of the renderer's film.
+    fn sphere_fixture(renderer: &Renderer) -> (Scene, MaterialPool, PerspectiveCamera) {
+        let (scene, materials) = sphere_scene();
+        let camera = PerspectiveCamera::frame_bbox(
+            scene.world_bound(),
+            45.0,
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            renderer.param().film_res(),
+        );
+        (scene, materials, camera)
+    }
+
+    fn render_sphere(
+        renderer: &Renderer,
+        progress: Option<&(dyn Fn(RenderProgress) + Sync)>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<RenderOutput, RenderError> {
+        let (scene, materials, camera) = sphere_fixture(renderer);
+        let filter = PixelFilter::new(&GaussianFilter::new(Vec2 { x: 1.0, y: 1.0 }, 0.5));
+        renderer.render::<NormalIntegrator, NormalIntegratorManager>(
+            &scene, &materials, &camera, filter, false, progress, cancel,
+        )
+    }
+
+    #[test]
+    fn progress_is_monotonic_and_reaches_the_end() {
+        let reports = Mutex::new(Vec::new());
+        let progress = |progress: RenderProgress| reports.lock().unwrap().push(progress);
+        render_sphere(&Renderer::new(test_param()).unwrap(), Some(&progress), None).unwrap();
+
+        let reports = reports.into_inner().unwrap();
+        let last = reports.last().unwrap();
+        assert_eq!(last.tiles_total, 8 * 6);
+        assert_eq!(last.tiles_done, last.tiles_total);
+        assert_eq!(last.percent_complete, 1.0);
+        for pair in reports.windows(2) {
+            assert!(pair[0].tiles_done <= pair[1].tiles_done);
+            assert!(pair[0].percent_complete <= pair[1].percent_complete);
+            assert!(pair[0].elapsed <= pair[1].elapsed);
+        }
+    }
+
+    #[test]
+    fn cancelled_renders_return_promptly_with_a_partial_film() {
+        let renderer = Renderer::new(RenderParam {
+            num_pixel_samples: 64,
+            res: Vec2 { x: 2048, y: 2048 },
+            tile_size: Some(16),
+            ..test_param()
+        })
+        .unwrap();
+        let cancel = CancellationToken::new();
+        let canceller = {
+            let cancel = cancel.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(100));
+                cancel.cancel();
+            })
+        };
+
+        let start = Instant::now();
+        let output = render_sphere(&renderer, None, Some(&cancel)).unwrap();
+        canceller.join().unwrap();
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "{:?}",
+            start.elapsed()
+        );
+        assert!(output.cancelled);
+        assert!(output.film.num_tiles_complete() < output.film.num_tiles());
+
+        let image = output.film.to_image_buffer(ImagePixel::from_color);
+        for y in 0..2048 {
+            for x in 0..2048 {
+                let pixel = image.get_pixel(Vec2 { x, y });
+                assert!(pixel.r.is_finite() && pixel.g.is_finite() && pixel.b.is_finite());
+            }
+        }
+    }
+
+    // Spawns normal integrators, recording the threads they were spawned on.
+    struct ThreadRecorder {
+        normal: NormalIntegratorManager,
+        threads: Arc<Mutex<HashSet<ThreadId>>>,
+    }
+
+    impl IntegratorManager<NormalIntegrator> for ThreadRecorder {
+        type InitParam = Arc<Mutex<HashSet<ThreadId>>>;
+
+        fn new(threads: Self::InitParam) -> Self {
+            ThreadRecorder {
+                normal: NormalIntegratorManager::new(false),
+                threads,
+            }
+        }
+
+        fn spawn_integrator(&self, thread_id: u32) -> NormalIntegrator {
+            let mut threads = self.threads.lock().unwrap();
+            threads.insert(std::thread::current().id());
+            self.normal.spawn_integrator(thread_id)
+        }
+    }
+
+    #[test]
+    fn repeated_renders_reuse_the_threads() {
+        let renderer = Renderer::new(test_param()).unwrap();
+        let (scene, materials, camera) = sphere_fixture(&renderer);
+        let filter = PixelFilter::new(&GaussianFilter::new(Vec2 { x: 1.0, y: 1.0 }, 0.5));
+        let threads = Arc::new(Mutex::new(HashSet::new()));
+
+        let mut images = Vec::new();
+        for _ in 0..3 {
+            let output = renderer
+                .render::<NormalIntegrator, ThreadRecorder>(
+                    &scene,
+                    &materials,
+                    &camera,
+                    filter,
+                    threads.clone(),
+                    None,
+                    None,
+                )
+                .unwrap();
+            images.push(output.film.to_image_buffer(ImagePixel::from_color));
+        }
+
+        // Every render ran on the (at most) 2 threads of the pool, and not on the calling thread:
+        let threads = threads.lock().unwrap();
+        assert!(
+            !threads.is_empty() && threads.len() <= 2,
+            "{}",
+            threads.len()
+        );
+        assert!(!threads.contains(&std::thread::current().id()));
+        for image in &images[1..] {
+            assert_eq!(images[0].diff(image).unwrap().max_error, 0.0);
+        }
+    }
+
+    // Panics when it renders the pixel (37, 21).
+    struct PanickingIntegrator;
+
+    impl Integrator for PanickingIntegrator {
+        fn integrate(
+            &mut self,
+            _: PrimaryRay<Scalar>,
+            _: &Scene,
+            _: &MaterialPool,
+            _: &dyn LightPicker,
+            sampler: &mut Sampler,
+            pixel: Pixel,
+        ) -> Pixel {
+            if sampler.pixel_pos() == Some(Vec2 { x: 37, y: 21 }) {
+                panic!("the integrator failed");
+            }
+            pixel.add_sample(Color::black())
+        }
+    }
+
+    struct PanickingIntegratorManager;
+
+    impl IntegratorManager<PanickingIntegrator> for PanickingIntegratorManager {
+        type InitParam = ();
+
+        fn new(_: ()) -> Self {
+            PanickingIntegratorManager
+        }
+
+        fn spawn_integrator(&self, _: u32) -> PanickingIntegrator {
+            PanickingIntegrator
+        }
+    }
+
+    #[test]
+    fn worker_panics_are_reported_with_the_pixel() {
+        let renderer = Renderer::new(test_param()).unwrap();
+        let (scene, materials, camera) = sphere_fixture(&renderer);
+        let filter = PixelFilter::new(&GaussianFilter::new(Vec2 { x: 1.0, y: 1.0 }, 0.5));
+        let result = renderer.render::<PanickingIntegrator, PanickingIntegratorManager>(
+            &scene,
+            &materials,
+            &camera,
+            filter,
+            (),
+            None,
+            None,
+        );
+
+        match result {
+            Err(RenderError::WorkerPanic { pixel, message, .. }) => {
+                assert_eq!(pixel, Some(Vec2 { x: 37, y: 21 }));
+                assert_eq!(message, "the integrator failed");
+            }
+            _ => panic!("the panic wasn't reported"),
+        }
+        // The pool is still usable afterwards:
+        assert!(renderer
+            .render::<NormalIntegrator, NormalIntegratorManager>(
+                &scene, &materials, &camera, filter, false, None, None,
+            )
+            .is_ok());
+    }
+
+    // Records the pixels it renders, and how many samples each of them got.
+    struct PixelRecorder(Arc<Mutex<HashMap<(u32, u32), u32>>>);
+
+    impl Integrator for PixelRecorder {
+        fn integrate(
+            &mut self,
+            _: PrimaryRay<Scalar>,
+            _: &Scene,
+            _: &MaterialPool,
+            _: &dyn LightPicker,
+            sampler: &mut Sampler,
+            pixel: Pixel,
+        ) -> Pixel {
+            let pos = sampler.pixel_pos().unwrap();
+            *self.0.lock().unwrap().entry((pos.x, pos.y)).or_insert(0) += 1;
+            pixel.add_sample(Color::white())
+        }
+    }
+
+    struct PixelRecorderManager(Arc<Mutex<HashMap<(u32, u32), u32>>>);
+
+    impl IntegratorManager<PixelRecorder> for PixelRecorderManager {
+        type InitParam = Arc<Mutex<HashMap<(u32, u32), u32>>>;
+
+        fn new(pixels: Self::InitParam) -> Self {
+            PixelRecorderManager(pixels)
+        }
+
+        fn spawn_integrator(&self, _: u32) -> PixelRecorder {
+            PixelRecorder(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn partial_tiles_only_render_pixels_in_the_image() {
+        let res = Vec2 { x: 397, y: 251 };
+        let orders = [
+            PixelOrder::Scanline,
+            PixelOrder::Morton,
+            PixelOrder::Hilbert,
+        ];
+        let params = [Some(16), None]
+            .iter()
+            .flat_map(|&tile_size| orders.iter().map(move |&order| (tile_size, order)));
+        for (tile_size, pixel_order) in params {
+            let renderer = Renderer::new(RenderParam {
+                num_pixel_samples: 1,
+                res,
+                tile_size,
+                pixel_order,
+                ..test_param()
+            })
+            .unwrap();
+            let (scene, materials, camera) = sphere_fixture(&renderer);
+            let filter = PixelFilter::new(&GaussianFilter::new(Vec2 { x: 1.0, y: 1.0 }, 0.5));
+            let pixels = Arc::new(Mutex::new(HashMap::new()));
+            let output = renderer
+                .render::<PixelRecorder, PixelRecorderManager>(
+                    &scene,
+                    &materials,
+                    &camera,
+                    filter,
+                    pixels.clone(),
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            // Every pixel in the image was rendered once, and nothing outside of it:
+            let pixels = pixels.lock().unwrap();
+            assert_eq!(pixels.len(), res.x * res.y);
+            for (&(x, y), &count) in pixels.iter() {
+                assert!(
+                    (x as usize) < res.x && (y as usize) < res.y,
+                    "({}, {})",
+                    x,
+                    y
+                );
+                assert_eq!(count, 1);
+            }
+            let image = output.film.to_image_buffer(ImagePixel::from_color);
+            assert_eq!(image.res(), res);
+            let corner = image.get_pixel(Vec2 { x: 396, y: 250 });
+            assert!((corner.r - 1.0).abs() < 1e-9, "{}", corner.r);
+        }
+    }
+
+    #[test]
+    fn wide_filters_leave_flat_fields_flat() {
+        // The filters are sampled instead of weighting the samples, so they don't change the brightness
+        // of the image. Samples land up to 3 pixels away from their pixel, so a lot of them land in
+        // neighboring tiles:
+        let radius = Vec2 { x: 3.0, y: 3.0 };
+        let filters = [
+            PixelFilter::new(&BoxFilter::new(radius)),
+            PixelFilter::new(&TriangleFilter::new(radius)),
+            PixelFilter::new(&GaussianFilter::from_std_dev(radius, 1.0)),
+            PixelFilter::with_table_width(&LanczosSincFilter::new(radius, 3.0), 16),
+        ];
+        let res = Vec2 { x: 40, y: 24 };
+        let renderer = Renderer::new(RenderParam {
+            res,
+            tile_size: Some(8),
+            ..test_param()
+        })
+        .unwrap();
+        let (scene, materials, camera) = sphere_fixture(&renderer);
+        for &filter in filters.iter() {
+            let output = renderer
+                .render::<PixelRecorder, PixelRecorderManager>(
+                    &scene,
+                    &materials,
+                    &camera,
+                    filter,
+                    Arc::new(Mutex::new(HashMap::new())),
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            // The pixels on the borders of the tiles are just as bright as the rest:
+            let image = output.film.to_image_buffer(ImagePixel::from_color);
+            for y in 0..res.y {
+                for x in 0..res.x {
+                    let pixel = image.get_pixel(Vec2 { x, y });
+                    assert!((pixel.r - 1.0).abs() < 1e-9, "({}, {}) {}", x, y, pixel.r);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pixel_orders_render_the_same_image() {
+        let render = |pixel_order| {
+            let renderer = Renderer::new(RenderParam {
+                pixel_order,
+                ..test_param()
+            })
+            .unwrap();
+            render_sphere(&renderer, None, None)
+                .unwrap()
+                .film
+                .to_image_buffer(ImagePixel::from_color)
+        };
+        let scanline = render(PixelOrder::Scanline);
+        for &order in &[PixelOrder::Morton, PixelOrder::Hilbert] {
+            assert_eq!(scanline.diff(&render(order)).unwrap().max_error, 0.0);
+        }
+    }
+
+    #[test]
+    fn importance_maps_move_samples_to_the_bright_half() {
+        // The left half of the map is black, and the right half is white:
+        let res = test_param().res;
+        let mut map = Film::new(res, 8, Pixel::black()).to_image_buffer(ImagePixel::from_color);
+        for x in (res.x / 2)..res.x {
+            let x = (x as f64) + 0.5;
+            let (top, bottom) = (Vec2 { x, y: 0.0 }, Vec2 { x, y: res.y as f64 });
+            map.draw_line(top, bottom, ImagePixel::from_rgb(1.0, 1.0, 1.0));
+        }
+
+        let renderer = Renderer::new(RenderParam {
+            importance_map: Some(Arc::new(map.clone())),
+            ..test_param()
+        })
+        .unwrap();
+        let (scene, materials, camera) = sphere_fixture(&renderer);
+        let filter = PixelFilter::new(&GaussianFilter::new(Vec2 { x: 1.0, y: 1.0 }, 0.5));
+        let pixels = Arc::new(Mutex::new(HashMap::new()));
+        renderer
+            .render::<PixelRecorder, PixelRecorderManager>(
+                &scene,
+                &materials,
+                &camera,
+                filter,
+                pixels.clone(),
+                None,
+                None,
+            )
+            .unwrap();
+
+        // The white half has twice the average importance, and the black half still gets a sample:
+        let spp = test_param().num_pixel_samples;
+        let pixels = pixels.lock().unwrap();
+        assert_eq!(pixels.len(), res.x * res.y);
+        for (&(x, _), &count) in pixels.iter() {
+            let expected = if (x as usize) < res.x / 2 { 1 } else { 2 * spp };
+            assert_eq!(count, expected, "{}", x);
+        }
+
+        // The map has to match the resolution and can't be black:
+        let small = map.crop(Vec2 { x: 0, y: 0 }, Vec2 { x: 32, y: 48 });
+        let black = map.map(|_| ImagePixel::zero());
+        for map in vec![small, black] {
+            let param = RenderParam {
+                importance_map: Some(Arc::new(map)),
+                ..test_param()
+            };
+            match Renderer::new(param) {
+                Err(RenderError::InvalidParam(_)) => (),
+                _ => panic!("invalid importance maps have to be rejected"),
+            }
+        }
+    }
+
+    #[test]
+    fn sample_dumps_match_the_film() {
+        // A rectangle on the edge of the sphere, so that the samples of a pixel differ:
+        let (pmin, pmax) = (Vec2 { x: 40, y: 20 }, Vec2 { x: 52, y: 28 });
+        let renderer = Renderer::new(RenderParam {
+            sample_dump: Some((pmin, pmax)),
+            ..test_param()
+        })
+        .unwrap();
+        let output = render_sphere(&renderer, None, None).unwrap();
+        assert_eq!(output.samples.len(), 12 * 8 * 4);
+
+        let path = std::env::temp_dir().join("prism_sample_dump_test.bin");
+        let path = path.to_str().unwrap();
+        sample_dump::write_sample_dump(&output.samples, path).unwrap();
+        let records = sample_dump::load_sample_dump(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(records, output.samples);
+
+        let image = output.film.to_image_buffer(ImagePixel::from_color);
+        let stats = sample_dump::sample_stats(&records);
+        assert_eq!(stats.len(), 12 * 8);
+        let mut num_varying = 0;
+        for (&(x, y), stats) in stats.iter() {
+            let pixel = image.get_pixel(Vec2 {
+                x: x as usize,
+                y: y as usize,
+            });
+            assert_eq!(stats.count, 4);
+            assert_eq!(stats.mean, pixel.to_color());
+            if !stats.variance.is_black() {
+                num_varying += 1;
+            }
+        }
+        assert!(num_varying > 0);
+    }
+
+    // Renders a chart of vertical stripes, as seen through a camera at the origin that looks down the
+    // z-axis with a field-of-view of 90 degrees. The stripes of the first frequency (in cycles per pixel
+    // of an image of the given resolution) can be shown, the ones of the second can't.
+    struct ChartIntegrator(Vec2<usize>);
+
+    impl ChartIntegrator {
+        const LOW_FREQ: f64 = 0.1;
+        const HIGH_FREQ: f64 = 0.7;
+
+        fn eval(&self, x: f64) -> f64 {
+            let wave = |freq: f64| (2.0 * std::f64::consts::PI * freq * x).cos();
+            0.5 + 0.25 * wave(Self::LOW_FREQ) + 0.25 * wave(Self::HIGH_FREQ)
+        }
+    }
+
+    impl Integrator for ChartIntegrator {
+        fn integrate(
+            &mut self,
+            ray: PrimaryRay<Scalar>,
+            _: &Scene,
+            _: &MaterialPool,
+            _: &dyn LightPicker,
+            _: &mut Sampler,
+            pixel: Pixel,
+        ) -> Pixel {
+            // From the screen window of the camera to the pixels of the image:
+            let aspect = (self.0.x as f64) / (self.0.y as f64);
+            let dir = ray.ray.dir.cast::<f64>();
+            let x = (dir.x / dir.z + aspect) / (2.0 * aspect) * (self.0.x as f64);
+            let value = Scalar::from_f64(self.eval(x));
+            pixel.add_sample(Color {
+                r: value,
+                g: value,
+                b: value,
+            })
+        }
+    }
+
+    struct ChartIntegratorManager(Vec2<usize>);
+
+    impl IntegratorManager<ChartIntegrator> for ChartIntegratorManager {
+        type InitParam = Vec2<usize>;
+
+        fn new(res: Self::InitParam) -> Self {
+            ChartIntegratorManager(res)
+        }
+
+        fn spawn_integrator(&self, _: u32) -> ChartIntegrator {
+            ChartIntegrator(self.0)
+        }
+    }
+
+    #[test]
+    fn supersampling_reduces_aliasing() {
+        let res = Vec2 { x: 64, y: 16 };
+        let (scene, materials) = sphere_scene();
+        // Both images get the same number of samples:
+        let render = |supersample: u32, num_pixel_samples| {
+            let renderer = Renderer::new(RenderParam {
+                res,
+                supersample,
+                num_pixel_samples,
+                ..test_param()
+            })
+            .unwrap();
+            let film_res = renderer.param().film_res();
+            let camera = PerspectiveCamera::new(
+                Transf::new_identity(),
+                90.0,
+                0.0,
+                1.0,
+                PerspectiveCamera::default_screen_window(film_res),
+                film_res,
+            );
+            let filter = PixelFilter::new(&BoxFilter::new(Vec2 { x: 0.5, y: 0.5 }));
+            let film = renderer
+                .render::<ChartIntegrator, ChartIntegratorManager>(
+                    &scene, &materials, &camera, filter, res, None, None,
+                )
+                .unwrap()
+                .film;
+            let image = film.to_image_buffer(ImagePixel::from_color);
+            image.downscale(supersample as usize)
+        };
+
+        // The error compared to an image that only has the low frequency stripes (away from the edges,
+        // where the filter of the downscaling is cut off):
+        let error = |image: ImageBuffer| {
+            assert_eq!(image.res(), res);
+            let mut sum = 0.0;
+            let mut count = 0.0;
+            for y in 0..res.y {
+                for x in 4..(res.x - 4) {
+                    let wave = (2.0 * std::f64::consts::PI * ChartIntegrator::LOW_FREQ)
+                        * ((x as f64) + 0.5);
+                    let expected = 0.5 + 0.25 * wave.cos();
+                    sum += (image.get_pixel(Vec2 { x, y }).r - expected).powi(2);
+                    count += 1.0;
+                }
+            }
+            (sum / count).sqrt()
+        };
+        let error_1x = error(render(1, 16));
+        let error_2x = error(render(2, 4));
+        assert!(
+            error_2x < 0.5 * error_1x,
+            "{} isn't much smaller than {}",
+            error_2x,
+            error_1x
+        );
+    }
+}
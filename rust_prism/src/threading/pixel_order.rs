@@ -0,0 +1,147 @@
+//! The order the pixels of a tile are rendered in. Neighboring pixels trace similar camera rays, so
+//! visiting them close together in time keeps the nodes of the BVHs they traverse in the cache. The
+//! samples of a pixel don't depend on the order (see `Sampler::start_pixel`), so neither does the image.
+
+use pmath::vector::Vec2;
+use serde::Deserialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum PixelOrder {
+    /// Row by row, from the top left pixel.
+    Scanline,
+    /// Along the Z-order curve, which recursively visits the four quadrants of the tile.
+    Morton,
+    /// Along the Hilbert curve, which (unlike the Z-order curve) only ever steps to a neighboring pixel.
+    Hilbert,
+}
+
+impl Default for PixelOrder {
+    fn default() -> Self {
+        PixelOrder::Scanline
+    }
+}
+
+impl PixelOrder {
+    /// Fills `order` with the index (in scanline order) of every pixel of a tile of size `size`, in the
+    /// order they should be visited. Every index shows up exactly once.
+    pub fn tile_order(self, size: Vec2<usize>, order: &mut Vec<usize>) {
+        order.clear();
+        let num_pixels = size.x * size.y;
+        if self == PixelOrder::Scanline {
+            order.extend(0..num_pixels);
+            return;
+        }
+
+        // The curves cover a square with a power of two side, so any pixels outside of the tile (which
+        // isn't square at the edges of the film) are skipped:
+        let side = size.x.max(size.y).next_power_of_two();
+        order.reserve(num_pixels);
+        for d in 0..(side * side) {
+            let p = match self {
+                PixelOrder::Morton => morton_decode(d),
+                _ => hilbert_decode(side, d),
+            };
+            if p.x < size.x && p.y < size.y {
+                order.push(p.x + p.y * size.x);
+            }
+        }
+    }
+}
+
+/// The position of the `d`th point along the Z-order curve.
+fn morton_decode(d: usize) -> Vec2<usize> {
+    // Every other bit belongs to x:
+    let compact = |mut v: usize| {
+        let mut result = 0;
+        let mut bit = 0;
+        while v != 0 {
+            result |= (v & 1) << bit;
+            v >>= 2;
+            bit += 1;
+        }
+        result
+    };
+    Vec2 {
+        x: compact(d),
+        y: compact(d >> 1),
+    }
+}
+
+/// The position of the `d`th point along the Hilbert curve that covers a `side` by `side` square (`side`
+/// has to be a power of two).
+fn hilbert_decode(side: usize, d: usize) -> Vec2<usize> {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < side {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        // Rotate the quadrant so the curves of the quadrants connect:
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    Vec2 { x, y }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORDERS: [PixelOrder; 3] = [
+        PixelOrder::Scanline,
+        PixelOrder::Morton,
+        PixelOrder::Hilbert,
+    ];
+
+    #[test]
+    fn every_pixel_is_visited_once() {
+        let mut order = Vec::new();
+        for &pixel_order in ORDERS.iter() {
+            for &(x, y) in &[(1, 1), (8, 8), (16, 16), (13, 5), (3, 32), (32, 17)] {
+                let size = Vec2 { x, y };
+                pixel_order.tile_order(size, &mut order);
+                assert_eq!(order.len(), x * y);
+                let mut visited = vec![false; x * y];
+                for &i in order.iter() {
+                    assert!(
+                        !visited[i],
+                        "{:?} {:?} visits {} twice",
+                        pixel_order, size, i
+                    );
+                    visited[i] = true;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn curves_visit_quadrants_and_neighbors() {
+        let size = Vec2 { x: 16, y: 16 };
+        let mut order = Vec::new();
+
+        // The Z-order curve finishes a quadrant before starting the next one:
+        PixelOrder::Morton.tile_order(size, &mut order);
+        let quadrant = |i: usize| ((i % 16) / 8, (i / 16) / 8);
+        for (n, quarter) in order.chunks(64).enumerate() {
+            assert!(quarter.iter().all(|&i| quadrant(i) == quadrant(quarter[0])));
+            assert_eq!(quadrant(quarter[0]), (n % 2, n / 2));
+        }
+
+        // The Hilbert curve only ever steps to a neighboring pixel:
+        PixelOrder::Hilbert.tile_order(size, &mut order);
+        for pair in order.windows(2) {
+            let (a, b) = ((pair[0] % 16, pair[0] / 16), (pair[1] % 16, pair[1] / 16));
+            let dist = (a.0 as isize - b.0 as isize).abs() + (a.1 as isize - b.1 as isize).abs();
+            assert_eq!(dist, 1, "{:?} {:?}", a, b);
+        }
+    }
+}
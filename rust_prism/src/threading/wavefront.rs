@@ -0,0 +1,438 @@
+//! A wavefront organization of the path tracer. Instead of every thread tracing one path to completion
+//! before starting the next, a thread starts a path for every pixel of its tile and then advances all of
+//! them one bounce at a time: first the rays of every path are intersected together, and then every hit
+//! is shaded. This keeps the traversal and the shading code hot, and lets the scene intersect the rays
+//! as a batch (see `Scene::intersect_batch`).
+//!
+//! The samples of every pixel are drawn in the same order as with `PathTracerIntegrator`, so the result
+//! is the same as rendering with `Renderer`.
+
+use crate::camera::{Camera, CameraSample};
+use crate::film::sample_dump::SampleRecord;
+use crate::film::{Film, FilmTile, Pixel};
+use crate::filter::PixelFilter;
+use crate::integrator::path_tracer::{
+    PathState, PathTracerIntegrator, PathTracerIntegratorManager, PathTracerParam,
+};
+use crate::integrator::{Integrator, IntegratorManager};
+use crate::interaction::Interaction;
+use crate::light::light_picker::LightPicker;
+use crate::sampler::Sampler;
+use crate::scene::{PrimRef, Scene};
+use crate::shading::material::MaterialPool;
+use crate::threading::pixel_order::PixelOrder;
+use crate::threading::{
+    CancellationToken, ImportanceMap, RenderError, RenderLocation, RenderOutput, RenderParam,
+    RenderProgress, Renderer, SampleDump,
+};
+use crate::Scalar;
+use pmath::ray::Ray;
+use pmath::vector::Vec2;
+use std::mem;
+
+/// Renders with the path tracer, one bounce of every path in a tile at a time. This is an alternative to
+/// rendering a `PathTracerIntegrator` with `Renderer` (which traces one path at a time).
+pub struct WavefrontRenderer {
+    renderer: Renderer,
+}
+
+impl WavefrontRenderer {
+    /// Creates the thread pool and the sample tables used by every render.
+    pub fn new(param: RenderParam) -> Result<Self, RenderError> {
+        Ok(WavefrontRenderer {
+            renderer: Renderer::new(param)?,
+        })
+    }
+
+    pub fn param(&self) -> RenderParam {
+        self.renderer.param()
+    }
+
+    /// Renders the scene (see `Renderer::render`).
+    pub fn render(
+        &self,
+        scene: &Scene,
+        materials: &MaterialPool,
+        camera: &dyn Camera,
+        filter: PixelFilter,
+        int_param: PathTracerParam,
+        progress: Option<&(dyn Fn(RenderProgress) + Sync)>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<RenderOutput, RenderError> {
+        let film = self.renderer.new_film();
+        let integrator_manager = PathTracerIntegratorManager::new(int_param);
+        let num_pixel_samples = self.renderer.param.num_pixel_samples;
+        let sample_dump = self.renderer.param.sample_dump.map(SampleDump::new);
+        let sample_dump_ref = sample_dump.as_ref();
+
+        let default_cancel = CancellationToken::new();
+        let cancel = cancel.unwrap_or(&default_cancel);
+
+        let light_picker = self.renderer.param.light_picker.create(scene);
+        let light_picker_ref: &dyn LightPicker = &*light_picker;
+
+        let importance = self.renderer.importance.as_ref();
+        let pixel_order = self.renderer.param.pixel_order;
+        let film_ref = &film;
+        self.renderer.run_workers(
+            film_ref,
+            num_pixel_samples,
+            progress,
+            cancel,
+            |id, mut sampler, location| {
+                let integrator = integrator_manager.spawn_integrator(id);
+                integrator.request_samples(&mut sampler, light_picker_ref);
+                let mut wavefront = Wavefront {
+                    integrator,
+                    scene,
+                    materials,
+                    light_picker: light_picker_ref,
+                    samplers: Vec::new(),
+                    tile_pixels: Vec::new(),
+                    tile_order: Vec::new(),
+                    pixel_order,
+                    queue: Vec::new(),
+                    next_queue: Vec::new(),
+                    rays: Vec::new(),
+                    hits: Vec::new(),
+                    sample_dump: sample_dump_ref,
+                    records: Vec::new(),
+                    importance,
+                };
+                wavefront.render(
+                    camera,
+                    filter,
+                    &sampler,
+                    film_ref,
+                    num_pixel_samples,
+                    cancel,
+                    location,
+                );
+                if let Some(sample_dump) = sample_dump_ref {
+                    sample_dump.append(&mut wavefront.records);
+                }
+            },
+        )?;
+        self.renderer.record_tile_costs(&film);
+
+        Ok(RenderOutput {
+            film,
+            cancelled: cancel.is_cancelled(),
+            samples: sample_dump.map_or(Vec::new(), SampleDump::into_records),
+        })
+    }
+}
+
+/// A path that is still being traced, along with the pixel (in the tile) it belongs to.
+#[derive(Clone, Copy, Debug)]
+struct WorkItem {
+    pixel: usize,
+    path: PathState,
+}
+
+/// Where a pixel of the tile is, and whether its samples are recorded.
+#[derive(Clone, Copy, Debug)]
+struct TilePixel {
+    pos: Vec2<usize>,
+    dump: bool,
+}
+
+/// The state of a single render thread. The queues are sized by the tile, as every pixel of the tile has
+/// at most one path in flight.
+struct Wavefront<'a> {
+    integrator: PathTracerIntegrator,
+    scene: &'a Scene,
+    materials: &'a MaterialPool,
+    light_picker: &'a dyn LightPicker,
+    // Every pixel of the tile has its own sampler, so that the samples of a pixel are drawn in the
+    // same order as when the paths are traced one at a time:
+    samplers: Vec<Sampler<'a>>,
+    tile_pixels: Vec<TilePixel>,
+    // The order the paths of the pixels are started in (see `RenderParam::pixel_order`), which is also
+    // the order their rays are intersected in:
+    tile_order: Vec<usize>,
+    pixel_order: PixelOrder,
+    // The paths that have to be intersected at the current bounce, and those that survive it:
+    queue: Vec<WorkItem>,
+    next_queue: Vec<WorkItem>,
+    // The rays of the paths in the queue, with the primitive each of them skips:
+    rays: Vec<(Ray<Scalar>, Option<PrimRef>)>,
+    hits: Vec<Option<Interaction>>,
+    // The samples recorded by this thread (see `RenderParam::sample_dump`):
+    sample_dump: Option<&'a SampleDump>,
+    records: Vec<SampleRecord>,
+    // Scales the number of samples of every tile (see `RenderParam::importance_map`):
+    importance: Option<&'a ImportanceMap>,
+}
+
+impl<'a> Wavefront<'a> {
+    /// Loops over the tiles of the film until there are none left (the same as `thread_render`).
+    fn render(
+        &mut self,
+        camera: &dyn Camera,
+        filter: PixelFilter,
+        sampler: &Sampler<'a>,
+        film: &Film,
+        num_pixel_samples: u32,
+        cancel: &CancellationToken,
+        location: &mut RenderLocation,
+    ) {
+        let tile_area = film.tile_dim() * film.tile_dim();
+        self.queue.reserve(tile_area);
+        self.next_queue.reserve(tile_area);
+        self.rays.reserve(tile_area);
+        self.hits.reserve(tile_area);
+
+        while !cancel.is_cancelled() {
+            let mut film_tile = match film.get_tile() {
+                Some(film_tile) => film_tile,
+                _ => break,
+            };
+            location.tile = Some(film_tile.index);
+            let tile_samples = self.importance.map_or(num_pixel_samples, |importance| {
+                importance.tile_samples(film_tile.pos, film_tile.size, num_pixel_samples)
+            });
+
+            let num_pixels = film_tile.data.len();
+            let sample_dump = self.sample_dump;
+            self.tile_pixels.clear();
+            self.tile_pixels.extend((0..num_pixels).map(|i| {
+                let pos = Vec2 {
+                    x: film_tile.pos.x + (i % film_tile.size.x),
+                    y: film_tile.pos.y + (i / film_tile.size.x),
+                };
+                let dump = sample_dump.map_or(false, |dump| dump.contains(pos));
+                TilePixel { pos, dump }
+            }));
+            self.pixel_order
+                .tile_order(film_tile.size, &mut self.tile_order);
+            let tile_pixels = &self.tile_pixels;
+            self.samplers.clear();
+            self.samplers.extend((0..num_pixels).map(|i| {
+                let mut sampler = sampler.clone();
+                sampler.start_pixel(film_tile.seed as u32, tile_area as u32, i as u32);
+                sampler.set_pixel_pos(tile_pixels[i].pos);
+                sampler
+            }));
+
+            for sample in 0..tile_samples {
+                // Keep whatever was rendered of the tile so far:
+                if cancel.is_cancelled() {
+                    break;
+                }
+
+                // Start a path for every pixel:
+                for &i in self.tile_order.iter() {
+                    let pixel_pos = self.tile_pixels[i].pos;
+                    let pixel_pos = Vec2 {
+                        x: pixel_pos.x as f64 + 0.5,
+                        y: pixel_pos.y as f64 + 0.5,
+                    };
+                    let sampler = &mut self.samplers[i];
+                    sampler.start_pixel_sample();
+                    let camera_sample = CameraSample {
+                        p_film: (pixel_pos + filter.sample_pos(sampler.sample_film_pos().cast()))
+                            .cast(),
+                        p_lens: sampler.sample(),
+                        time: sampler.sample().x,
+                    };
+                    let path = self
+                        .integrator
+                        .start_path(camera.gen_primary_ray(camera_sample).ray);
+                    if path.is_done() {
+                        Self::finish(
+                            &self.integrator,
+                            &mut self.records,
+                            self.tile_pixels[i],
+                            sample,
+                            path,
+                            &mut film_tile,
+                            i,
+                        );
+                    } else {
+                        self.queue.push(WorkItem { pixel: i, path });
+                    }
+                }
+
+                // Advance every path by a bounce until all of them are done:
+                while !self.queue.is_empty() {
+                    self.rays.clear();
+                    self.rays.extend(
+                        self.queue
+                            .iter()
+                            .map(|item| (item.path.ray, item.path.exclude)),
+                    );
+                    self.scene.intersect_batch(&self.rays, &mut self.hits);
+
+                    for (mut item, hit) in self.queue.drain(..).zip(self.hits.drain(..)) {
+                        self.integrator.shade(
+                            &mut item.path,
+                            hit,
+                            self.scene,
+                            self.materials,
+                            self.light_picker,
+                            &mut self.samplers[item.pixel],
+                        );
+                        if item.path.is_done() {
+                            Self::finish(
+                                &self.integrator,
+                                &mut self.records,
+                                self.tile_pixels[item.pixel],
+                                sample,
+                                item.path,
+                                &mut film_tile,
+                                item.pixel,
+                            );
+                        } else {
+                            self.next_queue.push(item);
+                        }
+                    }
+                    mem::swap(&mut self.queue, &mut self.next_queue);
+                }
+            }
+
+            film.set_tile(film_tile);
+        }
+    }
+
+    /// Adds a completed path to the pixel `i` of the tile (recording it if the pixel is part of the sample
+    /// dump). This doesn't take `self` so that it can be called while the queue is drained.
+    fn finish(
+        integrator: &PathTracerIntegrator,
+        records: &mut Vec<SampleRecord>,
+        tile_pixel: TilePixel,
+        sample: u32,
+        path: PathState,
+        film_tile: &mut FilmTile,
+        i: usize,
+    ) {
+        if tile_pixel.dump || film_tile.is_split() {
+            let sample_pixel = integrator.finish(path, Pixel::black());
+            if tile_pixel.dump {
+                records.push(SampleRecord::from_sample(
+                    tile_pixel.pos,
+                    sample,
+                    sample_pixel,
+                ));
+            }
+            film_tile.add_sample(i, sample_pixel);
+        } else {
+            film_tile.data[i] = integrator.finish(path, film_tile.data[i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::perspective::PerspectiveCamera;
+    use crate::film::ImagePixel;
+    use crate::filter::{GaussianFilter, PixelFilter};
+    use crate::geometry::sphere::Sphere;
+    use crate::integrator::path_tracer::PathTracerIntegratorManager;
+    use crate::light::light_picker::LightPickerKind;
+    use crate::light::{point::Point, DirectLightParam};
+    use crate::sampler::{SampleSequence, SamplerMode};
+    use crate::scene::{SceneGeom, SceneLight, ScenePrim};
+    use crate::shading::material::matte::Matte;
+    use crate::shading::material::DEFAULT_MATERIAL_ID;
+    use crate::spectrum::Color;
+    use crate::transform::Transf;
+    use pmath::vector::Vec3;
+    use std::sync::Arc;
+
+    #[test]
+    fn matches_path_tracer() {
+        // A sphere on a (much larger) ground sphere, so that the paths bounce between them:
+        let mut materials = MaterialPool::new();
+        let red = materials.add_material(Matte::new(Color {
+            r: 0.8,
+            g: 0.1,
+            b: 0.1,
+        }));
+        let sphere = Arc::new(SceneGeom::new_material(
+            Arc::new(Sphere::new(1.0)),
+            materials.get_shared_material(red),
+            Transf::new_identity(),
+        ));
+        let ground = Arc::new(SceneGeom::new_material(
+            Arc::new(Sphere::new(100.0)),
+            materials.get_shared_material(DEFAULT_MATERIAL_ID),
+            Transf::new_translate(Vec3 {
+                x: 0.0,
+                y: -101.0,
+                z: 0.0,
+            }),
+        ));
+        let sphere_bbox = sphere.get_bbox();
+        let prims: Vec<Arc<dyn ScenePrim>> = vec![sphere, ground];
+        let light = Point::new(
+            Vec3 {
+                x: 0.0,
+                y: 4.0,
+                z: -4.0,
+            },
+            Color::white().scale(50.0),
+        );
+        let scene = Scene::new(
+            &prims,
+            vec![SceneLight::new(Arc::new(light), Transf::new_identity())],
+        );
+
+        let param = RenderParam {
+            num_pixel_samples: 4,
+            num_threads: 2,
+            sample_seed: 7,
+            blue_noise_count: 0,
+            sample_sequence: SampleSequence::Pmj,
+            sampler_mode: SamplerMode::Scrambled,
+            blue_noise_dither: false,
+            res: Vec2 { x: 32, y: 24 },
+            tile_size: Some(8),
+            pixel_order: PixelOrder::Scanline,
+            light_picker: LightPickerKind::All,
+            sample_dump: None,
+            importance_map: None,
+            split_buffers: false,
+            supersample: 1,
+        };
+        let camera = PerspectiveCamera::frame_bbox(
+            sphere_bbox,
+            45.0,
+            Vec3 {
+                x: 0.0,
+                y: -0.3,
+                z: 1.0,
+            },
+            param.film_res(),
+        );
+        let filter = PixelFilter::new(&GaussianFilter::new(Vec2 { x: 1.0, y: 1.0 }, 0.5));
+        let int_param = PathTracerParam {
+            max_bounce: 4,
+            direct_light: DirectLightParam::default(),
+            aovs: false,
+            max_direct: None,
+            max_indirect: None,
+        };
+
+        let to_image = |output: RenderOutput| output.film.to_image_buffer(ImagePixel::from_color);
+        let megakernel = Renderer::new(param.clone())
+            .unwrap()
+            .render::<PathTracerIntegrator, PathTracerIntegratorManager>(
+                &scene, &materials, &camera, filter, int_param, None, None,
+            )
+            .unwrap();
+        let wavefront = WavefrontRenderer::new(param)
+            .unwrap()
+            .render(&scene, &materials, &camera, filter, int_param, None, None)
+            .unwrap();
+        let (megakernel, wavefront) = (to_image(megakernel), to_image(wavefront));
+
+        // Every pixel draws its samples in the same order, so the images only differ by rounding:
+        let diff = megakernel.diff(&wavefront).unwrap();
+        assert!(diff.max_error < 1e-9, "{:?}", diff.max_error);
+        // Make sure the sphere is actually in the image:
+        let center = megakernel.get_pixel(Vec2 { x: 16, y: 12 });
+        assert!(center.r > center.b);
+    }
+}
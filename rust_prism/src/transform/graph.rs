@@ -0,0 +1,186 @@
+use crate::transform::{AnimatedTransf, Transf};
+
+/// Refers to a node in a `TransformGraph`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeId(u32);
+
+impl NodeId {
+    fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum LocalTransf {
+    Static(Transf),
+    Animated(AnimatedTransf),
+}
+
+impl LocalTransf {
+    fn get_start(self) -> Transf {
+        match self {
+            LocalTransf::Static(transf) => transf,
+            LocalTransf::Animated(motion) => motion.get_start(),
+        }
+    }
+
+    fn get_end(self) -> Transf {
+        match self {
+            LocalTransf::Static(transf) => transf,
+            LocalTransf::Animated(motion) => motion.get_end(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Node {
+    parent: Option<NodeId>,
+    local: LocalTransf, // node to parent
+
+    // The cached node to world transformation (only valid if the node isn't dirty):
+    world: AnimatedTransf,
+    // Whether the node or any of its ancestors moves while the shutter is open:
+    animated: bool,
+    dirty: bool,
+}
+
+/// A hierarchy of transformations. Every node has a local transformation relative to its parent, and
+/// the world transformations are cached until a local transformation changes.
+///
+/// Parents always have to be added before their children, so a node's index is always larger than
+/// that of its parent. This lets `update` recompute everything in a single pass.
+pub struct TransformGraph {
+    nodes: Vec<Node>,
+    // Whether any node is dirty (so `update` has something to do):
+    dirty: bool,
+}
+
+impl TransformGraph {
+    pub fn new() -> Self {
+        TransformGraph {
+            nodes: Vec::new(),
+            dirty: false,
+        }
+    }
+
+    /// Adds a node with a static transformation relative to `parent` (or the world if `None`).
+    pub fn add_node(&mut self, parent: Option<NodeId>, local: Transf) -> NodeId {
+        self.push_node(parent, LocalTransf::Static(local))
+    }
+
+    /// Adds a node that moves relative to `parent` (or the world if `None`) while the shutter is open.
+    pub fn add_animated_node(&mut self, parent: Option<NodeId>, local: AnimatedTransf) -> NodeId {
+        self.push_node(parent, LocalTransf::Animated(local))
+    }
+
+    fn push_node(&mut self, parent: Option<NodeId>, local: LocalTransf) -> NodeId {
+        if let Some(parent) = parent {
+            assert!(parent.index() < self.nodes.len());
+        }
+        let id = NodeId(self.nodes.len() as u32);
+        self.nodes.push(Node {
+            parent,
+            local,
+            world: AnimatedTransf::new(Transf::new_identity(), Transf::new_identity()),
+            animated: false,
+            dirty: true,
+        });
+        self.dirty = true;
+        id
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn get_parent(&self, node: NodeId) -> Option<NodeId> {
+        self.nodes[node.index()].parent
+    }
+
+    /// Sets the local transformation of a node. This invalidates the world transformations of the node
+    /// and all of its descendants until `update` is called.
+    pub fn set_local(&mut self, node: NodeId, local: Transf) {
+        self.nodes[node.index()].local = LocalTransf::Static(local);
+        self.mark_dirty(node);
+    }
+
+    /// Same as `set_local`, but the node moves while the shutter is open.
+    pub fn set_local_animated(&mut self, node: NodeId, local: AnimatedTransf) {
+        self.nodes[node.index()].local = LocalTransf::Animated(local);
+        self.mark_dirty(node);
+    }
+
+    fn mark_dirty(&mut self, node: NodeId) {
+        // Descendants are picked up by `update`, as they always come after their parent:
+        self.nodes[node.index()].dirty = true;
+        self.dirty = true;
+    }
+
+    /// Recomputes the world transformations of every node that was changed (and their descendants).
+    pub fn update(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        for i in 0..self.nodes.len() {
+            let node = self.nodes[i];
+            let parent = node.parent.map(|parent| self.nodes[parent.index()]);
+            let dirty = node.dirty || parent.map_or(false, |parent| parent.dirty);
+            if !dirty {
+                continue;
+            }
+
+            let local_animated = match node.local {
+                LocalTransf::Static(_) => false,
+                LocalTransf::Animated(_) => true,
+            };
+            // Composing the start and end separately isn't exactly the same as interpolating the
+            // composition, but it agrees at the shutter open and close times:
+            let (world, animated) = match parent {
+                Some(parent) => (
+                    AnimatedTransf::new(
+                        parent.world.get_start() * node.local.get_start(),
+                        parent.world.get_end() * node.local.get_end(),
+                    ),
+                    parent.animated || local_animated,
+                ),
+                None => (
+                    AnimatedTransf::new(node.local.get_start(), node.local.get_end()),
+                    local_animated,
+                ),
+            };
+
+            let node = &mut self.nodes[i];
+            node.world = world;
+            node.animated = animated;
+            node.dirty = true;
+        }
+        // The dirty flags are kept during the pass so that they propagate to the children:
+        for node in self.nodes.iter_mut() {
+            node.dirty = false;
+        }
+        self.dirty = false;
+    }
+
+    /// Returns the node to world transformation when the shutter opens.
+    ///
+    /// # Panics
+    /// If the graph was changed since the last call to `update`.
+    pub fn get_world(&self, node: NodeId) -> Transf {
+        self.get_world_motion(node).get_start()
+    }
+
+    /// Returns the node to world transformation over the entire time the shutter is open.
+    ///
+    /// # Panics
+    /// If the graph was changed since the last call to `update`.
+    pub fn get_world_motion(&self, node: NodeId) -> AnimatedTransf {
+        assert!(!self.dirty, "TransformGraph has to be updated first");
+        self.nodes[node.index()].world
+    }
+
+    /// Returns whether the node (or any of its ancestors) moves while the shutter is open.
+    pub fn is_animated(&self, node: NodeId) -> bool {
+        assert!(!self.dirty, "TransformGraph has to be updated first");
+        self.nodes[node.index()].animated
+    }
+}
@@ -0,0 +1,130 @@
+//! Transformations that are keyed at a couple of points in time. Unlike `AnimatedTransf`, which only
+//! covers the time the shutter of a single frame is open, these describe an animation over many frames.
+
+use crate::transform::Transf;
+use pmath::matrix::Mat3x4;
+use pmath::quaternion::Quat;
+use pmath::vector::Vec3;
+use serde::Deserialize;
+
+/// How the transformation between two keyframes is computed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum Interpolation {
+    /// The matrices are interpolated linearly (like `AnimatedTransf`), which is only fine for
+    /// translations, scales, and small rotations.
+    Linear,
+    /// The matrices are split into a translation, a rotation, and a scale. The translations and scales
+    /// are interpolated linearly and the rotations along the shortest arc between them.
+    Quat,
+}
+
+impl Default for Interpolation {
+    fn default() -> Self {
+        Interpolation::Quat
+    }
+}
+
+/// A transformation keyed at increasing points in time. Before the first and after the last keyframe the
+/// transformation stays the same.
+#[derive(Clone, Debug)]
+pub struct Keyframes {
+    // Sorted by time:
+    keys: Vec<(f64, Transf)>,
+    interpolation: Interpolation,
+}
+
+impl Keyframes {
+    /// Creates the keyframes from a list of times and transformations.
+    ///
+    /// # Panics
+    /// If `keys` is empty or the times aren't increasing.
+    pub fn new(keys: Vec<(f64, Transf)>, interpolation: Interpolation) -> Self {
+        assert!(!keys.is_empty(), "Keyframes need at least one key");
+        assert!(
+            keys.windows(2).all(|pair| pair[0].0 < pair[1].0),
+            "The times of the keyframes have to be increasing"
+        );
+        Keyframes {
+            keys,
+            interpolation,
+        }
+    }
+
+    /// Returns the transformation at the given time.
+    pub fn eval(&self, time: f64) -> Transf {
+        // The index of the first key after the time:
+        let next = self.keys.iter().position(|&(key_time, _)| key_time > time);
+        let next = match next {
+            Some(0) => return self.keys[0].1,
+            Some(next) => next,
+            None => return self.keys[self.keys.len() - 1].1,
+        };
+        let (start_time, start) = self.keys[next - 1];
+        let (end_time, end) = self.keys[next];
+        let t = (time - start_time) / (end_time - start_time);
+        match self.interpolation {
+            Interpolation::Linear => Transf::from_mat3x4(start.get_frd().lerp(end.get_frd(), t)),
+            Interpolation::Quat => {
+                let start = Decomposed::new(start);
+                let end = Decomposed::new(end);
+                // q and -q are the same rotation, the one closest to the start is the shortest arc:
+                let end_rotation = if start.rotation.dot(end.rotation) < 0.0 {
+                    -end.rotation
+                } else {
+                    end.rotation
+                };
+                Decomposed {
+                    translation: start.translation.lerp(end.translation, t),
+                    rotation: start.rotation.slerp(end_rotation, t),
+                    scale: start.scale.lerp(end.scale, t),
+                }
+                .compose()
+            }
+        }
+    }
+}
+
+/// A transformation split into a scale, followed by a rotation, followed by a translation. Shears are
+/// lost.
+struct Decomposed {
+    translation: Vec3<f64>,
+    rotation: Quat<f64>,
+    scale: Vec3<f64>,
+}
+
+impl Decomposed {
+    fn new(transf: Transf) -> Self {
+        let frd = transf.get_frd();
+        let columns = [frd.get_column(0), frd.get_column(1), frd.get_column(2)];
+        let mut scale = Vec3 {
+            x: columns[0].length(),
+            y: columns[1].length(),
+            z: columns[2].length(),
+        };
+        // A mirroring is kept in the scale, so that what is left is a rotation:
+        if frd.determinant() < 0.0 {
+            scale.x = -scale.x;
+        }
+        let [c0, c1, c2] = [
+            columns[0].scale(1.0 / scale.x),
+            columns[1].scale(1.0 / scale.y),
+            columns[2].scale(1.0 / scale.z),
+        ];
+        let rotation = Mat3x4::from_arr([
+            c0.x, c1.x, c2.x, 0.0, //
+            c0.y, c1.y, c2.y, 0.0, //
+            c0.z, c1.z, c2.z, 0.0,
+        ]);
+        Decomposed {
+            translation: frd.get_column(3),
+            rotation: Quat::from_mat3x4(rotation),
+            scale,
+        }
+    }
+
+    fn compose(self) -> Transf {
+        Transf::new_translate(self.translation)
+            * Transf::from_mat3x4(self.rotation.normalize().to_mat3x4())
+            * Transf::new_scale(self.scale)
+    }
+}
@@ -0,0 +1,519 @@
+pub mod graph;
+pub mod keyframe;
+
+use crate::interaction::{self, GeomIntr, Interaction, IntrType, VolIntr};
+use crate::Scalar;
+use pmath::bbox::BBox3;
+use pmath::matrix::{Mat3x4, Mat4};
+use pmath::ray::{PrimaryRay, Ray, RayDiff};
+use pmath::vector::{Vec3, Vec4};
+
+use std::ops::Mul;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Transf {
+    frd: Mat3x4<f64>,
+    inv: Mat3x4<f64>,
+    // The inverse transpose (used to transform normals):
+    nrm: Mat3x4<f64>,
+}
+
+impl Transf {
+    fn from_parts(frd: Mat3x4<f64>, inv: Mat3x4<f64>) -> Self {
+        Transf {
+            frd,
+            inv,
+            nrm: inv.transpose(),
+        }
+    }
+
+    pub fn from_mat4(mat: Mat4<f64>) -> Self {
+        let frd = Mat3x4::from_mat4(mat);
+        Transf::from_parts(frd, frd.inverse())
+    }
+
+    pub fn from_mat3x4(mat: Mat3x4<f64>) -> Self {
+        Transf::from_parts(mat, mat.inverse())
+    }
+
+    pub fn new_identity() -> Self {
+        Transf::from_parts(Mat3x4::new_identity(), Mat3x4::new_identity())
+    }
+
+    pub fn new_translate(trans: Vec3<f64>) -> Self {
+        Transf::from_parts(Mat3x4::new_translate(trans), Mat3x4::new_translate(-trans))
+    }
+
+    pub fn new_scale(scale: Vec3<f64>) -> Self {
+        Transf::from_parts(
+            Mat3x4::new_scale(scale),
+            Mat3x4::new_scale(scale.inv_scale(1.)),
+        )
+    }
+
+    pub fn new_rotate(deg: f64, axis: Vec3<f64>) -> Self {
+        let frd = Mat3x4::new_rotate(deg, axis);
+        // inverse of rotation matrix is transpose
+        Transf::from_parts(frd, frd.transpose())
+    }
+
+    /// Creates a rotation of `rad` radians around `axis`.
+    pub fn from_axis_angle(axis: Vec3<f64>, rad: f64) -> Self {
+        Self::new_rotate(rad.to_degrees(), axis)
+    }
+
+    /// Creates a rotation from euler angles (in degrees). The rotation around the x-axis is applied
+    /// first, then the y-axis and finally the z-axis.
+    pub fn from_euler_xyz(deg: Vec3<f64>) -> Self {
+        TransfBuilder::new()
+            .rotate_deg(
+                Vec3 {
+                    x: 1.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                deg.x,
+            )
+            .rotate_deg(
+                Vec3 {
+                    x: 0.0,
+                    y: 1.0,
+                    z: 0.0,
+                },
+                deg.y,
+            )
+            .rotate_deg(
+                Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0,
+                },
+                deg.z,
+            )
+            .build()
+    }
+
+    /// Creats a lookat transformation. This is a transformation that goes from
+    /// camera to world space
+    ///
+    /// Note: camera space has the positive z-axis go into the screen, the y-axis pointing
+    /// up, and the x-axis pointing right (it's a left-handed coordinate system).
+    ///
+    /// If `up` is (nearly) parallel to the view direction, a different up vector is used.
+    pub fn new_lookat(up: Vec3<f64>, at: Vec3<f64>, pos: Vec3<f64>) -> Self {
+        let f = (at - pos).normalize();
+        let s = up.cross(f);
+        let s = if s.length2() > 1e-12 * up.length2() {
+            s.normalize()
+        } else {
+            // Pick the axis that is the least aligned with the view direction:
+            let abs_f = f.abs();
+            let fallback = if (abs_f.x <= abs_f.y) && (abs_f.x <= abs_f.z) {
+                Vec3 {
+                    x: 1.0,
+                    y: 0.0,
+                    z: 0.0,
+                }
+            } else if abs_f.y <= abs_f.z {
+                Vec3 {
+                    x: 0.0,
+                    y: 1.0,
+                    z: 0.0,
+                }
+            } else {
+                Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0,
+                }
+            };
+            fallback.cross(f).normalize()
+        };
+        let u = f.cross(s);
+
+        let r0 = Vec4::from_vec3(s, -s.dot(pos));
+        let r1 = Vec4::from_vec3(u, -u.dot(pos));
+        let r2 = Vec4::from_vec3(f, -f.dot(pos));
+
+        let inv = Mat3x4::from_rows([r0, r1, r2]);
+
+        Transf::from_parts(inv.inverse(), inv)
+    }
+
+    /// Inverses the transformation
+    pub fn inverse(&self) -> Self {
+        Transf::from_parts(self.inv, self.frd)
+    }
+
+    // Returns the normal matrix:
+    pub fn get_frd(self) -> Mat3x4<f64> {
+        self.frd
+    }
+
+    pub fn get_inv(self) -> Mat3x4<f64> {
+        self.inv
+    }
+
+    pub fn point(self, p: Vec3<f64>) -> Vec3<f64> {
+        self.frd.mul_vec_one(p)
+    }
+
+    /// Transforms a point along with a bound on its absolute error, returning the new bound.
+    pub fn point_with_error(self, p: Vec3<f64>, p_error: Vec3<f64>) -> (Vec3<f64>, Vec3<f64>) {
+        let g3 = pmath::gamma_f64(3);
+        let mut err = Vec3::zero();
+        for r in 0..3 {
+            let row = self.frd[r];
+            err[r] = (g3 + 1.0)
+                * (row[0].abs() * p_error.x + row[1].abs() * p_error.y + row[2].abs() * p_error.z)
+                + g3 * ((row[0] * p.x).abs()
+                    + (row[1] * p.y).abs()
+                    + (row[2] * p.z).abs()
+                    + row[3].abs());
+        }
+        (self.point(p), err)
+    }
+
+    pub fn points(self, ps: &mut [Vec3<f64>]) {
+        for p in ps.iter_mut() {
+            *p = self.point(*p);
+        }
+    }
+
+    pub fn points_f32(self, ps: &mut [Vec3<f32>]) {
+        for p in ps.iter_mut() {
+            *p = self.point(p.to_f64()).to_f32();
+        }
+    }
+
+    /// Returns the matrix used to transform normals (the inverse transpose). Unlike `normal`, this can be
+    /// used for things that shouldn't be normalized (like the derivatives of normals).
+    pub fn get_nrm(self) -> Mat3x4<f64> {
+        self.nrm
+    }
+
+    /// Transforms a normal (the result is normalized), which stays perpendicular to the surface even
+    /// when the transformation doesn't scale uniformly.
+    pub fn normal(self, n: Vec3<f64>) -> Vec3<f64> {
+        self.nrm.mul_vec_zero(n).normalize()
+    }
+
+    pub fn normals(self, ns: &mut [Vec3<f64>]) {
+        for n in ns.iter_mut() {
+            *n = self.normal(*n);
+        }
+    }
+
+    pub fn normals_f32(self, ns: &mut [Vec3<f32>]) {
+        for n in ns.iter_mut() {
+            *n = self.normal(n.to_f64()).to_f32();
+        }
+    }
+
+    pub fn vector(self, v: Vec3<f64>) -> Vec3<f64> {
+        self.frd.mul_vec_zero(v)
+    }
+
+    pub fn vectors(self, vs: &mut [Vec3<f64>]) {
+        for v in vs.iter_mut() {
+            *v = self.frd.mul_vec_zero(*v);
+        }
+    }
+
+    pub fn vectors_f32(self, vs: &mut [Vec3<f32>]) {
+        for v in vs.iter_mut() {
+            *v = self.vector(v.to_f64()).to_f32();
+        }
+    }
+
+    pub fn bbox(&self, b: BBox3<f64>) -> BBox3<f64> {
+        // From Arvo 1990 Graphics Gems 1
+
+        let pmin = self.frd.get_column(3);
+        let pmax = pmin;
+
+        let a0 = self.frd.get_column(0) * b.pmin;
+        let a1 = self.frd.get_column(0) * b.pmax;
+        let pmin = pmin + a0.min(a1);
+        let pmax = pmax + a0.max(a1);
+
+        let a0 = self.frd.get_column(1) * b.pmin;
+        let a1 = self.frd.get_column(1) * b.pmax;
+        let pmin = pmin + a0.min(a1);
+        let pmax = pmax + a0.max(a1);
+
+        let a0 = self.frd.get_column(2) * b.pmin;
+        let a1 = self.frd.get_column(2) * b.pmax;
+        let pmin = pmin + a0.min(a1);
+        let pmax = pmax + a0.max(a1);
+
+        BBox3 { pmin, pmax }
+    }
+
+    /// Transforms an interaction (in `f64`, even if `Scalar` is `f32`).
+    pub fn interaction(self, i: Interaction) -> Interaction {
+        let (p, p_error) = self.point_with_error(i.p.cast(), i.p_error.cast());
+        let (p, p_error) = interaction::point_to_scalar(p, p_error);
+        Interaction {
+            p,
+            p_error,
+            attribute_id: i.attribute_id,
+            prim: i.prim,
+            bary: i.bary,
+            n: self.normal(i.n.cast()).cast(),
+            wo: self.vector(i.wo.cast()).normalize().cast(),
+            t: i.t,
+            time: i.time,
+            geom: i.geom,
+
+            intr_type: match i.intr_type {
+                IntrType::Geom(geom_intr) => IntrType::Geom(self.geom_intr(geom_intr)),
+                IntrType::Vol(vol_intr) => IntrType::Vol(self.vol_intr(vol_intr)),
+            },
+        }
+    }
+
+    pub fn geom_intr(self, g: GeomIntr) -> GeomIntr {
+        let vector = |v: Vec3<Scalar>| self.vector(v.cast()).cast();
+        GeomIntr {
+            uv: g.uv,
+            dpdu: vector(g.dpdu),
+            dpdv: vector(g.dpdv),
+
+            sn: self.normal(g.sn.cast()).cast(),
+            sdpdu: vector(g.sdpdu),
+            sdpdv: vector(g.sdpdv),
+            sdndu: self.nrm.mul_vec_zero(g.sdndu.cast()).cast(),
+            sdndv: self.nrm.mul_vec_zero(g.sdndv.cast()).cast(),
+            shadow_p: self.point(g.shadow_p.cast()).cast(),
+            col: g.col,
+            triplanar: g.triplanar,
+        }
+    }
+
+    pub fn vol_intr(self, v: VolIntr) -> VolIntr {
+        v
+    }
+
+    pub fn ray(self, r: Ray<f64>) -> Ray<f64> {
+        Ray {
+            org: self.point(r.org),
+            dir: self.vector(r.dir),
+            time: r.time,
+            t_far: r.t_far,
+            t_near: r.t_near,
+        }
+    }
+
+    pub fn primary_ray(self, r: PrimaryRay<f64>) -> PrimaryRay<f64> {
+        PrimaryRay {
+            ray: self.ray(r.ray),
+            ray_diff: RayDiff {
+                rx_org: self.point(r.ray_diff.rx_org),
+                rx_dir: self.vector(r.ray_diff.rx_dir),
+                ry_org: self.point(r.ray_diff.ry_org),
+                ry_dir: self.vector(r.ray_diff.ry_dir),
+            },
+        }
+    }
+}
+
+impl Mul for Transf {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        Transf::from_parts(self.frd * rhs.frd, rhs.inv * self.inv)
+    }
+}
+
+/// Composes a transformation step by step, where every step is applied after the previous ones:
+///
+/// ```ignore
+/// let transf = TransfBuilder::new()
+///     .scale(Vec3 { x: 2.0, y: 2.0, z: 2.0 })
+///     .rotate_deg(Vec3 { x: 0.0, y: 1.0, z: 0.0 }, 45.0)
+///     .translate(Vec3 { x: 0.0, y: 1.0, z: 0.0 })
+///     .build();
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct TransfBuilder {
+    transf: Transf,
+}
+
+impl TransfBuilder {
+    pub fn new() -> Self {
+        TransfBuilder {
+            transf: Transf::new_identity(),
+        }
+    }
+
+    /// Applies `transf` after the current transformation.
+    pub fn then(self, transf: Transf) -> Self {
+        TransfBuilder {
+            transf: transf * self.transf,
+        }
+    }
+
+    pub fn translate(self, trans: Vec3<f64>) -> Self {
+        self.then(Transf::new_translate(trans))
+    }
+
+    pub fn rotate_deg(self, axis: Vec3<f64>, deg: f64) -> Self {
+        self.then(Transf::new_rotate(deg, axis))
+    }
+
+    pub fn scale(self, scale: Vec3<f64>) -> Self {
+        self.then(Transf::new_scale(scale))
+    }
+
+    pub fn build(self) -> Transf {
+        self.transf
+    }
+}
+
+/// A transformation that changes while the shutter is open (from time 0 to time 1). The matrices are
+/// interpolated linearly, so rotations should be split into multiple small steps.
+#[derive(Clone, Copy, Debug)]
+pub struct AnimatedTransf {
+    start: Transf,
+    end: Transf,
+}
+
+impl AnimatedTransf {
+    /// Constructs a transformation that is `start` when the shutter opens and `end` when it closes.
+    pub fn new(start: Transf, end: Transf) -> Self {
+        AnimatedTransf { start, end }
+    }
+
+    pub fn get_start(self) -> Transf {
+        self.start
+    }
+
+    pub fn get_end(self) -> Transf {
+        self.end
+    }
+
+    /// Returns the transformation at the given time.
+    pub fn interpolate(self, time: f64) -> Transf {
+        if time <= 0.0 {
+            self.start
+        } else if time >= 1.0 {
+            self.end
+        } else {
+            Transf::from_mat3x4(self.start.frd.lerp(self.end.frd, time))
+        }
+    }
+
+    /// Returns a bounding box that contains the transformed `b` at every point in time. Because the
+    /// matrices are interpolated linearly, every point moves along a line, so the bounding boxes at the
+    /// start and at the end are enough.
+    pub fn bound_motion(self, b: BBox3<f64>) -> BBox3<f64> {
+        self.start.bbox(b).combine_bnd(self.end.bbox(b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec3(x: f64, y: f64, z: f64) -> Vec3<f64> {
+        Vec3 { x, y, z }
+    }
+
+    fn assert_close(a: Vec3<f64>, b: Vec3<f64>) {
+        assert!((a - b).length() < 1e-9, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn composed_transforms_match_applying_them_in_turn() {
+        let scale = Transf::new_scale(vec3(2.0, 0.5, 3.0));
+        let rotate = Transf::from_axis_angle(vec3(1.0, 2.0, 0.5).normalize(), 0.7);
+        let translate = Transf::new_translate(vec3(-1.0, 4.0, 2.0));
+        let composed = translate * rotate * scale;
+        let built = TransfBuilder::new()
+            .scale(vec3(2.0, 0.5, 3.0))
+            .rotate_deg(vec3(1.0, 2.0, 0.5).normalize(), 0.7f64.to_degrees())
+            .translate(vec3(-1.0, 4.0, 2.0))
+            .build();
+
+        let p = vec3(0.3, -1.2, 2.5);
+        let n = vec3(0.2, 0.9, -0.4).normalize();
+        for transf in &[composed, built] {
+            assert_close(
+                transf.point(p),
+                translate.point(rotate.point(scale.point(p))),
+            );
+            assert_close(
+                transf.vector(p),
+                translate.vector(rotate.vector(scale.vector(p))),
+            );
+            assert_close(
+                transf.normal(n),
+                translate.normal(rotate.normal(scale.normal(n))),
+            );
+            assert_close(transf.inverse().point(transf.point(p)), p);
+            assert_close((*transf * transf.inverse()).point(p), p);
+        }
+    }
+
+    #[test]
+    fn euler_angles_rotate_around_x_then_y_then_z() {
+        let euler = Transf::from_euler_xyz(vec3(30.0, -45.0, 60.0));
+        let separate = Transf::new_rotate(60.0, vec3(0.0, 0.0, 1.0))
+            * Transf::new_rotate(-45.0, vec3(0.0, 1.0, 0.0))
+            * Transf::new_rotate(30.0, vec3(1.0, 0.0, 0.0));
+        let p = vec3(1.0, 2.0, 3.0);
+        assert_close(euler.point(p), separate.point(p));
+        assert_close(
+            Transf::from_axis_angle(vec3(0.0, 0.0, 1.0), std::f64::consts::FRAC_PI_2).point(p),
+            Transf::new_rotate(90.0, vec3(0.0, 0.0, 1.0)).point(p),
+        );
+    }
+
+    #[test]
+    fn lookat_handles_up_parallel_to_the_view_direction() {
+        for &up in &[vec3(0.0, 1.0, 0.0), vec3(0.0, -1.0, 1e-9)] {
+            let transf = Transf::new_lookat(up, vec3(0.0, 10.0, 0.0), vec3(0.0, 2.0, 0.0));
+            // The camera looks along its z-axis:
+            assert_close(transf.point(vec3(0.0, 0.0, 8.0)), vec3(0.0, 10.0, 0.0));
+            let x = transf.vector(vec3(1.0, 0.0, 0.0));
+            assert!(x.x.is_finite() && x.y.is_finite() && x.z.is_finite());
+            assert!((x.length() - 1.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn normals_stay_perpendicular_under_non_uniform_scale() {
+        let transf = Transf::new_scale(vec3(1.0, 1.0, 4.0));
+        for i in 1..16 {
+            for j in 0..32 {
+                // A point on the unit sphere, where the normal is the point itself, and two tangents:
+                let (theta, phi) = (
+                    (i as f64) * std::f64::consts::PI / 16.0,
+                    (j as f64) * std::f64::consts::PI / 16.0,
+                );
+                let n = vec3(
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                    theta.cos(),
+                );
+                let dpdtheta = vec3(
+                    theta.cos() * phi.cos(),
+                    theta.cos() * phi.sin(),
+                    -theta.sin(),
+                );
+                let dpdphi = vec3(-phi.sin(), phi.cos(), 0.0);
+
+                let mut normals = [n.to_f32()];
+                transf.normals_f32(&mut normals);
+                for &n in &[transf.normal(n), normals[0].to_f64()] {
+                    assert!((n.length() - 1.0).abs() < 1e-6);
+                    for &tangent in &[dpdtheta, dpdphi] {
+                        let tangent = transf.vector(tangent).normalize();
+                        assert!(n.dot(tangent).abs() < 1e-6, "{}", n.dot(tangent));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,38 @@
+//! Polls a file for changes. This is used to reload the materials of a scene while it's being rendered,
+//! and doesn't need any platform specific file notifications.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub struct FileWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl FileWatcher {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let modified = modified_time(&path);
+        FileWatcher { path, modified }
+    }
+
+    /// Returns whether the file was modified since the last time this was called (or since the watcher
+    /// was constructed). A file that can't be read (for instance, because an editor is replacing it)
+    /// doesn't count as modified until it can be read again.
+    pub fn poll(&mut self) -> bool {
+        match modified_time(&self.path) {
+            Some(modified) if Some(modified) != self.modified => {
+                self.modified = Some(modified);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
@@ -0,0 +1,168 @@
+//! Runs the command line renderer: the output of `--help` is compared with `cli_help.txt` (so that changes
+//! to the usage are intentional), and tiny scenes are rendered from start to finish.
+
+use prism::film::png;
+use prism::pmath::vector::Vec2;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::{self, Command};
+
+fn prism_cli() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_prism-cli"))
+}
+
+#[test]
+fn help_matches_the_golden_file() {
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/cli_help.txt");
+    let output = prism_cli().arg("--help").output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        fs::read_to_string(golden).unwrap()
+    );
+}
+
+#[test]
+fn tiny_render_from_the_command_line() {
+    let scene = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/sphere.ron");
+    let out = env::temp_dir().join(format!("prism-cli-{}.png", process::id()));
+    let status = prism_cli()
+        .arg("--scene")
+        .arg(&scene)
+        .args(&["--res", "24x16", "--spp", "2", "--threads", "2", "--quiet"])
+        .arg("--out")
+        .arg(&out)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let image = png::read_png(out.to_str().unwrap()).unwrap();
+    fs::remove_file(&out).unwrap();
+    assert_eq!(image.res(), Vec2 { x: 24, y: 16 });
+}
+
+#[test]
+fn every_integrator_and_sampler_renders() {
+    let scene = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/sphere.ron");
+    for (i, args) in [
+        ["--integrator", "direct"],
+        ["--integrator", "ao"],
+        ["--sampler", "sobol"],
+        ["--sampler", "stratified"],
+        ["--sampler", "random"],
+    ]
+    .iter()
+    .enumerate()
+    {
+        let out = env::temp_dir().join(format!("prism-cli-{}-{}.png", process::id(), i));
+        let status = prism_cli()
+            .arg("--scene")
+            .arg(&scene)
+            .args(&["--res", "12x8", "--spp", "4", "--quiet"])
+            .args(args)
+            .arg("--out")
+            .arg(&out)
+            .status()
+            .unwrap();
+        assert!(status.success(), "{:?}", args);
+
+        let image = png::read_png(out.to_str().unwrap()).unwrap();
+        fs::remove_file(&out).unwrap();
+        assert_eq!(image.res(), Vec2 { x: 12, y: 8 }, "{:?}", args);
+    }
+}
+
+#[test]
+fn inspect_prints_how_the_image_was_rendered() {
+    let scene = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/sphere.ron");
+    for extension in &["png", "exr"] {
+        let out =
+            env::temp_dir().join(format!("prism-cli-inspect-{}.{}", process::id(), extension));
+        let status = prism_cli()
+            .arg("--scene")
+            .arg(&scene)
+            .args(&["--res", "24x16", "--spp", "2", "--seed", "7", "--quiet"])
+            .args(&["--threads", "2", "--sampler", "pmj-shifted"])
+            .arg("--out")
+            .arg(&out)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let output = prism_cli().arg("inspect").arg(&out).output().unwrap();
+        fs::remove_file(&out).unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<&str> = stdout.lines().collect();
+        for expected in &[
+            "prism:resolution: 24x16",
+            "prism:spp: 2",
+            "prism:seed: 7",
+            "prism:threads: 2",
+            "prism:complete: true",
+        ] {
+            assert!(lines.contains(expected), "{}: {}", expected, stdout);
+        }
+        assert!(lines.contains(&format!("prism:scene: {}", scene.display()).as_str()));
+        assert!(lines.iter().any(|line| line.starts_with("prism:sampler: ")));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("prism:renderTime: ")));
+    }
+
+    // Inspecting something that isn't an image fails:
+    let output = prism_cli().arg("inspect").arg(&scene).output().unwrap();
+    assert_eq!(output.status.code(), Some(4));
+    let output = prism_cli().arg("inspect").output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn invalid_arguments_are_reported() {
+    let scene = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/sphere.ron");
+    for args in &[
+        &["--res", "24"][..],
+        &["--spp", "0"],
+        &["--integrator", "whitted"],
+        &["--sampler", "halton"],
+        &["--frobnicate"],
+        // Arguments that can't be used together, or with the scene:
+        &["--quiet", "--verbose"],
+        &["--wavefront", "--integrator", "normal"],
+        &["--frames", "1-2"],
+        &["--crop", "0,0,1000,1"],
+    ] {
+        let output = prism_cli()
+            .arg("--scene")
+            .arg(&scene)
+            .args(*args)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.starts_with("error: "), "{:?}: {}", args, stderr);
+    }
+}
+
+#[test]
+fn missing_measured_data_is_an_io_error() {
+    let scene = fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/sphere.ron"))
+        .unwrap()
+        .replace(
+            "Matte(color: (0.8, 0.8, 0.8))",
+            "Measured(path: \"does-not-exist.binary\")",
+        );
+    let path = env::temp_dir().join(format!("prism-cli-merl-{}.ron", process::id()));
+    fs::write(&path, scene).unwrap();
+    let output = prism_cli()
+        .arg("--scene")
+        .arg(&path)
+        .args(&["--res", "12x8", "--spp", "1", "--quiet"])
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("does-not-exist.binary"), "{}", stderr);
+}
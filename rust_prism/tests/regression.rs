@@ -0,0 +1,102 @@
+//! Renders every scene in `scenes/regression` with the command line renderer and compares it with its
+//! reference image (the png next to the scene, see `--reference`), which forces a single thread so that
+//! the renders are deterministic. Run with PRISM_UPDATE_REFERENCES set to write new references after an
+//! intended change to the output (or to create them for a new scene), and check them in.
+//!
+//! There is no glossy material yet, so there is no glossy scene either.
+//!
+//! The references are rendered with `f64`. With the `f32-render` feature the renders can't match them
+//! exactly, so they are only checked to be close to them instead (see `Scalar`).
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+/// Every scene in `scenes/regression`, sorted by name.
+fn regression_scenes() -> Vec<PathBuf> {
+    let scene_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/regression");
+    let mut scenes: Vec<_> = fs::read_dir(&scene_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "ron"))
+        .collect();
+    scenes.sort();
+    assert!(!scenes.is_empty());
+    scenes
+}
+
+#[cfg(not(feature = "f32-render"))]
+#[test]
+fn regression_scenes_match_their_references() {
+    let out_dir = env::temp_dir().join(format!("prism-regression-{}", process::id()));
+    fs::create_dir_all(&out_dir).unwrap();
+
+    let mut failed = Vec::new();
+    for scene in &regression_scenes() {
+        let name = scene.file_stem().unwrap().to_str().unwrap();
+        let out = out_dir.join(format!("{}.png", name));
+        let status = Command::new(env!("CARGO_BIN_EXE_prism-cli"))
+            .arg("--scene")
+            .arg(scene)
+            .arg("--out")
+            .arg(&out)
+            .arg("--reference")
+            .arg(scene.with_extension("png"))
+            .arg("--quiet")
+            .status()
+            .unwrap();
+        if !status.success() {
+            failed.push(name.to_owned());
+        }
+    }
+    fs::remove_dir_all(&out_dir).ok();
+    assert!(failed.is_empty(), "failed regression scenes: {:?}", failed);
+}
+
+#[cfg(feature = "f32-render")]
+#[test]
+fn f32_renders_are_close_to_the_f64_references() {
+    use prism::film::png::read_png;
+
+    // The paths of the two renders start out the same, but they can diverge (and end up with a different
+    // number of bounces), so single pixels can differ a lot more than the average does:
+    const MAX_MEAN_ERROR: f64 = 0.005;
+
+    let out_dir = env::temp_dir().join(format!("prism-regression-f32-{}", process::id()));
+    fs::create_dir_all(&out_dir).unwrap();
+
+    let mut failed = Vec::new();
+    for scene in &regression_scenes() {
+        let name = scene.file_stem().unwrap().to_str().unwrap();
+        let out = out_dir.join(format!("{}.png", name));
+        let status = Command::new(env!("CARGO_BIN_EXE_prism-cli"))
+            .arg("--scene")
+            .arg(scene)
+            .arg("--out")
+            .arg(&out)
+            .arg("--threads")
+            .arg("1")
+            .arg("--quiet")
+            .status()
+            .unwrap();
+        assert!(status.success(), "{} failed to render", name);
+
+        let image = read_png(out.to_str().unwrap()).unwrap();
+        let reference = read_png(scene.with_extension("png").to_str().unwrap()).unwrap();
+        let diff = image.diff(&reference).unwrap();
+        println!(
+            "{}: mean error {:.5}, max error {:.5}",
+            name, diff.mean_abs_error, diff.max_error
+        );
+        if diff.mean_abs_error > MAX_MEAN_ERROR {
+            failed.push(name.to_owned());
+        }
+    }
+    fs::remove_dir_all(&out_dir).ok();
+    assert!(
+        failed.is_empty(),
+        "f32 renders too far from the references: {:?}",
+        failed
+    );
+}